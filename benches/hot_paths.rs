@@ -0,0 +1,43 @@
+// Times tree walks, batch reads and patch application on synthetic
+// repositories with criterion, one group per repository size over the cases
+// of `bench::cases`, which `/api/benchmark` times too. `cargo bench` runs
+// the default sizes; BENCH_SIZES (file counts), BENCH_ENGINES and
+// BENCH_ON_DISK=1, to measure a real file system, pick others:
+//
+//     BENCH_SIZES=1000,50000 BENCH_ON_DISK=1 cargo bench --bench hot_paths
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use repopatch::bench::{self, BenchOptions};
+use std::env;
+
+fn options() -> BenchOptions {
+    let mut options = BenchOptions::default();
+    if let Ok(sizes) = env::var("BENCH_SIZES") {
+        options.sizes = sizes.split(',').map(|s| s.trim().parse().expect("BENCH_SIZES must be file counts")).collect();
+    }
+    if let Ok(engines) = env::var("BENCH_ENGINES") {
+        options.engines = engines.split(',').map(String::from).collect();
+    }
+    options.on_disk = env::var("BENCH_ON_DISK").is_ok_and(|v| v == "1");
+    options
+}
+
+fn hot_paths(c: &mut Criterion) {
+    let options = options();
+    for &files in &options.sizes {
+        let measured = bench::with_repository(files, &options, |store| {
+            let mut group = c.benchmark_group(format!("{}_files", files));
+            for mut case in bench::cases(store, files, &options)? {
+                group.bench_function(&case.name, |b| b.iter(|| (case.run)().expect("benchmark case failed")));
+            }
+            group.finish();
+            Ok(())
+        });
+        if let Err(e) = measured {
+            panic!("{}", e);
+        }
+    }
+}
+
+criterion_group!(benches, hot_paths);
+criterion_main!(benches);
@@ -0,0 +1,38 @@
+// cargo +nightly fuzz run paths
+//
+// Feeds arbitrary strings through the relative path check and, as patch
+// headers, through path stripping and an apply against a memory store. No
+// accepted path and no written file may be able to leave the store root.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use repopatch::patch::PatchSet;
+use repopatch::store::{check_relative, MemoryStore};
+use std::path::{Component, Path};
+
+fn could_escape(path: &str) -> bool {
+    let unified = path.replace('\\', "/").to_ascii_lowercase().replace("%2e", ".").replace("%2f", "/").replace("%5c", "/");
+    path.contains('\0')
+        || unified.starts_with('/')
+        || unified.as_bytes().get(1) == Some(&b':')
+        || Path::new(&unified).components().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(path) = std::str::from_utf8(data) else {
+        return;
+    };
+    if check_relative(path).is_ok() {
+        assert!(!could_escape(path), "accepted {:?}", path);
+    }
+    let header = path.replace('\n', "");
+    let patch = format!("--- /dev/null\n+++ {}\n@@ -0,0 +1 @@\n+x\n", header);
+    let store = MemoryStore::default();
+    for strip in 0..3 {
+        let outcome = PatchSet::parse(&patch, strip).apply(&store);
+        for written in &outcome.applied_files {
+            assert!(!could_escape(written), "{:?} -p{} wrote {:?}", header, strip, written);
+        }
+    }
+});
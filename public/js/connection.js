@@ -14,7 +14,10 @@ export async function tryFetchWithFallback(url, customOptions = {}) {
   let response;
   const defaultOptions = {
     headers: {
-      "ngrok-skip-browser-warning": "true"
+      "ngrok-skip-browser-warning": "true",
+      "X-Repopatch-Client": state.clientId,
+      ...(state.accessToken ? { "Authorization": `Bearer ${state.accessToken}` } : {}),
+      ...(state.csrfToken && customOptions.method && customOptions.method !== 'GET' ? { "X-CSRF-Token": state.csrfToken } : {})
     }
   };
   // Merge default options with custom options, combining headers appropriately.
@@ -54,6 +57,7 @@ export async function tryFetchWithFallback(url, customOptions = {}) {
 export async function checkConnection() {
   let endpointInput = document.getElementById('endpoint-url').value.trim() || "/"; // Default to relative root
   const statusElement = document.getElementById('connection-status');
+  state.accessToken = document.getElementById('access-token').value.trim();
 
   statusElement.textContent = "Connecting...";
   statusElement.style.color = "#e0e0e0";
@@ -84,6 +88,8 @@ export async function checkConnection() {
       } else {
         state.baseEndpoint = endpointInput;
       }
+      await fetchCsrfToken();
+      subscribeToChanges();
       statusElement.textContent = "Connected";
       statusElement.style.color = "#00ff00";
       console.log(`Successfully connected to ${state.baseEndpoint}`);
@@ -100,4 +106,45 @@ export async function checkConnection() {
     statusElement.style.color = "#ff0000";
     console.error(`Connection error: ${error.message}`);
   }
-}
\ No newline at end of file
+}
+
+/**
+ * Fetches the CSRF token required by servers using cookie-based login.
+ * Older servers without /api/csrf simply leave the token empty.
+ */
+async function fetchCsrfToken() {
+  try {
+    const response = await tryFetchWithFallback(`${state.baseEndpoint}/api/csrf`);
+    if (response.ok) {
+      const data = await response.json();
+      state.csrfToken = data.csrfToken || '';
+    }
+  } catch (error) {
+    console.log(`No CSRF token available: ${error.message}`);
+  }
+}
+
+let eventSource = null;
+
+/**
+ * Subscribes to file change events so trees refresh and stale files are
+ * flagged when files change on disk. Servers without /api/events just
+ * fail the subscription.
+ */
+function subscribeToChanges() {
+  if (eventSource) eventSource.close();
+  if (typeof EventSource === 'undefined') return;
+  eventSource = new EventSource(`${state.baseEndpoint}/api/events?client=${encodeURIComponent(state.clientId)}`);
+  eventSource.addEventListener('fileChanged', (event) => {
+    const { path } = JSON.parse(event.data);
+    state.staleFiles.add(path);
+    console.warn(`${path} changed on disk since it was read; regenerate the patch against the new content`);
+  });
+  eventSource.addEventListener('treeChanged', async (event) => {
+    const { root } = JSON.parse(event.data);
+    const { fetchDirectoryStructure } = await import('./explorer.js');
+    for (const dir of state.directories.filter(d => d.path === root)) {
+      await fetchDirectoryStructure(dir.id);
+    }
+  });
+}
@@ -42,6 +42,8 @@ export async function fetchDirectoryStructure(dirId) {
         if (response.ok && data.success) {
             dir.path = data.root; // Update with canonicalized path from server
             dir.tree = data.tree; // Assign the full tree structure
+            dir.fileCount = data.fileCount; // Totals shown in the directory list
+            dir.size = data.size;
             delete dir.error; // Clear any previous error
             console.log(`Directory structure updated successfully for ${dir.id}:`, dir.tree ? Object.keys(dir.tree).length + ' top-level items' : 'empty tree');
             // Don't save full tree to localStorage, saveStateToLocalStorage handles this
@@ -2,7 +2,7 @@
 // Main entry point for the Patch Preview application.
 
 import { state, loadStateFromLocalStorage, saveStateToLocalStorage } from './state.js';
-import { debounce } from './utils.js'; // Keep debounce
+import { debounce, formatBytes } from './utils.js'; // Keep debounce
 import { fetchDirectoryStructure } from './explorer.js'; // Keep for fetching structure
 import { checkConnection, tryFetchWithFallback } from './connection.js'; // Keep connection
 import { handleZipUpload, handleFolderUpload } from './uploader.js'; // Keep uploader
@@ -42,6 +42,9 @@ function renderDirectoriesList() {
          typeBadge.className = 'dir-type';
          typeBadge.textContent = dir.type === 'path' ? 'Server' : 'Uploaded';
          nameSpan.prepend(typeBadge); // Add badge before name
+         if (dir.fileCount !== undefined) {
+             nameSpan.append(` — ${dir.fileCount} files, ${formatBytes(dir.size || 0)}`);
+         }
          div.appendChild(nameSpan);
 
 
@@ -179,6 +182,7 @@ document.addEventListener('DOMContentLoaded', async () => {
     } else {
          endpointInput.value = ''; // Ensure placeholder shows if no value
     }
+    document.getElementById('access-token').value = state.accessToken;
 
 
     // Initial connection check if endpoint exists
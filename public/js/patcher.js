@@ -254,7 +254,7 @@ export async function applyPatch(patchContent) {
              // clearPreview(); // Clear preview after successful apply
         } else {
              const errorMsg = data.error || `Server responded with status ${response.status}`;
-             const details = data.details ? `\nDetails:\n${data.details.join('\n')}` : '';
+             const details = data.details ? `\nDetails:\n${data.details.map(d => d.code ? `[${d.code}] ${d.message}` : d).join('\n')}` : '';
              const appliedCount = data.appliedFiles?.length || 0;
              let finalError = `Error applying patch: ${errorMsg}`;
              if (appliedCount > 0) {
@@ -6,6 +6,7 @@ import { getState, setState, getDirectories, setDirectories } from './stateDB.js
 
 export const STORAGE_KEYS = {
     ENDPOINT_URL: 'repoPatch_endpointUrl', // Changed prefix
+    ACCESS_TOKEN: 'repoPatch_accessToken', // Bearer token for servers running with USERS_FILE
     DIRECTORIES: 'repoPatch_directories', // Use stateDB for directories
     SELECTED_DIRECTORY_ID: 'repoPatch_selectedDirectoryId', // Store selected ID
     FAILED_FILES: 'repoPatch_failedFiles' // Keep for tracking fetch/patch errors maybe
@@ -15,6 +16,10 @@ export const state = {
     directories: [],                  // Array of { id, type, path, name, tree (optional, fetched on demand) }
     selectedDirectoryId: null,        // ID of the directory selected to apply the patch against
     baseEndpoint: "/",                // Base endpoint URL set to relative root by default
+    accessToken: '',                  // Bearer token sent with every API request, if set
+    csrfToken: '',                    // Echoed in X-CSRF-Token on state-changing requests
+    clientId: Math.random().toString(36).slice(2), // Per-tab id the server tracks file reads under
+    staleFiles: new Set(),            // Files changed on disk since they were last fetched
     failedFiles: new Set(),           // Track files that failed to fetch (useful for preview)
     patchContent: '',                 // Store the current patch content from the input
     patchPreviewContent: '',          // Store the generated preview content
@@ -27,6 +32,7 @@ export const state = {
  */
 export async function saveStateToLocalStorage() {
     localStorage.setItem(STORAGE_KEYS.ENDPOINT_URL, state.baseEndpoint);
+    localStorage.setItem(STORAGE_KEYS.ACCESS_TOKEN, state.accessToken);
     localStorage.setItem(STORAGE_KEYS.SELECTED_DIRECTORY_ID, state.selectedDirectoryId); // Save selected ID
 
     // Save larger/complex state items to IndexedDB
@@ -54,6 +60,7 @@ export async function loadStateFromLocalStorage() {
     if (state.baseEndpoint === 'null' || state.baseEndpoint === 'undefined') {
          state.baseEndpoint = "/"; // Handle legacy bad values
     }
+    state.accessToken = localStorage.getItem(STORAGE_KEYS.ACCESS_TOKEN) || '';
 
 
     // Load directories from IndexedDB
@@ -83,4 +83,20 @@ export function debounce(func, wait) {
 }
 
 
-// Removed: getLanguage, naturalCompare, sortTreeEntries, collectFolderPaths
\ No newline at end of file
+// Removed: getLanguage, naturalCompare, sortTreeEntries, collectFolderPaths
+
+/**
+ * Formats a byte count for display, e.g. 3.1 MB.
+ * @param {number} bytes - The size in bytes.
+ * @returns {string} - The formatted size.
+ */
+export function formatBytes(bytes) {
+    const units = ['B', 'KB', 'MB', 'GB'];
+    let value = bytes;
+    let unit = 0;
+    while (value >= 1024 && unit < units.length - 1) {
+        value /= 1024;
+        unit++;
+    }
+    return `${unit === 0 ? value : value.toFixed(1)} ${units[unit]}`;
+}
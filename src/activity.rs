@@ -0,0 +1,140 @@
+// What happened in each workspace, for a UI timeline: patches applied, files
+// written by replace, format and folder deletes, secret scans that flagged a
+// patch, and version control operations such as checkpoints and pull
+// requests. The access log has every request; this keeps the ones that did
+// something, described the way a person would read them. Events are kept in
+// memory, the newest MAX_EVENTS of each workspace.
+//
+// GET /api/activity?path=... pages through a workspace's events, newest
+// first; pass the previous page's nextBefore as `before` for the next one.
+
+use crate::auth::{Session, Stores};
+use actix_web::{get, web, HttpResponse};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+const MAX_EVENTS: usize = 1000;
+const DEFAULT_PAGE: usize = 50;
+const MAX_PAGE: usize = 200;
+const KINDS: &[&str] = &["patch", "write", "scan", "vcs"];
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Event {
+    pub id: u64,
+    pub time: String,
+    #[serde(skip)]
+    created: DateTime<Utc>,
+    /// `patch`, `write`, `scan` or `vcs`.
+    pub kind: &'static str,
+    /// What was done, e.g. `apply_patch`, `checkpoint` or `pull_request`.
+    pub action: &'static str,
+    /// Who did it; None without sign-in.
+    pub user: Option<String>,
+    pub summary: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<String>,
+    pub success: bool,
+    /// The history report of an applied patch, for /api/export_patch.
+    #[serde(rename = "reportId", skip_serializing_if = "Option::is_none")]
+    pub report_id: Option<String>,
+}
+
+impl Event {
+    pub fn new(kind: &'static str, action: &'static str, summary: String) -> Self {
+        Event { id: 0, time: String::new(), created: Utc::now(), kind, action, user: None, summary, files: Vec::new(), success: true, report_id: None }
+    }
+
+    pub fn files(mut self, files: Vec<String>) -> Self {
+        self.files = files;
+        self
+    }
+
+    pub fn failed(mut self, failed: bool) -> Self {
+        self.success = !failed;
+        self
+    }
+
+    pub fn report(mut self, report_id: String) -> Self {
+        self.report_id = Some(report_id);
+        self
+    }
+}
+
+#[derive(Default)]
+pub struct Activity {
+    workspaces: Mutex<HashMap<String, VecDeque<Event>>>,
+    next_id: AtomicU64,
+}
+
+impl Activity {
+    /// Adds `event` to the timeline of the workspace shown as `root`.
+    pub fn record(&self, root: &str, session: Option<&Session>, mut event: Event) {
+        event.id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        event.created = Utc::now();
+        event.time = event.created.to_rfc3339_opts(SecondsFormat::Millis, true);
+        event.user = session.map(|s| s.user.clone());
+        let mut workspaces = self.workspaces.lock().unwrap_or_else(|e| e.into_inner());
+        let events = workspaces.entry(root.to_string()).or_default();
+        if events.len() == MAX_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    /// Up to `limit` events of `root` older than `before`, newest first, and
+    /// whether there are more.
+    pub fn page(&self, root: &str, kinds: &[&str], since: Option<DateTime<Utc>>, before: Option<u64>, limit: usize) -> (Vec<Event>, bool) {
+        let workspaces = self.workspaces.lock().unwrap_or_else(|e| e.into_inner());
+        let mut matching = workspaces
+            .get(root)
+            .into_iter()
+            .flatten()
+            .rev()
+            .filter(|e| before.is_none_or(|b| e.id < b))
+            .filter(|e| kinds.is_empty() || kinds.contains(&e.kind))
+            .take_while(|e| since.is_none_or(|s| e.created >= s));
+        let page: Vec<Event> = matching.by_ref().take(limit).cloned().collect();
+        (page, matching.next().is_some())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ActivityQuery {
+    path: Option<String>,
+    /// Comma-separated kinds to keep; all when missing.
+    kind: Option<String>,
+    /// Only events at or after this RFC 3339 time.
+    since: Option<String>,
+    /// The nextBefore of the previous page.
+    before: Option<u64>,
+    limit: Option<usize>,
+}
+
+#[get("/api/activity")]
+pub async fn get_activity(query: web::Query<ActivityQuery>, stores: Stores, activity: web::Data<Activity>) -> HttpResponse {
+    let requested_path = query.path.clone().unwrap_or_else(|| std::env::current_dir().unwrap_or_default().to_string_lossy().to_string());
+    let root = match stores.open_root(&requested_path) {
+        Ok(s) => s.display_path(""),
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid directory path: {}", e) })),
+    };
+    let kinds: Vec<&str> = query.kind.as_deref().unwrap_or_default().split(',').map(str::trim).filter(|k| !k.is_empty()).collect();
+    if let Some(kind) = kinds.iter().find(|k| !KINDS.contains(k)) {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": format!("Unknown kind {}: use {}", kind, KINDS.join(", ")),
+            "field": "kind"
+        }));
+    }
+    let since = match query.since.as_deref().map(DateTime::parse_from_rfc3339).transpose() {
+        Ok(since) => since.map(|s| s.with_timezone(&Utc)),
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid since: {}", e), "field": "since" })),
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE).clamp(1, MAX_PAGE);
+    let (events, more) = activity.page(&root, &kinds, since, query.before, limit);
+    let next_before = events.last().filter(|_| more).map(|e| e.id);
+    HttpResponse::Ok().json(json!({ "success": true, "root": root, "events": events, "nextBefore": next_before }))
+}
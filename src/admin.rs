@@ -0,0 +1,181 @@
+// Runtime introspection for operators. /api/admin is only available to
+// admin users (see auth.rs) and reports what the server is currently holding:
+// in-flight jobs, the agent tunnel, file watches and event subscribers,
+// remote instances, users and login sessions. POST /api/admin/flush prunes
+// expired login state. POST /api/benchmark times the hot paths on synthetic
+// repositories on this machine, see repopatch::bench.
+
+use crate::auth::{Session, UserRegistry};
+use crate::instance::RootLocks;
+use crate::oidc::Oidc;
+use crate::presence::Presence;
+use crate::proxy::InstanceRegistry;
+use crate::watch::Watchdog;
+use actix_web::{get, post, web, HttpResponse};
+use repopatch::bench::{self, BenchOptions};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Counters shared by the handlers and the agent tunnel.
+pub struct Runtime {
+    started: Instant,
+    active_jobs: AtomicUsize,
+    total_jobs: AtomicUsize,
+    tunnel_connected: AtomicBool,
+    tunnel_hub: Mutex<Option<String>>,
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Runtime {
+            started: Instant::now(),
+            active_jobs: AtomicUsize::new(0),
+            total_jobs: AtomicUsize::new(0),
+            tunnel_connected: AtomicBool::new(false),
+            tunnel_hub: Mutex::new(None),
+        }
+    }
+}
+
+impl Runtime {
+    /// Counts a job (patch application or batch read) until the guard drops.
+    pub fn start_job(runtime: &web::Data<Runtime>) -> JobGuard {
+        runtime.active_jobs.fetch_add(1, Ordering::Relaxed);
+        runtime.total_jobs.fetch_add(1, Ordering::Relaxed);
+        JobGuard(runtime.clone())
+    }
+
+    /// Records the state of the agent tunnel to `hub`.
+    pub fn set_tunnel(&self, hub: &str, connected: bool) {
+        *self.tunnel_hub.lock().unwrap_or_else(|e| e.into_inner()) = Some(hub.to_string());
+        self.tunnel_connected.store(connected, Ordering::Relaxed);
+    }
+}
+
+pub struct JobGuard(web::Data<Runtime>);
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        self.0.active_jobs.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Helper function to reject non-admin callers
+pub fn require_admin(session: &Option<web::ReqData<Session>>) -> Result<(), HttpResponse> {
+    match session {
+        Some(s) if s.admin => Ok(()),
+        _ => Err(HttpResponse::Forbidden().json(json!({ "success": false, "error": "Admin access required" }))),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/api/admin")]
+pub async fn status(
+    session: Option<web::ReqData<Session>>,
+    runtime: web::Data<Runtime>,
+    instances: web::Data<InstanceRegistry>,
+    users: web::Data<UserRegistry>,
+    oidc: Option<web::Data<Oidc>>,
+    watchdog: web::Data<Watchdog>,
+    presence: web::Data<Presence>,
+    locks: Option<web::Data<RootLocks>>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(&session) {
+        return response;
+    }
+    let (clients, subscribed, watched) = watchdog.stats();
+    let (present, patching) = presence.stats();
+    let (sessions, pending_logins) = oidc.as_ref().map(|o| o.counts()).unwrap_or((0, 0));
+    let hub = runtime.tunnel_hub.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    HttpResponse::Ok().json(json!({
+        "success": true,
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptimeSecs": runtime.started.elapsed().as_secs(),
+        "jobs": {
+            "active": runtime.active_jobs.load(Ordering::Relaxed),
+            "total": runtime.total_jobs.load(Ordering::Relaxed)
+        },
+        "tunnel": hub.map(|hub| json!({ "hub": hub, "connected": runtime.tunnel_connected.load(Ordering::Relaxed) })),
+        "watcher": { "clients": clients, "subscribedClients": subscribed, "watchedPaths": watched },
+        "presence": { "clients": present, "patchesInProgress": patching },
+        "instances": instances.list(),
+        "lockedRoots": locks.map(|l| l.held()),
+        "users": users.len(),
+        "oidc": oidc.is_some().then(|| json!({ "sessions": sessions, "pendingLogins": pending_logins }))
+    }))
+}
+
+#[derive(Deserialize, Default)]
+pub struct FlushRequest {
+    /// Also log out every OIDC session, not just expired ones.
+    #[serde(rename = "revokeSessions", default)]
+    revoke_sessions: bool,
+}
+
+#[post("/api/admin/flush")]
+pub async fn flush(
+    session: Option<web::ReqData<Session>>,
+    body: Option<web::Json<FlushRequest>>,
+    oidc: Option<web::Data<Oidc>>,
+) -> HttpResponse {
+    if let Err(response) = require_admin(&session) {
+        return response;
+    }
+    let revoke = body.map(|b| b.revoke_sessions).unwrap_or(false);
+    let removed = oidc.map(|o| o.prune(revoke)).unwrap_or(0);
+    log::info!("Admin flush removed {} sessions", removed);
+    HttpResponse::Ok().json(json!({ "success": true, "removedSessions": removed }))
+}
+
+/// Largest synthetic repository /api/benchmark builds, in files.
+const MAX_BENCHMARK_FILES: usize = 20_000;
+/// Most timed runs of each case.
+const MAX_BENCHMARK_ITERATIONS: usize = 50;
+
+#[derive(Deserialize, Default)]
+pub struct BenchmarkRequest {
+    /// Files in each synthetic repository, one run per size.
+    sizes: Option<Vec<usize>>,
+    lines: Option<usize>,
+    iterations: Option<usize>,
+    /// Patch engines to time, by name.
+    engines: Option<Vec<String>>,
+    /// Build the repositories in the temp directory instead of in memory.
+    #[serde(rename = "onDisk", default)]
+    on_disk: bool,
+}
+
+#[post("/api/benchmark")]
+pub async fn benchmark(session: Option<web::ReqData<Session>>, body: Option<web::Json<BenchmarkRequest>>, runtime: web::Data<Runtime>) -> HttpResponse {
+    if let Err(response) = require_admin(&session) {
+        return response;
+    }
+    let body = body.map(web::Json::into_inner).unwrap_or_default();
+    let defaults = BenchOptions::default();
+    let options = BenchOptions {
+        sizes: body.sizes.unwrap_or_else(|| vec![100, 1_000]),
+        lines: body.lines.unwrap_or(defaults.lines),
+        iterations: body.iterations.unwrap_or(5),
+        engines: body.engines.unwrap_or(defaults.engines),
+        on_disk: body.on_disk,
+    };
+    if options.sizes.is_empty() || options.sizes.iter().any(|&files| files == 0 || files > MAX_BENCHMARK_FILES) {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("sizes must be between 1 and {} files", MAX_BENCHMARK_FILES) }));
+    }
+    if !(1..=MAX_BENCHMARK_ITERATIONS).contains(&options.iterations) {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("iterations must be between 1 and {}", MAX_BENCHMARK_ITERATIONS) }));
+    }
+    if !(3..=1_000).contains(&options.lines) {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": "lines must be between 3 and 1000" }));
+    }
+    let _job = Runtime::start_job(&runtime);
+    let started = Instant::now();
+    match web::block(move || bench::run(&options)).await {
+        Ok(Ok(timings)) => HttpResponse::Ok().json(json!({ "success": true, "timings": timings, "elapsedMs": started.elapsed().as_millis() as u64 })),
+        Ok(Err(e)) => HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Benchmark task failed: {}", e) })),
+    }
+}
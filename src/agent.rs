@@ -0,0 +1,261 @@
+// Remote agent mode (`repopatch agent`): instead of waiting for the web UI to
+// connect, repopatch dials out to a hub over WebSocket and serves the API
+// requests the hub tunnels to it. This reaches machines behind NAT/firewalls
+// without ngrok or port forwarding.
+//
+// Frames are JSON text messages:
+//   agent -> hub  {"type":"hello","name":"...","version":"..."}
+//   hub -> agent  {"type":"request","id":"1","method":"POST","path":"/api/files","body":"{...}"}
+//   agent -> hub  {"type":"response","id":"1","status":200,"contentType":"application/json","body":"{...}"}
+//
+// A response body that isn't valid UTF-8, such as a file from /api/raw, is
+// sent base64-encoded with "encoding":"base64" so it arrives byte for byte.
+// HUB_TOKEN is only sent over wss://, or over ws:// to a loopback hub.
+
+use crate::admin::Runtime;
+use actix_web::http::{Method, Uri};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use awc::ws::{Frame, Message};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_RESPONSE_BYTES: usize = 256 * 1024 * 1024;
+
+pub struct AgentConfig {
+    pub hub_url: String,
+    pub token: Option<String>,
+    pub name: String,
+}
+
+impl AgentConfig {
+    /// Reads HUB_URL (required), HUB_TOKEN and AGENT_NAME from the environment.
+    pub fn from_env() -> Result<Self, String> {
+        let hub_url = env::var("HUB_URL").map_err(|_| "HUB_URL must be set in agent mode".to_string())?;
+        let token = env::var("HUB_TOKEN").ok().filter(|t| !t.is_empty());
+        let name = env::var("AGENT_NAME")
+            .ok()
+            .or_else(|| env::var("HOSTNAME").ok())
+            .unwrap_or_else(|| "repopatch-agent".to_string());
+        if token.is_some() {
+            check_transport(&hub_url)?;
+        }
+        Ok(AgentConfig { hub_url, token, name })
+    }
+}
+
+// The token would be readable on the wire over plain ws:// to another host
+fn check_transport(hub_url: &str) -> Result<(), String> {
+    let uri = Uri::from_str(hub_url).map_err(|e| format!("Invalid HUB_URL {}: {}", hub_url, e))?;
+    if uri.scheme_str().is_some_and(|scheme| scheme.eq_ignore_ascii_case("wss")) {
+        return Ok(());
+    }
+    let host = uri.host().unwrap_or("").trim_start_matches('[').trim_end_matches(']');
+    let loopback = host.eq_ignore_ascii_case("localhost") || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback());
+    if loopback {
+        Ok(())
+    } else {
+        Err(format!("HUB_TOKEN is only sent over wss://; use a wss:// HUB_URL instead of {}", hub_url))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum HubMessage {
+    Request(TunnelRequest),
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Deserialize)]
+struct TunnelRequest {
+    id: String,
+    method: String,
+    path: String,
+    #[serde(default)]
+    body: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum AgentMessage {
+    Hello {
+        name: String,
+        version: String,
+    },
+    Response {
+        id: String,
+        status: u16,
+        #[serde(rename = "contentType")]
+        content_type: String,
+        body: String,
+        /// "base64" when `body` is base64-encoded
+        #[serde(skip_serializing_if = "Option::is_none")]
+        encoding: Option<&'static str>,
+    },
+}
+
+// Sends UTF-8 bodies as they are and anything else as base64
+fn encode_body(body: &[u8]) -> (String, Option<&'static str>) {
+    match std::str::from_utf8(body) {
+        Ok(text) => (text.to_string(), None),
+        Err(_) => (STANDARD.encode(body), Some("base64")),
+    }
+}
+
+/// Keeps a tunnel to the hub open, reconnecting with exponential backoff.
+/// Requests are forwarded to the API server listening on `local`.
+pub async fn run(config: AgentConfig, local: SocketAddr, runtime: Arc<Runtime>) {
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        let result = serve_connection(&config, local, &runtime).await;
+        runtime.set_tunnel(&config.hub_url, false);
+        match result {
+            Ok(()) => {
+                log::info!("Hub closed the tunnel, reconnecting");
+                backoff = Duration::from_secs(1);
+            }
+            Err(e) => log::warn!("Tunnel to {} failed: {}", config.hub_url, e),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn serve_connection(config: &AgentConfig, local: SocketAddr, runtime: &Runtime) -> Result<(), String> {
+    let client = awc::Client::default();
+    let mut request = client.ws(config.hub_url.as_str());
+    if let Some(token) = &config.token {
+        request = request.bearer_auth(token);
+    }
+    let (_, framed) = request.connect().await.map_err(|e| format!("connect failed: {}", e))?;
+    log::info!("Connected to hub {} as {}", config.hub_url, config.name);
+    runtime.set_tunnel(&config.hub_url, true);
+
+    let (mut sink, mut stream) = framed.split();
+    let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+
+    // Responses are produced concurrently and funnelled through one writer
+    let writer = actix_web::rt::spawn(async move {
+        while let Some(message) = rx.recv().await {
+            if sink.send(message).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let hello = AgentMessage::Hello { name: config.name.clone(), version: env!("CARGO_PKG_VERSION").to_string() };
+    let _ = tx.send(Message::Text(serde_json::to_string(&hello).unwrap_or_default().into()));
+
+    let result = loop {
+        let frame = match stream.next().await {
+            Some(Ok(frame)) => frame,
+            Some(Err(e)) => break Err(format!("protocol error: {}", e)),
+            None => break Ok(()),
+        };
+        match frame {
+            Frame::Text(text) => match serde_json::from_slice::<HubMessage>(&text) {
+                Ok(HubMessage::Request(req)) => {
+                    let tx = tx.clone();
+                    actix_web::rt::spawn(async move {
+                        let response = forward(local, req).await;
+                        let _ = tx.send(Message::Text(serde_json::to_string(&response).unwrap_or_default().into()));
+                    });
+                }
+                Ok(HubMessage::Unknown) => log::debug!("Ignoring unknown hub message"),
+                Err(e) => log::warn!("Invalid hub message: {}", e),
+            },
+            Frame::Ping(payload) => {
+                let _ = tx.send(Message::Pong(payload));
+            }
+            Frame::Close(_) => break Ok(()),
+            _ => {}
+        }
+    };
+
+    drop(tx);
+    let _ = writer.await;
+    result
+}
+
+async fn forward(local: SocketAddr, req: TunnelRequest) -> AgentMessage {
+    let error = |status: u16, message: String| AgentMessage::Response {
+        id: req.id.clone(),
+        status,
+        content_type: "application/json".to_string(),
+        body: serde_json::json!({ "success": false, "error": message }).to_string(),
+        encoding: None,
+    };
+
+    // Only the API is tunnelled; the UI is served by the hub
+    if !req.path.starts_with("/api/") {
+        return error(404, format!("Path {} is not tunnelled", req.path));
+    }
+    let method = match Method::from_str(&req.method.to_uppercase()) {
+        Ok(m) => m,
+        Err(_) => return error(400, format!("Invalid method {}", req.method)),
+    };
+
+    log::debug!("Tunnelled request {} {} {}", req.id, method, req.path);
+    let url = format!("http://{}{}", local, req.path);
+    let request = awc::Client::default()
+        .request(method, url)
+        .insert_header(("Content-Type", "application/json"));
+    let sent = match req.body.clone() {
+        Some(body) => request.send_body(body).await,
+        None => request.send().await,
+    };
+    let mut response = match sent {
+        Ok(r) => r,
+        Err(e) => return error(502, format!("Local request failed: {}", e)),
+    };
+    let content_type = response
+        .headers()
+        .get("Content-Type")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .to_string();
+    match response.body().limit(MAX_RESPONSE_BYTES).await {
+        Ok(body) => {
+            let (body, encoding) = encode_body(&body);
+            AgentMessage::Response { id: req.id, status: response.status().as_u16(), content_type, body, encoding }
+        }
+        Err(e) => error(502, format!("Failed to read local response: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_only_travel_encrypted_or_to_loopback() {
+        assert!(check_transport("wss://hub.example.com/agents").is_ok());
+        assert!(check_transport("ws://localhost:8080/agents").is_ok());
+        assert!(check_transport("ws://127.0.0.1:8080/agents").is_ok());
+        assert!(check_transport("ws://[::1]:8080/agents").is_ok());
+        assert!(check_transport("ws://hub.example.com/agents").is_err());
+        assert!(check_transport("ws://10.0.0.9/agents").is_err());
+        assert!(check_transport("ws://localhost.example.com/agents").is_err());
+    }
+
+    #[test]
+    fn binary_bodies_are_sent_as_base64() {
+        assert_eq!(encode_body(b"{\"success\":true}"), ("{\"success\":true}".to_string(), None));
+        let png = [0x89, b'P', b'N', b'G', 0xff];
+        let (body, encoding) = encode_body(&png);
+        assert_eq!(encoding, Some("base64"));
+        assert_eq!(STANDARD.decode(body).unwrap(), png);
+
+        let message = AgentMessage::Response { id: "1".to_string(), status: 200, content_type: "image/png".to_string(), body: "iVBORw==".to_string(), encoding: Some("base64") };
+        let frame: serde_json::Value = serde_json::from_str(&serde_json::to_string(&message).unwrap()).unwrap();
+        assert_eq!(frame["encoding"], "base64");
+    }
+}
@@ -0,0 +1,69 @@
+// Server-side memory for policy checks: which patches were dry-run recently
+// and which confirmation tokens are outstanding. Both are keyed by a
+// fingerprint of the target directory and the patch text, so approving one
+// patch never unlocks a different one.
+
+use crate::auth::random_token;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_APPROVAL_TTL: Duration = Duration::from_secs(15 * 60);
+
+pub struct Approvals {
+    ttl: Duration,
+    dry_runs: Mutex<HashMap<u64, Instant>>,
+    tokens: Mutex<HashMap<String, (u64, Instant)>>,
+}
+
+/// Identifies `patch` applied to `root`.
+pub fn fingerprint(root: &str, patch: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    root.hash(&mut hasher);
+    patch.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Approvals {
+    /// Dry runs and tokens expire after `ttl`.
+    pub fn new(ttl: Duration) -> Self {
+        Approvals { ttl, dry_runs: Mutex::default(), tokens: Mutex::default() }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn record_dry_run(&self, fingerprint: u64) {
+        let mut dry_runs = self.dry_runs.lock().unwrap_or_else(|e| e.into_inner());
+        dry_runs.retain(|_, at| at.elapsed() < self.ttl);
+        dry_runs.insert(fingerprint, Instant::now());
+    }
+
+    pub fn has_dry_run(&self, fingerprint: u64) -> bool {
+        let dry_runs = self.dry_runs.lock().unwrap_or_else(|e| e.into_inner());
+        dry_runs.get(&fingerprint).is_some_and(|at| at.elapsed() < self.ttl)
+    }
+
+    /// Issues a single-use token confirming the patch with `fingerprint`.
+    pub fn issue_token(&self, fingerprint: u64) -> String {
+        let token = random_token();
+        let mut tokens = self.tokens.lock().unwrap_or_else(|e| e.into_inner());
+        tokens.retain(|_, (_, at)| at.elapsed() < self.ttl);
+        tokens.insert(token.clone(), (fingerprint, Instant::now()));
+        token
+    }
+
+    /// True if `token` is live and was issued for `fingerprint`; valid
+    /// tokens are used up.
+    pub fn redeem(&self, token: &str, fingerprint: u64) -> bool {
+        let mut tokens = self.tokens.lock().unwrap_or_else(|e| e.into_inner());
+        let valid = tokens.get(token).is_some_and(|(fp, at)| *fp == fingerprint && at.elapsed() < self.ttl);
+        if valid {
+            tokens.remove(token);
+        }
+        valid
+    }
+}
@@ -0,0 +1,382 @@
+//! ZIP and tar archives as read-only folders. An entry is addressed as
+//! `archive!entry`, such as `fixtures/site.zip!pages/index.html`, and
+//! [`ArchiveStoreProvider`] makes those paths readable through any store,
+//! so `/api/file` and the other readers work on them unchanged while writes
+//! are refused.
+
+use crate::store::{DirEntry, FileStore, StoreProvider};
+use flate2::read::{DeflateDecoder, GzDecoder};
+use std::collections::BTreeSet;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Archives bigger than this aren't opened, nor gzipped tar archives that
+/// inflate to more.
+pub const MAX_ARCHIVE_BYTES: u64 = 64 * 1024 * 1024;
+/// Entries bigger than this once inflated aren't read.
+pub const MAX_ENTRY_BYTES: u64 = 64 * 1024 * 1024;
+
+/// An entry of an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    /// Relative path inside the archive, `/`-separated.
+    pub path: String,
+    pub size: u64,
+    pub is_dir: bool,
+}
+
+/// The kind of archive `path` names by its extension: `zip`, `tar` or
+/// `tar.gz`.
+pub fn archive_kind(path: &str) -> Option<&'static str> {
+    let lower = path.to_ascii_lowercase();
+    if lower.ends_with(".tar.gz") || lower.ends_with(".tgz") {
+        Some("tar.gz")
+    } else if lower.ends_with(".tar") {
+        Some("tar")
+    } else if lower.ends_with(".zip") || lower.ends_with(".jar") {
+        Some("zip")
+    } else {
+        None
+    }
+}
+
+/// Splits `archive!entry` at the first `!` that follows an archive's name,
+/// trimming slashes off the entry; the entry is empty for the archive's top
+/// level.
+pub fn split_archive_path(path: &str) -> Option<(&str, &str)> {
+    path.match_indices('!').map(|(at, _)| (&path[..at], path[at + 1..].trim_matches('/'))).find(|(archive, _)| archive_kind(archive).is_some())
+}
+
+// An entry name as a relative path, or None for names that would escape
+fn clean_name(name: &str) -> Option<String> {
+    let parts: Vec<&str> = name.split(['/', '\\']).filter(|p| !p.is_empty() && *p != ".").collect();
+    if parts.is_empty() || parts.contains(&"..") {
+        return None;
+    }
+    Some(parts.join("/"))
+}
+
+/// The entries of an archive of `kind` in `data`, in archive order.
+pub fn entries(kind: &str, data: &[u8]) -> Result<Vec<ArchiveEntry>, String> {
+    let mut entries = Vec::new();
+    if kind == "zip" {
+        for record in zip_directory(data)? {
+            if let Some(path) = clean_name(&record.name) {
+                entries.push(ArchiveEntry { path, size: record.size, is_dir: record.name.ends_with('/') });
+            }
+        }
+    } else {
+        visit_tar(kind, data, &mut |entry, _| {
+            entries.push(entry);
+            Ok(false)
+        })?;
+    }
+    Ok(entries)
+}
+
+/// The content of the file `entry` of an archive of `kind` in `data`; None
+/// when there is no such file.
+pub fn read_entry(kind: &str, data: &[u8], entry: &str) -> Result<Option<Vec<u8>>, String> {
+    if kind == "zip" {
+        return match zip_directory(data)?.into_iter().find(|r| !r.name.ends_with('/') && clean_name(&r.name).as_deref() == Some(entry)) {
+            Some(record) => zip_read(data, &record).map(Some),
+            None => Ok(None),
+        };
+    }
+    let mut content = None;
+    visit_tar(kind, data, &mut |found, reader| {
+        if found.is_dir || found.path != entry {
+            return Ok(false);
+        }
+        let mut data = Vec::new();
+        reader.take(MAX_ENTRY_BYTES).read_to_end(&mut data).map_err(|e| format!("Failed to read {}: {}", entry, e))?;
+        content = Some(data);
+        Ok(true)
+    })?;
+    Ok(content)
+}
+
+// Calls `visit` with each regular file and folder of a tar archive until
+// it returns true
+fn visit_tar(kind: &str, data: &[u8], visit: &mut dyn FnMut(ArchiveEntry, &mut dyn Read) -> Result<bool, String>) -> Result<(), String> {
+    if kind != "tar.gz" {
+        return visit_tar_entries(data, visit);
+    }
+    // One byte over the limit tells a full archive from a cut one
+    let mut inflated = GzDecoder::new(data).take(MAX_ARCHIVE_BYTES + 1);
+    let visited = visit_tar_entries(&mut inflated, visit);
+    if inflated.limit() == 0 {
+        return Err("The archive is too large once inflated".to_string());
+    }
+    visited
+}
+
+fn visit_tar_entries(reader: impl Read, visit: &mut dyn FnMut(ArchiveEntry, &mut dyn Read) -> Result<bool, String>) -> Result<(), String> {
+    let mut archive = tar::Archive::new(reader);
+    let entries = archive.entries().map_err(|e| format!("Invalid tar archive: {}", e))?;
+    for entry in entries {
+        let mut entry = entry.map_err(|e| format!("Invalid tar entry: {}", e))?;
+        let kind = entry.header().entry_type();
+        if !kind.is_file() && !kind.is_dir() {
+            continue;
+        }
+        let path = entry.path().map_err(|e| format!("Invalid tar entry path: {}", e))?;
+        let Some(path) = clean_name(&path.to_string_lossy()) else { continue };
+        let found = ArchiveEntry { path, size: entry.size(), is_dir: kind.is_dir() };
+        if visit(found, &mut entry)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+struct ZipRecord {
+    name: String,
+    method: u16,
+    flags: u16,
+    compressed: usize,
+    size: u64,
+    local: usize,
+}
+
+// The records of a ZIP archive's central directory
+fn zip_directory(data: &[u8]) -> Result<Vec<ZipRecord>, String> {
+    let u16_at = |at: usize| data.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+    let u32_at = |at: usize| data.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    let not_zip = || "Not a ZIP archive: no central directory".to_string();
+    // The end of central directory record is in the last 64 KiB and 22 bytes
+    let search_from = data.len().saturating_sub(22 + 65535);
+    let eocd = data[search_from..].windows(4).rposition(|w| w == b"PK\x05\x06").map(|i| i + search_from).ok_or_else(not_zip)?;
+    let count = u16_at(eocd + 10).ok_or_else(not_zip)?;
+    let offset = u32_at(eocd + 16).ok_or_else(not_zip)?;
+    if count == 0xffff || offset == 0xffff_ffff {
+        return Err("ZIP64 archives aren't supported".to_string());
+    }
+    let mut at = offset as usize;
+    let mut records = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        if data.get(at..at + 4) != Some(b"PK\x01\x02") {
+            return Err(not_zip());
+        }
+        let field = |offset: usize| u16_at(at + offset).map(usize::from).ok_or_else(not_zip);
+        let (name_len, extra_len, comment_len) = (field(28)?, field(30)?, field(32)?);
+        let name = data.get(at + 46..at + 46 + name_len).ok_or_else(not_zip)?;
+        records.push(ZipRecord {
+            name: String::from_utf8_lossy(name).into_owned(),
+            method: u16_at(at + 10).ok_or_else(not_zip)?,
+            flags: u16_at(at + 8).ok_or_else(not_zip)?,
+            compressed: u32_at(at + 20).ok_or_else(not_zip)? as usize,
+            size: u32_at(at + 24).ok_or_else(not_zip)? as u64,
+            local: u32_at(at + 42).ok_or_else(not_zip)? as usize,
+        });
+        at += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(records)
+}
+
+// A ZIP entry's content, stored or deflated
+fn zip_read(data: &[u8], record: &ZipRecord) -> Result<Vec<u8>, String> {
+    let corrupt = || format!("The ZIP entry {} is corrupt", record.name);
+    if record.flags & 1 == 1 {
+        return Err(format!("{} is encrypted", record.name));
+    }
+    let local = record.local;
+    if data.get(local..local + 4) != Some(b"PK\x03\x04") {
+        return Err(corrupt());
+    }
+    let u16_at = |at: usize| data.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as usize).ok_or_else(corrupt);
+    let start = local + 30 + u16_at(local + 26)? + u16_at(local + 28)?;
+    let body = data.get(start..start + record.compressed).ok_or_else(corrupt)?;
+    match record.method {
+        0 => Ok(body.to_vec()),
+        8 => {
+            let mut inflated = Vec::new();
+            DeflateDecoder::new(body)
+                .take(MAX_ENTRY_BYTES)
+                .read_to_end(&mut inflated)
+                .map_err(|e| format!("Failed to inflate {}: {}", record.name, e))?;
+            Ok(inflated)
+        }
+        other => Err(format!("{} uses ZIP compression method {}, which isn't supported", record.name, other)),
+    }
+}
+
+fn read_only(path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, format!("{} is inside an archive, which is read-only", path))
+}
+
+/// A store whose `archive!entry` paths read the entries of its archives.
+/// Entries can't be written or removed; every other path goes to the
+/// wrapped store. A file literally named like an entry path wins.
+pub struct ArchiveStore {
+    inner: Arc<dyn FileStore>,
+}
+
+impl ArchiveStore {
+    pub fn new(inner: Arc<dyn FileStore>) -> Self {
+        ArchiveStore { inner }
+    }
+
+    fn split<'p>(&self, path: &'p str) -> Option<(&'p str, &'p str)> {
+        split_archive_path(path).filter(|_| !self.inner.exists(path))
+    }
+
+    fn open(&self, archive: &str) -> io::Result<(&'static str, Vec<u8>)> {
+        let kind = archive_kind(archive).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not an archive", archive)))?;
+        if let Some(size) = self.inner.file_size(archive).filter(|&s| s > MAX_ARCHIVE_BYTES) {
+            let message = format!("{} is {} MiB, over the {} MiB archives are opened up to", archive, size / 1024 / 1024, MAX_ARCHIVE_BYTES / 1024 / 1024);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        Ok((kind, self.inner.read(archive)?))
+    }
+
+    fn entries(&self, archive: &str) -> io::Result<Vec<ArchiveEntry>> {
+        let (kind, data) = self.open(archive)?;
+        entries(kind, &data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl FileStore for ArchiveStore {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let Some((archive, entry)) = self.split(path) else {
+            return self.inner.read(path);
+        };
+        let (kind, data) = self.open(archive)?;
+        match read_entry(kind, &data, entry) {
+            Ok(Some(content)) => Ok(content),
+            Ok(None) => Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no file {}", archive, entry))),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+
+    fn read_head(&self, path: &str, max: usize) -> io::Result<Vec<u8>> {
+        if self.split(path).is_none() {
+            return self.inner.read_head(path, max);
+        }
+        let mut data = self.read(path)?;
+        data.truncate(max);
+        Ok(data)
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        if self.split(path).is_some() {
+            return Err(read_only(path));
+        }
+        self.inner.write(path, data)
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        if self.split(path).is_some() {
+            return Err(read_only(path));
+        }
+        self.inner.remove(path)
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        if self.split(path).is_some() {
+            return Err(read_only(path));
+        }
+        self.inner.remove_dir(path)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        match self.split(path) {
+            Some((archive, entry)) => self.entries(archive).is_ok_and(|e| e.iter().any(|e| !e.is_dir && e.path == entry)),
+            None => self.inner.exists(path),
+        }
+    }
+
+    fn list_dir(&self, path: &str) -> io::Result<Vec<DirEntry>> {
+        let Some((archive, dir)) = self.split(path) else {
+            return self.inner.list_dir(path);
+        };
+        // Folders are implied by the entries below them
+        let mut names = BTreeSet::new();
+        for entry in self.entries(archive)? {
+            let rest = if dir.is_empty() { Some(entry.path.as_str()) } else { entry.path.strip_prefix(dir).and_then(|r| r.strip_prefix('/')) };
+            if let Some(rest) = rest.filter(|r| !r.is_empty()) {
+                match rest.split_once('/') {
+                    Some((folder, _)) => names.insert((folder.to_string(), true)),
+                    None => names.insert((rest.to_string(), entry.is_dir)),
+                };
+            }
+        }
+        Ok(names.into_iter().map(|(name, is_dir)| DirEntry { name, is_dir, is_symlink: false }).collect())
+    }
+
+    fn display_path(&self, path: &str) -> String {
+        self.inner.display_path(path)
+    }
+
+    fn validate(&self, path: &str) -> Result<(), String> {
+        self.inner.validate(self.split(path).map_or(path, |(archive, _)| archive))
+    }
+
+    fn check_writable(&self, path: &str) -> Result<(), String> {
+        if self.split(path).is_some() {
+            return Err(read_only(path).to_string());
+        }
+        self.inner.check_writable(path)
+    }
+
+    fn file_size(&self, path: &str) -> Option<u64> {
+        match self.split(path) {
+            Some((archive, entry)) => self.entries(archive).ok()?.into_iter().find(|e| !e.is_dir && e.path == entry).map(|e| e.size),
+            None => self.inner.file_size(path),
+        }
+    }
+
+    fn modified(&self, path: &str) -> Option<SystemTime> {
+        self.inner.modified(self.split(path).map_or(path, |(archive, _)| archive))
+    }
+
+    fn available_space(&self, path: &str) -> Option<u64> {
+        self.inner.available_space(path)
+    }
+
+    fn file_id(&self, path: &str) -> Option<(u64, u64)> {
+        match self.split(path) {
+            Some(_) => None,
+            None => self.inner.file_id(path),
+        }
+    }
+
+    fn local_path(&self, path: &str) -> Option<PathBuf> {
+        match self.split(path) {
+            Some(_) => None,
+            None => self.inner.local_path(path),
+        }
+    }
+}
+
+/// Opens every store of another provider as an [`ArchiveStore`], and
+/// client file paths of the form `archive!entry` as the entry.
+pub struct ArchiveStoreProvider {
+    inner: Arc<dyn StoreProvider>,
+}
+
+impl ArchiveStoreProvider {
+    pub fn new(inner: Arc<dyn StoreProvider>) -> Self {
+        ArchiveStoreProvider { inner }
+    }
+}
+
+impl StoreProvider for ArchiveStoreProvider {
+    fn open_root(&self, root: &str) -> Result<Arc<dyn FileStore>, String> {
+        Ok(Arc::new(ArchiveStore::new(self.inner.open_root(root)?)))
+    }
+
+    fn open_file(&self, path: &str) -> Result<(Arc<dyn FileStore>, String), String> {
+        let (store, name) = match self.inner.open_file(path) {
+            Ok(file) => file,
+            Err(e) => {
+                let Some((archive, entry)) = split_archive_path(path) else { return Err(e) };
+                let (store, name) = self.inner.open_file(archive)?;
+                (store, format!("{}!{}", name, entry))
+            }
+        };
+        Ok((Arc::new(ArchiveStore::new(store)), name))
+    }
+}
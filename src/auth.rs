@@ -0,0 +1,353 @@
+// Multi-user mode: when USERS_FILE points at a JSON file, every /api and
+// /remote request must carry `Authorization: Bearer <token>` of a configured
+// user, and that user only sees the directories below their allowed roots.
+//
+//   {"users": [{"name": "alice", "token": "...", "roots": ["/srv/repos/alice"]}]}
+//
+// With OIDC enabled (see oidc.rs) a session cookie is accepted as well; users
+// logging in that way are matched against USERS_FILE by name, and may omit
+// the token. Without USERS_FILE or OIDC the server stays unauthenticated.
+//
+// Users with `"admin": true` may use /api/admin and register or remove remote
+// instances; so may requests carrying the ADMIN_TOKEN bearer token, in every
+// mode. Other users of USERS_FILE reach remote instances with their own
+// token, never the one the instance was registered with.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use crate::memory::{BudgetedStoreProvider, MemoryBudget};
+use crate::oidc::{Oidc, SESSION_COOKIE};
+use crate::usage::{self, MeteredStoreProvider, Usage};
+use repopatch::store::{canonical_client_path, ScopedStoreProvider, StoreProvider};
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::json;
+use std::env;
+use std::fs;
+use std::future::{ready, Ready};
+use std::ops::Deref;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+struct UsersFile {
+    users: Vec<UserConfig>,
+}
+
+#[derive(Deserialize)]
+struct UserConfig {
+    name: String,
+    #[serde(default)]
+    token: Option<String>,
+    roots: Vec<String>,
+    #[serde(default)]
+    admin: bool,
+}
+
+struct User {
+    name: String,
+    token: Option<String>,
+    admin: bool,
+    stores: Arc<dyn StoreProvider>,
+}
+
+/// The configured users; empty when multi-user mode is off.
+#[derive(Default)]
+pub struct UserRegistry {
+    users: Vec<User>,
+}
+
+impl UserRegistry {
+    /// Loads the users listed in USERS_FILE, scoping each to their roots on top of `stores`.
+    pub fn from_env(stores: &Arc<dyn StoreProvider>) -> Result<Self, String> {
+        let path = match env::var("USERS_FILE") {
+            Ok(p) if !p.is_empty() => p,
+            _ => return Ok(UserRegistry::default()),
+        };
+        let content = fs::read_to_string(&path).map_err(|e| format!("Failed to read USERS_FILE {}: {}", path, e))?;
+        let file: UsersFile = serde_json::from_str(&content).map_err(|e| format!("Invalid USERS_FILE {}: {}", path, e))?;
+
+        let mut users = Vec::new();
+        for user in file.users {
+            if user.token.as_deref() == Some("") {
+                return Err(format!("User {} has an empty token", user.name));
+            }
+            let mut roots = Vec::new();
+            for root in &user.roots {
+                let canonical = canonical_client_path(root).map_err(|e| format!("Invalid root for user {}: {}", user.name, e))?;
+                roots.push(canonical);
+            }
+            log::info!("Loaded user {} with roots {:?}", user.name, roots);
+            users.push(User {
+                name: user.name,
+                token: user.token,
+                admin: user.admin,
+                stores: Arc::new(ScopedStoreProvider::new(stores.clone(), roots)),
+            });
+        }
+        Ok(UserRegistry { users })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    fn find(&self, token: &str) -> Option<&User> {
+        self.users
+            .iter()
+            .find(|u| u.token.as_ref().is_some_and(|t| constant_time_eq(t.as_bytes(), token.as_bytes())))
+    }
+
+    fn by_name(&self, name: &str) -> Option<&User> {
+        self.users.iter().find(|u| u.name == name)
+    }
+}
+
+/// Generates an unguessable hex token for sessions and CSRF protection.
+pub fn random_token() -> String {
+    let bytes: [u8; 32] = rand::rng().random();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Compares tokens without leaking where they differ.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The authenticated user of a request.
+#[derive(Clone)]
+pub struct Session {
+    pub user: String,
+    pub admin: bool,
+    /// Whether the user only sees their roots from USERS_FILE.
+    pub restricted: bool,
+    /// Whether a bearer token, rather than a cookie, authenticated the request.
+    pub bearer: bool,
+    stores: Arc<dyn StoreProvider>,
+}
+
+#[cfg(test)]
+impl Session {
+    /// A session on an empty in-memory store.
+    pub fn for_test(user: &str, admin: bool, restricted: bool) -> Self {
+        use repopatch::store::{MemoryStore, MemoryStoreProvider};
+        let stores = Arc::new(MemoryStoreProvider::new("/test", Arc::new(MemoryStore::new())));
+        Session { user: user.to_string(), admin, restricted, bearer: false, stores }
+    }
+}
+
+/// Middleware enforcing bearer-token (multi-user mode) and session cookie
+/// (OIDC) authentication. It runs before csrf::verify, which exempts requests
+/// whose bearer token authenticated them.
+pub async fn authenticate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let is_api = req.path().starts_with("/api/") || req.path().starts_with("/remote/");
+    let registry = req.app_data::<web::Data<UserRegistry>>().cloned().unwrap_or_default();
+    let oidc = req.app_data::<web::Data<Oidc>>().cloned();
+    // Registering remote instances is for admins as well
+    let admin_only = req.path().starts_with("/api/admin") || (req.path().starts_with("/api/instances") && req.method() != Method::GET);
+    let protected = admin_only
+        || match &oidc {
+            Some(_) => !req.path().starts_with("/auth/"),
+            None => registry.is_enabled() && is_api,
+        };
+    if !protected {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+
+    let token = req
+        .headers()
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim);
+    let admin_token = env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty());
+    let mut session = token
+        .and_then(|t| registry.find(t))
+        .map(|user| Session { user: user.name.clone(), admin: user.admin, restricted: true, bearer: true, stores: user.stores.clone() });
+    if session.is_none() && admin_only {
+        if let (Some(token), Some(expected)) = (token, &admin_token) {
+            if constant_time_eq(token.as_bytes(), expected.as_bytes()) {
+                session = req
+                    .app_data::<web::Data<dyn StoreProvider>>()
+                    .map(|stores| Session { user: "admin".to_string(), admin: true, restricted: false, bearer: true, stores: stores.clone().into_inner() });
+            }
+        }
+    }
+
+    if let (None, Some(oidc)) = (&session, &oidc) {
+        if let Some(name) = req.cookie(SESSION_COOKIE).and_then(|c| oidc.session_user(c.value())) {
+            session = if registry.is_enabled() {
+                registry.by_name(&name).map(|user| Session { user: name, admin: user.admin, restricted: true, bearer: false, stores: user.stores.clone() })
+            } else {
+                req.app_data::<web::Data<dyn StoreProvider>>()
+                    .map(|stores| Session { user: name, admin: false, restricted: false, bearer: false, stores: stores.clone().into_inner() })
+            };
+            if session.is_none() {
+                let response = HttpResponse::Forbidden().json(json!({ "success": false, "error": "User is not allowed on this server" }));
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+        }
+    }
+
+    match session {
+        Some(session) => {
+            log::debug!("Request {} by user {}", req.path(), session.user);
+            req.extensions_mut().insert(session);
+            Ok(next.call(req).await?.map_into_left_body())
+        }
+        None if oidc.is_some() && !is_api => {
+            let response = HttpResponse::Found().insert_header(("Location", "/auth/login")).finish();
+            Ok(req.into_response(response).map_into_right_body())
+        }
+        None => {
+            let response = HttpResponse::Unauthorized().json(json!({ "success": false, "error": "Missing or invalid access token" }));
+            Ok(req.into_response(response).map_into_right_body())
+        }
+    }
+}
+
+/// The store provider a request may use: the user's scoped provider in
+/// multi-user mode, the server-wide one otherwise.
+#[derive(Clone)]
+pub struct Stores(Arc<dyn StoreProvider>);
+
+impl Deref for Stores {
+    type Target = dyn StoreProvider;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+impl FromRequest for Stores {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let extensions = req.extensions();
+        let session = extensions.get::<Session>();
+        let stores = match session {
+            Some(session) => session.stores.clone(),
+            None => match req.app_data::<web::Data<dyn StoreProvider>>() {
+                Some(data) => data.clone().into_inner(),
+                None => return ready(Err(actix_web::error::ErrorInternalServerError("Store provider is not configured"))),
+            },
+        };
+        // Reads are counted against the memory cap, and nothing starts past it
+        let stores: Arc<dyn StoreProvider> = match req.app_data::<web::Data<MemoryBudget>>() {
+            Some(budget) => {
+                if let Err(overloaded) = budget.admit() {
+                    return ready(Err(InternalError::from_response(overloaded.message.clone(), overloaded.response()).into()));
+                }
+                Arc::new(BudgetedStoreProvider::new(stores, budget.clone().into_inner()))
+            }
+            None => stores,
+        };
+        let Some(usage) = req.app_data::<web::Data<Usage>>() else {
+            return ready(Ok(Stores(stores)));
+        };
+        // Every read and write is counted against the caller's quotas
+        let user = usage::identity(session);
+        if let Err(exceeded) = usage.check_read(&user) {
+            return ready(Err(InternalError::from_response(exceeded.message.clone(), exceeded.response()).into()));
+        }
+        ready(Ok(Stores(Arc::new(MeteredStoreProvider::new(stores, usage.clone().into_inner(), user)))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::{get, middleware, App};
+    use repopatch::store::{MemoryStore, MemoryStoreProvider};
+
+    #[get("/api/whoami")]
+    async fn whoami(session: Option<web::ReqData<Session>>) -> HttpResponse {
+        HttpResponse::Ok().json(session.map(|s| json!({ "user": s.user, "admin": s.admin, "bearer": s.bearer })))
+    }
+
+    #[get("/api/admin")]
+    async fn admin_status(session: web::ReqData<Session>) -> HttpResponse {
+        HttpResponse::Ok().json(json!({ "user": session.user, "admin": session.admin }))
+    }
+
+    fn stores() -> Arc<dyn StoreProvider> {
+        Arc::new(MemoryStoreProvider::new("/srv", Arc::new(MemoryStore::new())))
+    }
+
+    fn registry() -> UserRegistry {
+        let user = |name: &str, token: &str, admin| User { name: name.to_string(), token: Some(token.to_string()), admin, stores: stores() };
+        UserRegistry { users: vec![user("alice", "alice-token", false), user("root", "root-token", true)] }
+    }
+
+    #[actix_web::test]
+    async fn bearer_tokens_pick_their_user() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(registry()))
+                .wrap(middleware::from_fn(authenticate))
+                .service(whoami),
+        )
+        .await;
+
+        let alice = TestRequest::get().uri("/api/whoami").insert_header(("Authorization", "Bearer alice-token")).to_request();
+        let session: serde_json::Value = call_and_read_body_json(&app, alice).await;
+        assert_eq!(session, json!({ "user": "alice", "admin": false, "bearer": true }));
+        let root = TestRequest::get().uri("/api/whoami").insert_header(("Authorization", "Bearer root-token")).to_request();
+        let session: serde_json::Value = call_and_read_body_json(&app, root).await;
+        assert_eq!(session["admin"], true);
+
+        for header in ["Bearer alice-token-2", "Bearer ", "alice-token"] {
+            let request = TestRequest::get().uri("/api/whoami").insert_header(("Authorization", header)).to_request();
+            assert_eq!(call_service(&app, request).await.status(), 401, "{}", header);
+        }
+        assert_eq!(call_service(&app, TestRequest::get().uri("/api/whoami").to_request()).await.status(), 401);
+    }
+
+    #[actix_web::test]
+    async fn the_admin_token_only_opens_admin_routes() {
+        // Only this test sets it, and only admin routes read it
+        env::set_var("ADMIN_TOKEN", "admin-secret");
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(UserRegistry::default()))
+                .app_data(web::Data::from(stores()))
+                .wrap(middleware::from_fn(authenticate))
+                .service(whoami)
+                .service(admin_status),
+        )
+        .await;
+
+        let admin = TestRequest::get().uri("/api/admin").insert_header(("Authorization", "Bearer admin-secret")).to_request();
+        let status: serde_json::Value = call_and_read_body_json(&app, admin).await;
+        assert_eq!(status, json!({ "user": "admin", "admin": true }));
+        let wrong = TestRequest::get().uri("/api/admin").insert_header(("Authorization", "Bearer admin-secre")).to_request();
+        assert_eq!(call_service(&app, wrong).await.status(), 401);
+
+        // Without USERS_FILE or OIDC other routes stay open, and the token
+        // doesn't make their caller anyone
+        let other = TestRequest::get().uri("/api/whoami").insert_header(("Authorization", "Bearer admin-secret")).to_request();
+        let session: serde_json::Value = call_and_read_body_json(&app, other).await;
+        assert_eq!(session, serde_json::Value::Null);
+    }
+
+    #[test]
+    fn tokens_compare_whole() {
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokens"));
+        assert!(!constant_time_eq(b"token", b"tokem"));
+        assert_ne!(random_token(), random_token());
+        assert_eq!(random_token().len(), 64);
+    }
+}
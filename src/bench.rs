@@ -0,0 +1,199 @@
+//! Timings of the hot paths on synthetic repositories: walking the tree,
+//! reading files in a batch and applying a patch with each engine.
+//! `/api/benchmark` times them with [`run`] and the criterion bench target
+//! (`cargo bench`) runs the same [`cases`], so numbers from a laptop and
+//! from a deployed server compare.
+//!
+//! ```
+//! use repopatch::bench::{self, BenchOptions};
+//!
+//! let timings = bench::run(&BenchOptions { sizes: vec![20], iterations: 2, ..BenchOptions::default() }).unwrap();
+//! assert!(timings.iter().any(|t| t.case == "tree_walk" && t.files == 20));
+//! ```
+
+use crate::engine;
+use crate::patch::ApplyOptions;
+use crate::store::{FileStore, FsStore, MemoryStore};
+use crate::tree::{build_filtered_tree, walk_files, TreeFilter};
+use ignore::gitignore::Gitignore;
+use serde::Serialize;
+use std::fs;
+use std::time::{Duration, Instant};
+
+/// Files in each folder of a synthetic repository, and folders in each
+/// folder above them.
+const FAN_OUT: usize = 10;
+
+/// Files a benchmarked patch changes, at most.
+const PATCHED_FILES: usize = 10;
+
+/// What to measure.
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Files in each synthetic repository, one run per size.
+    pub sizes: Vec<usize>,
+    /// Lines in each file.
+    pub lines: usize,
+    /// Timed runs of each case, after one untimed warm-up run.
+    pub iterations: usize,
+    /// Engines to apply the patch with, by name.
+    pub engines: Vec<String>,
+    /// Write the repositories to a temporary directory instead of keeping
+    /// them in memory, so the file system is measured too.
+    pub on_disk: bool,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        BenchOptions { sizes: vec![100, 1_000, 10_000], lines: 40, iterations: 10, engines: vec!["dmp".to_string(), "strict".to_string()], on_disk: false }
+    }
+}
+
+/// Timings of one case on one repository size, in microseconds.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Timing {
+    /// `tree_walk`, `batch_read` or `patch_apply:<engine>`.
+    pub case: String,
+    pub files: usize,
+    pub iterations: usize,
+    pub min_us: u64,
+    pub median_us: u64,
+    pub mean_us: u64,
+}
+
+/// Path of the `index`th file of a synthetic repository:
+/// `pkg3/mod4/file345.rs` and so on.
+pub fn synthetic_path(index: usize) -> String {
+    let mut path = format!("file{}.rs", index);
+    let mut folder = index / FAN_OUT;
+    let mut depth = 0;
+    while folder > 0 || depth == 0 {
+        let name = if depth == 0 { "mod" } else { "pkg" };
+        path = format!("{}{}/{}", name, folder % FAN_OUT, path);
+        folder /= FAN_OUT;
+        depth += 1;
+    }
+    path
+}
+
+/// Content of the `index`th file: `lines` small functions.
+pub fn synthetic_content(index: usize, lines: usize) -> String {
+    (0..lines).map(|line| format!("fn f{}_{}() -> usize {{ {} }}\n", index, line, line)).collect()
+}
+
+/// Writes a synthetic repository of `files` files to `store`.
+pub fn populate(store: &dyn FileStore, files: usize, lines: usize) -> Result<(), String> {
+    for index in 0..files {
+        let path = synthetic_path(index);
+        store.write(&path, synthetic_content(index, lines).as_bytes()).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+/// A unified diff changing the middle line of the first few files.
+pub fn synthetic_patch(files: usize, lines: usize) -> String {
+    let line = lines / 2;
+    let mut patch = String::new();
+    for index in 0..files.min(PATCHED_FILES) {
+        let path = synthetic_path(index);
+        let context = |l: usize| format!("fn f{}_{}() -> usize {{ {} }}", index, l, l);
+        let (before, after) = (line.saturating_sub(1), (line + 1).min(lines.saturating_sub(1)));
+        patch.push_str(&format!("--- a/{0}\n+++ b/{0}\n@@ -{1},3 +{1},3 @@\n", path, before + 1));
+        patch.push_str(&format!(" {}\n-{}\n+fn f{}_{}() -> usize {{ {} + 1 }}\n {}\n", context(before), context(line), index, line, line, context(after)));
+    }
+    patch
+}
+
+// Helper function to time `iterations` runs of `body` after a warm-up run
+fn measure(case: String, files: usize, iterations: usize, mut body: impl FnMut() -> Result<(), String>) -> Result<Timing, String> {
+    body()?;
+    let mut runs: Vec<Duration> = Vec::with_capacity(iterations);
+    for _ in 0..iterations.max(1) {
+        let started = Instant::now();
+        body()?;
+        runs.push(started.elapsed());
+    }
+    runs.sort();
+    let micros = |d: Duration| d.as_micros() as u64;
+    let total: Duration = runs.iter().sum();
+    Ok(Timing {
+        case,
+        files,
+        iterations: runs.len(),
+        min_us: micros(runs[0]),
+        median_us: micros(runs[runs.len() / 2]),
+        mean_us: micros(total / runs.len() as u32),
+    })
+}
+
+/// One thing to time on a populated store, named like [`Timing::case`].
+pub struct Case<'a> {
+    pub name: String,
+    pub run: Box<dyn FnMut() -> Result<(), String> + 'a>,
+}
+
+/// The cases of `options` on `store`, which holds a synthetic repository
+/// of `files` files.
+pub fn cases<'a>(store: &'a dyn FileStore, files: usize, options: &BenchOptions) -> Result<Vec<Case<'a>>, String> {
+    let mut paths = Vec::new();
+    walk_files(store, "", &Gitignore::empty(), &mut |path| paths.push(path.to_string()))?;
+    let mut cases = vec![
+        Case { name: "tree_walk".to_string(), run: Box::new(move || build_filtered_tree(store, "", &Gitignore::empty(), &TreeFilter::default()).map(|_| ())) },
+        Case {
+            name: "batch_read".to_string(),
+            run: Box::new(move || paths.iter().try_for_each(|path| store.read(path).map(|_| ()).map_err(|e| format!("Failed to read {}: {}", path, e)))),
+        },
+    ];
+    let patch = synthetic_patch(files, options.lines);
+    for name in &options.engines {
+        let engine = engine::by_name(name).ok_or_else(|| format!("Unknown patch engine '{}'", name))?;
+        let patch_set = engine.parse(&patch, 1);
+        let engine_name = name.clone();
+        cases.push(Case {
+            name: format!("patch_apply:{}", name),
+            run: Box::new(move || {
+                let outcome = engine.preview(&patch_set, store, &ApplyOptions::default()).outcome;
+                match outcome.details.first() {
+                    Some(failure) => Err(format!("The benchmark patch did not apply with {}: {}", engine_name, failure.message)),
+                    None => Ok(()),
+                }
+            }),
+        });
+    }
+    Ok(cases)
+}
+
+/// Builds a synthetic repository of `files` files, in memory or on disk as
+/// `options` say, and hands its store to `body`.
+pub fn with_repository<T>(files: usize, options: &BenchOptions, body: impl FnOnce(&dyn FileStore) -> Result<T, String>) -> Result<T, String> {
+    if !options.on_disk {
+        let store = MemoryStore::new();
+        populate(&store, files, options.lines)?;
+        return body(&store);
+    }
+    let dir = std::env::temp_dir().join(format!("repopatch-bench-{}-{}", std::process::id(), files));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    let result = fs::canonicalize(&dir).map_err(|e| e.to_string()).and_then(|root| {
+        let store = FsStore::new(root);
+        populate(&store, files, options.lines)?;
+        body(&store)
+    });
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+/// Builds a synthetic repository of each size and times every case on it.
+pub fn run(options: &BenchOptions) -> Result<Vec<Timing>, String> {
+    let mut timings = Vec::new();
+    for &files in &options.sizes {
+        with_repository(files, options, |store| {
+            for case in cases(store, files, options)? {
+                timings.push(measure(case.name, files, options.iterations, case.run)?);
+            }
+            Ok(())
+        })?;
+    }
+    Ok(timings)
+}
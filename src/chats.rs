@@ -0,0 +1,370 @@
+// Chat transcripts and where patches came from. Clients send the
+// conversation that produced a patch to POST /api/chats, then name the chat
+// (and message) with chatId and messageId when calling /api/apply_patch; the
+// resulting reportId is linked to it. GET /api/chats/provenance finds the
+// chat behind a report or every linked change to a file, long after the
+// report itself has left history.rs.
+//
+// Chats are owned by the signed-in user (shared without authentication) and
+// saved as JSON in CHATS_FILE (default repopatch-chats.json in the working
+// directory); an empty CHATS_FILE keeps them in memory. With DATA_DIR each
+// chat and link is a row of the metadata database instead, imported from
+// CHATS_FILE the first time.
+
+use crate::auth::{random_token, Session, Stores};
+use crate::settings::user_of;
+use crate::validation::ValidJson;
+use repopatch::metadata::{object_entries, write_json_atomically, Metadata};
+use actix_web::{get, post, web, HttpResponse};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_CHATS_FILE: &str = "repopatch-chats.json";
+const ROLES: &[&str] = &["system", "user", "assistant", "tool"];
+const MAX_TITLE_CHARS: usize = 200;
+/// Record kinds in the metadata database, keyed by chat and report id.
+const CHATS: &str = "chats";
+const LINKS: &str = "chat_links";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Message {
+    pub id: String,
+    /// `system`, `user`, `assistant` or `tool`.
+    pub role: String,
+    pub content: String,
+    pub created: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Chat {
+    pub id: String,
+    pub title: String,
+    pub owner: String,
+    pub created: String,
+    pub updated: String,
+    pub messages: Vec<Message>,
+}
+
+/// A patch application made on behalf of a chat.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Link {
+    pub report_id: String,
+    pub chat_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    /// Display path of the directory the patch was applied to.
+    pub root: String,
+    /// Changed files, relative to root.
+    pub files: Vec<String>,
+    pub created: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct ChatData {
+    chats: BTreeMap<String, Chat>,
+    links: Vec<Link>,
+}
+
+pub struct ChatStore {
+    path: Option<PathBuf>,
+    metadata: Option<Arc<Metadata>>,
+    data: Mutex<ChatData>,
+}
+
+impl ChatStore {
+    /// Opens the store named by CHATS_FILE, or the chats in `metadata` when
+    /// given. A missing file starts empty; an unreadable or invalid one is
+    /// an error.
+    pub fn from_env(metadata: Option<Arc<Metadata>>) -> Result<Self, String> {
+        let path = match env::var("CHATS_FILE") {
+            Ok(p) if p.is_empty() => None,
+            Ok(p) => Some(PathBuf::from(p)),
+            Err(_) => Some(PathBuf::from(DEFAULT_CHATS_FILE)),
+        };
+        if let Some(metadata) = metadata {
+            if let Some(path) = &path {
+                let chats = metadata.import_file(CHATS, path, |mut file| object_entries(file["chats"].take()))?;
+                let links = metadata.import_file(LINKS, path, |mut file| {
+                    let links: Vec<Link> = serde_json::from_value(file["links"].take()).map_err(|e| e.to_string())?;
+                    links.into_iter().map(|l| Ok((l.report_id.clone(), serde_json::to_value(l).map_err(|e| e.to_string())?))).collect()
+                })?;
+                if chats + links > 0 {
+                    log::info!("Imported {} chats and {} links from {} into {}", chats, links, path.display(), metadata.path().display());
+                }
+            }
+            let data = ChatData {
+                chats: metadata.load_as(CHATS)?.into_iter().collect(),
+                links: metadata.load_as::<Link>(LINKS)?.into_iter().map(|(_, link)| link).collect(),
+            };
+            return Ok(ChatStore { path: None, metadata: Some(metadata), data: Mutex::new(data) });
+        }
+        let data = match &path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(content) => serde_json::from_str(&content).map_err(|e| format!("Invalid {}: {}", path.display(), e))?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => ChatData::default(),
+                Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+            },
+            None => ChatData::default(),
+        };
+        Ok(ChatStore { path, metadata: None, data: Mutex::new(data) })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ChatData> {
+        self.data.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Saves the store after the chat `chat_id` changed.
+    fn save(&self, data: &ChatData, chat_id: &str) -> Result<(), String> {
+        match (&self.metadata, data.chats.get(chat_id)) {
+            (Some(metadata), Some(chat)) => metadata.put(CHATS, chat_id, chat),
+            _ => self.save_file(data),
+        }
+    }
+
+    fn save_file(&self, data: &ChatData) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        write_json_atomically(path, data)
+    }
+
+    /// Checks that `user` may link patches to the chat and, when given, that
+    /// the chat has the message.
+    pub fn check(&self, user: &str, chat_id: &str, message_id: Option<&str>) -> Result<(), String> {
+        let data = self.lock();
+        let chat = data.chats.get(chat_id).filter(|c| c.owner == user).ok_or_else(|| format!("No chat {}", chat_id))?;
+        match message_id {
+            Some(id) if !chat.messages.iter().any(|m| m.id == id) => Err(format!("Chat {} has no message {}", chat_id, id)),
+            _ => Ok(()),
+        }
+    }
+
+    /// The chats of `user` and the links to them.
+    pub fn export(&self, user: &str) -> (Vec<Chat>, Vec<Link>) {
+        let data = self.lock();
+        let chats: Vec<Chat> = data.chats.values().filter(|c| c.owner == user).cloned().collect();
+        let links = data.links.iter().filter(|l| chats.iter().any(|c| c.id == l.chat_id)).cloned().collect();
+        (chats, links)
+    }
+
+    /// Adds chats exported elsewhere as chats of `user`, with the links to
+    /// them. Chats whose id is taken and links already known are skipped.
+    /// Returns how many chats and links were added.
+    pub fn import(&self, user: &str, chats: Vec<Chat>, links: Vec<Link>) -> Result<(usize, usize), String> {
+        let mut data = self.lock();
+        let added: Vec<Chat> = chats.into_iter().filter(|c| !data.chats.contains_key(&c.id)).map(|c| Chat { owner: user.to_string(), ..c }).collect();
+        let links: Vec<Link> = links
+            .into_iter()
+            .filter(|l| added.iter().any(|c| c.id == l.chat_id) && !data.links.iter().any(|known| known.report_id == l.report_id))
+            .collect();
+        let counts = (added.len(), links.len());
+        if let Some(metadata) = &self.metadata {
+            metadata.put_all(CHATS, &added.iter().map(|c| (c.id.clone(), c)).collect::<Vec<_>>())?;
+            metadata.put_all(LINKS, &links.iter().map(|l| (l.report_id.clone(), l)).collect::<Vec<_>>())?;
+        }
+        data.chats.extend(added.into_iter().map(|c| (c.id.clone(), c)));
+        data.links.extend(links);
+        if self.metadata.is_none() {
+            self.save_file(&data)?;
+        }
+        Ok(counts)
+    }
+
+    /// Records that the report was produced by the chat.
+    pub fn link(&self, link: Link) -> Result<(), String> {
+        let mut data = self.lock();
+        if let Some(metadata) = &self.metadata {
+            metadata.put(LINKS, &link.report_id, &link)?;
+            data.links.push(link);
+            return Ok(());
+        }
+        data.links.push(link);
+        self.save_file(&data)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MessageInput {
+    /// Kept when given, so a client can send a message again to update it.
+    id: Option<String>,
+    role: String,
+    content: String,
+}
+
+#[derive(Deserialize)]
+pub struct SaveChatRequest {
+    /// Adds to an existing chat instead of starting one.
+    #[serde(rename = "chatId")]
+    chat_id: Option<String>,
+    title: Option<String>,
+    #[serde(default)]
+    messages: Vec<MessageInput>,
+}
+
+#[derive(Deserialize)]
+pub struct ChatsQuery {
+    #[serde(rename = "chatId")]
+    chat_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ProvenanceQuery {
+    #[serde(rename = "reportId")]
+    report_id: Option<String>,
+    /// A file; lists every linked change to it.
+    path: Option<String>,
+}
+
+fn summary(chat: &Chat, links: &[Link]) -> serde_json::Value {
+    json!({
+        "chatId": chat.id,
+        "title": chat.title,
+        "created": chat.created,
+        "updated": chat.updated,
+        "messages": chat.messages.len(),
+        "patches": links.iter().filter(|l| l.chat_id == chat.id).count()
+    })
+}
+
+#[post("/api/chats")]
+pub async fn save_chat(body: ValidJson<SaveChatRequest>, session: Option<web::ReqData<Session>>, chats: web::Data<ChatStore>) -> HttpResponse {
+    if let Some(message) = body.messages.iter().find(|m| !ROLES.contains(&m.role.as_str())) {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Unknown role '{}': expected one of {}", message.role, ROLES.join(", ")) }));
+    }
+    if body.title.as_ref().is_some_and(|t| t.chars().count() > MAX_TITLE_CHARS) {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("title must be at most {} characters", MAX_TITLE_CHARS) }));
+    }
+    let user = user_of(&session);
+    let now = Utc::now().to_rfc3339();
+    let mut guard = chats.lock();
+    let data = &mut *guard;
+    let chat_id = match &body.chat_id {
+        Some(id) if data.chats.get(id).is_some_and(|c| c.owner == user) => id.clone(),
+        Some(id) => return HttpResponse::NotFound().json(json!({ "success": false, "error": format!("No chat {}", id) })),
+        None => {
+            let id = random_token()[..16].to_string();
+            let chat = Chat { id: id.clone(), title: String::new(), owner: user, created: now.clone(), updated: now.clone(), messages: Vec::new() };
+            data.chats.insert(id.clone(), chat);
+            id
+        }
+    };
+    let Some(chat) = data.chats.get_mut(&chat_id) else {
+        return HttpResponse::NotFound().json(json!({ "success": false, "error": format!("No chat {}", chat_id) }));
+    };
+    if let Some(title) = &body.title {
+        chat.title = title.trim().to_string();
+    }
+    let mut ids = Vec::new();
+    for input in &body.messages {
+        let id = input.id.clone().unwrap_or_else(|| {
+            (chat.messages.len() + 1..).map(|n| format!("m{}", n)).find(|id| !chat.messages.iter().any(|m| m.id == *id)).unwrap_or_default()
+        });
+        match chat.messages.iter_mut().find(|m| m.id == id) {
+            Some(message) => {
+                message.role = input.role.clone();
+                message.content = input.content.clone();
+            }
+            None => chat.messages.push(Message { id: id.clone(), role: input.role.clone(), content: input.content.clone(), created: now.clone() }),
+        }
+        ids.push(id);
+    }
+    if chat.title.is_empty() {
+        // Name it after the first thing the user asked
+        let first = chat.messages.iter().find(|m| m.role == "user").map(|m| m.content.lines().next().unwrap_or("").trim());
+        chat.title = first.unwrap_or("").chars().take(80).collect();
+    }
+    chat.updated = now;
+    let response = json!({ "success": true, "chat": summary(chat, &data.links), "messageIds": ids });
+    if let Err(e) = chats.save(data, &chat_id) {
+        log::error!("{}", e);
+        return HttpResponse::InternalServerError().json(json!({ "success": false, "error": e }));
+    }
+    HttpResponse::Ok().json(response)
+}
+
+#[get("/api/chats")]
+pub async fn get_chats(query: web::Query<ChatsQuery>, session: Option<web::ReqData<Session>>, chats: web::Data<ChatStore>) -> HttpResponse {
+    let user = user_of(&session);
+    let data = chats.lock();
+    let Some(chat_id) = &query.chat_id else {
+        let mut list: Vec<&Chat> = data.chats.values().filter(|c| c.owner == user).collect();
+        list.sort_by(|a, b| b.updated.cmp(&a.updated));
+        let list: Vec<_> = list.into_iter().map(|c| summary(c, &data.links)).collect();
+        return HttpResponse::Ok().json(json!({ "success": true, "chats": list }));
+    };
+    match data.chats.get(chat_id).filter(|c| c.owner == user) {
+        Some(chat) => {
+            let links: Vec<&Link> = data.links.iter().filter(|l| l.chat_id == chat.id).collect();
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "chatId": chat.id,
+                "title": chat.title,
+                "created": chat.created,
+                "updated": chat.updated,
+                "messages": chat.messages,
+                "patches": links
+            }))
+        }
+        None => HttpResponse::NotFound().json(json!({ "success": false, "error": format!("No chat {}", chat_id) })),
+    }
+}
+
+#[get("/api/chats/provenance")]
+pub async fn provenance(
+    query: web::Query<ProvenanceQuery>,
+    stores: Stores,
+    session: Option<web::ReqData<Session>>,
+    chats: web::Data<ChatStore>,
+) -> HttpResponse {
+    let file = match &query.path {
+        Some(path) => match stores.open_file(path) {
+            Ok((store, name)) => Some((store.display_path(""), name)),
+            Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+        },
+        None => None,
+    };
+    if file.is_none() && query.report_id.is_none() {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": "Give reportId or path" }));
+    }
+    let user = user_of(&session);
+    let data = chats.lock();
+    let origins: Vec<_> = data
+        .links
+        .iter()
+        .filter(|l| query.report_id.as_ref().is_none_or(|id| *id == l.report_id))
+        .filter(|l| file.as_ref().is_none_or(|(root, name)| *root == l.root && l.files.contains(name)))
+        .filter_map(|link| {
+            let chat = data.chats.get(&link.chat_id).filter(|c| c.owner == user)?;
+            let position = link.message_id.as_ref().and_then(|id| chat.messages.iter().position(|m| m.id == *id));
+            // The user message the change answered: the last one up to the
+            // linked message, or before the link without one
+            let earlier = match position {
+                Some(i) => &chat.messages[..=i],
+                None => &chat.messages[..chat.messages.iter().take_while(|m| m.created <= link.created).count()],
+            };
+            let message = position.map(|i| &chat.messages[i]);
+            let prompt = earlier.iter().rfind(|m| m.role == "user");
+            Some(json!({
+                "reportId": link.report_id,
+                "root": link.root,
+                "files": link.files,
+                "created": link.created,
+                "chatId": chat.id,
+                "title": chat.title,
+                "message": message,
+                "prompt": prompt
+            }))
+        })
+        .collect();
+    if origins.is_empty() && query.report_id.is_some() && file.is_none() {
+        return HttpResponse::NotFound().json(json!({ "success": false, "error": "No chat is linked to that report" }));
+    }
+    HttpResponse::Ok().json(json!({ "success": true, "origins": origins }))
+}
@@ -0,0 +1,392 @@
+// Subcommands that use the patch engine without starting the server:
+//
+//   repopatch apply [--dir <repo>] [--patch <file>] [--dry-run] [--strip <n>]
+//                   [--format] [--debug]
+//   repopatch pack [--dir <repo>] [--glob <glob>]... [--exclude <glob>]...
+//                  [--max-tokens <n>] [--long-lines <wrap|elide>]
+//                  [--line-width <n>] [--out <file>]
+//
+// `apply` reads the patch from --patch (stdin when it's missing or `-`),
+// applies it to --dir (the current directory by default) and prints the
+// report /api/apply_patch would return. The exit code is 0 when every file
+// applied, 1 when some didn't and 2 for bad arguments. The server's
+// path rewrites, protected paths, patch policy deny rules and SECRET_SCAN
+// apply; rules that
+// ask for a dry run or a confirmation don't, since whoever runs the command
+// is the one confirming.
+//
+// `pack` writes the files of --dir that the ignore rules and globs keep into
+// one Markdown file for a prompt (stdout without --out), leaving out files
+// that would take it over --max-tokens. --long-lines wraps or elides the
+// lines of minified files at --line-width characters. A summary goes to
+// stderr.
+
+use crate::{apply_options, apply_result, format_and_write, revisions};
+use crate::format::Formatters;
+use ignore::gitignore::Gitignore;
+use repopatch::longlines::LongLines;
+use repopatch::pack::pack;
+use repopatch::packages::{find_packages, Package};
+use repopatch::patch::{self, PatchSet};
+use repopatch::policy::{Action, Policy, Violation};
+use repopatch::remap::PathRewrites;
+use repopatch::secrets;
+use repopatch::store::{FileStore, OverlayStore, StoreProvider};
+use repopatch::tree::{load_gitignore, PathFilter};
+use serde_json::json;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+
+const APPLY_USAGE: &str = "Usage: repopatch apply [--dir <repo>] [--patch <file>] [--dry-run] [--strip <n>] [--format] [--debug]";
+const PACK_USAGE: &str = "Usage: repopatch pack [--dir <repo>] [--glob <glob>]... [--exclude <glob>]... [--max-tokens <n>] [--long-lines <wrap|elide>] [--line-width <n>] [--out <file>]";
+
+#[derive(Default)]
+struct ApplyArgs {
+    dir: Option<String>,
+    patch: Option<String>,
+    dry_run: bool,
+    strip: Option<usize>,
+    format: bool,
+    debug: bool,
+}
+
+fn parse_apply_args(args: &[String]) -> Result<ApplyArgs, String> {
+    let mut parsed = ApplyArgs::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().cloned().ok_or_else(|| format!("{} needs a value", name));
+        match arg.as_str() {
+            "--dir" | "-d" => parsed.dir = Some(value(arg)?),
+            "--patch" | "-p" => parsed.patch = Some(value(arg)?),
+            "--strip" => parsed.strip = Some(value(arg)?.parse().map_err(|_| "--strip needs a number".to_string())?),
+            "--dry-run" | "-n" => parsed.dry_run = true,
+            "--format" => parsed.format = true,
+            "--debug" => parsed.debug = true,
+            other => return Err(format!("Unknown argument {}", other)),
+        }
+    }
+    Ok(parsed)
+}
+
+#[derive(Default)]
+struct PackArgs {
+    dir: Option<String>,
+    globs: Vec<String>,
+    excludes: Vec<String>,
+    max_tokens: Option<usize>,
+    long_lines: LongLines,
+    out: Option<String>,
+}
+
+fn parse_pack_args(args: &[String]) -> Result<PackArgs, String> {
+    let mut parsed = PackArgs::default();
+    let (mut long_lines, mut line_width) = (None, None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().cloned().ok_or_else(|| format!("{} needs a value", name));
+        match arg.as_str() {
+            "--dir" | "-d" => parsed.dir = Some(value(arg)?),
+            "--glob" | "-g" => parsed.globs.push(value(arg)?),
+            "--exclude" | "-x" => parsed.excludes.push(value(arg)?),
+            "--max-tokens" | "-t" => {
+                let max = value(arg)?;
+                parsed.max_tokens = Some(parse_count(&max).ok_or_else(|| format!("Invalid token count {}", max))?);
+            }
+            "--long-lines" => long_lines = Some(value(arg)?),
+            "--line-width" => line_width = Some(value(arg)?.parse().map_err(|_| "--line-width needs a number".to_string())?),
+            "--out" | "-o" => parsed.out = Some(value(arg)?),
+            other => return Err(format!("Unknown argument {}", other)),
+        }
+    }
+    parsed.long_lines = LongLines::parse(long_lines.as_deref(), line_width)?;
+    Ok(parsed)
+}
+
+// A count like 2000, 100k or 1.5m
+fn parse_count(value: &str) -> Option<usize> {
+    let value = value.trim().to_lowercase();
+    let (number, scale) = match value.strip_suffix('k') {
+        Some(n) => (n, 1_000.0),
+        None => match value.strip_suffix('m') {
+            Some(n) => (n, 1_000_000.0),
+            None => (value.as_str(), 1.0),
+        },
+    };
+    let count = number.parse::<f64>().ok()? * scale;
+    (count.is_finite() && count >= 1.0).then_some(count as usize)
+}
+
+fn current_dir() -> String {
+    env::current_dir().map(|d| d.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+/// The store's packages when a policy rule is limited to some, none
+/// otherwise.
+pub fn policy_packages(policy: &Policy, store: &dyn FileStore) -> Vec<Package> {
+    if !policy.uses_packages() {
+        return Vec::new();
+    }
+    find_packages(store).unwrap_or_else(|e| {
+        log::warn!("Package rules of the patch policy match nothing in {}: {}", store.display_path(""), e);
+        Vec::new()
+    })
+}
+
+/// Why [`precheck`] turned a patch away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// It changes no files.
+    Empty,
+    /// It adds what looks like secrets and SECRET_SCAN is `block`.
+    Secrets,
+    /// It breaks a policy rule of one of the blocking kinds.
+    Policy,
+}
+
+/// What [`precheck`] found in a patch.
+pub struct Precheck {
+    /// The fields to add to the report, or the whole report of a rejected
+    /// patch.
+    pub report: serde_json::Value,
+    pub rejection: Option<Rejection>,
+    /// The secret scan's findings; none unless SECRET_SCAN is set.
+    pub findings: Vec<secrets::Finding>,
+    /// Every violation of the policy, blocking or not.
+    pub violations: Vec<Violation>,
+}
+
+impl Precheck {
+    /// The fields to add to the report, or the report of a rejected patch.
+    pub fn into_result(self) -> Result<serde_json::Value, serde_json::Value> {
+        match self.rejection {
+            None => Ok(self.report),
+            Some(_) => Err(self.report),
+        }
+    }
+}
+
+/// Rejects a patch that changes no files, rewrites its paths, scans it for
+/// secrets when SECRET_SCAN is set and checks it against the policy,
+/// rejecting violations of the `blocking` kinds. /api/apply_patch,
+/// /api/apply_patches and `repopatch apply` all go through here.
+pub fn precheck(store: &dyn FileStore, patch_set: &mut PatchSet, rewrites: &PathRewrites, policy: &Policy, blocking: &[Action]) -> Precheck {
+    let failure = |error: &str| json!({ "success": false, "error": error, "appliedFiles": [], "details": [] });
+    let mut checked = Precheck { report: json!({}), rejection: None, findings: Vec::new(), violations: Vec::new() };
+    if patch_set.files.is_empty() {
+        checked.report = failure("No file changes found in the patch.");
+        checked.rejection = Some(Rejection::Empty);
+        return checked;
+    }
+    let rewritten = rewrites.apply(patch_set);
+    if !rewritten.is_empty() {
+        checked.report["rewrittenPaths"] = json!(rewritten);
+    }
+    let scan_mode = env::var("SECRET_SCAN").unwrap_or_default();
+    if matches!(scan_mode.as_str(), "warn" | "block") {
+        checked.findings = secrets::scan(patch_set);
+        if !checked.findings.is_empty() {
+            if scan_mode == "block" {
+                log::warn!("Blocked patch with {} possible secrets", checked.findings.len());
+                let details: Vec<_> = checked
+                    .findings
+                    .iter()
+                    .map(|f| {
+                        let message = format!("Line {} looks like a {} ({}); remove it or set SECRET_SCAN=warn", f.line, f.rule, f.excerpt);
+                        patch::PatchFailure::new(patch::ErrorCode::SecretDetected, &f.file, message)
+                    })
+                    .collect();
+                checked.report = failure("Patch adds content that looks like secrets.");
+                checked.report["details"] = json!(details);
+                checked.report["warnings"] = json!(checked.findings);
+                checked.rejection = Some(Rejection::Secrets);
+                return checked;
+            }
+            checked.report["warnings"] = json!(checked.findings);
+        }
+    }
+    checked.violations = policy.evaluate_in(patch_set, &policy_packages(policy, store));
+    let blocked: Vec<_> = checked.violations.iter().filter(|v| blocking.contains(&v.action)).collect();
+    if !blocked.is_empty() {
+        let details: Vec<_> = blocked
+            .iter()
+            .flat_map(|v| v.files.iter().map(move |f| patch::PatchFailure::new(patch::ErrorCode::PolicyViolation, f, format!("[{}] {}", v.rule, v.message))))
+            .collect();
+        let mut report = failure("Patch violates the server's patch policy.");
+        report["details"] = json!(details);
+        report["violations"] = json!(blocked);
+        checked.report = report;
+        checked.rejection = Some(Rejection::Policy);
+        return checked;
+    }
+    if !checked.violations.is_empty() {
+        checked.report["violations"] = json!(checked.violations);
+    }
+    checked
+}
+
+/// Runs `repopatch apply` with the arguments after the subcommand and
+/// returns the exit code.
+pub fn apply(args: &[String], stores: &dyn StoreProvider, policy: &Policy, rewrites: &PathRewrites, formatters: &Formatters) -> i32 {
+    let args = match parse_apply_args(args) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{}\n{}", e, APPLY_USAGE);
+            return 2;
+        }
+    };
+    let dir = args.dir.clone().unwrap_or_else(current_dir);
+    let store = match stores.open_root(&dir) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Invalid directory {}: {}", dir, e);
+            return 2;
+        }
+    };
+    let read = match args.patch.as_deref() {
+        None | Some("-") => {
+            let mut content = String::new();
+            io::stdin().read_to_string(&mut content).map(|_| content).map_err(|e| format!("Failed to read stdin: {}", e))
+        }
+        Some(path) => fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e)),
+    };
+    let content = match read {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+    let content = content.trim();
+    if content.is_empty() {
+        eprintln!("Patch content cannot be empty");
+        return 2;
+    }
+
+    let mut patch_set = PatchSet::parse(content, args.strip.unwrap_or(1));
+    let report = match precheck(&*store, &mut patch_set, rewrites, policy, &[Action::Deny]).into_result() {
+        Err(report) => report,
+        Ok(mut extra) => {
+            let options = patch::ApplyOptions { trace: args.debug, ..apply_options(patch::DEFAULT_CONTEXT_LINES) };
+            if args.dry_run {
+                let outcome = patch_set.apply_with(&OverlayStore::new(&*store), &options);
+                if outcome.is_success() {
+                    extra["message"] = json!("Dry run: the patch applies cleanly. Nothing was written.");
+                }
+                extra["dryRun"] = json!(true);
+                apply_result(outcome, extra)
+            } else {
+                let outcome = patch_set.apply_with(&*store, &options);
+                if args.format && outcome.is_success() {
+                    let touched: Vec<String> = outcome.applied_files.iter().filter(|p| store.exists(p)).cloned().collect();
+                    extra["formatting"] = format_and_write(formatters, &*store, &touched, &options);
+                }
+                if !outcome.applied_files.is_empty() {
+                    extra["revisions"] = revisions(&*store, &outcome.applied_files);
+                }
+                apply_result(outcome, extra)
+            }
+        }
+    };
+    println!("{}", serde_json::to_string_pretty(&report).unwrap_or_default());
+    if report["success"] == true { 0 } else { 1 }
+}
+
+/// Runs `repopatch pack` with the arguments after the subcommand and
+/// returns the exit code.
+pub fn pack_files(args: &[String], stores: &dyn StoreProvider) -> i32 {
+    let args = match parse_pack_args(args) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{}\n{}", e, PACK_USAGE);
+            return 2;
+        }
+    };
+    let dir = args.dir.clone().unwrap_or_else(current_dir);
+    let store = match stores.open_root(&dir) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Invalid directory {}: {}", dir, e);
+            return 2;
+        }
+    };
+    let filter = match PathFilter::new(&args.globs, &args.excludes) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+    let ig = load_gitignore(&*store, "").unwrap_or_else(Gitignore::empty);
+    let packed = match pack(&*store, &ig, &filter, &store.display_path(""), args.max_tokens, args.long_lines) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let written = match &args.out {
+        Some(out) => fs::write(out, &packed.text).map_err(|e| format!("Failed to write {}: {}", out, e)),
+        None => io::stdout().write_all(packed.text.as_bytes()).map_err(|e| format!("Failed to write stdout: {}", e)),
+    };
+    if let Err(e) = written {
+        eprintln!("{}", e);
+        return 1;
+    }
+    let over_budget = packed.omitted.iter().filter(|o| o.reason == "budget").count();
+    eprintln!(
+        "Packed {} files, about {} tokens{}",
+        packed.files.len(),
+        packed.tokens,
+        args.out.as_ref().map(|o| format!(", into {}", o)).unwrap_or_default()
+    );
+    if over_budget > 0 {
+        eprintln!("Left out {} files over the budget of {} tokens", over_budget, args.max_tokens.unwrap_or_default());
+    }
+    if !packed.long_lines.is_empty() && args.long_lines == LongLines::Keep {
+        eprintln!("{} files have very long lines; --long-lines elide would shorten them", packed.long_lines.len());
+    }
+    let unreadable = packed.omitted.len() - over_budget;
+    if unreadable > 0 {
+        eprintln!("Skipped {} binary files or files over {} KiB", unreadable, repopatch::semantic::MAX_FILE_BYTES / 1024);
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use repopatch::policy::RuleConfig;
+    use repopatch::store::MemoryStore;
+
+    #[test]
+    fn prechecks_reject_with_the_server_reports() {
+        let store = MemoryStore::from_files([("migrations/001.sql", "old\n")]);
+        let rule = RuleConfig {
+            id: "frozen-migrations".to_string(),
+            action: Action::Deny,
+            paths: vec!["migrations/**".to_string()],
+            packages: Vec::new(),
+            operations: Vec::new(),
+            min_files: 1,
+            message: Some("Migrations are frozen".to_string()),
+        };
+        let policy = Policy::new(vec![rule]).unwrap();
+        let rewrites = PathRewrites::new(&[]).unwrap();
+
+        let mut patch_set = PatchSet::parse("--- a/migrations/001.sql\n+++ b/migrations/001.sql\n@@ -1 +1 @@\n-old\n+new\n", 1);
+        let checked = precheck(&store, &mut patch_set, &rewrites, &policy, &[Action::Deny]);
+        assert_eq!(checked.rejection, Some(Rejection::Policy));
+        assert_eq!(checked.report["details"][0]["code"], "POLICY_VIOLATION");
+        assert_eq!(checked.report["details"][0]["file"], "migrations/001.sql");
+        assert_eq!(checked.report["details"][0]["message"], "[frozen-migrations] Migrations are frozen");
+
+        // Rules of other kinds are reported and left to the caller
+        let checked = precheck(&store, &mut patch_set, &rewrites, &policy, &[Action::Confirm]);
+        assert_eq!(checked.violations.len(), 1);
+        assert_eq!(checked.into_result().unwrap()["violations"][0]["rule"], "frozen-migrations");
+
+        let checked = precheck(&store, &mut PatchSet::parse("not a patch", 1), &rewrites, &policy, &[Action::Deny]);
+        assert_eq!(checked.rejection, Some(Rejection::Empty));
+    }
+}
@@ -0,0 +1,103 @@
+// Optional `repopatch.toml` next to the binary's working directory (or the
+// file named by REPOPATCH_CONFIG). Settings that don't fit an environment
+// variable live here; everything is optional.
+
+use crate::format::FormatterConfig;
+use crate::logging::LoggingConfig;
+use crate::lsp::LanguageServerConfig;
+use crate::usage::QuotaConfig;
+use repopatch::diagnostics::CheckerConfig;
+use repopatch::policy::RuleConfig;
+use repopatch::remap::RewriteConfig;
+use repopatch::scaffold::TemplateConfig;
+use repopatch::store::HiddenFiles;
+use repopatch::tree::TreeLimits;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub logging: Option<LoggingConfig>,
+    #[serde(default)]
+    pub formatters: Vec<FormatterConfig>,
+    /// Check commands for /api/diagnostics; `None` of them uses the built-in ones.
+    #[serde(default)]
+    pub checkers: Vec<CheckerConfig>,
+    /// Language servers for /api/lsp; `None` of them uses the built-in ones.
+    #[serde(default)]
+    pub language_servers: Vec<LanguageServerConfig>,
+    #[serde(default)]
+    pub policy: Vec<RuleConfig>,
+    /// Usage quotas of single users, over the QUOTA_* defaults.
+    #[serde(default)]
+    pub quotas: Vec<QuotaConfig>,
+    /// Rewrites of patch paths, tried in order before they are resolved.
+    #[serde(default)]
+    pub path_rewrites: Vec<RewriteConfig>,
+    /// Globs that patches may never touch; `None` uses the built-in list.
+    #[serde(default)]
+    pub protected_paths: Option<Vec<String>>,
+    #[serde(default)]
+    pub hidden_files: HiddenFiles,
+    #[serde(default)]
+    pub tree_limits: TreeLimits,
+    /// Workspace aliases: `@name/...` in API paths stands for the root.
+    #[serde(default)]
+    pub workspaces: BTreeMap<String, String>,
+    /// Patch engine of each workspace root, `*` for the others.
+    #[serde(default)]
+    pub patch_engines: BTreeMap<String, String>,
+    /// File templates for /api/scaffold, added to the built-in ones.
+    #[serde(default)]
+    pub templates: Vec<TemplateConfig>,
+    #[serde(default)]
+    pub server: ServerTuning,
+}
+
+/// `[server]`: HTTP server tuning. Unset values keep actix-web's defaults.
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ServerTuning {
+    /// Worker threads; defaults to one per CPU core.
+    pub workers: Option<usize>,
+    /// Threads each worker may start for blocking file system work, such
+    /// as tree walks and file reads; defaults to 512 shared by the workers.
+    pub blocking_threads: Option<usize>,
+    /// 0 turns keep-alive off.
+    pub keep_alive_secs: Option<u64>,
+    /// Time a client has to send the request head.
+    pub client_request_timeout_ms: Option<u64>,
+    pub client_disconnect_timeout_ms: Option<u64>,
+    pub tls_handshake_timeout_ms: Option<u64>,
+    /// Per worker.
+    pub max_connections: Option<usize>,
+    /// TLS handshakes per worker at once.
+    pub max_connection_rate: Option<usize>,
+    pub backlog: Option<u32>,
+    pub shutdown_timeout_secs: Option<u64>,
+    /// Also accept HTTP/2 without TLS (h2c) on the HTTP listener.
+    #[serde(default)]
+    pub h2c: bool,
+}
+
+impl Config {
+    /// Loads the config file. A missing default file is not an error; a
+    /// missing REPOPATCH_CONFIG file or invalid TOML is.
+    pub fn load() -> Result<Self, String> {
+        let (path, explicit) = match env::var("REPOPATCH_CONFIG") {
+            Ok(p) if !p.is_empty() => (PathBuf::from(p), true),
+            _ => (PathBuf::from("repopatch.toml"), false),
+        };
+        let content = match fs::read_to_string(&path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => return Ok(Config::default()),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+        toml::from_str(&content).map_err(|e| format!("Invalid {}: {}", path.display(), e))
+    }
+}
@@ -0,0 +1,243 @@
+//! Picking prompt context under a token budget. Candidates are the target
+//! files, the files [`crate::related`] suggests for them and, given a
+//! question, the files sharing its vocabulary. The best-scoring ones go in
+//! whole; one that doesn't fit is replaced by its definitions closest to the
+//! question, as far as they fit.
+
+use crate::related::{related_files, CoChanges};
+use crate::semantic::{cosine, hash_embedding, INDEX_PATH, MAX_FILE_BYTES};
+use crate::store::FileStore;
+use crate::syntax::{self, Language};
+use crate::tree::walk_files;
+use ignore::gitignore::Gitignore;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// The usual rule of thumb for code and English with BPE tokenizers.
+pub const CHARS_PER_TOKEN: usize = 4;
+/// Path line and code fence around each file or symbol in a prompt.
+pub const OVERHEAD_TOKENS: usize = 8;
+/// Weight of question similarity against the related-file scores.
+const QUESTION_WEIGHT: f64 = 2.0;
+/// Files less similar to the question than this are not candidates.
+const MIN_SIMILARITY: f32 = 0.05;
+const MAX_RELATED: usize = 200;
+/// Files reported as left out, best first.
+const MAX_SKIPPED: usize = 20;
+
+/// Estimates the tokens `text` takes in a prompt.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+/// A definition included instead of its whole file.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SymbolSelection {
+    pub name: String,
+    #[serde(rename = "startLine")]
+    pub start_line: usize,
+    #[serde(rename = "endLine")]
+    pub end_line: usize,
+    pub tokens: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+/// A file in the plan, whole or as some of its definitions.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Selection {
+    pub path: String,
+    /// `file` or `symbols`.
+    pub kind: &'static str,
+    pub tokens: usize,
+    /// None for targets, which always come first.
+    pub score: Option<f64>,
+    pub reasons: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub symbols: Vec<SymbolSelection>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+/// A candidate that didn't fit.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Skipped {
+    pub path: String,
+    pub tokens: usize,
+    pub score: Option<f64>,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct ContextPlan {
+    pub budget: usize,
+    /// Estimated tokens of the selections.
+    pub used: usize,
+    pub selections: Vec<Selection>,
+    pub skipped: Vec<Skipped>,
+}
+
+struct Candidate {
+    path: String,
+    source: String,
+    score: Option<f64>,
+    reasons: Vec<&'static str>,
+}
+
+/// Plans the context for `targets` (store paths) and/or `question` within
+/// `budget` tokens. `co_changes` supplies commit history for a target, if
+/// any; `with_content` puts the selected text in the plan.
+pub fn plan_context(
+    store: &dyn FileStore,
+    ig: &Gitignore,
+    targets: &[String],
+    question: Option<&str>,
+    co_changes: &dyn Fn(&str) -> Option<CoChanges>,
+    budget: usize,
+    with_content: bool,
+) -> Result<ContextPlan, String> {
+    let read = |path: &str| -> Option<String> {
+        if store.file_size(path).is_none_or(|s| s > MAX_FILE_BYTES) {
+            return None;
+        }
+        store.read(path).ok().filter(|d| !d.contains(&0)).map(|d| String::from_utf8_lossy(&d).into_owned())
+    };
+    let mut candidates: BTreeMap<String, Candidate> = BTreeMap::new();
+    for target in targets {
+        let source = read(target).ok_or_else(|| format!("{} is missing, binary or over {} KiB", target, MAX_FILE_BYTES / 1024))?;
+        candidates.insert(target.clone(), Candidate { path: target.clone(), source, score: None, reasons: vec!["target"] });
+    }
+    for target in targets {
+        for related in related_files(store, ig, target, co_changes(target).as_ref(), MAX_RELATED)? {
+            if let Some(candidate) = candidates.get_mut(&related.path) {
+                if let Some(score) = candidate.score.as_mut() {
+                    *score += related.score;
+                    for reason in related.reasons {
+                        if !candidate.reasons.contains(&reason) {
+                            candidate.reasons.push(reason);
+                        }
+                    }
+                }
+            } else if let Some(source) = read(&related.path) {
+                candidates.insert(related.path.clone(), Candidate { path: related.path, source, score: Some(related.score), reasons: related.reasons });
+            }
+        }
+    }
+
+    // Without a question, definitions are ranked by likeness to the targets
+    let query = match question {
+        Some(q) => hash_embedding(q),
+        None => hash_embedding(&targets.iter().filter_map(|t| candidates.get(t)).map(|c| c.source.as_str()).collect::<Vec<_>>().join("\n")),
+    };
+    if question.is_some() {
+        let mut paths = Vec::new();
+        let index_dir = INDEX_PATH.split('/').next().unwrap_or(INDEX_PATH);
+        walk_files(store, "", ig, &mut |path| {
+            if !path.starts_with(&format!("{}/", index_dir)) {
+                paths.push(path.to_string());
+            }
+        })?;
+        for path in paths {
+            let existing = candidates.get(&path).map(|c| c.source.clone());
+            let Some(source) = existing.or_else(|| read(&path)) else { continue };
+            let similarity = cosine(&query, &hash_embedding(&source));
+            if similarity < MIN_SIMILARITY {
+                continue;
+            }
+            let candidate = candidates.entry(path.clone()).or_insert_with(|| Candidate { path, source, score: Some(0.0), reasons: Vec::new() });
+            if let Some(score) = candidate.score.as_mut() {
+                *score += QUESTION_WEIGHT * similarity as f64;
+                candidate.reasons.push("question");
+            }
+        }
+    }
+
+    let mut ranked: Vec<Candidate> = candidates.into_values().collect();
+    ranked.sort_by(|a, b| match (a.score, b.score) {
+        (None, None) => targets.iter().position(|t| *t == a.path).cmp(&targets.iter().position(|t| *t == b.path)),
+        (None, Some(_)) => std::cmp::Ordering::Less,
+        (Some(_), None) => std::cmp::Ordering::Greater,
+        (Some(x), Some(y)) => y.total_cmp(&x).then_with(|| a.path.cmp(&b.path)),
+    });
+
+    let mut plan = ContextPlan { budget, ..ContextPlan::default() };
+    for candidate in ranked {
+        let tokens = estimate_tokens(&candidate.source) + OVERHEAD_TOKENS;
+        if plan.used + tokens <= budget {
+            plan.used += tokens;
+            plan.selections.push(Selection {
+                path: candidate.path,
+                kind: "file",
+                tokens,
+                score: candidate.score.map(round),
+                reasons: candidate.reasons,
+                symbols: Vec::new(),
+                content: with_content.then_some(candidate.source),
+            });
+            continue;
+        }
+        let symbols = pick_symbols(&candidate, &query, budget - plan.used, with_content);
+        if symbols.is_empty() {
+            if plan.skipped.len() < MAX_SKIPPED {
+                plan.skipped.push(Skipped { path: candidate.path, tokens, score: candidate.score.map(round) });
+            }
+            continue;
+        }
+        let tokens = symbols.iter().map(|s| s.tokens).sum();
+        plan.used += tokens;
+        plan.selections.push(Selection {
+            path: candidate.path,
+            kind: "symbols",
+            tokens,
+            score: candidate.score.map(round),
+            reasons: candidate.reasons,
+            symbols,
+            content: None,
+        });
+    }
+    Ok(plan)
+}
+
+fn round(score: f64) -> f64 {
+    (score * 1000.0).round() / 1000.0
+}
+
+// The definitions of a file most like `query` that fit in `room` tokens,
+// in source order and without overlaps
+fn pick_symbols(candidate: &Candidate, query: &[f32], room: usize, with_content: bool) -> Vec<SymbolSelection> {
+    let Some(language) = Language::from_path(&candidate.path) else { return Vec::new() };
+    let Ok(tree) = syntax::parse(language, &candidate.source) else { return Vec::new() };
+    let source = &candidate.source;
+    let mut definitions: Vec<(f32, syntax::Definition)> = syntax::definitions(language, &tree, source)
+        .into_iter()
+        .map(|d| (cosine(query, &hash_embedding(&source[d.range.clone()])), d))
+        .filter(|(similarity, _)| *similarity > 0.0)
+        .collect();
+    definitions.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let mut used = 0;
+    let mut picked: Vec<(Range<usize>, String)> = Vec::new();
+    for (_, definition) in definitions {
+        let range = definition.range;
+        if picked.iter().any(|(r, _)| r.start < range.end && range.start < r.end) {
+            continue;
+        }
+        let tokens = estimate_tokens(&source[range.clone()]) + OVERHEAD_TOKENS;
+        if used + tokens <= room {
+            used += tokens;
+            picked.push((range, definition.name));
+        }
+    }
+    picked.sort_by_key(|(r, _)| r.start);
+    let line_of = |offset: usize| source[..offset].matches('\n').count() + 1;
+    picked
+        .into_iter()
+        .map(|(range, name)| SymbolSelection {
+            name,
+            start_line: line_of(range.start),
+            end_line: line_of(range.end.saturating_sub(1).max(range.start)),
+            tokens: estimate_tokens(&source[range.clone()]) + OVERHEAD_TOKENS,
+            content: with_content.then(|| source[range].to_string()),
+        })
+        .collect()
+}
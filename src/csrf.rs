@@ -0,0 +1,168 @@
+// CSRF protection for state-changing requests (double-submit cookie).
+// GET /api/csrf hands out a token and sets the same value in a SameSite
+// cookie; POST/PUT/PATCH/DELETE requests must echo it in X-CSRF-Token.
+// Requests authenticated with a bearer token are exempt, as browsers never
+// attach one on their own; merely sending an Authorization header is not
+// enough, since auth then falls back to the session cookie.
+//
+// CSRF_PROTECTION=on|off overrides the default, which is on whenever cookie
+// based auth (OIDC) is enabled.
+//
+// CORS doesn't cover WebSockets either, so the endpoints that upgrade to one
+// check the page's Origin with `origin_allowed` instead.
+
+use actix_web::body::MessageBody;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method};
+use actix_web::middleware::Next;
+use actix_web::{get, web, Error, HttpMessage, HttpRequest, HttpResponse};
+use crate::auth::{constant_time_eq, random_token, Session};
+use serde_json::json;
+use std::env;
+
+pub const CSRF_COOKIE: &str = "repopatch_csrf";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+pub struct CsrfConfig {
+    pub enabled: bool,
+}
+
+impl CsrfConfig {
+    pub fn from_env(default: bool) -> Self {
+        let enabled = match env::var("CSRF_PROTECTION").map(|v| v.to_lowercase()) {
+            Ok(v) if v == "on" || v == "true" => true,
+            Ok(v) if v == "off" || v == "false" => false,
+            _ => default,
+        };
+        CsrfConfig { enabled }
+    }
+}
+
+/// Whether an upgrade comes from the server's own origin or an allowed one.
+/// Browsers always send Origin; clients without one aren't driven by a page.
+pub fn origin_allowed(req: &HttpRequest, allowed: &[String]) -> bool {
+    let Some(origin) = req.headers().get(header::ORIGIN) else { return true };
+    let Ok(origin) = origin.to_str() else { return false };
+    let info = req.connection_info();
+    origin == format!("{}://{}", info.scheme(), info.host()) || allowed.iter().any(|a| a == origin)
+}
+
+/// Middleware rejecting state-changing requests without a matching token.
+pub async fn verify(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let enabled = req.app_data::<web::Data<CsrfConfig>>().is_some_and(|c| c.enabled);
+    let safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    // auth::authenticate ran first and marked the session
+    let bearer = req.extensions().get::<Session>().is_some_and(|s| s.bearer);
+    if !enabled || safe || bearer {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+
+    let header = req.headers().get(CSRF_HEADER).and_then(|v| v.to_str().ok()).unwrap_or("");
+    let valid = req
+        .cookie(CSRF_COOKIE)
+        .is_some_and(|c| !header.is_empty() && constant_time_eq(c.value().as_bytes(), header.as_bytes()));
+    if valid {
+        Ok(next.call(req).await?.map_into_left_body())
+    } else {
+        log::warn!("Rejected {} {}: missing or invalid CSRF token", req.method(), req.path());
+        let response = HttpResponse::Forbidden().json(json!({
+            "success": false,
+            "code": "CSRF_MISMATCH",
+            "error": format!("Missing or invalid {} header; fetch one from /api/csrf", CSRF_HEADER)
+        }));
+        Ok(req.into_response(response).map_into_right_body())
+    }
+}
+
+#[get("/api/csrf")]
+pub async fn issue_token(req: HttpRequest, config: web::Data<CsrfConfig>) -> HttpResponse {
+    // Keep an existing token so concurrent tabs don't invalidate each other
+    let token = req.cookie(CSRF_COOKIE).map(|c| c.value().to_string()).unwrap_or_else(random_token);
+    let cookie = Cookie::build(CSRF_COOKIE, token.clone())
+        .path("/")
+        .secure(req.connection_info().scheme() == "https")
+        .same_site(SameSite::Strict)
+        .finish();
+    HttpResponse::Ok().cookie(cookie).json(json!({
+        "success": true,
+        "enabled": config.enabled,
+        "csrfToken": token
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{middleware, post, App};
+
+    #[post("/api/change")]
+    async fn change() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    // Stands in for auth::authenticate: a valid token yields a bearer session,
+    // anything else falls back to a cookie session
+    async fn authenticate(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+        let mut session = Session::for_test("alice", false, false);
+        session.bearer = req.headers().get("Authorization").is_some_and(|v| v == "Bearer valid");
+        req.extensions_mut().insert(session);
+        next.call(req).await
+    }
+
+    #[actix_web::test]
+    async fn only_authenticated_bearer_tokens_skip_the_check() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(CsrfConfig { enabled: true }))
+                .wrap(middleware::from_fn(verify))
+                .wrap(middleware::from_fn(authenticate))
+                .service(change),
+        )
+        .await;
+
+        let valid = TestRequest::post().uri("/api/change").insert_header(("Authorization", "Bearer valid")).to_request();
+        assert_eq!(call_service(&app, valid).await.status(), 200);
+        let bogus = TestRequest::post()
+            .uri("/api/change")
+            .insert_header(("Authorization", "Bearer bogus"))
+            .cookie(Cookie::new("repopatch_session", "abc"))
+            .to_request();
+        assert_eq!(call_service(&app, bogus).await.status(), 403);
+        let echoed = TestRequest::post()
+            .uri("/api/change")
+            .cookie(Cookie::new(CSRF_COOKIE, "token"))
+            .insert_header((CSRF_HEADER, "token"))
+            .to_request();
+        assert_eq!(call_service(&app, echoed).await.status(), 200);
+        let mismatched = TestRequest::post()
+            .uri("/api/change")
+            .cookie(Cookie::new(CSRF_COOKIE, "token"))
+            .insert_header((CSRF_HEADER, "other"))
+            .to_request();
+        assert_eq!(call_service(&app, mismatched).await.status(), 403);
+    }
+
+    fn upgrade(origin: Option<&str>) -> HttpRequest {
+        let req = TestRequest::get().uri("/api/presence?client=a&root=/srv").insert_header((header::HOST, "repopatch.local:3000"));
+        match origin {
+            Some(origin) => req.insert_header((header::ORIGIN, origin)).to_http_request(),
+            None => req.to_http_request(),
+        }
+    }
+
+    #[test]
+    fn only_own_and_allowed_origins_open_sockets() {
+        let allowed = ["https://ui.example.com".to_string()];
+        assert!(origin_allowed(&upgrade(Some("http://repopatch.local:3000")), &allowed));
+        assert!(origin_allowed(&upgrade(Some("https://ui.example.com")), &allowed));
+        assert!(origin_allowed(&upgrade(None), &allowed), "not a browser");
+        assert!(!origin_allowed(&upgrade(Some("https://evil.example.com")), &allowed));
+        assert!(!origin_allowed(&upgrade(Some("http://repopatch.local:3000.evil.example.com")), &allowed));
+        assert!(!origin_allowed(&upgrade(Some("null")), &allowed));
+    }
+}
@@ -0,0 +1,276 @@
+//! File-level dependency graphs: which files of the tree each file imports,
+//! from Rust `mod`/`use`, TypeScript/JavaScript imports and `require()`,
+//! Python imports and C `#include`s. Module names that don't resolve to a
+//! file in the tree (packages, the standard library) are reported as
+//! external.
+
+use crate::moves::{resolve_relative, TS_EXTENSIONS};
+use crate::store::{dir_of, join_path, normalize_path, FileStore};
+use crate::syntax::{self, Language};
+use crate::tree::walk_files;
+use ignore::gitignore::Gitignore;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::sync::LazyLock;
+
+/// Larger files are not parsed for imports.
+const MAX_SOURCE_BYTES: u64 = 1024 * 1024;
+const C_EXTENSIONS: &[&str] = &["c", "h", "cc", "cpp", "cxx", "hpp", "hh", "m", "mm"];
+
+static INCLUDE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?m)^\s*#\s*(?:include|import)\s*(?:"([^"]+)"|<([^>]+)>)"#).expect("include pattern must compile"));
+
+/// What one file depends on.
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct FileDeps {
+    /// Store paths of the files it imports.
+    pub imports: BTreeSet<String>,
+    /// Modules and packages outside the tree, by their top-level name.
+    pub external: BTreeSet<String>,
+}
+
+/// Dependencies of every parsed file below a folder.
+#[derive(Debug, Default, Clone)]
+pub struct DependencyGraph {
+    pub files: BTreeMap<String, FileDeps>,
+}
+
+impl DependencyGraph {
+    /// The files in the graph that import `path`.
+    pub fn dependents(&self, path: &str) -> Vec<&str> {
+        self.files.iter().filter(|(_, deps)| deps.imports.contains(path)).map(|(file, _)| file.as_str()).collect()
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.files.values().map(|deps| deps.imports.len()).sum()
+    }
+}
+
+/// Builds the graph for the non-ignored files below `dir`. Imports are
+/// resolved against every non-ignored file of the store.
+pub fn dependency_graph(store: &dyn FileStore, ig: &Gitignore, dir: &str) -> Result<DependencyGraph, String> {
+    let mut files = HashSet::new();
+    walk_files(store, "", ig, &mut |path| {
+        files.insert(path.to_string());
+    })?;
+    let resolver = Resolver { files: &files };
+    let mut graph = DependencyGraph::default();
+    let mut paths: Vec<&String> = files.iter().filter(|p| dir.is_empty() || p.starts_with(&format!("{}/", dir.trim_end_matches('/')))).collect();
+    paths.sort();
+    for path in paths {
+        if store.file_size(path).is_none_or(|size| size > MAX_SOURCE_BYTES) {
+            continue;
+        }
+        let Ok(data) = store.read(path) else { continue };
+        let Ok(source) = String::from_utf8(data) else { continue };
+        if let Some(deps) = resolver.file_deps(path, &source)? {
+            graph.files.insert(path.clone(), deps);
+        }
+    }
+    Ok(graph)
+}
+
+struct Resolver<'a> {
+    files: &'a HashSet<String>,
+}
+
+impl Resolver<'_> {
+    // None for files in languages without imports we understand
+    fn file_deps(&self, path: &str, source: &str) -> Result<Option<FileDeps>, String> {
+        let mut deps = FileDeps::default();
+        match Language::from_path(path) {
+            Some(Language::Rust) => {
+                let tree = syntax::parse(Language::Rust, source)?;
+                self.rust(path, &syntax::rust_modules(&tree, source), &mut deps);
+            }
+            Some(language @ (Language::TypeScript | Language::Tsx)) => {
+                let tree = syntax::parse(language, source)?;
+                for import in syntax::imports(language, &tree, source) {
+                    self.typescript(path, &source[import.module], &mut deps);
+                }
+            }
+            Some(Language::Python) => {
+                let tree = syntax::parse(Language::Python, source)?;
+                for import in syntax::imports(Language::Python, &tree, source) {
+                    let names: Vec<&str> = import.names.iter().map(|n| &source[n.clone()]).collect();
+                    self.python(path, &source[import.module], &names, &mut deps);
+                }
+            }
+            None if path.rsplit_once('.').is_some_and(|(_, e)| C_EXTENSIONS.contains(&e.to_lowercase().as_str())) => {
+                for captures in INCLUDE.captures_iter(source) {
+                    self.include(path, captures.get(1).or(captures.get(2)).map_or("", |m| m.as_str()), captures.get(1).is_some(), &mut deps);
+                }
+            }
+            None => return Ok(None),
+        }
+        deps.imports.remove(path);
+        Ok(Some(deps))
+    }
+
+    fn first_file(&self, candidates: impl IntoIterator<Item = String>) -> Option<String> {
+        candidates.into_iter().find(|c| self.files.contains(c))
+    }
+
+    fn rust(&self, path: &str, modules: &syntax::RustModules, deps: &mut FileDeps) {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        let children_dir = if matches!(name, "lib.rs" | "main.rs" | "mod.rs") {
+            dir_of(path).to_string()
+        } else {
+            path.trim_end_matches(".rs").to_string()
+        };
+        for module in &modules.mods {
+            let dir = join_path(&children_dir, module);
+            if let Some(file) = self.first_file([format!("{}.rs", dir), join_path(&dir, "mod.rs")]) {
+                deps.imports.insert(file);
+            }
+        }
+
+        // The crate root is the nearest folder above with a lib.rs or main.rs
+        let mut root = dir_of(path);
+        let root = loop {
+            if self.files.contains(&join_path(root, "lib.rs")) || self.files.contains(&join_path(root, "main.rs")) {
+                break Some(root);
+            }
+            if root.is_empty() {
+                break None;
+            }
+            root = dir_of(root);
+        };
+        let Some(root) = root else {
+            for segments in &modules.uses {
+                deps.external.extend(segments.first().filter(|s| !matches!(s.as_str(), "crate" | "self" | "super")).cloned());
+            }
+            return;
+        };
+        let relative = path.strip_prefix(root).unwrap_or(path).trim_start_matches('/').trim_end_matches(".rs");
+        let mut own: Vec<&str> = relative.split('/').filter(|s| !s.is_empty()).collect();
+        if own.last().is_some_and(|s| matches!(*s, "lib" | "main" | "mod")) {
+            own.pop();
+        }
+
+        for segments in &modules.uses {
+            let Some(first) = segments.first() else { continue };
+            let module: Option<Vec<&str>> = match first.as_str() {
+                "crate" => Some(segments[1..].iter().map(String::as_str).collect()),
+                "self" | "super" => {
+                    let mut base = own.clone();
+                    let supers = segments.iter().take_while(|s| *s == "super").count();
+                    for _ in 0..supers {
+                        base.pop();
+                    }
+                    let skip = if first == "self" { 1 } else { supers };
+                    base.extend(segments[skip..].iter().map(String::as_str));
+                    Some(base)
+                }
+                // A name is a module of the current one or else a crate
+                _ => {
+                    let mut base = own.clone();
+                    base.extend(segments.iter().map(String::as_str));
+                    self.rust_module(root, &base[..own.len() + 1]).map(|_| base)
+                }
+            };
+            match module.and_then(|m| (0..=m.len()).rev().find_map(|k| self.rust_module(root, &m[..k]))) {
+                Some(file) => {
+                    deps.imports.insert(file);
+                }
+                None if !matches!(first.as_str(), "crate" | "self" | "super") => {
+                    deps.external.insert(first.clone());
+                }
+                None => {}
+            }
+        }
+    }
+
+    // The file defining the module `parts` of the crate rooted at `root`
+    fn rust_module(&self, root: &str, parts: &[&str]) -> Option<String> {
+        if parts.is_empty() {
+            return self.first_file([join_path(root, "lib.rs"), join_path(root, "main.rs")]);
+        }
+        let dir = join_path(root, &parts.join("/"));
+        self.first_file([format!("{}.rs", dir), join_path(&dir, "mod.rs")])
+    }
+
+    fn typescript(&self, path: &str, spec: &str, deps: &mut FileDeps) {
+        if !(spec == "." || spec == ".." || spec.starts_with("./") || spec.starts_with("../")) {
+            if spec.starts_with("node:") || spec.is_empty() {
+                return;
+            }
+            let package = match spec.strip_prefix('@') {
+                Some(scoped) => format!("@{}", scoped.splitn(3, '/').take(2).collect::<Vec<_>>().join("/")),
+                None => spec.split('/').next().unwrap_or(spec).to_string(),
+            };
+            deps.external.insert(package);
+            return;
+        }
+        let Some(target) = normalize_path(&join_path(dir_of(path), spec)) else { return };
+        let mut candidates = vec![target.clone()];
+        // ESM-style `./a.js` imports name the compiled file of a.ts
+        if let Some(stem) = target.strip_suffix(".js").or_else(|| target.strip_suffix(".mjs")) {
+            candidates.extend([".ts", ".tsx", ".mts"].iter().map(|e| format!("{}{}", stem, e)));
+        }
+        candidates.extend(TS_EXTENSIONS.iter().map(|e| format!("{}{}", target, e)));
+        candidates.extend(TS_EXTENSIONS.iter().map(|e| join_path(&target, &format!("index{}", e))));
+        if let Some(file) = self.first_file(candidates) {
+            deps.imports.insert(file);
+        }
+    }
+
+    fn python(&self, path: &str, module: &str, names: &[&str], deps: &mut FileDeps) {
+        let bases: Vec<Vec<String>> = if module.starts_with('.') {
+            resolve_relative(path, module).into_iter().collect()
+        } else {
+            let parts: Vec<String> = module.split('.').map(String::from).collect();
+            // The script's folder, the root and a src/ layout
+            let mut roots = vec![dir_of(path), "", "src"];
+            roots.dedup();
+            roots
+                .into_iter()
+                .map(|root| root.split('/').filter(|s| !s.is_empty()).map(String::from).chain(parts.iter().cloned()).collect())
+                .collect()
+        };
+        let module_file = |parts: &[String]| {
+            let stem = parts.join("/");
+            self.first_file([format!("{}.py", stem), format!("{}.pyi", stem), join_path(&stem, "__init__.py")])
+        };
+        for base in bases {
+            // `from package import module` imports the submodules too
+            let submodules: Vec<String> = names
+                .iter()
+                .filter_map(|name| {
+                    let mut submodule = base.clone();
+                    submodule.push(name.to_string());
+                    module_file(&submodule)
+                })
+                .collect();
+            let package = module_file(&base);
+            if package.is_none() && submodules.is_empty() {
+                continue;
+            }
+            deps.imports.extend(package);
+            deps.imports.extend(submodules);
+            return;
+        }
+        if !module.starts_with('.') {
+            deps.external.insert(module.split('.').next().unwrap_or(module).to_string());
+        }
+    }
+
+    fn include(&self, path: &str, header: &str, quoted: bool, deps: &mut FileDeps) {
+        let mut candidates = Vec::new();
+        if quoted {
+            candidates.extend(normalize_path(&join_path(dir_of(path), header)));
+        }
+        candidates.extend(normalize_path(header));
+        candidates.extend(normalize_path(&join_path("include", header)));
+        match self.first_file(candidates) {
+            Some(file) => {
+                deps.imports.insert(file);
+            }
+            None if !quoted => {
+                deps.external.insert(header.to_string());
+            }
+            None => {}
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! Compiler and linter output turned into diagnostics a UI can show inline:
+//! cargo's JSON messages, tsc's `file(line,col): error TS…` lines and the
+//! `file:line:col: severity: message` lines of gcc, clang, mypy and others.
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::sync::LazyLock;
+
+const FORMATS: &[&str] = &["cargo", "tsc", "gcc"];
+
+/// A check command as written in the config file.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CheckerConfig {
+    pub name: String,
+    /// Program and arguments, run in the checked folder.
+    pub command: Vec<String>,
+    /// How to read its output: `cargo`, `tsc` or `gcc`.
+    pub format: String,
+    /// Only run it in folders holding this file, e.g. `tsconfig.json`.
+    #[serde(default)]
+    pub when: Option<String>,
+}
+
+/// The check commands of a server: the configured ones, or without any,
+/// cargo check for Rust and tsc for TypeScript.
+pub struct Checkers(Vec<CheckerConfig>);
+
+impl Checkers {
+    pub fn new(configured: Vec<CheckerConfig>) -> Result<Self, String> {
+        if let Some(checker) = configured.iter().find(|c| !FORMATS.contains(&c.format.as_str())) {
+            return Err(format!("Checker {}: unknown format {}; use {}", checker.name, checker.format, FORMATS.join(", ")));
+        }
+        if let Some(checker) = configured.iter().find(|c| c.command.is_empty()) {
+            return Err(format!("Checker {}: the command is empty", checker.name));
+        }
+        if !configured.is_empty() {
+            return Ok(Checkers(configured));
+        }
+        let checker = |name: &str, command: &[&str], format: &str, when: &str| CheckerConfig {
+            name: name.to_string(),
+            command: command.iter().map(|c| c.to_string()).collect(),
+            format: format.to_string(),
+            when: Some(when.to_string()),
+        };
+        Ok(Checkers(vec![
+            checker("cargo", &["cargo", "check", "--all-targets", "--message-format=json"], "cargo", "Cargo.toml"),
+            checker("tsc", &["npx", "--no-install", "tsc", "--noEmit", "--pretty", "false"], "tsc", "tsconfig.json"),
+        ]))
+    }
+
+    /// The checkers for a folder, given whether a file exists in it.
+    pub fn for_dir(&self, exists: impl Fn(&str) -> bool) -> Vec<&CheckerConfig> {
+        self.0.iter().filter(|c| c.when.as_deref().is_none_or(&exists)).collect()
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// As the tool printed it; None for messages about no file in particular.
+    pub file: Option<String>,
+    /// 1-based.
+    pub line: Option<usize>,
+    /// 1-based.
+    pub column: Option<usize>,
+    /// `error`, `warning`, `note` or `help`.
+    pub severity: String,
+    pub message: String,
+    /// The tool's code for it, e.g. `E0308` or `TS2322`.
+    pub code: Option<String>,
+    /// Help and notes attached to it.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub notes: Vec<String>,
+}
+
+/// The diagnostics in `output` as a tool with `format` prints them, in order
+/// and without repeats.
+pub fn parse(format: &str, output: &str) -> Result<Vec<Diagnostic>, String> {
+    let mut diagnostics = match format {
+        "cargo" => parse_cargo(output),
+        "tsc" => parse_tsc(output),
+        "gcc" => parse_gcc(output),
+        other => return Err(format!("Unknown diagnostics format {}: use {}", other, FORMATS.join(", "))),
+    };
+    let mut seen = std::collections::HashSet::new();
+    diagnostics.retain(|d| seen.insert((d.file.clone(), d.line, d.column, d.message.clone())));
+    Ok(diagnostics)
+}
+
+fn severity(level: &str) -> String {
+    match level {
+        "fatal error" | "failure-note" => "error",
+        "info" => "note",
+        l if l.starts_with("error") => "error",
+        l => l,
+    }
+    .to_string()
+}
+
+fn parse_cargo(output: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    for line in output.lines() {
+        let Ok(json) = serde_json::from_str::<serde_json::Value>(line) else {
+            continue;
+        };
+        if json["reason"] != "compiler-message" {
+            continue;
+        }
+        let message = &json["message"];
+        let level = message["level"].as_str().unwrap_or("error");
+        let spans = message["spans"].as_array().map(Vec::as_slice).unwrap_or_default();
+        let span = spans.iter().find(|s| s["is_primary"] == true).or(spans.first());
+        // Summaries like "aborting due to 2 previous errors" point nowhere
+        if span.is_none() && level != "error" {
+            continue;
+        }
+        let text = message["message"].as_str().unwrap_or_default();
+        if text.starts_with("aborting due to") || text.ends_with("warnings emitted") || text.ends_with("warning emitted") {
+            continue;
+        }
+        let number = |key: &str| span.and_then(|s| s[key].as_u64()).map(|n| n as usize);
+        let notes = message["children"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|c| Some(format!("{}: {}", c["level"].as_str()?, c["message"].as_str()?)))
+            .collect();
+        diagnostics.push(Diagnostic {
+            file: span.and_then(|s| s["file_name"].as_str()).map(str::to_string),
+            line: number("line_start"),
+            column: number("column_start"),
+            severity: severity(level),
+            message: text.to_string(),
+            code: message["code"]["code"].as_str().map(str::to_string),
+            notes,
+        });
+    }
+    diagnostics
+}
+
+static TSC: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(?:(.+?)\((\d+),(\d+)\): )?(error|warning|message) (TS\d+): (.*)$").expect("tsc pattern must compile")
+});
+
+fn parse_tsc(output: &str) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    for line in output.lines() {
+        if let Some(m) = TSC.captures(line) {
+            let number = |i: usize| m.get(i).and_then(|n| n.as_str().parse().ok());
+            diagnostics.push(Diagnostic {
+                file: m.get(1).map(|f| f.as_str().to_string()),
+                line: number(2),
+                column: number(3),
+                severity: if &m[4] == "message" { "note".to_string() } else { m[4].to_string() },
+                message: m[6].to_string(),
+                code: Some(m[5].to_string()),
+                notes: Vec::new(),
+            });
+        } else if let Some(last) = diagnostics.last_mut().filter(|_| line.starts_with(' ')) {
+            // Elaborations of the message above, indented
+            last.message.push('\n');
+            last.message.push_str(line.trim());
+        }
+    }
+    diagnostics
+}
+
+static GCC: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(.+?):(\d+):(?:(\d+):)?\s*(fatal error|error|warning|note|info|help):\s*(.*?)(?:\s+\[([\w\-./:]+)\])?$").expect("gcc pattern must compile")
+});
+
+fn parse_gcc(output: &str) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    for line in output.lines() {
+        let Some(m) = GCC.captures(line) else {
+            continue;
+        };
+        let severity = severity(&m[4]);
+        // A note right after a diagnostic belongs to it
+        if severity == "note" {
+            if let Some(last) = diagnostics.last_mut().filter(|d| d.severity != "note") {
+                last.notes.push(format!("note: {}", &m[5]));
+                continue;
+            }
+        }
+        diagnostics.push(Diagnostic {
+            file: Some(m[1].to_string()),
+            line: m[2].parse().ok(),
+            column: m.get(3).and_then(|c| c.as_str().parse().ok()),
+            severity,
+            message: m[5].to_string(),
+            code: m.get(6).map(|c| c.as_str().to_string()),
+            notes: Vec::new(),
+        });
+    }
+    diagnostics
+}
@@ -0,0 +1,268 @@
+//! Unified diff generation, the inverse of [`crate::patch`]: turns old and
+//! new file content into patch text the engine can apply.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use similar::{ChangeTag, DiffOp, DiffTag, TextDiff};
+
+/// Lines of unchanged context around each hunk.
+pub const DEFAULT_CONTEXT_RADIUS: usize = 3;
+
+/// Returns a `--- a/path` / `+++ b/path` diff from `old` to `new`, or an
+/// empty string when they are equal.
+pub fn unified_diff(path: &str, old: &str, new: &str) -> String {
+    if old == new {
+        return String::new();
+    }
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(DEFAULT_CONTEXT_RADIUS)
+        .header(&format!("a/{}", path), &format!("b/{}", path))
+        .to_string()
+}
+
+/// Returns a git-style rename of `old_path` to `new_path`, followed by the
+/// hunks turning `old` into `new` when the content changes too.
+pub fn rename_diff(old_path: &str, new_path: &str, old: &str, new: &str) -> String {
+    let mut out = format!("diff --git a/{0} b/{1}\nrename from {0}\nrename to {1}\n", old_path, new_path);
+    if old != new {
+        let hunks = TextDiff::from_lines(old, new)
+            .unified_diff()
+            .context_radius(DEFAULT_CONTEXT_RADIUS)
+            .header(&format!("a/{}", old_path), &format!("b/{}", new_path))
+            .to_string();
+        out.push_str(&hunks);
+    }
+    out
+}
+
+/// One file's content before and after a change; `None` means the file did
+/// not exist on that side.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileChange {
+    pub path: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// Commit-style metadata for [`format_patch`].
+#[derive(Debug, Clone)]
+pub struct PatchHeader {
+    /// `Name <email>`.
+    pub author: String,
+    pub date: DateTime<Utc>,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Returns a `diff --git` section per changed file, with the mode lines git
+/// writes for created and deleted files.
+pub fn git_diff(changes: &[FileChange]) -> String {
+    let mut out = String::new();
+    for change in changes {
+        let old = change.old.as_deref().unwrap_or_default();
+        let new = change.new.as_deref().unwrap_or_default();
+        if change.old.is_some() && change.new.is_some() && old == new {
+            continue;
+        }
+        out.push_str(&format!("diff --git a/{0} b/{0}\n", change.path));
+        let (old_header, new_header) = match (&change.old, &change.new) {
+            (None, _) => {
+                out.push_str("new file mode 100644\n");
+                ("/dev/null".to_string(), format!("b/{}", change.path))
+            }
+            (_, None) => {
+                out.push_str("deleted file mode 100644\n");
+                (format!("a/{}", change.path), "/dev/null".to_string())
+            }
+            _ => (format!("a/{}", change.path), format!("b/{}", change.path)),
+        };
+        if old != new {
+            let diff = TextDiff::from_lines(old, new);
+            out.push_str(&diff.unified_diff().context_radius(DEFAULT_CONTEXT_RADIUS).header(&old_header, &new_header).to_string());
+        }
+    }
+    out
+}
+
+// Helper function to count inserted and deleted lines for the diffstat
+fn line_counts(change: &FileChange) -> (usize, usize) {
+    let diff = TextDiff::from_lines(change.old.as_deref().unwrap_or_default(), change.new.as_deref().unwrap_or_default());
+    diff.iter_all_changes().fold((0, 0), |(ins, del), c| match c.tag() {
+        ChangeTag::Insert => (ins + 1, del),
+        ChangeTag::Delete => (ins, del + 1),
+        ChangeTag::Equal => (ins, del),
+    })
+}
+
+fn plural(count: usize, word: &str) -> String {
+    format!("{} {}{}", count, word, if count == 1 { "" } else { "s" })
+}
+
+/// Returns a `git diff --stat` style summary of `changes`.
+pub fn diffstat(changes: &[FileChange]) -> String {
+    let changes: Vec<&FileChange> = changes.iter().filter(|c| c.old != c.new).collect();
+    let width = changes.iter().map(|c| c.path.len()).max().unwrap_or(0);
+    let mut out = String::new();
+    let (mut insertions, mut deletions) = (0, 0);
+    for change in &changes {
+        let (ins, del) = line_counts(change);
+        insertions += ins;
+        deletions += del;
+        out.push_str(&format!(" {:width$} | {} {}{}\n", change.path, ins + del, "+".repeat(ins.min(40)), "-".repeat(del.min(40)), width = width));
+    }
+    out.push_str(&format!(" {} changed", plural(changes.len(), "file")));
+    if insertions > 0 {
+        out.push_str(&format!(", {}(+)", plural(insertions, "insertion")));
+    }
+    if deletions > 0 {
+        out.push_str(&format!(", {}(-)", plural(deletions, "deletion")));
+    }
+    out.push('\n');
+    out
+}
+
+/// Returns `changes` as a `git format-patch` style mail that `git am` can
+/// apply: headers, message, diffstat and the diff.
+pub fn format_patch(header: &PatchHeader, changes: &[FileChange]) -> String {
+    let changes: Vec<FileChange> = changes.iter().filter(|c| c.old != c.new).cloned().collect();
+    let mut out = format!(
+        "From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001\nFrom: {}\nDate: {}\nSubject: [PATCH] {}\n\n",
+        header.author,
+        header.date.to_rfc2822(),
+        header.subject
+    );
+    if !header.body.trim().is_empty() {
+        out.push_str(header.body.trim_end());
+        out.push_str("\n\n");
+    }
+    out.push_str("---\n");
+    out.push_str(&diffstat(&changes));
+    out.push('\n');
+    out.push_str(&git_diff(&changes));
+    out.push_str(&format!("-- \nrepopatch {}\n", env!("CARGO_PKG_VERSION")));
+    out
+}
+
+/// How a line in a side-by-side view differs from the other side.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ViewKind {
+    Equal,
+    Delete,
+    Insert,
+    /// Paired with a changed line on the other side; `spans` marks the
+    /// characters that differ.
+    Change,
+    /// Stands for `skipped` unchanged lines left out around the changes.
+    Skip,
+}
+
+/// One line of one side of a side-by-side view.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ViewLine {
+    /// 1-based line number on this side.
+    pub number: usize,
+    pub text: String,
+    pub kind: ViewKind,
+    /// `[start, end)` character ranges that differ from the paired line.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub spans: Vec<(usize, usize)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<usize>,
+}
+
+/// Old and new lines aligned row by row; `None` is a gap opposite an
+/// inserted or deleted line.
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+pub struct SideBySide {
+    pub old: Vec<Option<ViewLine>>,
+    pub new: Vec<Option<ViewLine>>,
+}
+
+impl SideBySide {
+    fn push(&mut self, old: Option<ViewLine>, new: Option<ViewLine>) {
+        self.old.push(old);
+        self.new.push(new);
+    }
+}
+
+fn view_line(number: usize, text: &str, kind: ViewKind, spans: Vec<(usize, usize)>) -> ViewLine {
+    ViewLine { number: number + 1, text: text.trim_end_matches(['\n', '\r']).to_string(), kind, spans, skipped: None }
+}
+
+type Spans = Vec<(usize, usize)>;
+
+// Helper function to find the characters that differ between two paired lines
+fn char_spans(old: &str, new: &str) -> (Spans, Spans) {
+    let (mut old_spans, mut new_spans) = (Vec::new(), Vec::new());
+    for op in TextDiff::from_chars(old, new).ops() {
+        let (_, old_range, new_range) = op.as_tag_tuple();
+        if op.tag() != DiffTag::Equal {
+            if !old_range.is_empty() {
+                old_spans.push((old_range.start, old_range.end));
+            }
+            if !new_range.is_empty() {
+                new_spans.push((new_range.start, new_range.end));
+            }
+        }
+    }
+    (old_spans, new_spans)
+}
+
+/// Aligns `old` and `new` for a side-by-side view with intra-line
+/// differences. With `context`, runs of unchanged lines further than that
+/// from a change collapse into a single [`ViewKind::Skip`] row.
+pub fn side_by_side(old: &str, new: &str, context: Option<usize>) -> SideBySide {
+    let diff = TextDiff::from_lines(old, new);
+    let (old_lines, new_lines) = (diff.old_slices(), diff.new_slices());
+    let mut view = SideBySide::default();
+    let ops = diff.ops();
+    for (i, op) in ops.iter().enumerate() {
+        match *op {
+            DiffOp::Equal { old_index, new_index, len } => {
+                // Leading context follows a change, trailing context precedes one
+                let keep = context.unwrap_or(len);
+                let head = if i == 0 { 0 } else { keep.min(len) };
+                let tail = if i + 1 == ops.len() { 0 } else { keep.min(len) };
+                let collapse = context.is_some() && head + tail < len;
+                for k in 0..len {
+                    if collapse && k == head {
+                        let skip = |index: usize| ViewLine { number: index + 1, text: String::new(), kind: ViewKind::Skip, spans: Vec::new(), skipped: Some(len - head - tail) };
+                        view.push(Some(skip(old_index + k)), Some(skip(new_index + k)));
+                    }
+                    if collapse && k >= head && k < len - tail {
+                        continue;
+                    }
+                    let (o, m) = (old_index + k, new_index + k);
+                    view.push(Some(view_line(o, old_lines[o], ViewKind::Equal, Vec::new())), Some(view_line(m, new_lines[m], ViewKind::Equal, Vec::new())));
+                }
+            }
+            DiffOp::Delete { old_index, old_len, .. } => {
+                for (o, line) in old_lines.iter().enumerate().skip(old_index).take(old_len) {
+                    view.push(Some(view_line(o, line, ViewKind::Delete, Vec::new())), None);
+                }
+            }
+            DiffOp::Insert { new_index, new_len, .. } => {
+                for (m, line) in new_lines.iter().enumerate().skip(new_index).take(new_len) {
+                    view.push(None, Some(view_line(m, line, ViewKind::Insert, Vec::new())));
+                }
+            }
+            DiffOp::Replace { old_index, old_len, new_index, new_len } => {
+                for k in 0..old_len.max(new_len) {
+                    let (o, m) = (old_index + k, new_index + k);
+                    match (k < old_len, k < new_len) {
+                        (true, true) => {
+                            let (old_spans, new_spans) = char_spans(old_lines[o].trim_end_matches(['\n', '\r']), new_lines[m].trim_end_matches(['\n', '\r']));
+                            view.push(Some(view_line(o, old_lines[o], ViewKind::Change, old_spans)), Some(view_line(m, new_lines[m], ViewKind::Change, new_spans)));
+                        }
+                        (true, false) => view.push(Some(view_line(o, old_lines[o], ViewKind::Delete, Vec::new())), None),
+                        (false, true) => view.push(None, Some(view_line(m, new_lines[m], ViewKind::Insert, Vec::new()))),
+                        (false, false) => {}
+                    }
+                }
+            }
+        }
+    }
+    view
+}
@@ -0,0 +1,697 @@
+//! Text of the PDF and Word documents kept in a repository, such as specs
+//! and design notes, for reading and packing next to the code. Built with
+//! the `documents` feature; without it no document has text.
+//!
+//! Extraction is deliberately basic. PDFs give the strings their pages
+//! show, decoded through the fonts' ToUnicode maps where they have one,
+//! which is good for documents written by word processors and typesetters
+//! but yields nothing for scans or composite fonts without such a map.
+//! DOCX files give the paragraphs of `word/document.xml`.
+
+#[cfg(feature = "documents")]
+use regex::bytes::Regex;
+#[cfg(feature = "documents")]
+use std::borrow::Cow;
+#[cfg(feature = "documents")]
+use std::collections::{HashMap, HashSet};
+#[cfg(feature = "documents")]
+use std::sync::LazyLock;
+
+/// Documents bigger than this are not read.
+pub const MAX_DOCUMENT_BYTES: u64 = 16 * 1024 * 1024;
+
+// How many bytes the streams of one PDF may inflate to, all together
+#[cfg(feature = "documents")]
+const MAX_INFLATED_BYTES: u64 = MAX_DOCUMENT_BYTES * 4;
+
+/// Whether this build extracts text from documents.
+pub const ENABLED: bool = cfg!(feature = "documents");
+
+/// The kind of document `path` names, `pdf` or `docx`, when this build can
+/// extract its text.
+pub fn document_kind(path: &str) -> Option<&'static str> {
+    if !ENABLED {
+        return None;
+    }
+    let ext = path.rsplit_once('.').map(|(_, ext)| ext.to_lowercase())?;
+    match ext.as_str() {
+        "pdf" => Some("pdf"),
+        "docx" => Some("docx"),
+        _ => None,
+    }
+}
+
+/// The text of the document `data` read from `path`; None for files that
+/// aren't documents or when this build doesn't extract them.
+pub fn extract_text(path: &str, data: &[u8]) -> Option<Result<String, String>> {
+    #[cfg(feature = "documents")]
+    {
+        let text = match document_kind(path)? {
+            "pdf" => pdf_text(data),
+            _ => docx_text(data),
+        };
+        Some(text.map(|t| tidy(&t)))
+    }
+    #[cfg(not(feature = "documents"))]
+    {
+        let _ = (path, data);
+        None
+    }
+}
+
+// Trims every line and collapses runs of blank lines
+#[cfg(feature = "documents")]
+fn tidy(text: &str) -> String {
+    let mut tidy = String::with_capacity(text.len());
+    let mut blank = true;
+    for line in text.lines().map(str::trim_end) {
+        if line.trim().is_empty() {
+            if !blank {
+                tidy.push('\n');
+            }
+            blank = true;
+        } else {
+            tidy.push_str(line);
+            tidy.push('\n');
+            blank = false;
+        }
+    }
+    tidy.truncate(tidy.trim_end().len());
+    if !tidy.is_empty() {
+        tidy.push('\n');
+    }
+    tidy
+}
+
+#[cfg(feature = "documents")]
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack.get(from..)?.windows(needle.len()).position(|w| w == needle).map(|i| i + from)
+}
+
+// The strings shown by the content streams of a PDF, in file order
+#[cfg(feature = "documents")]
+fn pdf_text(data: &[u8]) -> Result<String, String> {
+    if !data.starts_with(b"%PDF") {
+        return Err("Not a PDF file".to_string());
+    }
+    if find(data, b"/Encrypt", 0).is_some() {
+        return Err("The PDF is encrypted".to_string());
+    }
+    let objects = pdf_objects(data);
+    let mut budget = MAX_INFLATED_BYTES;
+    let compressed = compressed_objects(&objects, &mut budget)?;
+    // Later definitions replace earlier ones, as incremental updates do
+    let by_number: HashMap<u32, &PdfObject> = objects.iter().chain(&compressed).map(|o| (o.number, o)).collect();
+    let (fonts, cmaps) = pdf_fonts(&by_number, &mut budget)?;
+
+    let mut state = TextState { fonts: &fonts, font: None, unreadable: false };
+    let mut text = String::new();
+    for object in &objects {
+        let Some(raw) = object.stream else { continue };
+        if cmaps.contains(&object.number) {
+            continue;
+        }
+        if [&b"/Image"[..], b"/FontFile", b"/Length1", b"/XRef", b"/Metadata", b"/ObjStm"].iter().any(|k| find(&object.dict, k, 0).is_some()) {
+            continue;
+        }
+        if let Some(content) = decode_stream(&object.dict, raw, &mut budget)? {
+            text.push_str(&content_text(&content, &mut state));
+        }
+    }
+    if state.unreadable && text.trim().is_empty() {
+        return Err("The PDF's text is not extractable: its fonts don't say which characters they show".to_string());
+    }
+    Ok(text)
+}
+
+// One object of a PDF: its dictionary and, for a stream, the raw data
+#[cfg(feature = "documents")]
+struct PdfObject<'a> {
+    number: u32,
+    dict: Cow<'a, [u8]>,
+    stream: Option<&'a [u8]>,
+}
+
+// The next match of a needle at or after a position that only moves
+// forward, so each part of the file is searched once
+#[cfg(feature = "documents")]
+struct Next {
+    needle: &'static [u8],
+    found: Option<Option<usize>>,
+}
+
+#[cfg(feature = "documents")]
+impl Next {
+    fn new(needle: &'static [u8]) -> Self {
+        Next { needle, found: None }
+    }
+
+    fn from(&mut self, data: &[u8], at: usize) -> Option<usize> {
+        match self.found {
+            Some(Some(found)) if found >= at => Some(found),
+            Some(None) => None,
+            _ => *self.found.insert(find(data, self.needle, at)),
+        }
+    }
+}
+
+// Every `N G obj ... endobj` of a PDF, in one pass from start to end
+#[cfg(feature = "documents")]
+fn pdf_objects(data: &[u8]) -> Vec<PdfObject<'_>> {
+    let (mut obj, mut endobj, mut stream) = (Next::new(b"obj"), Next::new(b"endobj"), Next::new(b"stream"));
+    let mut objects = Vec::new();
+    let mut at = 0;
+    while let Some(keyword) = obj.from(data, at) {
+        at = keyword + 3;
+        if data[..keyword].ends_with(b"end") {
+            continue;
+        }
+        let Some(number) = object_number(&data[keyword.saturating_sub(24)..keyword]) else { continue };
+        let end = endobj.from(data, at).unwrap_or(data.len());
+        let start = stream.from(data, at).filter(|&s| s < end && !data[..s].ends_with(b"end"));
+        let body = start.and_then(|start| match data.get(start + 6..start + 8) {
+            Some(b"\r\n") => Some((start, start + 8)),
+            Some([b'\n', _]) | Some([b'\r', _]) => Some((start, start + 7)),
+            _ => None,
+        });
+        match body {
+            Some((start, body)) => {
+                let Some(stream_end) = find(data, b"endstream", body) else { break };
+                objects.push(PdfObject { number, dict: Cow::Borrowed(&data[at..start]), stream: Some(&data[body..stream_end]) });
+                at = stream_end + 9;
+            }
+            None => {
+                objects.push(PdfObject { number, dict: Cow::Borrowed(&data[at..end]), stream: None });
+                at = end;
+            }
+        }
+    }
+    objects
+}
+
+// The number of the object whose `obj` keyword follows `before`
+#[cfg(feature = "documents")]
+fn object_number(before: &[u8]) -> Option<u32> {
+    static HEADER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?-u)(?:^|[^0-9])([0-9]{1,9})\s+[0-9]{1,5}\s+$").expect("object header pattern must compile"));
+    let number = HEADER.captures(before)?.get(1)?;
+    std::str::from_utf8(number.as_bytes()).ok()?.parse().ok()
+}
+
+// The objects packed into object streams (PDF 1.5), which is where writers
+// put most dictionaries, fonts among them
+#[cfg(feature = "documents")]
+fn compressed_objects<'a>(objects: &[PdfObject<'a>], budget: &mut u64) -> Result<Vec<PdfObject<'a>>, String> {
+    static COUNT: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?-u)/N\s+([0-9]+)").expect("count pattern must compile"));
+    static FIRST: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?-u)/First\s+([0-9]+)").expect("first pattern must compile"));
+    let integer = |pattern: &Regex, dict: &[u8]| -> Option<usize> { std::str::from_utf8(pattern.captures(dict)?.get(1)?.as_bytes()).ok()?.parse().ok() };
+
+    let mut unpacked = Vec::new();
+    for object in objects {
+        let Some(raw) = object.stream else { continue };
+        if find(&object.dict, b"/ObjStm", 0).is_none() {
+            continue;
+        }
+        let (Some(count), Some(first)) = (integer(&COUNT, &object.dict), integer(&FIRST, &object.dict)) else { continue };
+        let Some(decoded) = decode_stream(&object.dict, raw, budget)? else { continue };
+        let Some(header) = decoded.get(..first) else { continue };
+        let numbers: Vec<usize> = header.split(u8::is_ascii_whitespace).filter_map(|n| std::str::from_utf8(n).ok()?.parse().ok()).collect();
+        let entries: Vec<(usize, usize)> = numbers.chunks_exact(2).take(count).map(|pair| (pair[0], pair[1])).collect();
+        for (index, &(number, offset)) in entries.iter().enumerate() {
+            let end = entries.get(index + 1).map_or(decoded.len(), |&(_, next)| first.saturating_add(next));
+            let Some(dict) = decoded.get(first.saturating_add(offset)..end) else { continue };
+            let Ok(number) = u32::try_from(number) else { continue };
+            unpacked.push(PdfObject { number, dict: Cow::Owned(dict.to_vec()), stream: None });
+        }
+    }
+    Ok(unpacked)
+}
+
+// The data of a stream, inflated when it is compressed; None for filters
+// other than Flate and for broken streams. What is inflated comes out of
+// `budget`, shared by all the streams of the document, and an error ends
+// the extraction once it runs out.
+#[cfg(feature = "documents")]
+fn decode_stream(dict: &[u8], raw: &[u8], budget: &mut u64) -> Result<Option<Vec<u8>>, String> {
+    use std::io::Read;
+
+    if find(dict, b"/FlateDecode", 0).is_some() {
+        let mut decoded = Vec::new();
+        if flate2::read::ZlibDecoder::new(raw).take(*budget + 1).read_to_end(&mut decoded).is_err() {
+            return Ok(None);
+        }
+        *budget = (*budget).checked_sub(decoded.len() as u64).ok_or_else(|| format!("The PDF inflates to more than {} MiB", MAX_INFLATED_BYTES >> 20))?;
+        Ok(Some(decoded))
+    } else if find(dict, b"/Filter", 0).is_some() {
+        Ok(None)
+    } else {
+        Ok(Some(raw.to_vec()))
+    }
+}
+
+// How a font's strings become text
+#[cfg(feature = "documents")]
+enum Font {
+    /// One byte per character, taken as Latin-1
+    Simple,
+    /// Through the font's ToUnicode map
+    Mapped(CMap),
+    /// A composite (CID) font without a ToUnicode map: its codes are glyph
+    /// numbers that say nothing about the characters
+    Unreadable,
+}
+
+// The fonts of the page resources by name, as `Tf` selects them, and the
+// ToUnicode streams among the objects
+#[cfg(feature = "documents")]
+type Fonts = (HashMap<Vec<u8>, Font>, HashSet<u32>);
+
+// The fonts of a PDF's objects. A name used for different fonts on
+// different pages keeps the first.
+#[cfg(feature = "documents")]
+fn pdf_fonts(objects: &HashMap<u32, &PdfObject>, budget: &mut u64) -> Result<Fonts, String> {
+    static FONT_DICT: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?s-u)/Font\s*<<(.*?)>>").expect("font dictionary pattern must compile"));
+    static FONT_REF: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?-u)/Font\s+([0-9]+)\s+[0-9]+\s+R").expect("font reference pattern must compile"));
+    static ENTRY: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?-u)/([^\s/<>\[\]()%]+)\s+([0-9]+)\s+[0-9]+\s+R").expect("entry pattern must compile"));
+    static TO_UNICODE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?-u)/ToUnicode\s+([0-9]+)\s+[0-9]+\s+R").expect("ToUnicode pattern must compile"));
+    let number = |m: Option<regex::bytes::Match>| -> Option<u32> { std::str::from_utf8(m?.as_bytes()).ok()?.parse().ok() };
+
+    let mut fonts = HashMap::new();
+    let mut cmaps = HashSet::new();
+    let mut numbers: Vec<u32> = objects.keys().copied().collect();
+    numbers.sort_unstable();
+    for object in numbers.iter().map(|n| objects[n]) {
+        let inline = FONT_DICT.captures_iter(&object.dict).filter_map(|c| c.get(1)).map(|m| m.as_bytes());
+        let referenced = FONT_REF.captures_iter(&object.dict).filter_map(|c| objects.get(&number(c.get(1))?)).map(|o| &o.dict[..]);
+        for resources in inline.chain(referenced) {
+            for entry in ENTRY.captures_iter(resources) {
+                let (Some(name), Some(font)) = (entry.get(1), number(entry.get(2))) else { continue };
+                if fonts.contains_key(name.as_bytes()) {
+                    continue;
+                }
+                let Some(font) = objects.get(&font) else { continue };
+                let to_unicode = number(TO_UNICODE.captures(&font.dict).and_then(|c| c.get(1)));
+                let cmap = match to_unicode.and_then(|n| objects.get(&n)).and_then(|o| Some((&o.dict, o.stream?))) {
+                    Some((dict, raw)) => decode_stream(dict, raw, budget)?,
+                    None => None,
+                };
+                let kind = match cmap {
+                    Some(cmap) => {
+                        cmaps.extend(to_unicode);
+                        Font::Mapped(CMap::parse(&cmap))
+                    }
+                    None if find(&font.dict, b"/Type0", 0).is_some() => Font::Unreadable,
+                    None => Font::Simple,
+                };
+                fonts.insert(name.as_bytes().to_vec(), kind);
+            }
+        }
+    }
+    Ok((fonts, cmaps))
+}
+
+// A ToUnicode CMap: which characters the codes of a font's strings stand for
+#[cfg(feature = "documents")]
+#[derive(Default)]
+struct CMap {
+    /// Code length in bytes and the codes of that length in use
+    codespace: Vec<(usize, u32, u32)>,
+    chars: HashMap<(usize, u32), String>,
+    /// Consecutive codes, mapped from a first character or one by one
+    ranges: Vec<(usize, u32, u32, RangeTarget)>,
+}
+
+#[cfg(feature = "documents")]
+enum RangeTarget {
+    From(Vec<u16>),
+    Each(Vec<String>),
+}
+
+#[cfg(feature = "documents")]
+impl CMap {
+    fn parse(data: &[u8]) -> Self {
+        static CODESPACE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?s-u)begincodespacerange(.*?)endcodespacerange").expect("codespace pattern must compile"));
+        static BFCHAR: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?s-u)beginbfchar(.*?)endbfchar").expect("bfchar pattern must compile"));
+        static BFRANGE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?s-u)beginbfrange(.*?)endbfrange").expect("bfrange pattern must compile"));
+        static HEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?-u)<([0-9A-Fa-f\s]*)>").expect("hex pattern must compile"));
+        static RANGE: LazyLock<Regex> = LazyLock::new(|| {
+            Regex::new(r"(?s-u)<([0-9A-Fa-f\s]*)>\s*<([0-9A-Fa-f\s]*)>\s*(?:<([0-9A-Fa-f\s]*)>|\[(.*?)\])").expect("range pattern must compile")
+        });
+        let hex = |block: &[u8]| -> Vec<Vec<u8>> { HEX.captures_iter(block).filter_map(|c| c.get(1)).map(|m| hex_bytes(m.as_bytes())).collect() };
+
+        let mut cmap = CMap::default();
+        for block in CODESPACE.captures_iter(data).filter_map(|c| c.get(1)) {
+            for pair in hex(block.as_bytes()).chunks_exact(2) {
+                if let (Some((len, low)), Some((_, high))) = (code(&pair[0]), code(&pair[1])) {
+                    cmap.codespace.push((len, low, high));
+                }
+            }
+        }
+        for block in BFCHAR.captures_iter(data).filter_map(|c| c.get(1)) {
+            for pair in hex(block.as_bytes()).chunks_exact(2) {
+                if let Some(source) = code(&pair[0]) {
+                    cmap.chars.insert(source, utf16_text(&pair[1]));
+                }
+            }
+        }
+        for block in BFRANGE.captures_iter(data).filter_map(|c| c.get(1)) {
+            for range in RANGE.captures_iter(block.as_bytes()) {
+                let bytes = |index: usize| range.get(index).map(|m| hex_bytes(m.as_bytes()));
+                let (Some((len, low)), Some((_, high))) = (bytes(1).and_then(|b| code(&b)), bytes(2).and_then(|b| code(&b))) else { continue };
+                if low > high {
+                    continue;
+                }
+                let target = match (bytes(3), range.get(4)) {
+                    (Some(first), _) => RangeTarget::From(first.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect()),
+                    (None, Some(list)) => RangeTarget::Each(hex(list.as_bytes()).iter().map(|b| utf16_text(b)).collect()),
+                    (None, None) => continue,
+                };
+                cmap.ranges.push((len, low, high, target));
+            }
+        }
+        // Without a codespace the lengths of the mapped codes say how long codes are
+        if cmap.codespace.is_empty() {
+            let mut lengths: Vec<usize> = cmap.chars.keys().map(|&(len, _)| len).chain(cmap.ranges.iter().map(|r| r.0)).collect();
+            lengths.sort_unstable();
+            lengths.dedup();
+            cmap.codespace = lengths.into_iter().map(|len| (len, 0, u32::MAX >> (32 - 8 * len))).collect();
+        }
+        cmap
+    }
+
+    // The text of a string shown in this font
+    fn decode(&self, bytes: &[u8]) -> String {
+        let mut text = String::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let rest = &bytes[i..];
+            let len = self
+                .codespace
+                .iter()
+                .find(|&&(len, low, high)| rest.get(..len).and_then(code).is_some_and(|(_, c)| (low..=high).contains(&c)))
+                .map_or(1, |&(len, ..)| len);
+            let Some((len, c)) = rest.get(..len).and_then(code) else { break };
+            if let Some(chars) = self.lookup(len, c) {
+                text.extend(chars.chars().filter(|c| !c.is_control()));
+            }
+            i += len;
+        }
+        text
+    }
+
+    fn lookup(&self, len: usize, c: u32) -> Option<String> {
+        if let Some(chars) = self.chars.get(&(len, c)) {
+            return Some(chars.clone());
+        }
+        let (_, low, _, target) = self.ranges.iter().find(|&&(l, low, high, _)| l == len && (low..=high).contains(&c))?;
+        let offset = c - low;
+        match target {
+            RangeTarget::From(units) => {
+                let mut units = units.clone();
+                let last = units.last_mut()?;
+                *last = last.wrapping_add(u16::try_from(offset).ok()?);
+                Some(String::from_utf16_lossy(&units))
+            }
+            RangeTarget::Each(list) => list.get(offset as usize).cloned(),
+        }
+    }
+}
+
+// A code of one to four bytes, with its length
+#[cfg(feature = "documents")]
+fn code(bytes: &[u8]) -> Option<(usize, u32)> {
+    (1..=4).contains(&bytes.len()).then(|| (bytes.len(), bytes.iter().fold(0, |code, &b| code << 8 | b as u32)))
+}
+
+#[cfg(feature = "documents")]
+fn hex_bytes(hex: &[u8]) -> Vec<u8> {
+    let digits: Vec<u8> = hex.iter().copied().filter(u8::is_ascii_hexdigit).collect();
+    digits.chunks(2).filter_map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()).collect()
+}
+
+#[cfg(feature = "documents")]
+fn utf16_text(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect();
+    String::from_utf16_lossy(&units)
+}
+
+// The font `Tf` last selected, decoding the strings shown
+#[cfg(feature = "documents")]
+struct TextState<'a> {
+    fonts: &'a HashMap<Vec<u8>, Font>,
+    font: Option<&'a Font>,
+    /// Whether a string was left out for want of a ToUnicode map
+    unreadable: bool,
+}
+
+#[cfg(feature = "documents")]
+impl TextState<'_> {
+    fn decode(&mut self, bytes: &[u8]) -> String {
+        match self.font {
+            Some(Font::Mapped(cmap)) => cmap.decode(bytes),
+            Some(Font::Unreadable) => {
+                self.unreadable = true;
+                String::new()
+            }
+            Some(Font::Simple) | None => decode_pdf_string(bytes),
+        }
+    }
+}
+
+// The text of one content stream: the operands of its text-showing
+// operators, with line breaks where it moves to a new line
+#[cfg(feature = "documents")]
+fn content_text(content: &[u8], state: &mut TextState) -> String {
+    let mut text = String::new();
+    let mut operands: Vec<Operand> = Vec::new();
+    let mut i = 0;
+    while i < content.len() {
+        let byte = content[i];
+        match byte {
+            b'(' => {
+                let (string, next) = literal_string(content, i + 1);
+                operands.push(Operand::Text(string));
+                i = next;
+            }
+            // Dictionaries of marked content say nothing that is shown
+            b'<' if content.get(i + 1) == Some(&b'<') => i += 2,
+            b'>' => i += 1,
+            b'<' => {
+                let end = find(content, b">", i).unwrap_or(content.len());
+                operands.push(Operand::Text(hex_bytes(&content[i + 1..end])));
+                i = end + 1;
+            }
+            b'[' | b']' => {
+                operands.push(Operand::Bracket);
+                i += 1;
+            }
+            b'%' => {
+                while i < content.len() && content[i] != b'\n' && content[i] != b'\r' {
+                    i += 1;
+                }
+            }
+            _ if byte.is_ascii_whitespace() => i += 1,
+            _ => {
+                let start = i;
+                while i < content.len() && !content[i].is_ascii_whitespace() && !b"()<>[]/%".contains(&content[i]) {
+                    i += 1;
+                }
+                if i == start {
+                    // A name such as /F1
+                    i += 1;
+                    while i < content.len() && !content[i].is_ascii_whitespace() && !b"()<>[]/%".contains(&content[i]) {
+                        i += 1;
+                    }
+                    operands.push(Operand::Name(content[start + 1..i].to_vec()));
+                    continue;
+                }
+                let word = &content[start..i];
+                match std::str::from_utf8(word).ok().and_then(|w| w.parse::<f64>().ok()) {
+                    Some(number) => operands.push(Operand::Number(number)),
+                    None => {
+                        show(&mut text, word, &operands, state);
+                        operands.clear();
+                    }
+                }
+            }
+        }
+    }
+    text
+}
+
+#[cfg(feature = "documents")]
+enum Operand {
+    Text(Vec<u8>),
+    Number(f64),
+    Bracket,
+    Name(Vec<u8>),
+}
+
+// Applies one operator to the text so far
+#[cfg(feature = "documents")]
+fn show(text: &mut String, operator: &[u8], operands: &[Operand], state: &mut TextState) {
+    let newline = |text: &mut String| {
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+    };
+    match operator {
+        b"Tj" | b"TJ" => {
+            for operand in operands {
+                match operand {
+                    Operand::Text(bytes) => text.push_str(&state.decode(bytes)),
+                    // Wide kerning in a TJ array is a word space
+                    Operand::Number(n) if *n < -200.0 && !text.ends_with(' ') => text.push(' '),
+                    _ => {}
+                }
+            }
+        }
+        b"'" | b"\"" => {
+            newline(text);
+            if let Some(Operand::Text(bytes)) = operands.last() {
+                text.push_str(&state.decode(bytes));
+            }
+        }
+        b"Tf" => {
+            if let [.., Operand::Name(name), Operand::Number(_)] = operands {
+                state.font = state.fonts.get(name);
+            }
+        }
+        b"T*" | b"ET" => newline(text),
+        b"Td" | b"TD" => {
+            if let [.., Operand::Number(_), Operand::Number(ty)] = operands {
+                if ty.abs() > 0.01 {
+                    newline(text);
+                } else if !text.ends_with([' ', '\n']) && !text.is_empty() {
+                    text.push(' ');
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+// A literal string starting after its opening parenthesis, and the index
+// after its closing one
+#[cfg(feature = "documents")]
+fn literal_string(content: &[u8], mut i: usize) -> (Vec<u8>, usize) {
+    let mut bytes = Vec::new();
+    let mut depth = 0;
+    while i < content.len() {
+        let byte = content[i];
+        i += 1;
+        match byte {
+            b'\\' => {
+                let Some(&escaped) = content.get(i) else { break };
+                i += 1;
+                match escaped {
+                    b'n' => bytes.push(b'\n'),
+                    b'r' => bytes.push(b'\r'),
+                    b't' => bytes.push(b'\t'),
+                    b'b' | b'f' => {}
+                    b'0'..=b'7' => {
+                        let mut value = (escaped - b'0') as u32;
+                        for _ in 0..2 {
+                            match content.get(i) {
+                                Some(&d @ b'0'..=b'7') => {
+                                    value = value * 8 + (d - b'0') as u32;
+                                    i += 1;
+                                }
+                                _ => break,
+                            }
+                        }
+                        bytes.push(value as u8);
+                    }
+                    b'\r' | b'\n' => {
+                        if escaped == b'\r' && content.get(i) == Some(&b'\n') {
+                            i += 1;
+                        }
+                    }
+                    other => bytes.push(other),
+                }
+            }
+            b'(' => {
+                depth += 1;
+                bytes.push(byte);
+            }
+            b')' if depth == 0 => break,
+            b')' => {
+                depth -= 1;
+                bytes.push(byte);
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    (bytes, i)
+}
+
+// UTF-16 strings start with a byte order mark; others are taken as Latin-1,
+// close to PDFDocEncoding and to the WinAnsi encoding most fonts use
+#[cfg(feature = "documents")]
+fn decode_pdf_string(bytes: &[u8]) -> String {
+    if let Some(utf16) = bytes.strip_prefix(&[0xfe, 0xff]) {
+        let units: Vec<u16> = utf16.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect();
+        return String::from_utf16_lossy(&units);
+    }
+    bytes.iter().map(|&b| b as char).filter(|c| !c.is_control() || *c == '\n' || *c == '\t').collect()
+}
+
+// The paragraphs of a Word document's body
+#[cfg(feature = "documents")]
+fn docx_text(data: &[u8]) -> Result<String, String> {
+    let xml = crate::archive::read_entry("zip", data, "word/document.xml")
+        .map_err(|e| format!("Not a DOCX file: {}", e))?
+        .ok_or("Not a DOCX file: no word/document.xml")?;
+    let xml = String::from_utf8_lossy(&xml);
+    let mut text = String::new();
+    let mut rest = xml.as_ref();
+    while let Some(open) = rest.find('<') {
+        let Some(close) = rest[open..].find('>').map(|c| open + c) else { break };
+        let tag = &rest[open + 1..close];
+        let name = tag.trim_start_matches('/').split([' ', '/']).next().unwrap_or_default();
+        let after = &rest[close + 1..];
+        rest = after;
+        match name {
+            "w:t" if !tag.starts_with('/') && !tag.ends_with('/') => {
+                let end = after.find("</w:t>").unwrap_or(after.len());
+                text.push_str(&unescape_xml(&after[..end]));
+                rest = &after[end..];
+            }
+            "w:tab" => text.push('\t'),
+            "w:br" | "w:cr" => text.push('\n'),
+            "w:p" if tag.starts_with('/') || tag.ends_with('/') => text.push('\n'),
+            _ => {}
+        }
+    }
+    Ok(text)
+}
+
+#[cfg(feature = "documents")]
+fn unescape_xml(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        unescaped.push_str(&rest[..amp]);
+        let Some(semi) = rest[amp..].find(';').map(|s| amp + s) else { break };
+        let entity = &rest[amp + 1..semi];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse::<u32>))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => unescaped.push(c),
+            None => unescaped.push_str(&rest[amp..=semi]),
+        }
+        rest = &rest[semi + 1..];
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
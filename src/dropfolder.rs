@@ -0,0 +1,201 @@
+// Drop-folder mode, for tools that can only write files: patches saved into
+// a folder as *.patch or *.diff are checked and applied to a bound
+// workspace, and each gets a <name>.result.json next to it with the same
+// report /api/apply_patch returns. A patch is dry-run first and only applied
+// when every hunk fits, so a bad one leaves the workspace alone. Patch
+// policy rules that deny a change or need a confirmation reject it; none
+// can be confirmed here. SECRET_SCAN applies as for the endpoint.
+//
+//   DROP_FOLDER     folder to watch
+//   DROP_WORKSPACE  directory (or @alias) the patches are applied to
+//
+// With both set the server watches the folder next to serving the API.
+// `repopatch drop <workspace> [folder]` does the same without the server;
+// without a folder it reads patches from stdin, separated by NUL bytes, and
+// prints one JSON result line for each.
+
+use crate::cli::precheck;
+use crate::{apply_options, apply_result, revisions};
+use notify::{Event, RecursiveMode, Watcher};
+use repopatch::patch::{self, PatchSet};
+use repopatch::policy::{Action, Policy};
+use repopatch::remap::PathRewrites;
+use repopatch::store::{FileStore, OverlayStore, StoreProvider};
+use serde_json::json;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, SystemTime};
+
+pub const RESULT_SUFFIX: &str = ".result.json";
+const PATCH_EXTENSIONS: &[&str] = &["patch", "diff"];
+/// Files changed more recently than this may still be being written.
+const SETTLE: Duration = Duration::from_millis(500);
+/// The folder is also scanned this often, in case an event was missed.
+const RESCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct DropFolder {
+    folder: PathBuf,
+    store: Arc<dyn FileStore>,
+    policy: Arc<Policy>,
+    rewrites: Arc<PathRewrites>,
+}
+
+impl DropFolder {
+    pub fn new(folder: PathBuf, store: Arc<dyn FileStore>, policy: Arc<Policy>, rewrites: Arc<PathRewrites>) -> Result<Self, String> {
+        if !folder.is_dir() {
+            return Err(format!("Drop folder {} is not a directory", folder.display()));
+        }
+        Ok(DropFolder { folder, store, policy, rewrites })
+    }
+
+    /// The folder configured by DROP_FOLDER and DROP_WORKSPACE, if any.
+    pub fn from_env(stores: &dyn StoreProvider, policy: Arc<Policy>, rewrites: Arc<PathRewrites>) -> Result<Option<Self>, String> {
+        let folder = env::var("DROP_FOLDER").ok().filter(|f| !f.is_empty());
+        let workspace = env::var("DROP_WORKSPACE").ok().filter(|w| !w.is_empty());
+        match (folder, workspace) {
+            (None, None) => Ok(None),
+            (Some(folder), Some(workspace)) => DropFolder::new(PathBuf::from(folder), stores.open_root(&workspace)?, policy, rewrites).map(Some),
+            _ => Err("Set both DROP_FOLDER and DROP_WORKSPACE to enable the drop folder".to_string()),
+        }
+    }
+
+    pub fn workspace(&self) -> String {
+        self.store.display_path("")
+    }
+
+    pub fn folder(&self) -> &Path {
+        &self.folder
+    }
+
+    // Patch files without a result at least as new as they are
+    fn pending(&self) -> Vec<PathBuf> {
+        let Ok(entries) = fs::read_dir(&self.folder) else { return Vec::new() };
+        let settled = SystemTime::now() - SETTLE;
+        let mut pending: Vec<PathBuf> = entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|e| PATCH_EXTENSIONS.contains(&e.to_string_lossy().to_lowercase().as_str())))
+            .filter(|p| {
+                let Ok(modified) = fs::metadata(p).and_then(|m| m.modified()) else { return false };
+                let result = fs::metadata(result_path(p)).and_then(|m| m.modified()).ok();
+                p.is_file() && modified <= settled && result.is_none_or(|r| r < modified)
+            })
+            .collect();
+        pending.sort();
+        pending
+    }
+
+    /// Applies every pending patch in name order and writes its result.
+    pub fn process_pending(&self) {
+        for path in self.pending() {
+            let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+            let mut result = match fs::read(&path) {
+                Ok(data) => apply_text(&*self.store, &self.policy, &self.rewrites, &String::from_utf8_lossy(&data)),
+                Err(e) => json!({ "success": false, "error": format!("Failed to read {}: {}", name, e), "appliedFiles": [], "details": [] }),
+            };
+            result["patch"] = json!(name);
+            result["workspace"] = json!(self.workspace());
+            result["processedAt"] = json!(chrono::Utc::now().to_rfc3339());
+            if result["success"] == true {
+                log::info!("Applied dropped patch {} to {}", name, self.workspace());
+            } else {
+                log::warn!("Dropped patch {} was not applied: {}", name, result["error"]);
+            }
+            let content = serde_json::to_vec_pretty(&result).unwrap_or_default();
+            if let Err(e) = fs::write(result_path(&path), content) {
+                log::error!("Failed to write the result of {}: {}", name, e);
+            }
+        }
+    }
+
+    /// Processes the folder whenever it changes, until the process exits.
+    pub fn watch(self) -> Result<(), String> {
+        let (sender, receiver) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            if event.is_ok() {
+                let _ = sender.send(());
+            }
+        })
+        .map_err(|e| format!("Failed to watch {}: {}", self.folder.display(), e))?;
+        watcher
+            .watch(&self.folder, RecursiveMode::NonRecursive)
+            .map_err(|e| format!("Failed to watch {}: {}", self.folder.display(), e))?;
+        loop {
+            self.process_pending();
+            match receiver.recv_timeout(RESCAN_INTERVAL) {
+                Ok(()) => {
+                    // Let the writer finish, then handle a burst of events once
+                    std::thread::sleep(SETTLE);
+                    while receiver.try_recv().is_ok() {}
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Err("The folder watcher stopped".to_string()),
+            }
+        }
+    }
+}
+
+fn result_path(patch: &Path) -> PathBuf {
+    let mut name = patch.as_os_str().to_owned();
+    name.push(RESULT_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Checks and applies one patch to `store`, returning the apply_patch report.
+pub fn apply_text(store: &dyn FileStore, policy: &Policy, rewrites: &PathRewrites, text: &str) -> serde_json::Value {
+    let failure = |error: String| json!({ "success": false, "error": error, "appliedFiles": [], "details": [] });
+    let text = text.trim();
+    if text.is_empty() {
+        return failure("Patch content cannot be empty".to_string());
+    }
+    let mut patch_set = PatchSet::parse(text, 1);
+    let extra = match precheck(store, &mut patch_set, rewrites, policy, &[Action::Deny, Action::Confirm]).into_result() {
+        Ok(extra) => extra,
+        Err(report) => return report,
+    };
+
+    let options = apply_options(patch::DEFAULT_CONTEXT_LINES);
+    let overlay = OverlayStore::new(store);
+    let outcome = patch_set.apply_with(&overlay, &options);
+    if !outcome.is_success() {
+        let mut extra = extra;
+        extra["dryRun"] = json!(true);
+        extra["message"] = json!("The patch does not apply cleanly. Nothing was written.");
+        return apply_result(outcome, extra);
+    }
+    let outcome = patch_set.apply_with(store, &options);
+    let mut extra = extra;
+    extra["revisions"] = revisions(store, &outcome.applied_files);
+    apply_result(outcome, extra)
+}
+
+/// Applies NUL-separated patches from stdin as they arrive, printing a
+/// result line for each; returns the process exit code.
+pub fn run_stdin(store: &dyn FileStore, policy: &Policy, rewrites: &PathRewrites) -> i32 {
+    let mut stdin = io::stdin().lock();
+    let mut stdout = io::stdout().lock();
+    let mut failed = false;
+    let mut chunk = Vec::new();
+    loop {
+        chunk.clear();
+        match stdin.read_until(0, &mut chunk) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Failed to read stdin: {}", e);
+                return 1;
+            }
+        }
+        let text = String::from_utf8_lossy(chunk.strip_suffix(&[0]).unwrap_or(&chunk)).into_owned();
+        if text.trim().is_empty() {
+            continue;
+        }
+        let result = apply_text(store, policy, rewrites, &text);
+        failed |= result["success"] != true;
+        let _ = writeln!(stdout, "{}", result);
+        let _ = stdout.flush();
+    }
+    if failed { 1 } else { 0 }
+}
@@ -0,0 +1,61 @@
+//! Finds files with identical content, e.g. vendored copies or files a bad
+//! patch created twice.
+
+use crate::store::FileStore;
+use crate::tree::walk_files;
+use ignore::gitignore::Gitignore;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+/// Files sharing the same content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    /// Size of each copy in bytes.
+    pub size: u64,
+    /// Store paths of the copies, in walk order.
+    pub paths: Vec<String>,
+}
+
+// Files read so far with one content, keyed by a hash of that content
+type ContentBuckets = HashMap<u64, Vec<(Vec<u8>, Vec<String>)>>;
+
+/// Groups the non-empty, non-ignored files below `dir` by content, largest
+/// waste (size times extra copies) first. Only files whose sizes collide
+/// are read, and candidates with equal hashes are compared byte by byte.
+pub fn find_duplicates(store: &dyn FileStore, dir: &str, ig: &Gitignore) -> Result<Vec<DuplicateGroup>, String> {
+    let mut by_size: HashMap<u64, Vec<String>> = HashMap::new();
+    walk_files(store, dir, ig, &mut |path| {
+        if let Some(size) = store.file_size(path).filter(|size| *size > 0) {
+            by_size.entry(size).or_default().push(path.to_string());
+        }
+    })?;
+
+    let mut groups = Vec::new();
+    for (size, paths) in by_size.into_iter().filter(|(_, paths)| paths.len() > 1) {
+        // Several buckets per hash keep hash collisions apart
+        let mut by_hash = ContentBuckets::new();
+        for path in paths {
+            let Ok(content) = store.read(&path) else { continue };
+            let mut hasher = DefaultHasher::new();
+            content.hash(&mut hasher);
+            let bucket = by_hash.entry(hasher.finish()).or_default();
+            match bucket.iter_mut().find(|(c, _)| *c == content) {
+                Some((_, same)) => same.push(path),
+                None => bucket.push((content, vec![path])),
+            }
+        }
+        groups.extend(
+            by_hash
+                .into_values()
+                .flatten()
+                .filter(|(_, paths)| paths.len() > 1)
+                .map(|(_, paths)| DuplicateGroup { size, paths }),
+        );
+    }
+    groups.sort_by(|a, b| {
+        let waste = |g: &DuplicateGroup| g.size * (g.paths.len() as u64 - 1);
+        waste(b).cmp(&waste(a)).then_with(|| a.paths.cmp(&b.paths))
+    });
+    Ok(groups)
+}
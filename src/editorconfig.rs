@@ -0,0 +1,176 @@
+//! Minimal EditorConfig support (https://editorconfig.org) for files the
+//! patch engine creates: indentation, line endings, final newline, trailing
+//! whitespace and the UTF-8 BOM.
+
+use crate::store::{join_path, FileStore};
+use globset::GlobBuilder;
+use std::collections::HashMap;
+
+/// The properties that apply to one file. Unset properties leave the
+/// content alone.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FileSettings {
+    pub indent_style: Option<IndentStyle>,
+    pub indent_size: Option<usize>,
+    pub tab_width: Option<usize>,
+    pub end_of_line: Option<String>,
+    pub insert_final_newline: Option<bool>,
+    pub trim_trailing_whitespace: Option<bool>,
+    pub charset: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndentStyle {
+    Tab,
+    Space,
+}
+
+/// Resolves the settings for `path` (a store path) from the `.editorconfig`
+/// files in its parent directories, stopping at one with `root = true`.
+/// Closer files and later sections win.
+pub fn settings_for(store: &dyn FileStore, path: &str) -> FileSettings {
+    let mut dirs = Vec::new();
+    let mut dir = path.rsplit_once('/').map(|(d, _)| d).unwrap_or("");
+    loop {
+        dirs.push(dir.to_string());
+        if dir.is_empty() {
+            break;
+        }
+        dir = dir.rsplit_once('/').map(|(d, _)| d).unwrap_or("");
+    }
+
+    // Collect from the closest file outwards, then apply outermost first
+    let mut files = Vec::new();
+    for dir in dirs {
+        let Ok(content) = store.read(&join_path(&dir, ".editorconfig")) else { continue };
+        let content = String::from_utf8_lossy(&content).to_string();
+        let is_root = parse(&content).0;
+        files.push((dir, content));
+        if is_root {
+            break;
+        }
+    }
+    let mut properties: HashMap<String, String> = HashMap::new();
+    for (dir, content) in files.iter().rev() {
+        let relative = path.strip_prefix(dir.as_str()).unwrap_or(path).trim_start_matches('/');
+        for (pattern, section) in parse(content).1 {
+            if section_matches(&pattern, relative) {
+                properties.extend(section);
+            }
+        }
+    }
+    FileSettings::from_properties(&properties)
+}
+
+// A section glob and its properties
+type Section = (String, HashMap<String, String>);
+
+// Helper function to split an .editorconfig into its root flag and sections
+fn parse(content: &str) -> (bool, Vec<Section>) {
+    let mut root = false;
+    let mut sections: Vec<Section> = Vec::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(pattern) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            sections.push((pattern.to_string(), HashMap::new()));
+        } else if let Some((key, value)) = line.split_once('=') {
+            let (key, value) = (key.trim().to_lowercase(), value.trim().to_lowercase());
+            match sections.last_mut() {
+                Some((_, section)) => {
+                    section.insert(key, value);
+                }
+                None if key == "root" => root = value == "true",
+                None => {}
+            }
+        }
+    }
+    (root, sections)
+}
+
+// Helper function to match a section glob. Globs without a slash match the
+// file name at any depth, as in the specification.
+fn section_matches(pattern: &str, relative: &str) -> bool {
+    let pattern = match pattern.strip_prefix('/') {
+        Some(anchored) => anchored.to_string(),
+        None if pattern.contains('/') => pattern.to_string(),
+        None => format!("**/{}", pattern),
+    };
+    GlobBuilder::new(&pattern)
+        .literal_separator(true)
+        .build()
+        .map(|glob| glob.compile_matcher().is_match(relative))
+        .unwrap_or(false)
+}
+
+impl FileSettings {
+    fn from_properties(properties: &HashMap<String, String>) -> Self {
+        let get = |key: &str| properties.get(key).filter(|v| v.as_str() != "unset");
+        let boolean = |key: &str| get(key).and_then(|v| v.parse().ok());
+        let tab_width = get("tab_width").and_then(|v| v.parse().ok());
+        FileSettings {
+            indent_style: get("indent_style").and_then(|v| match v.as_str() {
+                "tab" => Some(IndentStyle::Tab),
+                "space" => Some(IndentStyle::Space),
+                _ => None,
+            }),
+            indent_size: get("indent_size").and_then(|v| if v == "tab" { tab_width } else { v.parse().ok() }),
+            tab_width,
+            end_of_line: get("end_of_line").cloned(),
+            insert_final_newline: boolean("insert_final_newline"),
+            trim_trailing_whitespace: boolean("trim_trailing_whitespace"),
+            charset: get("charset").cloned(),
+        }
+    }
+
+    /// Rewrites `content` to follow these settings and returns the bytes
+    /// to write.
+    pub fn apply(&self, content: &str) -> Vec<u8> {
+        if *self == FileSettings::default() {
+            return content.as_bytes().to_vec();
+        }
+        let eol = match self.end_of_line.as_deref() {
+            Some("crlf") => "\r\n",
+            Some("cr") => "\r",
+            Some("lf") => "\n",
+            _ if content.contains("\r\n") => "\r\n",
+            _ => "\n",
+        };
+        let had_final_newline = content.ends_with('\n');
+        let mut lines: Vec<String> = content.lines().map(|l| self.fix_line(l.trim_end_matches('\r'))).collect();
+        if self.insert_final_newline == Some(false) {
+            while lines.last().is_some_and(|l| l.is_empty()) {
+                lines.pop();
+            }
+        }
+        let mut out = lines.join(eol);
+        let final_newline = self.insert_final_newline.unwrap_or(had_final_newline);
+        if final_newline && !lines.is_empty() {
+            out.push_str(eol);
+        }
+        let mut bytes = Vec::with_capacity(out.len() + 3);
+        if self.charset.as_deref() == Some("utf-8-bom") && !out.starts_with('\u{feff}') {
+            bytes.extend_from_slice("\u{feff}".as_bytes());
+        }
+        bytes.extend_from_slice(out.as_bytes());
+        bytes
+    }
+
+    fn fix_line(&self, line: &str) -> String {
+        let line = if self.trim_trailing_whitespace == Some(true) { line.trim_end() } else { line };
+        let body = line.trim_start_matches([' ', '\t']);
+        let indent = &line[..line.len() - body.len()];
+        let size = self.indent_size.or(self.tab_width).unwrap_or(4).max(1);
+        let tab_width = self.tab_width.unwrap_or(size).max(1);
+        let new_indent = match self.indent_style {
+            Some(IndentStyle::Space) if indent.contains('\t') => indent.replace('\t', &" ".repeat(tab_width)),
+            Some(IndentStyle::Tab) if indent.contains(' ') => {
+                let columns: usize = indent.chars().map(|c| if c == '\t' { tab_width } else { 1 }).sum();
+                format!("{}{}", "\t".repeat(columns / tab_width), " ".repeat(columns % tab_width))
+            }
+            _ => return line.to_string(),
+        };
+        format!("{}{}", new_indent, body)
+    }
+}
@@ -0,0 +1,235 @@
+// Optional semantic search: GET /api/semantic_search?path=<root>&q=<text>
+// embeds the files of the root (only the ones changed since the last call),
+// keeps the vectors in <root>/.repopatch/semantic-index.json (with DATA_DIR,
+// in the metadata database instead) and returns the chunks closest to the
+// query.
+//
+// Configuration:
+//   EMBEDDINGS_PROVIDER  `local` (built-in bag-of-words model) or `openai`
+//                        (any OpenAI-compatible /v1/embeddings endpoint,
+//                        including Ollama and llama.cpp); unset disables search
+//   EMBEDDINGS_URL       default https://api.openai.com/v1/embeddings
+//   EMBEDDINGS_MODEL     default text-embedding-3-small
+//   EMBEDDINGS_API_KEY   sent as a bearer token when set
+
+use crate::auth::Stores;
+use actix_web::{get, web, HttpResponse};
+use ignore::gitignore::Gitignore;
+use repopatch::metadata::Metadata;
+use repopatch::semantic::{self, Chunk, VectorIndex};
+use repopatch::store::FileStore;
+use repopatch::tree;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashSet;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_EMBEDDINGS_URL: &str = "https://api.openai.com/v1/embeddings";
+const DEFAULT_EMBEDDINGS_MODEL: &str = "text-embedding-3-small";
+/// Chunks sent per embeddings request.
+const BATCH_SIZE: usize = 64;
+/// Characters of a chunk sent to the provider.
+const MAX_INPUT_CHARS: usize = 8000;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+const MAX_RESPONSE_BYTES: usize = 64 * 1024 * 1024;
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 50;
+/// Record kind in the metadata database, keyed by workspace root.
+const INDEX_KIND: &str = "semantic_indexes";
+
+enum Provider {
+    Local,
+    OpenAi { url: String, model: String, api_key: Option<String> },
+}
+
+pub struct Embeddings {
+    provider: Option<Provider>,
+    metadata: Option<Arc<Metadata>>,
+    // One index refresh at a time, so concurrent searches don't embed twice
+    refresh: tokio::sync::Mutex<()>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiResponse {
+    data: Vec<OpenAiEmbedding>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiEmbedding {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+impl Embeddings {
+    /// Reads the provider settings; indexes go to `metadata` when given.
+    pub fn from_env(metadata: Option<Arc<Metadata>>) -> Result<Self, String> {
+        let provider = match env::var("EMBEDDINGS_PROVIDER").unwrap_or_default().as_str() {
+            "" => None,
+            "local" => Some(Provider::Local),
+            "openai" => Some(Provider::OpenAi {
+                url: env::var("EMBEDDINGS_URL").unwrap_or_else(|_| DEFAULT_EMBEDDINGS_URL.to_string()),
+                model: env::var("EMBEDDINGS_MODEL").unwrap_or_else(|_| DEFAULT_EMBEDDINGS_MODEL.to_string()),
+                api_key: env::var("EMBEDDINGS_API_KEY").ok().filter(|k| !k.is_empty()),
+            }),
+            other => return Err(format!("Unknown EMBEDDINGS_PROVIDER {}: expected local or openai", other)),
+        };
+        Ok(Embeddings { provider, metadata, refresh: tokio::sync::Mutex::new(()) })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.provider.is_some()
+    }
+
+    // The name an index is built for; vectors of different models don't mix
+    fn model(&self) -> String {
+        match &self.provider {
+            Some(Provider::OpenAi { url, model, .. }) => format!("{} {}", url, model),
+            _ => format!("local-hash-{}", semantic::HASH_DIMENSIONS),
+        }
+    }
+
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, String> {
+        let Some(Provider::OpenAi { url, model, api_key }) = &self.provider else {
+            return Ok(texts.iter().map(|t| semantic::hash_embedding(t)).collect());
+        };
+        let input: Vec<String> = texts.into_iter().map(|t| t.chars().take(MAX_INPUT_CHARS).collect()).collect();
+        let count = input.len();
+        let mut request = awc::Client::builder().timeout(REQUEST_TIMEOUT).finish().post(url);
+        if let Some(key) = api_key {
+            request = request.bearer_auth(key);
+        }
+        let mut response = request
+            .send_json(&json!({ "model": model, "input": input }))
+            .await
+            .map_err(|e| format!("Embeddings request failed: {}", e))?;
+        let status = response.status();
+        let body = response.body().limit(MAX_RESPONSE_BYTES).await.map_err(|e| format!("Failed to read the embeddings response: {}", e))?;
+        if !status.is_success() {
+            return Err(format!("Embeddings provider returned {}: {}", status, String::from_utf8_lossy(&body)));
+        }
+        let mut parsed: OpenAiResponse = serde_json::from_slice(&body).map_err(|e| format!("Invalid embeddings response: {}", e))?;
+        if parsed.data.len() != count {
+            return Err(format!("Embeddings provider returned {} vectors for {} inputs", parsed.data.len(), count));
+        }
+        parsed.data.sort_by_key(|d| d.index);
+        Ok(parsed.data.into_iter().map(|d| d.embedding).collect())
+    }
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    path: String,
+    q: String,
+    limit: Option<usize>,
+}
+
+/// The index of `store`, from the metadata database when there is one.
+fn load_index(metadata: Option<&Metadata>, store: &dyn FileStore, model: &str) -> VectorIndex {
+    match metadata {
+        Some(metadata) => {
+            let loaded = metadata.get(INDEX_KIND, &store.display_path("")).ok().flatten().and_then(|v| serde_json::from_value(v).ok());
+            VectorIndex::or_empty(loaded, model)
+        }
+        None => VectorIndex::load(store, model),
+    }
+}
+
+fn save_index(metadata: Option<&Metadata>, store: &dyn FileStore, index: &VectorIndex) -> Result<(), String> {
+    match metadata {
+        Some(metadata) => metadata.put(INDEX_KIND, &store.display_path(""), index),
+        None => index.save(store),
+    }
+}
+
+#[get("/api/semantic_search")]
+pub async fn search(query: web::Query<SearchQuery>, stores: Stores, embeddings: web::Data<Embeddings>) -> HttpResponse {
+    if !embeddings.enabled() {
+        return HttpResponse::NotFound().json(json!({ "success": false, "error": "Semantic search is off; set EMBEDDINGS_PROVIDER to enable it" }));
+    }
+    if query.q.trim().is_empty() {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": "q is required" }));
+    }
+    let store = match stores.open_root(&query.path) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+
+    let guard = embeddings.refresh.lock().await;
+    let model = embeddings.model();
+    let task_store = store.clone();
+    let metadata = embeddings.metadata.clone();
+    let loaded = web::block(move || {
+        let mut index = load_index(metadata.as_deref(), &*task_store, &model);
+        let ig = tree::load_gitignore(&*task_store, "").unwrap_or_else(Gitignore::empty);
+        index.refresh(&*task_store, &ig).map(|pending| (index, pending))
+    })
+    .await;
+    let (mut index, pending) = match loaded {
+        Ok(Ok(loaded)) => loaded,
+        Ok(Err(e)) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": e })),
+        Err(e) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Index task failed: {}", e) })),
+    };
+
+    let embedded = pending.len();
+    let mut failure = None;
+    for batch in pending.chunks(BATCH_SIZE) {
+        match embeddings.embed(batch.iter().map(|c| c.text.clone()).collect()).await {
+            Ok(vectors) => batch.iter().zip(vectors).for_each(|(chunk, vector)| index.insert(chunk, vector)),
+            Err(e) => {
+                // Files embedded only in part would look up to date next time
+                let failed: HashSet<&str> = batch.iter().map(|c: &Chunk| c.path.as_str()).collect();
+                index.entries.retain(|e| !failed.contains(e.path.as_str()));
+                failure = Some(e);
+                break;
+            }
+        }
+    }
+    let task_store = store.clone();
+    let metadata = embeddings.metadata.clone();
+    let saved = web::block(move || save_index(metadata.as_deref(), &*task_store, &index).map(|_| index)).await;
+    drop(guard);
+    let index = match saved {
+        Ok(Ok(index)) => index,
+        Ok(Err(e)) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": e })),
+        Err(e) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Index task failed: {}", e) })),
+    };
+    if let Some(e) = failure {
+        return HttpResponse::BadGateway().json(json!({ "success": false, "error": e }));
+    }
+
+    let query_vector = match embeddings.embed(vec![query.q.clone()]).await {
+        Ok(mut vectors) => vectors.pop().unwrap_or_default(),
+        Err(e) => return HttpResponse::BadGateway().json(json!({ "success": false, "error": e })),
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let hits: Vec<_> = index.search(&query_vector, limit).into_iter().map(|hit| (hit.entry.clone(), hit.score)).collect();
+    let task_store = store.clone();
+    let results = web::block(move || {
+        hits.into_iter()
+            .map(|(entry, score)| {
+                let content = task_store
+                    .read(&entry.path)
+                    .map(|data| String::from_utf8_lossy(&data).lines().skip(entry.start_line - 1).take(entry.end_line + 1 - entry.start_line).collect::<Vec<_>>().join("\n"))
+                    .unwrap_or_default();
+                json!({
+                    "path": entry.path,
+                    "startLine": entry.start_line,
+                    "endLine": entry.end_line,
+                    "score": score,
+                    "content": content
+                })
+            })
+            .collect::<Vec<_>>()
+    })
+    .await
+    .unwrap_or_default();
+    HttpResponse::Ok().json(json!({
+        "success": true,
+        "root": store.display_path(""),
+        "model": index.model,
+        "index": { "files": index.file_count(), "chunks": index.entries.len(), "embedded": embedded },
+        "results": results
+    }))
+}
@@ -0,0 +1,405 @@
+//! Interchangeable patch engines. A [`PatchEngine`] works out the new content
+//! of each file a patch modifies; everything around that (path checks,
+//! notebooks, snapshots, writes and failure reports) is shared through
+//! [`PatchSet::apply_using`], so outcomes of different engines compare
+//! directly.
+//!
+//! - `dmp`, the default: exact matching, then ignoring trailing whitespace,
+//!   then diff-match-patch fuzzy matching.
+//! - `strict`: unified-diff semantics. Context must match exactly and hunk
+//!   headers must count their lines right.
+//! - `git`: hands each file's hunks to `git apply`.
+//! - `dmp-text`: diff-match-patch's own patch text (`@@ -382,8 +481,9 @@` with
+//!   character offsets and `%0A`-escaped bodies) under `---`/`+++` headers.
+//! - `search-replace`: `<<<<<<< SEARCH` / `=======` / `>>>>>>> REPLACE` blocks,
+//!   each after a line naming its file. The search text is found like a `dmp`
+//!   hunk; an empty one creates the file.
+//!
+//! [`detect_format`] tells these apart for clients that don't know what their
+//! model wrote.
+//!
+//! ```
+//! use repopatch::engine;
+//! use repopatch::patch::ApplyOptions;
+//! use repopatch::store::MemoryStore;
+//!
+//! let store = MemoryStore::from_files([("greeting.txt", "hello  \nworld\n")]);
+//! let patch = "--- a/greeting.txt\n+++ b/greeting.txt\n@@ -1,2 +1,2 @@\n-hello\n+goodbye\n world\n";
+//!
+//! let strict = engine::by_name("strict").unwrap();
+//! assert!(!strict.preview(&strict.parse(patch, 1), &store, &ApplyOptions::default()).outcome.is_success());
+//! let dmp = engine::by_name("dmp").unwrap();
+//! assert!(dmp.apply(&dmp.parse(patch, 1), &store, &ApplyOptions::default()).is_success());
+//! assert_eq!(store.get("greeting.txt").unwrap(), "goodbye\nworld\n");
+//! ```
+
+use crate::patch::{apply_hunks, ApplyOptions, ApplyOutcome, FilePatch, Matching, PatchSet, Tracer};
+use crate::store::{FileStore, OverlayStore};
+use diff_match_patch_rs::{Compat, DiffMatchPatch};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{LazyLock, OnceLock};
+
+/// Engine used when neither the request nor the workspace names one.
+pub const DEFAULT_ENGINE: &str = "dmp";
+
+/// Names of the built-in engines.
+pub const ENGINES: [&str; 5] = ["dmp", "strict", "git", "dmp-text", "search-replace"];
+
+/// Parses patches and applies them to a [`FileStore`].
+pub trait PatchEngine: Send + Sync {
+    /// Name clients select the engine by.
+    fn name(&self) -> &'static str;
+
+    /// Parses `patch_content`, stripping `strip_level` leading path
+    /// components like `patch -pN`.
+    fn parse(&self, patch_content: &str, strip_level: usize) -> PatchSet {
+        PatchSet::parse(patch_content, strip_level)
+    }
+
+    /// The new content of `file` patched from its `original` content, and
+    /// whether each of its hunks applied.
+    fn patch_file(&self, file: &FilePatch, original: &str, tracer: &mut Tracer) -> Result<(String, Vec<bool>), String>;
+
+    /// Applies `patch` to `store`.
+    fn apply(&self, patch: &PatchSet, store: &dyn FileStore, options: &ApplyOptions) -> ApplyOutcome {
+        patch.apply_using(self, store, options)
+    }
+
+    /// What applying `patch` to `store` would do, without writing to it.
+    fn preview(&self, patch: &PatchSet, store: &dyn FileStore, options: &ApplyOptions) -> Preview {
+        let overlay = OverlayStore::new(store);
+        let outcome = self.apply(patch, &overlay, options);
+        Preview { outcome, files: overlay.changes() }
+    }
+}
+
+/// Outcome of [`PatchEngine::preview`].
+pub struct Preview {
+    pub outcome: ApplyOutcome,
+    /// Would-be content of every file the patch writes, `None` for files it
+    /// removes.
+    pub files: BTreeMap<String, Option<Vec<u8>>>,
+}
+
+/// The engine called `name`, if there is one.
+pub fn by_name(name: &str) -> Option<Box<dyn PatchEngine>> {
+    match name {
+        "dmp" => Some(Box::new(DmpEngine::default())),
+        "strict" => Some(Box::new(StrictEngine)),
+        "git" => Some(Box::new(GitEngine)),
+        "dmp-text" => Some(Box::new(DmpTextEngine::default())),
+        "search-replace" => Some(Box::new(SearchReplaceEngine::default())),
+        _ => None,
+    }
+}
+
+// Helper function to name the engines in an error message
+fn unknown_engine(name: &str) -> String {
+    format!("Unknown patch engine '{}'; expected one of {}", name, ENGINES.join(", "))
+}
+
+/// What a patch is written as, from [`detect_format`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchFormat {
+    /// A unified diff with `diff --git` headers.
+    Git,
+    Unified,
+    DmpText,
+    SearchReplace,
+}
+
+impl PatchFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            PatchFormat::Git => "git",
+            PatchFormat::Unified => "unified",
+            PatchFormat::DmpText => "dmp-text",
+            PatchFormat::SearchReplace => "search-replace",
+        }
+    }
+
+    /// The engine for patches in this format; `None` for plain unified diffs,
+    /// which every line-based engine reads. Git diffs fall back to that too
+    /// when git isn't installed.
+    pub fn engine(self) -> Option<&'static str> {
+        match self {
+            PatchFormat::Git => GitEngine::available().then_some("git"),
+            PatchFormat::Unified => None,
+            PatchFormat::DmpText => Some("dmp-text"),
+            PatchFormat::SearchReplace => Some("search-replace"),
+        }
+    }
+}
+
+static SEARCH_MARKER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^<{5,9} ?SEARCH\s*$").expect("search marker pattern must compile"));
+static DIVIDER_MARKER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^={5,9}\s*$").expect("divider marker pattern must compile"));
+static REPLACE_MARKER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^>{5,9} ?REPLACE\s*$").expect("replace marker pattern must compile"));
+// diff-match-patch headers have nothing after the closing @@
+static DMP_HUNK_HEADER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^@@ -\d+(,\d+)? \+\d+(,\d+)? @@$").expect("hunk header pattern must compile"));
+
+/// Guesses what `patch_content` is written as: SEARCH/REPLACE blocks, then
+/// hunks with diff-match-patch's escapes, then git headers, then any `@@`
+/// hunks. `None` when it looks like none of them.
+pub fn detect_format(patch_content: &str) -> Option<PatchFormat> {
+    let lines: Vec<&str> = patch_content.lines().map(|l| l.trim_end_matches('\r')).collect();
+    if let Some(search) = lines.iter().position(|l| SEARCH_MARKER.is_match(l)) {
+        if lines[search..].iter().any(|l| REPLACE_MARKER.is_match(l)) {
+            return Some(PatchFormat::SearchReplace);
+        }
+    }
+    let headers: Vec<&&str> = lines.iter().filter(|l| l.starts_with("@@ -")).collect();
+    if headers.is_empty() {
+        return None;
+    }
+    let escaped = lines.iter().any(|l| l.starts_with([' ', '-', '+']) && !l.starts_with("---") && !l.starts_with("+++") && l.contains("%0A"));
+    if escaped && headers.iter().all(|h| DMP_HUNK_HEADER.is_match(h)) {
+        return Some(PatchFormat::DmpText);
+    }
+    if lines.iter().any(|l| l.starts_with("diff --git ")) {
+        return Some(PatchFormat::Git);
+    }
+    Some(PatchFormat::Unified)
+}
+
+/// Engines of workspace roots, with `*` standing for every other root.
+#[derive(Default)]
+pub struct EngineSelection {
+    defaults: BTreeMap<String, String>,
+}
+
+impl EngineSelection {
+    /// Checks the engine names of `defaults`. Roots are compared after
+    /// resolving symlinks, like the roots of opened workspaces.
+    pub fn new(defaults: &BTreeMap<String, String>) -> Result<Self, String> {
+        let mut resolved = BTreeMap::new();
+        for (root, name) in defaults {
+            if by_name(name).is_none() {
+                return Err(format!("patch_engines: {}", unknown_engine(name)));
+            }
+            let root = match root.as_str() {
+                "*" => root.clone(),
+                _ => fs::canonicalize(root).map(|p| p.display().to_string()).unwrap_or_else(|_| root.clone()),
+            };
+            resolved.insert(root, name.clone());
+        }
+        Ok(EngineSelection { defaults: resolved })
+    }
+
+    /// The engine `requested` by a client, or else the one configured for
+    /// the workspace at `root`, or else [`DEFAULT_ENGINE`].
+    pub fn select(&self, requested: Option<&str>, root: &str) -> Result<Box<dyn PatchEngine>, String> {
+        let name = requested
+            .or_else(|| self.defaults.get(root.trim_end_matches('/')).or_else(|| self.defaults.get("*")).map(String::as_str))
+            .unwrap_or(DEFAULT_ENGINE);
+        by_name(name).ok_or_else(|| unknown_engine(name))
+    }
+}
+
+/// Exact matching first, then ignoring trailing whitespace, then
+/// diff-match-patch fuzzy matching, so hunks still land when their context
+/// has drifted.
+#[derive(Default)]
+pub struct DmpEngine {
+    dmp: DiffMatchPatch,
+}
+
+impl PatchEngine for DmpEngine {
+    fn name(&self) -> &'static str {
+        "dmp"
+    }
+
+    fn patch_file(&self, file: &FilePatch, original: &str, tracer: &mut Tracer) -> Result<(String, Vec<bool>), String> {
+        apply_hunks(Matching::Fuzzy(&self.dmp), original, file, tracer)
+    }
+}
+
+/// A hunk applies only where its context and removed lines are exactly in
+/// the file, and only if its header counts them right. Hunks may still move
+/// to another line, like `patch --fuzz=0`.
+pub struct StrictEngine;
+
+impl PatchEngine for StrictEngine {
+    fn name(&self) -> &'static str {
+        "strict"
+    }
+
+    fn patch_file(&self, file: &FilePatch, original: &str, tracer: &mut Tracer) -> Result<(String, Vec<bool>), String> {
+        apply_hunks(Matching::Exact, original, file, tracer)
+    }
+}
+
+/// Runs `git apply --recount --reject` on each file in a scratch directory,
+/// so git's matching decides and stores that aren't git checkouts (or
+/// directories at all) work too. Needs `git` on the PATH.
+pub struct GitEngine;
+
+impl GitEngine {
+    /// Whether `git` can be run, checked once.
+    pub fn available() -> bool {
+        static AVAILABLE: OnceLock<bool> = OnceLock::new();
+        *AVAILABLE.get_or_init(|| Command::new("git").arg("--version").stdout(Stdio::null()).stderr(Stdio::null()).status().is_ok_and(|s| s.success()))
+    }
+}
+
+// Name of the file patched in the scratch directory
+const GIT_TARGET: &str = "target";
+
+static SCRATCH_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+impl PatchEngine for GitEngine {
+    fn name(&self) -> &'static str {
+        "git"
+    }
+
+    fn patch_file(&self, file: &FilePatch, original: &str, tracer: &mut Tracer) -> Result<(String, Vec<bool>), String> {
+        let hunk_count = file.hunk_count();
+        let raw = file.raw();
+        let body = raw.find("\n@@").map(|i| &raw[i + 1..]).ok_or("No hunks found in patch")?;
+        let scratch = std::env::temp_dir().join(format!("repopatch-git-apply-{}-{}", std::process::id(), SCRATCH_COUNT.fetch_add(1, Ordering::Relaxed)));
+        let result = git_apply(&scratch, original, &format!("--- a/{0}\n+++ b/{0}\n{1}\n", GIT_TARGET, body));
+        let _ = fs::remove_dir_all(&scratch);
+        let (content, stderr) = result?;
+
+        let mut applied = vec![true; hunk_count];
+        for line in stderr.lines() {
+            if let Some(number) = line.strip_prefix("Rejected hunk #").and_then(|rest| rest.trim_end_matches('.').parse::<usize>().ok()) {
+                if let Some(ok) = applied.get_mut(number.wrapping_sub(1)) {
+                    *ok = false;
+                }
+            }
+            if line.starts_with("Hunk #") || line.starts_with("Rejected hunk #") {
+                tracer.step(file.path(), "hunk", || format!("git apply: {}", line));
+            }
+        }
+        match content {
+            Some(content) => Ok((content, applied)),
+            None if applied.contains(&false) => Ok((original.to_string(), applied)),
+            None => Err(format!("git apply failed: {}", stderr.trim())),
+        }
+    }
+}
+
+// Helper function to run git apply on `original` in a fresh `scratch`
+// directory, returning the patched content (`None` if git refused) and git's
+// messages
+fn git_apply(scratch: &Path, original: &str, patch: &str) -> Result<(Option<String>, String), String> {
+    fs::create_dir_all(scratch).map_err(|e| format!("Failed to create {}: {}", scratch.display(), e))?;
+    let target = scratch.join(GIT_TARGET);
+    fs::write(&target, original).map_err(|e| format!("Failed to write {}: {}", target.display(), e))?;
+    // Keep git from finding a repository around the temp directory
+    let ceiling = scratch.parent().map(Path::to_path_buf).unwrap_or_else(|| PathBuf::from("/"));
+    let mut child = Command::new("git")
+        .current_dir(scratch)
+        .args(["apply", "--recount", "--reject", "--verbose", "--whitespace=nowarn", "-"])
+        .env("GIT_CEILING_DIRECTORIES", ceiling)
+        .env_remove("GIT_DIR")
+        .env_remove("GIT_WORK_TREE")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run git: {}", e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(patch.as_bytes()).map_err(|e| format!("Failed to write to git: {}", e))?;
+    }
+    let output = child.wait_with_output().map_err(|e| format!("Failed to run git: {}", e))?;
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    let content = match output.status.success() {
+        true => Some(fs::read_to_string(&target).map_err(|e| format!("Failed to read {}: {}", target.display(), e))?),
+        false => None,
+    };
+    Ok((content, stderr))
+}
+
+/// Applies diff-match-patch patch text, as made by `patch_toText`, with its
+/// own fuzzy matching. Each `@@` patch is a hunk.
+#[derive(Default)]
+pub struct DmpTextEngine {
+    dmp: DiffMatchPatch,
+}
+
+impl PatchEngine for DmpTextEngine {
+    fn name(&self) -> &'static str {
+        "dmp-text"
+    }
+
+    fn patch_file(&self, file: &FilePatch, original: &str, tracer: &mut Tracer) -> Result<(String, Vec<bool>), String> {
+        let raw = file.raw();
+        let body = raw.find("\n@@").map(|i| &raw[i + 1..]).ok_or("No hunks found in patch")?;
+        let patches = self.dmp.patch_from_text::<Compat>(body).map_err(|e| format!("Invalid diff-match-patch text: {:?}", e))?;
+        let (content, applied) = self.dmp.patch_apply(&patches, original).map_err(|e| format!("{:?}", e))?;
+        for (index, ok) in applied.iter().enumerate() {
+            tracer.step(file.path(), "hunk", || format!("Patch {} {}", index + 1, if *ok { "applied" } else { "has no match" }));
+        }
+        Ok((content, applied))
+    }
+}
+
+/// Reads SEARCH/REPLACE blocks as hunks that remove the search lines and add
+/// the replacement, found like [`DmpEngine`] finds hunks. Paths are taken as
+/// written, whatever the strip level.
+#[derive(Default)]
+pub struct SearchReplaceEngine {
+    dmp: DiffMatchPatch,
+}
+
+impl PatchEngine for SearchReplaceEngine {
+    fn name(&self) -> &'static str {
+        "search-replace"
+    }
+
+    fn parse(&self, patch_content: &str, _strip_level: usize) -> PatchSet {
+        PatchSet::parse(&search_replace_to_unified(patch_content), 1)
+    }
+
+    fn patch_file(&self, file: &FilePatch, original: &str, tracer: &mut Tracer) -> Result<(String, Vec<bool>), String> {
+        apply_hunks(Matching::Fuzzy(&self.dmp), original, file, tracer)
+    }
+}
+
+// Helper function to write SEARCH/REPLACE blocks as a unified diff with a file
+// section per block. A block's path is the last line before it that isn't a
+// code fence; blocks without a path or an end are left out.
+fn search_replace_to_unified(content: &str) -> String {
+    let lines: Vec<&str> = content.lines().map(|l| l.trim_end_matches('\r')).collect();
+    let mut unified = String::new();
+    let mut path: Option<&str> = None;
+    let mut index = 0;
+    while index < lines.len() {
+        let line = lines[index];
+        index += 1;
+        if !SEARCH_MARKER.is_match(line) {
+            let candidate = line.trim().trim_matches(['`', '*']).trim_end_matches(':');
+            if !candidate.is_empty() && !line.trim_start().starts_with("```") {
+                path = Some(candidate);
+            }
+            continue;
+        }
+        let start = index;
+        let Some(divider) = lines[start..].iter().position(|l| DIVIDER_MARKER.is_match(l)).map(|p| start + p) else {
+            continue;
+        };
+        let Some(end) = lines[divider..].iter().position(|l| REPLACE_MARKER.is_match(l)).map(|p| divider + p) else {
+            continue;
+        };
+        index = end + 1;
+        let Some(path) = path else {
+            continue;
+        };
+        let (search, replace) = (&lines[start..divider], &lines[divider + 1..end]);
+        if search.is_empty() {
+            unified.push_str(&format!("--- /dev/null\n+++ b/{}\n@@ -0,0 +1,{} @@\n", path, replace.len()));
+        } else {
+            unified.push_str(&format!("--- a/{0}\n+++ b/{0}\n@@ -1,{1} +1,{2} @@\n", path, search.len(), replace.len()));
+        }
+        search.iter().for_each(|l| unified.push_str(&format!("-{}\n", l)));
+        replace.iter().for_each(|l| unified.push_str(&format!("+{}\n", l)));
+    }
+    unified
+}
@@ -0,0 +1,52 @@
+//! Single definitions cut out of a file, so a prompt for a targeted edit can
+//! carry one function or type instead of the whole source.
+
+use crate::syntax::{self, Language};
+use serde::Serialize;
+
+/// The source of one definition.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Extract {
+    pub name: String,
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+    /// 1-based first and last line of `content`.
+    #[serde(rename = "startLine")]
+    pub start_line: usize,
+    #[serde(rename = "endLine")]
+    pub end_line: usize,
+    /// Whole lines: the definition with its doc comments and attributes,
+    /// plus `context` lines on either side.
+    pub content: String,
+}
+
+/// Finds the definitions of `symbol` in `source` (the file at `path`).
+/// `symbol` is a name, or `Type::name` / `Class.name` for a method. Every
+/// match is returned, e.g. a method several types implement.
+pub fn extract(path: &str, source: &str, symbol: &str, context: usize) -> Result<Vec<Extract>, String> {
+    let language = Language::from_path(path).ok_or_else(|| format!("Can't parse {}: only Rust, TypeScript/JavaScript and Python are supported", path))?;
+    let (container, name) = match symbol.rsplit_once("::").or_else(|| symbol.rsplit_once('.')) {
+        Some((container, name)) => (Some(container), name),
+        None => (None, symbol),
+    };
+    if name.is_empty() {
+        return Err("Symbol is required".to_string());
+    }
+    let tree = syntax::parse(language, source)?;
+
+    let mut starts = vec![0];
+    starts.extend(source.match_indices('\n').map(|(i, _)| i + 1).filter(|i| *i < source.len()));
+    let line_of = |offset: usize| starts.partition_point(|s| *s <= offset);
+    let extracts = syntax::definitions(language, &tree, source)
+        .into_iter()
+        .filter(|d| d.name == name && container.is_none_or(|c| d.container.as_deref() == Some(c)))
+        .map(|d| {
+            let first = line_of(d.range.start).saturating_sub(context).max(1);
+            let last = (line_of(d.range.end.saturating_sub(1).max(d.range.start)) + context).min(starts.len());
+            let end = starts.get(last).copied().unwrap_or(source.len());
+            Extract { name: d.name, kind: d.kind, container: d.container, start_line: first, end_line: last, content: source[starts[first - 1]..end].to_string() }
+        })
+        .collect();
+    Ok(extracts)
+}
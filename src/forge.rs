@@ -0,0 +1,244 @@
+// Opens pull requests for changes applied through repopatch. POST
+// /api/pr/create takes a report from history.rs, commits its files onto a
+// new branch with a private index (the working tree and the checked out
+// branch are left alone), pushes the branch with the repository's own git
+// credentials and opens a GitHub pull request or GitLab merge request.
+//
+// Forge credentials come from GITHUB_TOKEN (GITHUB_API_URL for GitHub
+// Enterprise) and GITLAB_TOKEN (GITLAB_URL for self-hosted GitLab).
+
+use crate::activity::{Activity, Event};
+use crate::auth::{Session, Stores};
+use crate::history::{History, Report};
+use crate::validation::ValidJson;
+use actix_web::{post, web, HttpResponse};
+use repopatch::diff;
+use serde::Deserialize;
+use serde_json::json;
+use std::env;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const DEFAULT_GITHUB_API: &str = "https://api.github.com";
+const DEFAULT_GITLAB_URL: &str = "https://gitlab.com";
+const DEFAULT_REMOTE: &str = "origin";
+const MAX_FORGE_RESPONSE_BYTES: usize = 1024 * 1024;
+
+#[derive(Deserialize)]
+pub struct CreatePrRequest {
+    #[serde(rename = "reportId")]
+    report_id: String,
+    /// Defaults to a summary of the changed files.
+    title: Option<String>,
+    /// Defaults to the diffstat of the change.
+    body: Option<String>,
+    /// Defaults to `repopatch/<reportId>`.
+    branch: Option<String>,
+    /// Target branch; defaults to the checked out branch.
+    base: Option<String>,
+    remote: Option<String>,
+    draft: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Forge {
+    GitHub { api: String, token: String },
+    GitLab { url: String, token: String },
+}
+
+/// A remote URL split into host and `owner/repo` path.
+#[derive(Debug, PartialEq, Eq)]
+struct RemoteRepo {
+    host: String,
+    path: String,
+}
+
+// Helper function to parse https, ssh:// and scp-style git remote URLs
+fn parse_remote(url: &str) -> Option<RemoteRepo> {
+    let url = url.trim();
+    let rest = if let Some((_, rest)) = url.split_once("://") {
+        rest.rsplit_once('@').map(|(_, r)| r).unwrap_or(rest).to_string()
+    } else {
+        // git@host:owner/repo
+        let (_, rest) = url.rsplit_once('@').unwrap_or(("", url));
+        rest.replacen(':', "/", 1)
+    };
+    let (host, path) = rest.split_once('/')?;
+    let host = host.split(':').next().unwrap_or(host).to_string();
+    let path = path.trim_end_matches('/').trim_end_matches(".git").to_string();
+    (!host.is_empty() && path.contains('/')).then_some(RemoteRepo { host, path })
+}
+
+// Helper function to pick the forge for a remote host from the configured tokens
+fn forge_for(host: &str) -> Result<Forge, String> {
+    let gitlab_url = env::var("GITLAB_URL").unwrap_or_else(|_| DEFAULT_GITLAB_URL.to_string());
+    let gitlab_host = gitlab_url.split("://").nth(1).unwrap_or(&gitlab_url).trim_end_matches('/');
+    if host == gitlab_host || host.contains("gitlab") {
+        let token = env::var("GITLAB_TOKEN").map_err(|_| format!("Set GITLAB_TOKEN to open merge requests on {}", host))?;
+        return Ok(Forge::GitLab { url: gitlab_url.trim_end_matches('/').to_string(), token });
+    }
+    let api = env::var("GITHUB_API_URL").unwrap_or_else(|_| DEFAULT_GITHUB_API.to_string());
+    if host == "github.com" || env::var("GITHUB_API_URL").is_ok() {
+        let token = env::var("GITHUB_TOKEN").map_err(|_| format!("Set GITHUB_TOKEN to open pull requests on {}", host))?;
+        return Ok(Forge::GitHub { api: api.trim_end_matches('/').to_string(), token });
+    }
+    Err(format!("Don't know which forge hosts {}; set GITHUB_API_URL or GITLAB_URL", host))
+}
+
+/// Runs git in `dir`, returning its trimmed stdout.
+pub fn git(dir: &Path, args: &[&str], envs: &[(&str, &Path)], stdin: Option<&[u8]>) -> Result<String, String> {
+    let mut command = Command::new("git");
+    command.current_dir(dir).args(args).stdout(Stdio::piped()).stderr(Stdio::piped());
+    command.stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() });
+    for (key, value) in envs {
+        command.env(key, value);
+    }
+    let mut child = command.spawn().map_err(|e| format!("Failed to run git: {}", e))?;
+    if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        pipe.write_all(input).map_err(|e| format!("Failed to write to git: {}", e))?;
+    }
+    let output = child.wait_with_output().map_err(|e| format!("Failed to run git: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("git {} failed: {}", args.first().unwrap_or(&""), String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// The branch pushed for a report.
+struct PushedBranch {
+    commit: String,
+    base: String,
+    remote: RemoteRepo,
+    warnings: Vec<String>,
+}
+
+// Builds a commit holding the report's files on top of HEAD without touching
+// the working tree or the real index, then pushes it as `branch`
+fn push_branch(report: &Report, branch: &str, base: Option<&str>, remote: &str, message: &str) -> Result<PushedBranch, String> {
+    let dir = Path::new(&report.root);
+    let remote_url = git(dir, &["remote", "get-url", remote], &[], None)?;
+    let remote_repo = parse_remote(&remote_url).ok_or_else(|| format!("Can't parse the URL of remote {}: {}", remote, remote_url))?;
+    let prefix = git(dir, &["rev-parse", "--show-prefix"], &[], None)?;
+    let head = git(dir, &["rev-parse", "HEAD"], &[], None)?;
+    let base = match base {
+        Some(base) => base.to_string(),
+        None => git(dir, &["rev-parse", "--abbrev-ref", "HEAD"], &[], None)?,
+    };
+    let index = dir.join(git(dir, &["rev-parse", "--git-path", "repopatch-index"], &[], None)?);
+    let _cleanup = RemoveOnDrop(index.clone());
+    let env = [("GIT_INDEX_FILE", index.as_path())];
+
+    git(dir, &["read-tree", "HEAD"], &env, None)?;
+    let mut warnings = Vec::new();
+    for change in &report.changes {
+        let path = format!("{}{}", prefix, change.path);
+        let committed = git(dir, &["show", &format!("HEAD:{}", path)], &[], None).ok();
+        if committed.as_deref() != change.old.as_deref().map(str::trim) {
+            warnings.push(format!("{} had uncommitted changes before the patch; they are included", change.path));
+        }
+        match &change.new {
+            Some(content) => {
+                let blob = git(dir, &["hash-object", "-w", "--stdin"], &[], Some(content.as_bytes()))?;
+                let mode = git(dir, &["ls-tree", "HEAD", "--", &path], &[], None)?
+                    .split_whitespace()
+                    .next()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| "100644".to_string());
+                git(dir, &["update-index", "--add", "--cacheinfo", &format!("{},{},{}", mode, blob, path)], &env, None)?;
+            }
+            None => {
+                git(dir, &["update-index", "--force-remove", "--", &path], &env, None)?;
+            }
+        }
+    }
+    let tree = git(dir, &["write-tree"], &env, None)?;
+    let commit = git(dir, &["commit-tree", &tree, "-p", &head, "-F", "-"], &[], Some(message.as_bytes()))?;
+    git(dir, &["push", remote, &format!("{}:refs/heads/{}", commit, branch)], &[], None)?;
+    log::info!("Pushed {} as {} to {}", commit, branch, remote_url);
+    Ok(PushedBranch { commit, base, remote: remote_repo, warnings })
+}
+
+struct RemoveOnDrop(PathBuf);
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+// Helper function to open the pull or merge request, returning its web URL
+async fn open_request(forge: &Forge, repo: &RemoteRepo, branch: &str, base: &str, title: &str, body: &str, draft: bool) -> Result<String, String> {
+    let client = awc::Client::default();
+    let (request, payload, url_key) = match forge {
+        Forge::GitHub { api, token } => (
+            client
+                .post(format!("{}/repos/{}/pulls", api, repo.path))
+                .bearer_auth(token)
+                .insert_header(("Accept", "application/vnd.github+json"))
+                .insert_header(("User-Agent", concat!("repopatch/", env!("CARGO_PKG_VERSION")))),
+            json!({ "title": title, "body": body, "head": branch, "base": base, "draft": draft }),
+            "html_url",
+        ),
+        Forge::GitLab { url, token } => {
+            let project: String = serde_urlencoded::to_string([("id", &repo.path)]).unwrap_or_default().trim_start_matches("id=").to_string();
+            let title = if draft { format!("Draft: {}", title) } else { title.to_string() };
+            (
+                client.post(format!("{}/api/v4/projects/{}/merge_requests", url, project)).insert_header(("PRIVATE-TOKEN", token.as_str())),
+                json!({ "title": title, "description": body, "source_branch": branch, "target_branch": base }),
+                "web_url",
+            )
+        }
+    };
+    let mut response = request.send_json(&payload).await.map_err(|e| format!("Forge request failed: {}", e))?;
+    let status = response.status();
+    let body = response.body().limit(MAX_FORGE_RESPONSE_BYTES).await.map_err(|e| format!("Failed to read forge response: {}", e))?;
+    let value: serde_json::Value = serde_json::from_slice(&body).unwrap_or_default();
+    if !status.is_success() {
+        let message = value.get("message").map(|m| m.to_string()).unwrap_or_else(|| String::from_utf8_lossy(&body).into_owned());
+        return Err(format!("Forge returned {}: {}", status, message));
+    }
+    value.get(url_key).and_then(|u| u.as_str()).map(str::to_string).ok_or_else(|| "Forge response has no URL".to_string())
+}
+
+#[post("/api/pr/create")]
+pub async fn create(
+    body: ValidJson<CreatePrRequest>,
+    stores: Stores,
+    session: Option<web::ReqData<Session>>,
+    history: web::Data<History>,
+    activity: web::Data<Activity>,
+) -> HttpResponse {
+    let Some(report) = history.get(&body.report_id) else {
+        return HttpResponse::NotFound().json(json!({ "success": false, "error": format!("No applied patch with report id {}", body.report_id) }));
+    };
+    if let Err(e) = stores.open_root(&report.root) {
+        return HttpResponse::Forbidden().json(json!({ "success": false, "error": e }));
+    }
+    let title = body.title.clone().unwrap_or_else(|| report.default_subject());
+    let description = body.body.clone().unwrap_or_else(|| format!("Applied with repopatch.\n\n```\n{}```\n", diff::diffstat(&report.changes)));
+    let branch = body.branch.clone().unwrap_or_else(|| format!("repopatch/{}", report.id));
+    let remote = body.remote.clone().unwrap_or_else(|| DEFAULT_REMOTE.to_string());
+    let message = format!("{}\n\n{}", title, body.body.clone().unwrap_or_else(|| diff::diffstat(&report.changes)));
+    let (root, files): (String, Vec<String>) = (report.root.clone(), report.changes.iter().map(|c| c.path.clone()).collect());
+
+    let (task_branch, base) = (branch.clone(), body.base.clone());
+    let pushed = match web::block(move || push_branch(&report, &task_branch, base.as_deref(), &remote, &message)).await {
+        Ok(Ok(pushed)) => pushed,
+        Ok(Err(e)) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+        Err(e) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Git task failed: {}", e) })),
+    };
+    let forge = match forge_for(&pushed.remote.host) {
+        Ok(f) => f,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e, "branch": branch, "commit": pushed.commit })),
+    };
+    match open_request(&forge, &pushed.remote, &branch, &pushed.base, &title, &description, body.draft.unwrap_or(false)).await {
+        Ok(url) => {
+            log::info!("Opened {} for {}", url, branch);
+            let event = Event::new("vcs", "pull_request", format!("Opened {} from {}", url, branch)).files(files).report(body.report_id.clone());
+            activity.record(&root, session.as_deref(), event);
+            HttpResponse::Ok().json(json!({ "success": true, "url": url, "branch": branch, "commit": pushed.commit, "warnings": pushed.warnings }))
+        }
+        Err(e) => HttpResponse::BadGateway().json(json!({ "success": false, "error": e, "branch": branch, "commit": pushed.commit })),
+    }
+}
@@ -0,0 +1,154 @@
+// Runs code formatters over files and turns their output into a diff, so
+// style drift introduced by LLM patches can be reviewed or cleaned up in
+// the same step. Formatters read the file on stdin and print the result;
+// `{path}` in a command is replaced by the file's path relative to the root.
+//
+// repopatch.toml:
+//   [[formatters]]
+//   extensions = ["rs"]
+//   command = ["rustfmt", "--edition", "2021", "--emit", "stdout"]
+//
+// Without a [[formatters]] entry rustfmt, prettier and black are used.
+
+use repopatch::diff::unified_diff;
+use repopatch::store::FileStore;
+use serde::Deserialize;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const FORMAT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct FormatterConfig {
+    /// Extensions (without the dot) this formatter handles.
+    pub extensions: Vec<String>,
+    /// Program and arguments.
+    pub command: Vec<String>,
+}
+
+pub struct Formatters(Vec<FormatterConfig>);
+
+/// Files formatted (or not) by one request.
+#[derive(Default)]
+pub struct FormatOutcome {
+    pub patch: String,
+    pub changed: Vec<String>,
+    pub unchanged: Vec<String>,
+    /// Files without a configured formatter.
+    pub skipped: Vec<String>,
+    pub errors: Vec<(String, String)>,
+}
+
+impl Formatters {
+    pub fn new(configured: Vec<FormatterConfig>) -> Self {
+        if !configured.is_empty() {
+            return Formatters(configured);
+        }
+        let formatter = |extensions: &[&str], command: &[&str]| FormatterConfig {
+            extensions: extensions.iter().map(|e| e.to_string()).collect(),
+            command: command.iter().map(|c| c.to_string()).collect(),
+        };
+        Formatters(vec![
+            formatter(&["rs"], &["rustfmt", "--edition", "2021", "--emit", "stdout"]),
+            formatter(
+                &["js", "jsx", "mjs", "cjs", "ts", "tsx", "css", "scss", "json", "md", "yaml", "yml", "html", "vue"],
+                &["prettier", "--stdin-filepath", "{path}"],
+            ),
+            formatter(&["py", "pyi"], &["black", "--quiet", "-"]),
+        ])
+    }
+
+    fn for_path(&self, path: &str) -> Option<&FormatterConfig> {
+        let extension = path.rsplit_once('.')?.1.to_lowercase();
+        self.0.iter().find(|f| f.extensions.iter().any(|e| e.eq_ignore_ascii_case(&extension)))
+    }
+
+    /// Formats `paths` (store paths) without writing anything.
+    pub fn format(&self, store: &dyn FileStore, paths: &[String]) -> FormatOutcome {
+        let root = store.display_path("");
+        let cwd = Path::new(&root).is_dir().then(|| Path::new(&root));
+        let mut outcome = FormatOutcome::default();
+        for path in paths {
+            let Some(formatter) = self.for_path(path) else {
+                outcome.skipped.push(path.clone());
+                continue;
+            };
+            let original = match store.read(path).map(String::from_utf8) {
+                Ok(Ok(content)) => content,
+                Ok(Err(_)) => {
+                    outcome.errors.push((path.clone(), "file is not valid UTF-8".to_string()));
+                    continue;
+                }
+                Err(e) => {
+                    outcome.errors.push((path.clone(), e.to_string()));
+                    continue;
+                }
+            };
+            match run_formatter(formatter, path, &original, cwd) {
+                Ok(formatted) if formatted == original => outcome.unchanged.push(path.clone()),
+                Ok(formatted) => {
+                    outcome.patch.push_str(&unified_diff(path, &original, &formatted));
+                    outcome.changed.push(path.clone());
+                }
+                Err(e) => outcome.errors.push((path.clone(), e)),
+            }
+        }
+        outcome
+    }
+}
+
+// Helper function to pipe `input` through a formatter, killing it after FORMAT_TIMEOUT
+fn run_formatter(formatter: &FormatterConfig, path: &str, input: &str, cwd: Option<&Path>) -> Result<String, String> {
+    let (program, args) = formatter.command.split_first().ok_or("Formatter command is empty")?;
+    let mut command = Command::new(program);
+    command
+        .args(args.iter().map(|a| a.replace("{path}", path)))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(cwd) = cwd {
+        command.current_dir(cwd);
+    }
+    let mut child = command.spawn().map_err(|e| format!("Failed to run {}: {}", program, e))?;
+
+    // Feed stdin and drain the pipes on threads so a large file can't deadlock
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = input.to_string();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let reader = thread::spawn(move || {
+        let mut out = String::new();
+        stdout.read_to_string(&mut out).map(|_| out)
+    });
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let errors = thread::spawn(move || {
+        let mut out = String::new();
+        let _ = stderr.read_to_string(&mut out);
+        out
+    });
+
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait().map_err(|e| e.to_string())? {
+            Some(status) => break status,
+            None if started.elapsed() > FORMAT_TIMEOUT => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("{} timed out after {}s", program, FORMAT_TIMEOUT.as_secs()));
+            }
+            None => thread::sleep(Duration::from_millis(20)),
+        }
+    };
+    let _ = writer.join();
+    let output = reader.join().map_err(|_| "Formatter output reader panicked".to_string())?;
+    let stderr = errors.join().unwrap_or_default();
+    if !status.success() {
+        let detail = stderr.lines().next().unwrap_or("no error output");
+        return Err(format!("{} failed ({}): {}", program, status, detail));
+    }
+    output.map_err(|e| format!("{} printed invalid output: {}", program, e))
+}
@@ -0,0 +1,168 @@
+// Recent patch applications, kept in memory so a change made through
+// repopatch can be exported later (GET /api/export_patch). Each report holds
+// the before and after content of the files it touched. With DATA_DIR they
+// are also rows of the metadata database, so they outlive restarts.
+
+use crate::auth::random_token;
+use crate::memory::CacheSize;
+use chrono::{DateTime, Utc};
+use repopatch::diff::FileChange;
+use repopatch::metadata::Metadata;
+use repopatch::patch::{ApplyOutcome, PatchSet};
+use repopatch::store::FileStore;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+const MAX_REPORTS: usize = 100;
+/// Record kind in the metadata database, keyed by report id.
+const KIND: &str = "reports";
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub id: String,
+    /// Display path of the directory the patch was applied to.
+    pub root: String,
+    pub created: DateTime<Utc>,
+    pub changes: Vec<FileChange>,
+}
+
+/// A report as saved in the metadata database and in state exports.
+#[derive(Serialize, Deserialize)]
+pub struct StoredReport {
+    #[serde(default)]
+    pub id: String,
+    pub root: String,
+    pub created: String,
+    pub changes: Vec<FileChange>,
+}
+
+impl From<&Report> for StoredReport {
+    fn from(report: &Report) -> Self {
+        StoredReport { id: report.id.clone(), root: report.root.clone(), created: report.created.to_rfc3339(), changes: report.changes.clone() }
+    }
+}
+
+impl StoredReport {
+    pub fn into_report(self) -> Result<Report, String> {
+        let created = DateTime::parse_from_rfc3339(&self.created).map_err(|e| format!("Invalid date in report {}: {}", self.id, e))?;
+        Ok(Report { id: self.id, root: self.root, created: created.with_timezone(&Utc), changes: self.changes })
+    }
+}
+
+pub struct History {
+    reports: Mutex<VecDeque<Report>>,
+    metadata: Option<Arc<Metadata>>,
+}
+
+/// Content of every file `patch` reads or writes, taken before applying it.
+pub fn snapshot(store: &dyn FileStore, patch: &PatchSet) -> HashMap<String, Option<String>> {
+    patch
+        .files
+        .iter()
+        .flat_map(|f| f.old_path.iter().chain(f.new_path.iter()))
+        .map(|path| (path.clone(), store.read(path).ok().map(|data| String::from_utf8_lossy(&data).into_owned())))
+        .collect()
+}
+
+/// The changes `outcome` made, comparing `before` with the store's current
+/// content. Renames show up as a deletion and a creation.
+pub fn changes(store: &dyn FileStore, patch: &PatchSet, outcome: &ApplyOutcome, before: &HashMap<String, Option<String>>) -> Vec<FileChange> {
+    let mut changes: Vec<FileChange> = Vec::new();
+    for file in &patch.files {
+        let applied = file.new_path.as_deref().unwrap_or(file.path());
+        if !outcome.applied_files.iter().any(|p| p == applied) {
+            continue;
+        }
+        for path in file.old_path.iter().chain(file.new_path.iter()) {
+            if changes.iter().any(|c| &c.path == path) {
+                continue;
+            }
+            let old = before.get(path).cloned().flatten();
+            let new = store.read(path).ok().map(|data| String::from_utf8_lossy(&data).into_owned());
+            changes.push(FileChange { path: path.clone(), old, new });
+        }
+    }
+    changes
+}
+
+impl Report {
+    /// Commit subject used when the caller doesn't provide one.
+    pub fn default_subject(&self) -> String {
+        let paths: Vec<&str> = self.changes.iter().map(|c| c.path.as_str()).collect();
+        format!("Update {}", paths.join(", "))
+    }
+}
+
+impl History {
+    /// An empty history, or the latest reports in `metadata` when given.
+    pub fn new(metadata: Option<Arc<Metadata>>) -> Result<Self, String> {
+        let mut reports = VecDeque::new();
+        if let Some(metadata) = &metadata {
+            metadata.prune(KIND, MAX_REPORTS)?;
+            for (id, stored) in metadata.load_as::<StoredReport>(KIND)? {
+                reports.push_back(StoredReport { id, ..stored }.into_report()?);
+            }
+        }
+        Ok(History { reports: Mutex::new(reports), metadata })
+    }
+
+    /// Stores a report and returns its id.
+    pub fn record(&self, root: String, changes: Vec<FileChange>) -> String {
+        let id = random_token()[..16].to_string();
+        let report = Report { id: id.clone(), root, created: Utc::now(), changes };
+        self.save(&report);
+        let mut reports = self.reports.lock().unwrap_or_else(|e| e.into_inner());
+        if reports.len() == MAX_REPORTS {
+            reports.pop_front();
+        }
+        reports.push_back(report);
+        id
+    }
+
+    fn save(&self, report: &Report) {
+        let Some(metadata) = &self.metadata else { return };
+        // The report still works from memory until a restart
+        if let Err(e) = metadata.put(KIND, &report.id, &StoredReport::from(report)).and_then(|()| metadata.prune(KIND, MAX_REPORTS)) {
+            log::warn!("Failed to save report {}: {}", report.id, e);
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<Report> {
+        let reports = self.reports.lock().unwrap_or_else(|e| e.into_inner());
+        reports.iter().find(|r| r.id == id).cloned()
+    }
+
+    /// Every kept report, oldest first.
+    pub fn reports(&self) -> Vec<Report> {
+        self.reports.lock().unwrap_or_else(|e| e.into_inner()).iter().cloned().collect()
+    }
+
+    /// Adds a report made elsewhere in order of creation, unless one with its
+    /// id is kept or it is older than every report of a full history.
+    /// Returns whether it was added.
+    pub fn restore(&self, report: Report) -> bool {
+        let mut reports = self.reports.lock().unwrap_or_else(|e| e.into_inner());
+        if reports.iter().any(|r| r.id == report.id) {
+            return false;
+        }
+        let at = reports.partition_point(|r| r.created <= report.created);
+        if at == 0 && reports.len() == MAX_REPORTS {
+            return false;
+        }
+        self.save(&report);
+        reports.insert(at, report);
+        if reports.len() > MAX_REPORTS {
+            reports.pop_front();
+        }
+        true
+    }
+}
+
+impl CacheSize for History {
+    fn cached_bytes(&self) -> u64 {
+        let reports = self.reports.lock().unwrap_or_else(|e| e.into_inner());
+        let content = |c: &FileChange| c.old.as_ref().map_or(0, String::len) + c.new.as_ref().map_or(0, String::len);
+        reports.iter().flat_map(|r| &r.changes).map(|c| content(c) as u64).sum()
+    }
+}
@@ -0,0 +1,88 @@
+// Error messages in the client's language. Handlers answer in English; this
+// middleware looks the `error` of failed JSON responses up in the message
+// catalogs, replaces it with the translation for the request's
+// Accept-Language and adds its `messageCode`, keeping the English text in
+// `errorOriginal` for logs and bug reports.
+//
+// GET /api/messages hands a UI the whole catalog for one language, so it can
+// show the same wording for messages it builds from codes.
+
+use actix_web::body::{self, BodySize, EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{get, web, Error, HttpRequest, HttpResponse};
+use repopatch::messages::Catalogs;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// Error bodies are small; bigger ones are passed through untouched.
+const MAX_ERROR_BODY: usize = 64 * 1024;
+
+fn accept_language(req: &HttpRequest) -> Option<&str> {
+    req.headers().get(header::ACCEPT_LANGUAGE).and_then(|v| v.to_str().ok())
+}
+
+/// Middleware translating the `error` of failed JSON responses.
+pub async fn localize(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let Some(catalogs) = req.app_data::<web::Data<Catalogs>>().cloned() else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    let response = next.call(req).await?;
+    let json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    let small = matches!(response.response().body().size(), BodySize::Sized(n) if n as usize <= MAX_ERROR_BODY);
+    if response.status().as_u16() < 400 || !json || !small {
+        return Ok(response.map_into_left_body());
+    }
+
+    let locale = catalogs.negotiate(accept_language(response.request())).to_string();
+    let (req, response) = response.into_parts();
+    let (mut response, body) = response.into_parts();
+    let bytes = body::to_bytes(body).await.map_err(|e| actix_web::error::ErrorInternalServerError(e.into().to_string()))?;
+    let mut value: Value = match serde_json::from_slice(&bytes) {
+        Ok(value) => value,
+        Err(_) => return Ok(ServiceResponse::new(req, response.set_body(bytes).map_into_boxed_body().map_into_right_body())),
+    };
+    let localized = value["error"].as_str().and_then(|error| Some((error.to_string(), catalogs.localize(error, &locale)?)));
+    if let (Some((original, localized)), Some(object)) = (localized, value.as_object_mut()) {
+        if localized.text != original {
+            object.insert("errorOriginal".to_string(), json!(original));
+        }
+        object.insert("error".to_string(), json!(localized.text));
+        object.insert("messageCode".to_string(), json!(localized.code));
+        if let Ok(language) = HeaderValue::from_str(&locale) {
+            response.headers_mut().insert(header::CONTENT_LANGUAGE, language);
+        }
+    }
+    response.headers_mut().append(header::VARY, HeaderValue::from_static("Accept-Language"));
+    let response = response.set_body(serde_json::to_vec(&value).unwrap_or_else(|_| bytes.to_vec()));
+    Ok(ServiceResponse::new(req, response.map_into_boxed_body().map_into_right_body()))
+}
+
+#[derive(Deserialize)]
+pub struct MessagesQuery {
+    /// A language tag; the request's Accept-Language when missing.
+    lang: Option<String>,
+}
+
+#[get("/api/messages")]
+pub async fn get_messages(req: HttpRequest, query: web::Query<MessagesQuery>, catalogs: web::Data<Catalogs>) -> HttpResponse {
+    let locale = catalogs.negotiate(query.lang.as_deref().or(accept_language(&req)));
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_LANGUAGE, locale))
+        .insert_header((header::VARY, "Accept-Language"))
+        .json(json!({
+            "success": true,
+            "locale": locale,
+            "defaultLocale": catalogs.default_locale(),
+            "locales": catalogs.locales(),
+            "messages": catalogs.messages(locale),
+        }))
+}
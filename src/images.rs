@@ -0,0 +1,423 @@
+//! Images in a repository: their format and dimensions, read from the file
+//! headers, and downscaled PNG previews. PNG and BMP images are decoded
+//! here; other formats are only described, and previews of them are left
+//! to the client.
+
+use serde::Serialize;
+use std::io::{Read, Write};
+
+/// Bytes read from the start of an image for its dimensions when listing
+/// a tree. JPEG files with large metadata segments may need more; they are
+/// listed without dimensions.
+pub const HEADER_BYTES: usize = 256 * 1024;
+
+/// Images with more pixels than this aren't decoded.
+pub const MAX_PIXELS: u64 = 40_000_000;
+
+const EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "webp", "bmp", "ico", "svg"];
+
+/// What a listed image is.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageInfo {
+    /// `png`, `jpeg`, `gif`, `webp`, `bmp`, `ico` or `svg`.
+    pub format: &'static str,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ImageInfo {
+    /// The media type to serve the image as.
+    pub fn content_type(&self) -> &'static str {
+        match self.format {
+            "jpeg" => "image/jpeg",
+            "gif" => "image/gif",
+            "webp" => "image/webp",
+            "bmp" => "image/bmp",
+            "ico" => "image/x-icon",
+            "svg" => "image/svg+xml",
+            _ => "image/png",
+        }
+    }
+
+    /// Whether [`decode`] can read images of this format.
+    pub fn decodable(&self) -> bool {
+        matches!(self.format, "png" | "bmp")
+    }
+}
+
+/// Whether `path` names an image by its extension.
+pub fn is_image(path: &str) -> bool {
+    path.rsplit_once('.').is_some_and(|(_, ext)| EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+/// The format and dimensions of the image `data`, None when it isn't one
+/// or its header says nothing about its size.
+pub fn image_info(data: &[u8]) -> Option<ImageInfo> {
+    let be16 = |at: usize| data.get(at..at + 2).map(|b| u16::from_be_bytes([b[0], b[1]]) as u32);
+    let le16 = |at: usize| data.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as u32);
+    let be32 = |at: usize| data.get(at..at + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+    let le32 = |at: usize| data.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    let info = |format, width, height| Some(ImageInfo { format, width, height });
+
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") && data.get(12..16) == Some(b"IHDR") {
+        return info("png", be32(16)?, be32(20)?);
+    }
+    if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        return info("gif", le16(6)?, le16(8)?);
+    }
+    if data.starts_with(b"BM") && data.len() > 26 {
+        let height = le32(22)? as i32;
+        return info("bmp", le32(18)? as i32 as u32, height.unsigned_abs());
+    }
+    if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        return match data.get(12..16)? {
+            b"VP8 " => info("webp", le16(26)? & 0x3fff, le16(28)? & 0x3fff),
+            b"VP8L" => {
+                let bits = le32(21)?;
+                info("webp", (bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1)
+            }
+            b"VP8X" => {
+                let le24 = |at: usize| data.get(at..at + 3).map(|b| u32::from_le_bytes([b[0], b[1], b[2], 0]));
+                info("webp", le24(24)? + 1, le24(27)? + 1)
+            }
+            _ => None,
+        };
+    }
+    if data.starts_with(&[0, 0, 1, 0]) && le16(4)? > 0 {
+        let side = |byte: u8| if byte == 0 { 256 } else { byte as u32 };
+        return info("ico", side(*data.get(6)?), side(*data.get(7)?));
+    }
+    if data.starts_with(&[0xff, 0xd8]) {
+        // The first start-of-frame segment has the size
+        let mut at = 2;
+        while at + 9 < data.len() {
+            if data[at] != 0xff {
+                return None;
+            }
+            let marker = data[at + 1];
+            if marker == 0xff {
+                at += 1;
+                continue;
+            }
+            let is_frame = (0xc0..=0xcf).contains(&marker) && !matches!(marker, 0xc4 | 0xc8 | 0xcc);
+            if is_frame {
+                return info("jpeg", be16(at + 7)?, be16(at + 5)?);
+            }
+            at += 2 + be16(at + 2)? as usize;
+        }
+        return None;
+    }
+    svg_info(data)
+}
+
+// An SVG's size from the width and height of its root element, or else
+// from its viewBox
+fn svg_info(data: &[u8]) -> Option<ImageInfo> {
+    let text = std::str::from_utf8(data.get(..data.len().min(64 * 1024))?).ok()?;
+    let start = text.find("<svg")?;
+    let tag = &text[start..start + text[start..].find('>')?];
+    let attribute = |name: &str| {
+        let at = tag.find(&format!(" {}=", name)).or_else(|| tag.find(&format!("\n{}=", name)))? + name.len() + 2;
+        let rest = &tag[at..];
+        let quote = rest.chars().next().filter(|c| matches!(c, '"' | '\''))?;
+        let value = &rest[quote.len_utf8()..];
+        Some(value[..value.find(quote)?].trim().to_string())
+    };
+    let length = |value: Option<String>| value.and_then(|v| v.trim_end_matches("px").parse::<f64>().ok()).filter(|v| *v > 0.0);
+    let (width, height) = match (length(attribute("width")), length(attribute("height"))) {
+        (Some(width), Some(height)) => (width, height),
+        _ => {
+            let view_box: Vec<f64> = attribute("viewBox")?.split([' ', ',']).filter_map(|n| n.parse().ok()).collect();
+            (*view_box.get(2)?, *view_box.get(3)?)
+        }
+    };
+    Some(ImageInfo { format: "svg", width: width.round() as u32, height: height.round() as u32 })
+}
+
+/// Decoded pixels, 8-bit RGBA by rows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rgba {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+/// Decodes a PNG or uncompressed BMP image.
+pub fn decode(data: &[u8]) -> Result<Rgba, String> {
+    let info = image_info(data).ok_or("Not an image")?;
+    if info.width == 0 || info.height == 0 {
+        return Err("The image is empty".to_string());
+    }
+    if info.width as u64 * info.height as u64 > MAX_PIXELS {
+        return Err(format!("The image is {}x{}, over the {} pixels decoded", info.width, info.height, MAX_PIXELS));
+    }
+    match info.format {
+        "png" => decode_png(data),
+        "bmp" => decode_bmp(data),
+        other => Err(format!("{} images can't be decoded", other.to_uppercase())),
+    }
+}
+
+/// `image` scaled down by averaging to fit in `size` by `size` pixels,
+/// keeping its aspect ratio. Images already that small stay as they are.
+pub fn downscale(image: &Rgba, size: u32) -> Rgba {
+    let scale = (size as f64 / image.width as f64).min(size as f64 / image.height as f64);
+    if scale >= 1.0 {
+        return image.clone();
+    }
+    let width = ((image.width as f64 * scale).round() as u32).max(1);
+    let height = ((image.height as f64 * scale).round() as u32).max(1);
+    let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+    for y in 0..height {
+        let y0 = (y as u64 * image.height as u64 / height as u64) as u32;
+        let y1 = (((y + 1) as u64 * image.height as u64).div_ceil(height as u64) as u32).max(y0 + 1);
+        for x in 0..width {
+            let x0 = (x as u64 * image.width as u64 / width as u64) as u32;
+            let x1 = (((x + 1) as u64 * image.width as u64).div_ceil(width as u64) as u32).max(x0 + 1);
+            // Colours weighted by alpha, so transparent pixels don't darken edges
+            let mut sum = [0u64; 4];
+            for sy in y0..y1 {
+                for sx in x0..x1 {
+                    let at = ((sy * image.width + sx) * 4) as usize;
+                    let alpha = image.pixels[at + 3] as u64;
+                    for (total, &value) in sum.iter_mut().zip(&image.pixels[at..at + 3]) {
+                        *total += value as u64 * alpha;
+                    }
+                    sum[3] += alpha;
+                }
+            }
+            let count = ((x1 - x0) * (y1 - y0)) as u64;
+            for channel in 0..3 {
+                pixels.push(sum[channel].checked_div(sum[3]).unwrap_or(0) as u8);
+            }
+            pixels.push((sum[3] / count) as u8);
+        }
+    }
+    Rgba { width, height, pixels }
+}
+
+/// `image` as an 8-bit RGBA PNG.
+pub fn encode_png(image: &Rgba) -> Vec<u8> {
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    let mut header = Vec::with_capacity(13);
+    header.extend(image.width.to_be_bytes());
+    header.extend(image.height.to_be_bytes());
+    header.extend([8, 6, 0, 0, 0]);
+    chunk(&mut png, b"IHDR", &header);
+    let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    for row in image.pixels.chunks((image.width * 4) as usize) {
+        // Each row unfiltered
+        let _ = encoder.write_all(&[0]);
+        let _ = encoder.write_all(row);
+    }
+    let data = encoder.finish().unwrap_or_default();
+    chunk(&mut png, b"IDAT", &data);
+    chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend(kind);
+    png.extend(data);
+    let crc = crc32(&png[start..]);
+    png.extend(crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn decode_png(data: &[u8]) -> Result<Rgba, String> {
+    let corrupt = || "The PNG file is corrupt".to_string();
+    let (mut width, mut height, mut depth, mut color, mut interlaced) = (0, 0, 0, 0, false);
+    let (mut palette, mut transparency, mut compressed) = (Vec::new(), Vec::new(), Vec::new());
+    let mut header_seen = false;
+    let mut at = 8;
+    while at + 8 <= data.len() {
+        let len = u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]) as usize;
+        let kind = &data[at + 4..at + 8];
+        let body = data.get(at + 8..at + 8 + len).ok_or_else(corrupt)?;
+        match kind {
+            // decode() checked the size of the first header only
+            b"IHDR" if header_seen => return Err("The PNG file has more than one IHDR chunk".to_string()),
+            b"IHDR" if len >= 13 => {
+                header_seen = true;
+                width = u32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+                height = u32::from_be_bytes([body[4], body[5], body[6], body[7]]);
+                (depth, color, interlaced) = (body[8], body[9], body[12] == 1);
+            }
+            b"PLTE" => palette = body.to_vec(),
+            b"tRNS" => transparency = body.to_vec(),
+            b"IDAT" => compressed.extend_from_slice(body),
+            b"IEND" => break,
+            _ => {}
+        }
+        at += 12 + len;
+    }
+    let channels = match color {
+        0 | 3 => 1,
+        2 => 3,
+        4 => 2,
+        6 => 4,
+        _ => return Err(format!("Unknown PNG color type {}", color)),
+    };
+    if ![1, 2, 4, 8, 16].contains(&depth) {
+        return Err(format!("Unknown PNG bit depth {}", depth));
+    }
+    let bits = channels * depth as usize;
+    let row_bytes = |w: u32| (w as usize * bits).div_ceil(8);
+    let expected: usize = passes(width, height, interlaced).iter().map(|&(_, _, _, _, w, h)| if w == 0 { 0 } else { (row_bytes(w) + 1) * h as usize }).sum();
+    let mut raw = Vec::with_capacity(expected);
+    flate2::read::ZlibDecoder::new(&compressed[..])
+        .take(expected as u64)
+        .read_to_end(&mut raw)
+        .map_err(|e| format!("The PNG data is corrupt: {}", e))?;
+    if raw.len() < expected {
+        return Err(corrupt());
+    }
+
+    let sample = |row: &[u8], index: usize| -> u16 {
+        match depth {
+            16 => u16::from_be_bytes([row[index * 2], row[index * 2 + 1]]),
+            8 => row[index] as u16,
+            _ => {
+                let bit = index * depth as usize;
+                let shift = 8 - depth as usize - bit % 8;
+                ((row[bit / 8] >> shift) & ((1u8 << depth) - 1)) as u16
+            }
+        }
+    };
+    // Samples scaled to 8 bits
+    let max = ((1u32 << depth) - 1) as u16;
+    let scaled = |value: u16| -> u8 { if depth == 16 { (value >> 8) as u8 } else { (value as u32 * 255 / max as u32) as u8 } };
+    let key = |index: usize| transparency.get(index * 2..index * 2 + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+
+    let bpp = bits.div_ceil(8).max(1);
+    let mut pixels = vec![0u8; width as usize * height as usize * 4];
+    let mut offset = 0;
+    for (x0, y0, dx, dy, pass_width, pass_height) in passes(width, height, interlaced) {
+        if pass_width == 0 || pass_height == 0 {
+            continue;
+        }
+        let stride = row_bytes(pass_width);
+        let mut previous = vec![0u8; stride];
+        for row_index in 0..pass_height as usize {
+            let filter = raw[offset];
+            let mut row = raw[offset + 1..offset + 1 + stride].to_vec();
+            offset += stride + 1;
+            unfilter(filter, &mut row, &previous, bpp)?;
+            for x in 0..pass_width as usize {
+                let rgba = match color {
+                    0 => {
+                        let v = sample(&row, x);
+                        let g = scaled(v);
+                        [g, g, g, if key(0) == Some(v) { 0 } else { 255 }]
+                    }
+                    2 => {
+                        let (r, g, b) = (sample(&row, x * 3), sample(&row, x * 3 + 1), sample(&row, x * 3 + 2));
+                        let transparent = key(0) == Some(r) && key(1) == Some(g) && key(2) == Some(b);
+                        [scaled(r), scaled(g), scaled(b), if transparent { 0 } else { 255 }]
+                    }
+                    3 => {
+                        let index = sample(&row, x) as usize;
+                        let entry = palette.get(index * 3..index * 3 + 3).ok_or_else(corrupt)?;
+                        [entry[0], entry[1], entry[2], transparency.get(index).copied().unwrap_or(255)]
+                    }
+                    4 => {
+                        let g = scaled(sample(&row, x * 2));
+                        [g, g, g, scaled(sample(&row, x * 2 + 1))]
+                    }
+                    _ => [scaled(sample(&row, x * 4)), scaled(sample(&row, x * 4 + 1)), scaled(sample(&row, x * 4 + 2)), scaled(sample(&row, x * 4 + 3))],
+                };
+                let (px, py) = (x0 + x * dx, y0 + row_index * dy);
+                let at = (py * width as usize + px) * 4;
+                pixels[at..at + 4].copy_from_slice(&rgba);
+            }
+            previous = row;
+        }
+    }
+    Ok(Rgba { width, height, pixels })
+}
+
+// The sub-images a PNG is stored as: the whole image, or the seven Adam7
+// passes, as first column and row, steps and size
+fn passes(width: u32, height: u32, interlaced: bool) -> Vec<(usize, usize, usize, usize, u32, u32)> {
+    if !interlaced {
+        return vec![(0, 0, 1, 1, width, height)];
+    }
+    [(0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4), (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2)]
+        .iter()
+        .map(|&(x0, y0, dx, dy)| {
+            let size = |total: u32, start: usize, step: usize| (total as usize).saturating_sub(start).div_ceil(step) as u32;
+            (x0, y0, dx, dy, size(width, x0, dx), size(height, y0, dy))
+        })
+        .collect()
+}
+
+fn unfilter(filter: u8, row: &mut [u8], previous: &[u8], bpp: usize) -> Result<(), String> {
+    for i in 0..row.len() {
+        let left = if i >= bpp { row[i - bpp] } else { 0 };
+        let up = previous[i];
+        let upper_left = if i >= bpp { previous[i - bpp] } else { 0 };
+        let predicted = match filter {
+            0 => 0,
+            1 => left,
+            2 => up,
+            3 => ((left as u16 + up as u16) / 2) as u8,
+            4 => {
+                let p = left as i16 + up as i16 - upper_left as i16;
+                let (pa, pb, pc) = ((p - left as i16).abs(), (p - up as i16).abs(), (p - upper_left as i16).abs());
+                if pa <= pb && pa <= pc {
+                    left
+                } else if pb <= pc {
+                    up
+                } else {
+                    upper_left
+                }
+            }
+            other => return Err(format!("Unknown PNG filter {}", other)),
+        };
+        row[i] = row[i].wrapping_add(predicted);
+    }
+    Ok(())
+}
+
+fn decode_bmp(data: &[u8]) -> Result<Rgba, String> {
+    let le16 = |at: usize| data.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as usize);
+    let le32 = |at: usize| data.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    let corrupt = || "The BMP file is corrupt".to_string();
+    let offset = le32(10).ok_or_else(corrupt)? as usize;
+    let width = le32(18).ok_or_else(corrupt)? as i32;
+    let height = le32(22).ok_or_else(corrupt)? as i32;
+    let bits = le16(28).ok_or_else(corrupt)?;
+    let compression = le32(30).ok_or_else(corrupt)?;
+    // Bitfields are taken to be the usual BGRA masks
+    if !matches!((bits, compression), (24, 0) | (32, 0) | (32, 3)) {
+        return Err(format!("Only uncompressed 24 and 32-bit BMP images can be decoded, not {}-bit ones", bits));
+    }
+    let (width, top_down) = (width.unsigned_abs(), height < 0);
+    let height = height.unsigned_abs();
+    let bytes = bits / 8;
+    let stride = (width as usize * bytes).div_ceil(4) * 4;
+    let mut pixels = Vec::with_capacity(width as usize * height as usize * 4);
+    for y in 0..height as usize {
+        let row = if top_down { y } else { height as usize - 1 - y };
+        let start = offset + row * stride;
+        let line = data.get(start..start + width as usize * bytes).ok_or_else(corrupt)?;
+        for px in line.chunks(bytes) {
+            // 32-bit BMPs without bitfields usually leave alpha at 0
+            let alpha = if bytes == 4 && compression == 3 { px[3] } else { 255 };
+            pixels.extend([px[2], px[1], px[0], alpha]);
+        }
+    }
+    Ok(Rgba { width, height, pixels })
+}
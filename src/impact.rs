@@ -0,0 +1,165 @@
+//! What a patch may affect, for deciding what to verify after applying it:
+//! the files that import the ones it changes, directly or through others,
+//! the packages they belong to and the tests likely to cover them.
+
+use crate::deps::DependencyGraph;
+use crate::packages::{package_of, Package};
+use crate::store::dir_of;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Import levels followed from the changed files by default.
+pub const DEFAULT_MAX_DEPTH: usize = 3;
+
+/// A file importing a changed file, or one of the other dependents.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Dependent {
+    pub path: String,
+    /// 1 for direct importers of a changed file.
+    pub depth: usize,
+    /// The file it imports that brought it in.
+    pub via: String,
+}
+
+/// A test worth running.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SuggestedTest {
+    pub path: String,
+    /// `changed` (the patch changes it), `dependent` (it imports a changed
+    /// file), `namesake` (named after a changed file) or `package` (a test
+    /// folder of an affected package).
+    pub reason: &'static str,
+}
+
+/// An affected package and the command that runs its tests.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AffectedPackage {
+    pub name: String,
+    pub root: String,
+    pub kind: &'static str,
+    #[serde(rename = "testCommand")]
+    pub test_command: String,
+}
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Impact {
+    pub changed: Vec<String>,
+    pub dependents: Vec<Dependent>,
+    pub packages: Vec<AffectedPackage>,
+    pub tests: Vec<SuggestedTest>,
+    /// Dependents beyond the depth limit were left out.
+    pub truncated: bool,
+}
+
+/// Whether `path` looks like a test by the usual conventions: a `test`,
+/// `tests`, `spec` or `__tests__` folder, or a name such as `foo_test.go`,
+/// `test_foo.py`, `foo.test.ts` or `foo.spec.js`.
+pub fn is_test_file(path: &str) -> bool {
+    is_test_dir(dir_of(path)) || test_subject(path).is_some()
+}
+
+fn is_test_dir(dir: &str) -> bool {
+    dir.split('/').any(|d| matches!(d, "test" | "tests" | "spec" | "__tests__"))
+}
+
+// The name a test file is named after: `foo` for foo_test.go, test_foo.py,
+// foo.test.ts or foo.spec.js
+fn test_subject(path: &str) -> Option<&str> {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let (stem, _) = name.rsplit_once('.')?;
+    stem.strip_suffix("_test")
+        .or_else(|| stem.strip_prefix("test_"))
+        .or_else(|| stem.strip_suffix(".test"))
+        .or_else(|| stem.strip_suffix(".spec"))
+        .filter(|s| !s.is_empty())
+}
+
+fn stem(path: &str) -> &str {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    name.split_once('.').map_or(name, |(stem, _)| stem)
+}
+
+/// The impact of changing `changed`, following importers in `graph` up to
+/// `max_depth` levels (0 for no limit).
+pub fn impact(graph: &DependencyGraph, changed: &[String], packages: &[Package], max_depth: usize) -> Impact {
+    let mut importers: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (file, deps) in &graph.files {
+        for import in &deps.imports {
+            importers.entry(import.as_str()).or_default().push(file.as_str());
+        }
+    }
+
+    let mut seen: BTreeSet<&str> = changed.iter().map(String::as_str).collect();
+    let mut dependents = Vec::new();
+    let mut truncated = false;
+    let mut level: Vec<&str> = changed.iter().map(String::as_str).collect();
+    let mut depth = 0;
+    while !level.is_empty() {
+        depth += 1;
+        let mut next = Vec::new();
+        for &file in &level {
+            for &importer in importers.get(file).into_iter().flatten() {
+                if seen.contains(importer) {
+                    continue;
+                }
+                if max_depth > 0 && depth > max_depth {
+                    truncated = true;
+                    continue;
+                }
+                seen.insert(importer);
+                dependents.push(Dependent { path: importer.to_string(), depth, via: file.to_string() });
+                next.push(importer);
+            }
+        }
+        level = next;
+    }
+
+    let mut tests: BTreeMap<String, &'static str> = BTreeMap::new();
+    for path in changed.iter().filter(|p| is_test_file(p)) {
+        tests.insert(path.clone(), "changed");
+    }
+    for dependent in dependents.iter().filter(|d| is_test_file(&d.path)) {
+        tests.entry(dependent.path.clone()).or_insert("dependent");
+    }
+    let subjects: BTreeSet<(&str, &str)> = changed.iter().filter(|p| !is_test_file(p)).map(|p| (dir_of(p), stem(p))).collect();
+    for file in graph.files.keys() {
+        let Some(subject) = test_subject(file) else {
+            continue;
+        };
+        // Next to the file, or in a test folder anywhere
+        let namesake = subjects.iter().any(|(dir, stem)| *stem == subject && (dir_of(file) == *dir || is_test_dir(dir_of(file))));
+        if namesake {
+            tests.entry(file.clone()).or_insert("namesake");
+        }
+    }
+
+    let mut affected: BTreeMap<&str, &Package> = BTreeMap::new();
+    for path in changed.iter().map(String::as_str).chain(dependents.iter().map(|d| d.path.as_str())) {
+        if let Some(package) = package_of(packages, path) {
+            affected.insert(package.root.as_str(), package);
+        }
+    }
+    for package in affected.values() {
+        let test_dir = if package.root.is_empty() { "tests/".to_string() } else { format!("{}/tests/", package.root) };
+        for file in graph.files.keys().filter(|f| f.starts_with(&test_dir)) {
+            tests.entry(file.clone()).or_insert("package");
+        }
+    }
+
+    Impact {
+        changed: changed.to_vec(),
+        dependents,
+        packages: affected.values().map(|p| AffectedPackage { name: p.name.clone(), root: p.root.clone(), kind: p.kind, test_command: test_command(p) }).collect(),
+        tests: tests.into_iter().map(|(path, reason)| SuggestedTest { path, reason }).collect(),
+        truncated,
+    }
+}
+
+fn test_command(package: &Package) -> String {
+    match package.kind {
+        "cargo" => format!("cargo test -p {}", package.name),
+        "go" if package.root.is_empty() => "go test ./...".to_string(),
+        "go" => format!("go test ./{}/...", package.root),
+        _ => format!("npm test --workspace {}", package.name),
+    }
+}
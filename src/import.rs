@@ -0,0 +1,146 @@
+// Fetching patches from the web: POST /api/import_patch takes a raw diff URL,
+// a gist, or a GitHub pull request or commit page, downloads the diff and
+// returns it parsed and validated so the UI can preview or apply it.
+//
+// Only hosts in IMPORT_ALLOWED_HOSTS are contacted, redirects included, and
+// downloads stop at IMPORT_MAX_BYTES.
+
+use crate::auth::Stores;
+use crate::validation::ValidJson;
+use actix_web::{post, web, HttpResponse};
+use awc::http::Uri;
+use repopatch::patch::{ApplyOptions, PatchSet};
+use repopatch::policy;
+use repopatch::remap::PathRewrites;
+use repopatch::store::{check_relative, OverlayStore};
+use serde::Deserialize;
+use serde_json::json;
+use std::env;
+use std::time::Duration;
+
+const DEFAULT_ALLOWED_HOSTS: &str =
+    "github.com,patch-diff.githubusercontent.com,raw.githubusercontent.com,gist.github.com,gist.githubusercontent.com,gitlab.com";
+const DEFAULT_MAX_BYTES: usize = 5 * 1024 * 1024;
+const MAX_REDIRECTS: usize = 5;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+pub struct ImportRequest {
+    url: String,
+    /// When set, the patch is also dry-run against this directory.
+    #[serde(rename = "directoryPath")]
+    directory_path: Option<String>,
+}
+
+// Helper function to point GitHub and GitLab pages at their raw diff
+fn diff_url(url: &str) -> String {
+    let url = url.trim().trim_end_matches('/');
+    let Ok(uri) = url.parse::<Uri>() else {
+        return url.to_string();
+    };
+    let path = uri.path();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (uri.host().unwrap_or_default(), segments.as_slice()) {
+        ("github.com", [_, _, "pull" | "commit", _, ..]) => {
+            format!("https://github.com/{}.diff", segments[..4].join("/"))
+        }
+        ("gitlab.com", [.., "-", "merge_requests" | "commit", _]) => format!("{}.diff", url),
+        ("gist.github.com", [_, _]) => format!("{}/raw", url),
+        _ => url.to_string(),
+    }
+}
+
+fn allowed_hosts() -> Vec<String> {
+    env::var("IMPORT_ALLOWED_HOSTS")
+        .unwrap_or_else(|_| DEFAULT_ALLOWED_HOSTS.to_string())
+        .split(',')
+        .map(|h| h.trim().to_lowercase())
+        .filter(|h| !h.is_empty())
+        .collect()
+}
+
+// Helper function to download `url`, checking every redirect target against the allowlist
+async fn fetch(url: &str, max_bytes: usize) -> Result<(String, String), String> {
+    let hosts = allowed_hosts();
+    let client = awc::Client::builder().disable_redirects().timeout(FETCH_TIMEOUT).finish();
+    let mut url = url.to_string();
+    for _ in 0..=MAX_REDIRECTS {
+        let uri: Uri = url.parse().map_err(|e| format!("Invalid URL {}: {}", url, e))?;
+        if uri.scheme_str() != Some("https") {
+            return Err(format!("Only https URLs can be imported: {}", url));
+        }
+        let host = uri.host().unwrap_or_default().to_lowercase();
+        if !hosts.contains(&host) {
+            return Err(format!("Host {} is not in IMPORT_ALLOWED_HOSTS", host));
+        }
+        let mut response = client
+            .get(&url)
+            .insert_header(("User-Agent", concat!("repopatch/", env!("CARGO_PKG_VERSION"))))
+            .send()
+            .await
+            .map_err(|e| format!("Failed to fetch {}: {}", url, e))?;
+        if response.status().is_redirection() {
+            let location = response.headers().get("Location").and_then(|v| v.to_str().ok()).ok_or("Redirect without a Location")?;
+            url = if location.starts_with('/') { format!("https://{}{}", host, location) } else { location.to_string() };
+            continue;
+        }
+        if !response.status().is_success() {
+            return Err(format!("Fetching {} returned {}", url, response.status()));
+        }
+        let body = response
+            .body()
+            .limit(max_bytes)
+            .await
+            .map_err(|e| format!("Failed to read {} (limit {} bytes): {}", url, max_bytes, e))?;
+        let text = String::from_utf8(body.to_vec()).map_err(|_| format!("{} is not UTF-8 text", url))?;
+        return Ok((url, text));
+    }
+    Err(format!("Too many redirects fetching {}", url))
+}
+
+#[post("/api/import_patch")]
+pub async fn import_patch(body: ValidJson<ImportRequest>, stores: Stores, rewrites: web::Data<PathRewrites>) -> HttpResponse {
+    let max_bytes = env::var("IMPORT_MAX_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_MAX_BYTES);
+    let (url, content) = match fetch(&diff_url(&body.url), max_bytes).await {
+        Ok(fetched) => fetched,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+    log::info!("Imported {} bytes of patch from {}", content.len(), url);
+
+    let mut patch_set = PatchSet::parse(&content, 1);
+    if patch_set.files.is_empty() {
+        return HttpResponse::UnprocessableEntity().json(json!({ "success": false, "url": url, "error": "The URL does not contain a unified diff" }));
+    }
+    let rewritten = rewrites.apply(&mut patch_set);
+    let mut problems: Vec<_> = patch_set
+        .files
+        .iter()
+        .filter_map(|f| f.parse_error().map(|e| json!({ "file": f.path(), "error": e })))
+        .collect();
+    for path in patch_set.files.iter().flat_map(|f| f.old_path.iter().chain(f.new_path.iter())) {
+        if let Err(e) = check_relative(path) {
+            problems.push(json!({ "file": path, "error": e }));
+        }
+    }
+    let mut response = json!({
+        "success": problems.is_empty(),
+        "url": url,
+        "patchContent": content,
+        "summary": policy::summarize(&patch_set),
+        "problems": problems
+    });
+    if !rewritten.is_empty() {
+        response["rewrittenPaths"] = json!(rewritten);
+    }
+
+    if let Some(directory) = &body.directory_path {
+        let store = match stores.open_root(directory) {
+            Ok(s) => s,
+            Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid directory path: {}", e) })),
+        };
+        let overlay = OverlayStore::new(&*store);
+        let outcome = patch_set.apply_with(&overlay, &ApplyOptions::default());
+        response["dryRun"] = json!({ "success": outcome.is_success(), "appliedFiles": outcome.applied_files, "details": outcome.details });
+    }
+    HttpResponse::Ok().json(response)
+}
@@ -0,0 +1,266 @@
+// This server instance among others on the same machine.
+//
+// Port selection: with PORT_AUTO=true a busy PORT isn't fatal, the server
+// takes the next free one of the following PORT_AUTO_TRIES (default 20).
+// The port it listens on is reported by /api/connect and, when PORT_FILE is
+// set (default repopatch-port.json with PORT_AUTO), written there as
+// {"port", "url", "pid", "started"} for scripts and UIs to find it. The file
+// is removed when the server stops.
+//
+// Root locks: the first time an instance watches or writes a repository (the
+// folder holding .git or another VCS folder, else the folder opened) it
+// takes an exclusive lock on it, held until it exits. Another instance can
+// still read the repository but not write it or watch it, and says which
+// instance has it. Lock files live in LOCK_DIR (default repopatch-locks in
+// the temp directory); ROOT_LOCKS=off turns locking off.
+
+use repopatch::store::{DirEntry, FileStore, StoreProvider};
+use repopatch::tree::VCS_DIRS;
+use serde_json::json;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+
+const DEFAULT_PORT_TRIES: u16 = 20;
+const DEFAULT_PORT_FILE: &str = "repopatch-port.json";
+
+/// Binds `port`, or with PORT_AUTO the first free port from it on.
+pub fn listen(port: u16) -> io::Result<TcpListener> {
+    let auto = env::var("PORT_AUTO").is_ok_and(|v| v == "true");
+    let tries = match auto {
+        true => env::var("PORT_AUTO_TRIES").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_PORT_TRIES).max(1),
+        false => 1,
+    };
+    let mut last_error = None;
+    for candidate in (port..=u16::MAX).take(tries as usize) {
+        match TcpListener::bind(("0.0.0.0", candidate)) {
+            Ok(listener) => {
+                if candidate != port {
+                    log::warn!("Port {} is busy, using {}", port, candidate);
+                }
+                return Ok(listener);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && auto => {
+                log::debug!("Port {} is busy", candidate);
+                last_error = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::AddrInUse, format!("No free port from {}", port))))
+}
+
+/// The discovery file, removed again when dropped.
+pub struct PortFile(PathBuf);
+
+impl PortFile {
+    /// Writes the discovery file if PORT_FILE or PORT_AUTO asks for one.
+    pub fn write(port: u16, https: bool) -> io::Result<Option<PortFile>> {
+        let path = match env::var("PORT_FILE") {
+            Ok(p) if p.is_empty() => return Ok(None),
+            Ok(p) => PathBuf::from(p),
+            Err(_) if env::var("PORT_AUTO").is_ok_and(|v| v == "true") => PathBuf::from(DEFAULT_PORT_FILE),
+            Err(_) => return Ok(None),
+        };
+        let scheme = if https { "https" } else { "http" };
+        let content = json!({
+            "port": port,
+            "url": format!("{}://localhost:{}", scheme, port),
+            "pid": std::process::id(),
+            "started": chrono::Utc::now().to_rfc3339()
+        });
+        fs::write(&path, serde_json::to_vec_pretty(&content).unwrap_or_default())?;
+        log::info!("Wrote port {} to {}", port, path.display());
+        Ok(Some(PortFile(path)))
+    }
+}
+
+impl Drop for PortFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// The port the server listens on, for /api/connect.
+pub struct Listening {
+    pub port: u16,
+}
+
+/// Repository locks this instance holds.
+pub struct RootLocks {
+    dir: PathBuf,
+    /// Unset for the command line subcommands.
+    port: OnceLock<u16>,
+    /// Open lock files by repository; the lock lasts as long as the file.
+    held: Mutex<HashMap<PathBuf, File>>,
+}
+
+impl RootLocks {
+    /// The locks configured by ROOT_LOCKS and LOCK_DIR, or `None` when off.
+    pub fn from_env() -> Result<Option<Arc<Self>>, String> {
+        if env::var("ROOT_LOCKS").is_ok_and(|v| v == "off" || v == "false") {
+            return Ok(None);
+        }
+        let dir = env::var("LOCK_DIR").ok().filter(|d| !d.is_empty()).map(PathBuf::from).unwrap_or_else(|| env::temp_dir().join("repopatch-locks"));
+        fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        Ok(Some(Arc::new(RootLocks { dir, port: OnceLock::new(), held: Mutex::new(HashMap::new()) })))
+    }
+
+    /// Records the server's port in the locks it takes.
+    pub fn set_port(&self, port: u16) {
+        let _ = self.port.set(port);
+    }
+
+    /// Takes the lock of the repository holding the folder `dir` unless this
+    /// instance has it already. Folders that don't exist on disk, like those
+    /// of the in-memory repository, need no lock.
+    pub fn acquire(&self, dir: &Path) -> Result<(), String> {
+        let Some(repo) = repository_of(dir) else {
+            return Ok(());
+        };
+        let mut held = self.held.lock().unwrap_or_else(|e| e.into_inner());
+        if held.contains_key(&repo) {
+            return Ok(());
+        }
+        let lock_path = self.dir.join(format!("{:016x}.lock", fnv1a(&repo.to_string_lossy())));
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+            .map_err(|e| format!("Failed to open {}: {}", lock_path.display(), e))?;
+        if file.try_lock().is_err() {
+            let mut owner = String::new();
+            let _ = file.read_to_string(&mut owner);
+            let owner: serde_json::Value = serde_json::from_str(&owner).unwrap_or_default();
+            let port = owner["port"].as_u64().map(|p| format!(" on port {}", p)).unwrap_or_default();
+            return Err(format!("{} is in use by another RepoPatch instance (pid {}{})", repo.display(), owner["pid"], port));
+        }
+        let owner = json!({ "root": repo.to_string_lossy(), "pid": std::process::id(), "port": self.port.get() });
+        let _ = file.set_len(0).and_then(|()| file.rewind()).and_then(|()| file.write_all(owner.to_string().as_bytes()));
+        log::info!("Locked {} for this instance", repo.display());
+        held.insert(repo, file);
+        Ok(())
+    }
+
+    /// The repositories this instance has locked.
+    pub fn held(&self) -> Vec<String> {
+        let held = self.held.lock().unwrap_or_else(|e| e.into_inner());
+        let mut roots: Vec<String> = held.keys().map(|p| p.to_string_lossy().into_owned()).collect();
+        roots.sort();
+        roots
+    }
+}
+
+// The innermost folder from `dir` up holding a VCS folder, else `dir`
+fn repository_of(dir: &Path) -> Option<PathBuf> {
+    let dir = dir.canonicalize().ok().filter(|d| d.is_dir())?;
+    let repo = dir.ancestors().find(|d| VCS_DIRS.iter().any(|vcs| d.join(vcs).exists()));
+    Some(repo.unwrap_or(&dir).to_path_buf())
+}
+
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// A store whose writes first take the lock of its repository.
+pub struct LockedStore {
+    inner: Arc<dyn FileStore>,
+    locks: Arc<RootLocks>,
+}
+
+impl FileStore for LockedStore {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.inner.read(path)
+    }
+
+    fn read_head(&self, path: &str, max: usize) -> io::Result<Vec<u8>> {
+        self.inner.read_head(path, max)
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.check_writable(path).map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e))?;
+        self.inner.write(path, data)
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        self.check_writable(path).map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e))?;
+        self.inner.remove(path)
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        self.check_writable(path).map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e))?;
+        self.inner.remove_dir(path)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn list_dir(&self, path: &str) -> io::Result<Vec<DirEntry>> {
+        self.inner.list_dir(path)
+    }
+
+    fn display_path(&self, path: &str) -> String {
+        self.inner.display_path(path)
+    }
+
+    fn validate(&self, path: &str) -> Result<(), String> {
+        self.inner.validate(path)
+    }
+
+    fn check_writable(&self, path: &str) -> Result<(), String> {
+        self.locks.acquire(Path::new(&self.inner.display_path("")))?;
+        self.inner.check_writable(path)
+    }
+
+    fn file_size(&self, path: &str) -> Option<u64> {
+        self.inner.file_size(path)
+    }
+
+    fn modified(&self, path: &str) -> Option<SystemTime> {
+        self.inner.modified(path)
+    }
+
+    fn available_space(&self, path: &str) -> Option<u64> {
+        self.inner.available_space(path)
+    }
+
+    fn file_id(&self, path: &str) -> Option<(u64, u64)> {
+        self.inner.file_id(path)
+    }
+
+    fn local_path(&self, path: &str) -> Option<PathBuf> {
+        self.inner.local_path(path)
+    }
+}
+
+/// Opens every store of another provider as a [`LockedStore`].
+pub struct LockedStoreProvider {
+    inner: Arc<dyn StoreProvider>,
+    locks: Arc<RootLocks>,
+}
+
+impl LockedStoreProvider {
+    pub fn new(inner: Arc<dyn StoreProvider>, locks: Arc<RootLocks>) -> Self {
+        LockedStoreProvider { inner, locks }
+    }
+}
+
+impl StoreProvider for LockedStoreProvider {
+    fn open_root(&self, root: &str) -> Result<Arc<dyn FileStore>, String> {
+        let store = self.inner.open_root(root)?;
+        Ok(Arc::new(LockedStore { inner: store, locks: self.locks.clone() }))
+    }
+
+    fn open_file(&self, path: &str) -> Result<(Arc<dyn FileStore>, String), String> {
+        let (store, name) = self.inner.open_file(path)?;
+        Ok((Arc::new(LockedStore { inner: store, locks: self.locks.clone() }), name))
+    }
+}
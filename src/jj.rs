@@ -0,0 +1,70 @@
+// Jujutsu (jj) repositories: a workspace in a folder holding `.jj`, alone or
+// colocated with `.git`. /api/workspace_info describes the working-copy
+// change, and /api/apply_patch reports the jj operation from before the
+// patch as `jjOperation`; `jj op restore <id>` undoes the patch and anything
+// after it. Needs the `jj` binary on the PATH; without it the repository is
+// still detected and the rest is an `error`.
+
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+// One field per line; the description's first line comes last
+const CHANGE_TEMPLATE: &str =
+    r#"change_id.short() ++ "\n" ++ commit_id.short() ++ "\n" ++ empty ++ "\n" ++ conflict ++ "\n" ++ bookmarks.map(|b| b.name()).join(",") ++ "\n" ++ description.first_line()"#;
+
+/// The innermost folder from `dir` up holding a `.jj` folder.
+pub fn repository_root(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors().find(|d| d.join(".jj").is_dir()).map(Path::to_path_buf)
+}
+
+// Runs jj in `dir`, returning its trimmed stdout
+fn jj(dir: &Path, args: &[&str]) -> Result<String, String> {
+    let output = Command::new("jj")
+        .current_dir(dir)
+        .args(["--no-pager", "--color", "never"])
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("Failed to run jj: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("jj {} failed: {}", args.first().unwrap_or(&""), String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// The jj repository holding `dir` and its working-copy change, null when
+/// there is none.
+pub fn info(dir: &Path) -> serde_json::Value {
+    let Some(root) = repository_root(dir) else {
+        return serde_json::Value::Null;
+    };
+    let mut info = json!({ "root": root.to_string_lossy(), "colocated": root.join(".git").exists() });
+    match jj(dir, &["log", "-r", "@", "--no-graph", "-T", CHANGE_TEMPLATE]) {
+        Ok(change) => {
+            let fields: Vec<&str> = change.splitn(6, '\n').collect();
+            let field = |i: usize| fields.get(i).copied().unwrap_or_default();
+            info["changeId"] = json!(field(0));
+            info["commitId"] = json!(field(1));
+            info["empty"] = json!(field(2) == "true");
+            info["conflict"] = json!(field(3) == "true");
+            info["bookmarks"] = json!(field(4).split(',').filter(|b| !b.is_empty()).collect::<Vec<_>>());
+            info["description"] = json!(field(5));
+        }
+        Err(e) => info["error"] = json!(e),
+    }
+    info
+}
+
+/// The id of the repository's latest operation. jj snapshots the working
+/// copy first, so restoring it brings back the files as they are now.
+pub fn operation(dir: &Path) -> Option<String> {
+    match jj(dir, &["op", "log", "--no-graph", "-n", "1", "-T", r#"id.short() ++ "\n""#]) {
+        Ok(id) if !id.is_empty() => Some(id),
+        Ok(_) => None,
+        Err(e) => {
+            log::warn!("No jj operation to undo to in {}: {}", dir.display(), e);
+            None
+        }
+    }
+}
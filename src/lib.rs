@@ -0,0 +1,62 @@
+//! RepoPatch: browse local repositories and apply LLM-generated patches to them.
+//!
+//! The HTTP server lives in the `repopatch` binary: its routes, sessions and
+//! everything tied to a running server (auth, quotas, the terminal, remote
+//! instances) are modules of the binary. This library holds the parts that
+//! work on a repository without a server, so other tools can use them:
+//!
+//! - Patching: [`patch`] parses unified diffs and applies them, [`engine`]
+//!   offers the alternative engines, [`diff`] produces diffs, and [`policy`],
+//!   [`remap`] and [`secrets`] check and rewrite patches before they apply.
+//! - Storage: [`store`] is the [`store::FileStore`] abstraction over the
+//!   disk, memory and overlays, with [`archive`] reading ZIP and tar files as
+//!   folders and [`seed`] filling in-memory repositories.
+//! - Browsing: [`tree`], [`stats`], [`packages`], [`duplicates`],
+//!   [`longlines`], [`images`], [`table`], [`documents`] and [`notebook`]
+//!   describe the files of a tree and their contents.
+//! - Prompt context: [`pack`], [`context`], [`extract`], [`related`],
+//!   [`deps`], [`semantic`] and [`preview`] pick and package what a model
+//!   gets to see; [`impact`] and [`diagnostics`] report on what it changed.
+//! - Editing: [`replace`], [`refactor`], [`moves`], [`scaffold`] and
+//!   [`editorconfig`], with [`syntax`] parsing the languages refactorings
+//!   understand.
+//! - Server support: [`metadata`] keeps server state in SQLite or JSON
+//!   files, [`messages`] holds the translated messages and
+//!   [`bench`](mod@bench) times the hot paths.
+
+pub mod archive;
+pub mod bench;
+pub mod context;
+pub mod deps;
+pub mod diagnostics;
+pub mod diff;
+pub mod documents;
+pub mod duplicates;
+pub mod editorconfig;
+pub mod engine;
+pub mod extract;
+pub mod images;
+pub mod impact;
+pub mod longlines;
+pub mod messages;
+pub mod metadata;
+pub mod moves;
+pub mod notebook;
+pub mod pack;
+pub mod packages;
+pub mod patch;
+pub mod policy;
+pub mod preview;
+pub mod refactor;
+pub mod remap;
+pub mod related;
+pub mod replace;
+pub mod scaffold;
+pub mod secrets;
+pub mod seed;
+pub mod semantic;
+pub mod stats;
+pub mod store;
+pub mod syntax;
+pub mod table;
+pub mod tree;
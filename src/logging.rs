@@ -0,0 +1,215 @@
+// Logging sinks. Without a `[logging]` section in repopatch.toml, logs go to
+// stderr through env_logger as before. With one, application and access
+// (request) logs can be written to separate, rotated files, as text or JSON:
+//
+//   [logging]
+//   level = "info"                 # error, warn, info, debug, trace
+//   format = "json"                # or "text"
+//   file = "logs/repopatch.log"    # application log
+//   access_file = "logs/access.log"
+//   max_size_mb = 10               # rotate when a file exceeds this size
+//   daily = true                   # and/or when the day changes
+//   keep = 5                       # rotated files to keep (name.1 ... name.5)
+//   stderr = false                 # also write to stderr
+
+use chrono::{Local, NaiveDate};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Deserialize;
+use serde_json::json;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// Target used by actix's request logger.
+const ACCESS_TARGET: &str = "actix_web::middleware::logger";
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+    #[serde(default = "default_level")]
+    pub level: String,
+    #[serde(default)]
+    pub format: LogFormat,
+    pub file: Option<PathBuf>,
+    pub access_file: Option<PathBuf>,
+    pub max_size_mb: Option<u64>,
+    #[serde(default)]
+    pub daily: bool,
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+    #[serde(default)]
+    pub stderr: bool,
+}
+
+fn default_level() -> String {
+    "info".to_string()
+}
+
+fn default_keep() -> usize {
+    5
+}
+
+/// A log file renamed to `name.1`, `name.2`, ... once it grows past
+/// `max_size` bytes or, if `daily`, when the local date changes.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened_on: NaiveDate,
+    max_size: Option<u64>,
+    daily: bool,
+    keep: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, config: &LoggingConfig) -> io::Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            opened_on: Local::now().date_naive(),
+            max_size: config.max_size_mb.map(|mb| mb * 1024 * 1024),
+            daily: config.daily,
+            keep: config.keep,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated_path(self.keep));
+            for index in (1..self.keep).rev() {
+                let _ = fs::rename(self.rotated_path(index), self.rotated_path(index + 1));
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+        self.size = 0;
+        self.opened_on = Local::now().date_naive();
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let too_big = self.max_size.is_some_and(|max| self.size > 0 && self.size + line.len() as u64 > max);
+        let new_day = self.daily && Local::now().date_naive() != self.opened_on;
+        if too_big || new_day {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+struct FileLogger {
+    level: LevelFilter,
+    format: LogFormat,
+    app: Option<Mutex<RotatingFile>>,
+    access: Option<Mutex<RotatingFile>>,
+    stderr: bool,
+}
+
+impl FileLogger {
+    fn format(&self, record: &Record) -> String {
+        let timestamp = Local::now().to_rfc3339();
+        match self.format {
+            LogFormat::Text => format!("[{} {} {}] {}\n", timestamp, record.level(), record.target(), record.args()),
+            LogFormat::Json => format!(
+                "{}\n",
+                json!({
+                    "ts": timestamp,
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "msg": record.args().to_string()
+                })
+            ),
+        }
+    }
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = self.format(record);
+        // Access lines go to the access log if there is one, else with the rest
+        let sink = match (&self.access, record.target() == ACCESS_TARGET) {
+            (Some(access), true) => Some(access),
+            _ => self.app.as_ref(),
+        };
+        if let Some(sink) = sink {
+            let mut file = sink.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = file.write_line(&line) {
+                eprintln!("Failed to write log file {}: {}", file.path.display(), e);
+            }
+        }
+        if self.stderr || sink.is_none() {
+            let _ = io::stderr().write_all(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        for sink in [&self.app, &self.access].into_iter().flatten() {
+            let _ = sink.lock().unwrap_or_else(|e| e.into_inner()).file.flush();
+        }
+    }
+}
+
+/// Installs the global logger described by `config`, falling back to
+/// env_logger (RUST_LOG, default `info`) when there is no `[logging]` section.
+pub fn init(config: Option<&LoggingConfig>) -> Result<(), String> {
+    let config = match config {
+        Some(c) => c,
+        None => {
+            env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+            return Ok(());
+        }
+    };
+    let level = LevelFilter::from_str(&config.level).map_err(|_| format!("Invalid log level '{}'", config.level))?;
+    let open = |path: &Option<PathBuf>| -> Result<Option<Mutex<RotatingFile>>, String> {
+        path.as_ref()
+            .map(|p| {
+                RotatingFile::open(p.clone(), config)
+                    .map(Mutex::new)
+                    .map_err(|e| format!("Failed to open log file {}: {}", p.display(), e))
+            })
+            .transpose()
+    };
+    let logger = FileLogger {
+        level,
+        format: config.format,
+        app: open(&config.file)?,
+        access: open(&config.access_file)?,
+        stderr: config.stderr,
+    };
+    log::set_boxed_logger(Box::new(logger)).map_err(|e| e.to_string())?;
+    log::set_max_level(level);
+    Ok(())
+}
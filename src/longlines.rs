@@ -0,0 +1,118 @@
+//! Very long lines, as in minified bundles and JSON blobs: finding the files
+//! that have them, and wrapping or eliding those lines where they would
+//! swallow a prompt's token budget or make patch hunks impossible to match.
+
+use crate::store::FileStore;
+
+/// Lines longer than this many characters are long lines.
+pub const LONG_LINE_CHARS: usize = 1000;
+
+/// Characters a long line is wrapped at, or cut to, by default.
+pub const DEFAULT_WIDTH: usize = 200;
+
+/// Narrower widths would flood a file with continuation lines.
+pub const MIN_WIDTH: usize = 20;
+
+/// Files up to this size with an extension that is often minified are read
+/// for long lines when listing a tree; bigger ones are not flagged.
+pub const SNIFF_BYTES: u64 = 2 * 1024 * 1024;
+
+const SNIFFED_EXTENSIONS: &[&str] = &["js", "mjs", "cjs", "css", "json", "map", "svg", "xml", "html", "htm"];
+
+/// How to show the long lines of a file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LongLines {
+    /// As they are.
+    #[default]
+    Keep,
+    /// Split into lines of `width` characters.
+    Wrap { width: usize },
+    /// Cut to their first `width` characters, with a note of what was left out.
+    Elide { width: usize },
+}
+
+impl LongLines {
+    /// Parses a `keep`, `wrap` or `elide` mode with an optional width.
+    pub fn parse(mode: Option<&str>, width: Option<usize>) -> Result<Self, String> {
+        let width = width.unwrap_or(DEFAULT_WIDTH);
+        if width < MIN_WIDTH {
+            return Err(format!("The line width must be at least {}", MIN_WIDTH));
+        }
+        match mode.unwrap_or("keep") {
+            "keep" => Ok(LongLines::Keep),
+            "wrap" => Ok(LongLines::Wrap { width }),
+            "elide" => Ok(LongLines::Elide { width }),
+            other => Err(format!("Unknown long line mode {}: use keep, wrap or elide", other)),
+        }
+    }
+}
+
+/// The length in characters of the longest line of `text`.
+pub fn longest_line(text: &str) -> usize {
+    text.lines().map(|line| line.chars().count()).max().unwrap_or(0)
+}
+
+/// Whether `content` has a line longer than [`LONG_LINE_CHARS`].
+pub fn has_long_lines(content: &[u8]) -> bool {
+    // Counting bytes first skips the character count for ordinary lines
+    content
+        .split(|&b| b == b'\n')
+        .any(|line| line.len() > LONG_LINE_CHARS && String::from_utf8_lossy(line).chars().count() > LONG_LINE_CHARS)
+}
+
+/// Whether a listed file has long lines: files named like `app.min.js` are
+/// taken at their word, and files with an extension that is often minified
+/// are read when they are at most [`SNIFF_BYTES`].
+pub fn file_has_long_lines(store: &dyn FileStore, path: &str, size: Option<u64>) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    if name.contains(".min.") {
+        return true;
+    }
+    let sniffed = name.rsplit_once('.').is_some_and(|(_, ext)| SNIFFED_EXTENSIONS.contains(&ext.to_lowercase().as_str()));
+    if !sniffed || size.is_none_or(|s| s <= LONG_LINE_CHARS as u64 || s > SNIFF_BYTES) {
+        return false;
+    }
+    store.read(path).is_ok_and(|content| has_long_lines(&content))
+}
+
+/// `text` with its long lines shown as `mode` says, and how many there were.
+pub fn soften(text: &str, mode: LongLines) -> (String, usize) {
+    if !has_long_lines(text.as_bytes()) {
+        return (text.to_string(), 0);
+    }
+    let mut softened = String::with_capacity(text.len());
+    let mut count = 0;
+    for line in text.split_inclusive('\n') {
+        let (body, newline) = match line.strip_suffix('\n') {
+            Some(body) => (body, "\n"),
+            None => (line, ""),
+        };
+        let chars = body.chars().count();
+        if chars <= LONG_LINE_CHARS {
+            softened.push_str(line);
+            continue;
+        }
+        count += 1;
+        match mode {
+            LongLines::Keep => softened.push_str(body),
+            LongLines::Wrap { width } => {
+                let mut rest = body;
+                while !rest.is_empty() {
+                    let end = rest.char_indices().nth(width).map_or(rest.len(), |(i, _)| i);
+                    softened.push_str(&rest[..end]);
+                    rest = &rest[end..];
+                    if !rest.is_empty() {
+                        softened.push('\n');
+                    }
+                }
+            }
+            LongLines::Elide { width } => {
+                let end = body.char_indices().nth(width).map_or(body.len(), |(i, _)| i);
+                softened.push_str(&body[..end]);
+                softened.push_str(&format!(" … [{} more characters]", chars - width));
+            }
+        }
+        softened.push_str(newline);
+    }
+    (softened, count)
+}
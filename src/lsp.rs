@@ -0,0 +1,688 @@
+// Language server bridge: code intelligence for files under review, from the
+// language servers editors use. The first request for a file starts the
+// server for its language in the file's project (the nearest folder above it
+// holding one of the server's root markers, e.g. Cargo.toml) and later ones
+// reuse it. Files are sent to the server as they are on disk at each request,
+// so answers follow applied patches.
+//
+//   GET /api/lsp/definition?path=<file>&line=12&column=8
+//   GET /api/lsp/hover?path=<file>&line=12&column=8
+//   GET /api/lsp/references?path=<file>&line=12&column=8
+//
+// Language servers build and run project code (build scripts, proc macros,
+// plugins), so the bridge is off unless LSP is set:
+//
+//   LSP            `admin` (signed-in admins), `users` (signed-in users) or
+//                  `anyone` (every client, for single-user setups)
+//   LSP_IDLE_SECS  stops servers without requests for this long (default 600)
+//
+// repopatch.toml:
+//   [[language_servers]]
+//   name = "gopls"
+//   command = ["gopls"]
+//   extensions = ["go"]
+//   root_markers = ["go.mod"]
+//
+// Without a [[language_servers]] entry rust-analyzer, typescript-language-server
+// and pyright are used.
+
+use crate::auth::{Session, Stores};
+use actix_web::{get, web, HttpResponse};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::env;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_IDLE_SECS: u64 = 600;
+const INITIALIZE_TIMEOUT: Duration = Duration::from_secs(60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+const REAP_INTERVAL: Duration = Duration::from_secs(30);
+/// Most locations one response lists.
+const MAX_LOCATIONS: usize = 500;
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LanguageServerConfig {
+    pub name: String,
+    /// Program and arguments; the server talks LSP on stdin and stdout.
+    pub command: Vec<String>,
+    /// Extensions (without the dot) of the files it handles.
+    pub extensions: Vec<String>,
+    /// Files marking a project root for it.
+    #[serde(default)]
+    pub root_markers: Vec<String>,
+    /// `languageId` of its documents; by default derived from the extension.
+    #[serde(default)]
+    pub language_id: Option<String>,
+}
+
+fn default_servers() -> Vec<LanguageServerConfig> {
+    let server = |name: &str, command: &[&str], extensions: &[&str], root_markers: &[&str]| LanguageServerConfig {
+        name: name.to_string(),
+        command: command.iter().map(|c| c.to_string()).collect(),
+        extensions: extensions.iter().map(|e| e.to_string()).collect(),
+        root_markers: root_markers.iter().map(|m| m.to_string()).collect(),
+        language_id: None,
+    };
+    vec![
+        server("rust-analyzer", &["rust-analyzer"], &["rs"], &["Cargo.toml"]),
+        server(
+            "typescript-language-server",
+            &["typescript-language-server", "--stdio"],
+            &["ts", "tsx", "js", "jsx", "mjs", "cjs"],
+            &["tsconfig.json", "jsconfig.json", "package.json"],
+        ),
+        server("pyright", &["pyright-langserver", "--stdio"], &["py", "pyi"], &["pyproject.toml", "setup.py", "setup.cfg", "pyrightconfig.json"]),
+    ]
+}
+
+fn language_id(config: &LanguageServerConfig, extension: &str) -> String {
+    if let Some(id) = &config.language_id {
+        return id.clone();
+    }
+    match extension {
+        "rs" => "rust",
+        "ts" => "typescript",
+        "tsx" => "typescriptreact",
+        "js" | "mjs" | "cjs" => "javascript",
+        "jsx" => "javascriptreact",
+        "py" | "pyi" => "python",
+        other => other,
+    }
+    .to_string()
+}
+
+/// The running language servers, one per server and project root.
+pub struct LanguageServers {
+    configs: Vec<LanguageServerConfig>,
+    admins_only: bool,
+    signed_in: bool,
+    idle: Duration,
+    running: Mutex<HashMap<(String, PathBuf), Arc<Server>>>,
+}
+
+impl LanguageServers {
+    /// The bridge, or `None` when LSP leaves it off. Idle servers are stopped
+    /// in the background for as long as it lives.
+    pub fn from_env(configured: Vec<LanguageServerConfig>) -> Result<Option<Arc<Self>>, String> {
+        let (admins_only, signed_in) = match env::var("LSP").unwrap_or_default().as_str() {
+            "" | "off" => return Ok(None),
+            "admin" => (true, true),
+            "users" => (false, true),
+            "anyone" => (false, false),
+            other => return Err(format!("Invalid LSP {}: use admin, users or anyone", other)),
+        };
+        let idle = match env::var("LSP_IDLE_SECS") {
+            Ok(secs) => secs.parse().map_err(|_| format!("Invalid LSP_IDLE_SECS {}", secs))?,
+            Err(_) => DEFAULT_IDLE_SECS,
+        };
+        if let Some(server) = configured.iter().find(|s| s.command.is_empty()) {
+            return Err(format!("Language server {}: the command is empty", server.name));
+        }
+        let configs = if configured.is_empty() { default_servers() } else { configured };
+        let servers = Arc::new(LanguageServers {
+            configs,
+            admins_only,
+            signed_in,
+            idle: Duration::from_secs(idle.max(1)),
+            running: Mutex::new(HashMap::new()),
+        });
+        let weak = Arc::downgrade(&servers);
+        thread::spawn(move || reap(weak));
+        Ok(Some(servers))
+    }
+
+    pub fn needs_sign_in(&self) -> bool {
+        self.signed_in
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<(String, PathBuf), Arc<Server>>> {
+        self.running.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn for_path(&self, path: &str) -> Option<(&LanguageServerConfig, String)> {
+        let extension = path.rsplit_once('.')?.1.to_lowercase();
+        let config = self.configs.iter().find(|c| c.extensions.iter().any(|e| e.eq_ignore_ascii_case(&extension)))?;
+        Some((config, extension))
+    }
+
+    // The server for `config` in `root`, started if it isn't running
+    fn server(&self, config: &LanguageServerConfig, root: &Path) -> Result<Arc<Server>, String> {
+        let key = (config.name.clone(), root.to_path_buf());
+        if let Some(server) = self.lock().get(&key).filter(|s| s.alive.load(Ordering::Relaxed)) {
+            return Ok(server.clone());
+        }
+        // Started outside the lock, since initializing can take a while
+        let server = Server::start(config, root)?;
+        let mut running = self.lock();
+        // Another request may have started one meanwhile
+        if let Some(other) = running.get(&key).filter(|s| s.alive.load(Ordering::Relaxed)) {
+            let other = other.clone();
+            thread::spawn(move || server.shut_down());
+            return Ok(other);
+        }
+        log::info!("Started language server {} in {}", config.name, root.display());
+        running.insert(key, server.clone());
+        Ok(server)
+    }
+}
+
+// Stops servers idle for longer than the bridge allows until it is dropped
+fn reap(servers: Weak<LanguageServers>) {
+    loop {
+        thread::sleep(REAP_INTERVAL);
+        let Some(servers) = servers.upgrade() else {
+            return;
+        };
+        let stopped: Vec<Arc<Server>> = {
+            let mut running = servers.lock();
+            let idle: Vec<_> = running.iter().filter(|(_, s)| !s.alive.load(Ordering::Relaxed) || s.idle_for() > servers.idle).map(|(k, _)| k.clone()).collect();
+            idle.iter().filter_map(|key| running.remove(key)).collect()
+        };
+        for server in stopped {
+            log::info!("Stopping idle language server {} in {}", server.name, server.root.display());
+            server.shut_down();
+        }
+    }
+}
+
+type Pending = Arc<Mutex<HashMap<u64, mpsc::Sender<Result<Value, String>>>>>;
+
+struct Server {
+    name: String,
+    root: PathBuf,
+    child: Mutex<Child>,
+    stdin: Arc<Mutex<ChildStdin>>,
+    next_id: AtomicU64,
+    pending: Pending,
+    /// Version and content hash of every document sent to the server.
+    documents: Mutex<HashMap<String, (i64, u64)>>,
+    alive: Arc<AtomicBool>,
+    last_used: Mutex<Instant>,
+}
+
+impl Server {
+    fn start(config: &LanguageServerConfig, root: &Path) -> Result<Arc<Self>, String> {
+        let (program, args) = config.command.split_first().ok_or("Language server command is empty")?;
+        let mut command = Command::new(program);
+        command.args(args).current_dir(root).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let mut child = command.spawn().map_err(|e| format!("Failed to start {}: {}", program, e))?;
+        let stdin = Arc::new(Mutex::new(child.stdin.take().expect("stdin is piped")));
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let name = config.name.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                log::debug!("{}: {}", name, line);
+            }
+        });
+
+        let pending: Pending = Arc::default();
+        let alive = Arc::new(AtomicBool::new(true));
+        {
+            let (pending, alive, stdin) = (pending.clone(), alive.clone(), stdin.clone());
+            let name = config.name.clone();
+            thread::spawn(move || read_messages(&name, stdout, &pending, &stdin, &alive));
+        }
+        let server = Arc::new(Server {
+            name: config.name.clone(),
+            root: root.to_path_buf(),
+            child: Mutex::new(child),
+            stdin,
+            next_id: AtomicU64::new(1),
+            pending,
+            documents: Mutex::new(HashMap::new()),
+            alive,
+            last_used: Mutex::new(Instant::now()),
+        });
+
+        let root_uri = file_uri(root);
+        let folder = root.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let capabilities = json!({
+            "textDocument": {
+                "synchronization": { "didSave": false },
+                "hover": { "contentFormat": ["markdown", "plaintext"] },
+                "definition": { "linkSupport": true },
+                "references": {}
+            },
+            "workspace": { "workspaceFolders": true, "configuration": true },
+            "general": { "positionEncodings": ["utf-16"] }
+        });
+        let params = json!({
+            "processId": std::process::id(),
+            "clientInfo": { "name": "repopatch" },
+            "rootUri": root_uri,
+            "rootPath": root.to_string_lossy(),
+            "workspaceFolders": [{ "uri": root_uri, "name": folder }],
+            "capabilities": capabilities
+        });
+        if let Err(e) = server.request("initialize", params, INITIALIZE_TIMEOUT) {
+            server.kill();
+            return Err(format!("{} failed to initialize: {}", config.name, e));
+        }
+        server.notify("initialized", json!({}))?;
+        Ok(server)
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last_used.lock().unwrap_or_else(|e| e.into_inner()).elapsed()
+    }
+
+    fn touch(&self) {
+        *self.last_used.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+
+    fn notify(&self, method: &str, params: Value) -> Result<(), String> {
+        send(&self.stdin, &json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+            .map_err(|e| format!("{} is gone: {}", self.name, e))
+    }
+
+    fn request(&self, method: &str, params: Value, timeout: Duration) -> Result<Value, String> {
+        self.touch();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel();
+        self.pending.lock().unwrap_or_else(|e| e.into_inner()).insert(id, tx);
+        if let Err(e) = send(&self.stdin, &json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })) {
+            self.pending.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+            return Err(format!("{} is gone: {}", self.name, e));
+        }
+        match rx.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(_) => {
+                self.pending.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+                let _ = self.notify("$/cancelRequest", json!({ "id": id }));
+                Err(format!("{} didn't answer {} within {}s", self.name, method, timeout.as_secs()))
+            }
+        }
+    }
+
+    // Helper function to hand the server a file's current text, opening it or
+    // replacing what it had
+    fn sync(&self, uri: &str, language_id: &str, text: &str) -> Result<(), String> {
+        let mut hasher = DefaultHasher::new();
+        text.hash(&mut hasher);
+        let hash = hasher.finish();
+        let mut documents = self.documents.lock().unwrap_or_else(|e| e.into_inner());
+        match documents.get(uri).copied() {
+            Some((_, known)) if known == hash => Ok(()),
+            Some((version, _)) => {
+                documents.insert(uri.to_string(), (version + 1, hash));
+                self.notify(
+                    "textDocument/didChange",
+                    json!({ "textDocument": { "uri": uri, "version": version + 1 }, "contentChanges": [{ "text": text }] }),
+                )
+            }
+            None => {
+                documents.insert(uri.to_string(), (1, hash));
+                self.notify("textDocument/didOpen", json!({ "textDocument": { "uri": uri, "languageId": language_id, "version": 1, "text": text } }))
+            }
+        }
+    }
+
+    fn shut_down(&self) {
+        if self.alive.load(Ordering::Relaxed) && self.request("shutdown", Value::Null, SHUTDOWN_TIMEOUT).is_ok() {
+            let _ = self.notify("exit", Value::Null);
+            thread::sleep(Duration::from_millis(200));
+        }
+        self.kill();
+    }
+
+    fn kill(&self) {
+        let mut child = self.child.lock().unwrap_or_else(|e| e.into_inner());
+        #[cfg(unix)]
+        // SAFETY: signalling the process group the server leads
+        unsafe {
+            libc::kill(-(child.id() as i32), libc::SIGKILL);
+        }
+        let _ = child.kill();
+        let _ = child.wait();
+        self.alive.store(false, Ordering::Relaxed);
+    }
+}
+
+fn send(stdin: &Mutex<ChildStdin>, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    let mut stdin = stdin.lock().unwrap_or_else(|e| e.into_inner());
+    write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    stdin.flush()
+}
+
+// One message from the server, None once it closes its output
+fn read_message(reader: &mut impl BufRead) -> Option<Value> {
+    loop {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header).ok()? == 0 {
+                return None;
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse::<usize>().ok();
+                }
+            }
+        }
+        let Some(length) = length else {
+            continue;
+        };
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).ok()?;
+        if let Ok(message) = serde_json::from_slice(&body) {
+            return Some(message);
+        }
+    }
+}
+
+// Routes responses to their requests and answers the server's own requests
+// until it exits
+fn read_messages(name: &str, stdout: impl Read, pending: &Pending, stdin: &Mutex<ChildStdin>, alive: &AtomicBool) {
+    let mut reader = BufReader::new(stdout);
+    while let Some(message) = read_message(&mut reader) {
+        match (message.get("id"), message.get("method")) {
+            (Some(id), Some(method)) => {
+                // Configuration, progress and registration requests; the
+                // defaults the server has are fine
+                let result = match method.as_str() {
+                    Some("workspace/configuration") => {
+                        let items = message["params"]["items"].as_array().map_or(0, Vec::len);
+                        Value::Array(vec![Value::Null; items])
+                    }
+                    _ => Value::Null,
+                };
+                let _ = send(stdin, &json!({ "jsonrpc": "2.0", "id": id, "result": result }));
+            }
+            (Some(id), None) => {
+                let Some(tx) = id.as_u64().and_then(|id| pending.lock().unwrap_or_else(|e| e.into_inner()).remove(&id)) else {
+                    continue;
+                };
+                let result = match message.get("error") {
+                    Some(error) => Err(error["message"].as_str().unwrap_or("request failed").to_string()),
+                    None => Ok(message.get("result").cloned().unwrap_or(Value::Null)),
+                };
+                let _ = tx.send(result);
+            }
+            _ => {}
+        }
+    }
+    alive.store(false, Ordering::Relaxed);
+    for (_, tx) in pending.lock().unwrap_or_else(|e| e.into_inner()).drain() {
+        let _ = tx.send(Err(format!("{} exited", name)));
+    }
+}
+
+fn file_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            uri.push(byte as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    uri
+}
+
+fn uri_path(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        let hex = encoded.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok()).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (encoded[i], hex) {
+            (b'%', Some(byte)) => {
+                bytes.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                bytes.push(byte);
+                i += 1;
+            }
+        }
+    }
+    Some(PathBuf::from(String::from_utf8(bytes).ok()?))
+}
+
+// LSP counts columns in UTF-16 code units; the API in characters, from 1
+fn utf16_column(line: &str, column: usize) -> usize {
+    line.chars().take(column.saturating_sub(1)).map(char::len_utf16).sum()
+}
+
+fn char_column(line: &str, utf16: u64) -> usize {
+    let mut units = 0;
+    let mut chars = 0;
+    for c in line.chars() {
+        if units >= utf16 as usize {
+            break;
+        }
+        units += c.len_utf16();
+        chars += 1;
+    }
+    chars + 1
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PositionQuery {
+    path: String,
+    /// 1-based.
+    line: usize,
+    /// 1-based, in characters.
+    column: usize,
+    /// The server's workspace; by default the file's project.
+    root: Option<String>,
+    /// Whether references include the declaration (default true).
+    include_declaration: Option<bool>,
+}
+
+#[derive(Clone, Copy)]
+enum Lookup {
+    Definition,
+    Hover,
+    References,
+}
+
+#[get("/api/lsp/definition")]
+pub async fn lsp_definition(
+    query: web::Query<PositionQuery>,
+    stores: Stores,
+    session: Option<web::ReqData<Session>>,
+    servers: Option<web::Data<LanguageServers>>,
+) -> HttpResponse {
+    look_up(Lookup::Definition, query.into_inner(), stores, session, servers).await
+}
+
+#[get("/api/lsp/hover")]
+pub async fn lsp_hover(
+    query: web::Query<PositionQuery>,
+    stores: Stores,
+    session: Option<web::ReqData<Session>>,
+    servers: Option<web::Data<LanguageServers>>,
+) -> HttpResponse {
+    look_up(Lookup::Hover, query.into_inner(), stores, session, servers).await
+}
+
+#[get("/api/lsp/references")]
+pub async fn lsp_references(
+    query: web::Query<PositionQuery>,
+    stores: Stores,
+    session: Option<web::ReqData<Session>>,
+    servers: Option<web::Data<LanguageServers>>,
+) -> HttpResponse {
+    look_up(Lookup::References, query.into_inner(), stores, session, servers).await
+}
+
+async fn look_up(
+    lookup: Lookup,
+    query: PositionQuery,
+    stores: Stores,
+    session: Option<web::ReqData<Session>>,
+    servers: Option<web::Data<LanguageServers>>,
+) -> HttpResponse {
+    let Some(servers) = servers else {
+        return HttpResponse::NotFound().json(json!({ "success": false, "error": "The language server bridge is disabled; set LSP to enable it" }));
+    };
+    match &session {
+        Some(s) if s.admin || !servers.admins_only => {}
+        Some(_) => return HttpResponse::Forbidden().json(json!({ "success": false, "error": "Admin access required" })),
+        None if !servers.signed_in => {}
+        None => {
+            return HttpResponse::Forbidden()
+                .json(json!({ "success": false, "error": "The language server bridge needs a signed-in user; configure USERS_FILE or OIDC, or set LSP=anyone" }))
+        }
+    }
+    if query.line == 0 || query.column == 0 {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": "line and column count from 1" }));
+    }
+    let (file_store, name) = match stores.open_file(&query.path) {
+        Ok(f) => f,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+    let Some((config, extension)) = servers.for_path(&name) else {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("No language server handles {}", name) }));
+    };
+    let config = config.clone();
+    let (task_store, task_name) = (file_store.clone(), name.clone());
+    let text = match web::block(move || task_store.read(&task_name)).await.unwrap_or_else(|e| Err(io::Error::other(e.to_string()))).map(String::from_utf8) {
+        Ok(Ok(text)) => text,
+        Ok(Err(_)) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": "File is not valid UTF-8" })),
+        Err(e) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Failed to read file: {}", e) })),
+    };
+    let Some(line_text) = text.lines().nth(query.line - 1).map(str::to_string) else {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("{} has no line {}", name, query.line) }));
+    };
+    let file = PathBuf::from(file_store.display_path(&name));
+    let root = match &query.root {
+        Some(root) => match stores.open_root(root) {
+            Ok(s) => PathBuf::from(s.display_path("")),
+            Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid root: {}", e) })),
+        },
+        None => project_root(&stores, &file, &config.root_markers),
+    };
+    if !file.starts_with(&root) {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("{} is not inside {}", file.display(), root.display()) }));
+    }
+    let include_declaration = query.include_declaration.unwrap_or(true);
+    let position = json!({ "line": query.line - 1, "character": utf16_column(&line_text, query.column) });
+
+    let result = web::block(move || -> Result<Value, String> {
+        let server = servers.server(&config, &root)?;
+        let uri = file_uri(&file);
+        server.sync(&uri, &language_id(&config, &extension), &text)?;
+        let document = json!({ "textDocument": { "uri": uri }, "position": position });
+        let mut response = json!({ "success": true, "server": config.name, "root": root.to_string_lossy() });
+        match lookup {
+            Lookup::Definition => {
+                let found = server.request("textDocument/definition", document, REQUEST_TIMEOUT)?;
+                response["locations"] = json!(locations(&stores, &root, &found));
+            }
+            Lookup::Hover => {
+                let found = server.request("textDocument/hover", document, REQUEST_TIMEOUT)?;
+                response["contents"] = hover_text(&found["contents"]).map_or(Value::Null, Value::String);
+            }
+            Lookup::References => {
+                let mut params = document;
+                params["context"] = json!({ "includeDeclaration": include_declaration });
+                let found = server.request("textDocument/references", params, REQUEST_TIMEOUT)?;
+                response["locations"] = json!(locations(&stores, &root, &found));
+            }
+        }
+        Ok(response)
+    })
+    .await;
+    match result {
+        Ok(Ok(response)) => HttpResponse::Ok().json(response),
+        Ok(Err(e)) => HttpResponse::BadGateway().json(json!({ "success": false, "error": e })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Language server task failed: {}", e) })),
+    }
+}
+
+// The nearest folder above `file` holding a root marker that the client may
+// open, else its repository or its folder
+fn project_root(stores: &Stores, file: &Path, markers: &[String]) -> PathBuf {
+    let dir = file.parent().unwrap_or(file);
+    let allowed = |d: &Path| stores.open_root(&d.to_string_lossy()).is_ok();
+    dir.ancestors()
+        .take_while(|d| allowed(d))
+        .find(|d| markers.iter().any(|m| d.join(m).is_file()))
+        .map(Path::to_path_buf)
+        .or_else(|| crate::vcs::repository_root(dir).filter(|r| allowed(r)))
+        .unwrap_or_else(|| dir.to_path_buf())
+}
+
+// Locations or location links as the API lists them. Files outside the roots
+// the client may open keep their path but get no preview.
+fn locations(stores: &Stores, root: &Path, found: &Value) -> Vec<Value> {
+    let entries = match found {
+        Value::Array(entries) => entries.clone(),
+        Value::Null => Vec::new(),
+        single => vec![single.clone()],
+    };
+    let mut lines: HashMap<PathBuf, Option<Vec<String>>> = HashMap::new();
+    entries
+        .iter()
+        .take(MAX_LOCATIONS)
+        .filter_map(|entry| {
+            let uri = entry.get("targetUri").or_else(|| entry.get("uri"))?.as_str()?;
+            let range = entry.get("targetSelectionRange").or_else(|| entry.get("range"))?;
+            let path = uri_path(uri)?;
+            let text = lines.entry(path.clone()).or_insert_with(|| {
+                let (store, name) = stores.open_file(&path.to_string_lossy()).ok()?;
+                let data = store.read(&name).ok()?;
+                Some(String::from_utf8_lossy(&data).lines().map(str::to_string).collect())
+            });
+            let point = |key: &str| -> (usize, usize) {
+                let line = range[key]["line"].as_u64().unwrap_or(0);
+                let character = range[key]["character"].as_u64().unwrap_or(0);
+                let column = match text.as_ref().and_then(|t| t.get(line as usize)) {
+                    Some(line_text) => char_column(line_text, character),
+                    None => character as usize + 1,
+                };
+                (line as usize + 1, column)
+            };
+            let (line, column) = point("start");
+            let (end_line, end_column) = point("end");
+            let preview = text.as_ref().and_then(|t| t.get(line - 1)).map(|l| l.trim().to_string());
+            let file = path.strip_prefix(root).ok().map(|p| p.to_string_lossy().into_owned());
+            Some(json!({
+                "path": path.to_string_lossy(),
+                "file": file,
+                "line": line,
+                "column": column,
+                "endLine": end_line,
+                "endColumn": end_column,
+                "preview": preview,
+            }))
+        })
+        .collect()
+}
+
+// Hover contents as markdown: MarkupContent, a MarkedString or a list of them
+fn hover_text(contents: &Value) -> Option<String> {
+    let text = match contents {
+        Value::String(s) => s.clone(),
+        Value::Array(items) => items.iter().filter_map(hover_text).collect::<Vec<_>>().join("\n\n"),
+        Value::Object(o) => match (o.get("language").and_then(Value::as_str), o.get("value").and_then(Value::as_str)) {
+            (Some(language), Some(value)) => format!("```{}\n{}\n```", language, value),
+            (None, Some(value)) => value.to_string(),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    (!text.trim().is_empty()).then_some(text)
+}
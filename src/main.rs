@@ -1,38 +1,232 @@
 use actix_cors::Cors;
-use actix_web::{get, post, web, App, HttpResponse, HttpRequest, HttpServer};
-use actix_web::http::header;
+use actix_files::NamedFile;
+use actix_web::{get, post, web, App, HttpMessage, HttpResponse, HttpRequest, HttpServer};
+use actix_web::http::{header, KeepAlive, StatusCode};
 use rust_embed::RustEmbed;
-use mime_guess;
 use ignore::gitignore::Gitignore;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
-use std::fs::{self, File, OpenOptions};
+use std::fs::File;
 use std::io::BufReader;
-use std::path::{Path, PathBuf};
-use alphanumeric_sort::compare_str;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use rustls_pemfile::{certs, pkcs8_private_keys};
-use tokio::fs as tokio_fs;
 use rustls::ServerConfig;
 use futures::stream::{self, StreamExt};
-use diff_match_patch_rs::{DiffMatchPatch, Compat};
+use repopatch::patch::{self, ApplyOptions, PatchSet};
+use repopatch::archive::ArchiveStoreProvider;
+use repopatch::store::{AliasStoreProvider, FileStore, LocalStoreProvider, MemoryStoreProvider, OverlayStore, ProtectedPaths, ProtectedStoreProvider, StoreProvider};
+use repopatch::engine::{self, EngineSelection};
+use repopatch::metadata::Metadata;
+use repopatch::policy::{Action, Policy};
+use repopatch::refactor::{self, RenameScope};
+use repopatch::remap::PathRewrites;
+use repopatch::replace::{self, ReplaceSpec};
+use repopatch::{context, deps, diagnostics, diff, documents, duplicates, extract, images, impact, longlines, messages, moves, notebook, packages, policy, preview, related, scaffold, seed, stats, table, tree};
+use auth::{Session, Stores};
+use validation::ValidJson;
+
+mod activity;
+mod admin;
+mod agent;
+mod approvals;
+mod auth;
+mod chats;
+mod cli;
+mod config;
+mod csrf;
+mod dropfolder;
+mod embeddings;
+mod forge;
+mod format;
+mod history;
+mod i18n;
+mod import;
+mod instance;
+mod jj;
+mod logging;
+mod lsp;
+mod memory;
+mod multipatch;
+mod oidc;
+mod packcache;
+mod presence;
+mod profiles;
+mod proxy;
+mod reviews;
+mod selftest;
+mod service;
+mod settings;
+mod state;
+mod terminal;
+mod testrun;
+mod thumbnail;
+mod trash;
+mod upload;
+mod usage;
+mod validation;
+mod vcs;
+mod watch;
+mod writable;
+
+/// Free space (in MiB) patch writes must leave on the target volume.
+const DEFAULT_DISK_RESERVE_MB: u64 = 16;
+const DEFAULT_RECENT_FILES: usize = 50;
+const MAX_RECENT_FILES: usize = 1000;
+const DEFAULT_PREVIEW_CONTEXT: usize = 3;
+const DEFAULT_RELATED_LIMIT: usize = 20;
+const MAX_RELATED_LIMIT: usize = 100;
+const MAX_PREVIEW_CONTEXT: usize = 50;
+const DEFAULT_CONFIRM_DELETES: usize = 5;
+const DEFAULT_CONFIRM_FILES: usize = 50;
+const DEFAULT_MEMORY_REPO_ROOT: &str = "/memory";
+const DEFAULT_PATCH_AUTHOR: &str = "RepoPatch <repopatch@localhost>";
+/// Largest JSON body (in MiB, after Content-Encoding is decoded) by default.
+const DEFAULT_MAX_REQUEST_MB: usize = 2;
 
 #[derive(RustEmbed)]
 #[folder = "public/"]
 struct Asset;
 
-#[derive(Serialize)]
-struct TreeNode {
-    #[serde(rename = "type")]
-    node_type: String,
-    path: String,
-    children: Option<HashMap<String, TreeNode>>,
+#[derive(Deserialize)]
+struct DirectoryQuery {
+    path: Option<String>,
 }
 
 #[derive(Deserialize)]
-struct DirectoryQuery {
+struct FileQuery {
     path: Option<String>,
+    /// Also return the content with line-number prefixes
+    #[serde(default)]
+    numbered: bool,
+    /// Comma-separated FILE_FIELDS (or `blame`) to return, instead of all of them
+    fields: Option<String>,
+    /// Also return who last changed each line
+    #[serde(default)]
+    blame: bool,
+    /// `wrap` or `elide` lines longer than longlines::LONG_LINE_CHARS
+    #[serde(rename = "longLines")]
+    long_lines: Option<String>,
+    /// Characters to wrap or cut long lines at
+    #[serde(rename = "lineWidth")]
+    line_width: Option<usize>,
+    /// `source` shows a notebook's cells without their outputs
+    notebook: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TreeQuery {
+    path: Option<String>,
+    /// Comma-separated roots to list together, instead of `path`
+    roots: Option<String>,
+    /// A saved /api/profiles selection of each root
+    profile: Option<String>,
+    /// List this /api/packages member of `path` instead of all of it
+    package: Option<String>,
+    /// Comma-separated file extensions to include, e.g. `rs,toml`
+    extensions: Option<String>,
+    #[serde(rename = "maxSize")]
+    max_size: Option<u64>,
+    #[serde(rename = "includeHidden", default = "default_include_hidden")]
+    include_hidden: bool,
+    /// Comma-separated tree::NODE_FIELDS of each node, instead of all of them
+    fields: Option<String>,
+    /// List the entries of ZIP and tar archives as read-only folders
+    #[serde(default)]
+    archives: bool,
+}
+
+fn default_include_hidden() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct RecentFilesQuery {
+    path: Option<String>,
+    /// Unix timestamp in seconds, or an age such as `90m`, `24h` or `7d`
+    since: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct ExportPatchQuery {
+    #[serde(rename = "reportId")]
+    report_id: String,
+    subject: Option<String>,
+    body: Option<String>,
+    author: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PreviewQuery {
+    path: String,
+    line: usize,
+    context: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct DepsQuery {
+    path: String,
+    /// Folder (relative to `path`) whose files to report, default all
+    dir: Option<String>,
+    /// Report one file (relative to `path`) and the files importing it
+    file: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RelatedQuery {
+    /// The file to find related files for
+    path: String,
+    /// Folder to search, default the file's git repository or folder
+    root: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct PlanContextRequest {
+    #[serde(rename = "directoryPath")]
+    directory_path: String,
+    /// Token budget for the selected context
+    budget: usize,
+    /// Files the question is about, relative to the directory
+    #[serde(default)]
+    files: Vec<String>,
+    question: Option<String>,
+    /// Return the selected text too
+    #[serde(rename = "includeContent", default)]
+    include_content: bool,
+}
+
+#[derive(Deserialize)]
+struct ImpactRequest {
+    #[serde(rename = "directoryPath")]
+    directory_path: String,
+    #[serde(rename = "patchContent")]
+    patch_content: String,
+    /// Import levels to follow from the changed files; 0 follows all of them
+    #[serde(rename = "maxDepth")]
+    max_depth: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct ExtractQuery {
+    path: String,
+    symbol: String,
+    context: Option<usize>,
+}
+
+/// Fields of a file read that a client can pick with `fields`; `success`
+/// and `error` always come along.
+const FILE_FIELDS: &[&str] = &["content", "numberedContent", "changedSinceRead", "revision", "language", "longLines", "extractedFrom"];
+
+// Helper function to keep only the requested fields of a response object
+fn project_fields(mut body: serde_json::Value, fields: Option<&[String]>) -> serde_json::Value {
+    if let (Some(fields), Some(object)) = (fields, body.as_object_mut()) {
+        object.retain(|key, _| key == "success" || key == "error" || fields.iter().any(|f| f == key));
+    }
+    body
 }
 
 #[derive(Serialize)]
@@ -40,310 +234,1198 @@ struct FileResult {
     success: bool,
     content: Option<String>,
     error: Option<String>,
+    /// Set for identified clients: whether the content differs from their previous read.
+    #[serde(rename = "changedSinceRead", skip_serializing_if = "Option::is_none")]
+    changed_since_read: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revision: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language: Option<&'static str>,
+    #[serde(rename = "numberedContent", skip_serializing_if = "Option::is_none")]
+    numbered_content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawQuery {
+    path: String,
+    /// Ask the browser to save the file instead of showing it
+    #[serde(default)]
+    download: bool,
 }
 
 #[derive(Deserialize)]
 struct FilesRequest {
     paths: Vec<String>,
+    /// Also return each file's content with line-number prefixes
+    #[serde(default)]
+    numbered: bool,
+    /// Refuse files outside this saved /api/profiles selection
+    profile: Option<String>,
+    /// FILE_FIELDS to return for each file, instead of all of them
+    fields: Option<Vec<String>>,
 }
 
 #[derive(Deserialize)]
 struct ApplyPatchRequest {
     #[serde(rename = "directoryPath")]
     directory_path: String,
-    #[serde(rename = "patchContent")]
+    #[serde(rename = "patchContent", default)]
     patch_content: String,
+    /// A finished /api/patch_upload, instead of patchContent
+    #[serde(rename = "uploadId")]
+    upload_id: Option<String>,
+    /// Lines of surrounding content returned around failed hunks on CONTEXT_MISMATCH.
+    #[serde(rename = "contextLines")]
+    context_lines: Option<usize>,
+    /// Run the configured formatters over the patched files afterwards.
+    format: Option<bool>,
+    /// Apply to an in-memory copy and report the outcome without writing.
+    #[serde(rename = "dryRun")]
+    dry_run: Option<bool>,
+    /// Token returned by a previous attempt that needed confirmation.
+    #[serde(rename = "confirmationToken")]
+    confirmation_token: Option<String>,
+    /// Return a step-by-step trace of how the patch was applied.
+    debug: Option<bool>,
+    /// Content to use instead of what is on disk, by path relative to
+    /// directoryPath. Nothing is written; the patched content is returned.
+    #[serde(rename = "virtualFiles")]
+    virtual_files: Option<HashMap<String, String>>,
+    /// Revisions the files were read at; stale files fail with 409
+    #[serde(rename = "ifRevision", default)]
+    if_revision: HashMap<String, String>,
+    /// The /api/chats conversation the patch came from, linked to the report
+    #[serde(rename = "chatId")]
+    chat_id: Option<String>,
+    #[serde(rename = "messageId")]
+    message_id: Option<String>,
+    /// Patch engine to use instead of the workspace's, see repopatch::engine
+    engine: Option<String>,
+    /// "auto" picks the engine from what the patch looks like
+    #[serde(rename = "patchFormat")]
+    patch_format: Option<String>,
 }
 
 #[derive(Deserialize)]
-struct CheckWritableRequest {
+struct DiffViewRequest {
+    /// Either a directory and a patch to preview against it...
+    #[serde(rename = "directoryPath")]
+    directory_path: Option<String>,
+    #[serde(rename = "patchContent")]
+    patch_content: Option<String>,
+    /// ...or a change already applied, from its apply_patch response.
+    #[serde(rename = "reportId")]
+    report_id: Option<String>,
+    /// Unchanged lines kept around each change; all of them when unset.
+    context: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct FormatRequest {
     #[serde(rename = "directoryPath")]
     directory_path: String,
+    /// Files relative to the directory
+    paths: Vec<String>,
+    /// Write the formatted files instead of only returning the diff
+    #[serde(default)]
+    write: bool,
+    #[serde(rename = "ifRevision", default)]
+    if_revision: HashMap<String, String>,
 }
 
-fn validate_path(requested_path: &str) -> Result<PathBuf, String> {
-    let base_path = PathBuf::from(requested_path);
-    let resolved_path = base_path
-        .canonicalize()
-        .map_err(|e| format!("Failed to canonicalize base directory path '{}': {}", requested_path, e))?;
-    Ok(resolved_path)
+#[derive(Deserialize)]
+struct ReplaceRequest {
+    #[serde(rename = "directoryPath")]
+    directory_path: String,
+    pattern: String,
+    replacement: String,
+    /// Globs relative to the directory, e.g. `src/**/*.rs`; empty means all files
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    /// planId from the dry run; without it nothing is written
+    #[serde(rename = "planId")]
+    plan_id: Option<String>,
+    #[serde(rename = "ifRevision", default)]
+    if_revision: HashMap<String, String>,
 }
 
-fn natural_compare(a: &str, b: &str) -> std::cmp::Ordering {
-    compare_str(a, b)
+#[derive(Deserialize)]
+struct RenameSymbolRequest {
+    #[serde(rename = "directoryPath")]
+    directory_path: String,
+    /// File the symbol was picked in, relative to the directory
+    file: String,
+    symbol: String,
+    #[serde(rename = "newName")]
+    new_name: String,
+    /// `project` (default), `file` or `function`
+    #[serde(default)]
+    scope: Option<String>,
+    /// Line of the symbol in `file`, required for the `function` scope
+    line: Option<usize>,
 }
 
-fn build_tree(path: &Path, ig: &Gitignore) -> Result<HashMap<String, TreeNode>, String> {
-    let mut tree = HashMap::new();
-    let entries = fs::read_dir(path).map_err(|e| format!("Failed to read directory: {}", e))?;
-    let mut dirents = Vec::new();
+#[derive(Deserialize)]
+struct MoveRequest {
+    #[serde(rename = "directoryPath")]
+    directory_path: String,
+    /// File to move, relative to the directory
+    from: String,
+    to: String,
+    /// Rewrite imports and includes of the file (default true)
+    #[serde(rename = "updateReferences", default = "default_update_references")]
+    update_references: bool,
+}
 
-    for entry in entries {
-        let entry = entry.map_err(|e| format!("Directory entry error: {}", e))?;
-        let entry_path = entry.path();
-        let check_path = if entry_path.is_absolute() {
-            entry_path.clone()
-        } else {
-            path.join(&entry_path)
+fn default_update_references() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct ScaffoldRequest {
+    #[serde(rename = "directoryPath")]
+    directory_path: String,
+    template: String,
+    #[serde(default)]
+    variables: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct CheckWritableRequest {
+    #[serde(rename = "directoryPath")]
+    directory_path: String,
+    /// Check again now instead of answering with the kept status
+    #[serde(default)]
+    recheck: bool,
+}
+
+#[get("/api/directory")]
+async fn get_directory(
+    req: HttpRequest,
+    query: web::Query<TreeQuery>,
+    stores: Stores,
+    watchdog: web::Data<watch::Watchdog>,
+    profiles: web::Data<profiles::ProfileStore>,
+    limits: web::Data<tree::TreeLimits>,
+) -> HttpResponse {
+    let fields = match query.fields.as_deref().map(|f| tree::parse_fields(f, tree::NODE_FIELDS)).transpose() {
+        Ok(f) => f,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+    let project = |tree: &HashMap<String, tree::TreeNode>| match &fields {
+        Some(fields) => tree::project_tree(tree, fields),
+        None => json!(tree),
+    };
+    let filter = tree::TreeFilter {
+        extensions: query.extensions.as_deref().map(tree::TreeFilter::parse_extensions),
+        max_size: query.max_size,
+        include_hidden: query.include_hidden,
+        paths: None,
+        archives: query.archives,
+    };
+    // Each root gets its own profile of the requested name
+    let filter_for = |root: &str| -> Result<tree::TreeFilter, String> {
+        match &query.profile {
+            Some(name) => Ok(tree::TreeFilter { paths: Some(profiles.filter(root, name)?), ..filter.clone() }),
+            None => Ok(filter.clone()),
+        }
+    };
+    if let Some(roots) = &query.roots {
+        if query.path.is_some() || query.package.is_some() {
+            return HttpResponse::BadRequest().json(json!({ "success": false, "error": "Give path (and package) or roots, not both" }));
+        }
+        return get_merged_directory(&req, roots, &filter_for, &project, **limits, &stores, &watchdog).await;
+    }
+    let requested_path = query.path.clone().unwrap_or_else(|| env::current_dir().unwrap().to_string_lossy().to_string());
+    let store = match stores.open_root(&requested_path) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+    let store = match &query.package {
+        Some(name) => match open_package(&stores, &*store, name) {
+            Ok(s) => s,
+            Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+        },
+        None => store,
+    };
+    if let Some(client) = watch::client_id(&req) {
+        watchdog.record_root(&client, std::path::Path::new(&store.display_path("")));
+    }
+
+    let filter = match filter_for(&store.display_path("")) {
+        Ok(f) => f,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+    let task_store = store.clone();
+    let limits = **limits;
+    let scanned = web::block(move || {
+        let ig = tree::load_gitignore(&*task_store, "").unwrap_or_else(Gitignore::empty);
+        let mut scan = tree::TreeScan::new(limits);
+        tree::build_reported_tree(&*task_store, "", &ig, &filter, &mut scan).map(|tree| (tree, scan))
+    })
+    .await;
+    match scanned {
+        Ok(Ok((tree, scan))) => {
+            let (file_count, size) = tree::tree_totals(&tree);
+            let mut body = json!({
+                "success": true,
+                "tree": project(&tree),
+                "root": store.display_path(""),
+                "fileCount": file_count,
+                "size": size
+            });
+            add_scan_warnings(&mut body, &scan);
+            HttpResponse::Ok().json(body)
+        }
+        Ok(Err(e)) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": e })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Directory scan failed: {}", e) })),
+    }
+}
+
+// Helper function to list several roots as one tree keyed by root name
+async fn get_merged_directory(
+    req: &HttpRequest,
+    roots: &str,
+    filter_for: &dyn Fn(&str) -> Result<tree::TreeFilter, String>,
+    project: &dyn Fn(&HashMap<String, tree::TreeNode>) -> serde_json::Value,
+    limits: tree::TreeLimits,
+    stores: &Stores,
+    watchdog: &watch::Watchdog,
+) -> HttpResponse {
+    let requested: Vec<&str> = roots.split(',').map(str::trim).filter(|r| !r.is_empty()).collect();
+    if requested.is_empty() {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": "roots is empty" }));
+    }
+    let mut opened: Vec<(String, Arc<dyn FileStore>, tree::TreeFilter)> = Vec::new();
+    for root in requested {
+        let store = match stores.open_root(root) {
+            Ok(s) => s,
+            Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("{}: {}", root, e) })),
         };
-        if ig.matched(&check_path, entry_path.is_dir()).is_ignore() {
+        let display = store.display_path("");
+        if opened.iter().any(|(r, _, _)| *r == display) {
             continue;
         }
-        dirents.push(entry);
+        let filter = match filter_for(&display) {
+            Ok(f) => f,
+            Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+        };
+        if let Some(client) = watch::client_id(req) {
+            watchdog.record_root(&client, std::path::Path::new(&display));
+        }
+        opened.push((display, store, filter));
     }
-
-    dirents.sort_by(|a, b| {
-        let a_is_dir = a.path().is_dir();
-        let b_is_dir = b.path().is_dir();
-        if a_is_dir && !b_is_dir {
-            std::cmp::Ordering::Less
-        } else if !a_is_dir && b_is_dir {
-            std::cmp::Ordering::Greater
-        } else {
-            natural_compare(&a.file_name().to_string_lossy(), &b.file_name().to_string_lossy())
+    let scanned = web::block(move || {
+        let mut trees = Vec::new();
+        // One scan for all roots, so the limits bound the whole request
+        let mut scan = tree::TreeScan::new(limits);
+        for (display, store, filter) in opened {
+            let ig = tree::load_gitignore(&*store, "").unwrap_or_else(Gitignore::empty);
+            let tree = tree::build_reported_tree(&*store, "", &ig, &filter, &mut scan).map_err(|e| format!("{}: {}", display, e))?;
+            trees.push((display, tree));
         }
+        Ok::<_, String>((trees, scan))
+    })
+    .await;
+    let (trees, scan) = match scanned {
+        Ok(Ok(scanned)) => scanned,
+        Ok(Err(e)) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": e })),
+        Err(e) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Directory scan failed: {}", e) })),
+    };
+    let (tree, names) = tree::merge_roots(trees);
+    let (file_count, size) = tree::tree_totals(&tree);
+    let roots: Vec<_> = names
+        .iter()
+        .map(|name| {
+            let node = &tree[name];
+            json!({ "name": name, "root": node.path, "fileCount": node.file_count, "size": node.size })
+        })
+        .collect();
+    let mut body = json!({
+        "success": true,
+        "tree": project(&tree),
+        "roots": roots,
+        "fileCount": file_count,
+        "size": size
     });
+    add_scan_warnings(&mut body, &scan);
+    HttpResponse::Ok().json(body)
+}
 
-    for dirent in dirents {
-        let entry_path = dirent.path();
-        let name = dirent.file_name().to_string_lossy().to_string();
-        let entry_path_str = entry_path.to_string_lossy().to_string();
-        if entry_path.is_dir() {
-            let sub_ig_path = entry_path.join(".gitignore");
-            let (sub_ig, _) = if sub_ig_path.exists() {
-                Gitignore::new(sub_ig_path)
-            } else {
-                (ig.clone(), None)
-            };
-            match build_tree(&entry_path, &sub_ig) {
-                Ok(children) => {
-                    if !children.is_empty() {
-                        tree.insert(
-                            name,
-                            TreeNode {
-                                node_type: "folder".to_string(),
-                                path: entry_path_str,
-                                children: Some(children),
-                            },
-                        );
-                    }
-                }
-                Err(e) => {
-                    log::warn!("Skipping directory {}: {}", entry_path_str, e);
-                }
-            }
-        } else {
-            tree.insert(
-                name,
-                TreeNode {
-                    node_type: "file".to_string(),
-                    path: entry_path_str,
-                    children: None,
-                },
-            );
+// Helper function to add what a directory scan left out to its response
+fn add_scan_warnings(body: &mut serde_json::Value, scan: &tree::TreeScan) {
+    if !scan.warnings.is_empty() {
+        body["warnings"] = json!(scan.warnings);
+    }
+    if scan.truncated {
+        body["truncated"] = json!(true);
+    }
+}
+
+#[get("/api/workspaces")]
+async fn get_workspaces(stores: Stores, workspaces: web::Data<AliasStoreProvider>, monitor: web::Data<writable::WritabilityMonitor>) -> HttpResponse {
+    // Only the workspaces the caller may open
+    let opened: Vec<_> = workspaces.aliases().keys().filter_map(|name| Some((format!("@{}", name), stores.open_root(&format!("@{}", name)).ok()?))).collect();
+    let listed = web::block(move || {
+        opened
+            .into_iter()
+            .map(|(alias, store)| {
+                let root = store.display_path("");
+                json!({ "alias": alias, "root": root, "writability": monitor.status(store, false) })
+            })
+            .collect::<Vec<_>>()
+    })
+    .await;
+    match listed {
+        Ok(list) => HttpResponse::Ok().json(json!({ "success": true, "workspaces": list })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Listing workspaces failed: {}", e) })),
+    }
+}
+
+#[get("/api/workspace_info")]
+async fn get_workspace_info(
+    query: web::Query<DirectoryQuery>,
+    stores: Stores,
+    limits: web::Data<tree::TreeLimits>,
+    monitor: web::Data<writable::WritabilityMonitor>,
+    checkpoints: web::Data<vcs::Checkpoints>,
+) -> HttpResponse {
+    let requested_path = query.path.clone().unwrap_or_else(|| env::current_dir().unwrap().to_string_lossy().to_string());
+    let store = match stores.open_root(&requested_path) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+    let limits = **limits;
+    let checkpoints = checkpoints.into_inner();
+    let result = web::block(move || -> Result<serde_json::Value, String> {
+        let root = store.display_path("");
+        let vcs = vcs::open(store.clone(), checkpoints);
+        let ig = tree::load_gitignore(&*store, "").unwrap_or_else(Gitignore::empty);
+        let mut scan = tree::TreeScan::new(limits);
+        let tree = tree::build_reported_tree(&*store, "", &ig, &tree::TreeFilter::default(), &mut scan)?;
+        let (file_count, size) = tree::tree_totals(&tree);
+        let summary = tree::summarize_tree(&tree);
+        let mut info = json!({
+            "success": true,
+            "root": root,
+            "vcs": vcs_summary(vcs.status()),
+            "jj": jj::info(std::path::Path::new(&root)),
+            "writability": monitor.status(store, false),
+            "fileCount": file_count,
+            "size": size,
+            "languages": summary.languages,
+            "ignoreFiles": summary.ignore_files
+        });
+        add_scan_warnings(&mut info, &scan);
+        Ok(info)
+    })
+    .await;
+    match result {
+        Ok(Ok(info)) => HttpResponse::Ok().json(info),
+        Ok(Err(e)) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": e })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Workspace info task failed: {}", e) })),
+    }
+}
+
+#[get("/api/packages")]
+async fn get_packages(query: web::Query<DirectoryQuery>, stores: Stores) -> HttpResponse {
+    let requested_path = query.path.clone().unwrap_or_else(|| env::current_dir().unwrap().to_string_lossy().to_string());
+    let store = match stores.open_root(&requested_path) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+    let root = store.display_path("");
+    match web::block(move || packages::find_packages(&*store)).await {
+        Ok(Ok(packages)) => HttpResponse::Ok().json(json!({ "success": true, "root": root, "packages": packages })),
+        Ok(Err(e)) => HttpResponse::UnprocessableEntity().json(json!({ "success": false, "error": e })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Package task failed: {}", e) })),
+    }
+}
+
+/// Opens the folder of the package `name` in the store's workspace, as
+/// /api/packages lists it.
+pub fn open_package(stores: &Stores, store: &dyn FileStore, name: &str) -> Result<Arc<dyn FileStore>, String> {
+    let packages = packages::find_packages(store)?;
+    let package = packages
+        .iter()
+        .find(|p| p.name == name)
+        .ok_or_else(|| format!("No package named {} in {}; /api/packages lists them", name, store.display_path("")))?;
+    stores.open_root(&store.display_path(&package.root))
+}
+
+// Helper function to sum up a workspace's version control status, counting
+// its changes rather than listing them
+fn vcs_summary(status: Result<vcs::Status, String>) -> serde_json::Value {
+    match status {
+        Ok(status) => json!({
+            "kind": status.kind,
+            "root": status.root,
+            "branch": status.branch,
+            "commit": status.commit,
+            "checkpoint": status.checkpoint,
+            "dirty": status.dirty,
+            "changes": status.changes.len()
+        }),
+        Err(e) => json!({ "error": e }),
+    }
+}
+
+#[get("/api/stats")]
+async fn get_stats(query: web::Query<DirectoryQuery>, stores: Stores) -> HttpResponse {
+    let requested_path = query.path.clone().unwrap_or_else(|| env::current_dir().unwrap().to_string_lossy().to_string());
+    let store = match stores.open_root(&requested_path) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+    let root = store.display_path("");
+    let languages = web::block(move || {
+        let ig = tree::load_gitignore(&*store, "").unwrap_or_else(Gitignore::empty);
+        stats::language_stats(&*store, "", &ig)
+    })
+    .await;
+    match languages {
+        Ok(Ok(languages)) => {
+            let total = languages.values().fold(stats::LanguageStats::default(), |mut total, s| {
+                total.files += s.files;
+                total.lines += s.lines;
+                total.bytes += s.bytes;
+                total
+            });
+            HttpResponse::Ok().json(json!({ "success": true, "root": root, "languages": languages, "total": total }))
         }
+        Ok(Err(e)) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": e })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Stats task failed: {}", e) })),
     }
-    Ok(tree)
 }
 
-#[get("/api/directory")]
-async fn get_directory(query: web::Query<DirectoryQuery>) -> HttpResponse {
+#[get("/api/duplicates")]
+async fn get_duplicates(query: web::Query<DirectoryQuery>, stores: Stores) -> HttpResponse {
     let requested_path = query.path.clone().unwrap_or_else(|| env::current_dir().unwrap().to_string_lossy().to_string());
-    let dir_path = match validate_path(&requested_path) {
-        Ok(p) => p,
+    let store = match stores.open_root(&requested_path) {
+        Ok(s) => s,
         Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
     };
+    let task_store = store.clone();
+    let groups = web::block(move || {
+        let ig = tree::load_gitignore(&*task_store, "").unwrap_or_else(Gitignore::empty);
+        duplicates::find_duplicates(&*task_store, "", &ig)
+    })
+    .await;
+    match groups {
+        Ok(Ok(groups)) => {
+            let wasted: u64 = groups.iter().map(|g| g.size * (g.paths.len() as u64 - 1)).sum();
+            let groups: Vec<_> = groups
+                .iter()
+                .map(|g| json!({ "size": g.size, "files": g.paths.iter().map(|p| store.display_path(p)).collect::<Vec<_>>() }))
+                .collect();
+            HttpResponse::Ok().json(json!({ "success": true, "root": store.display_path(""), "groups": groups, "wastedBytes": wasted }))
+        }
+        Ok(Err(e)) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": e })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Duplicate scan failed: {}", e) })),
+    }
+}
 
-    if !dir_path.is_dir() {
-        return HttpResponse::BadRequest().json(json!({ "success": false, "error": "Provided path is not a directory" }));
+// Helper function to parse ?since= as a Unix timestamp or an age relative to now
+fn parse_since(since: &str) -> Result<SystemTime, String> {
+    let since = since.trim();
+    if let Ok(secs) = since.parse::<u64>() {
+        return Ok(UNIX_EPOCH + Duration::from_secs(secs));
     }
+    let invalid = || format!("Invalid since value '{}': expected Unix seconds or an age like 30m, 24h or 7d", since);
+    let split = since.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+    let amount: u64 = since[..split].parse().map_err(|_| invalid())?;
+    let unit = match &since[split..] {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    Ok(SystemTime::now().checked_sub(Duration::from_secs(amount.saturating_mul(unit))).unwrap_or(UNIX_EPOCH))
+}
 
-    let ig_path = dir_path.join(".gitignore");
-    let (ig, _) = if ig_path.exists() {
-        Gitignore::new(ig_path)
-    } else {
-        (Gitignore::empty(), None)
+#[get("/api/recent_files")]
+async fn get_recent_files(query: web::Query<RecentFilesQuery>, stores: Stores) -> HttpResponse {
+    let since = match query.since.as_deref().map(parse_since).transpose() {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e, "field": "since" })),
     };
+    let limit = query.limit.unwrap_or(DEFAULT_RECENT_FILES).min(MAX_RECENT_FILES);
+    let requested_path = query.path.clone().unwrap_or_else(|| env::current_dir().unwrap().to_string_lossy().to_string());
+    let store = match stores.open_root(&requested_path) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+    let task_store = store.clone();
+    let files = web::block(move || {
+        let ig = tree::load_gitignore(&*task_store, "").unwrap_or_else(Gitignore::empty);
+        tree::recent_files(&*task_store, "", &ig, since, limit)
+    })
+    .await;
+    match files {
+        Ok(Ok(files)) => {
+            let files: Vec<_> = files
+                .iter()
+                .map(|(path, modified)| {
+                    let secs = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+                    json!({ "path": store.display_path(path), "modified": secs })
+                })
+                .collect();
+            HttpResponse::Ok().json(json!({ "success": true, "root": store.display_path(""), "files": files }))
+        }
+        Ok(Err(e)) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": e })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Recent files scan failed: {}", e) })),
+    }
+}
 
-    match build_tree(&dir_path, &ig) {
-        Ok(tree) => HttpResponse::Ok().json(json!({ "success": true, "tree": tree, "root": dir_path.to_string_lossy().to_string() })),
-        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": e })),
+// GET /api/raw?path=... returns a file as it is, with its MIME type, byte
+// ranges, ETag and Last-Modified. Files on disk are sent straight from the
+// file, so multi-GB artifacts are never held in memory; archive entries and
+// in-memory repositories are read first.
+#[get("/api/raw")]
+async fn get_raw(req: HttpRequest, query: web::Query<RawQuery>, stores: Stores) -> HttpResponse {
+    let (store, file_path) = match stores.open_file(&query.path) {
+        Ok(f) => f,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+    let name = file_path.rsplit(['/', '!']).next().unwrap_or(&file_path).to_string();
+    let mime = mime_guess::from_path(&file_path).first_or_octet_stream();
+    // Repository files are untrusted: content that runs script in the
+    // browser is always downloaded rather than shown on this origin
+    let inline = !query.download && !is_active_content(&mime);
+    let disposition = header::ContentDisposition {
+        disposition: if inline { header::DispositionType::Inline } else { header::DispositionType::Attachment },
+        parameters: vec![header::DispositionParam::Filename(name)],
+    };
+    if let Some(local) = store.local_path(&file_path) {
+        return match NamedFile::open_async(&local).await {
+            Ok(file) => sandboxed(file.set_content_disposition(disposition).into_response(&req)),
+            Err(e) => HttpResponse::NotFound().json(json!({ "success": false, "error": format!("Failed to open file: {}", e) })),
+        };
     }
+    let task_store = store.clone();
+    let task_path = file_path.clone();
+    match web::block(move || task_store.read(&task_path)).await {
+        Ok(Ok(data)) => memory::hold_body(&req, sandboxed(HttpResponse::Ok().content_type(mime.as_ref()).insert_header(disposition).body(data))),
+        Ok(Err(e)) => HttpResponse::NotFound().json(json!({ "success": false, "error": format!("Failed to read file: {}", e) })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Read task failed: {}", e) })),
+    }
+}
+
+// HTML, SVG, XML and JavaScript can run script when a browser renders them
+fn is_active_content(mime: &mime_guess::Mime) -> bool {
+    use mime_guess::mime::{HTML, JAVASCRIPT, XML};
+    mime.subtype() == HTML || mime.subtype() == XML || mime.suffix() == Some(XML) || mime.subtype() == JAVASCRIPT || mime.subtype() == "ecmascript"
+}
+
+// Stops browsers from sniffing a raw file into something else and from
+// running anything it contains with this origin's cookies
+fn sandboxed(mut response: HttpResponse) -> HttpResponse {
+    let headers = response.headers_mut();
+    headers.insert(header::X_CONTENT_TYPE_OPTIONS, header::HeaderValue::from_static("nosniff"));
+    headers.insert(header::CONTENT_SECURITY_POLICY, header::HeaderValue::from_static("sandbox"));
+    response
 }
 
 #[get("/api/file")]
-async fn get_file(query: web::Query<DirectoryQuery>) -> HttpResponse {
+async fn get_file(
+    req: HttpRequest,
+    query: web::Query<FileQuery>,
+    stores: Stores,
+    watchdog: web::Data<watch::Watchdog>,
+    checkpoints: web::Data<vcs::Checkpoints>,
+) -> HttpResponse {
     let file_path_str = match query.path.as_ref() {
         Some(p) => p,
         None => return HttpResponse::BadRequest().json(json!({ "success": false, "error": "Path parameter is required" })),
     };
-    let file_path = match PathBuf::from(file_path_str).canonicalize() {
-        Ok(p) => p,
-        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid file path '{}': {}", file_path_str, e)})),
+    let fields = match query.fields.as_deref().map(|f| tree::parse_fields(f, &[FILE_FIELDS, &["blame"]].concat())).transpose() {
+        Ok(f) => f,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+    let long_lines = match longlines::LongLines::parse(query.long_lines.as_deref(), query.line_width) {
+        Ok(mode) => mode,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e, "field": "longLines" })),
+    };
+    let (store, file_path) = match stores.open_file(file_path_str) {
+        Ok(f) => f,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+
+    let source_view = match query.notebook.as_deref() {
+        None | Some("raw") => false,
+        Some("source") => notebook::is_notebook(&file_path),
+        Some(other) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Unknown notebook view {}: use raw or source", other), "field": "notebook" })),
+    };
+
+    let task_store = store.clone();
+    let task_path = file_path.clone();
+    let read = web::block(move || read_file_text(&*task_store, &task_path)).await.unwrap_or_else(|e| Err(e.to_string()));
+    match read {
+        Ok((raw, revision, extracted_from)) => {
+            let changed = watch::client_id(&req)
+                .map(|client| watchdog.record_read(&client, std::path::Path::new(&store.display_path(&file_path)), raw.as_bytes()));
+            let language = if extracted_from.is_some() { None } else { stats::detect_language(&file_path, raw.as_bytes()) };
+            let content = if source_view {
+                match notebook::source_view(&raw) {
+                    Ok(view) => view,
+                    Err(e) => return HttpResponse::UnprocessableEntity().json(json!({ "success": false, "error": e, "field": "notebook" })),
+                }
+            } else {
+                raw
+            };
+            let (shown, long_line_count) = longlines::soften(&content, long_lines);
+            let mut body = json!({ "success": true, "content": shown, "changedSinceRead": changed, "revision": revision, "language": language });
+            if long_line_count > 0 {
+                body["longLines"] = json!(long_line_count);
+            }
+            if let Some(kind) = extracted_from {
+                body["extractedFrom"] = json!(kind);
+            }
+            if query.numbered || fields.as_ref().is_some_and(|f| f.iter().any(|f| f == "numberedContent")) {
+                // Numbered before softening, so wrapped parts keep their line's number
+                body["numberedContent"] = json!(longlines::soften(&preview::number_lines(&content), long_lines).0);
+            }
+            // Blame is by line of the file on disk, which a source view or extracted text doesn't keep
+            if (query.blame || fields.as_ref().is_some_and(|f| f.iter().any(|f| f == "blame"))) && !source_view && extracted_from.is_none() {
+                let name = file_path.clone();
+                let blame = web::block(move || vcs::open(store, checkpoints.into_inner()).blame(&name)).await;
+                body["blame"] = match blame {
+                    Ok(Some(Ok(blame))) => json!(blame),
+                    Ok(Some(Err(e))) => {
+                        log::debug!("No blame for {}: {}", file_path, e);
+                        serde_json::Value::Null
+                    }
+                    Ok(None) | Err(_) => serde_json::Value::Null,
+                };
+            }
+            memory::hold_body(&req, HttpResponse::Ok().json(project_fields(body, fields.as_deref())))
+        }
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Failed to read file: {}", e) })),
+    }
+}
+
+#[get("/api/preview")]
+async fn get_preview(query: web::Query<PreviewQuery>, stores: Stores) -> HttpResponse {
+    let (store, file_path) = match stores.open_file(&query.path) {
+        Ok(f) => f,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+    let task_store = store.clone();
+    let task_path = file_path.clone();
+    match web::block(move || read_text(&*task_store, &task_path)).await.unwrap_or_else(|e| Err(e.to_string())) {
+        Ok(content) => {
+            let context = query.context.unwrap_or(DEFAULT_PREVIEW_CONTEXT).min(MAX_PREVIEW_CONTEXT);
+            let snippet = preview::snippet(&content, query.line, context);
+            HttpResponse::Ok().json(json!({ "success": true, "path": store.display_path(&file_path), "snippet": snippet }))
+        }
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Failed to read file: {}", e) })),
+    }
+}
+
+#[get("/api/deps")]
+async fn get_deps(query: web::Query<DepsQuery>, stores: Stores) -> HttpResponse {
+    let store = match stores.open_root(&query.path) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+    let query = query.into_inner();
+    for path in query.dir.iter().chain(&query.file) {
+        if let Err(e) = store.validate(path) {
+            return HttpResponse::BadRequest().json(json!({ "success": false, "error": e }));
+        }
+    }
+    let root = store.display_path("");
+    let task_store = store.clone();
+    let dir = query.dir.clone().unwrap_or_default();
+    let result = web::block(move || {
+        let ig = tree::load_gitignore(&*task_store, "").unwrap_or_else(Gitignore::empty);
+        deps::dependency_graph(&*task_store, &ig, &dir)
+    })
+    .await;
+    let graph = match result {
+        Ok(Ok(graph)) => graph,
+        Ok(Err(e)) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": e })),
+        Err(e) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Dependency task failed: {}", e) })),
+    };
+    match query.file {
+        Some(file) => match graph.files.get(&file) {
+            Some(deps) => HttpResponse::Ok().json(json!({
+                "success": true,
+                "root": root,
+                "file": file,
+                "imports": deps.imports,
+                "external": deps.external,
+                "importedBy": graph.dependents(&file)
+            })),
+            None => HttpResponse::NotFound().json(json!({ "success": false, "error": format!("No dependencies known for {}: not found, ignored or not a supported language", file) })),
+        },
+        None => HttpResponse::Ok().json(json!({ "success": true, "root": root, "files": graph.files, "edgeCount": graph.edge_count() })),
+    }
+}
+
+#[get("/api/related")]
+async fn get_related(query: web::Query<RelatedQuery>, stores: Stores, checkpoints: web::Data<vcs::Checkpoints>) -> HttpResponse {
+    let (file_store, name) = match stores.open_file(&query.path) {
+        Ok(f) => f,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+    let file_path = file_store.display_path(&name);
+    let root = query.root.clone().unwrap_or_else(|| {
+        let dir = file_store.display_path("");
+        vcs::repository_root(std::path::Path::new(&dir)).map(|root| root.to_string_lossy().into_owned()).unwrap_or(dir)
+    });
+    let store = match stores.open_root(&root) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid root: {}", e) })),
     };
+    let root = store.display_path("");
+    let Some(target) = file_path.strip_prefix(&root).map(|p| p.trim_start_matches('/').to_string()).filter(|p| !p.is_empty()) else {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("{} is not inside {}", file_path, root) }));
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_RELATED_LIMIT).min(MAX_RELATED_LIMIT);
+    let task_store = store.clone();
+    let task_target = target.clone();
+    let result = web::block(move || {
+        let history = vcs::open(task_store.clone(), checkpoints.into_inner()).co_changes(&task_target);
+        let ig = tree::load_gitignore(&*task_store, "").unwrap_or_else(Gitignore::empty);
+        related::related_files(&*task_store, &ig, &task_target, history.as_ref(), limit).map(|files| (files, history.is_some()))
+    })
+    .await;
+    match result {
+        Ok(Ok((files, history))) => HttpResponse::Ok().json(json!({ "success": true, "root": root, "file": target, "related": files, "history": history })),
+        Ok(Err(e)) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": e })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Related files task failed: {}", e) })),
+    }
+}
+
+#[post("/api/plan_context")]
+async fn plan_context(body: ValidJson<PlanContextRequest>, stores: Stores, checkpoints: web::Data<vcs::Checkpoints>) -> HttpResponse {
+    let store = match stores.open_root(&body.directory_path) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid directory path: {}", e) })),
+    };
+    let question = body.question.clone().filter(|q| !q.trim().is_empty());
+    if body.files.is_empty() && question.is_none() {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": "Give files, a question or both" }));
+    }
+    if body.budget == 0 {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": "budget must be at least 1" }));
+    }
+    for path in &body.files {
+        if let Err(e) = store.validate(path) {
+            return HttpResponse::BadRequest().json(json!({ "success": false, "error": e }));
+        }
+    }
+    let task_store = store.clone();
+    let body = body.into_inner();
+    let result = web::block(move || {
+        let ig = tree::load_gitignore(&*task_store, "").unwrap_or_else(Gitignore::empty);
+        let vcs = vcs::open(task_store.clone(), checkpoints.into_inner());
+        let history = |path: &str| vcs.co_changes(path);
+        context::plan_context(&*task_store, &ig, &body.files, question.as_deref(), &history, body.budget, body.include_content)
+    })
+    .await;
+    match result {
+        Ok(Ok(plan)) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "root": store.display_path(""),
+            "budget": plan.budget,
+            "used": plan.used,
+            "selections": plan.selections,
+            "skipped": plan.skipped
+        })),
+        Ok(Err(e)) => HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Planning task failed: {}", e) })),
+    }
+}
+
+#[post("/api/impact")]
+async fn get_impact(body: ValidJson<ImpactRequest>, stores: Stores, rewrites: web::Data<PathRewrites>) -> HttpResponse {
+    let store = match stores.open_root(&body.directory_path) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid directory path: {}", e) })),
+    };
+    let mut patch_set = PatchSet::parse(body.patch_content.trim(), 1);
+    if patch_set.files.is_empty() {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": "No file changes found in the patch." }));
+    }
+    rewrites.apply(&mut patch_set);
+    let mut changed: Vec<String> = Vec::new();
+    for path in patch_set.files.iter().flat_map(|f| f.old_path.iter().chain(f.new_path.iter())) {
+        if let Err(e) = store.validate(path) {
+            return HttpResponse::BadRequest().json(json!({ "success": false, "error": e }));
+        }
+        if !changed.contains(path) {
+            changed.push(path.clone());
+        }
+    }
+    let max_depth = body.max_depth.unwrap_or(impact::DEFAULT_MAX_DEPTH);
+    let root = store.display_path("");
+    let result = web::block(move || -> Result<(impact::Impact, Option<String>), String> {
+        let ig = tree::load_gitignore(&*store, "").unwrap_or_else(Gitignore::empty);
+        let graph = deps::dependency_graph(&*store, &ig, "")?;
+        // A broken manifest still leaves the files and tests to report
+        let (packages, warning) = match packages::find_packages(&*store) {
+            Ok(packages) => (packages, None),
+            Err(e) => (Vec::new(), Some(e)),
+        };
+        Ok((impact::impact(&graph, &changed, &packages, max_depth), warning))
+    })
+    .await;
+    match result {
+        Ok(Ok((impact, warning))) => {
+            let mut response = json!(impact);
+            response["success"] = json!(true);
+            response["root"] = json!(root);
+            if let Some(warning) = warning {
+                response["warnings"] = json!([warning]);
+            }
+            HttpResponse::Ok().json(response)
+        }
+        Ok(Err(e)) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": e })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Impact task failed: {}", e) })),
+    }
+}
+
+#[derive(Deserialize)]
+struct DiagnosticsQuery {
+    path: String,
+    /// Check this monorepo package, as /api/packages names it.
+    package: Option<String>,
+    /// Only run the checker with this name.
+    checker: Option<String>,
+}
+
+/// Most diagnostics one response lists.
+const MAX_DIAGNOSTICS: usize = 1000;
+
+#[get("/api/diagnostics")]
+async fn get_diagnostics(
+    query: web::Query<DiagnosticsQuery>,
+    stores: Stores,
+    checkers: web::Data<diagnostics::Checkers>,
+    session: Option<web::ReqData<Session>>,
+    test_runs: Option<web::Data<testrun::TestRunConfig>>,
+) -> HttpResponse {
+    let Some(test_runs) = test_runs else {
+        return HttpResponse::NotFound().json(json!({ "success": false, "error": "Diagnostics are disabled; set TEST_RUNS to enable them" }));
+    };
+    if let Err(response) = test_runs.check(session.as_deref()) {
+        return response;
+    }
+    let (store, dir) = match testrun::test_dir(&stores, &query.path, query.package.as_deref()) {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    let selected: Vec<diagnostics::CheckerConfig> = checkers
+        .for_dir(|file| store.exists(file))
+        .into_iter()
+        .filter(|c| query.checker.as_ref().is_none_or(|name| c.name == *name))
+        .cloned()
+        .collect();
+    if selected.is_empty() {
+        let error = match &query.checker {
+            Some(name) => format!("No checker named {} applies to {}", name, dir.display()),
+            None => format!("No checker applies to {}", dir.display()),
+        };
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": error }));
+    }
+    let timeout = test_runs.timeout();
+    let result = web::block(move || {
+        let mut found = Vec::new();
+        let mut runs = Vec::new();
+        for checker in &selected {
+            let started = std::time::Instant::now();
+            let run = testrun::run(&checker.command, &dir, timeout).and_then(|finished| {
+                // tsc and most linters print to stdout, gcc and clang to stderr
+                let output = format!("{}\n{}", finished.stdout, finished.stderr);
+                diagnostics::parse(&checker.format, &output).map(|parsed| (finished.status, parsed))
+            });
+            let mut summary = json!({ "name": checker.name, "command": checker.command, "durationMs": started.elapsed().as_millis() as u64 });
+            match run {
+                Ok((status, parsed)) => {
+                    summary["exitCode"] = json!(status.code());
+                    summary["success"] = json!(status.success());
+                    summary["count"] = json!(parsed.len());
+                    for mut diagnostic in parsed {
+                        diagnostic.file = diagnostic.file.map(|file| checked_path(&dir, &file));
+                        found.push((checker.name.clone(), diagnostic));
+                    }
+                }
+                Err(e) => {
+                    summary["success"] = json!(false);
+                    summary["error"] = json!(e);
+                }
+            }
+            runs.push(summary);
+        }
+        (dir, runs, found)
+    })
+    .await;
+    match result {
+        Ok((dir, runs, found)) => {
+            let count = |severity: &str| found.iter().filter(|(_, d)| d.severity == severity).count();
+            let (errors, warnings) = (count("error"), count("warning"));
+            let truncated = found.len() > MAX_DIAGNOSTICS;
+            let listed: Vec<serde_json::Value> = found
+                .into_iter()
+                .take(MAX_DIAGNOSTICS)
+                .map(|(checker, diagnostic)| {
+                    let mut entry = json!(diagnostic);
+                    entry["checker"] = json!(checker);
+                    entry
+                })
+                .collect();
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "root": dir.to_string_lossy(),
+                "checkers": runs,
+                "diagnostics": listed,
+                "errors": errors,
+                "warnings": warnings,
+                "truncated": truncated,
+            }))
+        }
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Diagnostics task failed: {}", e) })),
+    }
+}
+
+// Helper function to make a path a checker printed relative to the checked
+// folder: absolute paths inside it, and paths cargo gives relative to the
+// workspace root above it
+fn checked_path(dir: &std::path::Path, file: &str) -> String {
+    let path = std::path::Path::new(file);
+    let candidates = if path.is_absolute() { vec![path.to_path_buf()] } else { dir.ancestors().map(|a| a.join(path)).collect() };
+    candidates
+        .iter()
+        .find(|c| c.exists() && c.starts_with(dir))
+        .and_then(|c| c.strip_prefix(dir).ok())
+        .map_or_else(|| file.to_string(), |p| p.to_string_lossy().into_owned())
+}
+
+#[get("/api/extract")]
+async fn get_extract(query: web::Query<ExtractQuery>, stores: Stores) -> HttpResponse {
+    let (store, file_path) = match stores.open_file(&query.path) {
+        Ok(f) => f,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+    let task_store = store.clone();
+    let task_path = file_path.clone();
+    let content = match web::block(move || read_text(&*task_store, &task_path)).await.unwrap_or_else(|e| Err(e.to_string())) {
+        Ok(c) => c,
+        Err(e) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Failed to read file: {}", e) })),
+    };
+    let context = query.context.unwrap_or(0).min(MAX_PREVIEW_CONTEXT);
+    match extract::extract(&file_path, &content, &query.symbol, context) {
+        Ok(matches) if matches.is_empty() => HttpResponse::NotFound().json(json!({
+            "success": false,
+            "error": format!("No definition of {} in {}", query.symbol, store.display_path(&file_path))
+        })),
+        Ok(matches) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "path": store.display_path(&file_path),
+            "language": stats::detect_language(&file_path, content.as_bytes()),
+            "revision": patch::revision(content.as_bytes()),
+            "matches": matches
+        })),
+        Err(e) => HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    }
+}
+
+// Helper function to answer a patch whose blocking task panicked or was cancelled
+fn patch_task_failed(e: actix_web::error::BlockingError) -> HttpResponse {
+    HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Patch task failed: {}", e), "appliedFiles": [], "details": [] }))
+}
+
+// Helper function to read a file from a store as UTF-8 text
+fn read_text(store: &dyn FileStore, path: &str) -> Result<String, String> {
+    let data = store.read(path).map_err(|e| e.to_string())?;
+    String::from_utf8(data).map_err(|e| match images::image_info(e.as_bytes()) {
+        Some(image) => format!("{} is a {}x{} {} image; previews come from /api/thumbnail", path, image.width, image.height, image.format.to_uppercase()),
+        None => "stream did not contain valid UTF-8".to_string(),
+    })
+}
+
+// Helper function to read a file as text with the revision of its content,
+// and for PDF and Word files their extracted text and kind
+fn read_file_text(store: &dyn FileStore, path: &str) -> Result<(String, String, Option<&'static str>), String> {
+    let Some(kind) = documents::document_kind(path) else {
+        let text = read_text(store, path)?;
+        let revision = patch::revision(text.as_bytes());
+        return Ok((text, revision, None));
+    };
+    if let Some(size) = store.file_size(path).filter(|&s| s > documents::MAX_DOCUMENT_BYTES) {
+        return Err(format!("{} is {} MiB, over the {} MiB documents are read up to", path, size / 1024 / 1024, documents::MAX_DOCUMENT_BYTES / 1024 / 1024));
+    }
+    let data = store.read(path).map_err(|e| e.to_string())?;
+    let text = documents::extract_text(path, &data).unwrap_or_else(|| Err(format!("No text extraction for {}", path)))?;
+    Ok((text, patch::revision(&data), Some(kind)))
+}
+
+#[derive(Deserialize)]
+struct TablePreviewQuery {
+    path: String,
+    rows: Option<usize>,
+}
 
-    if !file_path.is_file() {
-        return HttpResponse::BadRequest().json(json!({ "success": false, "error": "Path is not a file" }));
+// GET /api/table_preview?path=...&rows=100 returns the columns and first
+// rows of a CSV, TSV or Parquet file
+#[get("/api/table_preview")]
+async fn get_table_preview(query: web::Query<TablePreviewQuery>, stores: Stores) -> HttpResponse {
+    let rows = query.rows.unwrap_or(table::DEFAULT_ROWS);
+    if rows > table::MAX_ROWS {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": format!("At most {} rows can be previewed", table::MAX_ROWS),
+            "field": "rows"
+        }));
     }
-
-    match fs::read_to_string(&file_path) {
-        Ok(content) => HttpResponse::Ok().json(json!({ "success": true, "content": content })),
-        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Failed to read file: {}", e) })),
+    let (store, file_path) = match stores.open_file(&query.path) {
+        Ok(f) => f,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+    if table::table_format(&file_path).is_none() {
+        return HttpResponse::UnsupportedMediaType().json(json!({ "success": false, "error": format!("{} isn't a CSV, TSV or Parquet file", query.path) }));
+    }
+    if let Some(size) = store.file_size(&file_path).filter(|&s| s > table::MAX_TABLE_BYTES) {
+        return HttpResponse::PayloadTooLarge().json(json!({
+            "success": false,
+            "error": format!("{} is {} MiB, over the {} MiB tables are read up to", query.path, size / 1024 / 1024, table::MAX_TABLE_BYTES / 1024 / 1024)
+        }));
+    }
+    let result = web::block(move || store.read(&file_path).map(|data| table::preview(&file_path, &data, rows))).await;
+    match result {
+        Ok(Ok(Ok(preview))) => {
+            let mut response = json!(preview);
+            response["success"] = json!(true);
+            response["path"] = json!(query.path);
+            HttpResponse::Ok().json(response)
+        }
+        Ok(Ok(Err(e))) => HttpResponse::UnprocessableEntity().json(json!({ "success": false, "error": e })),
+        Ok(Err(e)) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Failed to read file: {}", e) })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Table preview task failed: {}", e) })),
     }
 }
 
 #[post("/api/files")]
-async fn get_files_batch(body: web::Json<FilesRequest>) -> HttpResponse {
+async fn get_files_batch(
+    req: HttpRequest,
+    body: ValidJson<FilesRequest>,
+    stores: Stores,
+    runtime: web::Data<admin::Runtime>,
+    watchdog: web::Data<watch::Watchdog>,
+    profiles: web::Data<profiles::ProfileStore>,
+) -> HttpResponse {
+    let _job = admin::Runtime::start_job(&runtime);
+    let fields = match body.fields.as_ref().map(|f| tree::parse_fields(&f.join(","), FILE_FIELDS)).transpose() {
+        Ok(f) => f,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
     let paths = body.paths.clone();
+    let numbered = body.numbered || fields.as_ref().is_some_and(|f| f.iter().any(|f| f == "numberedContent"));
+    let profile = body.profile.clone();
     if paths.is_empty() {
         return HttpResponse::BadRequest().json(json!({ "success": false, "error": "Paths array is required and cannot be empty" }));
     }
 
     let concurrency_limit = 50;
     let mut results = HashMap::new();
+    let client = watch::client_id(&req);
     let mut stream = stream::iter(paths).map(|path| {
+        let stores = stores.clone();
+        let watchdog = watchdog.clone();
+        let client = client.clone();
+        let profiles = profiles.clone();
+        let profile = profile.clone();
         async move {
-            let validated_path = match PathBuf::from(&path).canonicalize() {
-                Ok(p) => p,
-                Err(e) => return (path, FileResult { success: false, content: None, error: Some(format!("Invalid path: {}", e)) }),
+            let (store, file_path) = match stores.open_file(&path) {
+                Ok(f) => f,
+                Err(e) => return (path, FileResult { success: false, content: None, error: Some(e), changed_since_read: None, revision: None, language: None, numbered_content: None }),
             };
-
-            if !validated_path.is_file() {
-                return (path, FileResult { success: false, content: None, error: Some("Path is not a file".to_string()) });
+            if let Some(name) = &profile {
+                let error = match profiles.allows_file(&store.display_path(&file_path), name) {
+                    Ok(true) => None,
+                    Ok(_) => Some(format!("Excluded by profile '{}'", name)),
+                    Err(e) => Some(e),
+                };
+                if let Some(e) = error {
+                    return (path, FileResult { success: false, content: None, error: Some(e), changed_since_read: None, revision: None, language: None, numbered_content: None });
+                }
             }
 
-            match tokio_fs::read_to_string(&validated_path).await {
-                Ok(content) => (path.clone(), FileResult { success: true, content: Some(content), error: None }),
-                Err(e) => (path.clone(), FileResult { success: false, content: None, error: Some(format!("Failed to read file: {}", e)) }),
+            let full_path = store.display_path(&file_path);
+            match web::block(move || read_text(&*store, &file_path)).await {
+                Ok(Ok(content)) => {
+                    let changed = client.map(|client| watchdog.record_read(&client, std::path::Path::new(&full_path), content.as_bytes()));
+                    let revision = Some(patch::revision(content.as_bytes()));
+                    let language = stats::detect_language(&full_path, content.as_bytes());
+                    let numbered_content = numbered.then(|| preview::number_lines(&content));
+                    (path, FileResult { success: true, content: Some(content), error: None, changed_since_read: changed, revision, language, numbered_content })
+                }
+                Ok(Err(e)) => (path, FileResult { success: false, content: None, error: Some(format!("Failed to read file: {}", e)), changed_since_read: None, revision: None, language: None, numbered_content: None }),
+                Err(e) => (path, FileResult { success: false, content: None, error: Some(format!("Failed to read file: {}", e)), changed_since_read: None, revision: None, language: None, numbered_content: None }),
             }
         }
     }).buffer_unordered(concurrency_limit);
 
     while let Some((path, result)) = stream.next().await {
-        results.insert(path, result);
+        results.insert(path, project_fields(json!(result), fields.as_deref()));
     }
 
-    HttpResponse::Ok().json(json!({ "success": true, "files": results }))
+    memory::hold_body(&req, HttpResponse::Ok().json(json!({ "success": true, "files": results })))
 }
 
 #[post("/api/check_writable")]
-async fn check_writable(body: web::Json<CheckWritableRequest>) -> HttpResponse {
-    let base_dir = match validate_path(&body.directory_path) {
-        Ok(p) => p,
+async fn check_writable(body: ValidJson<CheckWritableRequest>, stores: Stores, monitor: web::Data<writable::WritabilityMonitor>) -> HttpResponse {
+    let store = match stores.open_root(&body.directory_path) {
+        Ok(s) => s,
         Err(e) => return HttpResponse::BadRequest().json(json!({
             "success": false,
             "writable": false,
             "error": format!("Invalid directory path: {}", e)
         })),
     };
-
-    if !base_dir.is_dir() {
-        return HttpResponse::BadRequest().json(json!({
-            "success": false,
-            "writable": false,
-            "error": "Provided path is not a directory".to_string()
-        }));
-    }
-
-    let test_file_name = format!(".repopatch_writetest_{}", chrono::Utc::now().timestamp_nanos_opt().unwrap_or(0));
-    let test_file_path = base_dir.join(&test_file_name);
-
-    log::debug!("Attempting writability check in {:?} with file {:?}", base_dir, test_file_path);
-
-    match OpenOptions::new().write(true).create_new(true).open(&test_file_path) {
-        Ok(_) => {
-            log::debug!("Writability test file created successfully: {:?}", test_file_path);
-            match fs::remove_file(&test_file_path) {
-                Ok(_) => {
-                    log::debug!("Writability test file deleted successfully: {:?}", test_file_path);
-                    HttpResponse::Ok().json(json!({ "success": true, "writable": true }))
-                }
-                Err(e) => {
-                    log::warn!("Failed to delete writability test file {:?}: {}", test_file_path, e);
-                    HttpResponse::Ok().json(json!({
-                        "success": true,
-                        "writable": false,
-                        "error": format!("Failed to delete temporary test file: {}", e)
-                    }))
-                }
-            }
-        }
-        Err(e) => {
-            log::info!("Failed to create writability test file {:?}: {}", test_file_path, e);
-            HttpResponse::Ok().json(json!({
-                "success": true,
-                "writable": false,
-                "error": format!("Failed to create temporary test file (check permissions): {}", e)
-            }))
-        }
-    }
-}
-
-// Helper function to split patch content into per-file patches
-fn split_patch_content(patch_content: &str) -> Vec<(String, String, String)> {
-    let lines: Vec<&str> = patch_content.lines().map(|l| l.trim_end()).collect();
-    let mut patches = Vec::new();
-    let mut current_old_path = None;
-    let mut current_new_path = None;
-    let mut current_patch_lines = Vec::new();
-
-    for line in lines {
-        if line.starts_with("--- ") {
-            // Store previous patch if it exists and is valid
-            if let (Some(old_path), Some(new_path)) = (current_old_path.take(), current_new_path.take()) {
-                if !current_patch_lines.is_empty() {
-                    let patch_text = current_patch_lines.join("\n");
-                    log::debug!("Collected patch for old_path: {}, new_path: {}, lines: {}", old_path, new_path, current_patch_lines.len());
-                    patches.push((old_path, new_path, patch_text));
-                } else {
-                    log::warn!("Skipping empty patch for old_path: {}", old_path);
-                }
+    let recheck = body.recheck;
+    match web::block(move || monitor.status(store, recheck)).await {
+        Ok(status) => {
+            let mut response = json!({ "success": true, "writable": status.writable, "checkedAt": status.checked_at });
+            if let Some(error) = status.error {
+                response["error"] = json!(error);
             }
-            current_old_path = Some(line[4..].trim().to_string());
-            current_new_path = None;
-            current_patch_lines = vec![line.to_string()];
-        } else if line.starts_with("+++ ") {
-            if current_old_path.is_none() {
-                log::warn!("Found +++ line without preceding --- line: {}", line);
-                current_patch_lines.clear(); // Reset to avoid malformed patch
-                continue;
-            }
-            current_new_path = Some(line[4..].trim().to_string());
-            current_patch_lines.push(line.to_string());
-        } else if !line.is_empty() || !current_patch_lines.is_empty() {
-            // Include non-empty lines or empty lines after content has started
-            current_patch_lines.push(line.to_string());
-        }
-    }
-
-    // Store the final patch if valid
-    if let (Some(old_path), Some(new_path)) = (current_old_path, current_new_path) {
-        if !current_patch_lines.is_empty() {
-            let patch_text = current_patch_lines.join("\n");
-            log::debug!("Collected final patch for old_path: {}, new_path: {}, lines: {}", old_path, new_path, current_patch_lines.len());
-            patches.push((old_path, new_path, patch_text));
-        } else {
-            log::warn!("Skipping empty final patch for old_path: {}", old_path);
+            HttpResponse::Ok().json(response)
         }
-    }
-
-    patches
-}
-
-// Helper function to strip path components (e.g., to match -p1 behavior)
-fn strip_path(path: &str, strip_level: usize) -> String {
-    let parts: Vec<&str> = path.split('/').collect();
-    if parts.len() > strip_level {
-        parts[strip_level..].join("/")
-    } else {
-        path.to_string()
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "writable": false, "error": format!("Writability check failed: {}", e) })),
     }
 }
 
+// Actix handlers take their dependencies as extractors
+#[allow(clippy::too_many_arguments)]
 #[post("/api/apply_patch")]
-async fn apply_patch(body: web::Json<ApplyPatchRequest>) -> HttpResponse {
-    let base_dir = match validate_path(&body.directory_path) {
-        Ok(p) => p,
+async fn apply_patch(
+    body: ValidJson<ApplyPatchRequest>,
+    stores: Stores,
+    runtime: web::Data<admin::Runtime>,
+    formatters: web::Data<format::Formatters>,
+    policy: web::Data<Policy>,
+    rewrites: web::Data<PathRewrites>,
+    approvals: web::Data<approvals::Approvals>,
+    history: web::Data<history::History>,
+    reviews: web::Data<reviews::Reviews>,
+    presence: web::Data<presence::Presence>,
+    uploads: web::Data<upload::Uploads>,
+    chats: web::Data<chats::ChatStore>,
+    activity: web::Data<activity::Activity>,
+    usage: web::Data<usage::Usage>,
+    engines: web::Data<EngineSelection>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let _job = admin::Runtime::start_job(&runtime);
+    let store = match stores.open_root(&body.directory_path) {
+        Ok(s) => s,
         Err(e) => return HttpResponse::BadRequest().json(json!({ 
             "success": false, 
             "error": format!("Invalid directory path: {}", e),
@@ -352,16 +1434,19 @@ async fn apply_patch(body: web::Json<ApplyPatchRequest>) -> HttpResponse {
         })),
     };
 
-    if !base_dir.is_dir() {
-        return HttpResponse::BadRequest().json(json!({ 
-            "success": false, 
-            "error": "Provided path is not a directory".to_string(),
-            "appliedFiles": [],
-            "details": []
-        }));
-    }
-
-    let patch_content = body.patch_content.trim();
+    let uploaded = match body.upload_id.as_deref().map(|id| uploads.get(id)).transpose() {
+        Ok(uploaded) => uploaded,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e, "appliedFiles": [], "details": [] })),
+    };
+    // Uploaded patches are counted while they are uploaded
+    let queued = if uploaded.is_some() { 0 } else { body.patch_content.len() as u64 };
+    // Read from the app data: actix handlers take at most 16 extractors
+    let budget = req.app_data::<web::Data<memory::MemoryBudget>>().map(|budget| budget.clone().into_inner());
+    let _queued = match budget.map(|budget| budget.reserve(memory::Pool::Patches, queued)).transpose() {
+        Ok(reservation) => reservation,
+        Err(overloaded) => return overloaded.response(),
+    };
+    let patch_content = uploaded.as_deref().unwrap_or(&body.patch_content).trim();
     if patch_content.is_empty() {
         return HttpResponse::BadRequest().json(json!({ 
             "success": false, 
@@ -371,177 +1456,671 @@ async fn apply_patch(body: web::Json<ApplyPatchRequest>) -> HttpResponse {
         }));
     }
 
-    // Initialize diff-match-patch
-    let dmp = DiffMatchPatch::new();
+    let chat_owner = req.extensions().get::<Session>().map_or_else(|| settings::SHARED_USER.to_string(), |s| s.user.clone());
+    if let Some(chat_id) = &body.chat_id {
+        if let Err(e) = chats.check(&chat_owner, chat_id, body.message_id.as_deref()) {
+            return HttpResponse::NotFound().json(json!({ "success": false, "error": e, "appliedFiles": [], "details": [] }));
+        }
+    }
+
+    let detected = match body.patch_format.as_deref() {
+        None | Some("unified") => None,
+        Some("auto") if body.engine.is_some() => {
+            return HttpResponse::BadRequest().json(json!({ "success": false, "error": "Send either engine or patchFormat \"auto\", not both", "appliedFiles": [], "details": [] }));
+        }
+        Some("auto") => match engine::detect_format(patch_content) {
+            Some(format) => Some(format),
+            None => return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": "Could not tell the patch format: it has no unified diff hunks or SEARCH/REPLACE blocks",
+                "appliedFiles": [],
+                "details": []
+            })),
+        },
+        Some(other) => {
+            return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Unknown patchFormat '{}'; expected auto or unified", other), "appliedFiles": [], "details": [] }));
+        }
+    };
+    let requested = detected.and_then(engine::PatchFormat::engine).or(body.engine.as_deref());
+    let engine = match engines.select(requested, &store.display_path("")) {
+        Ok(engine) => engine,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e, "appliedFiles": [], "details": [] })),
+    };
+
+    let context_lines = body.context_lines.unwrap_or(patch::DEFAULT_CONTEXT_LINES);
 
     // Log patch application attempt
-    log::info!("Applying patch to directory: {:?}", base_dir);
+    log::info!("Applying patch to directory: {}", store.display_path(""));
     log::debug!("Patch content length: {} bytes", patch_content.len());
 
-    // Split patch content into per-file patches
-    let file_patches = split_patch_content(patch_content);
-    let mut applied_files = Vec::new();
-    let mut details = Vec::new();
-
-    for (old_path, new_path, patch_text) in file_patches {
-        // Strip paths to match -p1 behavior
-        let stripped_old_path = if old_path != "/dev/null" {
-            strip_path(&old_path, 1)
-        } else {
-            "/dev/null".to_string()
-        };
-        let stripped_new_path = if new_path != "/dev/null" {
-            strip_path(&new_path, 1)
+    let options = ApplyOptions { trace: body.debug.unwrap_or(false), ..apply_options(context_lines) };
+    let mut patch_set = engine.parse(patch_content, 1);
+    let mut extra = json!({ "engine": engine.name() });
+    if let Some(format) = detected {
+        if patch_set.files.is_empty() {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": format!("The patch looks like {} but no file changes could be read from it", format.name()),
+                "appliedFiles": [],
+                "details": []
+            }));
+        }
+        extra["patchFormat"] = json!(format.name());
+    }
+    // Denying rules block here; rules asking for a dry run or a confirmation
+    // are settled with the approvals below
+    let checked = cli::precheck(&*store, &mut patch_set, &rewrites, &policy, &[Action::Deny]);
+    if !checked.findings.is_empty() {
+        let blocked = checked.rejection == Some(cli::Rejection::Secrets);
+        let mut files: Vec<String> = checked.findings.iter().map(|f| f.file.clone()).collect();
+        files.dedup();
+        let summary = if blocked {
+            format!("Blocked a patch with {} possible secrets", checked.findings.len())
         } else {
-            "/dev/null".to_string()
+            format!("Found {} possible secrets in a patch", checked.findings.len())
         };
+        let event = activity::Event::new("scan", "secret_scan", summary).files(files).failed(blocked);
+        activity.record(&store.display_path(""), req.extensions().get::<Session>(), event);
+    }
+    let status = match checked.rejection {
+        None => None,
+        Some(cli::Rejection::Empty) => Some(StatusCode::BAD_REQUEST),
+        Some(cli::Rejection::Secrets) => Some(StatusCode::UNPROCESSABLE_ENTITY),
+        Some(cli::Rejection::Policy) => Some(StatusCode::FORBIDDEN),
+    };
+    if let Some(status) = status {
+        return HttpResponse::build(status).json(checked.report);
+    }
+    let violations = checked.violations;
+    if let serde_json::Value::Object(fields) = checked.report {
+        extra.as_object_mut().expect("extra is an object").extend(fields);
+    }
 
-        // Determine the target file path
-        let file_path = if stripped_old_path != "/dev/null" {
-            stripped_old_path.clone()
-        } else {
-            stripped_new_path.clone()
+    let fingerprint = approvals::fingerprint(&store.display_path(""), patch_content);
+    let dry_run = body.dry_run.unwrap_or(false);
+    let simulated = body.virtual_files.is_some();
+    if let Err(response) = check_policy(&patch_set, &violations, &approvals, fingerprint, dry_run || simulated, body.confirmation_token.as_deref()) {
+        return response;
+    }
+    let client = watch::client_id(&req);
+    let touched: Vec<String> = patch_set.files.iter().map(|f| f.path().to_string()).collect();
+    let collaborators = presence.others_on(&store.display_path(""), client.as_deref(), &touched);
+    if !collaborators.is_empty() {
+        extra["collaborators"] = json!(collaborators);
+    }
+    if let Some(virtual_files) = &body.virtual_files {
+        if let Some((path, e)) = virtual_files.keys().find_map(|p| store.validate(p).err().map(|e| (p, e))) {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": format!("Invalid virtual file {}: {}", path, e),
+                "appliedFiles": [],
+                "details": []
+            }));
+        }
+        let virtual_files = virtual_files.clone();
+        let task_store = store.clone();
+        let simulated = web::block(move || {
+            let overlay = OverlayStore::with_files(&*task_store, virtual_files.into_iter().map(|(p, c)| (p, c.into_bytes())));
+            let outcome = engine.apply(&patch_set, &overlay, &options);
+            // Only files the patch touched, so clients can chain the next patch on top
+            let files: serde_json::Map<String, serde_json::Value> = overlay
+                .changes()
+                .into_iter()
+                .filter(|(path, _)| outcome.applied_files.contains(path) || patch_set.files.iter().any(|f| f.path() == path))
+                .map(|(path, data)| (path, json!(data.map(|d| String::from_utf8_lossy(&d).into_owned()))))
+                .collect();
+            (outcome, files)
+        })
+        .await;
+        let (outcome, files) = match simulated {
+            Ok(simulated) => simulated,
+            Err(e) => return patch_task_failed(e),
         };
-        let full_path = base_dir.join(&file_path);
-
-        log::debug!("Processing patch for file: {}", file_path);
-
-        if stripped_old_path == "/dev/null" {
-            // New file creation
-            match dmp.patch_from_text::<Compat>(&patch_text) {
-                Ok(patches) => {
-                    match dmp.patch_apply(&patches, "") {
-                        Ok((new_content, applied)) => {
-                            if applied.iter().all(|&b| b) {
-                                if let Some(parent) = full_path.parent() {
-                                    if let Err(e) = fs::create_dir_all(parent) {
-                                        details.push(format!("Failed to create directory for {}: {}", file_path, e));
-                                        continue;
-                                    }
-                                }
-                                if let Err(e) = fs::write(&full_path, &new_content) {
-                                    details.push(format!("Failed to write new file {}: {}", file_path, e));
-                                } else {
-                                    applied_files.push(file_path.clone());
-                                    log::info!("Created new file: {}", file_path);
-                                }
-                                log::debug!("Finished applying patch for new file {}", file_path);
-                            } else {
-                                details.push(format!("Failed to apply patch for new file {}: partial application", file_path));
-                            }
-                        }
-                        Err(e) => {
-                            details.push(format!("Error applying patch for new file {}: {:?}", file_path, e));
-                        }
-                    }
-                }
-                Err(e) => {
-                    let patch_snippet = if patch_text.len() > 100 {
-                        format!("{}...", &patch_text[..100])
-                    } else {
-                        patch_text.clone()
-                    };
-                    details.push(format!("Failed to parse patch for new file {}: {:?}. Patch text: {}", file_path, e, patch_snippet));
-                }
-            }
-        } else if stripped_new_path == "/dev/null" {
-            // File deletion
-            log::debug!("Attempting to delete file: {}", file_path);
-            if full_path.exists() {
-                log::debug!("File {} exists, proceeding with deletion.", file_path);
-                if let Err(e) = fs::remove_file(&full_path) {
-                    details.push(format!("Failed to delete file {}: {}", file_path, e));
-                } else {
-                    applied_files.push(file_path.clone());
-                    log::info!("Deleted file: {}", file_path);
+        extra["simulated"] = json!(true);
+        extra["files"] = json!(files);
+        if outcome.is_success() {
+            extra["message"] = json!("Simulated: the patch applies cleanly. Nothing was written.");
+        }
+        return apply_response(outcome, extra);
+    }
+    if let Err(response) = check_revisions(&*store, &body.if_revision) {
+        return response;
+    }
+    if dry_run {
+        let task_store = store.clone();
+        let outcome = match web::block(move || engine.preview(&patch_set, &*task_store, &options).outcome).await {
+            Ok(outcome) => outcome,
+            Err(e) => return patch_task_failed(e),
+        };
+        if outcome.is_success() {
+            approvals.record_dry_run(fingerprint);
+            extra["message"] = json!("Dry run: the patch applies cleanly. Nothing was written.");
+        }
+        extra["dryRun"] = json!(true);
+        extra["reviews"] = reviews.counts(&reviews::patch_id(&store.display_path(""), patch_content));
+        return apply_response(outcome, extra);
+    }
+
+    let identity = usage::identity(req.extensions().get::<Session>());
+    if let Err(exceeded) = usage.check_patch(&identity).and_then(|_| usage.check_write(&identity, 0)) {
+        return exceeded.response();
+    }
+    let user = req.extensions().get::<Session>().map(|s| s.user.clone());
+    let client = client.unwrap_or_else(|| "anonymous".to_string());
+    let presence = presence.into_inner();
+    let _patching = presence.start_patch(&store.display_path(""), &client, user.as_deref().unwrap_or(&client), touched);
+    // Restoring the jj operation from before the patch undoes it
+    let jj_root = std::path::PathBuf::from(store.display_path(""));
+    let jj_operation = match jj::repository_root(&jj_root) {
+        Some(_) => web::block(move || jj::operation(&jj_root)).await.ok().flatten(),
+        None => None,
+    };
+    let format = body.format.unwrap_or(false);
+    let task_store = store.clone();
+    let applied = web::block(move || {
+        let before = history::snapshot(&*task_store, &patch_set);
+        let outcome = engine.apply(&patch_set, &*task_store, &options);
+        let formatting = (format && outcome.is_success()).then(|| {
+            let touched: Vec<String> = outcome.applied_files.iter().filter(|p| task_store.exists(p)).cloned().collect();
+            format_and_write(&formatters, &*task_store, &touched, &options)
+        });
+        let changes = history::changes(&*task_store, &patch_set, &outcome, &before);
+        let revisions = revisions(&*task_store, &outcome.applied_files);
+        (patch_set, outcome, formatting, changes, revisions)
+    })
+    .await;
+    let (patch_set, outcome, formatting, changes, revisions) = match applied {
+        Ok(applied) => applied,
+        Err(e) => return patch_task_failed(e),
+    };
+    if let Some(summary) = formatting {
+        extra["formatting"] = summary;
+    }
+    if !outcome.applied_files.is_empty() {
+        usage.add(&identity, usage::Counters { patches_applied: 1, ..Default::default() });
+        let files: Vec<String> = changes.iter().map(|c| c.path.clone()).collect();
+        let report_id = history.record(store.display_path(""), changes);
+        if let Some(chat_id) = &body.chat_id {
+            let link = chats::Link {
+                report_id: report_id.clone(),
+                chat_id: chat_id.clone(),
+                message_id: body.message_id.clone(),
+                root: store.display_path(""),
+                files,
+                created: chrono::Utc::now().to_rfc3339(),
+            };
+            if let Err(e) = chats.link(link) {
+                log::error!("{}", e);
+                // Keep the secret scan's findings alongside this one
+                let warning = json!(format!("The change was not linked to chat {}: {}", chat_id, e));
+                match extra["warnings"].as_array_mut() {
+                    Some(warnings) => warnings.push(warning),
+                    None => extra["warnings"] = json!([warning]),
                 }
-            } else {
-                log::warn!("File {} marked for deletion in patch, but it does not exist.", file_path);
-                details.push(format!("File to delete does not exist: {}", file_path));
             }
+        }
+        extra["reportId"] = json!(report_id);
+        extra["revisions"] = revisions;
+        if let Some(operation) = jj_operation {
+            extra["jjOperation"] = json!(operation);
+        }
+    }
+    if let (Some(id), true) = (&body.upload_id, outcome.is_success()) {
+        uploads.remove(id);
+    }
+    let summary = if outcome.is_success() {
+        format!("Applied a patch to {} files", outcome.applied_files.len())
+    } else {
+        format!("Applied a patch to {} of {} files", outcome.applied_files.len(), patch_set.files.len())
+    };
+    let mut event = activity::Event::new("patch", "apply_patch", summary).files(outcome.applied_files.clone()).failed(!outcome.is_success());
+    if let Some(report_id) = extra["reportId"].as_str() {
+        event = event.report(report_id.to_string());
+    }
+    activity.record(&store.display_path(""), req.extensions().get::<Session>(), event);
+    apply_response(outcome, extra)
+}
+
+#[get("/api/export_patch")]
+async fn export_patch(query: web::Query<ExportPatchQuery>, stores: Stores, history: web::Data<history::History>) -> HttpResponse {
+    let Some(report) = history.get(&query.report_id) else {
+        return HttpResponse::NotFound().json(json!({ "success": false, "error": format!("No applied patch with report id {}", query.report_id) }));
+    };
+    // Only callers who can still open the directory may export its changes
+    if let Err(e) = stores.open_root(&report.root) {
+        return HttpResponse::Forbidden().json(json!({ "success": false, "error": e }));
+    }
+    let header = diff::PatchHeader {
+        author: query.author.clone().unwrap_or_else(|| DEFAULT_PATCH_AUTHOR.to_string()),
+        date: report.created,
+        subject: query.subject.clone().unwrap_or_else(|| report.default_subject()),
+        body: query.body.clone().unwrap_or_default(),
+    };
+    HttpResponse::Ok()
+        .content_type("text/x-patch; charset=utf-8")
+        .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"0001-repopatch-{}.patch\"", report.id)))
+        .body(diff::format_patch(&header, &report.changes))
+}
+
+#[post("/api/diff_view")]
+async fn diff_view(
+    body: ValidJson<DiffViewRequest>,
+    stores: Stores,
+    history: web::Data<history::History>,
+    reviews: web::Data<reviews::Reviews>,
+    rewrites: web::Data<PathRewrites>,
+) -> HttpResponse {
+    let mut review_counts = None;
+    let (changes, details) = if let Some(report_id) = &body.report_id {
+        let Some(report) = history.get(report_id) else {
+            return HttpResponse::NotFound().json(json!({ "success": false, "error": format!("No applied patch with report id {}", report_id) }));
+        };
+        if let Err(e) = stores.open_root(&report.root) {
+            return HttpResponse::Forbidden().json(json!({ "success": false, "error": e }));
+        }
+        (report.changes, Vec::new())
+    } else {
+        let (Some(directory), Some(patch_content)) = (&body.directory_path, &body.patch_content) else {
+            return HttpResponse::BadRequest().json(json!({ "success": false, "error": "Send reportId, or directoryPath and patchContent" }));
+        };
+        let store = match stores.open_root(directory) {
+            Ok(s) => s,
+            Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid directory path: {}", e) })),
+        };
+        let mut patch_set = PatchSet::parse(patch_content, 1);
+        rewrites.apply(&mut patch_set);
+        review_counts = Some(reviews.counts(&reviews::patch_id(&store.display_path(""), patch_content.trim())));
+        let previewed = web::block(move || {
+            let before = history::snapshot(&*store, &patch_set);
+            let overlay = OverlayStore::new(&*store);
+            let outcome = patch_set.apply_with(&overlay, &apply_options(patch::DEFAULT_CONTEXT_LINES));
+            (history::changes(&overlay, &patch_set, &outcome, &before), outcome.details)
+        })
+        .await;
+        match previewed {
+            Ok(previewed) => previewed,
+            Err(e) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Diff task failed: {}", e) })),
+        }
+    };
+    let files: Vec<_> = changes
+        .iter()
+        .map(|c| {
+            let status = match (&c.old, &c.new) {
+                (None, _) => "create",
+                (_, None) => "delete",
+                _ => "modify",
+            };
+            let view = diff::side_by_side(c.old.as_deref().unwrap_or_default(), c.new.as_deref().unwrap_or_default(), body.context);
+            json!({ "path": c.path, "status": status, "old": view.old, "new": view.new })
+        })
+        .collect();
+    let mut response = json!({ "success": details.is_empty(), "files": files, "details": details });
+    if let Some(counts) = review_counts {
+        response["reviews"] = counts;
+    }
+    HttpResponse::Ok().json(response)
+}
+
+// Helper function to enforce policy violations: deny rules reject the patch,
+// dry_run rules need an earlier successful dry run and confirm rules a token
+fn check_policy(
+    patch_set: &PatchSet,
+    violations: &[policy::Violation],
+    approvals: &approvals::Approvals,
+    fingerprint: u64,
+    dry_run: bool,
+    token: Option<&str>,
+) -> Result<(), HttpResponse> {
+    if dry_run {
+        return Ok(());
+    }
+    let needs_dry_run: Vec<_> = violations.iter().filter(|v| v.action == Action::DryRun).collect();
+    if !needs_dry_run.is_empty() && !approvals.has_dry_run(fingerprint) {
+        return Err(HttpResponse::build(StatusCode::PRECONDITION_REQUIRED).json(json!({
+            "success": false,
+            "code": "DRY_RUN_REQUIRED",
+            "error": "Send this patch with dryRun: true first.",
+            "appliedFiles": [],
+            "details": [],
+            "violations": needs_dry_run
+        })));
+    }
+    let needs_confirmation: Vec<_> = violations.iter().filter(|v| v.action == Action::Confirm).collect();
+    if !needs_confirmation.is_empty() && !token.is_some_and(|t| approvals.redeem(t, fingerprint)) {
+        return Err(HttpResponse::build(StatusCode::PRECONDITION_REQUIRED).json(json!({
+            "success": false,
+            "code": "CONFIRMATION_REQUIRED",
+            "error": "Resend this patch with the confirmationToken to apply it.",
+            "confirmationToken": approvals.issue_token(fingerprint),
+            "expiresInSecs": approvals.ttl().as_secs(),
+            "summary": policy::summarize(patch_set),
+            "appliedFiles": [],
+            "details": [],
+            "violations": needs_confirmation
+        })));
+    }
+    Ok(())
+}
+
+// Helper function to format files and apply the result through the patch engine,
+// summarizing the outcome for API responses
+fn format_and_write(formatters: &format::Formatters, store: &dyn FileStore, paths: &[String], options: &ApplyOptions) -> serde_json::Value {
+    let outcome = formatters.format(store, paths);
+    let mut errors: Vec<_> = outcome.errors.iter().map(|(path, error)| json!({ "path": path, "error": error })).collect();
+    let mut written = Vec::new();
+    if !outcome.patch.is_empty() {
+        let applied = PatchSet::parse(&outcome.patch, 1).apply_with(store, options);
+        errors.extend(applied.details.iter().map(|d| json!({ "path": d.file, "error": d.message })));
+        written = applied.applied_files;
+    }
+    json!({
+        "changed": outcome.changed,
+        "written": written,
+        "unchanged": outcome.unchanged,
+        "skipped": outcome.skipped,
+        "errors": errors,
+        "patchContent": outcome.patch
+    })
+}
+
+#[post("/api/format")]
+async fn format_files(
+    body: ValidJson<FormatRequest>,
+    stores: Stores,
+    session: Option<web::ReqData<Session>>,
+    formatters: web::Data<format::Formatters>,
+    activity: web::Data<activity::Activity>,
+) -> HttpResponse {
+    let store = match stores.open_root(&body.directory_path) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid directory path: {}", e) })),
+    };
+    if let Some(path) = body.paths.iter().find(|p| store.validate(p).is_err() || !store.exists(p)) {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("{} is not a file in the directory", path), "field": "paths" }));
+    }
+    if body.write {
+        if let Err(response) = check_revisions(&*store, &body.if_revision) {
+            return response;
+        }
+    }
+    let paths = body.paths.clone();
+    let write = body.write;
+    let task_store = store.clone();
+    let result = web::block(move || {
+        if write {
+            format_and_write(&formatters, &*task_store, &paths, &apply_options(patch::DEFAULT_CONTEXT_LINES))
         } else {
-            // File modification
-            log::debug!("Attempting to modify file: {}", file_path);
-            log::trace!("Full path for modification: {:?}", full_path);
-            if full_path.exists() {
-                match fs::read_to_string(&full_path) {
-                    Ok(original_content) => {
-                        match dmp.patch_from_text::<Compat>(&patch_text) {
-                            Ok(patches) => {
-                                log::trace!("Parsed {} patch hunk(s) for file {}", patches.len(), file_path);
-                                log::trace!("Attempting to apply parsed hunks to original content of {}", file_path);
-                                match dmp.patch_apply(&patches, &original_content) {
-                                    Ok((new_content, applied)) => {
-                                        if applied.iter().all(|&b| b) {
-                                            if let Err(e) = fs::write(&full_path, &new_content) {
-                                                details.push(format!("Failed to write modified file {}: {}", file_path, e));
-                                            } else {
-                                                applied_files.push(file_path.clone());
-                                                log::info!("Modified file: {}", file_path);
-                                            }
-                                            log::debug!("Successfully applied patch and wrote modifications for {}", file_path);
-                                        } else {
-                                            details.push(format!("Failed to apply patch for file {}: partial application", file_path));
-                                            log::warn!("Partial patch application for file {}: {:?}", file_path, applied);
-                                            log::trace!("Original content length: {}, New content length: {}", original_content.len(), new_content.len());
-                                        }
-                                    }
-                                    Err(e) => {
-                                        details.push(format!("Error applying patch for file {}: {:?}", file_path, e));
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                let patch_snippet = if patch_text.len() > 100 {
-                                    format!("{}...", &patch_text[..100])
-                                } else {
-                                    patch_text.clone()
-                                };
-                                details.push(format!("Failed to parse patch for file {}: {:?}. Patch text: {}", file_path, e, patch_snippet));
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        log::error!("Failed to read existing file {} for patching: {}", file_path, e);
-                        details.push(format!("Failed to read file {}: {}", file_path, e));
-                    }
-                }
-                log::debug!("Finished processing modification for file: {}", file_path);
-            } else {
-                log::warn!("File {} marked for modification in patch, but it does not exist.", file_path);
-                details.push(format!("File to modify does not exist: {}", file_path));
+            let outcome = formatters.format(&*task_store, &paths);
+            json!({
+                "changed": outcome.changed,
+                "unchanged": outcome.unchanged,
+                "skipped": outcome.skipped,
+                "errors": outcome.errors.iter().map(|(path, error)| json!({ "path": path, "error": error })).collect::<Vec<_>>(),
+                "patchContent": outcome.patch
+            })
+        }
+    })
+    .await;
+    match result {
+        Ok(mut summary) => {
+            summary["success"] = json!(summary["errors"].as_array().is_none_or(|e| e.is_empty()));
+            if body.write {
+                let task_store = store.clone();
+                let paths = body.paths.clone();
+                summary["revisions"] = web::block(move || revisions(&*task_store, &paths)).await.unwrap_or_default();
+                let changed: Vec<String> = summary["changed"].as_array().into_iter().flatten().filter_map(|p| p.as_str().map(str::to_string)).collect();
+                let event = activity::Event::new("write", "format", format!("Formatted {} files", changed.len())).files(changed);
+                activity.record(&store.display_path(""), session.as_deref(), event.failed(summary["success"] != true));
             }
+            HttpResponse::Ok().json(summary)
         }
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Format task failed: {}", e) })),
+    }
+}
+
+// Helper function to build apply options from the request and DISK_RESERVE_MB
+fn apply_options(context_lines: usize) -> ApplyOptions {
+    let disk_reserve_mb = env::var("DISK_RESERVE_MB").ok().and_then(|v| v.parse::<u64>().ok()).unwrap_or(DEFAULT_DISK_RESERVE_MB);
+    ApplyOptions { context_lines, disk_reserve: disk_reserve_mb * 1024 * 1024, trace: false }
+}
+
+// Helper function to reject a write with 409 when a file changed since the
+// caller read it at the revision they sent as ifRevision
+fn check_revisions(store: &dyn FileStore, expected: &HashMap<String, String>) -> Result<(), HttpResponse> {
+    let stale = patch::check_revisions(store, expected);
+    if stale.is_empty() {
+        return Ok(());
+    }
+    Err(HttpResponse::Conflict().json(json!({
+        "success": false,
+        "error": "Some files changed since they were read.",
+        "appliedFiles": [],
+        "details": stale
+    })))
+}
+
+// Helper function to list the current revision of each existing file in `paths`
+fn revisions(store: &dyn FileStore, paths: &[String]) -> serde_json::Value {
+    let revisions: serde_json::Map<String, serde_json::Value> = paths
+        .iter()
+        .filter_map(|path| store.read(path).ok().map(|data| (path.clone(), json!(patch::revision(&data)))))
+        .collect();
+    json!(revisions)
+}
+
+// Helper function to report an apply outcome the way /api/apply_patch does
+fn apply_response(outcome: patch::ApplyOutcome, extra: serde_json::Value) -> HttpResponse {
+    let response = apply_result(outcome, extra);
+    if response["success"] == true {
+        HttpResponse::Ok().json(response)
+    } else {
+        HttpResponse::InternalServerError().json(response)
     }
+}
 
-    // Construct response
-    if details.is_empty() {
+// Helper function to build the apply_patch report of an outcome, with `extra`
+// fields merged in
+fn apply_result(outcome: patch::ApplyOutcome, extra: serde_json::Value) -> serde_json::Value {
+    let patch::ApplyOutcome { applied_files, details, trace } = outcome;
+    let success = details.is_empty();
+    let mut response = if success {
         log::info!("Patch applied successfully to {} files", applied_files.len());
-        HttpResponse::Ok().json(json!({
+        json!({
             "success": true,
             "message": "Patch applied successfully.",
             "appliedFiles": applied_files,
             "details": []
-        }))
+        })
     } else {
-        log::warn!("Patch application completed with issues: {:?}", details);
-        HttpResponse::InternalServerError().json(json!({
+        log::warn!("Patch application completed with issues: {:?}", details.iter().map(|d| &d.message).collect::<Vec<_>>());
+        json!({
             "success": false,
             "error": "Patch application failed for some files.",
             "appliedFiles": applied_files,
             "details": details
-        }))
+        })
+    };
+    if !trace.is_empty() {
+        response["trace"] = json!(trace);
+    }
+    if let (Some(response), serde_json::Value::Object(extra)) = (response.as_object_mut(), extra) {
+        response.extend(extra);
+    }
+    response
+}
+
+#[post("/api/replace")]
+async fn replace_in_files(
+    body: ValidJson<ReplaceRequest>,
+    stores: Stores,
+    session: Option<web::ReqData<Session>>,
+    runtime: web::Data<admin::Runtime>,
+    activity: web::Data<activity::Activity>,
+) -> HttpResponse {
+    let _job = admin::Runtime::start_job(&runtime);
+    let store = match stores.open_root(&body.directory_path) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid directory path: {}", e) })),
+    };
+    let spec = match ReplaceSpec::new(&body.pattern, &body.replacement, &body.include, &body.exclude) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+    let task_store = store.clone();
+    let planned = web::block(move || {
+        let ig = tree::load_gitignore(&*task_store, "").unwrap_or_else(Gitignore::empty);
+        replace::plan(&*task_store, "", &ig, &spec)
+    })
+    .await;
+    let plan = match planned {
+        Ok(Ok(plan)) => plan,
+        Ok(Err(e)) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": e })),
+        Err(e) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Replace task failed: {}", e) })),
+    };
+    let files: Vec<_> = plan.files.iter().map(|f| json!({ "path": store.display_path(&f.path), "matches": f.matches })).collect();
+
+    let Some(plan_id) = &body.plan_id else {
+        return HttpResponse::Ok().json(json!({
+            "success": true,
+            "dryRun": true,
+            "planId": plan.id(),
+            "files": files,
+            "patchContent": plan.patch
+        }));
+    };
+    if *plan_id != plan.id() {
+        return HttpResponse::Conflict().json(json!({
+            "success": false,
+            "code": "PLAN_CHANGED",
+            "error": "planId does not match the current changes; review the new diff and confirm its planId",
+            "planId": plan.id(),
+            "files": files,
+            "patchContent": plan.patch
+        }));
+    }
+    if let Err(response) = check_revisions(&*store, &body.if_revision) {
+        return response;
+    }
+    log::info!("Replacing {} in {} files under {}", body.pattern, plan.files.len(), store.display_path(""));
+    let task_store = store.clone();
+    let applied = web::block(move || {
+        let outcome = plan.patch_set().apply_with(&*task_store, &apply_options(patch::DEFAULT_CONTEXT_LINES));
+        let revisions = revisions(&*task_store, &outcome.applied_files);
+        (outcome, revisions)
+    })
+    .await;
+    let (outcome, revisions) = match applied {
+        Ok(applied) => applied,
+        Err(e) => return patch_task_failed(e),
+    };
+    let summary = format!("Replaced {} in {} files", body.pattern, outcome.applied_files.len());
+    let event = activity::Event::new("write", "replace", summary).files(outcome.applied_files.clone()).failed(!outcome.is_success());
+    activity.record(&store.display_path(""), session.as_deref(), event);
+    apply_response(outcome, json!({ "revisions": revisions }))
+}
+
+#[post("/api/refactor/rename_symbol")]
+async fn rename_symbol(body: ValidJson<RenameSymbolRequest>, stores: Stores) -> HttpResponse {
+    let store = match stores.open_root(&body.directory_path) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid directory path: {}", e) })),
+    };
+    let scope = match (body.scope.as_deref().unwrap_or("project"), body.line) {
+        ("project", _) => RenameScope::Project,
+        ("file", _) => RenameScope::File,
+        ("function", Some(line)) => RenameScope::Function { line },
+        ("function", None) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": "The function scope requires line", "field": "line" })),
+        (other, _) => {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "error": format!("Unknown scope {}: expected project, file or function", other),
+                "field": "scope"
+            }))
+        }
+    };
+    let task_store = store.clone();
+    let task_body = body.into_inner();
+    let result = web::block(move || {
+        let ig = tree::load_gitignore(&*task_store, "").unwrap_or_else(Gitignore::empty);
+        refactor::rename_symbol(&*task_store, &ig, &task_body.file, &task_body.symbol, &task_body.new_name, scope)
+    })
+    .await;
+    match result {
+        Ok(Ok(plan)) => {
+            let files: Vec<_> = plan.files.iter().map(|f| json!({ "path": store.display_path(&f.path), "matches": f.matches })).collect();
+            let conflicts: Vec<_> = plan.conflicts.iter().map(|p| store.display_path(p)).collect();
+            HttpResponse::Ok().json(json!({ "success": true, "files": files, "conflicts": conflicts, "patchContent": plan.patch }))
+        }
+        Ok(Err(e)) => HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Rename task failed: {}", e) })),
+    }
+}
+
+#[post("/api/move")]
+async fn move_file(body: ValidJson<MoveRequest>, stores: Stores) -> HttpResponse {
+    let store = match stores.open_root(&body.directory_path) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid directory path: {}", e) })),
+    };
+    let task_store = store.clone();
+    let task_body = body.into_inner();
+    let result = web::block(move || {
+        let ig = tree::load_gitignore(&*task_store, "").unwrap_or_else(Gitignore::empty);
+        moves::move_file(&*task_store, &ig, &task_body.from, &task_body.to, task_body.update_references)
+    })
+    .await;
+    match result {
+        Ok(Ok(plan)) => {
+            let files: Vec<_> = plan.files.iter().map(|f| json!({ "path": store.display_path(&f.path), "matches": f.matches })).collect();
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "files": files,
+                "unresolved": plan.unresolved,
+                "notes": plan.notes,
+                "patchContent": plan.patch
+            }))
+        }
+        Ok(Err(e)) => HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Move task failed: {}", e) })),
+    }
+}
+
+#[get("/api/scaffold")]
+async fn list_templates(templates: web::Data<scaffold::Templates>) -> HttpResponse {
+    let list: Vec<_> = templates
+        .list()
+        .iter()
+        .map(|t| json!({ "name": t.name, "description": t.description, "variables": t.variables }))
+        .collect();
+    HttpResponse::Ok().json(json!({ "success": true, "templates": list }))
+}
+
+#[post("/api/scaffold")]
+async fn scaffold_files(body: ValidJson<ScaffoldRequest>, stores: Stores, templates: web::Data<scaffold::Templates>) -> HttpResponse {
+    let store = match stores.open_root(&body.directory_path) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid directory path: {}", e) })),
+    };
+    let Some(template) = templates.get(&body.template) else {
+        return HttpResponse::NotFound().json(json!({ "success": false, "error": format!("No template named {}", body.template) }));
+    };
+    match scaffold::plan(&*store, template, &body.variables) {
+        Ok(plan) => {
+            let files: Vec<_> = plan.files.iter().map(|f| json!({ "path": store.display_path(&f.path), "append": f.append })).collect();
+            HttpResponse::Ok().json(json!({ "success": true, "files": files, "patchContent": plan.patch }))
+        }
+        Err(e) => HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
     }
 }
 
 #[get("/api/connect")]
-async fn connect(_req: HttpRequest) -> HttpResponse {
-    let port = env::var("PORT").unwrap_or_else(|_| "3000".to_string());
+async fn connect(session: Option<web::ReqData<Session>>, listening: web::Data<instance::Listening>) -> HttpResponse {
     HttpResponse::Ok().json(json!({
         "success": true,
         "status": "Server is running",
         "timestamp": chrono::Utc::now().to_rfc3339(),
-        "port": port
+        "port": listening.port.to_string(),
+        "user": session.map(|s| s.user.clone())
     }))
 }
 
@@ -573,8 +2152,17 @@ async fn serve_asset(req: HttpRequest) -> actix_web::Result<HttpResponse> {
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
     dotenv::dotenv().ok();
+    let config = config::Config::load().map_err(std::io::Error::other)?;
+    logging::init(config.logging.as_ref()).map_err(std::io::Error::other)?;
+
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("self-test") {
+        std::process::exit(selftest::run(args.get(2).map(String::as_str)));
+    }
+    if args.get(1).map(String::as_str) == Some("service") {
+        std::process::exit(service::run(&args[2..]));
+    }
 
     let port = env::var("PORT").unwrap_or_else(|_| "3000".to_string()).parse::<u16>().unwrap();
     let use_https = env::var("USE_HTTPS").unwrap_or_else(|_| "false".to_string()) == "true";
@@ -587,6 +2175,174 @@ async fn main() -> std::io::Result<()> {
 
     log::info!("Allowed Origins: {:?}", allowed_origins);
 
+    let protected_paths = config
+        .protected_paths
+        .clone()
+        .unwrap_or_else(|| ProtectedPaths::DEFAULT.iter().map(|p| p.to_string()).collect());
+    log::info!("Protected paths: {:?}", protected_paths);
+    let protected = ProtectedPaths::new(&protected_paths).map_err(std::io::Error::other)?.with_hidden(config.hidden_files);
+    log::info!("Hidden files: {:?}", config.hidden_files);
+    let backend: Arc<dyn StoreProvider> = match env::var("MEMORY_REPO") {
+        Ok(seed_path) if !seed_path.is_empty() => {
+            let store = seed::load(std::path::Path::new(&seed_path)).map_err(std::io::Error::other)?;
+            let root = env::var("MEMORY_REPO_ROOT").unwrap_or_else(|_| DEFAULT_MEMORY_REPO_ROOT.to_string());
+            log::info!("Serving {} files from {} in memory as {}", store.files().len(), seed_path, root);
+            Arc::new(MemoryStoreProvider::new(&root, Arc::new(store)))
+        }
+        _ => Arc::new(LocalStoreProvider),
+    };
+    let backend = Arc::new(ArchiveStoreProvider::new(backend));
+    let protected_stores: Arc<dyn StoreProvider> = Arc::new(ProtectedStoreProvider::new(backend, protected));
+    let aliases = Arc::new(AliasStoreProvider::new(protected_stores, config.workspaces.clone()).map_err(std::io::Error::other)?);
+    if !aliases.aliases().is_empty() {
+        log::info!("Workspace aliases: {:?}", aliases.aliases());
+    }
+    let workspaces = web::Data::from(aliases.clone());
+    let memory_repo = env::var("MEMORY_REPO").is_ok_and(|p| !p.is_empty());
+    let locks = if memory_repo { None } else { instance::RootLocks::from_env().map_err(std::io::Error::other)? };
+    let stores: Arc<dyn StoreProvider> = match &locks {
+        Some(locks) => Arc::new(instance::LockedStoreProvider::new(aliases, locks.clone())),
+        None => aliases,
+    };
+    let confirm_deletes = env::var("CONFIRM_DELETES").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_CONFIRM_DELETES);
+    let confirm_files = env::var("CONFIRM_FILES").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_CONFIRM_FILES);
+    let mut rules = config.policy.clone();
+    rules.extend(policy::guard_rules(confirm_deletes, confirm_files));
+    let policy = Arc::new(Policy::new(rules).map_err(std::io::Error::other)?);
+    if !config.policy.is_empty() {
+        log::info!("Patch policy has {} rules", config.policy.len());
+    }
+    let rewrites = Arc::new(PathRewrites::new(&config.path_rewrites).map_err(std::io::Error::other)?);
+    if !rewrites.is_empty() {
+        log::info!("Rewriting patch paths with {} rules", config.path_rewrites.len());
+    }
+    let engines = web::Data::new(EngineSelection::new(&config.patch_engines).map_err(std::io::Error::other)?);
+
+    if args.get(1).map(String::as_str) == Some("apply") {
+        let formatters = format::Formatters::new(config.formatters.clone());
+        std::process::exit(cli::apply(&args[2..], &*stores, &policy, &rewrites, &formatters));
+    }
+    if args.get(1).map(String::as_str) == Some("pack") {
+        std::process::exit(cli::pack_files(&args[2..], &*stores));
+    }
+    if args.get(1).map(String::as_str) == Some("drop") {
+        let Some(workspace) = args.get(2) else {
+            eprintln!("Usage: repopatch drop <workspace> [folder]");
+            std::process::exit(2);
+        };
+        let store = stores.open_root(workspace).map_err(std::io::Error::other)?;
+        let Some(folder) = args.get(3) else {
+            std::process::exit(dropfolder::run_stdin(&*store, &policy, &rewrites));
+        };
+        let drop = dropfolder::DropFolder::new(folder.into(), store, policy, rewrites).map_err(std::io::Error::other)?;
+        log::info!("Applying patches dropped into {} to {}", drop.folder().display(), drop.workspace());
+        return drop.watch().map_err(std::io::Error::other);
+    }
+    if let Some(drop) = dropfolder::DropFolder::from_env(&*stores, policy.clone(), rewrites.clone()).map_err(std::io::Error::other)? {
+        log::info!("Applying patches dropped into {} to {}", drop.folder().display(), drop.workspace());
+        std::thread::spawn(move || {
+            if let Err(e) = drop.watch() {
+                log::error!("Drop folder stopped: {}", e);
+            }
+        });
+    }
+    let policy = web::Data::from(policy);
+    let rewrites = web::Data::from(rewrites);
+    let instances = web::Data::new(proxy::InstanceRegistry::from_env());
+    let runtime: Arc<admin::Runtime> = Arc::default();
+    let users = web::Data::new(auth::UserRegistry::from_env(&stores).map_err(std::io::Error::other)?);
+    let metadata = Metadata::from_env().map_err(std::io::Error::other)?;
+    if let Some(metadata) = &metadata {
+        log::info!("Metadata database: {}", metadata.path().display());
+    }
+    let settings = web::Data::new(settings::SettingsStore::from_env(metadata.clone()).map_err(std::io::Error::other)?);
+    let chats = web::Data::new(chats::ChatStore::from_env(metadata.clone()).map_err(std::io::Error::other)?);
+    let profiles = web::Data::new(profiles::ProfileStore::from_env(metadata.clone()).map_err(std::io::Error::other)?);
+    let tree_limits = web::Data::new(config.tree_limits);
+    let writability = web::Data::from(writable::WritabilityMonitor::from_env());
+    let pack_cache = web::Data::new(packcache::PackCache::from_env().map_err(std::io::Error::other)?);
+    let checkpoints = web::Data::new(vcs::Checkpoints::from_env());
+    if users.is_enabled() {
+        log::info!("Multi-user mode enabled");
+    }
+    let oidc = match oidc::OidcConfig::from_env().map_err(std::io::Error::other)? {
+        Some(config) => {
+            log::info!("OIDC login enabled for issuer {}", config.issuer);
+            Some(web::Data::new(oidc::Oidc::discover(config, metadata.clone()).await.map_err(std::io::Error::other)?))
+        }
+        None => None,
+    };
+    let terminal = terminal::TerminalConfig::from_env(&allowed_origins).map_err(std::io::Error::other)?.map(web::Data::new);
+    if terminal.is_some() {
+        if users.is_enabled() || oidc.is_some() {
+            log::info!("Terminal enabled");
+        } else {
+            log::warn!("TERMINAL is set but nobody can sign in; configure USERS_FILE or OIDC to use the terminal");
+        }
+    }
+    let language_servers = lsp::LanguageServers::from_env(config.language_servers.clone()).map_err(std::io::Error::other)?.map(web::Data::from);
+    if let Some(servers) = &language_servers {
+        if !servers.needs_sign_in() || users.is_enabled() || oidc.is_some() {
+            log::info!("Language server bridge enabled");
+        } else {
+            log::warn!("LSP is set but nobody can sign in; configure USERS_FILE or OIDC, or set LSP=anyone");
+        }
+    }
+    let test_runs = testrun::TestRunConfig::from_env().map_err(std::io::Error::other)?.map(web::Data::new);
+    if let Some(test_runs) = &test_runs {
+        if !test_runs.needs_sign_in() || users.is_enabled() || oidc.is_some() {
+            log::info!("Test runs enabled");
+        } else {
+            log::warn!("TEST_RUNS is set but nobody can sign in; configure USERS_FILE or OIDC, or set TEST_RUNS=anyone");
+        }
+    }
+    let csrf = web::Data::new(csrf::CsrfConfig::from_env(oidc.is_some()));
+    let catalogs = web::Data::new(messages::Catalogs::from_env().map_err(std::io::Error::other)?);
+    log::info!("Message catalogs: {:?}, default {}", catalogs.locales(), catalogs.default_locale());
+    log::info!("CSRF protection {}", if csrf.enabled { "enabled" } else { "disabled" });
+
+    let formatters = web::Data::new(format::Formatters::new(config.formatters.clone()));
+    let checkers = web::Data::new(diagnostics::Checkers::new(config.checkers.clone()).map_err(std::io::Error::other)?);
+    let templates = web::Data::new(scaffold::Templates::new(config.templates.clone()));
+    let approval_ttl = env::var("CONFIRMATION_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(approvals::DEFAULT_APPROVAL_TTL);
+    let approvals = web::Data::new(approvals::Approvals::new(approval_ttl));
+    let history = web::Data::new(history::History::new(metadata.clone()).map_err(std::io::Error::other)?);
+    let activity = web::Data::new(activity::Activity::default());
+    let usage = web::Data::new(usage::Usage::from_env(&config.quotas).map_err(std::io::Error::other)?);
+    if usage.has_quotas() {
+        log::info!("Usage quotas enabled");
+    }
+    let reviews = web::Data::new(reviews::Reviews::default());
+    let max_upload = env::var("MAX_UPLOAD_MB").ok().and_then(|v| v.parse::<usize>().ok()).map(|mb| mb * 1024 * 1024);
+    let max_request_mb = env::var("MAX_REQUEST_MB").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_MAX_REQUEST_MB);
+    let presence = web::Data::new(presence::Presence::new(&allowed_origins));
+    let trash = web::Data::new(trash::Trash::from_env());
+    let caches: Vec<(&'static str, Arc<dyn memory::CacheSize>)> =
+        vec![("history", history.clone().into_inner()), ("checkpoints", checkpoints.clone().into_inner()), ("trash", trash.clone().into_inner())];
+    let memory = Arc::new(memory::MemoryBudget::from_env(caches).map_err(std::io::Error::other)?);
+    if let Some(limit) = memory.limit() {
+        log::info!("Memory limit: {} MiB", limit / 1024 / 1024);
+    }
+    let uploads = web::Data::new(upload::Uploads::new(max_upload.unwrap_or(upload::DEFAULT_MAX_UPLOAD_BYTES), memory.clone()));
+    let embeddings = web::Data::new(embeddings::Embeddings::from_env(metadata.clone()).map_err(std::io::Error::other)?);
+    if embeddings.enabled() {
+        log::info!("Semantic search enabled");
+    }
+    let agent_runtime = runtime.clone();
+    let watchdog = watch::Watchdog::new(locks.clone());
+    let agent_mode = args.get(1).map(String::as_str) == Some("agent");
+    // Agents serve on loopback only; clients reach the API through the hub
+    let listener = if agent_mode { std::net::TcpListener::bind(("127.0.0.1", 0))? } else { instance::listen(port)? };
+    let port = listener.local_addr()?.port();
+    if let Some(locks) = &locks {
+        locks.set_port(port);
+    }
+    let listening = web::Data::new(instance::Listening { port });
+    let locks = locks.map(web::Data::from);
     let server = HttpServer::new(move || {
         let mut cors = Cors::default();
         for origin in &allowed_origins {
@@ -594,28 +2350,212 @@ async fn main() -> std::io::Result<()> {
             cors = cors.allowed_origin(origin);
         }
         cors = cors
-            .allowed_methods(vec!["GET", "POST", "OPTIONS"])
+            .allowed_methods(vec!["GET", "POST", "PUT", "DELETE", "OPTIONS"])
             .allowed_headers(vec![
                 header::CONTENT_TYPE,
                 header::AUTHORIZATION,
                 header::ACCEPT,
                 header::HeaderName::from_static("ngrok-skip-browser-warning"),
+                header::HeaderName::from_static("x-csrf-token"),
+                header::HeaderName::from_static("x-repopatch-client"),
             ])
             .supports_credentials()
             .max_age(3600);
 
+        let oidc = oidc.clone();
+        let terminal = terminal.clone();
+        let test_runs = test_runs.clone();
+        let language_servers = language_servers.clone();
+        let locks = locks.clone();
         App::new()
+            .configure(|cfg| {
+                if let Some(oidc) = oidc {
+                    cfg.app_data(oidc).service(oidc::login).service(oidc::callback).service(oidc::logout);
+                }
+                if let Some(terminal) = terminal {
+                    cfg.app_data(terminal);
+                }
+                if let Some(test_runs) = test_runs {
+                    cfg.app_data(test_runs);
+                }
+                if let Some(servers) = language_servers {
+                    cfg.app_data(servers);
+                }
+                if let Some(locks) = locks {
+                    cfg.app_data(locks);
+                }
+            })
+            .app_data(web::Data::from(stores.clone()))
+            .app_data(instances.clone())
+            .app_data(listening.clone())
+            .app_data(web::Data::from(runtime.clone()))
+            .app_data(web::Data::from(watchdog.clone()))
+            .app_data(users.clone())
+            .app_data(csrf.clone())
+            .app_data(catalogs.clone())
+            .app_data(formatters.clone())
+            .app_data(checkers.clone())
+            .app_data(templates.clone())
+            .app_data(policy.clone())
+            .app_data(rewrites.clone())
+            .app_data(engines.clone())
+            .app_data(approvals.clone())
+            .app_data(history.clone())
+            .app_data(activity.clone())
+            .app_data(usage.clone())
+            .app_data(web::Data::from(memory.clone()))
+            .app_data(reviews.clone())
+            .app_data(presence.clone())
+            .app_data(uploads.clone())
+            .app_data(settings.clone())
+            .app_data(chats.clone())
+            .app_data(profiles.clone())
+            .app_data(tree_limits.clone())
+            .app_data(writability.clone())
+            .app_data(pack_cache.clone())
+            .app_data(checkpoints.clone())
+            .app_data(trash.clone())
+            .app_data(embeddings.clone())
+            .app_data(workspaces.clone())
+            .app_data(validation::json_config(max_request_mb * 1024 * 1024))
+            .app_data(validation::query_config())
+            .wrap(actix_web::middleware::from_fn(csrf::verify))
+            .wrap(actix_web::middleware::from_fn(auth::authenticate))
+            .wrap(actix_web::middleware::from_fn(i18n::localize))
             .wrap(cors)
             .wrap(actix_web::middleware::Logger::default())
             .service(get_directory)
+            .service(get_workspaces)
+            .service(get_workspace_info)
+            .service(get_packages)
             .service(get_file)
+            .service(get_raw)
+            .service(get_preview)
+            .service(get_extract)
+            .service(get_deps)
+            .service(get_related)
+            .service(plan_context)
+            .service(get_impact)
+            .service(get_diagnostics)
+            .service(testrun::list_tests)
+            .service(testrun::run_tests)
+            .service(lsp::lsp_definition)
+            .service(lsp::lsp_hover)
+            .service(lsp::lsp_references)
+            .service(embeddings::search)
+            .service(get_stats)
+            .service(get_duplicates)
+            .service(get_recent_files)
             .service(get_files_batch)
+            .service(get_table_preview)
             .service(apply_patch)
+            .service(multipatch::apply_patches)
+            .service(export_patch)
+            .service(activity::get_activity)
+            .service(usage::get_usage)
+            .service(diff_view)
+            .service(forge::create)
+            .service(import::import_patch)
+            .service(upload::start)
+            .service(upload::append)
+            .service(upload::finish)
+            .service(reviews::add_comment)
+            .service(reviews::list_comments)
+            .service(reviews::resolve_comment)
+            .service(thumbnail::get_thumbnail)
+            .service(trash::delete_tree)
+            .service(trash::list_trash)
+            .service(trash::restore)
+            .service(replace_in_files)
+            .service(rename_symbol)
+            .service(move_file)
+            .service(list_templates)
+            .service(scaffold_files)
+            .service(format_files)
             .service(check_writable)
             .service(connect)
+            .service(csrf::issue_token)
+            .service(i18n::get_messages)
+            .service(settings::get_settings)
+            .service(chats::save_chat)
+            .service(chats::get_chats)
+            .service(chats::provenance)
+            .service(packcache::pack_workspace)
+            .service(vcs::get_status)
+            .service(vcs::get_diff)
+            .service(vcs::take_checkpoint)
+            .service(profiles::list_profiles)
+            .service(profiles::save_profile)
+            .service(profiles::delete_profile)
+            .service(settings::put_settings)
+            .service(state::export_state)
+            .service(
+                web::resource("/api/import_state")
+                    .app_data(web::PayloadConfig::new(state::MAX_STATE_BYTES))
+                    .route(web::post().to(state::import_state)),
+            )
+            .service(admin::status)
+            .service(admin::flush)
+            .service(admin::benchmark)
+            .service(memory::metrics)
+            .service(watch::events)
+            .service(presence::connect)
+            .service(terminal::connect)
+            .service(proxy::list_instances)
+            .service(proxy::register_instance)
+            .service(proxy::remove_instance)
+            .service(
+                web::resource("/remote/{name}/api/{tail:.*}")
+                    .app_data(web::PayloadConfig::new(proxy::MAX_BODY_BYTES))
+                    .to(proxy::proxy),
+            )
             .default_service(web::to(serve_asset))
     });
 
+    let tuning = config.server.clone();
+    let mut server = server;
+    if let Some(workers) = tuning.workers {
+        server = server.workers(workers.max(1));
+    }
+    if let Some(threads) = tuning.blocking_threads {
+        server = server.worker_max_blocking_threads(threads.max(1));
+    }
+    if let Some(secs) = tuning.keep_alive_secs {
+        server = server.keep_alive(if secs == 0 { KeepAlive::Disabled } else { KeepAlive::Timeout(Duration::from_secs(secs)) });
+    }
+    if let Some(ms) = tuning.client_request_timeout_ms {
+        server = server.client_request_timeout(Duration::from_millis(ms));
+    }
+    if let Some(ms) = tuning.client_disconnect_timeout_ms {
+        server = server.client_disconnect_timeout(Duration::from_millis(ms));
+    }
+    if let Some(ms) = tuning.tls_handshake_timeout_ms {
+        server = server.tls_handshake_timeout(Duration::from_millis(ms));
+    }
+    if let Some(max) = tuning.max_connections {
+        server = server.max_connections(max);
+    }
+    if let Some(max) = tuning.max_connection_rate {
+        server = server.max_connection_rate(max);
+    }
+    if let Some(backlog) = tuning.backlog {
+        server = server.backlog(backlog);
+    }
+    if let Some(secs) = tuning.shutdown_timeout_secs {
+        server = server.shutdown_timeout(secs);
+    }
+    log::debug!("Server tuning: {:?}", tuning);
+
+    if agent_mode {
+        let config = agent::AgentConfig::from_env().map_err(std::io::Error::other)?;
+        let server = server.listen(listener)?;
+        let local_addr = server.addrs()[0];
+        log::info!("Starting agent for hub {} (local API at http://{})", config.hub_url, local_addr);
+        let server = server.run();
+        actix_web::rt::spawn(agent::run(config, local_addr, agent_runtime));
+        return server.await;
+    }
+
     if use_https {
         let cert_file = File::open("server.cert").expect("Failed to open server.cert");
         let key_file = File::open("server.key").expect("Failed to open server.key");
@@ -632,14 +2572,15 @@ async fn main() -> std::io::Result<()> {
             .with_single_cert(cert_chain, rustls::pki_types::PrivateKeyDer::Pkcs8(private_key))
             .expect("Failed to build TLS config");
 
+        let _port_file = instance::PortFile::write(port, true)?;
         log::info!("Starting HTTPS server at https://0.0.0.0:{}", port);
-        server.bind_rustls_0_23(("0.0.0.0", port), config)?
+        server.listen_rustls_0_23(listener, config)?
             .run()
             .await
     } else {
-        log::info!("Starting HTTP server at http://0.0.0.0:{}", port);
-        server.bind(("0.0.0.0", port))?
-            .run()
-            .await
+        let _port_file = instance::PortFile::write(port, false)?;
+        log::info!("Starting HTTP server at http://0.0.0.0:{}{}", port, if tuning.h2c { " (HTTP/2 without TLS allowed)" } else { "" });
+        let server = if tuning.h2c { server.listen_auto_h2c(listener)? } else { server.listen(listener)? };
+        server.run().await
     }
 }
\ No newline at end of file
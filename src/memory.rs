@@ -0,0 +1,387 @@
+// Memory accounting, so a server on a small VPS sheds load instead of
+// growing until the kernel kills it. The server keeps rough counts of three
+// kinds of memory:
+//
+//   caches   patch reports, snapshot checkpoints and the trash
+//   reads    file contents being read through the workspaces
+//   patches  patch text of chunked uploads and of patches being applied
+//
+// A read is counted while the store reads the file; handlers sending file
+// contents back keep them counted until the response has been sent, with
+// [`hold_body`].
+//
+// MEMORY_LIMIT_MB caps their sum. Past it, requests opening a workspace get
+// 429 with Retry-After, reads that would cross it fail and uploads and
+// patches are refused until memory is released. Caches are counted but never
+// refused: they have their own limits and shrink as they evict.
+//
+// GET /metrics reports the counts, the cap, the requests shed and the
+// resident set size of the process in the Prometheus text format.
+
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::web::Bytes;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use repopatch::store::{DirEntry, FileStore, StoreProvider};
+use serde_json::json;
+use std::env;
+use std::fmt::Write;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+/// Seconds clients are asked to wait after a request was shed.
+const RETRY_AFTER_SECS: u64 = 5;
+
+/// What a cache holds in memory, for [`MemoryBudget`].
+pub trait CacheSize: Send + Sync {
+    /// Approximate bytes of file content and patch text held.
+    fn cached_bytes(&self) -> u64;
+}
+
+/// Memory that can be refused when the budget is used up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pool {
+    Reads,
+    Patches,
+}
+
+impl Pool {
+    fn name(self) -> &'static str {
+        match self {
+            Pool::Reads => "reads",
+            Pool::Patches => "patches",
+        }
+    }
+}
+
+/// The memory cap was reached.
+pub struct Overloaded {
+    pub message: String,
+}
+
+impl Overloaded {
+    pub fn response(&self) -> HttpResponse {
+        HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", RETRY_AFTER_SECS.to_string()))
+            .json(json!({ "success": false, "code": "MEMORY_LIMIT", "error": self.message, "retryAfterSecs": RETRY_AFTER_SECS }))
+    }
+}
+
+pub struct MemoryBudget {
+    limit: Option<u64>,
+    reads: AtomicU64,
+    patches: AtomicU64,
+    shed: AtomicU64,
+    caches: Vec<(&'static str, Arc<dyn CacheSize>)>,
+}
+
+impl MemoryBudget {
+    /// A budget capped by MEMORY_LIMIT_MB, counting `caches` by name.
+    pub fn from_env(caches: Vec<(&'static str, Arc<dyn CacheSize>)>) -> Result<Self, String> {
+        let limit = match env::var("MEMORY_LIMIT_MB") {
+            Ok(v) if !v.is_empty() => Some(v.parse::<u64>().map_err(|_| format!("MEMORY_LIMIT_MB must be a number, not {}", v))?),
+            _ => None,
+        };
+        Ok(MemoryBudget {
+            limit: limit.filter(|&mb| mb > 0).map(|mb| mb.saturating_mul(1024 * 1024)),
+            reads: AtomicU64::new(0),
+            patches: AtomicU64::new(0),
+            shed: AtomicU64::new(0),
+            caches,
+        })
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    fn pool(&self, pool: Pool) -> &AtomicU64 {
+        match pool {
+            Pool::Reads => &self.reads,
+            Pool::Patches => &self.patches,
+        }
+    }
+
+    fn cached(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        self.caches.iter().map(|(name, cache)| (*name, cache.cached_bytes()))
+    }
+
+    /// Bytes held by the caches and every reservation.
+    pub fn used(&self) -> u64 {
+        self.cached().map(|(_, bytes)| bytes).sum::<u64>() + self.reads.load(Ordering::Relaxed) + self.patches.load(Ordering::Relaxed)
+    }
+
+    // Counts a refusal and says why
+    fn refuse(&self, used: u64, limit: u64) -> Overloaded {
+        self.shed.fetch_add(1, Ordering::Relaxed);
+        Overloaded { message: format!("The server is holding {} of the {} MiB it may use; try again shortly", used / 1024 / 1024, limit / 1024 / 1024) }
+    }
+
+    /// Whether a new request may start.
+    pub fn admit(&self) -> Result<(), Overloaded> {
+        match self.limit {
+            Some(limit) if self.used() >= limit => Err(self.refuse(self.used(), limit)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts `bytes` of `pool` until the reservation drops, unless they
+    /// would take the total over the cap.
+    pub fn reserve(self: &Arc<Self>, pool: Pool, bytes: u64) -> Result<Reservation, Overloaded> {
+        self.take(pool, bytes)?;
+        Ok(Reservation { budget: self.clone(), pool, bytes })
+    }
+
+    /// Counts `bytes` of `pool` until the reservation drops, even past the
+    /// cap, for memory that is already held.
+    pub fn hold(self: &Arc<Self>, pool: Pool, bytes: u64) -> Reservation {
+        self.pool(pool).fetch_add(bytes, Ordering::Relaxed);
+        Reservation { budget: self.clone(), pool, bytes }
+    }
+
+    fn take(&self, pool: Pool, bytes: u64) -> Result<(), Overloaded> {
+        if let Some(limit) = self.limit {
+            let used = self.used();
+            if used.saturating_add(bytes) > limit {
+                return Err(self.refuse(used, limit));
+            }
+        }
+        self.pool(pool).fetch_add(bytes, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Memory counted against a [`MemoryBudget`] until dropped.
+pub struct Reservation {
+    budget: Arc<MemoryBudget>,
+    pool: Pool,
+    bytes: u64,
+}
+
+impl Reservation {
+    /// Counts `bytes` more, unless they would take the total over the cap.
+    pub fn grow(&mut self, bytes: u64) -> Result<(), Overloaded> {
+        self.budget.take(self.pool, bytes)?;
+        self.bytes += bytes;
+        Ok(())
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.pool(self.pool).fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+/// A response body counted as [`Pool::Reads`] until it has been sent.
+struct HeldBody {
+    body: BoxBody,
+    _reservation: Reservation,
+}
+
+impl MessageBody for HeldBody {
+    type Error = <BoxBody as MessageBody>::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        Pin::new(&mut self.get_mut().body).poll_next(cx)
+    }
+}
+
+/// Keeps the body of `response`, such as file contents read for it, counted
+/// as [`Pool::Reads`] until it has been sent.
+pub fn hold_body(req: &HttpRequest, response: HttpResponse) -> HttpResponse {
+    let Some(budget) = req.app_data::<web::Data<MemoryBudget>>() else {
+        return response;
+    };
+    let BodySize::Sized(bytes) = response.body().size() else {
+        return response;
+    };
+    let reservation = budget.clone().into_inner().hold(Pool::Reads, bytes);
+    response.map_body(|_, body| HeldBody { body, _reservation: reservation }).map_into_boxed_body()
+}
+
+/// A store counting the files being read through it as [`Pool::Reads`]
+/// while it reads them.
+pub struct BudgetedStore {
+    inner: Arc<dyn FileStore>,
+    budget: Arc<MemoryBudget>,
+}
+
+impl FileStore for BudgetedStore {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let size = self.inner.file_size(path).unwrap_or(0);
+        let _reading = self.budget.reserve(Pool::Reads, size).map_err(|o| io::Error::new(io::ErrorKind::OutOfMemory, o.message))?;
+        self.inner.read(path)
+    }
+
+    fn read_head(&self, path: &str, max: usize) -> io::Result<Vec<u8>> {
+        let size = self.inner.file_size(path).unwrap_or(0).min(max as u64);
+        let _reading = self.budget.reserve(Pool::Reads, size).map_err(|o| io::Error::new(io::ErrorKind::OutOfMemory, o.message))?;
+        self.inner.read_head(path, max)
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.inner.write(path, data)
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        self.inner.remove(path)
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        self.inner.remove_dir(path)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn list_dir(&self, path: &str) -> io::Result<Vec<DirEntry>> {
+        self.inner.list_dir(path)
+    }
+
+    fn display_path(&self, path: &str) -> String {
+        self.inner.display_path(path)
+    }
+
+    fn validate(&self, path: &str) -> Result<(), String> {
+        self.inner.validate(path)
+    }
+
+    fn check_writable(&self, path: &str) -> Result<(), String> {
+        self.inner.check_writable(path)
+    }
+
+    fn file_size(&self, path: &str) -> Option<u64> {
+        self.inner.file_size(path)
+    }
+
+    fn modified(&self, path: &str) -> Option<SystemTime> {
+        self.inner.modified(path)
+    }
+
+    fn available_space(&self, path: &str) -> Option<u64> {
+        self.inner.available_space(path)
+    }
+
+    fn file_id(&self, path: &str) -> Option<(u64, u64)> {
+        self.inner.file_id(path)
+    }
+
+    fn local_path(&self, path: &str) -> Option<PathBuf> {
+        self.inner.local_path(path)
+    }
+}
+
+/// Opens every store of another provider as a [`BudgetedStore`].
+pub struct BudgetedStoreProvider {
+    inner: Arc<dyn StoreProvider>,
+    budget: Arc<MemoryBudget>,
+}
+
+impl BudgetedStoreProvider {
+    pub fn new(inner: Arc<dyn StoreProvider>, budget: Arc<MemoryBudget>) -> Self {
+        BudgetedStoreProvider { inner, budget }
+    }
+
+    fn budget(&self, store: Arc<dyn FileStore>) -> Arc<dyn FileStore> {
+        Arc::new(BudgetedStore { inner: store, budget: self.budget.clone() })
+    }
+}
+
+impl StoreProvider for BudgetedStoreProvider {
+    fn open_root(&self, root: &str) -> Result<Arc<dyn FileStore>, String> {
+        Ok(self.budget(self.inner.open_root(root)?))
+    }
+
+    fn open_file(&self, path: &str) -> Result<(Arc<dyn FileStore>, String), String> {
+        let (store, name) = self.inner.open_file(path)?;
+        Ok((self.budget(store), name))
+    }
+}
+
+// Resident set size of this process, from /proc
+#[cfg(target_os = "linux")]
+fn resident_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // SAFETY: sysconf only reads a configuration value; it takes no pointers
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    (page_size > 0).then(|| pages * page_size as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_bytes() -> Option<u64> {
+    None
+}
+
+#[get("/metrics")]
+pub async fn metrics(budget: web::Data<MemoryBudget>) -> HttpResponse {
+    let mut out = String::new();
+    out.push_str("# HELP repopatch_memory_bytes Approximate bytes held, by pool.\n# TYPE repopatch_memory_bytes gauge\n");
+    for pool in [Pool::Reads, Pool::Patches] {
+        let _ = writeln!(out, "repopatch_memory_bytes{{pool=\"{}\"}} {}", pool.name(), budget.pool(pool).load(Ordering::Relaxed));
+    }
+    for (name, bytes) in budget.cached() {
+        let _ = writeln!(out, "repopatch_memory_bytes{{pool=\"cache\",cache=\"{}\"}} {}", name, bytes);
+    }
+    if let Some(limit) = budget.limit() {
+        out.push_str("# HELP repopatch_memory_limit_bytes MEMORY_LIMIT_MB in bytes.\n# TYPE repopatch_memory_limit_bytes gauge\n");
+        let _ = writeln!(out, "repopatch_memory_limit_bytes {}", limit);
+    }
+    out.push_str("# HELP repopatch_memory_shed_total Requests, reads and patches refused at the memory limit.\n# TYPE repopatch_memory_shed_total counter\n");
+    let _ = writeln!(out, "repopatch_memory_shed_total {}", budget.shed.load(Ordering::Relaxed));
+    if let Some(rss) = resident_bytes() {
+        out.push_str("# HELP process_resident_memory_bytes Resident memory size in bytes.\n# TYPE process_resident_memory_bytes gauge\n");
+        let _ = writeln!(out, "process_resident_memory_bytes {}", rss);
+    }
+    HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use repopatch::store::MemoryStore;
+
+    fn budget(limit: Option<u64>) -> Arc<MemoryBudget> {
+        Arc::new(MemoryBudget { limit, reads: AtomicU64::new(0), patches: AtomicU64::new(0), shed: AtomicU64::new(0), caches: Vec::new() })
+    }
+
+    #[test]
+    fn reads_past_the_cap_are_refused() {
+        let budget = budget(Some(10));
+        let inner = Arc::new(MemoryStore::from_files([("small.txt", "12345"), ("large.txt", "12345678901")]));
+        let store = BudgetedStore { inner, budget: budget.clone() };
+
+        assert_eq!(store.read("small.txt").unwrap(), b"12345");
+        assert_eq!(store.read("large.txt").unwrap_err().kind(), io::ErrorKind::OutOfMemory);
+        assert_eq!(store.read_head("large.txt", 4).unwrap(), b"1234");
+        assert_eq!(budget.used(), 0);
+        assert_eq!(budget.shed.load(Ordering::Relaxed), 1);
+
+        let _held = budget.hold(Pool::Patches, 8);
+        assert!(store.read("small.txt").is_err());
+        assert!(budget.admit().is_ok());
+    }
+
+    #[actix_web::test]
+    async fn response_bodies_stay_counted_until_sent() {
+        let budget = budget(None);
+        let req = TestRequest::default().app_data(web::Data::from(budget.clone())).to_http_request();
+
+        let response = hold_body(&req, HttpResponse::Ok().body("file contents"));
+        assert_eq!(budget.reads.load(Ordering::Relaxed), 13);
+        let sent = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(sent, "file contents");
+        assert_eq!(budget.reads.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,256 @@
+//! Message catalogs for answering in the client's language. The server
+//! writes its messages in English; `messages/en.toml` names each of them by a
+//! code, and the other catalogs translate those codes. A message is matched
+//! back to its code by its English template, so handlers keep formatting
+//! plain English and the parts they fill in are carried over.
+
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+const BUILTIN: &[(&str, &str)] = &[
+    ("en", include_str!("../messages/en.toml")),
+    ("de", include_str!("../messages/de.toml")),
+    ("es", include_str!("../messages/es.toml")),
+    ("fr", include_str!("../messages/fr.toml")),
+];
+
+/// Filled-in parts translated in turn, e.g. the OS error inside
+/// "Invalid path '/x': No such file or directory (os error 2)".
+const MAX_NESTING: usize = 3;
+
+/// A message found in the catalogs, in the requested language.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Localized {
+    pub code: String,
+    pub text: String,
+}
+
+struct Template {
+    code: String,
+    pattern: Regex,
+}
+
+pub struct Catalogs {
+    catalogs: BTreeMap<String, BTreeMap<String, String>>,
+    templates: Vec<Template>,
+    default_locale: String,
+}
+
+impl Catalogs {
+    /// The catalogs shipped with the server.
+    pub fn builtin() -> Self {
+        Catalogs::new(builtin_catalogs(), "en").expect("built-in message catalogs must be consistent")
+    }
+
+    /// Catalogs by locale, which must include `en`. Translations may only
+    /// use codes and placeholders the English catalog has.
+    pub fn new(catalogs: BTreeMap<String, BTreeMap<String, String>>, default_locale: &str) -> Result<Self, String> {
+        let english = catalogs.get("en").ok_or("The message catalogs have no en catalog")?;
+        for (locale, catalog) in &catalogs {
+            for (code, text) in catalog {
+                let Some(source) = english.get(code) else {
+                    return Err(format!("Message catalog {}: unknown code {}", locale, code));
+                };
+                let count = split(source).iter().filter_map(|p| p.arg).max().map_or(0, |i| i + 1);
+                if let Some(index) = split(text).iter().filter_map(|p| p.arg).find(|&i| i >= count) {
+                    return Err(format!("Message catalog {}: {} uses placeholder {{{}}} but the message has {}", locale, code, index + 1, count));
+                }
+            }
+        }
+        let default_locale = default_locale.to_lowercase();
+        if !catalogs.contains_key(&default_locale) {
+            return Err(format!("No message catalog for the locale {}", default_locale));
+        }
+        let mut templates: Vec<(usize, Template)> = english
+            .iter()
+            .filter_map(|(code, text)| {
+                let parts = split(text);
+                let literal: usize = parts.iter().map(|p| p.text.chars().filter(|c| c.is_alphanumeric()).count()).sum();
+                // "{}: {}" and the like would match anything
+                if literal < 3 {
+                    return None;
+                }
+                let pattern = parts.iter().map(|p| regex::escape(&p.text) + if p.arg.is_some() { "(.+?)" } else { "" }).collect::<String>();
+                let pattern = Regex::new(&format!("^(?s){}$", pattern)).ok()?;
+                Some((literal, Template { code: code.clone(), pattern }))
+            })
+            .collect();
+        // The most specific template wins
+        templates.sort_by_key(|(literal, _)| std::cmp::Reverse(*literal));
+        Ok(Catalogs { catalogs, templates: templates.into_iter().map(|(_, t)| t).collect(), default_locale })
+    }
+
+    /// The built-in catalogs, with those in `MESSAGES_DIR` added over them,
+    /// answering in `LOCALE` (default `en`) when a client asks for none.
+    pub fn from_env() -> Result<Self, String> {
+        let mut catalogs = builtin_catalogs();
+        if let Ok(dir) = std::env::var("MESSAGES_DIR") {
+            if !dir.is_empty() {
+                for (locale, catalog) in load_dir(Path::new(&dir))? {
+                    catalogs.entry(locale).or_default().extend(catalog);
+                }
+            }
+        }
+        let locale = std::env::var("LOCALE").ok().filter(|l| !l.is_empty()).unwrap_or_else(|| "en".to_string());
+        Catalogs::new(catalogs, &locale)
+    }
+
+    pub fn locales(&self) -> Vec<&str> {
+        self.catalogs.keys().map(String::as_str).collect()
+    }
+
+    pub fn default_locale(&self) -> &str {
+        &self.default_locale
+    }
+
+    /// The best catalog for an `Accept-Language` header: by quality, each
+    /// tag as given and then by its language alone, so `de-AT` gets `de`.
+    pub fn negotiate(&self, accept_language: Option<&str>) -> &str {
+        let mut tags: Vec<(f32, String)> = accept_language
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let tag = parts.next()?.trim().to_lowercase();
+                let quality = parts
+                    .filter_map(|p| p.trim().strip_prefix("q="))
+                    .find_map(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (!tag.is_empty() && quality > 0.0).then_some((quality, tag))
+            })
+            .collect();
+        tags.sort_by(|a, b| b.0.total_cmp(&a.0));
+        for (_, tag) in &tags {
+            if tag == "*" {
+                break;
+            }
+            let language = tag.split(['-', '_']).next().unwrap_or(tag);
+            for candidate in [tag.as_str(), language] {
+                if let Some((locale, _)) = self.catalogs.get_key_value(candidate) {
+                    return locale;
+                }
+            }
+        }
+        &self.default_locale
+    }
+
+    /// `message` in `locale`, when it is one of the catalogued messages.
+    /// Messages without a translation stay in English but keep their code.
+    pub fn localize(&self, message: &str, locale: &str) -> Option<Localized> {
+        self.translate(message, locale, 0)
+    }
+
+    fn translate(&self, message: &str, locale: &str, depth: usize) -> Option<Localized> {
+        let (template, captures) = self.templates.iter().find_map(|t| Some((t, t.pattern.captures(message)?)))?;
+        let args: Vec<String> = captures
+            .iter()
+            .skip(1)
+            .map(|c| {
+                let arg = c.map_or("", |c| c.as_str());
+                if depth < MAX_NESTING {
+                    self.translate(arg, locale, depth + 1).map_or_else(|| arg.to_string(), |l| l.text)
+                } else {
+                    arg.to_string()
+                }
+            })
+            .collect();
+        let text = match self.catalogs.get(locale).and_then(|c| c.get(&template.code)) {
+            Some(translation) => fill(translation, &args),
+            None => fill(&self.catalogs["en"][&template.code], &args),
+        };
+        Some(Localized { code: template.code.clone(), text })
+    }
+
+    /// Every code with its text in `locale`, English where it has none.
+    pub fn messages(&self, locale: &str) -> BTreeMap<String, String> {
+        let mut messages = self.catalogs["en"].clone();
+        if let Some(catalog) = self.catalogs.get(locale) {
+            messages.extend(catalog.iter().map(|(code, text)| (code.clone(), text.clone())));
+        }
+        messages
+    }
+}
+
+fn builtin_catalogs() -> BTreeMap<String, BTreeMap<String, String>> {
+    BUILTIN
+        .iter()
+        .map(|(locale, text)| (locale.to_string(), parse(text).expect("built-in message catalogs must parse")))
+        .collect()
+}
+
+/// The `*.toml` catalogs in `dir`, by locale (the file name, lowercased).
+pub fn load_dir(dir: &Path) -> Result<BTreeMap<String, BTreeMap<String, String>>, String> {
+    let entries = std::fs::read_dir(dir).map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+    let mut catalogs = BTreeMap::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("toml") {
+            continue;
+        }
+        let Some(locale) = path.file_stem().and_then(|s| s.to_str()).map(str::to_lowercase) else {
+            continue;
+        };
+        let text = std::fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        catalogs.insert(locale, parse(&text).map_err(|e| format!("Invalid {}: {}", path.display(), e))?);
+    }
+    Ok(catalogs)
+}
+
+/// A catalog: a table of codes and message templates.
+pub fn parse(text: &str) -> Result<BTreeMap<String, String>, String> {
+    toml::from_str(text).map_err(|e| e.to_string())
+}
+
+/// `template` with its placeholders replaced by `args`: `{}` takes the next
+/// one and `{2}` the second.
+pub fn fill(template: &str, args: &[String]) -> String {
+    let parts = split(template);
+    let mut filled = String::new();
+    for part in parts {
+        filled.push_str(&part.text);
+        if let Some(arg) = part.arg {
+            filled.push_str(args.get(arg).map_or("", String::as_str));
+        }
+    }
+    filled
+}
+
+struct Part {
+    text: String,
+    /// The 0-based argument following the text.
+    arg: Option<usize>,
+}
+
+// The template as literal text, each part followed by a placeholder but the last
+fn split(template: &str) -> Vec<Part> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut next = 0;
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        text.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let index = after.find('}').map(|end| &after[..end]).filter(|n| n.chars().all(|c| c.is_ascii_digit()));
+        match index {
+            Some(n) => {
+                let arg = match n.parse::<usize>() {
+                    Ok(position) if position > 0 => position - 1,
+                    _ => {
+                        next += 1;
+                        next - 1
+                    }
+                };
+                parts.push(Part { text: std::mem::take(&mut text), arg: Some(arg) });
+                rest = &after[n.len() + 1..];
+            }
+            None => {
+                text.push('{');
+                rest = after;
+            }
+        }
+    }
+    text.push_str(rest);
+    parts.push(Part { text, arg: None });
+    parts
+}
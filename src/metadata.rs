@@ -0,0 +1,308 @@
+//! The metadata database: one SQLite file under the server's data directory
+//! holding the state that used to be spread over JSON files, such as user
+//! settings, chats, workspace profiles, reports of applied patches, login
+//! sessions and semantic indexes. Each is a kind of record: a JSON value
+//! under a key, stamped with when it was last written, so a change writes
+//! one row instead of a whole file and the data can be queried with
+//! SQLite's JSON functions.
+//!
+//! Built with the `sqlite` feature, which compiles SQLite in through
+//! rusqlite; without it, as by default, [`Metadata::open`] fails and the
+//! server keeps its JSON files.
+
+use chrono::{SecondsFormat, Utc};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Name of the database inside the data directory.
+pub const DATABASE_FILE: &str = "repopatch.db";
+
+/// Whether this build can keep metadata in SQLite.
+pub const ENABLED: bool = cfg!(feature = "sqlite");
+
+/// Schema changes in the order they were made. A database's `user_version`
+/// is the number of them applied, so opening one runs only the new ones.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE records (
+        kind TEXT NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        updated TEXT NOT NULL,
+        PRIMARY KEY (kind, key)
+    )",
+    "CREATE INDEX records_updated ON records (kind, updated)",
+];
+
+/// A stored value and when it was written (RFC 3339, UTC).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub key: String,
+    pub value: Value,
+    pub updated: String,
+}
+
+pub struct Metadata {
+    path: PathBuf,
+    connection: Mutex<sqlite::Connection>,
+}
+
+impl Metadata {
+    /// Opens the database in DATA_DIR; None when it is unset or empty.
+    pub fn from_env() -> Result<Option<Arc<Self>>, String> {
+        match env::var("DATA_DIR") {
+            Ok(dir) if !dir.is_empty() => Metadata::open(Path::new(&dir)).map(|m| Some(Arc::new(m))).map_err(|e| format!("DATA_DIR: {}", e)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Opens or creates the database in `dir`, creating the directory as
+    /// needed, and brings its schema up to date.
+    pub fn open(dir: &Path) -> Result<Self, String> {
+        if !ENABLED {
+            return Err("this server was built without the sqlite feature".to_string());
+        }
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create the data directory {}: {}", dir.display(), e))?;
+        let path = dir.join(DATABASE_FILE);
+        let connection = sqlite::Connection::open(&path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+        // Readers don't wait for writers, and writers wait for each other
+        connection.execute_batch("PRAGMA journal_mode = WAL; PRAGMA busy_timeout = 5000;")?;
+        let metadata = Metadata { path, connection: Mutex::new(connection) };
+        metadata.migrate()?;
+        Ok(metadata)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn lock(&self) -> MutexGuard<'_, sqlite::Connection> {
+        self.connection.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn migrate(&self) -> Result<(), String> {
+        let connection = self.lock();
+        let version = user_version(&connection)?;
+        if version > MIGRATIONS.len() {
+            return Err(format!(
+                "{} has schema version {}, newer than the {} this server knows; upgrade repopatch",
+                self.path.display(),
+                version,
+                MIGRATIONS.len()
+            ));
+        }
+        for (number, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let script = format!("BEGIN IMMEDIATE; {}; PRAGMA user_version = {}; COMMIT;", migration, number + 1);
+            if let Err(e) = connection.execute_batch(&script) {
+                let _ = connection.execute_batch("ROLLBACK");
+                return Err(format!("Migration {} of {} failed: {}", number + 1, self.path.display(), e));
+            }
+        }
+        Ok(())
+    }
+
+    /// The number of migrations applied.
+    pub fn schema_version(&self) -> Result<usize, String> {
+        user_version(&self.lock())
+    }
+
+    /// Every record of `kind`, oldest first.
+    pub fn load(&self, kind: &str) -> Result<Vec<Record>, String> {
+        let rows = self.lock().query("SELECT key, value, updated FROM records WHERE kind = ?1 ORDER BY rowid", &[kind])?;
+        rows.into_iter().map(record).collect()
+    }
+
+    /// Every record of `kind` decoded as `T`, oldest first.
+    pub fn load_as<T: DeserializeOwned>(&self, kind: &str) -> Result<Vec<(String, T)>, String> {
+        self.load(kind)?
+            .into_iter()
+            .map(|r| serde_json::from_value(r.value).map(|value| (r.key, value)).map_err(|e| format!("Invalid {} in {}: {}", kind, self.path.display(), e)))
+            .collect()
+    }
+
+    pub fn get(&self, kind: &str, key: &str) -> Result<Option<Value>, String> {
+        let rows = self.lock().query("SELECT key, value, updated FROM records WHERE kind = ?1 AND key = ?2", &[kind, key])?;
+        Ok(rows.into_iter().next().map(record).transpose()?.map(|r| r.value))
+    }
+
+    /// The records of `kind` whose JSON has `value` at `field`, a path like
+    /// `owner` or `files[0]`.
+    pub fn find(&self, kind: &str, field: &str, value: &str) -> Result<Vec<Record>, String> {
+        let sql = "SELECT key, value, updated FROM records WHERE kind = ?1 AND json_extract(value, ?2) = ?3 ORDER BY rowid";
+        let rows = self.lock().query(sql, &[kind, &format!("$.{}", field), value])?;
+        rows.into_iter().map(record).collect()
+    }
+
+    /// Saves `value` under `key`, replacing what was there.
+    pub fn put(&self, kind: &str, key: &str, value: &impl Serialize) -> Result<(), String> {
+        self.put_all(kind, &[(key.to_string(), value)])
+    }
+
+    /// Saves several values in one transaction.
+    pub fn put_all<T: Serialize>(&self, kind: &str, values: &[(String, T)]) -> Result<(), String> {
+        let encoded = values
+            .iter()
+            .map(|(key, value)| serde_json::to_string(value).map(|json| (key, json)).map_err(|e| format!("Failed to encode {} {}: {}", kind, key, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let connection = self.lock();
+        let updated = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        connection.execute_batch("BEGIN IMMEDIATE")?;
+        for (key, value) in encoded {
+            // A replaced row gets a new rowid, so rowids follow the writes
+            let sql = "INSERT OR REPLACE INTO records (kind, key, value, updated) VALUES (?1, ?2, ?3, ?4)";
+            if let Err(e) = connection.execute(sql, &[kind, key, &value, &updated]) {
+                let _ = connection.execute_batch("ROLLBACK");
+                return Err(e);
+            }
+        }
+        connection.execute_batch("COMMIT")
+    }
+
+    /// Removes a record; false if there was none.
+    pub fn delete(&self, kind: &str, key: &str) -> Result<bool, String> {
+        Ok(self.lock().execute("DELETE FROM records WHERE kind = ?1 AND key = ?2", &[kind, key])? > 0)
+    }
+
+    /// Removes every record of `kind` but the `keep` written last and returns
+    /// how many went.
+    pub fn prune(&self, kind: &str, keep: usize) -> Result<usize, String> {
+        let sql = "DELETE FROM records WHERE kind = ?1 AND rowid NOT IN
+            (SELECT rowid FROM records WHERE kind = ?1 ORDER BY rowid DESC LIMIT CAST(?2 AS INTEGER))";
+        self.lock().execute(sql, &[kind, &keep.to_string()])
+    }
+
+    /// Moves the entries of a JSON file the server kept before into `kind`,
+    /// unless the database already has records of that kind. `entries`
+    /// turns the file's content into keyed values. Returns how many were
+    /// imported; a missing file imports none.
+    pub fn import_file(&self, kind: &str, path: &Path, entries: impl FnOnce(Value) -> Result<Vec<(String, Value)>, String>) -> Result<usize, String> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+        let count = self.lock().query("SELECT count(*) FROM records WHERE kind = ?1", &[kind])?;
+        if count.first().and_then(|row| row.first()).is_some_and(|n| n != "0") {
+            return Ok(0);
+        }
+        let value = serde_json::from_str(&content).map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+        let values = entries(value).map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+        self.put_all(kind, &values)?;
+        Ok(values.len())
+    }
+}
+
+/// Saves `value` as pretty JSON in `path`, the way the server keeps its JSON
+/// files without a database. It is written to a temporary file next to
+/// `path`, named uniquely so concurrent saves don't share one, and renamed
+/// over it, so a crash never leaves half a file.
+pub fn write_json_atomically(path: &Path, value: &impl Serialize) -> Result<(), String> {
+    static SAVES: AtomicU64 = AtomicU64::new(0);
+    let content = serde_json::to_vec_pretty(value).map_err(|e| format!("Failed to encode {}: {}", path.display(), e))?;
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(".{}-{}.tmp", std::process::id(), SAVES.fetch_add(1, Ordering::Relaxed)));
+    let temp = PathBuf::from(temp);
+    fs::write(&temp, content).and_then(|()| fs::rename(&temp, path)).map_err(|e| {
+        let _ = fs::remove_file(&temp);
+        format!("Failed to save {}: {}", path.display(), e)
+    })
+}
+
+/// The members of a JSON object, for [`Metadata::import_file`] when a file
+/// keeps its entries under their keys.
+pub fn object_entries(value: Value) -> Result<Vec<(String, Value)>, String> {
+    match value {
+        Value::Object(members) => Ok(members.into_iter().collect()),
+        _ => Err("expected a JSON object".to_string()),
+    }
+}
+
+fn user_version(connection: &sqlite::Connection) -> Result<usize, String> {
+    let rows = connection.query("PRAGMA user_version", &[])?;
+    Ok(rows.first().and_then(|row| row.first()).and_then(|v| v.parse().ok()).unwrap_or(0))
+}
+
+fn record(row: Vec<String>) -> Result<Record, String> {
+    let [key, value, updated]: [String; 3] = row.try_into().map_err(|_| "Unexpected columns in records".to_string())?;
+    let value = serde_json::from_str(&value).map_err(|e| format!("Invalid record {}: {}", key, e))?;
+    Ok(Record { key, value, updated })
+}
+
+/// The connection, through rusqlite, with every value read as text.
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use rusqlite::types::ValueRef;
+    use rusqlite::OpenFlags;
+    use std::path::Path;
+
+    pub struct Connection(rusqlite::Connection);
+
+    impl Connection {
+        pub fn open(path: &Path) -> Result<Self, String> {
+            let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+            rusqlite::Connection::open_with_flags(path, flags).map(Connection).map_err(|e| e.to_string())
+        }
+
+        /// Runs statements that take no parameters.
+        pub fn execute_batch(&self, sql: &str) -> Result<(), String> {
+            self.0.execute_batch(sql).map_err(|e| e.to_string())
+        }
+
+        /// Runs one statement and returns the number of rows it changed.
+        pub fn execute(&self, sql: &str, params: &[&str]) -> Result<usize, String> {
+            self.0.execute(sql, rusqlite::params_from_iter(params)).map_err(|e| e.to_string())
+        }
+
+        /// Runs one statement and returns its rows, every column as text
+        /// (NULL as empty).
+        pub fn query(&self, sql: &str, params: &[&str]) -> Result<Vec<Vec<String>>, String> {
+            let mut statement = self.0.prepare(sql).map_err(|e| e.to_string())?;
+            let columns = statement.column_count();
+            let rows = statement.query_map(rusqlite::params_from_iter(params), |row| {
+                (0..columns)
+                    .map(|column| {
+                        Ok(match row.get_ref(column)? {
+                            ValueRef::Null => String::new(),
+                            ValueRef::Integer(n) => n.to_string(),
+                            ValueRef::Real(n) => n.to_string(),
+                            ValueRef::Text(text) | ValueRef::Blob(text) => String::from_utf8_lossy(text).into_owned(),
+                        })
+                    })
+                    .collect()
+            });
+            rows.and_then(Iterator::collect).map_err(|e| e.to_string())
+        }
+    }
+}
+
+/// Stands in for the connection in builds without SQLite; it can't be
+/// opened, so the rest is never called.
+#[cfg(not(feature = "sqlite"))]
+mod sqlite {
+    use std::path::Path;
+
+    pub enum Connection {}
+
+    impl Connection {
+        pub fn open(_path: &Path) -> Result<Self, String> {
+            Err("built without the sqlite feature".to_string())
+        }
+
+        pub fn execute_batch(&self, _sql: &str) -> Result<(), String> {
+            match *self {}
+        }
+
+        pub fn execute(&self, _sql: &str, _params: &[&str]) -> Result<usize, String> {
+            match *self {}
+        }
+
+        pub fn query(&self, _sql: &str, _params: &[&str]) -> Result<Vec<Vec<String>>, String> {
+            match *self {}
+        }
+    }
+}
@@ -0,0 +1,336 @@
+//! Moving a file and rewriting the references to it. TypeScript/JavaScript
+//! and Python imports are found with tree-sitter; in other files, quoted
+//! strings naming the file by a relative or root-relative path (C includes,
+//! `include_str!`, HTML attributes and the like) are rewritten. The result
+//! is a patch for review, like [`crate::refactor`]'s.
+
+use crate::diff::{rename_diff, unified_diff};
+use crate::replace::FileReplacement;
+use crate::store::{dir_of, join_path, normalize_path, FileStore};
+use crate::syntax::{self, Language};
+use crate::tree::walk_files;
+use ignore::gitignore::Gitignore;
+use regex::Regex;
+use std::ops::Range;
+
+/// Extensions TypeScript module resolution tries, longest first.
+pub(crate) const TS_EXTENSIONS: &[&str] = &[".d.ts", ".ts", ".tsx", ".mts", ".cts", ".js", ".jsx", ".mjs", ".cjs"];
+/// Larger files are not searched for references.
+const MAX_REFERENCING_FILE_BYTES: u64 = 1024 * 1024;
+
+/// The would-be move.
+#[derive(Debug, Default)]
+pub struct MovePlan {
+    /// Files whose references were rewritten and how many; the moved file is
+    /// listed under its new path when its own relative references changed.
+    pub files: Vec<FileReplacement>,
+    /// The rename and the rewritten references, with `a/` and `b/` prefixes.
+    pub patch: String,
+    /// References to the file that could not be rewritten, as `path:line: text`.
+    pub unresolved: Vec<String>,
+    /// What the plan leaves to the caller.
+    pub notes: Vec<String>,
+}
+
+/// Plans moving `from` to `to`, rewriting references to it across the store
+/// when `update_references` is set.
+pub fn move_file(store: &dyn FileStore, ig: &Gitignore, from: &str, to: &str, update_references: bool) -> Result<MovePlan, String> {
+    store.validate(from)?;
+    store.validate(to)?;
+    if normalize_path(from) == normalize_path(to) {
+        return Err("The source and destination are the same path".to_string());
+    }
+    let data = store.read(from).map_err(|e| format!("Failed to read {}: {}", from, e))?;
+    if store.exists(to) || store.list_dir(to).is_ok() {
+        return Err(format!("{} already exists", to));
+    }
+    let mut plan = MovePlan::default();
+    let moved_text = String::from_utf8(data).ok();
+    let mut moved_new = moved_text.clone();
+
+    if update_references {
+        let strings = Regex::new(r#""([^"\n]{1,300})"|'([^'\n]{1,300})'|<([^<>\s]{1,300})>"#).map_err(|e| e.to_string())?;
+        let needles = needles(from);
+        let mut candidates = Vec::new();
+        walk_files(store, "", ig, &mut |path| {
+            if path != from && store.file_size(path).is_none_or(|size| size <= MAX_REFERENCING_FILE_BYTES) {
+                candidates.push(path.to_string());
+            }
+        })?;
+        for path in candidates {
+            let Some(source) = store.read(&path).ok().and_then(|data| String::from_utf8(data).ok()) else {
+                continue;
+            };
+            if !needles.iter().any(|n| source.contains(n.as_str())) {
+                continue;
+            }
+            let referrer = Referrer { store, strings: &strings, from, to, old_path: &path, new_path: &path };
+            let (rewritten, count) = referrer.rewrite(&source, &mut plan.unresolved)?;
+            if count > 0 {
+                plan.patch.push_str(&unified_diff(&path, &source, &rewritten));
+                plan.files.push(FileReplacement { path, matches: count });
+            }
+        }
+        // The moved file's own relative references now start from elsewhere
+        if let Some(source) = &moved_text {
+            let referrer = Referrer { store, strings: &strings, from, to, old_path: from, new_path: to };
+            let (rewritten, count) = referrer.rewrite(source, &mut plan.unresolved)?;
+            if count > 0 {
+                moved_new = Some(rewritten);
+                plan.files.push(FileReplacement { path: to.to_string(), matches: count });
+            }
+        }
+        if Language::from_path(from) == Some(Language::Rust) {
+            plan.notes.push("Rust mod declarations and use paths are not updated".to_string());
+        }
+    }
+
+    let rename = match (&moved_text, &moved_new) {
+        (Some(old), Some(new)) => rename_diff(from, to, old, new),
+        _ => rename_diff(from, to, "", ""),
+    };
+    plan.patch.insert_str(0, &rename);
+    Ok(plan)
+}
+
+// Strings a file has to contain to possibly refer to `path`
+fn needles(path: &str) -> Vec<String> {
+    let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let stem = name.split('.').next().unwrap_or(name);
+    let mut needles = vec![stem.to_string()];
+    // index.ts and __init__.py are usually imported through their folder
+    if matches!(stem, "index" | "__init__") && !dir.is_empty() {
+        needles.push(dir.rsplit('/').next().unwrap_or(dir).to_string());
+    }
+    needles
+}
+
+/// A file that may refer to the moved one, at its location before and after
+/// the move (the same unless it is the moved file).
+struct Referrer<'a> {
+    store: &'a dyn FileStore,
+    strings: &'a Regex,
+    from: &'a str,
+    to: &'a str,
+    old_path: &'a str,
+    new_path: &'a str,
+}
+
+impl Referrer<'_> {
+    fn is_moved(&self) -> bool {
+        self.old_path != self.new_path
+    }
+
+    // Returns the rewritten source and the number of references changed
+    fn rewrite(&self, source: &str, unresolved: &mut Vec<String>) -> Result<(String, usize), String> {
+        let mut edits: Vec<(Range<usize>, String)> = Vec::new();
+        match Language::from_path(self.old_path) {
+            Some(language @ (Language::TypeScript | Language::Tsx)) => {
+                let tree = syntax::parse(language, source)?;
+                for import in syntax::imports(language, &tree, source) {
+                    if let Some(specifier) = self.ts_specifier(&source[import.module.clone()]) {
+                        edits.push((import.module, specifier));
+                    }
+                }
+            }
+            Some(Language::Python) => {
+                let tree = syntax::parse(Language::Python, source)?;
+                for import in syntax::imports(Language::Python, &tree, source) {
+                    match self.py_import(source, &import) {
+                        Ok(Some(module)) => edits.push((import.module, module)),
+                        Ok(None) => {}
+                        Err(()) => unresolved.push(describe(self.new_path, source, import.module.start)),
+                    }
+                }
+            }
+            _ => {
+                for captures in self.strings.captures_iter(source) {
+                    let Some(spec) = captures.iter().skip(1).flatten().next() else { continue };
+                    if let Some(path) = self.path_reference(spec.as_str()) {
+                        edits.push((spec.range(), path));
+                    }
+                }
+            }
+        }
+        let count = edits.len();
+        let mut out = String::with_capacity(source.len());
+        let mut last = 0;
+        for (range, replacement) in edits {
+            out.push_str(&source[last..range.start]);
+            out.push_str(&replacement);
+            last = range.end;
+        }
+        out.push_str(&source[last..]);
+        Ok((out, count))
+    }
+
+    // The new specifier for a relative TypeScript import, if it changes
+    fn ts_specifier(&self, spec: &str) -> Option<String> {
+        if !(spec == "." || spec == ".." || spec.starts_with("./") || spec.starts_with("../")) {
+            return None;
+        }
+        let target = normalize_path(&join_path(dir_of(self.old_path), spec))?;
+        let new_target = if target == self.from {
+            self.to.to_string()
+        } else if TS_EXTENSIONS.iter().any(|e| format!("{}{}", target, e) == self.from || join_path(&target, &format!("index{}", e)) == self.from) {
+            // Extensionless imports stay extensionless and reach index files through their folder
+            let stripped = strip_ts_extension(self.to);
+            match stripped.rsplit_once('/') {
+                Some((dir, "index")) => dir.to_string(),
+                None if stripped == "index" => String::new(),
+                _ => stripped.to_string(),
+            }
+        } else if self.is_moved() {
+            target
+        } else {
+            return None;
+        };
+        let relative = relative_path(dir_of(self.new_path), &new_target);
+        let specifier = if relative.is_empty() {
+            ".".to_string()
+        } else if relative == ".." || relative.starts_with("../") {
+            relative
+        } else {
+            format!("./{}", relative)
+        };
+        (specifier != spec).then_some(specifier)
+    }
+
+    // The new module for a Python import if it changes, or Err when the
+    // import refers to the moved file in a way that can't be rewritten
+    fn py_import(&self, source: &str, import: &syntax::Import) -> Result<Option<String>, ()> {
+        let module = &source[import.module.clone()];
+        let (Some(old_parts), Some(new_parts)) = (module_parts(self.from), module_parts(self.to)) else {
+            return Ok(None);
+        };
+        if let Some(style) = self.py_match(module, &old_parts) {
+            return self.py_module(style, &old_parts, &new_parts).map(|m| (m != module).then_some(m)).ok_or(());
+        }
+        // `from package import module`
+        for name in &import.names {
+            let combined = if module.ends_with('.') { format!("{}{}", module, &source[name.clone()]) } else { format!("{}.{}", module, &source[name.clone()]) };
+            if let Some(style) = self.py_match(&combined, &old_parts) {
+                if import.names.len() != 1 || old_parts.last() != new_parts.last() {
+                    return Err(());
+                }
+                let parent = &new_parts[..new_parts.len() - 1];
+                return self.py_module(style, &old_parts, parent).filter(|m| !m.is_empty()).map(|m| (m != module).then_some(m)).ok_or(());
+            }
+        }
+        if self.is_moved() && module.starts_with('.') {
+            let Some(target) = resolve_relative(self.old_path, module) else {
+                return Ok(None);
+            };
+            let new_module = relative_module(self.new_path, &target.iter().map(String::as_str).collect::<Vec<_>>());
+            return Ok((new_module != module).then_some(new_module));
+        }
+        Ok(None)
+    }
+
+    // How `module`, imported from this file, names the module `parts`
+    fn py_match(&self, module: &str, parts: &[&str]) -> Option<PyStyle> {
+        if module.starts_with('.') {
+            let resolved = resolve_relative(self.old_path, module)?;
+            return (resolved == parts).then_some(PyStyle::Relative);
+        }
+        let names: Vec<&str> = module.split('.').collect();
+        if !parts.ends_with(&names) {
+            return None;
+        }
+        let prefix = parts.len() - names.len();
+        // A bare name only counts from the script's own folder or the root
+        let prefix_dir = parts[..prefix].join("/");
+        let visible = names.len() > 1 || prefix == 0 || self.old_path.starts_with(&format!("{}/", prefix_dir));
+        visible.then_some(PyStyle::Absolute { prefix })
+    }
+
+    // Spells the module `target` in the style the import used
+    fn py_module(&self, style: PyStyle, old_parts: &[&str], target: &[&str]) -> Option<String> {
+        match style {
+            PyStyle::Relative => Some(relative_module(self.new_path, target)),
+            PyStyle::Absolute { prefix } => {
+                let rest = target.strip_prefix(&old_parts[..prefix])?;
+                (!rest.is_empty()).then(|| rest.join("."))
+            }
+        }
+    }
+
+    // The new text of a quoted path in a file without import support
+    fn path_reference(&self, spec: &str) -> Option<String> {
+        if spec.is_empty() || spec.starts_with('/') || spec.contains("://") || spec.contains(char::is_whitespace) {
+            return None;
+        }
+        let resolved = normalize_path(&join_path(dir_of(self.old_path), spec));
+        let target = if resolved.as_deref() == Some(self.from) {
+            self.to.to_string()
+        } else if spec == self.from {
+            return Some(self.to.to_string());
+        } else if self.is_moved() && resolved.as_deref().is_some_and(|r| self.store.exists(r)) {
+            resolved?
+        } else {
+            return None;
+        };
+        let relative = relative_path(dir_of(self.new_path), &target);
+        let relative = if spec.starts_with("./") && !relative.starts_with("../") { format!("./{}", relative) } else { relative };
+        (relative != spec).then_some(relative)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum PyStyle {
+    /// `from .module import x`
+    Relative,
+    /// `import pkg.module`, where the first `prefix` folders of the file's
+    /// path are not part of the module name (a `src/` layout, say).
+    Absolute { prefix: usize },
+}
+
+// Helper function to give a reference's location for the unresolved list
+fn describe(path: &str, source: &str, offset: usize) -> String {
+    let line = source[..offset].matches('\n').count() + 1;
+    let text = source.lines().nth(line - 1).unwrap_or_default().trim();
+    format!("{}:{}: {}", path, line, text)
+}
+
+// The path of `target` relative to the folder `dir`, both store paths
+fn relative_path(dir: &str, target: &str) -> String {
+    let dir: Vec<&str> = dir.split('/').filter(|c| !c.is_empty()).collect();
+    let target: Vec<&str> = target.split('/').filter(|c| !c.is_empty()).collect();
+    let common = dir.iter().zip(&target).take_while(|(a, b)| a == b).count();
+    let mut parts = vec![".."; dir.len() - common];
+    parts.extend(&target[common..]);
+    parts.join("/")
+}
+
+fn strip_ts_extension(path: &str) -> &str {
+    TS_EXTENSIONS.iter().find_map(|e| path.strip_suffix(e)).unwrap_or(path)
+}
+
+// The Python module a store path defines, as its dotted components
+fn module_parts(path: &str) -> Option<Vec<&str>> {
+    let stem = path.strip_suffix(".py").or_else(|| path.strip_suffix(".pyi"))?;
+    let mut parts: Vec<&str> = stem.split('/').collect();
+    if parts.last() == Some(&"__init__") {
+        parts.pop();
+    }
+    Some(parts)
+}
+
+// Resolves a relative module such as `..pkg.mod` imported from `importer`
+pub(crate) fn resolve_relative(importer: &str, module: &str) -> Option<Vec<String>> {
+    let rest = module.trim_start_matches('.');
+    let dots = module.len() - rest.len();
+    let mut parts: Vec<String> = dir_of(importer).split('/').filter(|c| !c.is_empty()).map(String::from).collect();
+    for _ in 1..dots {
+        parts.pop()?;
+    }
+    parts.extend(rest.split('.').filter(|c| !c.is_empty()).map(String::from));
+    Some(parts)
+}
+
+// Spells the module `target` relative to the package of `importer`
+fn relative_module(importer: &str, target: &[&str]) -> String {
+    let package: Vec<&str> = dir_of(importer).split('/').filter(|c| !c.is_empty()).collect();
+    let common = package.iter().zip(target).take_while(|(a, b)| a == b).count();
+    format!("{}{}", ".".repeat(1 + package.len() - common), target[common..].join("."))
+}
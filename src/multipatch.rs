@@ -0,0 +1,346 @@
+// Patches spanning several workspaces: POST /api/apply_patches carries one
+// patch per registered workspace, keyed by its alias, for changes that touch
+// e.g. a frontend and a backend repository at once. Each workspace is its own
+// transaction: its patch is dry-run first and only written when every hunk
+// fits, and a write that still fails partway is rolled back, so a workspace
+// ends up with all of its patch or none of it. One workspace failing doesn't
+// stop the others; the response has the report of each, as /api/apply_patch
+// returns it, and is only successful when every workspace applied.
+//
+// Policy rules that need a confirmation reject a workspace's patch, since no
+// token can be sent here; rules that need a dry run are met by sending the
+// same batch with dryRun: true first.
+
+use crate::activity;
+use crate::admin;
+use crate::approvals;
+use crate::auth::{Session, Stores};
+use crate::cli::precheck;
+use crate::history;
+use crate::memory;
+use crate::usage;
+use crate::validation::ValidJson;
+use crate::{apply_options, apply_result, revisions};
+use actix_web::{post, web, HttpResponse};
+use repopatch::diff::FileChange;
+use repopatch::engine::{EngineSelection, PatchEngine};
+use repopatch::patch::{self, ApplyOptions, ApplyOutcome, PatchSet};
+use repopatch::policy::{Action, Policy};
+use repopatch::remap::PathRewrites;
+use repopatch::store::{AliasStoreProvider, FileStore};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+
+/// Most workspaces one request may patch.
+pub const MAX_WORKSPACES: usize = 20;
+
+#[derive(Deserialize)]
+pub struct WorkspacePatch {
+    #[serde(rename = "patchContent", default)]
+    patch_content: String,
+    #[serde(rename = "contextLines")]
+    context_lines: Option<usize>,
+    /// Patch engine to use instead of the workspace's
+    engine: Option<String>,
+    /// Revisions the files were read at; stale files fail the workspace
+    #[serde(rename = "ifRevision", default)]
+    if_revision: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+pub struct ApplyPatchesRequest {
+    /// Patches by workspace alias, without the `@`
+    patches: BTreeMap<String, WorkspacePatch>,
+    #[serde(rename = "dryRun", default)]
+    dry_run: bool,
+}
+
+// What every workspace of a request is applied with
+struct Batch<'a> {
+    stores: &'a Stores,
+    workspaces: &'a AliasStoreProvider,
+    policy: web::Data<Policy>,
+    rewrites: web::Data<PathRewrites>,
+    approvals: &'a approvals::Approvals,
+    engines: &'a EngineSelection,
+    usage: &'a usage::Usage,
+    identity: String,
+    dry_run: bool,
+}
+
+// Actix handlers take their dependencies as extractors
+#[allow(clippy::too_many_arguments)]
+#[post("/api/apply_patches")]
+pub async fn apply_patches(
+    body: ValidJson<ApplyPatchesRequest>,
+    stores: Stores,
+    session: Option<web::ReqData<Session>>,
+    workspaces: web::Data<AliasStoreProvider>,
+    runtime: web::Data<admin::Runtime>,
+    policy: web::Data<Policy>,
+    rewrites: web::Data<PathRewrites>,
+    approvals: web::Data<approvals::Approvals>,
+    history: web::Data<history::History>,
+    activity: web::Data<activity::Activity>,
+    usage: web::Data<usage::Usage>,
+    engines: web::Data<EngineSelection>,
+    memory: web::Data<memory::MemoryBudget>,
+) -> HttpResponse {
+    let _job = admin::Runtime::start_job(&runtime);
+    if body.patches.is_empty() {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": "Send at least one workspace in patches", "workspaces": {} }));
+    }
+    if body.patches.len() > MAX_WORKSPACES {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": format!("At most {} workspaces can be patched in one request", MAX_WORKSPACES),
+            "workspaces": {}
+        }));
+    }
+    let queued = body.patches.values().map(|p| p.patch_content.len() as u64).sum();
+    let _queued = match memory.into_inner().reserve(memory::Pool::Patches, queued) {
+        Ok(reservation) => reservation,
+        Err(overloaded) => return overloaded.response(),
+    };
+
+    let session = session.as_deref();
+    let batch = Batch {
+        stores: &stores,
+        workspaces: &workspaces,
+        policy,
+        rewrites,
+        approvals: &approvals,
+        engines: &engines,
+        usage: &usage,
+        identity: usage::identity(session),
+        dry_run: body.dry_run,
+    };
+    let mut reports = serde_json::Map::new();
+    for (id, request) in &body.patches {
+        let (mut report, root, changes) = apply_workspace(&batch, id, request).await;
+        if let (Some(root), Some(changes)) = (root, changes) {
+            let files: Vec<String> = changes.iter().map(|c| c.path.clone()).collect();
+            let report_id = history.record(root.clone(), changes);
+            usage.add(&batch.identity, usage::Counters { patches_applied: 1, ..Default::default() });
+            let summary = format!("Applied a patch to {} files of a {}-workspace change", files.len(), body.patches.len());
+            let event = activity::Event::new("patch", "apply_patches", summary).files(files).report(report_id.clone());
+            activity.record(&root, session, event);
+            report["reportId"] = json!(report_id);
+        }
+        report["workspace"] = json!(format!("@{}", id));
+        reports.insert(id.clone(), report);
+    }
+
+    let success = reports.values().all(|r| r["success"] == true);
+    let response = json!({ "success": success, "dryRun": body.dry_run, "workspaces": reports });
+    if success {
+        HttpResponse::Ok().json(response)
+    } else {
+        HttpResponse::InternalServerError().json(response)
+    }
+}
+
+// Applies one workspace's patch, returning its report and, when files were
+// written, the workspace root and the changes to record
+async fn apply_workspace(batch: &Batch<'_>, id: &str, request: &WorkspacePatch) -> (serde_json::Value, Option<String>, Option<Vec<FileChange>>) {
+    let failure = |error: String| (json!({ "success": false, "error": error, "appliedFiles": [], "details": [] }), None, None);
+    if !batch.workspaces.aliases().contains_key(id) {
+        return failure(format!("Unknown workspace {}; /api/workspaces lists them", id));
+    }
+    let store = match batch.stores.open_root(&format!("@{}", id)) {
+        Ok(s) => s,
+        Err(e) => return failure(e),
+    };
+    let patch_content = request.patch_content.trim().to_string();
+    if patch_content.is_empty() {
+        return failure("Patch content cannot be empty".to_string());
+    }
+    let root = store.display_path("");
+    let engine = match batch.engines.select(request.engine.as_deref(), &root) {
+        Ok(engine) => engine,
+        Err(e) => return failure(e),
+    };
+    let dry_run = batch.dry_run;
+    if !dry_run {
+        if let Err(exceeded) = batch.usage.check_patch(&batch.identity).and_then(|_| batch.usage.check_write(&batch.identity, 0)) {
+            let (mut report, ..) = failure(exceeded.message);
+            report["code"] = json!("QUOTA_EXCEEDED");
+            return (report, None, None);
+        }
+    }
+    // Dry runs are only stopped by denying rules; rules needing a dry run are
+    // met by an earlier dry run of the same patch
+    let fingerprint = approvals::fingerprint(&root, &patch_content);
+    let mut blocking = vec![Action::Deny];
+    if !dry_run {
+        blocking.push(Action::Confirm);
+        if !batch.approvals.has_dry_run(fingerprint) {
+            blocking.push(Action::DryRun);
+        }
+    }
+
+    let options = apply_options(request.context_lines.unwrap_or(patch::DEFAULT_CONTEXT_LINES));
+    let expected = request.if_revision.clone();
+    let (policy, rewrites) = (batch.policy.clone(), batch.rewrites.clone());
+    let task_store = store.clone();
+    let applied = web::block(move || {
+        let mut patch_set = engine.parse(&patch_content, 1);
+        let mut extra = match precheck(&*task_store, &mut patch_set, &rewrites, &policy, &blocking).into_result() {
+            Ok(extra) => extra,
+            Err(report) => return (report, None),
+        };
+        extra["engine"] = json!(engine.name());
+        let stale = patch::check_revisions(&*task_store, &expected);
+        if !stale.is_empty() {
+            return (json!({ "success": false, "error": "Some files changed since they were read.", "appliedFiles": [], "details": stale }), None);
+        }
+        let outcome = engine.preview(&patch_set, &*task_store, &options).outcome;
+        if dry_run || !outcome.is_success() {
+            extra["dryRun"] = json!(true);
+            extra["message"] = json!(if outcome.is_success() {
+                "Dry run: the patch applies cleanly. Nothing was written."
+            } else {
+                "The patch does not apply cleanly. Nothing was written."
+            });
+            return (apply_result(outcome, extra), None);
+        }
+
+        let (outcome, before, unrestored) = apply_or_roll_back(&*engine, &patch_set, &*task_store, &options);
+        if !outcome.is_success() {
+            let mut report = apply_result(outcome, extra);
+            report["rolledBack"] = std::mem::replace(&mut report["appliedFiles"], json!([]));
+            if !unrestored.is_empty() {
+                log::error!("Could not roll back {} in {}", unrestored.join(", "), task_store.display_path(""));
+                report["notRolledBack"] = json!(unrestored);
+            }
+            return (report, None);
+        }
+        let before = before.into_iter().map(|(path, data)| (path, data.map(|data| String::from_utf8_lossy(&data).into_owned()))).collect();
+        let changes = history::changes(&*task_store, &patch_set, &outcome, &before);
+        extra["revisions"] = revisions(&*task_store, &outcome.applied_files);
+        (apply_result(outcome, extra), Some(changes))
+    })
+    .await;
+    let (report, changes) = match applied {
+        Ok(applied) => applied,
+        Err(e) => return failure(format!("Patch task failed: {}", e)),
+    };
+    if dry_run && report["success"] == true {
+        batch.approvals.record_dry_run(fingerprint);
+    }
+    (report, changes.is_some().then_some(root), changes)
+}
+
+// Applies `patch_set`, putting every file back as it was when the write
+// fails partway. Returns the outcome, what the patched files held before and
+// the files that could not be restored.
+fn apply_or_roll_back(
+    engine: &dyn PatchEngine,
+    patch_set: &PatchSet,
+    store: &dyn FileStore,
+    options: &ApplyOptions,
+) -> (ApplyOutcome, HashMap<String, Option<Vec<u8>>>, Vec<String>) {
+    // Raw bytes, as files that aren't valid UTF-8 must come back unchanged
+    let before: HashMap<String, Option<Vec<u8>>> = patch_set
+        .files
+        .iter()
+        .flat_map(|f| f.old_path.iter().chain(f.new_path.iter()))
+        .map(|path| (path.clone(), store.read(path).ok()))
+        .collect();
+    let outcome = engine.apply(patch_set, store, options);
+    let unrestored = if outcome.is_success() { Vec::new() } else { rollback(store, &before) };
+    (outcome, before, unrestored)
+}
+
+// Puts back every file of `before` that changed since it was taken; returns
+// the files that could not be restored
+fn rollback(store: &dyn FileStore, before: &HashMap<String, Option<Vec<u8>>>) -> Vec<String> {
+    let mut unrestored = Vec::new();
+    for (path, old) in before {
+        if store.read(path).ok() == *old {
+            continue;
+        }
+        let restored = match old {
+            Some(content) => store.write(path, content),
+            None => store.remove(path),
+        };
+        if restored.is_err() {
+            unrestored.push(path.clone());
+        }
+    }
+    unrestored.sort();
+    unrestored
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use repopatch::engine;
+    use repopatch::store::{DirEntry, MemoryStore};
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // A store failing its second write, as when a disk briefly fills up
+    struct FlakyStore {
+        inner: MemoryStore,
+        writes: AtomicUsize,
+    }
+
+    impl FileStore for FlakyStore {
+        fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+            self.inner.read(path)
+        }
+
+        fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+            if self.writes.fetch_add(1, Ordering::SeqCst) == 1 {
+                return Err(io::Error::other("No space left on device"));
+            }
+            self.inner.write(path, data)
+        }
+
+        fn remove(&self, path: &str) -> io::Result<()> {
+            self.inner.remove(path)
+        }
+
+        fn exists(&self, path: &str) -> bool {
+            self.inner.exists(path)
+        }
+
+        fn list_dir(&self, path: &str) -> io::Result<Vec<DirEntry>> {
+            self.inner.list_dir(path)
+        }
+    }
+
+    #[test]
+    fn a_write_failing_partway_puts_back_what_was_written() {
+        let store = FlakyStore {
+            inner: MemoryStore::from_files([("a.txt", "one\r\nold\r\n"), ("b.txt", "old\n")]),
+            writes: AtomicUsize::new(0),
+        };
+        let diff = "--- a/a.txt\n+++ b/a.txt\n@@ -2 +2 @@\n-old\n+new\n--- a/b.txt\n+++ b/b.txt\n@@ -1 +1 @@\n-old\n+new\n";
+        let engine = engine::by_name("dmp").unwrap();
+        let patch_set = engine.parse(diff, 1);
+
+        let (outcome, before, unrestored) = apply_or_roll_back(&*engine, &patch_set, &store, &ApplyOptions::default());
+        assert_eq!(outcome.applied_files, ["a.txt"]);
+        assert!(!outcome.is_success());
+        assert!(unrestored.is_empty(), "{:?}", unrestored);
+        assert_eq!(before["a.txt"].as_deref(), Some(&b"one\r\nold\r\n"[..]));
+        assert_eq!(store.read("a.txt").unwrap(), b"one\r\nold\r\n");
+        assert_eq!(store.read("b.txt").unwrap(), b"old\n");
+    }
+
+    #[test]
+    fn rollback_restores_bytes_that_are_not_text() {
+        let binary = vec![0x89, b'P', b'N', b'G', 0xff, 0xfe, 0x00];
+        let store = MemoryStore::from_files([("logo.png", binary.clone())]);
+        let before = HashMap::from([("logo.png".to_string(), Some(binary.clone())), ("new.txt".to_string(), None)]);
+        store.write("logo.png", b"overwritten").unwrap();
+        store.write("new.txt", b"created").unwrap();
+
+        assert!(rollback(&store, &before).is_empty());
+        assert_eq!(store.read("logo.png").unwrap(), binary);
+        assert!(!store.exists("new.txt"));
+    }
+}
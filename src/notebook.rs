@@ -0,0 +1,294 @@
+//! Jupyter notebooks as plain source. A notebook's JSON buries its code in
+//! quoted line arrays between outputs and metadata, which wastes a prompt's
+//! tokens and is hard to patch. Its source view lists the cells one after
+//! another, each under a `# %% [code] id=...` marker as in the percent
+//! format editors use, without outputs. Patches made against the view are
+//! applied to it and the result is turned back into the notebook: cells keep
+//! their metadata by id, edited code cells lose their stale outputs, and
+//! cells added without an id are created.
+
+use crate::patch::{revision, PatchSet};
+use crate::store::{DirEntry, FileStore};
+use serde_json::{json, Map, Value};
+use std::collections::HashSet;
+use std::io;
+use std::time::SystemTime;
+
+const MARKER: &str = "# %% [";
+const CELL_TYPES: &[&str] = &["code", "markdown", "raw"];
+
+/// Whether `path` names a notebook.
+pub fn is_notebook(path: &str) -> bool {
+    path.rsplit_once('.').is_some_and(|(_, ext)| ext.eq_ignore_ascii_case("ipynb"))
+}
+
+/// The source view of a notebook. Fails for files that aren't notebooks and
+/// for notebooks with a cell line that would read as a marker.
+pub fn source_view(notebook: &str) -> Result<String, String> {
+    let notebook: Value = serde_json::from_str(notebook).map_err(|e| format!("Not a notebook: {}", e))?;
+    let cells = notebook["cells"].as_array().ok_or("Not a notebook: no cells")?;
+    let mut view = String::new();
+    for (index, cell) in cells.iter().enumerate() {
+        let source = cell_source(cell);
+        if source.lines().any(|line| line.starts_with(MARKER)) {
+            return Err(format!("Cell {} has a line starting with {}, which the source view uses for cell markers", index + 1, MARKER));
+        }
+        if index > 0 {
+            view.push('\n');
+        }
+        view.push_str(&format!("{}{}] id={}\n", MARKER, cell["cell_type"].as_str().unwrap_or("code"), cell_key(cell, index)));
+        view.push_str(&source);
+        if !source.is_empty() && !source.ends_with('\n') {
+            view.push('\n');
+        }
+    }
+    Ok(view)
+}
+
+/// The language of a notebook's code cells, from its kernel.
+pub fn language(notebook: &str) -> Option<String> {
+    let notebook: Value = serde_json::from_str(notebook).ok()?;
+    let metadata = &notebook["metadata"];
+    metadata["language_info"]["name"].as_str().or(metadata["kernelspec"]["language"].as_str()).map(str::to_lowercase)
+}
+
+/// The notebook `view` describes, keeping what it doesn't show from
+/// `notebook`, or a new notebook when there is none. `notebook` comes back
+/// as it is when the view leaves its cells unchanged.
+pub fn apply_view(notebook: Option<&str>, view: &str) -> Result<String, String> {
+    let mut original: Value = match notebook {
+        Some(text) => serde_json::from_str(text).map_err(|e| format!("Not a notebook: {}", e))?,
+        None => json!({ "cells": [], "metadata": {}, "nbformat": 4, "nbformat_minor": 5 }),
+    };
+    let old_cells: Vec<Value> = original["cells"].as_array().cloned().unwrap_or_default();
+    let with_ids = original["nbformat"].as_u64().unwrap_or(4) > 4 || original["nbformat_minor"].as_u64().unwrap_or(0) >= 5;
+    let mut used = HashSet::new();
+    let mut cells = Vec::new();
+    for (kind, key, source) in parse_view(view)? {
+        let existing = key
+            .filter(|key| !used.contains(key))
+            .and_then(|key| old_cells.iter().enumerate().find(|(i, cell)| cell_key(cell, *i) == key))
+            .filter(|(_, cell)| cell["cell_type"].as_str() == Some(kind.as_str()));
+        let cell = match existing {
+            Some((index, cell)) => {
+                used.insert(cell_key(cell, index));
+                if cell_source(cell).trim_end_matches('\n') == source {
+                    cell.clone()
+                } else {
+                    let mut cell = cell.clone();
+                    cell["source"] = source_lines(&source);
+                    if kind == "code" {
+                        cell["outputs"] = json!([]);
+                        cell["execution_count"] = Value::Null;
+                    }
+                    cell
+                }
+            }
+            None => new_cell(&kind, &source, with_ids.then(|| new_id(&source, cells.len()))),
+        };
+        cells.push(cell);
+    }
+    if cells == old_cells {
+        if let Some(text) = notebook {
+            return Ok(text.to_string());
+        }
+    }
+    original["cells"] = Value::Array(cells);
+    Ok(to_notebook_json(&original))
+}
+
+/// The notebooks of `patch_set` whose hunks were made against the source
+/// view rather than the JSON: more of their context and removed lines are
+/// lines of the view, or, for new notebooks, they start with a marker.
+pub fn view_paths(patch_set: &PatchSet, store: &dyn FileStore) -> HashSet<String> {
+    let mut paths = HashSet::new();
+    for file in &patch_set.files {
+        if !is_notebook(file.path()) && !file.new_path.as_deref().is_some_and(is_notebook) {
+            continue;
+        }
+        let against_view = if file.is_creation() {
+            file.added_lines().first().is_some_and(|(_, line)| line.starts_with(MARKER))
+        } else {
+            let Some(raw) = store.read(file.path()).ok().and_then(|data| String::from_utf8(data).ok()) else { continue };
+            let Ok(view) = source_view(&raw) else { continue };
+            let old_lines = file.old_lines();
+            let view_lines: HashSet<&str> = view.lines().collect();
+            let raw_lines: HashSet<&str> = raw.lines().collect();
+            let in_view = old_lines.iter().filter(|line| view_lines.contains(*line)).count();
+            let in_raw = old_lines.iter().filter(|line| raw_lines.contains(*line)).count();
+            in_view > in_raw
+        };
+        if against_view {
+            paths.extend(file.old_path.iter().chain(file.new_path.iter()).cloned());
+        }
+    }
+    paths
+}
+
+/// A store showing the notebooks at `paths` as their source view, and
+/// turning what is written to them back into notebooks.
+pub struct NotebookView<'a> {
+    base: &'a dyn FileStore,
+    paths: HashSet<String>,
+}
+
+impl<'a> NotebookView<'a> {
+    pub fn new(base: &'a dyn FileStore, paths: HashSet<String>) -> Self {
+        NotebookView { base, paths }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl FileStore for NotebookView<'_> {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let data = self.base.read(path)?;
+        if !self.paths.contains(path) {
+            return Ok(data);
+        }
+        let text = String::from_utf8(data).map_err(|e| invalid(e.to_string()))?;
+        source_view(&text).map(String::into_bytes).map_err(invalid)
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        if !self.paths.contains(path) {
+            return self.base.write(path, data);
+        }
+        let view = std::str::from_utf8(data).map_err(|e| invalid(e.to_string()))?;
+        let existing = match self.base.read(path) {
+            Ok(data) => Some(String::from_utf8(data).map_err(|e| invalid(e.to_string()))?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let notebook = apply_view(existing.as_deref(), view).map_err(invalid)?;
+        self.base.write(path, notebook.as_bytes())
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        self.base.remove(path)
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        self.base.remove_dir(path)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.base.exists(path)
+    }
+
+    fn list_dir(&self, path: &str) -> io::Result<Vec<DirEntry>> {
+        self.base.list_dir(path)
+    }
+
+    fn display_path(&self, path: &str) -> String {
+        self.base.display_path(path)
+    }
+
+    fn validate(&self, path: &str) -> Result<(), String> {
+        self.base.validate(path)
+    }
+
+    fn check_writable(&self, path: &str) -> Result<(), String> {
+        self.base.check_writable(path)
+    }
+
+    fn file_size(&self, path: &str) -> Option<u64> {
+        self.base.file_size(path)
+    }
+
+    fn modified(&self, path: &str) -> Option<SystemTime> {
+        self.base.modified(path)
+    }
+
+    fn available_space(&self, path: &str) -> Option<u64> {
+        self.base.available_space(path)
+    }
+
+    fn file_id(&self, path: &str) -> Option<(u64, u64)> {
+        self.base.file_id(path)
+    }
+}
+
+// A cell's source, which nbformat allows as one string or a list of lines
+fn cell_source(cell: &Value) -> String {
+    match &cell["source"] {
+        Value::String(source) => source.clone(),
+        Value::Array(lines) => lines.iter().filter_map(Value::as_str).collect(),
+        _ => String::new(),
+    }
+}
+
+// The id a cell is shown under: its own, or its position for notebooks
+// older than cell ids
+fn cell_key(cell: &Value, index: usize) -> String {
+    cell["id"].as_str().map_or_else(|| format!("#{}", index + 1), str::to_string)
+}
+
+// The cells of a view as their type, shown id and source
+fn parse_view(view: &str) -> Result<Vec<(String, Option<String>, String)>, String> {
+    let mut cells: Vec<(String, Option<String>, Vec<&str>)> = Vec::new();
+    for (number, line) in view.lines().enumerate() {
+        if let Some(rest) = line.strip_prefix(MARKER) {
+            let (kind, rest) = rest.split_once(']').ok_or_else(|| format!("Line {}: a cell marker needs a closing ]", number + 1))?;
+            if !CELL_TYPES.contains(&kind) {
+                return Err(format!("Line {}: unknown cell type {}: use {}", number + 1, kind, CELL_TYPES.join(", ")));
+            }
+            let key = rest.split_whitespace().find_map(|part| part.strip_prefix("id=")).map(str::to_string);
+            cells.push((kind.to_string(), key, Vec::new()));
+        } else {
+            match cells.last_mut() {
+                Some((_, _, lines)) => lines.push(line),
+                None if line.trim().is_empty() => {}
+                None => return Err(format!("Line {}: the source view must start with a cell marker such as {}code]", number + 1, MARKER)),
+            }
+        }
+    }
+    let count = cells.len();
+    Ok(cells
+        .into_iter()
+        .enumerate()
+        .map(|(index, (kind, key, mut lines))| {
+            // The blank line separating a cell from the next one
+            if index + 1 < count && lines.last() == Some(&"") {
+                lines.pop();
+            }
+            (kind, key, lines.join("\n"))
+        })
+        .collect())
+}
+
+fn source_lines(source: &str) -> Value {
+    source.split_inclusive('\n').map(|line| Value::String(line.to_string())).collect()
+}
+
+fn new_cell(kind: &str, source: &str, id: Option<String>) -> Value {
+    let mut cell = Map::new();
+    cell.insert("cell_type".to_string(), json!(kind));
+    if let Some(id) = id {
+        cell.insert("id".to_string(), json!(id));
+    }
+    cell.insert("metadata".to_string(), json!({}));
+    cell.insert("source".to_string(), source_lines(source));
+    if kind == "code" {
+        cell.insert("execution_count".to_string(), Value::Null);
+        cell.insert("outputs".to_string(), json!([]));
+    }
+    Value::Object(cell)
+}
+
+fn new_id(source: &str, position: usize) -> String {
+    revision(format!("{}\0{}", position, source).as_bytes())[..8].to_string()
+}
+
+// Notebook JSON as Jupyter writes it: sorted keys, one-space indents and a
+// final newline
+fn to_notebook_json(notebook: &Value) -> String {
+    let mut out = Vec::new();
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(b" ");
+    let mut serializer = serde_json::Serializer::with_formatter(&mut out, formatter);
+    serde::Serialize::serialize(notebook, &mut serializer).expect("JSON values always serialize");
+    out.push(b'\n');
+    String::from_utf8(out).expect("serde_json writes UTF-8")
+}
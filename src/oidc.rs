@@ -0,0 +1,449 @@
+// Optional OIDC login (authorization code flow). When OIDC_ISSUER is set the
+// UI and the API require either a session cookie obtained through
+// /auth/login or, in multi-user mode, a bearer token.
+//
+// Configuration:
+//   OIDC_ISSUER         issuer URL, used for discovery
+//   OIDC_CLIENT_ID      client id registered with the provider
+//   OIDC_CLIENT_SECRET  client secret
+//   OIDC_REDIRECT_URL   e.g. https://repopatch.example.com/auth/callback
+//   OIDC_USER_CLAIM     userinfo claim naming the user (default: email)
+//
+// Logins are bound to the browser that started them by a cookie holding the
+// `state`, and use PKCE and a nonce checked against the ID token. The user is
+// named by OIDC_USER_CLAIM alone, and an email only counts once the provider
+// has verified it, since users are matched against USERS_FILE by name.
+//
+// Sessions live in memory, or with DATA_DIR also in the metadata database so
+// logins survive a restart; the database then holds live session cookies.
+
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use crate::auth::{constant_time_eq, random_token};
+use chrono::{DateTime, Utc};
+use repopatch::metadata::Metadata;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const SESSION_COOKIE: &str = "repopatch_session";
+/// Holds the `state` of the login the browser started.
+const LOGIN_COOKIE: &str = "repopatch_login";
+const SESSION_TTL: Duration = Duration::from_secs(12 * 60 * 60);
+const LOGIN_TTL: Duration = Duration::from_secs(10 * 60);
+/// Logins in progress kept at once; the oldest are dropped past it.
+const MAX_PENDING: usize = 1000;
+/// Record kind in the metadata database, keyed by session id.
+const KIND: &str = "sessions";
+
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_url: String,
+    pub user_claim: String,
+}
+
+impl OidcConfig {
+    /// Returns `None` when OIDC_ISSUER is unset, an error when it is set but
+    /// the rest of the configuration is incomplete.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let issuer = match env::var("OIDC_ISSUER") {
+            Ok(i) if !i.is_empty() => i.trim_end_matches('/').to_string(),
+            _ => return Ok(None),
+        };
+        let required = |name: &str| env::var(name).map_err(|_| format!("{} must be set when OIDC_ISSUER is set", name));
+        Ok(Some(OidcConfig {
+            issuer,
+            client_id: required("OIDC_CLIENT_ID")?,
+            client_secret: required("OIDC_CLIENT_SECRET")?,
+            redirect_url: required("OIDC_REDIRECT_URL")?,
+            user_claim: env::var("OIDC_USER_CLAIM").unwrap_or_else(|_| "email".to_string()),
+        }))
+    }
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    id_token: Option<String>,
+}
+
+/// A login sent to the provider and not back yet.
+struct PendingLogin {
+    verifier: String,
+    nonce: String,
+    created: Instant,
+}
+
+struct LoginSession {
+    user: String,
+    expires: Instant,
+}
+
+/// A session as saved in the metadata database.
+#[derive(Serialize, Deserialize)]
+struct StoredSession {
+    user: String,
+    expires: String,
+}
+
+/// A discovered OIDC provider plus the logins and sessions in flight.
+pub struct Oidc {
+    config: OidcConfig,
+    discovery: Discovery,
+    pending: Mutex<HashMap<String, PendingLogin>>,
+    sessions: Mutex<HashMap<String, LoginSession>>,
+    metadata: Option<Arc<Metadata>>,
+}
+
+impl Oidc {
+    /// Fetches the provider's `.well-known/openid-configuration` and picks up
+    /// the live sessions saved in `metadata`.
+    pub async fn discover(config: OidcConfig, metadata: Option<Arc<Metadata>>) -> Result<Self, String> {
+        let url = format!("{}/.well-known/openid-configuration", config.issuer);
+        let discovery = awc::Client::default()
+            .get(&url)
+            .send()
+            .await
+            .map_err(|e| format!("OIDC discovery at {} failed: {}", url, e))?
+            .json::<Discovery>()
+            .await
+            .map_err(|e| format!("Invalid OIDC discovery document at {}: {}", url, e))?;
+        let mut sessions = HashMap::new();
+        if let Some(metadata) = &metadata {
+            for (id, stored) in metadata.load_as::<StoredSession>(KIND)? {
+                let left = DateTime::parse_from_rfc3339(&stored.expires).ok().and_then(|t| (t.with_timezone(&Utc) - Utc::now()).to_std().ok());
+                match left {
+                    Some(left) => {
+                        sessions.insert(id, LoginSession { user: stored.user, expires: Instant::now() + left });
+                    }
+                    None => {
+                        metadata.delete(KIND, &id)?;
+                    }
+                }
+            }
+        }
+        Ok(Oidc { config, discovery, pending: Mutex::new(HashMap::new()), sessions: Mutex::new(sessions), metadata })
+    }
+
+    /// Forgets the sessions in `ids` in the metadata database.
+    fn forget(&self, ids: &[String]) {
+        let Some(metadata) = &self.metadata else { return };
+        for id in ids {
+            if let Err(e) = metadata.delete(KIND, id) {
+                log::warn!("Failed to remove a session from {}: {}", metadata.path().display(), e);
+            }
+        }
+    }
+
+    /// Returns the user of a live session.
+    pub fn session_user(&self, id: &str) -> Option<String> {
+        let sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.get(id).filter(|s| s.expires > Instant::now()).map(|s| s.user.clone())
+    }
+
+    /// Returns the number of live sessions and of logins in progress.
+    pub fn counts(&self) -> (usize, usize) {
+        let sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner()).len();
+        let pending = self.pending.lock().unwrap_or_else(|e| e.into_inner()).len();
+        (sessions, pending)
+    }
+
+    /// Drops expired sessions and logins, or every session if `revoke_all`.
+    /// Returns how many sessions were removed.
+    pub fn prune(&self, revoke_all: bool) -> usize {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner()).retain(|_, started| started.created.elapsed() < LOGIN_TTL);
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        let removed: Vec<String> = sessions.iter().filter(|(_, s)| revoke_all || s.expires <= Instant::now()).map(|(id, _)| id.clone()).collect();
+        for id in &removed {
+            sessions.remove(id);
+        }
+        self.forget(&removed);
+        removed.len()
+    }
+
+    fn end_session(&self, id: &str) {
+        if self.sessions.lock().unwrap_or_else(|e| e.into_inner()).remove(id).is_some() {
+            self.forget(&[id.to_string()]);
+        }
+    }
+
+    fn secure_cookies(&self) -> bool {
+        self.config.redirect_url.starts_with("https://")
+    }
+
+    /// Starts a login, returning its `state` and the provider URL to send
+    /// the browser to.
+    fn authorize_url(&self) -> (String, String) {
+        let (state, verifier, nonce) = (random_token(), random_token(), random_token());
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+        let query = serde_urlencoded::to_string([
+            ("response_type", "code"),
+            ("client_id", self.config.client_id.as_str()),
+            ("redirect_uri", self.config.redirect_url.as_str()),
+            ("scope", "openid email profile"),
+            ("state", state.as_str()),
+            ("nonce", nonce.as_str()),
+            ("code_challenge", challenge.as_str()),
+            ("code_challenge_method", "S256"),
+        ])
+        .unwrap_or_default();
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.retain(|_, started| started.created.elapsed() < LOGIN_TTL);
+        while pending.len() >= MAX_PENDING {
+            let Some(oldest) = pending.iter().min_by_key(|(_, started)| started.created).map(|(state, _)| state.clone()) else { break };
+            pending.remove(&oldest);
+        }
+        pending.insert(state.clone(), PendingLogin { verifier, nonce, created: Instant::now() });
+        let separator = if self.discovery.authorization_endpoint.contains('?') { '&' } else { '?' };
+        (state.clone(), format!("{}{}{}", self.discovery.authorization_endpoint, separator, query))
+    }
+
+    /// The login of `state`, if the browser sending it, as its login cookie
+    /// tells, started it.
+    fn take_state(&self, state: &str, cookie: Option<&str>) -> Option<PendingLogin> {
+        if !cookie.is_some_and(|c| constant_time_eq(c.as_bytes(), state.as_bytes())) {
+            return None;
+        }
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.remove(state).filter(|started| started.created.elapsed() < LOGIN_TTL)
+    }
+
+    /// Exchanges an authorization code for the user name from the userinfo endpoint.
+    async fn exchange(&self, code: &str, pending: &PendingLogin) -> Result<String, String> {
+        let client = awc::Client::default();
+        let token = client
+            .post(&self.discovery.token_endpoint)
+            .send_form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", self.config.redirect_url.as_str()),
+                ("client_id", self.config.client_id.as_str()),
+                ("client_secret", self.config.client_secret.as_str()),
+                ("code_verifier", pending.verifier.as_str()),
+            ])
+            .await
+            .map_err(|e| format!("Token request failed: {}", e))?
+            .json::<TokenResponse>()
+            .await
+            .map_err(|e| format!("Invalid token response: {}", e))?;
+        let id_claims = id_token_claims(token.id_token.as_deref().ok_or("The token response has no ID token")?)?;
+        self.check_id_token(&id_claims, &pending.nonce)?;
+        let claims = client
+            .get(&self.discovery.userinfo_endpoint)
+            .bearer_auth(&token.access_token)
+            .send()
+            .await
+            .map_err(|e| format!("Userinfo request failed: {}", e))?
+            .json::<Value>()
+            .await
+            .map_err(|e| format!("Invalid userinfo response: {}", e))?;
+        if claims.get("sub") != id_claims.get("sub") {
+            return Err("The userinfo response is for another user than the ID token".to_string());
+        }
+        user_name(&claims, &self.config.user_claim)
+    }
+
+    // The ID token the token endpoint sent back over TLS needs no signature
+    // check (OpenID Connect Core 3.1.3.7), but must be for this client and
+    // this login
+    fn check_id_token(&self, claims: &Value, nonce: &str) -> Result<(), String> {
+        if claims.get("iss").and_then(Value::as_str).map(|i| i.trim_end_matches('/')) != Some(self.config.issuer.as_str()) {
+            return Err("The ID token was issued by another provider".to_string());
+        }
+        let audience = match claims.get("aud") {
+            Some(Value::Array(audiences)) => audiences.iter().any(|a| a.as_str() == Some(&self.config.client_id)),
+            Some(Value::String(audience)) => *audience == self.config.client_id,
+            _ => false,
+        };
+        if !audience {
+            return Err("The ID token is for another client".to_string());
+        }
+        if !claims.get("nonce").and_then(Value::as_str).is_some_and(|n| constant_time_eq(n.as_bytes(), nonce.as_bytes())) {
+            return Err("The ID token is for another login".to_string());
+        }
+        Ok(())
+    }
+
+    fn start_session(&self, user: String) -> String {
+        self.prune(false);
+        let id = random_token();
+        if let Some(metadata) = &self.metadata {
+            let expires = Utc::now() + SESSION_TTL;
+            // The session still works until a restart
+            if let Err(e) = metadata.put(KIND, &id, &StoredSession { user: user.clone(), expires: expires.to_rfc3339() }) {
+                log::warn!("Failed to save the session of {}: {}", user, e);
+            }
+        }
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.insert(id.clone(), LoginSession { user, expires: Instant::now() + SESSION_TTL });
+        id
+    }
+}
+
+/// The claims in the payload of a JWT.
+fn id_token_claims(token: &str) -> Result<Value, String> {
+    let payload = token.split('.').nth(1).ok_or("The ID token is not a JWT")?;
+    let payload = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).map_err(|e| format!("Invalid ID token: {}", e))?;
+    serde_json::from_slice(&payload).map_err(|e| format!("Invalid ID token: {}", e))
+}
+
+/// The user named by `claim`, which must be present. Emails count once the
+/// provider has verified them.
+fn user_name(claims: &Value, claim: &str) -> Result<String, String> {
+    let user = claims
+        .get(claim)
+        .and_then(Value::as_str)
+        .filter(|u| !u.is_empty())
+        .ok_or_else(|| format!("The userinfo response has no {} claim", claim))?;
+    if claim == "email" && claims.get("email_verified").and_then(Value::as_bool) != Some(true) {
+        return Err(format!("The email {} is not verified by the provider", user));
+    }
+    Ok(user.to_string())
+}
+
+#[derive(Deserialize)]
+struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+#[get("/auth/login")]
+pub async fn login(oidc: web::Data<Oidc>) -> HttpResponse {
+    let (state, url) = oidc.authorize_url();
+    // Lax, so the provider's redirect back brings it along
+    let cookie = Cookie::build(LOGIN_COOKIE, state)
+        .path("/auth")
+        .http_only(true)
+        .secure(oidc.secure_cookies())
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::seconds(LOGIN_TTL.as_secs() as i64))
+        .finish();
+    HttpResponse::Found().cookie(cookie).insert_header(("Location", url)).finish()
+}
+
+#[get("/auth/callback")]
+pub async fn callback(req: HttpRequest, query: web::Query<CallbackQuery>, oidc: web::Data<Oidc>) -> HttpResponse {
+    if let Some(error) = &query.error {
+        return HttpResponse::Unauthorized().json(json!({ "success": false, "error": format!("Login failed: {}", error) }));
+    }
+    let (code, state) = match (&query.code, &query.state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => return HttpResponse::BadRequest().json(json!({ "success": false, "error": "Missing code or state" })),
+    };
+    let Some(pending) = oidc.take_state(state, req.cookie(LOGIN_COOKIE).as_ref().map(Cookie::value)) else {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": "Unknown or expired login state, or the login was started in another browser" }));
+    };
+    let mut login_removal = Cookie::build(LOGIN_COOKIE, "").path("/auth").finish();
+    login_removal.make_removal();
+    match oidc.exchange(code, &pending).await {
+        Ok(user) => {
+            log::info!("User {} logged in via OIDC", user);
+            let cookie = Cookie::build(SESSION_COOKIE, oidc.start_session(user))
+                .path("/")
+                .http_only(true)
+                .secure(oidc.secure_cookies())
+                .same_site(SameSite::Lax)
+                .max_age(time::Duration::seconds(SESSION_TTL.as_secs() as i64))
+                .finish();
+            HttpResponse::Found().cookie(cookie).cookie(login_removal).insert_header(("Location", "/")).finish()
+        }
+        Err(e) => {
+            log::warn!("OIDC login failed: {}", e);
+            HttpResponse::Unauthorized().cookie(login_removal).json(json!({ "success": false, "error": e }))
+        }
+    }
+}
+
+#[post("/auth/logout")]
+pub async fn logout(req: HttpRequest, oidc: web::Data<Oidc>) -> HttpResponse {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        oidc.end_session(cookie.value());
+    }
+    let mut removal = Cookie::build(SESSION_COOKIE, "").path("/").finish();
+    removal.make_removal();
+    HttpResponse::Ok().cookie(removal).json(json!({ "success": true }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oidc() -> Oidc {
+        let config = OidcConfig {
+            issuer: "https://id.example.com".to_string(),
+            client_id: "repopatch".to_string(),
+            client_secret: "secret".to_string(),
+            redirect_url: "https://repopatch.example.com/auth/callback".to_string(),
+            user_claim: "email".to_string(),
+        };
+        let discovery = Discovery {
+            authorization_endpoint: "https://id.example.com/authorize".to_string(),
+            token_endpoint: "https://id.example.com/token".to_string(),
+            userinfo_endpoint: "https://id.example.com/userinfo".to_string(),
+        };
+        Oidc { config, discovery, pending: Mutex::new(HashMap::new()), sessions: Mutex::new(HashMap::new()), metadata: None }
+    }
+
+    #[test]
+    fn logins_only_finish_in_the_browser_that_started_them() {
+        let oidc = oidc();
+        let (state, url) = oidc.authorize_url();
+        assert!(url.contains("code_challenge_method=S256") && url.contains("nonce="));
+        assert!(oidc.take_state(&state, None).is_none(), "a forwarded callback has no login cookie");
+        assert!(oidc.take_state(&state, Some("someone-elses-state")).is_none());
+        let pending = oidc.take_state(&state, Some(&state)).unwrap();
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(pending.verifier.as_bytes()));
+        assert!(url.contains(&format!("code_challenge={}", challenge)));
+        assert!(oidc.take_state(&state, Some(&state)).is_none(), "a state is used once");
+    }
+
+    #[test]
+    fn pending_logins_are_capped() {
+        let oidc = oidc();
+        let (first, _) = oidc.authorize_url();
+        for _ in 0..MAX_PENDING + 10 {
+            oidc.authorize_url();
+        }
+        assert_eq!(oidc.counts().1, MAX_PENDING);
+        assert!(oidc.take_state(&first, Some(&first)).is_none(), "the oldest login was dropped");
+    }
+
+    #[test]
+    fn id_tokens_must_be_for_this_client_and_login() {
+        let oidc = oidc();
+        let token = |claims: Value| format!("e30.{}.sig", URL_SAFE_NO_PAD.encode(claims.to_string()));
+        let claims = id_token_claims(&token(json!({ "iss": "https://id.example.com", "aud": ["repopatch"], "nonce": "n1", "sub": "42" }))).unwrap();
+        assert!(oidc.check_id_token(&claims, "n1").is_ok());
+        assert!(oidc.check_id_token(&claims, "n2").is_err());
+        let other_client = json!({ "iss": "https://id.example.com", "aud": "other", "nonce": "n1" });
+        assert!(oidc.check_id_token(&other_client, "n1").is_err());
+        let other_issuer = json!({ "iss": "https://evil.example.com", "aud": "repopatch", "nonce": "n1" });
+        assert!(oidc.check_id_token(&other_issuer, "n1").is_err());
+        assert!(id_token_claims("not a token").is_err());
+    }
+
+    #[test]
+    fn users_are_named_by_the_configured_claim_only() {
+        let claims = json!({ "sub": "42", "preferred_username": "admin", "email": "eve@example.com", "email_verified": false });
+        assert!(user_name(&claims, "email").is_err(), "unverified emails don't count");
+        assert!(user_name(&json!({ "sub": "42", "preferred_username": "admin" }), "email").is_err(), "no fallback to other claims");
+        assert_eq!(user_name(&json!({ "email": "ann@example.com", "email_verified": true }), "email").unwrap(), "ann@example.com");
+        assert_eq!(user_name(&claims, "preferred_username").unwrap(), "admin");
+    }
+}
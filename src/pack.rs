@@ -0,0 +1,156 @@
+//! Packing a workspace into one Markdown file for a prompt: every text file
+//! the ignore rules and [`PathFilter`] keep, in tree order, each under its
+//! path in a code fence. With a budget, files that would take the pack over
+//! it are left out and the rest still go in. Minified lines can be wrapped
+//! or elided, see [`LongLines`]. Notebooks go in as their source view,
+//! see [`crate::notebook`], and PDF and Word files as their text when built
+//! with the `documents` feature.
+
+use crate::context::estimate_tokens;
+use crate::documents;
+use crate::longlines::{soften, LongLines};
+use crate::notebook;
+use crate::patch::revision;
+use crate::semantic::MAX_FILE_BYTES;
+use crate::store::{dir_of, FileStore};
+use crate::tree::{walk_files, PathFilter};
+use ignore::gitignore::Gitignore;
+use serde::Serialize;
+use std::time::UNIX_EPOCH;
+
+/// A file left out of the pack.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Omitted {
+    pub path: String,
+    /// Estimated tokens of its section; 0 for files that aren't read.
+    pub tokens: usize,
+    /// `budget`, `binary`, `tooLarge`, or `noText` for documents whose
+    /// text couldn't be extracted.
+    pub reason: &'static str,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Pack {
+    /// The Markdown.
+    pub text: String,
+    /// Estimated tokens of `text`.
+    pub tokens: usize,
+    pub files: Vec<String>,
+    pub omitted: Vec<Omitted>,
+    /// Packed files with lines longer than [`crate::longlines::LONG_LINE_CHARS`].
+    #[serde(rename = "longLines", skip_serializing_if = "Vec::is_empty")]
+    pub long_lines: Vec<String>,
+    /// Packed PDF and Word files, as their extracted text.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub documents: Vec<String>,
+}
+
+/// Packs the files below the store root that `filter` allows, keeping the
+/// whole text within `budget` tokens when one is given. `title` heads the
+/// pack, usually the workspace path, and `long_lines` says how to show the
+/// lines of minified files.
+pub fn pack(store: &dyn FileStore, ig: &Gitignore, filter: &PathFilter, title: &str, budget: Option<usize>, long_lines: LongLines) -> Result<Pack, String> {
+    Ok(pack_selection(store, selection(store, ig, filter)?, title, budget, long_lines))
+}
+
+/// The files [`pack`] considers, in tree order.
+pub fn selection(store: &dyn FileStore, ig: &Gitignore, filter: &PathFilter) -> Result<Vec<String>, String> {
+    let mut paths = Vec::new();
+    walk_files(store, "", ig, &mut |path| {
+        if filter.allows(path) && !excluded_folder(filter, path) {
+            paths.push(path.to_string());
+        }
+    })?;
+    Ok(paths)
+}
+
+/// A token for the state of the files `paths`, from their sizes and
+/// modification times, so a pack of them can be reused without reading
+/// them. `None` when the store can't tell when a file changed.
+pub fn fingerprint(store: &dyn FileStore, paths: &[String]) -> Option<String> {
+    let mut state = String::new();
+    for path in paths {
+        let size = store.file_size(path)?;
+        let modified = store.modified(path)?.duration_since(UNIX_EPOCH).ok()?;
+        state.push_str(&format!("{}\0{}\0{}\n", path, size, modified.as_nanos()));
+    }
+    Some(revision(state.as_bytes()))
+}
+
+/// Packs the files `paths` as [`pack`] does.
+pub fn pack_selection(store: &dyn FileStore, paths: Vec<String>, title: &str, budget: Option<usize>, long_lines: LongLines) -> Pack {
+    let mut pack = Pack { text: format!("# {}\n", title), ..Pack::default() };
+    let mut used = estimate_tokens(&pack.text);
+    for path in paths {
+        let document = documents::document_kind(&path).is_some();
+        let max_bytes = if document { documents::MAX_DOCUMENT_BYTES } else { MAX_FILE_BYTES };
+        if store.file_size(&path).is_none_or(|s| s > max_bytes) {
+            pack.omitted.push(Omitted { path, tokens: 0, reason: "tooLarge" });
+            continue;
+        }
+        let Ok(data) = store.read(&path) else {
+            pack.omitted.push(Omitted { path, tokens: 0, reason: "binary" });
+            continue;
+        };
+        let (content, language) = match documents::extract_text(&path, &data) {
+            Some(Ok(text)) => (text, Some("text".to_string())),
+            Some(Err(e)) => {
+                log::debug!("No text from {}: {}", path, e);
+                pack.omitted.push(Omitted { path, tokens: 0, reason: "noText" });
+                continue;
+            }
+            None if data.contains(&0) => {
+                pack.omitted.push(Omitted { path, tokens: 0, reason: "binary" });
+                continue;
+            }
+            None => {
+                let content = String::from_utf8_lossy(&data).into_owned();
+                match notebook::is_notebook(&path).then(|| notebook::source_view(&content)) {
+                    Some(Ok(view)) => (view, notebook::language(&content)),
+                    _ => (content, None),
+                }
+            }
+        };
+        let (content, long_line_count) = soften(&content, long_lines);
+        let section = section(&path, &content, language.as_deref());
+        let tokens = estimate_tokens(&section);
+        if budget.is_some_and(|b| used + tokens > b) {
+            pack.omitted.push(Omitted { path, tokens, reason: "budget" });
+            continue;
+        }
+        used += tokens;
+        pack.text.push_str(&section);
+        if long_line_count > 0 {
+            pack.long_lines.push(path.clone());
+        }
+        if document {
+            pack.documents.push(path.clone());
+        }
+        pack.files.push(path);
+    }
+    pack.tokens = estimate_tokens(&pack.text);
+    pack
+}
+
+// Whether a folder above `path` is excluded, as build_filtered_tree never
+// walks those
+fn excluded_folder(filter: &PathFilter, path: &str) -> bool {
+    let mut dir = dir_of(path);
+    while !dir.is_empty() {
+        if filter.excludes(dir) {
+            return true;
+        }
+        dir = dir_of(dir);
+    }
+    false
+}
+
+// One file's heading and fenced content. The fence is longer than any run of
+// backticks in the file so it can't be closed early.
+fn section(path: &str, content: &str, language: Option<&str>) -> String {
+    let longest = content.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest.max(2) + 1);
+    let language = language.or_else(|| path.rsplit_once('.').map(|(_, ext)| ext).filter(|ext| !ext.contains('/'))).unwrap_or("");
+    let newline = if content.ends_with('\n') || content.is_empty() { "" } else { "\n" };
+    format!("\n## {}\n\n{}{}\n{}{}{}\n", path, fence, language, content, newline, fence)
+}
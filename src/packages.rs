@@ -0,0 +1,213 @@
+//! Member packages of a monorepo, read from its workspace manifests: a Cargo
+//! workspace, npm or yarn `workspaces` in package.json, pnpm-workspace.yaml
+//! and go.work. Member globs like `crates/*` are matched against the store's
+//! folders.
+
+use crate::store::{join_path, normalize_path, FileStore};
+use crate::tree::VCS_DIRS;
+use globset::{GlobBuilder, GlobMatcher};
+use serde::Serialize;
+
+/// Folders never searched for members.
+const SKIPPED_DIRS: &[&str] = &["node_modules", "target", "vendor", "dist", "build"];
+/// How deep `**` in a member glob looks.
+const MAX_GLOB_DEPTH: usize = 8;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Package {
+    /// The name in the package's manifest, or its folder name without one.
+    pub name: String,
+    /// Store path of the package's folder, "" for the workspace root.
+    pub root: String,
+    /// `cargo`, `npm` or `go`.
+    pub kind: &'static str,
+    /// Store path of its manifest.
+    pub manifest: String,
+}
+
+/// Every member package the workspace manifests at the store root list,
+/// sorted by root. A Cargo workspace whose root is a package too lists it
+/// with root "".
+pub fn find_packages(store: &dyn FileStore) -> Result<Vec<Package>, String> {
+    let mut packages = Vec::new();
+    if let Some(manifest) = read_text(store, "Cargo.toml") {
+        packages.extend(cargo_members(store, &manifest)?);
+    }
+    let mut npm_patterns = Vec::new();
+    if let Some(manifest) = read_text(store, "package.json") {
+        let json: serde_json::Value = serde_json::from_str(&manifest).map_err(|e| format!("Invalid package.json: {}", e))?;
+        let workspaces = match &json["workspaces"] {
+            serde_json::Value::Object(w) => w.get("packages").cloned().unwrap_or_default(),
+            w => w.clone(),
+        };
+        npm_patterns.extend(workspaces.as_array().into_iter().flatten().filter_map(|p| p.as_str().map(str::to_string)));
+    }
+    if let Some(manifest) = read_text(store, "pnpm-workspace.yaml") {
+        npm_patterns.extend(pnpm_patterns(&manifest));
+    }
+    if !npm_patterns.is_empty() {
+        let (exclude, include): (Vec<String>, Vec<String>) = npm_patterns.into_iter().partition(|p| p.starts_with('!'));
+        let exclude: Vec<String> = exclude.iter().map(|p| p[1..].to_string()).collect();
+        for root in member_dirs(store, &include, &exclude)? {
+            let manifest = join_path(&root, "package.json");
+            let Some(text) = read_text(store, &manifest) else {
+                continue;
+            };
+            let name = serde_json::from_str::<serde_json::Value>(&text).ok().and_then(|j| j["name"].as_str().map(str::to_string));
+            packages.push(package(name, root, "npm", manifest));
+        }
+    }
+    if let Some(manifest) = read_text(store, "go.work") {
+        for root in go_work_uses(&manifest) {
+            let manifest = join_path(&root, "go.mod");
+            let Some(text) = read_text(store, &manifest) else {
+                continue;
+            };
+            let name = text.lines().find_map(|l| l.trim().strip_prefix("module ")).map(|m| m.trim().trim_matches('"').to_string());
+            packages.push(package(name, root, "go", manifest));
+        }
+    }
+    packages.sort_by(|a, b| a.root.cmp(&b.root).then(a.kind.cmp(b.kind)));
+    packages.dedup_by(|a, b| a.root == b.root && a.kind == b.kind);
+    Ok(packages)
+}
+
+/// The package holding `path`: the one with the longest root above it.
+pub fn package_of<'a>(packages: &'a [Package], path: &str) -> Option<&'a Package> {
+    packages
+        .iter()
+        .filter(|p| p.root.is_empty() || path == p.root || path.strip_prefix(&p.root).is_some_and(|rest| rest.starts_with('/')))
+        .max_by_key(|p| p.root.len())
+}
+
+fn package(name: Option<String>, root: String, kind: &'static str, manifest: String) -> Package {
+    let name = name.filter(|n| !n.is_empty()).unwrap_or_else(|| root.rsplit('/').next().unwrap_or_default().to_string());
+    Package { name, root, kind, manifest }
+}
+
+fn read_text(store: &dyn FileStore, path: &str) -> Option<String> {
+    store.read(path).ok().map(|data| String::from_utf8_lossy(&data).into_owned())
+}
+
+fn cargo_members(store: &dyn FileStore, manifest: &str) -> Result<Vec<Package>, String> {
+    let value: toml::Value = toml::from_str(manifest).map_err(|e| format!("Invalid Cargo.toml: {}", e))?;
+    let strings = |table: Option<&toml::Value>, key: &str| -> Vec<String> {
+        table.and_then(|t| t.get(key)).and_then(|v| v.as_array()).into_iter().flatten().filter_map(|v| v.as_str().map(str::to_string)).collect()
+    };
+    let name = |value: &toml::Value| value.get("package").and_then(|p| p.get("name")).and_then(|n| n.as_str()).map(str::to_string);
+    let mut packages = Vec::new();
+    if value.get("package").is_some() {
+        packages.push(package(name(&value), String::new(), "cargo", "Cargo.toml".to_string()));
+    }
+    let workspace = value.get("workspace");
+    for root in member_dirs(store, &strings(workspace, "members"), &strings(workspace, "exclude"))? {
+        let manifest = join_path(&root, "Cargo.toml");
+        let Some(text) = read_text(store, &manifest) else {
+            continue;
+        };
+        let member: Option<toml::Value> = toml::from_str(&text).ok();
+        packages.push(package(member.as_ref().and_then(name), root, "cargo", manifest));
+    }
+    Ok(packages)
+}
+
+// The entries of the `packages:` list in pnpm-workspace.yaml
+fn pnpm_patterns(manifest: &str) -> Vec<String> {
+    let mut patterns = Vec::new();
+    let mut in_packages = false;
+    for line in manifest.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if !line.starts_with([' ', '\t', '-']) {
+            in_packages = trimmed == "packages:";
+        } else if let Some(item) = trimmed.strip_prefix('-').filter(|_| in_packages) {
+            let item = item.split(" #").next().unwrap_or_default().trim().trim_matches(['\'', '"']);
+            if !item.is_empty() {
+                patterns.push(item.to_string());
+            }
+        }
+    }
+    patterns
+}
+
+// The folders of go.work's `use` directives, single or in a block
+fn go_work_uses(manifest: &str) -> Vec<String> {
+    let mut dirs = Vec::new();
+    let mut in_block = false;
+    for line in manifest.lines() {
+        let line = line.split("//").next().unwrap_or_default().trim();
+        let entry = if in_block {
+            if line == ")" {
+                in_block = false;
+                continue;
+            }
+            line
+        } else if let Some(rest) = line.strip_prefix("use") {
+            let rest = rest.trim();
+            if rest == "(" {
+                in_block = true;
+                continue;
+            }
+            rest
+        } else {
+            continue;
+        };
+        if let Some(dir) = normalize_path(entry.trim_matches('"')) {
+            dirs.push(dir);
+        }
+    }
+    dirs
+}
+
+// Folders matching one of the member globs and none of the excluded ones
+fn member_dirs(store: &dyn FileStore, include: &[String], exclude: &[String]) -> Result<Vec<String>, String> {
+    let matchers = |patterns: &[String]| -> Result<Vec<GlobMatcher>, String> {
+        patterns
+            .iter()
+            .filter_map(|p| normalize_path(p.trim_end_matches('/')))
+            .filter(|p| !p.is_empty())
+            .map(|p| {
+                GlobBuilder::new(&p)
+                    .literal_separator(true)
+                    .build()
+                    .map(|g| g.compile_matcher())
+                    .map_err(|e| format!("Invalid member glob {}: {}", p, e))
+            })
+            .collect()
+    };
+    let (include_globs, exclude_globs) = (matchers(include)?, matchers(exclude)?);
+    if include_globs.is_empty() {
+        return Ok(Vec::new());
+    }
+    let depth = include
+        .iter()
+        .map(|p| if p.contains("**") { MAX_GLOB_DEPTH } else { p.trim_matches('/').split('/').filter(|s| !s.is_empty() && *s != ".").count() })
+        .max()
+        .unwrap_or(1);
+    let mut dirs = Vec::new();
+    let mut level = vec![String::new()];
+    for _ in 0..depth {
+        let mut next = Vec::new();
+        for dir in &level {
+            let Ok(entries) = store.list_dir(dir) else {
+                continue;
+            };
+            for entry in entries.into_iter().filter(|e| e.is_dir) {
+                if VCS_DIRS.contains(&entry.name.as_str()) || SKIPPED_DIRS.contains(&entry.name.as_str()) || entry.name.starts_with('.') {
+                    continue;
+                }
+                next.push(join_path(dir, &entry.name));
+            }
+        }
+        for dir in &next {
+            if include_globs.iter().any(|g| g.is_match(dir)) && !exclude_globs.iter().any(|g| g.is_match(dir)) {
+                dirs.push(dir.clone());
+            }
+        }
+        level = next;
+    }
+    dirs.sort();
+    Ok(dirs)
+}
@@ -0,0 +1,181 @@
+// POST /api/pack packs a workspace into one Markdown file for a prompt, like
+// `repopatch pack`: every text file the ignore rules and the `include` and
+// `exclude` globs keep, leaving out files that would take it over
+// `maxTokens`. `package` packs one member of a monorepo instead, and
+// `longLines` wraps or elides the lines of minified files.
+//
+// Iterative prompting packs the same selection again and again, so packs are
+// cached gzipped in PACK_CACHE_DIR (default repopatch-packs in the temp
+// directory), keyed by the selection and the size and modification time of
+// every file in it. Packing an unchanged selection again reads no files.
+// PACK_CACHE_MB (default 256) caps the folder, dropping the least recently
+// used packs first; an empty PACK_CACHE_DIR turns the cache off.
+
+use crate::auth::Stores;
+use crate::validation::ValidJson;
+use actix_web::{post, web, HttpResponse};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use ignore::gitignore::Gitignore;
+use repopatch::longlines::LongLines;
+use repopatch::pack::{fingerprint, pack_selection, selection};
+use repopatch::patch::revision;
+use repopatch::tree::{load_gitignore, PathFilter};
+use serde::Deserialize;
+use serde_json::json;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const DEFAULT_CACHE_MB: u64 = 256;
+
+pub struct PackCache {
+    dir: Option<PathBuf>,
+    max_bytes: u64,
+}
+
+impl PackCache {
+    /// The cache configured by PACK_CACHE_DIR and PACK_CACHE_MB.
+    pub fn from_env() -> Result<Self, String> {
+        let dir = match env::var("PACK_CACHE_DIR") {
+            Ok(d) if d.is_empty() => None,
+            Ok(d) => Some(PathBuf::from(d)),
+            Err(_) => Some(env::temp_dir().join("repopatch-packs")),
+        };
+        if let Some(dir) = &dir {
+            fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        }
+        let max_mb = env::var("PACK_CACHE_MB").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_CACHE_MB);
+        Ok(PackCache { dir, max_bytes: max_mb * 1024 * 1024 })
+    }
+
+    fn entry(&self, key: &str) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| dir.join(format!("{}.json.gz", key)))
+    }
+
+    /// The cached pack under `key`, marked as just used.
+    fn get(&self, key: &str) -> Option<serde_json::Value> {
+        let path = self.entry(key)?;
+        let file = File::open(&path).ok()?;
+        let pack = serde_json::from_reader(GzDecoder::new(file)).ok()?;
+        let _ = File::options().write(true).open(&path).and_then(|f| f.set_modified(SystemTime::now()));
+        Some(pack)
+    }
+
+    fn put(&self, key: &str, pack: &serde_json::Value) {
+        let (Some(dir), Some(path)) = (&self.dir, self.entry(key)) else {
+            return;
+        };
+        // Write next to the entry and rename so a reader never sees half of it
+        let temp = path.with_extension("tmp");
+        let written = File::create(&temp).and_then(|file| {
+            let mut encoder = GzEncoder::new(file, Compression::fast());
+            serde_json::to_writer(&mut encoder, pack).map_err(io::Error::other)?;
+            encoder.finish()?.flush()
+        });
+        if let Err(e) = written.and_then(|()| fs::rename(&temp, &path)) {
+            log::warn!("Failed to cache a pack in {}: {}", path.display(), e);
+            let _ = fs::remove_file(&temp);
+            return;
+        }
+        self.evict(dir);
+    }
+
+    // Removes the least recently used entries until the folder fits
+    fn evict(&self, dir: &Path) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        let mut packs: Vec<(SystemTime, u64, PathBuf)> = entries
+            .filter_map(Result::ok)
+            .filter(|e| e.path().extension().is_some_and(|ext| ext == "gz"))
+            .filter_map(|e| {
+                let meta = e.metadata().ok()?;
+                Some((meta.modified().ok()?, meta.len(), e.path()))
+            })
+            .collect();
+        packs.sort_by_key(|p| std::cmp::Reverse(p.0));
+        let mut total = 0;
+        for (_, len, path) in packs {
+            total += len;
+            if total > self.max_bytes {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PackRequest {
+    #[serde(rename = "directoryPath")]
+    directory_path: String,
+    /// Globs of the files to pack; empty packs every file.
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    #[serde(rename = "maxTokens")]
+    max_tokens: Option<usize>,
+    /// Pack this /api/packages member of `directoryPath` instead of all of it.
+    package: Option<String>,
+    /// `wrap` or `elide` lines longer than longlines::LONG_LINE_CHARS.
+    #[serde(rename = "longLines")]
+    long_lines: Option<String>,
+    /// Characters to wrap or cut long lines at.
+    #[serde(rename = "lineWidth")]
+    line_width: Option<usize>,
+}
+
+#[post("/api/pack")]
+pub async fn pack_workspace(body: ValidJson<PackRequest>, stores: Stores, cache: web::Data<PackCache>) -> HttpResponse {
+    let store = match stores.open_root(&body.directory_path) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid directory path: {}", e) })),
+    };
+    let store = match &body.package {
+        Some(name) => match crate::open_package(&stores, &*store, name) {
+            Ok(s) => s,
+            Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+        },
+        None => store,
+    };
+    let filter = match PathFilter::new(&body.include, &body.exclude) {
+        Ok(f) => f,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+    let long_lines = match LongLines::parse(body.long_lines.as_deref(), body.line_width) {
+        Ok(mode) => mode,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e, "field": "longLines" })),
+    };
+    let root = store.display_path("");
+    let selection_key = format!("{}\0{:?}\0{:?}\0{:?}\0{:?}", root, body.include, body.exclude, body.max_tokens, long_lines);
+    let max_tokens = body.max_tokens;
+    let task_root = root.clone();
+    let result = web::block(move || -> Result<(serde_json::Value, bool), String> {
+        let ig = load_gitignore(&*store, "").unwrap_or_else(Gitignore::empty);
+        let paths = selection(&*store, &ig, &filter)?;
+        let key = fingerprint(&*store, &paths).map(|state| revision(format!("{}\0{}", selection_key, state).as_bytes()));
+        if let Some(pack) = key.as_deref().and_then(|k| cache.get(k)) {
+            return Ok((pack, true));
+        }
+        let pack = json!(pack_selection(&*store, paths, &task_root, max_tokens, long_lines));
+        if let Some(key) = &key {
+            cache.put(key, &pack);
+        }
+        Ok((pack, false))
+    })
+    .await;
+    match result {
+        Ok(Ok((mut pack, cached))) => {
+            pack["success"] = json!(true);
+            pack["root"] = json!(root);
+            pack["cached"] = json!(cached);
+            HttpResponse::Ok().json(pack)
+        }
+        Ok(Err(e)) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": e })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Pack task failed: {}", e) })),
+    }
+}
@@ -0,0 +1,960 @@
+//! The patch engine: parses unified diffs into a [`PatchSet`] and applies it to
+//! a [`FileStore`], falling back to diff-match-patch fuzzy matching so hunks
+//! still land when their context has drifted. Other ways of matching hunks
+//! plug in through [`crate::engine::PatchEngine`].
+//!
+//! ```
+//! use repopatch::patch::PatchSet;
+//! use repopatch::store::MemoryStore;
+//!
+//! let store = MemoryStore::from_files([("greeting.txt", "hello\nworld\n")]);
+//! let patch = "--- a/greeting.txt\n+++ b/greeting.txt\n@@ -1,2 +1,2 @@\n-hello\n+goodbye\n world\n";
+//!
+//! let outcome = PatchSet::parse(patch, 1).apply(&store);
+//! assert!(outcome.is_success());
+//! assert_eq!(store.get("greeting.txt").unwrap(), "goodbye\nworld\n");
+//! ```
+
+use crate::editorconfig;
+use crate::engine::{DmpEngine, PatchEngine};
+use crate::notebook::{self, NotebookView};
+use crate::store::FileStore;
+use diff_match_patch_rs::{Compat, DiffMatchPatch};
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io;
+
+/// Lines of surrounding content returned around failed hunks by default.
+pub const DEFAULT_CONTEXT_LINES: usize = 20;
+
+const DEV_NULL: &str = "/dev/null";
+
+/// Stable failure codes reported per file by apply_patch, so clients can
+/// branch on the failure type instead of parsing the message text.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    ParseError,
+    FileNotFound,
+    ContextMismatch,
+    WriteFailed,
+    PathEscape,
+    BinaryFile,
+    ReadFailed,
+    DiskFull,
+    ConcurrentModification,
+    SecretDetected,
+    PolicyViolation,
+    ProtectedPath,
+    StaleRevision,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PatchFailure {
+    pub code: ErrorCode,
+    pub file: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<Box<FileContext>>,
+}
+
+impl PatchFailure {
+    pub fn new(code: ErrorCode, file: &str, message: String) -> Self {
+        PatchFailure { code, file: file.to_string(), message, context: None }
+    }
+
+    pub fn with_context(mut self, context: FileContext) -> Self {
+        self.context = Some(Box::new(context));
+        self
+    }
+}
+
+/// Current on-disk content around the hunks that failed to match, returned
+/// with CONTEXT_MISMATCH so the caller can regenerate the patch directly.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FileContext {
+    /// 1-based, inclusive line range of `content` within the file.
+    pub start_line: usize,
+    pub end_line: usize,
+    pub total_lines: usize,
+    /// 0-based indices of the hunks that failed to apply.
+    pub failed_hunks: Vec<usize>,
+    pub content: String,
+}
+
+/// Result of applying a multi-file patch: the files that were written and a
+/// failure entry for every file that was not.
+#[derive(Debug, Default)]
+pub struct ApplyOutcome {
+    pub applied_files: Vec<String>,
+    pub details: Vec<PatchFailure>,
+    /// Steps taken, when [`ApplyOptions::trace`] is set.
+    pub trace: Vec<TraceStep>,
+}
+
+/// One step of applying a patch: a path resolved, a hunk matched, a file
+/// written. Returned to clients debugging a patch that did not apply.
+#[derive(Serialize, Debug, Clone)]
+pub struct TraceStep {
+    pub file: String,
+    pub step: &'static str,
+    pub message: String,
+}
+
+/// Collects trace steps when enabled; building the messages is skipped
+/// otherwise.
+pub struct Tracer {
+    enabled: bool,
+    steps: Vec<TraceStep>,
+}
+
+impl Tracer {
+    pub fn step(&mut self, file: &str, step: &'static str, message: impl FnOnce() -> String) {
+        if self.enabled {
+            self.steps.push(TraceStep { file: file.to_string(), step, message: message() });
+        }
+    }
+}
+
+impl ApplyOutcome {
+    /// True when every file in the patch set applied.
+    pub fn is_success(&self) -> bool {
+        self.details.is_empty()
+    }
+}
+
+/// Knobs for [`PatchSet::apply_with`].
+#[derive(Debug, Clone)]
+pub struct ApplyOptions {
+    /// Lines of current content included around failed hunks.
+    pub context_lines: usize,
+    /// Bytes that must stay free after each write; files that would eat
+    /// into it fail with DISK_FULL instead of being written.
+    pub disk_reserve: u64,
+    /// Record every step in [`ApplyOutcome::trace`].
+    pub trace: bool,
+}
+
+impl Default for ApplyOptions {
+    fn default() -> Self {
+        ApplyOptions { context_lines: DEFAULT_CONTEXT_LINES, disk_reserve: 0, trace: false }
+    }
+}
+
+/// The changes a patch makes to a single file.
+#[derive(Debug)]
+pub struct FilePatch {
+    /// Path before the change (after stripping), `None` when the file is created.
+    pub old_path: Option<String>,
+    /// Path after the change (after stripping), `None` when the file is deleted.
+    pub new_path: Option<String>,
+    hunks: Result<Vec<Hunk>, String>,
+    raw: String,
+}
+
+impl FilePatch {
+    /// The path failures are reported under: the old path, or the new path
+    /// for created files.
+    pub fn path(&self) -> &str {
+        self.old_path.as_deref().or(self.new_path.as_deref()).unwrap_or_default()
+    }
+
+    pub fn is_creation(&self) -> bool {
+        self.old_path.is_none()
+    }
+
+    pub fn is_deletion(&self) -> bool {
+        self.new_path.is_none()
+    }
+
+    pub fn is_rename(&self) -> bool {
+        matches!((&self.old_path, &self.new_path), (Some(old), Some(new)) if old != new)
+    }
+
+    /// Lines the patch adds, with their 1-based line numbers in the patched
+    /// file as given by the hunk headers.
+    pub fn added_lines(&self) -> Vec<(usize, &str)> {
+        let mut added = Vec::new();
+        for hunk in self.hunks.iter().flatten() {
+            let mut line_number = hunk.new_start.max(1);
+            for line in &hunk.lines {
+                match line.kind {
+                    LineKind::Remove => continue,
+                    LineKind::Add => added.push((line_number, line.text.as_str())),
+                    LineKind::Context => {}
+                }
+                line_number += 1;
+            }
+        }
+        added
+    }
+
+    /// Context and removed lines, the lines the patch expects to find.
+    pub fn old_lines(&self) -> Vec<&str> {
+        let lines = self.hunks.iter().flatten().flat_map(|h| &h.lines);
+        lines.filter(|line| !matches!(line.kind, LineKind::Add)).map(|line| line.text.as_str()).collect()
+    }
+
+    /// Numbers of added and removed lines.
+    pub fn line_counts(&self) -> (usize, usize) {
+        let lines = self.hunks.iter().flatten().flat_map(|h| &h.lines);
+        lines.fold((0, 0), |(added, removed), line| match line.kind {
+            LineKind::Add => (added + 1, removed),
+            LineKind::Remove => (added, removed + 1),
+            LineKind::Context => (added, removed),
+        })
+    }
+
+    /// Why the hunks could not be parsed, if they couldn't.
+    pub fn parse_error(&self) -> Option<&str> {
+        self.hunks.as_ref().err().map(String::as_str)
+    }
+
+    /// Number of hunks, or 0 if the hunks could not be parsed.
+    pub fn hunk_count(&self) -> usize {
+        self.hunks.as_ref().map(|h| h.len()).unwrap_or(0)
+    }
+
+    /// This file's part of the patch text, from its `---` header on.
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+}
+
+/// A parsed, possibly multi-file, unified diff.
+#[derive(Debug)]
+pub struct PatchSet {
+    pub files: Vec<FilePatch>,
+    /// Leading path components stripped from every header path.
+    pub strip_level: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineKind {
+    Context,
+    Remove,
+    Add,
+}
+
+#[derive(Debug, Clone)]
+struct HunkLine {
+    kind: LineKind,
+    text: String,
+    // False when followed by "\ No newline at end of file"
+    eol: bool,
+}
+
+#[derive(Debug)]
+struct Hunk {
+    old_start: usize,
+    // Start line on the new side, used to report positions in the result
+    new_start: usize,
+    old_len: usize,
+    lines: Vec<HunkLine>,
+}
+
+// One file's part of a patch, as split out of the whole text
+struct Section {
+    old_path: String,
+    new_path: String,
+    text: String,
+    // A git rename without content changes: the paths come from the
+    // "rename from"/"rename to" lines, which have no a/ b/ prefixes
+    pure_rename: bool,
+}
+
+// Helper function to split patch content into per-file patches
+fn split_patch_content(patch_content: &str) -> Vec<Section> {
+    let lines: Vec<&str> = patch_content.lines().map(|l| l.trim_end_matches('\r')).collect();
+    let mut patches = Vec::new();
+    let mut current_old_path = None;
+    let mut current_new_path = None;
+    let mut current_patch_lines = Vec::new();
+    let mut rename_from = None;
+    let mut rename_to = None;
+
+    // Store the previous patch if it exists and is valid
+    let flush = |patches: &mut Vec<Section>, old_path: Option<String>, new_path: Option<String>, lines: &[String]| {
+        if let (Some(old_path), Some(new_path)) = (old_path, new_path) {
+            if !lines.is_empty() {
+                log::debug!("Collected patch for old_path: {}, new_path: {}, lines: {}", old_path, new_path, lines.len());
+                patches.push(Section { old_path, new_path, text: lines.join("\n"), pure_rename: false });
+            } else {
+                log::warn!("Skipping empty patch for old_path: {}", old_path);
+            }
+        }
+    };
+    let flush_rename = |patches: &mut Vec<Section>, from: Option<String>, to: Option<String>, lines: &[String]| {
+        if let (Some(old_path), Some(new_path)) = (from, to) {
+            log::debug!("Collected rename from {} to {}", old_path, new_path);
+            patches.push(Section { old_path, new_path, text: lines.join("\n"), pure_rename: true });
+        }
+    };
+
+    for line in lines {
+        if line.starts_with("diff --git ") {
+            flush(&mut patches, current_old_path.take(), current_new_path.take(), &current_patch_lines);
+            flush_rename(&mut patches, rename_from.take(), rename_to.take(), &current_patch_lines);
+            current_patch_lines = vec![line.to_string()];
+        } else if let Some(old_path) = line.strip_prefix("--- ") {
+            flush(&mut patches, current_old_path.take(), current_new_path.take(), &current_patch_lines);
+            let old_path = header_path(old_path);
+            // Content of the renamed file, or the next file of a plain diff
+            // after a pure rename
+            if rename_from.as_ref().is_some_and(|from| old_path != format!("a/{}", from) && old_path != *from) {
+                flush_rename(&mut patches, rename_from.take(), rename_to.take(), &current_patch_lines);
+            }
+            rename_from = None;
+            rename_to = None;
+            current_old_path = Some(old_path);
+            current_new_path = None;
+            current_patch_lines = vec![line.to_string()];
+        } else if let Some(new_path) = line.strip_prefix("+++ ") {
+            if current_old_path.is_none() {
+                log::warn!("Found +++ line without preceding --- line: {}", line);
+                current_patch_lines.clear(); // Reset to avoid malformed patch
+                continue;
+            }
+            current_new_path = Some(header_path(new_path));
+            current_patch_lines.push(line.to_string());
+        } else {
+            if current_old_path.is_none() {
+                if let Some(from) = line.strip_prefix("rename from ") {
+                    rename_from = Some(from.trim().to_string());
+                } else if let Some(to) = line.strip_prefix("rename to ") {
+                    rename_to = Some(to.trim().to_string());
+                }
+            }
+            if !line.is_empty() || !current_patch_lines.is_empty() {
+                // Include non-empty lines or empty lines after content has started
+                current_patch_lines.push(line.to_string());
+            }
+        }
+    }
+
+    // Store the final patch if valid
+    flush(&mut patches, current_old_path, current_new_path, &current_patch_lines);
+    flush_rename(&mut patches, rename_from, rename_to, &current_patch_lines);
+
+    patches
+}
+
+// Helper function to drop the optional tab-separated timestamp from a ---/+++ header
+fn header_path(header: &str) -> String {
+    header.split('\t').next().unwrap_or(header).trim().to_string()
+}
+
+// Helper function to strip path components (e.g., to match -p1 behavior).
+// Like patch(1), a run of slashes counts as one separator.
+fn strip_path(path: &str, strip_level: usize) -> String {
+    let mut split = path.split('/');
+    let first = split.next().unwrap_or_default();
+    let parts: Vec<&str> = std::iter::once(first).chain(split.filter(|p| !p.is_empty())).collect();
+    if parts.len() > strip_level {
+        parts[strip_level..].join("/")
+    } else {
+        parts.join("/")
+    }
+}
+
+// Helper function to read a patch target as text, classifying the failure
+fn read_text_file(store: &dyn FileStore, path: &str) -> Result<String, (ErrorCode, String)> {
+    let data = store.read(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => (ErrorCode::FileNotFound, e.to_string()),
+        _ => (ErrorCode::ReadFailed, e.to_string()),
+    })?;
+    match String::from_utf8(data) {
+        Ok(content) if content.contains('\0') => Err((ErrorCode::BinaryFile, "file appears to be binary".to_string())),
+        Ok(content) => Ok(content),
+        Err(e) => Err((ErrorCode::BinaryFile, format!("file is not valid UTF-8: {}", e))),
+    }
+}
+
+// Helper function to shorten patch text for inclusion in error messages
+fn patch_snippet(patch_text: &str) -> String {
+    match patch_text.char_indices().nth(100) {
+        Some((idx, _)) => format!("{}...", &patch_text[..idx]),
+        None => patch_text.to_string(),
+    }
+}
+
+// Helper function to parse "@@ -a,b +c,d @@" into the old-file (start, length)
+fn parse_hunk_header(line: &str) -> Option<(usize, usize)> {
+    let rest = line.strip_prefix("@@ -")?;
+    let range = rest.split_whitespace().next()?;
+    let mut parts = range.splitn(2, ',');
+    let start = parts.next()?.parse().ok()?;
+    let len = match parts.next() {
+        Some(len) => len.parse().ok()?,
+        None => 1,
+    };
+    Some((start, len))
+}
+
+// Helper function to parse the new-side start line out of "@@ -a,b +c,d @@"
+fn parse_new_start(line: &str) -> Option<usize> {
+    let range = line.split_whitespace().find_map(|part| part.strip_prefix('+'))?;
+    range.split(',').next()?.parse().ok()
+}
+
+// Helper function to parse the hunks of a single-file patch. Line counts in the
+// headers are not trusted (LLM output often gets them wrong); the hunk body is.
+fn parse_hunks(patch_text: &str) -> Result<Vec<Hunk>, String> {
+    let mut hunks: Vec<Hunk> = Vec::new();
+    let mut in_hunk = false;
+
+    for line in patch_text.lines() {
+        if line.starts_with("@@") {
+            let (old_start, old_len) = parse_hunk_header(line)
+                .ok_or_else(|| format!("Malformed hunk header: {}", line))?;
+            let new_start = parse_new_start(line).unwrap_or(old_start);
+            hunks.push(Hunk { old_start, new_start, old_len, lines: Vec::new() });
+            in_hunk = true;
+            continue;
+        }
+        if !in_hunk {
+            // Headers such as ---/+++, "diff --git" or "index" lines
+            continue;
+        }
+        let hunk = hunks.last_mut().expect("in_hunk implies a hunk exists");
+        let (kind, text) = match line.chars().next() {
+            Some(' ') => (LineKind::Context, &line[1..]),
+            Some('-') => (LineKind::Remove, &line[1..]),
+            Some('+') => (LineKind::Add, &line[1..]),
+            Some('\\') => {
+                if let Some(last) = hunk.lines.last_mut() {
+                    last.eol = false;
+                }
+                continue;
+            }
+            // Blank context lines frequently lose their leading space
+            None => (LineKind::Context, ""),
+            Some(_) => {
+                in_hunk = false;
+                continue;
+            }
+        };
+        hunk.lines.push(HunkLine { kind, text: text.to_string(), eol: true });
+    }
+
+    if hunks.is_empty() {
+        return Err("No hunks found in patch".to_string());
+    }
+    Ok(hunks)
+}
+
+// Helper function to escape text for the diff-match-patch patch text format
+fn dmp_encode(text: &str) -> String {
+    text.replace('%', "%25").replace('\n', "%0A").replace('\r', "%0D")
+}
+
+// Helper function to count the chars making up the first `line_idx` lines
+fn line_offset(text: &str, line_idx: usize) -> usize {
+    text.split_inclusive('\n').take(line_idx).map(|l| l.chars().count()).sum()
+}
+
+// Helper function to express one unified-diff hunk as a diff-match-patch patch
+// located at `line_idx` of `text`
+fn hunk_to_dmp(lines: &[HunkLine], text: &str, line_idx: usize, eol: &str) -> String {
+    let start = line_offset(text, line_idx);
+    let mut body = String::new();
+    let (mut len1, mut len2) = (0, 0);
+    for line in lines {
+        let full = if line.eol { format!("{}{}", line.text, eol) } else { line.text.clone() };
+        let chars = full.chars().count();
+        let sign = match line.kind {
+            LineKind::Context => {
+                len1 += chars;
+                len2 += chars;
+                ' '
+            }
+            LineKind::Remove => {
+                len1 += chars;
+                '-'
+            }
+            LineKind::Add => {
+                len2 += chars;
+                '+'
+            }
+        };
+        body.push(sign);
+        body.push_str(&dmp_encode(&full));
+        body.push('\n');
+    }
+    let coord = |len: usize| if len == 0 { format!("{},0", start) } else { format!("{},{}", start + 1, len) };
+    format!("@@ -{} +{} @@\n{}", coord(len1), coord(len2), body)
+}
+
+// Helper function to find the old side of a hunk in `lines`, preferring the
+// occurrence closest to `line_idx`. Tries an exact match first, then, when
+// `loose`, one that ignores trailing whitespace.
+fn find_hunk(lines: &[&str], old: &[&str], line_idx: usize, loose: bool) -> Option<usize> {
+    if old.is_empty() {
+        return Some(line_idx.min(lines.len()));
+    }
+    if old.len() > lines.len() {
+        return None;
+    }
+    let strip_eol = |l: &str| l.trim_end_matches(['\r', '\n']).to_string();
+    let exact = |a: &str, b: &str| strip_eol(a) == b;
+    let loose_match = |a: &str, b: &str| a.trim_end() == b.trim_end();
+    let matchers = [&exact as &dyn Fn(&str, &str) -> bool, &loose_match];
+    for matches in &matchers[..if loose { 2 } else { 1 }] {
+        let found = (0..=lines.len() - old.len())
+            .filter(|&p| old.iter().enumerate().all(|(i, o)| matches(lines[p + i], o)))
+            .min_by_key(|&p| p.abs_diff(line_idx));
+        if found.is_some() {
+            return found;
+        }
+    }
+    None
+}
+
+// Helper function to apply one hunk whose old side was located at `pos`
+fn splice_hunk(lines: &[&str], hunk: &Hunk, pos: usize, eol: &str) -> String {
+    let mut out = String::new();
+    lines[..pos].iter().for_each(|l| out.push_str(l));
+    let mut idx = pos;
+    for line in &hunk.lines {
+        match line.kind {
+            LineKind::Context => {
+                out.push_str(lines[idx]);
+                idx += 1;
+            }
+            LineKind::Remove => idx += 1,
+            LineKind::Add => {
+                out.push_str(&line.text);
+                if line.eol {
+                    out.push_str(eol);
+                }
+            }
+        }
+    }
+    lines[idx..].iter().for_each(|l| out.push_str(l));
+    out
+}
+
+// Helper function to fall back to diff-match-patch fuzzy matching for a hunk.
+// diff-match-patch treats a patch without leading/trailing equality as being
+// anchored at the start/end of the text, so missing edge context is borrowed
+// from the expected location.
+fn fuzzy_apply_hunk(dmp: &DiffMatchPatch, text: &str, lines: &[&str], hunk: &Hunk, line_idx: usize, eol: &str) -> Result<Option<String>, String> {
+    let old_count = hunk.lines.iter().filter(|l| l.kind != LineKind::Add).count();
+    let mut start_idx = line_idx.min(lines.len());
+    let mut patch_lines: Vec<HunkLine> = Vec::with_capacity(hunk.lines.len() + 2);
+    let context_line = |l: &str| HunkLine {
+        kind: LineKind::Context,
+        text: l.trim_end_matches(['\r', '\n']).to_string(),
+        eol: l.ends_with('\n'),
+    };
+    if hunk.lines.first().is_some_and(|l| l.kind != LineKind::Context) && start_idx > 0 {
+        start_idx -= 1;
+        patch_lines.push(context_line(lines[start_idx]));
+    }
+    patch_lines.extend(hunk.lines.iter().cloned());
+    if let Some(next) = lines.get(line_idx + old_count) {
+        if hunk.lines.last().is_some_and(|l| l.kind != LineKind::Context) {
+            patch_lines.push(context_line(next));
+        }
+    }
+
+    let dmp_text = hunk_to_dmp(&patch_lines, text, start_idx, eol);
+    let patches = dmp.patch_from_text::<Compat>(&dmp_text).map_err(|e| format!("{:?}", e))?;
+    let (new_text, results) = dmp.patch_apply(&patches, text).map_err(|e| format!("{:?}", e))?;
+    Ok(results.iter().all(|&b| b).then_some(new_text))
+}
+
+/// How hunks are located in the file they patch.
+pub(crate) enum Matching<'a> {
+    /// Only where the context and removed lines are exactly as in the hunk,
+    /// whose header must count them right.
+    Exact,
+    /// Exactly, then ignoring trailing whitespace, then by diff-match-patch.
+    Fuzzy(&'a DiffMatchPatch),
+}
+
+// Helper function to apply hunks one at a time, returning the new content and
+// whether each hunk applied
+pub(crate) fn apply_hunks(matching: Matching, original: &str, patch: &FilePatch, tracer: &mut Tracer) -> Result<(String, Vec<bool>), String> {
+    let hunks = patch.hunks.as_ref().map_err(Clone::clone)?;
+    let file = patch.path();
+    let eol = if original.contains("\r\n") { "\r\n" } else { "\n" };
+    let mut text = original.to_string();
+    let mut line_delta: isize = 0;
+    let mut applied = Vec::with_capacity(hunks.len());
+
+    for (index, hunk) in hunks.iter().enumerate() {
+        let base_idx = if hunk.old_len == 0 { hunk.old_start } else { hunk.old_start.saturating_sub(1) };
+        let line_idx = (base_idx as isize + line_delta).max(0) as usize;
+        let lines: Vec<&str> = text.split_inclusive('\n').collect();
+        let old: Vec<&str> = hunk.lines.iter().filter(|l| l.kind != LineKind::Add).map(|l| l.text.as_str()).collect();
+
+        let loose = matches!(matching, Matching::Fuzzy(_));
+        let new_text = match find_hunk(&lines, &old, line_idx, loose) {
+            Some(_) if !loose && hunk.old_len != old.len() => {
+                tracer.step(file, "hunk", || format!("Hunk {} (line {}) counts {} old lines in its header but has {}", index + 1, line_idx + 1, hunk.old_len, old.len()));
+                None
+            }
+            Some(pos) => {
+                log::trace!("Hunk at line {} matched exactly at line {}", line_idx + 1, pos + 1);
+                tracer.step(file, "hunk", || format!("Hunk {} (line {}) matched exactly at line {}", index + 1, line_idx + 1, pos + 1));
+                Some(splice_hunk(&lines, hunk, pos, eol))
+            }
+            None => {
+                let Matching::Fuzzy(dmp) = matching else {
+                    tracer.step(file, "hunk", || format!("Hunk {} (line {}) has no exact match", index + 1, line_idx + 1));
+                    applied.push(false);
+                    continue;
+                };
+                log::trace!("Hunk at line {} has no exact match, trying fuzzy match", line_idx + 1);
+                let fuzzy = fuzzy_apply_hunk(dmp, &text, &lines, hunk, line_idx, eol)?;
+                tracer.step(file, "hunk", || match fuzzy {
+                    Some(_) => format!("Hunk {} (line {}) has no exact match, applied by fuzzy matching", index + 1, line_idx + 1),
+                    None => format!("Hunk {} (line {}) has no exact or fuzzy match", index + 1, line_idx + 1),
+                });
+                fuzzy
+            }
+        };
+        match new_text {
+            Some(new_text) => {
+                text = new_text;
+                let removed = hunk.lines.iter().filter(|l| l.kind == LineKind::Remove).count() as isize;
+                let added = hunk.lines.iter().filter(|l| l.kind == LineKind::Add).count() as isize;
+                line_delta += added - removed;
+                applied.push(true);
+            }
+            None => applied.push(false),
+        }
+    }
+    Ok((text, applied))
+}
+
+// Helper function to build the content of a file created from /dev/null
+fn new_file_content(hunks: &[Hunk]) -> String {
+    let mut content = String::new();
+    for line in hunks.iter().flat_map(|h| &h.lines).filter(|l| l.kind != LineKind::Remove) {
+        content.push_str(&line.text);
+        if line.eol {
+            content.push('\n');
+        }
+    }
+    content
+}
+
+// Helper function to cut a window of the current content around the failed hunks
+fn failure_context(content: &str, hunks: &[Hunk], applied: Option<&[bool]>, context_lines: usize) -> FileContext {
+    let failed_hunks: Vec<usize> = match applied {
+        Some(applied) => applied.iter().enumerate().filter(|(_, ok)| !**ok).map(|(i, _)| i).collect(),
+        None => (0..hunks.len()).collect(),
+    };
+    let lines: Vec<&str> = content.lines().collect();
+    let total_lines = lines.len();
+
+    let spans: Vec<(usize, usize)> = failed_hunks
+        .iter()
+        .filter_map(|&i| hunks.get(i).map(|h| (h.old_start, h.old_len)))
+        .collect();
+    let (first, last) = if spans.is_empty() {
+        (1, total_lines)
+    } else {
+        let first = spans.iter().map(|(start, _)| *start).min().unwrap_or(1);
+        let last = spans.iter().map(|(start, len)| start + len.saturating_sub(1)).max().unwrap_or(total_lines);
+        (first, last)
+    };
+    let start_line = first.saturating_sub(context_lines).max(1).min(total_lines.max(1));
+    let end_line = (last + context_lines).min(total_lines).max(start_line.min(total_lines));
+    let content = if total_lines == 0 {
+        String::new()
+    } else {
+        lines[start_line - 1..end_line].join("\n")
+    };
+
+    FileContext { start_line, end_line, total_lines, failed_hunks, content }
+}
+
+impl PatchSet {
+    /// Parses `patch_content`, stripping `strip_level` leading components from
+    /// every path like `patch -pN`. Hunk parse errors are kept per file and
+    /// reported as `PARSE_ERROR` when the set is applied.
+    pub fn parse(patch_content: &str, strip_level: usize) -> PatchSet {
+        let files = split_patch_content(patch_content)
+            .into_iter()
+            .map(|section| {
+                if section.pure_rename {
+                    return FilePatch {
+                        old_path: Some(strip_path(&section.old_path, 0)),
+                        new_path: Some(strip_path(&section.new_path, 0)),
+                        hunks: Ok(Vec::new()),
+                        raw: section.text,
+                    };
+                }
+                let strip = |path: String| (path != DEV_NULL).then(|| strip_path(&path, strip_level));
+                FilePatch {
+                    old_path: strip(section.old_path),
+                    new_path: strip(section.new_path),
+                    hunks: parse_hunks(&section.text),
+                    raw: section.text,
+                }
+            })
+            .collect();
+        PatchSet { files, strip_level }
+    }
+
+    /// Applies every file patch to `store` with default options.
+    pub fn apply(&self, store: &dyn FileStore) -> ApplyOutcome {
+        self.apply_with(store, &ApplyOptions::default())
+    }
+
+    /// Applies every file patch to `store` with the default engine, see
+    /// [`PatchSet::apply_using`].
+    pub fn apply_with(&self, store: &dyn FileStore, options: &ApplyOptions) -> ApplyOutcome {
+        self.apply_using(&DmpEngine::default(), store, options)
+    }
+
+    /// Applies every file patch to `store`, with `engine` working out the new
+    /// content of modified files. Files are independent: a failure in one
+    /// file is recorded in the outcome and the rest still apply. Notebooks
+    /// patched through their source view, see
+    /// [`crate::notebook::view_paths`], are patched in that view.
+    pub fn apply_using<E: PatchEngine + ?Sized>(&self, engine: &E, store: &dyn FileStore, options: &ApplyOptions) -> ApplyOutcome {
+        let mut outcome = ApplyOutcome::default();
+        let mut tracer = Tracer { enabled: options.trace, steps: Vec::new() };
+
+        let notebooks = notebook::view_paths(self, store);
+        for file in self.files.iter().filter(|f| notebooks.contains(f.path())) {
+            tracer.step(file.path(), "notebook", || "Patching the notebook's source view".to_string());
+        }
+        let view;
+        let store: &dyn FileStore = if notebooks.is_empty() {
+            store
+        } else {
+            view = NotebookView::new(store, notebooks);
+            &view
+        };
+
+        // Snapshot every target up front so edits made while the patch is
+        // being applied (e.g. an editor auto-saving) are not overwritten
+        let mut snapshots: HashMap<&str, Option<u64>> = self.files.iter().map(|f| (f.path(), fingerprint(store, f.path()))).collect();
+
+        for file in &self.files {
+            let snapshot = snapshots.get(file.path()).copied().flatten();
+            match apply_file(engine, store, file, snapshot, options, self.strip_level, &mut tracer) {
+                Ok(path) => {
+                    // Later sections of the same patch may touch this file again
+                    for touched in file.old_path.iter().chain(file.new_path.iter()) {
+                        snapshots.insert(touched, fingerprint(store, touched));
+                    }
+                    outcome.applied_files.push(path)
+                }
+                Err(failure) => {
+                    tracer.step(&failure.file, "failed", || format!("{:?}: {}", failure.code, failure.message));
+                    outcome.details.push(failure)
+                }
+            }
+        }
+
+        outcome.trace = tracer.steps;
+        outcome
+    }
+}
+
+// Helper function to refuse writes that would leave less than `reserve` bytes free
+fn check_space(store: &dyn FileStore, path: &str, size: u64, reserve: u64) -> Result<(), PatchFailure> {
+    match store.available_space(path) {
+        Some(available) if available < size.saturating_add(reserve) => {
+            log::warn!("Not writing {}: {} bytes needed plus {} reserved, {} available", path, size, reserve, available);
+            Err(PatchFailure::new(
+                ErrorCode::DiskFull,
+                path,
+                format!("Not enough disk space to write {}: {} bytes needed plus {} reserved, {} available", path, size, reserve, available),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Helper function to hash a file's current content, `None` if it can't be read
+fn fingerprint(store: &dyn FileStore, path: &str) -> Option<u64> {
+    let data = store.read(path).ok()?;
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+/// Revision token of file content, as returned with file reads and checked
+/// against `ifRevision` before writes.
+pub fn revision(data: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Checks that every file in `expected` is still at the revision the caller
+/// read it at; an empty revision means the file must not exist yet.
+pub fn check_revisions(store: &dyn FileStore, expected: &HashMap<String, String>) -> Vec<PatchFailure> {
+    let mut paths: Vec<&String> = expected.keys().collect();
+    paths.sort();
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let current = store.read(path).ok().map(|data| revision(&data)).unwrap_or_default();
+            let wanted = &expected[path];
+            (current != *wanted).then(|| {
+                let message = match (wanted.is_empty(), current.is_empty()) {
+                    (true, _) => format!("{} was created since it was read; re-read it and regenerate the change", path),
+                    (false, true) => format!("{} was deleted since revision {} was read", path, wanted),
+                    (false, false) => format!("{} changed since revision {} was read (now {}); re-read it and regenerate the change", path, wanted, current),
+                };
+                PatchFailure::new(ErrorCode::StaleRevision, path, message)
+            })
+        })
+        .collect()
+}
+
+// Helper function to fail when a file no longer matches its snapshot
+fn check_unchanged(store: &dyn FileStore, path: &str, snapshot: Option<u64>) -> Result<(), PatchFailure> {
+    if fingerprint(store, path) == snapshot {
+        return Ok(());
+    }
+    log::warn!("File {} changed on disk while the patch was being applied", path);
+    Err(PatchFailure::new(
+        ErrorCode::ConcurrentModification,
+        path,
+        format!("File {} was modified by another process while the patch was being applied; re-read it and regenerate the patch", path),
+    ))
+}
+
+// Helper function to apply one file patch, returning the path that was written
+fn apply_file<E: PatchEngine + ?Sized>(
+    engine: &E,
+    store: &dyn FileStore,
+    file: &FilePatch,
+    snapshot: Option<u64>,
+    options: &ApplyOptions,
+    strip_level: usize,
+    tracer: &mut Tracer,
+) -> Result<String, PatchFailure> {
+    let file_path = file.path().to_string();
+    if file_path.is_empty() {
+        return Err(PatchFailure::new(ErrorCode::ParseError, &file_path, "Patch header has an empty file path".to_string()));
+    }
+    for path in file.old_path.iter().chain(file.new_path.iter()) {
+        if let Err(e) = store.validate(path) {
+            log::warn!("Rejected patch target {}: {}", path, e);
+            return Err(PatchFailure::new(ErrorCode::PathEscape, path, e));
+        }
+        if let Err(e) = store.check_writable(path) {
+            log::warn!("Rejected patch target {}: {}", path, e);
+            return Err(PatchFailure::new(ErrorCode::ProtectedPath, path, e));
+        }
+    }
+    let mut targets: Vec<&String> = file.old_path.iter().chain(file.new_path.iter()).collect();
+    targets.dedup();
+    for path in targets {
+        tracer.step(path, "resolve", || format!("Resolved to {} (strip level {})", store.display_path(path), strip_level));
+    }
+
+    log::debug!("Processing patch for file: {}", file_path);
+
+    let parse_failure = |e: &String| {
+        PatchFailure::new(ErrorCode::ParseError, &file_path, format!("Failed to parse patch for file {}: {}. Patch text: {}", file_path, e, patch_snippet(&file.raw)))
+    };
+
+    match (&file.old_path, &file.new_path) {
+        (None, None) => Err(PatchFailure::new(ErrorCode::ParseError, &file_path, "Patch has neither an old nor a new path".to_string())),
+        (None, Some(_)) => {
+            // New file creation
+            let hunks = file.hunks.as_ref().map_err(parse_failure)?;
+            let content = editorconfig::settings_for(store, &file_path).apply(&new_file_content(hunks));
+            check_space(store, &file_path, content.len() as u64, options.disk_reserve)?;
+            check_unchanged(store, &file_path, snapshot)?;
+            store
+                .write(&file_path, &content)
+                .map_err(|e| PatchFailure::new(ErrorCode::WriteFailed, &file_path, format!("Failed to write new file {}: {}", file_path, e)))?;
+            tracer.step(&file_path, "write", || format!("Created with {} bytes", content.len()));
+            log::info!("Created new file: {}", file_path);
+            Ok(file_path)
+        }
+        (Some(_), None) => {
+            // File deletion
+            log::debug!("Attempting to delete file: {}", file_path);
+            if !store.exists(&file_path) {
+                log::warn!("File {} marked for deletion in patch, but it does not exist.", file_path);
+                return Err(PatchFailure::new(ErrorCode::FileNotFound, &file_path, format!("File to delete does not exist: {}", file_path)));
+            }
+            check_unchanged(store, &file_path, snapshot)?;
+            store
+                .remove(&file_path)
+                .map_err(|e| PatchFailure::new(ErrorCode::WriteFailed, &file_path, format!("Failed to delete file {}: {}", file_path, e)))?;
+            tracer.step(&file_path, "delete", || "Deleted".to_string());
+            log::info!("Deleted file: {}", file_path);
+            Ok(file_path)
+        }
+        (Some(_), Some(new_path)) if file.is_rename() && file.hunks.as_ref().is_ok_and(|h| h.is_empty()) => {
+            // Pure rename: the content moves as is, so binary files are fine
+            let data = store.read(&file_path).map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => PatchFailure::new(ErrorCode::FileNotFound, &file_path, format!("File to rename does not exist: {}", file_path)),
+                _ => PatchFailure::new(ErrorCode::ReadFailed, &file_path, format!("Failed to read file {}: {}", file_path, e)),
+            })?;
+            if store.exists(new_path) {
+                return Err(PatchFailure::new(ErrorCode::WriteFailed, new_path, format!("Cannot rename {} to {}: the target already exists", file_path, new_path)));
+            }
+            check_space(store, new_path, data.len() as u64, options.disk_reserve)?;
+            check_unchanged(store, &file_path, snapshot)?;
+            store
+                .write(new_path, &data)
+                .map_err(|e| PatchFailure::new(ErrorCode::WriteFailed, new_path, format!("Failed to write renamed file {}: {}", new_path, e)))?;
+            store.remove(&file_path).map_err(|e| {
+                PatchFailure::new(ErrorCode::WriteFailed, &file_path, format!("Failed to remove {} after renaming it to {}: {}", file_path, new_path, e))
+            })?;
+            tracer.step(new_path, "rename", || format!("Renamed from {} ({} bytes)", file_path, data.len()));
+            log::info!("Renamed file: {} -> {}", file_path, new_path);
+            Ok(new_path.clone())
+        }
+        (Some(_), Some(new_path)) => {
+            // File modification, possibly combined with a rename
+            log::debug!("Attempting to modify file: {}", file_path);
+            if !store.exists(&file_path) {
+                log::warn!("File {} marked for modification in patch, but it does not exist.", file_path);
+                return Err(PatchFailure::new(ErrorCode::FileNotFound, &file_path, format!("File to modify does not exist: {}", file_path)));
+            }
+            let original_content = read_text_file(store, &file_path).map_err(|(code, e)| {
+                log::error!("Failed to read existing file {} for patching: {}", file_path, e);
+                PatchFailure::new(code, &file_path, format!("Failed to read file {}: {}", file_path, e))
+            })?;
+            tracer.step(&file_path, "read", || format!("Read {} bytes, {} lines", original_content.len(), original_content.lines().count()));
+            let hunks = file.hunks.as_ref().map_err(parse_failure)?;
+            log::trace!("Parsed {} patch hunk(s) for file {}", hunks.len(), file_path);
+
+            let (new_content, applied) = engine.patch_file(file, &original_content, tracer).map_err(|e| {
+                PatchFailure::new(ErrorCode::ContextMismatch, &file_path, format!("Error applying patch for file {}: {}", file_path, e))
+                    .with_context(failure_context(&original_content, hunks, None, options.context_lines))
+            })?;
+            if !applied.iter().all(|&b| b) {
+                log::warn!("Partial patch application for file {}: {:?}", file_path, applied);
+                return Err(PatchFailure::new(ErrorCode::ContextMismatch, &file_path, format!("Failed to apply patch for file {}: partial application", file_path))
+                    .with_context(failure_context(&original_content, hunks, Some(&applied), options.context_lines)));
+            }
+
+            check_space(store, new_path, new_content.len() as u64, options.disk_reserve)?;
+            check_unchanged(store, &file_path, snapshot)?;
+            store
+                .write(new_path, new_content.as_bytes())
+                .map_err(|e| PatchFailure::new(ErrorCode::WriteFailed, new_path, format!("Failed to write modified file {}: {}", new_path, e)))?;
+            tracer.step(new_path, "write", || format!("Wrote {} bytes", new_content.len()));
+            if file.is_rename() {
+                store.remove(&file_path).map_err(|e| {
+                    PatchFailure::new(ErrorCode::WriteFailed, &file_path, format!("Failed to remove {} after renaming it to {}: {}", file_path, new_path, e))
+                })?;
+                tracer.step(&file_path, "delete", || format!("Removed after renaming to {}", new_path));
+                log::info!("Renamed file: {} -> {}", file_path, new_path);
+            } else {
+                log::info!("Modified file: {}", file_path);
+            }
+            Ok(new_path.clone())
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Approval rules evaluated against a patch before it is applied, such as
+//! "deny writes to *.lock" or "deleting more than 5 files needs a
+//! confirmation token". The server decides what each [`Action`] means for a
+//! request; this module only reports which rules a patch trips.
+
+use crate::packages::{package_of, Package};
+use crate::patch::{FilePatch, PatchSet};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Reject the patch.
+    Deny,
+    /// Require the same patch to have been dry-run first.
+    DryRun,
+    /// Require a confirmation token issued for this patch.
+    Confirm,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Create,
+    Modify,
+    Delete,
+    Rename,
+}
+
+impl Operation {
+    pub fn of(file: &FilePatch) -> Self {
+        if file.is_creation() {
+            Operation::Create
+        } else if file.is_deletion() {
+            Operation::Delete
+        } else if file.is_rename() {
+            Operation::Rename
+        } else {
+            Operation::Modify
+        }
+    }
+}
+
+/// One rule as written in the config file.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub id: String,
+    pub action: Action,
+    /// Globs over patch paths; empty matches every file.
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Names of the /api/packages members the rule is limited to; empty
+    /// matches files anywhere.
+    #[serde(default)]
+    pub packages: Vec<String>,
+    /// Operations the rule applies to; empty matches all of them.
+    #[serde(default)]
+    pub operations: Vec<Operation>,
+    /// The rule only fires when at least this many files match.
+    #[serde(default = "default_min_files")]
+    pub min_files: usize,
+    pub message: Option<String>,
+}
+
+fn default_min_files() -> usize {
+    1
+}
+
+/// Built-in confirm rules for destructive patches: deleting at least
+/// `deletions` files, or touching at least `files`. A threshold of 0
+/// disables that rule.
+pub fn guard_rules(deletions: usize, files: usize) -> Vec<RuleConfig> {
+    let mut rules = Vec::new();
+    if deletions > 0 {
+        rules.push(RuleConfig {
+            id: "mass-delete".to_string(),
+            action: Action::Confirm,
+            paths: Vec::new(),
+            packages: Vec::new(),
+            operations: vec![Operation::Delete],
+            min_files: deletions,
+            message: Some(format!("Patch deletes {} or more files", deletions)),
+        });
+    }
+    if files > 0 {
+        rules.push(RuleConfig {
+            id: "large-patch".to_string(),
+            action: Action::Confirm,
+            paths: Vec::new(),
+            packages: Vec::new(),
+            operations: Vec::new(),
+            min_files: files,
+            message: Some(format!("Patch touches {} or more files", files)),
+        });
+    }
+    rules
+}
+
+/// What a patch would do, shown to whoever has to confirm it.
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub files: usize,
+    pub created: usize,
+    pub modified: usize,
+    pub deleted: usize,
+    pub renamed: usize,
+    #[serde(rename = "linesAdded")]
+    pub lines_added: usize,
+    #[serde(rename = "linesRemoved")]
+    pub lines_removed: usize,
+    pub changes: Vec<SummaryEntry>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct SummaryEntry {
+    pub path: String,
+    pub operation: Operation,
+}
+
+pub fn summarize(patch: &PatchSet) -> Summary {
+    let mut summary = Summary { files: patch.files.len(), ..Summary::default() };
+    for file in &patch.files {
+        let operation = Operation::of(file);
+        match operation {
+            Operation::Create => summary.created += 1,
+            Operation::Modify => summary.modified += 1,
+            Operation::Delete => summary.deleted += 1,
+            Operation::Rename => summary.renamed += 1,
+        }
+        let (added, removed) = file.line_counts();
+        summary.lines_added += added;
+        summary.lines_removed += removed;
+        summary.changes.push(SummaryEntry { path: file.new_path.clone().unwrap_or_else(|| file.path().to_string()), operation });
+    }
+    summary
+}
+
+struct Rule {
+    config: RuleConfig,
+    paths: Option<GlobSet>,
+}
+
+/// A compiled set of rules.
+#[derive(Default)]
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+/// A rule the patch trips, with the files that matched it.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub rule: String,
+    pub action: Action,
+    pub files: Vec<String>,
+    pub message: String,
+}
+
+impl Policy {
+    pub fn new(rules: Vec<RuleConfig>) -> Result<Self, String> {
+        let rules = rules
+            .into_iter()
+            .map(|config| {
+                let paths = if config.paths.is_empty() {
+                    None
+                } else {
+                    let mut builder = GlobSetBuilder::new();
+                    for pattern in &config.paths {
+                        let glob = Glob::new(pattern).map_err(|e| format!("Policy rule {}: invalid glob {}: {}", config.id, pattern, e))?;
+                        builder.add(glob);
+                    }
+                    Some(builder.build().map_err(|e| format!("Policy rule {}: {}", config.id, e))?)
+                };
+                Ok(Rule { config, paths })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Policy { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether a rule is limited to packages, so [`Policy::evaluate_in`]
+    /// needs the workspace's.
+    pub fn uses_packages(&self) -> bool {
+        self.rules.iter().any(|r| !r.config.packages.is_empty())
+    }
+
+    /// Returns every rule `patch` trips, in configuration order. Rules
+    /// limited to packages match nothing.
+    pub fn evaluate(&self, patch: &PatchSet) -> Vec<Violation> {
+        self.evaluate_in(patch, &[])
+    }
+
+    /// Returns every rule `patch` trips in a workspace with `packages`.
+    pub fn evaluate_in(&self, patch: &PatchSet, packages: &[Package]) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        for rule in &self.rules {
+            let files: Vec<String> = patch
+                .files
+                .iter()
+                .filter(|f| rule.config.operations.is_empty() || rule.config.operations.contains(&Operation::of(f)))
+                .filter(|f| {
+                    let mut paths = f.old_path.iter().chain(f.new_path.iter());
+                    rule.paths.as_ref().is_none_or(|globs| paths.any(|p| globs.is_match(p)))
+                })
+                .filter(|f| {
+                    let mut paths = f.old_path.iter().chain(f.new_path.iter());
+                    rule.config.packages.is_empty()
+                        || paths.any(|p| package_of(packages, p).is_some_and(|package| rule.config.packages.contains(&package.name)))
+                })
+                .map(|f| f.path().to_string())
+                .collect();
+            if files.is_empty() || files.len() < rule.config.min_files {
+                continue;
+            }
+            let message = rule.config.message.clone().unwrap_or_else(|| match rule.config.action {
+                Action::Deny => format!("Rule {} does not allow changing these files", rule.config.id),
+                Action::DryRun => format!("Rule {} requires a dry run of this patch first", rule.config.id),
+                Action::Confirm => format!("Rule {} requires confirming this patch", rule.config.id),
+            });
+            violations.push(Violation { rule: rule.config.id.clone(), action: rule.config.action, files, message });
+        }
+        violations
+    }
+}
@@ -0,0 +1,335 @@
+// Collaboration presence: GET /api/presence?client=<id>&root=<dir> upgrades
+// to a WebSocket where each client says which files it has selected, and the
+// server pushes to everyone with the same root open who else is there, what
+// they have selected and which files are being patched right now (announced
+// by the client or taken from in-flight /api/apply_patch calls). Nothing is
+// locked: the point is that teammates sharing an instance can see each other.
+//
+// Client frames are JSON text messages:
+//   {"type": "select",   "files": ["src/main.rs"]}
+//   {"type": "patching", "files": ["src/main.rs"]}   // [] when done
+// The server sends {"type": "presence", "root": ..., "clients": [...]} on
+// every change. A client that falls QUEUE_MESSAGES behind is dropped and its
+// socket closed. As with the terminal, browsers may only connect from this
+// server's own origin or one in ALLOWED_ORIGINS.
+
+use crate::auth::{Session, Stores};
+use crate::csrf::origin_allowed;
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{self, Codec, Frame, Message};
+use actix_web::http::header;
+use actix_web::web::BytesMut;
+use actix_web::{get, rt, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{channel, Sender};
+use tokio_stream::wrappers::ReceiverStream;
+
+const MAX_FRAME_BYTES: usize = 64 * 1024;
+const MAX_FILES: usize = 1000;
+const QUEUE_MESSAGES: usize = 64;
+
+#[derive(Serialize, Clone)]
+struct MemberView {
+    client: String,
+    user: String,
+    selected: Vec<String>,
+    patching: Vec<String>,
+    since: String,
+    #[serde(skip)]
+    joined: DateTime<Utc>,
+}
+
+impl MemberView {
+    fn new(client: String, user: String, patching: Vec<String>, joined: DateTime<Utc>) -> Self {
+        let since = joined.to_rfc3339_opts(SecondsFormat::Secs, true);
+        MemberView { client, user, selected: Vec::new(), patching, since, joined }
+    }
+}
+
+struct Member {
+    root: String,
+    view: MemberView,
+    sender: Sender<Message>,
+}
+
+/// An /api/apply_patch call in progress.
+struct ActivePatch {
+    root: String,
+    client: String,
+    user: String,
+    files: Vec<String>,
+    started: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct PresenceState {
+    members: HashMap<u64, Member>,
+    patches: HashMap<u64, ActivePatch>,
+}
+
+#[derive(Default)]
+pub struct Presence {
+    state: Mutex<PresenceState>,
+    next_id: AtomicU64,
+    origins: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum ClientMessage {
+    Select { files: Vec<String> },
+    Patching { files: Vec<String> },
+}
+
+#[derive(Deserialize)]
+pub struct PresenceQuery {
+    client: String,
+    root: String,
+}
+
+impl Presence {
+    /// Presence for pages of `origins` besides the server's own.
+    pub fn new(origins: &[String]) -> Self {
+        Presence { origins: origins.to_vec(), ..Default::default() }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, PresenceState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Sends the current presence of `root` to everyone who has it open
+    fn broadcast(state: &mut PresenceState, root: &str) {
+        let mut clients: Vec<MemberView> = state.members.values().filter(|m| m.root == root).map(|m| m.view.clone()).collect();
+        for patch in state.patches.values().filter(|p| p.root == root) {
+            match clients.iter_mut().find(|c| c.client == patch.client) {
+                Some(view) => view.patching.extend(patch.files.iter().cloned()),
+                None => clients.push(MemberView::new(patch.client.clone(), patch.user.clone(), patch.files.clone(), patch.started)),
+            }
+        }
+        clients.sort_by(|a, b| a.joined.cmp(&b.joined).then_with(|| a.client.cmp(&b.client)));
+        let text = json!({ "type": "presence", "root": root, "clients": clients }).to_string();
+        // Gone and too slow clients alike are dropped
+        state.members.retain(|_, m| m.root != root || m.sender.try_send(Message::Text(text.clone().into())).is_ok());
+    }
+
+    fn join(&self, root: String, client: String, user: String, sender: Sender<Message>) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut state = self.lock();
+        let view = MemberView::new(client, user, Vec::new(), Utc::now());
+        state.members.insert(id, Member { root: root.clone(), view, sender });
+        Self::broadcast(&mut state, &root);
+        id
+    }
+
+    // False once the member has been dropped
+    fn update(&self, id: u64, message: ClientMessage) -> bool {
+        let mut state = self.lock();
+        let Some(member) = state.members.get_mut(&id) else {
+            return false;
+        };
+        match message {
+            ClientMessage::Select { mut files } => {
+                files.truncate(MAX_FILES);
+                member.view.selected = files;
+            }
+            ClientMessage::Patching { mut files } => {
+                files.truncate(MAX_FILES);
+                member.view.patching = files;
+            }
+        }
+        let root = member.root.clone();
+        Self::broadcast(&mut state, &root);
+        state.members.contains_key(&id)
+    }
+
+    fn leave(&self, id: u64) {
+        let mut state = self.lock();
+        if let Some(member) = state.members.remove(&id) {
+            Self::broadcast(&mut state, &member.root);
+        }
+    }
+
+    /// Announces that `client` is applying a patch to `files` under `root`
+    /// until the returned guard is dropped.
+    pub fn start_patch(self: &Arc<Self>, root: &str, client: &str, user: &str, files: Vec<String>) -> PatchGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut state = self.lock();
+        let patch = ActivePatch { root: root.to_string(), client: client.to_string(), user: user.to_string(), files, started: Utc::now() };
+        state.patches.insert(id, patch);
+        Self::broadcast(&mut state, root);
+        PatchGuard { presence: self.clone(), id }
+    }
+
+    /// Other clients that have any of `files` under `root` selected or are
+    /// patching them, for apply_patch responses.
+    pub fn others_on(&self, root: &str, client: Option<&str>, files: &[String]) -> Vec<serde_json::Value> {
+        let state = self.lock();
+        let touches = |list: &[String]| list.iter().filter(|f| files.contains(f)).cloned().collect::<Vec<_>>();
+        let members = state.members.values().filter(|m| m.root == root && Some(m.view.client.as_str()) != client);
+        let mut others: Vec<_> = members
+            .filter_map(|m| {
+                let mut shared = touches(&m.view.selected);
+                shared.extend(touches(&m.view.patching));
+                shared.sort();
+                shared.dedup();
+                (!shared.is_empty()).then(|| json!({ "client": m.view.client, "user": m.view.user, "files": shared }))
+            })
+            .collect();
+        let patches = state.patches.values().filter(|p| p.root == root && Some(p.client.as_str()) != client);
+        others.extend(patches.filter_map(|p| {
+            let shared = touches(&p.files);
+            (!shared.is_empty()).then(|| json!({ "client": p.client, "user": p.user, "files": shared, "patching": true }))
+        }));
+        others
+    }
+
+    /// Returns `(connected clients, patches in progress)`.
+    pub fn stats(&self) -> (usize, usize) {
+        let state = self.lock();
+        (state.members.len(), state.patches.len())
+    }
+}
+
+pub struct PatchGuard {
+    presence: Arc<Presence>,
+    id: u64,
+}
+
+impl Drop for PatchGuard {
+    fn drop(&mut self) {
+        let mut state = self.presence.lock();
+        if let Some(patch) = state.patches.remove(&self.id) {
+            Presence::broadcast(&mut state, &patch.root);
+        }
+    }
+}
+
+#[get("/api/presence")]
+pub async fn connect(
+    req: HttpRequest,
+    payload: web::Payload,
+    query: web::Query<PresenceQuery>,
+    stores: Stores,
+    session: Option<web::ReqData<Session>>,
+    presence: web::Data<Presence>,
+) -> HttpResponse {
+    if !origin_allowed(&req, &presence.origins) {
+        return HttpResponse::Forbidden().json(json!({ "success": false, "error": "Presence can't be joined from this origin; add it to ALLOWED_ORIGINS" }));
+    }
+    if let Err(e) = ws::verify_handshake(req.head()) {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Expected a WebSocket upgrade: {}", e) }));
+    }
+    let root = match stores.open_root(&query.root) {
+        Ok(s) => s.display_path(""),
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid directory path: {}", e) })),
+    };
+    let Some(key) = req.headers().get(header::SEC_WEBSOCKET_KEY) else {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": "Missing Sec-WebSocket-Key" }));
+    };
+    let accept = ws::hash_key(key.as_bytes());
+
+    let user = session.map(|s| s.user.clone()).unwrap_or_else(|| query.client.clone());
+    let (tx, rx) = channel(QUEUE_MESSAGES);
+    let presence = presence.into_inner();
+    let id = presence.join(root.clone(), query.client.clone(), user, tx.clone());
+    log::debug!("Client {} joined presence for {}", query.client, root);
+    rt::spawn(read_frames(payload, presence, id, tx));
+
+    let mut encoder = Codec::new();
+    let frames = ReceiverStream::new(rx).map(move |message| {
+        let mut buf = BytesMut::new();
+        encoder.encode(message, &mut buf).map(|()| buf.freeze())
+    });
+    HttpResponse::SwitchingProtocols()
+        .upgrade("websocket")
+        .insert_header((header::SEC_WEBSOCKET_ACCEPT, &accept[..]))
+        .streaming(frames)
+}
+
+// Reads client frames until the socket closes, then leaves the root
+async fn read_frames(mut payload: web::Payload, presence: Arc<Presence>, id: u64, tx: Sender<Message>) {
+    let mut decoder = Codec::new().max_size(MAX_FRAME_BYTES);
+    let mut buf = BytesMut::new();
+    'read: while let Some(Ok(chunk)) = payload.next().await {
+        buf.extend_from_slice(&chunk);
+        loop {
+            match decoder.decode(&mut buf) {
+                Ok(Some(Frame::Text(text))) => match serde_json::from_slice::<ClientMessage>(&text) {
+                    Ok(message) => {
+                        if !presence.update(id, message) {
+                            break 'read;
+                        }
+                    }
+                    Err(e) => log::debug!("Ignoring presence message: {}", e),
+                },
+                Ok(Some(Frame::Ping(data))) => {
+                    let _ = tx.try_send(Message::Pong(data));
+                }
+                Ok(Some(Frame::Close(reason))) => {
+                    let _ = tx.try_send(Message::Close(reason));
+                    break 'read;
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(e) => {
+                    log::debug!("Presence socket error: {}", e);
+                    break 'read;
+                }
+            }
+        }
+    }
+    presence.leave(id);
+    drop(tx);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+    use repopatch::store::{MemoryStore, MemoryStoreProvider, StoreProvider};
+
+    #[actix_web::test]
+    async fn foreign_pages_cannot_join() {
+        let stores: Arc<dyn StoreProvider> = Arc::new(MemoryStoreProvider::new("/srv", Arc::new(MemoryStore::new())));
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::from(stores))
+                .app_data(web::Data::new(Presence::new(&["https://ui.example.com".to_string()])))
+                .service(connect),
+        )
+        .await;
+        let upgrade = |origin: &str| {
+            TestRequest::get()
+                .uri("/api/presence?client=a&root=/srv")
+                .insert_header((header::HOST, "repopatch.local:3000"))
+                .insert_header((header::ORIGIN, origin))
+                .insert_header((header::UPGRADE, "websocket"))
+                .insert_header((header::CONNECTION, "upgrade"))
+                .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+                .insert_header((header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="))
+                .to_request()
+        };
+        assert_eq!(call_service(&app, upgrade("https://evil.example.com")).await.status(), 403);
+        assert_eq!(call_service(&app, upgrade("https://ui.example.com")).await.status(), 101);
+    }
+
+    #[test]
+    fn clients_that_fall_behind_are_dropped() {
+        let presence = Presence::new(&[]);
+        let (slow, _unread) = channel(QUEUE_MESSAGES);
+        let id = presence.join("/srv".to_string(), "slow".to_string(), "alice".to_string(), slow);
+        for _ in 1..QUEUE_MESSAGES {
+            assert!(presence.update(id, ClientMessage::Select { files: vec!["a.rs".to_string()] }));
+        }
+        assert_eq!(presence.stats(), (1, 0));
+        assert!(!presence.update(id, ClientMessage::Select { files: Vec::new() }), "the queue was full");
+        assert_eq!(presence.stats(), (0, 0));
+    }
+}
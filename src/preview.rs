@@ -0,0 +1,67 @@
+//! Context snippets around a line, for hover previews and jump-to-match
+//! without sending whole files to the client, and line-numbered copies of
+//! files for prompts.
+
+use serde::Serialize;
+
+/// One line of a [`Snippet`].
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SnippetLine {
+    /// 1-based line number.
+    pub number: usize,
+    /// Byte offset of the line start within the file.
+    pub offset: usize,
+    pub text: String,
+}
+
+/// A run of lines around a target line.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Snippet {
+    /// The requested line, clamped to the file.
+    pub line: usize,
+    #[serde(rename = "startOffset")]
+    pub start_offset: usize,
+    /// Byte offset just past the last line of the snippet, newline included.
+    #[serde(rename = "endOffset")]
+    pub end_offset: usize,
+    #[serde(rename = "totalLines")]
+    pub total_lines: usize,
+    pub lines: Vec<SnippetLine>,
+}
+
+/// Returns `line` (1-based) of `content` with up to `context` lines on
+/// either side. Lines past the end are clamped to the last line; an empty
+/// file yields an empty snippet.
+pub fn snippet(content: &str, line: usize, context: usize) -> Snippet {
+    let mut starts = vec![0];
+    starts.extend(content.match_indices('\n').map(|(i, _)| i + 1).filter(|i| *i < content.len()));
+    let total_lines = if content.is_empty() { 0 } else { starts.len() };
+    if total_lines == 0 {
+        return Snippet { line: 0, start_offset: 0, end_offset: 0, total_lines, lines: Vec::new() };
+    }
+
+    let line = line.clamp(1, total_lines);
+    let first = line.saturating_sub(context).max(1);
+    let last = (line + context).min(total_lines);
+    let end_of = |number: usize| starts.get(number).copied().unwrap_or(content.len());
+    let lines = (first..=last)
+        .map(|number| {
+            let offset = starts[number - 1];
+            let text = content[offset..end_of(number)].trim_end_matches('\n').trim_end_matches('\r');
+            SnippetLine { number, offset, text: text.to_string() }
+        })
+        .collect();
+    Snippet { line, start_offset: starts[first - 1], end_offset: end_of(last), total_lines, lines }
+}
+
+/// Returns `content` with every line prefixed by its 1-based number, padded
+/// to the width of the last one: `  9 | fn main() {`. Line endings are kept.
+pub fn number_lines(content: &str) -> String {
+    let total = content.lines().count();
+    let width = total.to_string().len();
+    let mut out = String::with_capacity(content.len() + total * (width + 3));
+    for (i, line) in content.split_inclusive('\n').enumerate() {
+        out.push_str(&format!("{:>width$} | {}", i + 1, line, width = width));
+    }
+    out
+}
@@ -0,0 +1,213 @@
+// Named folder selections per workspace, like a sparse checkout: a profile
+// ("backend only") is a list of include and exclude globs over paths
+// relative to the workspace root. PUT /api/profiles saves one, GET
+// /api/profiles?path=<root> lists them and DELETE /api/profiles?path=&name=
+// removes one. /api/directory and /api/files take `profile` to apply it.
+//
+// Profiles are shared by everyone who can open the workspace and saved as
+// JSON in PROFILES_FILE (default repopatch-profiles.json in the working
+// directory); an empty PROFILES_FILE keeps them in memory. With DATA_DIR
+// each workspace's profiles are a row of the metadata database instead,
+// imported from PROFILES_FILE the first time.
+
+use crate::auth::Stores;
+use crate::validation::ValidJson;
+use repopatch::metadata::{object_entries, write_json_atomically, Metadata};
+use actix_web::{delete, get, put, web, HttpResponse};
+use repopatch::tree::PathFilter;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_PROFILES_FILE: &str = "repopatch-profiles.json";
+const MAX_NAME_CHARS: usize = 100;
+/// Record kind in the metadata database, keyed by workspace root.
+const KIND: &str = "profiles";
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Profile {
+    /// Globs of the files to keep; empty keeps every file.
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+pub struct ProfileStore {
+    path: Option<PathBuf>,
+    metadata: Option<Arc<Metadata>>,
+    /// Profiles by workspace root (display path), then by name.
+    roots: Mutex<BTreeMap<String, BTreeMap<String, Profile>>>,
+}
+
+impl ProfileStore {
+    /// Opens the store named by PROFILES_FILE, or the profiles in
+    /// `metadata` when given. A missing file starts empty; an unreadable or
+    /// invalid one is an error.
+    pub fn from_env(metadata: Option<Arc<Metadata>>) -> Result<Self, String> {
+        let path = match env::var("PROFILES_FILE") {
+            Ok(p) if p.is_empty() => None,
+            Ok(p) => Some(PathBuf::from(p)),
+            Err(_) => Some(PathBuf::from(DEFAULT_PROFILES_FILE)),
+        };
+        if let Some(metadata) = metadata {
+            if let Some(path) = &path {
+                let imported = metadata.import_file(KIND, path, object_entries)?;
+                if imported > 0 {
+                    log::info!("Imported the profiles of {} workspaces from {} into {}", imported, path.display(), metadata.path().display());
+                }
+            }
+            let roots = metadata.load_as(KIND)?.into_iter().collect();
+            return Ok(ProfileStore { path: None, metadata: Some(metadata), roots: Mutex::new(roots) });
+        }
+        let roots = match &path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(content) => serde_json::from_str(&content).map_err(|e| format!("Invalid {}: {}", path.display(), e))?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+                Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+            },
+            None => BTreeMap::new(),
+        };
+        Ok(ProfileStore { path, metadata: None, roots: Mutex::new(roots) })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, BTreeMap<String, Profile>>> {
+        self.roots.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Saves the store after the profiles of `root` changed.
+    fn save(&self, roots: &BTreeMap<String, BTreeMap<String, Profile>>, root: &str) -> Result<(), String> {
+        if let Some(metadata) = &self.metadata {
+            return match roots.get(root) {
+                Some(profiles) => metadata.put(KIND, root, profiles),
+                None => metadata.delete(KIND, root).map(|_| ()),
+            };
+        }
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        write_json_atomically(path, roots)
+    }
+
+    /// The profiles of the workspaces `visible` accepts, by root.
+    pub fn export(&self, visible: impl Fn(&str) -> bool) -> BTreeMap<String, BTreeMap<String, Profile>> {
+        self.lock().iter().filter(|(root, _)| visible(root)).map(|(root, profiles)| (root.clone(), profiles.clone())).collect()
+    }
+
+    /// Adds profiles to the workspace `root`, replacing ones of the same name.
+    pub fn import(&self, root: &str, profiles: BTreeMap<String, Profile>) -> Result<(), String> {
+        for profile in profiles.values() {
+            PathFilter::new(&profile.include, &profile.exclude)?;
+        }
+        let mut roots = self.lock();
+        roots.entry(root.to_string()).or_default().extend(profiles);
+        self.save(&roots, root)
+    }
+
+    /// The compiled filter of the workspace's profile `name`.
+    pub fn filter(&self, root: &str, name: &str) -> Result<PathFilter, String> {
+        let roots = self.lock();
+        let profile = roots.get(root).and_then(|p| p.get(name)).ok_or_else(|| format!("No profile '{}' for {}", name, root))?;
+        PathFilter::new(&profile.include, &profile.exclude)
+    }
+
+    /// Whether the profile `name` keeps the file at `full_path` (a display
+    /// path), going by the innermost workspace containing it that has one.
+    pub fn allows_file(&self, full_path: &str, name: &str) -> Result<bool, String> {
+        let roots = self.lock();
+        let file = Path::new(full_path);
+        let (relative, profile) = roots
+            .iter()
+            .filter_map(|(root, profiles)| Some((file.strip_prefix(root).ok()?, profiles.get(name)?)))
+            .min_by_key(|(relative, _)| relative.components().count())
+            .ok_or_else(|| format!("No profile '{}' for a workspace containing {}", name, full_path))?;
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        Ok(PathFilter::new(&profile.include, &profile.exclude)?.allows(&relative))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ProfilesQuery {
+    path: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteProfileQuery {
+    path: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub struct SaveProfileRequest {
+    #[serde(rename = "directoryPath")]
+    directory_path: String,
+    name: String,
+    #[serde(flatten)]
+    profile: Profile,
+}
+
+#[get("/api/profiles")]
+pub async fn list_profiles(query: web::Query<ProfilesQuery>, stores: Stores, profiles: web::Data<ProfileStore>) -> HttpResponse {
+    let store = match stores.open_root(&query.path) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+    let root = store.display_path("");
+    let list = profiles.lock().get(&root).cloned().unwrap_or_default();
+    HttpResponse::Ok().json(json!({ "success": true, "root": root, "profiles": list }))
+}
+
+#[put("/api/profiles")]
+pub async fn save_profile(body: ValidJson<SaveProfileRequest>, stores: Stores, profiles: web::Data<ProfileStore>) -> HttpResponse {
+    let store = match stores.open_root(&body.directory_path) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid directory path: {}", e) })),
+    };
+    let name = body.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_CHARS {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("name must be 1 to {} characters", MAX_NAME_CHARS) }));
+    }
+    if let Err(e) = PathFilter::new(&body.profile.include, &body.profile.exclude) {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": e }));
+    }
+    let root = store.display_path("");
+    let mut roots = profiles.lock();
+    roots.entry(root.clone()).or_default().insert(name.to_string(), body.profile.clone());
+    match profiles.save(&roots, &root) {
+        Ok(()) => HttpResponse::Ok().json(json!({ "success": true, "root": root, "name": name, "profile": body.profile })),
+        Err(e) => {
+            log::error!("{}", e);
+            HttpResponse::InternalServerError().json(json!({ "success": false, "error": e }))
+        }
+    }
+}
+
+#[delete("/api/profiles")]
+pub async fn delete_profile(query: web::Query<DeleteProfileQuery>, stores: Stores, profiles: web::Data<ProfileStore>) -> HttpResponse {
+    let store = match stores.open_root(&query.path) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+    let root = store.display_path("");
+    let mut roots = profiles.lock();
+    let Some(workspace) = roots.get_mut(&root) else {
+        return HttpResponse::NotFound().json(json!({ "success": false, "error": format!("No profile '{}' for {}", query.name, root) }));
+    };
+    if workspace.remove(&query.name).is_none() {
+        return HttpResponse::NotFound().json(json!({ "success": false, "error": format!("No profile '{}' for {}", query.name, root) }));
+    }
+    if workspace.is_empty() {
+        roots.remove(&root);
+    }
+    match profiles.save(&roots, &root) {
+        Ok(()) => HttpResponse::Ok().json(json!({ "success": true })),
+        Err(e) => {
+            log::error!("{}", e);
+            HttpResponse::InternalServerError().json(json!({ "success": false, "error": e }))
+        }
+    }
+}
@@ -0,0 +1,268 @@
+// Multi-instance proxying: one repopatch instance keeps a registry of other
+// instances and forwards `/remote/{name}/api/...` to them, so a single UI
+// session can browse and patch repositories on several machines. Pointing
+// the UI's endpoint at `http://this-host:3000/remote/{name}` is enough, as
+// every API path is relative to it.
+//
+// Only admins register and remove instances, since the server makes requests
+// to whatever URL is registered, and names are never taken over. Users
+// limited to their roots by USERS_FILE are forwarded with their own bearer
+// token, so the remote instance applies its own limits to them.
+
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use crate::admin::require_admin;
+use crate::auth::Session;
+use crate::validation::ValidJson;
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::RwLock;
+
+const HOPS_HEADER: &str = "X-Repopatch-Hops";
+const MAX_HOPS: u32 = 3;
+pub const MAX_BODY_BYTES: usize = 256 * 1024 * 1024;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RemoteInstance {
+    pub name: String,
+    pub url: String,
+    /// Bearer token sent to the remote instance; never echoed back.
+    #[serde(default, skip_serializing)]
+    pub token: Option<String>,
+}
+
+#[derive(Default)]
+pub struct InstanceRegistry {
+    instances: RwLock<BTreeMap<String, RemoteInstance>>,
+}
+
+impl InstanceRegistry {
+    /// Seeds the registry from REMOTE_INSTANCES, e.g.
+    /// `laptop=http://10.0.0.2:3000,build=https://build.local:3000`.
+    pub fn from_env() -> Self {
+        let registry = InstanceRegistry::default();
+        for entry in env::var("REMOTE_INSTANCES").unwrap_or_default().split(',') {
+            if let Some((name, url)) = entry.trim().split_once('=') {
+                let instance = RemoteInstance { name: name.trim().to_string(), url: url.trim().to_string(), token: None };
+                match registry.insert(instance) {
+                    Ok(()) => log::info!("Registered remote instance {} -> {}", name.trim(), url.trim()),
+                    Err(e) => log::warn!("Ignoring REMOTE_INSTANCES entry {}: {}", entry, e),
+                }
+            }
+        }
+        registry
+    }
+
+    pub fn get(&self, name: &str) -> Option<RemoteInstance> {
+        self.instances.read().unwrap_or_else(|e| e.into_inner()).get(name).cloned()
+    }
+
+    pub fn list(&self) -> Vec<RemoteInstance> {
+        self.instances.read().unwrap_or_else(|e| e.into_inner()).values().cloned().collect()
+    }
+
+    pub fn insert(&self, mut instance: RemoteInstance) -> Result<(), String> {
+        if instance.name.is_empty() || !instance.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err("Instance name must be non-empty and contain only letters, digits, '-' or '_'".to_string());
+        }
+        if !(instance.url.starts_with("http://") || instance.url.starts_with("https://")) {
+            return Err("Instance URL must start with http:// or https://".to_string());
+        }
+        instance.url = instance.url.trim_end_matches('/').to_string();
+        let mut instances = self.instances.write().unwrap_or_else(|e| e.into_inner());
+        if instances.contains_key(&instance.name) {
+            return Err(format!("Instance {} is already registered; remove it first", instance.name));
+        }
+        instances.insert(instance.name.clone(), instance);
+        Ok(())
+    }
+
+    pub fn remove(&self, name: &str) -> bool {
+        self.instances.write().unwrap_or_else(|e| e.into_inner()).remove(name).is_some()
+    }
+}
+
+#[get("/api/instances")]
+pub async fn list_instances(registry: web::Data<InstanceRegistry>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "success": true, "instances": registry.list() }))
+}
+
+#[post("/api/instances")]
+pub async fn register_instance(body: ValidJson<RemoteInstance>, session: Option<web::ReqData<Session>>, registry: web::Data<InstanceRegistry>) -> HttpResponse {
+    if let Err(response) = require_admin(&session) {
+        return response;
+    }
+    let instance = body.into_inner();
+    let name = instance.name.clone();
+    match registry.insert(instance) {
+        Ok(()) => {
+            log::info!("Registered remote instance {}", name);
+            HttpResponse::Ok().json(json!({ "success": true, "name": name }))
+        }
+        Err(e) if registry.get(&name).is_some() => HttpResponse::Conflict().json(json!({ "success": false, "error": e })),
+        Err(e) => HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    }
+}
+
+#[delete("/api/instances/{name}")]
+pub async fn remove_instance(name: web::Path<String>, session: Option<web::ReqData<Session>>, registry: web::Data<InstanceRegistry>) -> HttpResponse {
+    if let Err(response) = require_admin(&session) {
+        return response;
+    }
+    if registry.remove(&name) {
+        HttpResponse::Ok().json(json!({ "success": true }))
+    } else {
+        HttpResponse::NotFound().json(json!({ "success": false, "error": format!("Unknown instance: {}", name) }))
+    }
+}
+
+/// Forwards `/remote/{name}/api/{tail}` to `{url}/api/{tail}` of the named instance.
+pub async fn proxy(
+    req: HttpRequest,
+    body: web::Bytes,
+    path: web::Path<(String, String)>,
+    session: Option<web::ReqData<Session>>,
+    registry: web::Data<InstanceRegistry>,
+) -> HttpResponse {
+    let (name, tail) = path.into_inner();
+    let instance = match registry.get(&name) {
+        Some(i) => i,
+        None => return HttpResponse::NotFound().json(json!({ "success": false, "error": format!("Unknown instance: {}", name) })),
+    };
+
+    let hops = req
+        .headers()
+        .get(HOPS_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(0);
+    if hops >= MAX_HOPS {
+        return HttpResponse::build(StatusCode::LOOP_DETECTED).json(json!({ "success": false, "error": "Too many proxy hops" }));
+    }
+
+    let mut url = format!("{}/api/{}", instance.url, tail);
+    if !req.query_string().is_empty() {
+        url = format!("{}?{}", url, req.query_string());
+    }
+    log::debug!("Proxying {} {} to {}", req.method(), req.path(), url);
+
+    let mut forwarded = awc::Client::default()
+        .request(req.method().clone(), url)
+        .insert_header((HOPS_HEADER, (hops + 1).to_string()));
+    if let Some(content_type) = req.headers().get("Content-Type") {
+        forwarded = forwarded.insert_header(("Content-Type", content_type.clone()));
+    }
+    match session.as_deref() {
+        // The instance's token would see past the caller's roots
+        Some(session) if session.restricted && !session.admin => {
+            if let Some(authorization) = req.headers().get("Authorization") {
+                forwarded = forwarded.insert_header(("Authorization", authorization.clone()));
+            }
+        }
+        _ => {
+            if let Some(token) = &instance.token {
+                forwarded = forwarded.bearer_auth(token);
+            }
+        }
+    }
+
+    let mut response = match forwarded.send_body(body).await {
+        Ok(r) => r,
+        Err(e) => {
+            log::warn!("Proxy request to instance {} failed: {}", name, e);
+            return HttpResponse::BadGateway().json(json!({ "success": false, "error": format!("Instance {} is unreachable: {}", name, e) }));
+        }
+    };
+    let status = StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let content_type = response.headers().get("Content-Type").cloned();
+    match response.body().limit(MAX_BODY_BYTES).await {
+        Ok(bytes) => {
+            let mut builder = HttpResponse::build(status);
+            if let Some(content_type) = content_type {
+                builder.insert_header(("Content-Type", content_type));
+            }
+            builder.body(bytes)
+        }
+        Err(e) => HttpResponse::BadGateway().json(json!({ "success": false, "error": format!("Failed to read response from {}: {}", name, e) })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+    use actix_web::{App, HttpMessage, HttpServer};
+
+    fn registry() -> web::Data<InstanceRegistry> {
+        let registry = InstanceRegistry::default();
+        registry.insert(RemoteInstance { name: "ci".to_string(), url: "http://10.0.0.9:3000".to_string(), token: Some("instance".to_string()) }).unwrap();
+        web::Data::new(registry)
+    }
+
+    #[test]
+    fn registered_names_are_not_taken_over() {
+        let registry = registry();
+        let takeover = RemoteInstance { name: "ci".to_string(), url: "http://attacker.example".to_string(), token: None };
+        assert!(registry.insert(takeover).is_err());
+        assert_eq!(registry.get("ci").unwrap().url, "http://10.0.0.9:3000");
+    }
+
+    #[actix_web::test]
+    async fn only_admins_register_and_remove_instances() {
+        let registry = registry();
+        let app = init_service(App::new().app_data(registry.clone()).service(register_instance).service(remove_instance)).await;
+        let body = json!({ "name": "metadata", "url": "http://169.254.169.254" });
+        for session in [None, Some(Session::for_test("alice", false, true)), Some(Session::for_test("bob", false, false))] {
+            let requests = [TestRequest::post().uri("/api/instances").set_json(&body), TestRequest::delete().uri("/api/instances/ci")];
+            for req in requests.map(TestRequest::to_request) {
+                if let Some(session) = &session {
+                    req.extensions_mut().insert(session.clone());
+                }
+                assert_eq!(call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+            }
+        }
+        assert!(registry.get("metadata").is_none() && registry.get("ci").is_some());
+
+        let req = TestRequest::post().uri("/api/instances").set_json(&body).to_request();
+        req.extensions_mut().insert(Session::for_test("admin", true, false));
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+        let req = TestRequest::post().uri("/api/instances").set_json(json!({ "name": "ci", "url": "http://other" })).to_request();
+        req.extensions_mut().insert(Session::for_test("admin", true, false));
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::CONFLICT);
+    }
+
+    #[actix_web::test]
+    async fn restricted_users_are_forwarded_with_their_own_token() {
+        // A remote instance echoing the Authorization header it got
+        let remote = HttpServer::new(|| {
+            App::new().route(
+                "/api/whoami",
+                web::get().to(|req: HttpRequest| async move {
+                    let authorization = req.headers().get("Authorization").and_then(|v| v.to_str().ok()).unwrap_or("none").to_string();
+                    HttpResponse::Ok().body(authorization)
+                }),
+            )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let address = remote.addrs()[0];
+        let remote = remote.run();
+        let handle = remote.handle();
+        actix_rt::spawn(remote);
+
+        let registry = web::Data::new(InstanceRegistry::default());
+        registry.insert(RemoteInstance { name: "ci".to_string(), url: format!("http://{}", address), token: Some("instance".to_string()) }).unwrap();
+        let app = init_service(App::new().app_data(registry).route("/remote/{name}/api/{tail:.*}", web::to(proxy))).await;
+        let forwarded = |session: Session| {
+            let req = TestRequest::get().uri("/remote/ci/api/whoami").insert_header(("Authorization", "Bearer alice-token")).to_request();
+            req.extensions_mut().insert(session);
+            call_and_read_body(&app, req)
+        };
+        assert_eq!(forwarded(Session::for_test("alice", false, true)).await, "Bearer alice-token");
+        assert_eq!(forwarded(Session::for_test("root", true, true)).await, "Bearer instance");
+        handle.stop(false).await;
+    }
+}
@@ -0,0 +1,108 @@
+//! Syntax-aware refactorings. Unlike [`crate::replace`] they only touch
+//! identifiers, never strings, comments or longer names containing the
+//! symbol, and produce a diff for review instead of writing files.
+
+use crate::diff::unified_diff;
+use crate::replace::FileReplacement;
+use crate::store::FileStore;
+use crate::syntax::{self, Language};
+use crate::tree::walk_files;
+use ignore::gitignore::Gitignore;
+use std::ops::Range;
+
+/// Where a rename applies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenameScope {
+    /// Every file of the same language family below the root.
+    Project,
+    /// Only the file the symbol was picked in.
+    File,
+    /// Only the function or closure enclosing the symbol on this line.
+    Function { line: usize },
+}
+
+/// The would-be rename.
+#[derive(Debug, Default)]
+pub struct RenamePlan {
+    pub files: Vec<FileReplacement>,
+    /// Unified diff of every changed file, with `a/` and `b/` prefixes.
+    pub patch: String,
+    /// Files where the new name already occurs, so the rename may clash.
+    pub conflicts: Vec<String>,
+}
+
+/// Checks that `name` is a plain identifier valid in all supported languages.
+pub fn check_identifier(name: &str) -> Result<(), String> {
+    let mut chars = name.chars();
+    let valid_start = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_');
+    if !valid_start || !chars.all(|c| c.is_alphanumeric() || c == '_') {
+        return Err(format!("{} is not a valid identifier", name));
+    }
+    Ok(())
+}
+
+/// Plans renaming `symbol` to `new_name`, starting from `file` (a store path
+/// whose extension selects the language).
+pub fn rename_symbol(
+    store: &dyn FileStore,
+    ig: &Gitignore,
+    file: &str,
+    symbol: &str,
+    new_name: &str,
+    scope: RenameScope,
+) -> Result<RenamePlan, String> {
+    check_identifier(symbol)?;
+    check_identifier(new_name)?;
+    let language = Language::from_path(file).ok_or_else(|| format!("Renaming is not supported for {}", file))?;
+    let mut plan = RenamePlan::default();
+
+    let mut targets = Vec::new();
+    match scope {
+        RenameScope::Project => walk_files(store, "", ig, &mut |path| {
+            if Language::from_path(path).is_some_and(|l| l.same_family(language)) {
+                targets.push(path.to_string());
+            }
+        })?,
+        RenameScope::File | RenameScope::Function { .. } => targets.push(file.to_string()),
+    }
+
+    for path in targets {
+        let Some(source) = store.read(&path).ok().and_then(|data| String::from_utf8(data).ok()) else {
+            if path == file {
+                return Err(format!("Failed to read {} as text", file));
+            }
+            continue;
+        };
+        let file_language = Language::from_path(&path).unwrap_or(language);
+        let tree = syntax::parse(file_language, &source)?;
+        let mut ranges = syntax::identifiers(file_language, &tree, &source, symbol);
+        if let RenameScope::Function { line } = scope {
+            let body = syntax::enclosing_function(file_language, &tree, &source, line, symbol)
+                .ok_or_else(|| format!("No function enclosing {} on line {} of {}", symbol, line, file))?;
+            ranges.retain(|r| r.start >= body.start && r.end <= body.end);
+        }
+        if ranges.is_empty() {
+            continue;
+        }
+        if !syntax::identifiers(file_language, &tree, &source, new_name).is_empty() {
+            plan.conflicts.push(path.clone());
+        }
+        let renamed = splice(&source, &ranges, new_name);
+        plan.patch.push_str(&unified_diff(&path, &source, &renamed));
+        plan.files.push(FileReplacement { path, matches: ranges.len() });
+    }
+    Ok(plan)
+}
+
+// Helper function to replace ordered, non-overlapping ranges of `source`
+fn splice(source: &str, ranges: &[Range<usize>], replacement: &str) -> String {
+    let mut out = String::with_capacity(source.len());
+    let mut last = 0;
+    for range in ranges {
+        out.push_str(&source[last..range.start]);
+        out.push_str(replacement);
+        last = range.end;
+    }
+    out.push_str(&source[last..]);
+    out
+}
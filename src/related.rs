@@ -0,0 +1,107 @@
+//! Files likely relevant to a given one, for suggesting prompt context: its
+//! imports and importers from [`crate::deps`], the files next to it and the
+//! files changed in the same commits.
+
+use crate::deps::dependency_graph;
+use crate::store::{dir_of, join_path, FileStore};
+use ignore::gitignore::Gitignore;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+const IMPORTS_SCORE: f64 = 1.0;
+const IMPORTED_BY_SCORE: f64 = 0.8;
+/// For a file changed in every commit that changed the target.
+const CO_CHANGE_SCORE: f64 = 1.0;
+const SAME_DIRECTORY_SCORE: f64 = 0.3;
+/// Commits touching more files than this (reformats, renames) say little
+/// about which files belong together.
+pub const MAX_COMMIT_FILES: usize = 50;
+
+/// A suggested file.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RelatedFile {
+    pub path: String,
+    pub score: f64,
+    /// `imports`, `importedBy`, `coChanged`, `sameDirectory`.
+    pub reasons: Vec<&'static str>,
+}
+
+/// How often files changed together with the target.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CoChanges {
+    /// Commits that changed the target.
+    pub commits: usize,
+    /// For every other file, the number of those commits that changed it.
+    pub counts: BTreeMap<String, usize>,
+}
+
+/// Reads `git log --format=%x1e --name-only` output listing commits that
+/// changed `target`, with paths relative to the store root.
+pub fn parse_co_changes(log: &str, target: &str) -> CoChanges {
+    let mut co_changes = CoChanges::default();
+    for commit in log.split('\x1e') {
+        let files: Vec<&str> = commit.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+        if !files.contains(&target) {
+            continue;
+        }
+        co_changes.commits += 1;
+        if files.len() > MAX_COMMIT_FILES {
+            continue;
+        }
+        for file in files.into_iter().filter(|f| *f != target) {
+            *co_changes.counts.entry(file.to_string()).or_default() += 1;
+        }
+    }
+    co_changes
+}
+
+/// Ranks the files related to `target`, best first, keeping at most
+/// `limit`. Co-changed files no longer in the store are dropped.
+pub fn related_files(
+    store: &dyn FileStore,
+    ig: &Gitignore,
+    target: &str,
+    history: Option<&CoChanges>,
+    limit: usize,
+) -> Result<Vec<RelatedFile>, String> {
+    let mut scores: BTreeMap<String, RelatedFile> = BTreeMap::new();
+    let mut add = |path: &str, score: f64, reason: &'static str| {
+        let entry = scores.entry(path.to_string()).or_insert_with(|| RelatedFile { path: path.to_string(), score: 0.0, reasons: Vec::new() });
+        entry.score += score;
+        entry.reasons.push(reason);
+    };
+
+    let graph = dependency_graph(store, ig, "")?;
+    if let Some(deps) = graph.files.get(target) {
+        for path in &deps.imports {
+            add(path, IMPORTS_SCORE, "imports");
+        }
+    }
+    for path in graph.dependents(target) {
+        add(path, IMPORTED_BY_SCORE, "importedBy");
+    }
+    if let Some(history) = history.filter(|h| h.commits > 0) {
+        for (path, count) in &history.counts {
+            if store.exists(path) {
+                add(path, CO_CHANGE_SCORE * *count as f64 / history.commits as f64, "coChanged");
+            }
+        }
+    }
+    let dir = dir_of(target);
+    let entries = store.list_dir(dir).map_err(|e| format!("Failed to read directory: {}", e))?;
+    for entry in entries.iter().filter(|e| !e.is_dir) {
+        let path = join_path(dir, &entry.name);
+        if path != target && !ig.matched(&path, false).is_ignore() {
+            add(&path, SAME_DIRECTORY_SCORE, "sameDirectory");
+        }
+    }
+
+    scores.remove(target);
+    let mut related: Vec<RelatedFile> = scores.into_values().collect();
+    related.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.path.cmp(&b.path)));
+    related.truncate(limit);
+    for file in &mut related {
+        file.score = (file.score * 1000.0).round() / 1000.0;
+    }
+    Ok(related)
+}
@@ -0,0 +1,75 @@
+//! Rewrite rules for patch paths, applied before the paths are resolved, for
+//! patches written against another layout than the real one: a model shown
+//! flattened paths, or the repository under a different root name.
+
+use crate::patch::PatchSet;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// One rule as written in the config file. The first rule whose `pattern`
+/// matches a path rewrites it: the match is replaced with `replacement`,
+/// which may use the pattern's groups as `$1` or `${name}`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteConfig {
+    pub pattern: String,
+    pub replacement: String,
+}
+
+/// A patch path and what a rule made of it.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Rewrite {
+    pub from: String,
+    pub to: String,
+}
+
+/// A compiled list of rules.
+#[derive(Default)]
+pub struct PathRewrites {
+    rules: Vec<(Regex, String)>,
+}
+
+impl PathRewrites {
+    pub fn new(rules: &[RewriteConfig]) -> Result<Self, String> {
+        let rules = rules
+            .iter()
+            .map(|rule| Regex::new(&rule.pattern).map(|regex| (regex, rule.replacement.clone())).map_err(|e| format!("Path rewrite {}: {}", rule.pattern, e)))
+            .collect::<Result<_, String>>()?;
+        Ok(PathRewrites { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// `path` as the first matching rule rewrites it, None when no rule
+    /// matches or the rule leaves it as it is. A rule that would empty the
+    /// path is skipped.
+    pub fn rewrite_path(&self, path: &str) -> Option<String> {
+        let (regex, replacement) = self.rules.iter().find(|(regex, _)| regex.is_match(path))?;
+        let rewritten = regex.replace(path, replacement.as_str()).trim_start_matches('/').to_string();
+        if rewritten.is_empty() {
+            log::warn!("Path rewrite {} would empty {}; keeping it", regex, path);
+            return None;
+        }
+        (rewritten != path).then_some(rewritten)
+    }
+
+    /// Rewrites the old and new path of every file in `patch`, returning each
+    /// path that changed once.
+    pub fn apply(&self, patch: &mut PatchSet) -> Vec<Rewrite> {
+        let mut rewrites: Vec<Rewrite> = Vec::new();
+        for file in &mut patch.files {
+            for path in file.old_path.iter_mut().chain(file.new_path.iter_mut()) {
+                let Some(to) = self.rewrite_path(path) else {
+                    continue;
+                };
+                if !rewrites.iter().any(|r| r.from == *path) {
+                    rewrites.push(Rewrite { from: path.clone(), to: to.clone() });
+                }
+                *path = to;
+            }
+        }
+        rewrites
+    }
+}
@@ -0,0 +1,99 @@
+//! Regex find-and-replace across a tree. A [`ReplacePlan`] is the would-be
+//! change expressed as a unified diff, so applying it goes through the same
+//! [`PatchSet`] machinery (and safety checks) as LLM patches.
+
+use crate::diff::unified_diff;
+use crate::patch::PatchSet;
+use crate::store::FileStore;
+use crate::tree::{glob_set, walk_files};
+use globset::GlobSet;
+use ignore::gitignore::Gitignore;
+use regex::Regex;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// What to replace and where.
+pub struct ReplaceSpec {
+    pub pattern: Regex,
+    /// Replacement text; `$1` and `${name}` refer to capture groups.
+    pub replacement: String,
+    /// Files to consider; `None` means every file.
+    pub include: Option<GlobSet>,
+    pub exclude: Option<GlobSet>,
+}
+
+impl ReplaceSpec {
+    /// Builds a spec from the user-supplied pattern and glob lists.
+    pub fn new(pattern: &str, replacement: &str, include: &[String], exclude: &[String]) -> Result<Self, String> {
+        let pattern = Regex::new(pattern).map_err(|e| format!("Invalid pattern: {}", e))?;
+        Ok(ReplaceSpec {
+            pattern,
+            replacement: replacement.to_string(),
+            include: glob_set(include)?,
+            exclude: glob_set(exclude)?,
+        })
+    }
+
+    fn covers(&self, path: &str) -> bool {
+        self.include.as_ref().is_none_or(|globs| globs.is_match(path))
+            && !self.exclude.as_ref().is_some_and(|globs| globs.is_match(path))
+    }
+}
+
+/// A file the plan changes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileReplacement {
+    pub path: String,
+    pub matches: usize,
+}
+
+/// The result of a dry run.
+#[derive(Debug)]
+pub struct ReplacePlan {
+    pub files: Vec<FileReplacement>,
+    /// Combined unified diff of every changed file, with `a/` and `b/` prefixes.
+    pub patch: String,
+}
+
+impl ReplacePlan {
+    /// Identifies the exact change; it differs whenever a file in scope
+    /// changed since the dry run.
+    pub fn id(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        self.patch.hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+
+    /// Parses the plan's diff for applying.
+    pub fn patch_set(&self) -> PatchSet {
+        PatchSet::parse(&self.patch, 1)
+    }
+}
+
+/// Runs the replacement over every non-ignored text file below `dir` in
+/// scope, without writing anything.
+pub fn plan(store: &dyn FileStore, dir: &str, ig: &Gitignore, spec: &ReplaceSpec) -> Result<ReplacePlan, String> {
+    let mut plan = ReplacePlan { files: Vec::new(), patch: String::new() };
+    walk_files(store, dir, ig, &mut |path| {
+        if !spec.covers(path) {
+            return;
+        }
+        let Some(content) = store.read(path).ok().and_then(|data| String::from_utf8(data).ok()) else {
+            return;
+        };
+        if content.contains('\0') {
+            return;
+        }
+        let matches = spec.pattern.find_iter(&content).count();
+        if matches == 0 {
+            return;
+        }
+        let replaced = spec.pattern.replace_all(&content, spec.replacement.as_str());
+        let diff = unified_diff(path, &content, &replaced);
+        if !diff.is_empty() {
+            plan.patch.push_str(&diff);
+            plan.files.push(FileReplacement { path: path.to_string(), matches });
+        }
+    })?;
+    Ok(plan)
+}
@@ -0,0 +1,192 @@
+// Review comments on pending patches, for teams that want a human to look at
+// a patch before it is applied. Comments are attached to a file (and
+// optionally a hunk or line) of a patch identified by its patchId, the same
+// fingerprint of directory and patch text approvals.rs uses. Dry runs and
+// /api/diff_view report how many comments are still unresolved.
+//
+// Comments are kept in memory only.
+
+use crate::approvals;
+use crate::auth::{Session, Stores};
+use crate::validation::ValidJson;
+use actix_web::{get, post, web, HttpResponse};
+use chrono::Utc;
+use repopatch::patch::PatchSet;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+const ANONYMOUS: &str = "anonymous";
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Comment {
+    pub id: usize,
+    pub file: String,
+    /// 0-based hunk index within the file's patch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hunk: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    pub author: String,
+    pub body: String,
+    pub resolved: bool,
+    pub created: String,
+}
+
+struct Thread {
+    root: String,
+    comments: Vec<Comment>,
+}
+
+#[derive(Default)]
+pub struct Reviews {
+    threads: Mutex<HashMap<String, Thread>>,
+}
+
+/// Identifies `patch` (as sent to apply_patch, trimmed) applied to `root`.
+pub fn patch_id(root: &str, patch: &str) -> String {
+    format!("{:016x}", approvals::fingerprint(root, patch))
+}
+
+impl Reviews {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Thread>> {
+        self.threads.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns `{ total, unresolved }` for the patch, for preview responses.
+    pub fn counts(&self, patch_id: &str) -> serde_json::Value {
+        let threads = self.lock();
+        let comments = threads.get(patch_id).map(|t| t.comments.as_slice()).unwrap_or_default();
+        json!({
+            "patchId": patch_id,
+            "total": comments.len(),
+            "unresolved": comments.iter().filter(|c| !c.resolved).count()
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CommentRequest {
+    #[serde(rename = "directoryPath")]
+    directory_path: String,
+    /// The pending patch; lets the server check `file` and `hunk`.
+    #[serde(rename = "patchContent")]
+    patch_content: Option<String>,
+    /// Instead of patchContent, from an earlier response.
+    #[serde(rename = "patchId")]
+    patch_id: Option<String>,
+    file: String,
+    hunk: Option<usize>,
+    line: Option<usize>,
+    body: String,
+}
+
+#[derive(Deserialize)]
+pub struct ReviewsQuery {
+    #[serde(rename = "patchId")]
+    patch_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResolveRequest {
+    #[serde(rename = "patchId")]
+    patch_id: String,
+    #[serde(rename = "commentId")]
+    comment_id: usize,
+    #[serde(default = "default_resolved")]
+    resolved: bool,
+}
+
+fn default_resolved() -> bool {
+    true
+}
+
+// Helper function to check that a comment points at a file and hunk of the patch
+fn check_target(patch: &str, file: &str, hunk: Option<usize>) -> Result<(), String> {
+    let patch_set = PatchSet::parse(patch, 1);
+    let target = patch_set
+        .files
+        .iter()
+        .find(|f| f.old_path.as_deref() == Some(file) || f.new_path.as_deref() == Some(file))
+        .ok_or_else(|| format!("The patch does not change {}", file))?;
+    match hunk {
+        Some(hunk) if hunk >= target.hunk_count() => Err(format!("{} has {} hunks in this patch", file, target.hunk_count())),
+        _ => Ok(()),
+    }
+}
+
+#[post("/api/reviews")]
+pub async fn add_comment(
+    body: ValidJson<CommentRequest>,
+    stores: Stores,
+    session: Option<web::ReqData<Session>>,
+    reviews: web::Data<Reviews>,
+) -> HttpResponse {
+    let store = match stores.open_root(&body.directory_path) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid directory path: {}", e) })),
+    };
+    let root = store.display_path("");
+    let patch_id = match (&body.patch_content, &body.patch_id) {
+        (Some(patch), _) => {
+            if let Err(e) = check_target(patch.trim(), &body.file, body.hunk) {
+                return HttpResponse::BadRequest().json(json!({ "success": false, "error": e }));
+            }
+            patch_id(&root, patch.trim())
+        }
+        (None, Some(id)) => id.clone(),
+        (None, None) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": "Send patchContent or patchId" })),
+    };
+    if body.body.trim().is_empty() {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": "Comment body cannot be empty" }));
+    }
+
+    let mut threads = reviews.lock();
+    let thread = threads.entry(patch_id.clone()).or_insert_with(|| Thread { root: root.clone(), comments: Vec::new() });
+    if thread.root != root {
+        return HttpResponse::Conflict().json(json!({ "success": false, "error": "patchId belongs to a different directory" }));
+    }
+    let comment = Comment {
+        id: thread.comments.len() + 1,
+        file: body.file.clone(),
+        hunk: body.hunk,
+        line: body.line,
+        author: session.map(|s| s.user.clone()).unwrap_or_else(|| ANONYMOUS.to_string()),
+        body: body.body.clone(),
+        resolved: false,
+        created: Utc::now().to_rfc3339(),
+    };
+    thread.comments.push(comment.clone());
+    HttpResponse::Ok().json(json!({ "success": true, "patchId": patch_id, "comment": comment }))
+}
+
+#[get("/api/reviews")]
+pub async fn list_comments(query: web::Query<ReviewsQuery>, stores: Stores, reviews: web::Data<Reviews>) -> HttpResponse {
+    let threads = reviews.lock();
+    let Some(thread) = threads.get(&query.patch_id) else {
+        return HttpResponse::Ok().json(json!({ "success": true, "patchId": query.patch_id, "comments": [] }));
+    };
+    if let Err(e) = stores.open_root(&thread.root) {
+        return HttpResponse::Forbidden().json(json!({ "success": false, "error": e }));
+    }
+    HttpResponse::Ok().json(json!({ "success": true, "patchId": query.patch_id, "comments": thread.comments }))
+}
+
+#[post("/api/reviews/resolve")]
+pub async fn resolve_comment(body: ValidJson<ResolveRequest>, stores: Stores, reviews: web::Data<Reviews>) -> HttpResponse {
+    let mut threads = reviews.lock();
+    let Some(thread) = threads.get_mut(&body.patch_id) else {
+        return HttpResponse::NotFound().json(json!({ "success": false, "error": format!("No comments for patch {}", body.patch_id) }));
+    };
+    if let Err(e) = stores.open_root(&thread.root) {
+        return HttpResponse::Forbidden().json(json!({ "success": false, "error": e }));
+    }
+    match thread.comments.iter_mut().find(|c| c.id == body.comment_id) {
+        Some(comment) => {
+            comment.resolved = body.resolved;
+            HttpResponse::Ok().json(json!({ "success": true, "comment": comment }))
+        }
+        None => HttpResponse::NotFound().json(json!({ "success": false, "error": format!("No comment {}", body.comment_id) })),
+    }
+}
@@ -0,0 +1,216 @@
+//! File templates for boilerplate such as a new Rust module or a React
+//! component. A template is a set of files whose paths and content contain
+//! `{{variable}}` placeholders, optionally with a case filter
+//! (`{{name|snake}}`, `|kebab`, `|pascal`, `|camel`, `|upper`). Files marked
+//! `append` are added to the end of an existing file instead, e.g. to declare
+//! the new module in its parent. Instantiating a template yields a patch for
+//! review rather than writing anything.
+
+use crate::diff::{git_diff, FileChange};
+use crate::store::FileStore;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const FILTERS: &[&str] = &["snake", "kebab", "pascal", "camel", "upper"];
+
+/// One template as written in the config file.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateConfig {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// Variables and their defaults; an empty default makes one required.
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+    pub files: Vec<TemplateFile>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateFile {
+    pub path: String,
+    #[serde(default)]
+    pub content: String,
+    /// Add the content to the end of the file, creating it if needed.
+    #[serde(default)]
+    pub append: bool,
+}
+
+/// The built-in templates plus configured ones, which replace built-ins of
+/// the same name.
+pub struct Templates(Vec<TemplateConfig>);
+
+/// A file an instantiated template creates or appends to.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ScaffoldFile {
+    pub path: String,
+    pub append: bool,
+}
+
+/// The would-be files of a template.
+#[derive(Debug, Default)]
+pub struct ScaffoldPlan {
+    pub files: Vec<ScaffoldFile>,
+    /// `diff --git` sections creating or extending every file.
+    pub patch: String,
+}
+
+impl Templates {
+    pub fn new(configured: Vec<TemplateConfig>) -> Self {
+        let mut templates: Vec<TemplateConfig> = builtin().into_iter().filter(|b| !configured.iter().any(|c| c.name == b.name)).collect();
+        templates.extend(configured);
+        Templates(templates)
+    }
+
+    pub fn list(&self) -> &[TemplateConfig] {
+        &self.0
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TemplateConfig> {
+        self.0.iter().find(|t| t.name == name)
+    }
+}
+
+fn builtin() -> Vec<TemplateConfig> {
+    let file = |path: &str, content: &str, append: bool| TemplateFile { path: path.to_string(), content: content.to_string(), append };
+    vec![
+        TemplateConfig {
+            name: "rust-module".to_string(),
+            description: "A Rust module folder with mod.rs, declared in its parent module".to_string(),
+            variables: BTreeMap::from([
+                ("name".to_string(), String::new()),
+                ("dir".to_string(), "src".to_string()),
+                ("parent".to_string(), "src/lib.rs".to_string()),
+            ]),
+            files: vec![
+                file("{{dir}}/{{name|snake}}/mod.rs", "//! {{name|pascal}}.\n", false),
+                file("{{parent}}", "pub mod {{name|snake}};\n", true),
+            ],
+        },
+        TemplateConfig {
+            name: "react-component".to_string(),
+            description: "A React component with a CSS module and a test".to_string(),
+            variables: BTreeMap::from([("name".to_string(), String::new()), ("dir".to_string(), "src/components".to_string())]),
+            files: vec![
+                file(
+                    "{{dir}}/{{name|pascal}}/{{name|pascal}}.tsx",
+                    "import styles from './{{name|pascal}}.module.css';\n\nexport interface {{name|pascal}}Props {}\n\nexport function {{name|pascal}}(props: {{name|pascal}}Props) {\n  return <div className={styles.root} />;\n}\n",
+                    false,
+                ),
+                file("{{dir}}/{{name|pascal}}/{{name|pascal}}.module.css", ".root {\n}\n", false),
+                file(
+                    "{{dir}}/{{name|pascal}}/{{name|pascal}}.test.tsx",
+                    "import { render } from '@testing-library/react';\nimport { {{name|pascal}} } from './{{name|pascal}}';\n\ntest('renders', () => {\n  render(<{{name|pascal}} />);\n});\n",
+                    false,
+                ),
+            ],
+        },
+    ]
+}
+
+// Helper function to split an identifier such as `MyWidget` or `my-widget` into lowercase words
+fn words(value: &str) -> Vec<String> {
+    let mut words: Vec<String> = Vec::new();
+    let mut previous: Option<char> = None;
+    for c in value.chars() {
+        if !c.is_alphanumeric() {
+            previous = None;
+            continue;
+        }
+        let boundary = match previous {
+            None => true,
+            Some(p) => c.is_uppercase() && !p.is_uppercase(),
+        };
+        if boundary || words.is_empty() {
+            words.push(String::new());
+        }
+        words.last_mut().expect("a word was pushed").extend(c.to_lowercase());
+        previous = Some(c);
+    }
+    words
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    chars.next().map(|c| c.to_uppercase().chain(chars).collect()).unwrap_or_default()
+}
+
+/// Applies a case filter to a variable value.
+pub fn apply_filter(value: &str, filter: &str) -> Result<String, String> {
+    let words = words(value);
+    match filter {
+        "snake" => Ok(words.join("_")),
+        "kebab" => Ok(words.join("-")),
+        "pascal" => Ok(words.iter().map(|w| capitalize(w)).collect()),
+        "camel" => Ok(words.iter().enumerate().map(|(i, w)| if i == 0 { w.clone() } else { capitalize(w) }).collect()),
+        "upper" => Ok(words.join("_").to_uppercase()),
+        _ => Err(format!("Unknown filter {}: expected one of {}", filter, FILTERS.join(", "))),
+    }
+}
+
+/// Replaces the `{{variable}}` and `{{variable|filter}}` placeholders of `text`.
+pub fn render(text: &str, values: &BTreeMap<String, String>) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..].find("}}").ok_or_else(|| format!("Unclosed placeholder in {}", text))? + start;
+        out.push_str(&rest[..start]);
+        let placeholder = rest[start + 2..end].trim();
+        let (name, filter) = match placeholder.split_once('|') {
+            Some((name, filter)) => (name.trim(), Some(filter.trim())),
+            None => (placeholder, None),
+        };
+        let value = values.get(name).ok_or_else(|| format!("Unknown variable {}", name))?;
+        match filter {
+            Some(filter) => out.push_str(&apply_filter(value, filter)?),
+            None => out.push_str(value),
+        }
+        rest = &rest[end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Instantiates `template` with `values` against `store`. Created files must
+/// not exist yet.
+pub fn plan(store: &dyn FileStore, template: &TemplateConfig, values: &BTreeMap<String, String>) -> Result<ScaffoldPlan, String> {
+    if let Some(unknown) = values.keys().find(|k| !template.variables.contains_key(*k)) {
+        return Err(format!("Template {} has no variable {}", template.name, unknown));
+    }
+    let mut resolved = template.variables.clone();
+    for (name, value) in values {
+        resolved.insert(name.clone(), value.clone());
+    }
+    if let Some((missing, _)) = resolved.iter().find(|(_, v)| v.is_empty()) {
+        return Err(format!("Template {} needs a value for {}", template.name, missing));
+    }
+
+    let mut plan = ScaffoldPlan::default();
+    let mut changes: Vec<FileChange> = Vec::new();
+    for file in &template.files {
+        let path = render(&file.path, &resolved)?;
+        store.validate(&path)?;
+        let content = render(&file.content, &resolved)?;
+        let existing = changes.iter().position(|c| c.path == path);
+        let old = match existing {
+            Some(i) => changes[i].new.clone(),
+            None => store.read(&path).ok().map(|data| String::from_utf8_lossy(&data).into_owned()),
+        };
+        let new = match (&old, file.append) {
+            (Some(_), false) => return Err(format!("{} already exists", path)),
+            (Some(old), true) if !old.is_empty() && !old.ends_with('\n') => format!("{}\n{}", old, content),
+            (Some(old), true) => format!("{}{}", old, content),
+            (None, _) => content,
+        };
+        match existing {
+            Some(i) => changes[i].new = Some(new),
+            None => {
+                changes.push(FileChange { path: path.clone(), old, new: Some(new) });
+                plan.files.push(ScaffoldFile { path, append: file.append });
+            }
+        }
+    }
+    plan.patch = git_diff(&changes);
+    Ok(plan)
+}
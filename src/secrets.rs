@@ -0,0 +1,97 @@
+//! Scans the lines a patch adds for credentials and copied license text,
+//! which LLMs occasionally echo back from their context.
+
+use crate::patch::PatchSet;
+use regex::Regex;
+use serde::Serialize;
+use std::sync::LazyLock;
+
+/// Minimum length of a token checked for entropy.
+const ENTROPY_MIN_LEN: usize = 20;
+/// Shannon entropy in bits per char above which a token looks random.
+const ENTROPY_THRESHOLD: f64 = 4.5;
+
+/// A suspicious line in a patch.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub file: String,
+    /// 1-based line in the patched file.
+    pub line: usize,
+    /// Which rule matched, e.g. `aws-access-key`.
+    pub rule: &'static str,
+    /// The matched text with most of it masked out.
+    pub excerpt: String,
+}
+
+static RULES: LazyLock<Vec<(&'static str, Regex)>> = LazyLock::new(|| {
+    [
+        ("aws-access-key", r"\b(AKIA|ASIA)[0-9A-Z]{16}\b"),
+        ("aws-secret-key", r#"(?i)aws.{0,20}(secret|private).{0,20}[=:]\s*['"]?[A-Za-z0-9/+=]{40}\b"#),
+        ("private-key", r"-----BEGIN ([A-Z0-9]+ )*PRIVATE KEY( BLOCK)?-----"),
+        ("github-token", r"\b(gh[pousr]_[A-Za-z0-9]{36,}|github_pat_[A-Za-z0-9_]{40,})\b"),
+        ("slack-token", r"\bxox[abprs]-[A-Za-z0-9-]{10,}\b"),
+        ("copyleft-license", r"(?i)GNU (Affero |Lesser )?General Public License"),
+    ]
+    .into_iter()
+    .map(|(rule, pattern)| (rule, Regex::new(pattern).expect("secret rule must compile")))
+    .collect()
+});
+
+static TOKEN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[A-Za-z0-9+/=_\-]{20,}").expect("token pattern must compile"));
+
+// Helper function to compute the Shannon entropy of a string in bits per char
+fn entropy(token: &str) -> f64 {
+    let mut counts = [0usize; 256];
+    token.bytes().for_each(|b| counts[b as usize] += 1);
+    let len = token.len() as f64;
+    counts
+        .iter()
+        .filter(|c| **c > 0)
+        .map(|&c| {
+            let p = c as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+// Helper function to keep a hint of the match without repeating the secret
+fn mask(text: &str) -> String {
+    let shown: String = text.chars().take(4).collect();
+    format!("{}{}", shown, "*".repeat(text.chars().count().saturating_sub(4).min(16)))
+}
+
+/// Checks one added line.
+pub fn scan_line(text: &str) -> Vec<(&'static str, String)> {
+    let mut hits: Vec<(&'static str, String)> =
+        RULES.iter().filter_map(|(rule, regex)| regex.find(text).map(|m| (*rule, mask(m.as_str())))).collect();
+    if hits.is_empty() {
+        let random = TOKEN
+            .find_iter(text)
+            .map(|m| m.as_str())
+            .find(|token| {
+                // Long identifiers can be varied too; random tokens mix in digits
+                token.len() >= ENTROPY_MIN_LEN
+                    && token.bytes().any(|b| b.is_ascii_digit())
+                    && token.bytes().any(|b| b.is_ascii_alphabetic())
+                    && entropy(token) >= ENTROPY_THRESHOLD
+            });
+        if let Some(token) = random {
+            hits.push(("high-entropy-string", mask(token)));
+        }
+    }
+    hits
+}
+
+/// Scans every line `patch` adds.
+pub fn scan(patch: &PatchSet) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    for file in &patch.files {
+        let path = file.new_path.as_deref().unwrap_or(file.path());
+        for (line, text) in file.added_lines() {
+            for (rule, excerpt) in scan_line(text) {
+                findings.push(Finding { file: path.to_string(), line, rule, excerpt });
+            }
+        }
+    }
+    findings
+}
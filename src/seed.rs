@@ -0,0 +1,59 @@
+//! Seeds a [`MemoryStore`] for in-memory repository mode, either from a JSON
+//! object mapping paths to file contents or from a tar archive (optionally
+//! gzipped).
+
+use crate::store::{check_relative, MemoryStore};
+use flate2::read::GzDecoder;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Loads `path`, choosing the format by extension: `.json`, `.tar`, or
+/// `.tar.gz` / `.tgz`.
+pub fn load(path: &Path) -> Result<MemoryStore, String> {
+    let name = path.to_string_lossy().to_lowercase();
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    if name.ends_with(".json") {
+        let mut data = Vec::new();
+        let mut file = file;
+        file.read_to_end(&mut data).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        from_json(&data)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        from_tar(GzDecoder::new(file))
+    } else if name.ends_with(".tar") {
+        from_tar(file)
+    } else {
+        Err(format!("Unsupported seed file {}: expected .json, .tar, .tar.gz or .tgz", path.display()))
+    }
+}
+
+/// Parses `{"path/in/repo": "content", ...}`.
+pub fn from_json(data: &[u8]) -> Result<MemoryStore, String> {
+    let files: BTreeMap<String, String> = serde_json::from_slice(data).map_err(|e| format!("Invalid seed JSON: {}", e))?;
+    for path in files.keys() {
+        check_relative(path)?;
+    }
+    Ok(MemoryStore::from_files(files))
+}
+
+/// Reads every regular file in a tar archive. A leading `./` is dropped.
+pub fn from_tar(reader: impl Read) -> Result<MemoryStore, String> {
+    let mut archive = tar::Archive::new(reader);
+    let mut files = BTreeMap::new();
+    let entries = archive.entries().map_err(|e| format!("Invalid tar archive: {}", e))?;
+    for entry in entries {
+        let mut entry = entry.map_err(|e| format!("Invalid tar entry: {}", e))?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path().map_err(|e| format!("Invalid tar entry path: {}", e))?;
+        let path = path.to_string_lossy().replace('\\', "/");
+        let path = path.trim_start_matches("./").to_string();
+        check_relative(&path)?;
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).map_err(|e| format!("Failed to read {} from the archive: {}", path, e))?;
+        files.insert(path, data);
+    }
+    Ok(MemoryStore::from_files(files))
+}
@@ -0,0 +1,140 @@
+// `repopatch self-test`: runs the patch engine against the golden fixtures in
+// tests/fixtures, which are embedded into the binary.
+//
+// Each fixture directory contains:
+//   before/        tree the patch is applied to (optional, defaults to empty)
+//   patch.diff     the patch to apply
+//   after/         expected tree after applying (optional, defaults to empty)
+//   expected.json  optional `{ "success": false, "codes": ["CONTEXT_MISMATCH"] }`
+
+use repopatch::patch::PatchSet;
+use repopatch::store::FsStore;
+use rust_embed::RustEmbed;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::path::Path;
+
+#[derive(RustEmbed)]
+#[folder = "tests/fixtures/"]
+struct Fixtures;
+
+#[derive(Deserialize)]
+struct Expectation {
+    #[serde(default = "default_success")]
+    success: bool,
+    #[serde(default)]
+    codes: Vec<String>,
+}
+
+fn default_success() -> bool {
+    true
+}
+
+/// Runs every fixture (or only those whose name contains `filter`) and
+/// returns the process exit code.
+pub fn run(filter: Option<&str>) -> i32 {
+    let mut cases: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for file in Fixtures::iter() {
+        if let Some((case, _)) = file.split_once('/') {
+            cases.entry(case.to_string()).or_default().push(file.to_string());
+        }
+    }
+
+    let mut passed = 0;
+    let mut failed = 0;
+    for (case, files) in cases.iter().filter(|(case, _)| filter.is_none_or(|f| case.contains(f))) {
+        match run_case(case, files) {
+            Ok(()) => {
+                println!("ok   {}", case);
+                passed += 1;
+            }
+            Err(e) => {
+                println!("FAIL {}: {}", case, e);
+                failed += 1;
+            }
+        }
+    }
+
+    println!("self-test: {} passed, {} failed", passed, failed);
+    if failed == 0 && passed > 0 { 0 } else { 1 }
+}
+
+fn fixture_tree(files: &[String], case: &str, prefix: &str) -> BTreeMap<String, Vec<u8>> {
+    let prefix = format!("{}/{}/", case, prefix);
+    files
+        .iter()
+        .filter_map(|f| f.strip_prefix(&prefix).map(|rel| (rel.to_string(), f)))
+        .filter_map(|(rel, f)| Fixtures::get(f).map(|data| (rel, data.data.into_owned())))
+        .collect()
+}
+
+fn disk_tree(root: &Path, dir: &Path, tree: &mut BTreeMap<String, Vec<u8>>) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            disk_tree(root, &path, tree)?;
+        } else {
+            let rel = path.strip_prefix(root).unwrap_or(&path).to_string_lossy().replace('\\', "/");
+            tree.insert(rel, fs::read(&path)?);
+        }
+    }
+    Ok(())
+}
+
+fn run_case(case: &str, files: &[String]) -> Result<(), String> {
+    let patch_file = Fixtures::get(&format!("{}/patch.diff", case)).ok_or("missing patch.diff")?;
+    let patch_content = String::from_utf8(patch_file.data.into_owned()).map_err(|e| format!("patch.diff is not UTF-8: {}", e))?;
+    let expectation: Expectation = match Fixtures::get(&format!("{}/expected.json", case)) {
+        Some(file) => serde_json::from_slice(&file.data).map_err(|e| format!("invalid expected.json: {}", e))?,
+        None => Expectation { success: true, codes: Vec::new() },
+    };
+
+    let work_dir = env::temp_dir().join(format!("repopatch-self-test-{}-{}", std::process::id(), case));
+    let _ = fs::remove_dir_all(&work_dir);
+    fs::create_dir_all(&work_dir).map_err(|e| format!("failed to create {:?}: {}", work_dir, e))?;
+    let result = check_case(case, files, &work_dir, &patch_content, &expectation);
+    let _ = fs::remove_dir_all(&work_dir);
+    result
+}
+
+fn check_case(case: &str, files: &[String], work_dir: &Path, patch_content: &str, expectation: &Expectation) -> Result<(), String> {
+    for (rel, data) in fixture_tree(files, case, "before") {
+        let path = work_dir.join(&rel);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("failed to create {:?}: {}", parent, e))?;
+        }
+        fs::write(&path, data).map_err(|e| format!("failed to write {:?}: {}", path, e))?;
+    }
+    let base_dir = work_dir.canonicalize().map_err(|e| e.to_string())?;
+
+    let outcome = PatchSet::parse(patch_content, 1).apply(&FsStore::new(&base_dir));
+    let codes: Vec<String> = outcome
+        .details
+        .iter()
+        .map(|d| serde_json::to_value(d.code).ok().and_then(|v| v.as_str().map(String::from)).unwrap_or_default())
+        .collect();
+
+    let success = outcome.is_success();
+    if success != expectation.success {
+        let messages: Vec<&str> = outcome.details.iter().map(|d| d.message.as_str()).collect();
+        return Err(format!("expected success={}, got success={} {:?}", expectation.success, success, messages));
+    }
+    if codes != expectation.codes {
+        return Err(format!("expected codes {:?}, got {:?}", expectation.codes, codes));
+    }
+
+    let mut actual = BTreeMap::new();
+    disk_tree(&base_dir, &base_dir, &mut actual).map_err(|e| format!("failed to read result tree: {}", e))?;
+    let expected = fixture_tree(files, case, "after");
+    if actual != expected {
+        let mismatched: BTreeSet<&String> = actual
+            .keys()
+            .chain(expected.keys())
+            .filter(|k| actual.get(*k) != expected.get(*k))
+            .collect();
+        return Err(format!("result tree differs from after/ at {:?}", mismatched));
+    }
+    Ok(())
+}
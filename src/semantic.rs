@@ -0,0 +1,205 @@
+//! The vector index behind semantic search. Files are cut into overlapping
+//! line windows, each embedded once and stored with the revision of its file
+//! in [`INDEX_PATH`] inside the store, so only changed files are embedded
+//! again. Embeddings come from a provider the server configures; this module
+//! also has a small local model ([`hash_embedding`]) for setups without one.
+
+use crate::patch::revision;
+use crate::store::FileStore;
+use crate::tree::walk_files;
+use ignore::gitignore::Gitignore;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// Where the index lives, relative to the store root.
+pub const INDEX_PATH: &str = ".repopatch/semantic-index.json";
+/// Lines per chunk, and lines shared by neighbouring chunks.
+pub const CHUNK_LINES: usize = 40;
+pub const CHUNK_OVERLAP: usize = 10;
+/// Larger files are not indexed.
+pub const MAX_FILE_BYTES: u64 = 512 * 1024;
+/// Dimensions of [`hash_embedding`] vectors.
+pub const HASH_DIMENSIONS: usize = 512;
+
+/// A window of a file waiting for its embedding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub path: String,
+    pub revision: String,
+    /// 1-based, inclusive.
+    pub start_line: usize,
+    pub end_line: usize,
+    pub text: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexEntry {
+    pub path: String,
+    pub revision: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub vector: Vec<f32>,
+}
+
+/// Embedded chunks of a store, for one embedding model.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct VectorIndex {
+    pub model: String,
+    pub entries: Vec<IndexEntry>,
+}
+
+/// One search result.
+#[derive(Debug, Clone)]
+pub struct Hit<'a> {
+    pub entry: &'a IndexEntry,
+    pub score: f32,
+}
+
+/// Cuts `source` into windows of [`CHUNK_LINES`] lines overlapping by
+/// [`CHUNK_OVERLAP`]. Blank windows are left out.
+pub fn chunk_file(path: &str, revision: &str, source: &str) -> Vec<Chunk> {
+    let lines: Vec<&str> = source.lines().collect();
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < lines.len() {
+        let end = (start + CHUNK_LINES).min(lines.len());
+        let text = lines[start..end].join("\n");
+        if !text.trim().is_empty() {
+            chunks.push(Chunk { path: path.to_string(), revision: revision.to_string(), start_line: start + 1, end_line: end, text });
+        }
+        if end == lines.len() {
+            break;
+        }
+        start = end - CHUNK_OVERLAP;
+    }
+    chunks
+}
+
+impl VectorIndex {
+    /// Loads the store's index for `model`. A missing or unreadable index,
+    /// or one built with another model, gives an empty one.
+    pub fn load(store: &dyn FileStore, model: &str) -> Self {
+        let loaded = store.read(INDEX_PATH).ok().and_then(|data| serde_json::from_slice::<VectorIndex>(&data).ok());
+        VectorIndex::or_empty(loaded, model)
+    }
+
+    /// `loaded` if it was built with `model`, else an empty index for it;
+    /// for indexes kept somewhere other than the store.
+    pub fn or_empty(loaded: Option<VectorIndex>, model: &str) -> Self {
+        match loaded {
+            Some(index) if index.model == model => index,
+            _ => VectorIndex { model: model.to_string(), entries: Vec::new() },
+        }
+    }
+
+    pub fn save(&self, store: &dyn FileStore) -> Result<(), String> {
+        let data = serde_json::to_vec(self).map_err(|e| format!("Failed to encode the index: {}", e))?;
+        store.write(INDEX_PATH, &data).map_err(|e| format!("Failed to write {}: {}", INDEX_PATH, e))
+    }
+
+    /// Drops the entries of files that changed or went away and returns the
+    /// chunks of new and changed files, which still need embeddings.
+    pub fn refresh(&mut self, store: &dyn FileStore, ig: &Gitignore) -> Result<Vec<Chunk>, String> {
+        let indexed: HashMap<&str, &str> = self.entries.iter().map(|e| (e.path.as_str(), e.revision.as_str())).collect();
+        let mut current: HashMap<String, String> = HashMap::new();
+        let mut pending = Vec::new();
+        let index_dir = INDEX_PATH.split('/').next().unwrap_or(INDEX_PATH);
+        walk_files(store, "", ig, &mut |path| {
+            if path.starts_with(&format!("{}/", index_dir)) || store.file_size(path).is_none_or(|s| s > MAX_FILE_BYTES) {
+                return;
+            }
+            let Ok(data) = store.read(path) else { return };
+            if data.contains(&0) {
+                return;
+            }
+            let rev = revision(&data);
+            if indexed.get(path) != Some(&rev.as_str()) {
+                pending.extend(chunk_file(path, &rev, &String::from_utf8_lossy(&data)));
+            }
+            current.insert(path.to_string(), rev);
+        })?;
+        self.entries.retain(|e| current.get(&e.path) == Some(&e.revision));
+        Ok(pending)
+    }
+
+    pub fn insert(&mut self, chunk: &Chunk, vector: Vec<f32>) {
+        self.entries.push(IndexEntry {
+            path: chunk.path.clone(),
+            revision: chunk.revision.clone(),
+            start_line: chunk.start_line,
+            end_line: chunk.end_line,
+            vector,
+        });
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.entries.iter().map(|e| e.path.as_str()).collect::<HashSet<_>>().len()
+    }
+
+    /// The `limit` entries most similar (by cosine) to `query`, best first.
+    pub fn search(&self, query: &[f32], limit: usize) -> Vec<Hit<'_>> {
+        let mut hits: Vec<Hit> = self.entries.iter().map(|entry| Hit { entry, score: cosine(query, &entry.vector) }).collect();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.entry.path.cmp(&b.entry.path)));
+        hits.truncate(limit);
+        hits
+    }
+}
+
+/// Cosine similarity; 0 for vectors of different lengths or zero vectors.
+pub fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = a.iter().map(|x| x * x).sum::<f32>().sqrt() * b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        0.0
+    } else {
+        dot / norm
+    }
+}
+
+// Helper function to split text into lowercase words, breaking identifiers
+// such as `buildTree` and `build_tree` into their parts
+fn tokens(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    for word in text.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()) {
+        let mut part = String::new();
+        let mut previous_lower = false;
+        for c in word.chars() {
+            if c.is_uppercase() && previous_lower && !part.is_empty() {
+                tokens.push(std::mem::take(&mut part));
+            }
+            previous_lower = c.is_lowercase() || c.is_ascii_digit();
+            part.extend(c.to_lowercase());
+        }
+        tokens.push(part);
+    }
+    tokens.retain(|t| t.chars().count() > 1);
+    tokens
+}
+
+// FNV-1a, which unlike the std hasher is stable across builds
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// The local model: a normalized bag of words hashed into
+/// [`HASH_DIMENSIONS`] buckets. It finds code sharing vocabulary with the
+/// query rather than meaning, but needs no network or model files.
+pub fn hash_embedding(text: &str) -> Vec<f32> {
+    let mut counts: HashMap<u64, f32> = HashMap::new();
+    for token in tokens(text) {
+        *counts.entry(fnv1a(&token)).or_default() += 1.0;
+    }
+    let mut vector = vec![0.0f32; HASH_DIMENSIONS];
+    for (hash, count) in counts {
+        let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+        vector[(hash % HASH_DIMENSIONS as u64) as usize] += sign * (1.0 + count.ln());
+    }
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+    vector
+}
@@ -0,0 +1,295 @@
+// `repopatch service install|start|stop|uninstall` keeps the server running
+// on a workstation: a systemd user unit on Linux, a launchd agent on macOS.
+//
+// `install` writes the unit for the current binary, runs it in the current
+// directory (so repopatch.toml, .env and the default data files resolve as
+// they do now) and copies the RepoPatch variables set in this shell into it,
+// then enables it to start at login. `install --print` only prints the unit.
+// The unit can hold tokens, so it is only readable by its owner.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const USAGE: &str = "Usage: repopatch service install [--print] | start | stop | uninstall";
+const SYSTEMD_UNIT: &str = "repopatch.service";
+const LAUNCHD_LABEL: &str = "com.repopatch.server";
+
+/// Variables the server reads, copied into the unit when set.
+const SERVICE_ENV: &[&str] = &[
+    "ADMIN_TOKEN",
+    "AGENT_NAME",
+    "ALLOWED_ORIGINS",
+    "CHATS_FILE",
+    "CHECKPOINT_MAX_MB",
+    "CONFIRMATION_TTL_SECS",
+    "CONFIRM_DELETES",
+    "CONFIRM_FILES",
+    "CSRF_PROTECTION",
+    "DATA_DIR",
+    "DELETE_TREE_MAX_FILES",
+    "DELETE_TREE_MAX_MB",
+    "DELETE_TREE_PER_HOUR",
+    "DISK_RESERVE_MB",
+    "DROP_FOLDER",
+    "DROP_WORKSPACE",
+    "EMBEDDINGS_API_KEY",
+    "EMBEDDINGS_MODEL",
+    "EMBEDDINGS_PROVIDER",
+    "EMBEDDINGS_URL",
+    "GITHUB_API_URL",
+    "GITHUB_TOKEN",
+    "GITLAB_TOKEN",
+    "GITLAB_URL",
+    "HUB_TOKEN",
+    "HUB_URL",
+    "IMPORT_ALLOWED_HOSTS",
+    "IMPORT_MAX_BYTES",
+    "LOCALE",
+    "LOCK_DIR",
+    "LSP",
+    "LSP_IDLE_SECS",
+    "MAX_REQUEST_MB",
+    "MAX_UPLOAD_MB",
+    "MEMORY_REPO",
+    "MEMORY_REPO_ROOT",
+    "MESSAGES_DIR",
+    "OIDC_CLIENT_ID",
+    "OIDC_CLIENT_SECRET",
+    "OIDC_ISSUER",
+    "OIDC_REDIRECT_URL",
+    "OIDC_USER_CLAIM",
+    "PACK_CACHE_DIR",
+    "PACK_CACHE_MB",
+    "PORT",
+    "PORT_AUTO",
+    "PORT_AUTO_TRIES",
+    "PORT_FILE",
+    "PROFILES_FILE",
+    "QUOTA_PATCHES",
+    "QUOTA_READ_MB",
+    "QUOTA_WRITE_MB",
+    "REMOTE_INSTANCES",
+    "REPOPATCH_CONFIG",
+    "ROOT_LOCKS",
+    "RUST_LOG",
+    "SECRET_SCAN",
+    "SETTINGS_FILE",
+    "TERMINAL",
+    "TERMINAL_IDLE_SECS",
+    "TERMINAL_SHELL",
+    "TEST_RUNS",
+    "TEST_TIMEOUT_SECS",
+    "TRASH_MB",
+    "USAGE_WINDOW_SECS",
+    "USERS_FILE",
+    "USE_HTTPS",
+    "WRITABLE_REFRESH_SECS",
+];
+
+enum Manager {
+    Systemd,
+    Launchd,
+}
+
+impl Manager {
+    fn current() -> Result<Self, String> {
+        match env::consts::OS {
+            "linux" => Ok(Manager::Systemd),
+            "macos" => Ok(Manager::Launchd),
+            os => Err(format!("Services are not supported on {}", os)),
+        }
+    }
+
+    fn unit_path(&self) -> Result<PathBuf, String> {
+        let home = env::var_os("HOME").map(PathBuf::from).ok_or("HOME is not set")?;
+        Ok(match self {
+            Manager::Systemd => env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| home.join(".config"))
+                .join("systemd/user")
+                .join(SYSTEMD_UNIT),
+            Manager::Launchd => home.join("Library/LaunchAgents").join(format!("{}.plist", LAUNCHD_LABEL)),
+        })
+    }
+}
+
+/// Runs `repopatch service` with the arguments after the subcommand and
+/// returns the exit code.
+pub fn run(args: &[String]) -> i32 {
+    let manager = match Manager::current() {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["install"] => install(&manager, false),
+        ["install", "--print"] => install(&manager, true),
+        ["start"] => start(&manager),
+        ["stop"] => stop(&manager),
+        ["uninstall"] => uninstall(&manager),
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+fn install(manager: &Manager, print: bool) -> Result<(), String> {
+    let exe = env::current_exe().and_then(|p| p.canonicalize()).map_err(|e| format!("Failed to find the repopatch binary: {}", e))?;
+    let dir = env::current_dir().map_err(|e| format!("Failed to read the current directory: {}", e))?;
+    let vars: Vec<(&str, String)> = SERVICE_ENV
+        .iter()
+        .filter_map(|name| env::var(name).ok().map(|value| (*name, value)))
+        .map(|(name, value)| match name {
+            // Relative to the current directory, like the unit's
+            "REPOPATCH_CONFIG" if !value.is_empty() => (name, dir.join(&value).to_string_lossy().into_owned()),
+            _ => (name, value),
+        })
+        .collect();
+    let unit = match manager {
+        Manager::Systemd => systemd_unit(&exe, &dir, &vars),
+        Manager::Launchd => launchd_plist(&exe, &dir, &vars),
+    };
+    if print {
+        print!("{}", unit);
+        return Ok(());
+    }
+
+    let path = manager.unit_path()?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    write_private(&path, &unit).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    println!("Wrote {}", path.display());
+    match manager {
+        Manager::Systemd => {
+            command("systemctl", &["--user", "daemon-reload"])?;
+            command("systemctl", &["--user", "enable", SYSTEMD_UNIT])?;
+            println!("Enabled {}; `repopatch service start` starts it now.", SYSTEMD_UNIT);
+            println!("User services only run while you are logged in unless lingering is on: loginctl enable-linger $USER");
+        }
+        Manager::Launchd => println!("It starts at login; `repopatch service start` starts it now."),
+    }
+    Ok(())
+}
+
+fn start(manager: &Manager) -> Result<(), String> {
+    let path = installed(manager)?;
+    match manager {
+        Manager::Systemd => command("systemctl", &["--user", "start", SYSTEMD_UNIT]),
+        Manager::Launchd => command("launchctl", &["load", "-w", &path.to_string_lossy()]),
+    }
+}
+
+fn stop(manager: &Manager) -> Result<(), String> {
+    let path = installed(manager)?;
+    match manager {
+        Manager::Systemd => command("systemctl", &["--user", "stop", SYSTEMD_UNIT]),
+        Manager::Launchd => command("launchctl", &["unload", &path.to_string_lossy()]),
+    }
+}
+
+fn uninstall(manager: &Manager) -> Result<(), String> {
+    let path = installed(manager)?;
+    match manager {
+        Manager::Systemd => command("systemctl", &["--user", "disable", "--now", SYSTEMD_UNIT])?,
+        // Fails when it isn't loaded, which is fine
+        Manager::Launchd => _ = command("launchctl", &["unload", "-w", &path.to_string_lossy()]),
+    }
+    fs::remove_file(&path).map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+    if let Manager::Systemd = manager {
+        command("systemctl", &["--user", "daemon-reload"])?;
+    }
+    println!("Removed {}", path.display());
+    Ok(())
+}
+
+fn installed(manager: &Manager) -> Result<PathBuf, String> {
+    let path = manager.unit_path()?;
+    if !path.exists() {
+        return Err(format!("{} does not exist; run `repopatch service install` first", path.display()));
+    }
+    Ok(path)
+}
+
+fn command(program: &str, args: &[&str]) -> Result<(), String> {
+    let status = Command::new(program).args(args).status().map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{} {} failed with {}", program, args.join(" "), status))
+    }
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, content: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    // The mode only applies to new files
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(content.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, content: &str) -> std::io::Result<()> {
+    fs::write(path, content)
+}
+
+fn systemd_unit(exe: &Path, dir: &Path, vars: &[(&str, String)]) -> String {
+    // % is doubled so systemd doesn't read specifiers; quoted values also
+    // escape backslashes and quotes. WorkingDirectory takes the bare path.
+    let specifiers = |value: &str| value.replace('%', "%%");
+    let quote = |value: &str| format!("\"{}\"", specifiers(value).replace('\\', "\\\\").replace('"', "\\\""));
+    let mut unit = format!(
+        "[Unit]\nDescription=RepoPatch server\nAfter=network.target\n\n[Service]\nExecStart={}\nWorkingDirectory={}\n",
+        quote(&exe.to_string_lossy()),
+        specifiers(&dir.to_string_lossy()),
+    );
+    for (name, value) in vars {
+        unit.push_str(&format!("Environment={}\n", quote(&format!("{}={}", name, value))));
+    }
+    unit.push_str("Restart=on-failure\nRestartSec=5\n\n[Install]\nWantedBy=default.target\n");
+    unit
+}
+
+fn launchd_plist(exe: &Path, dir: &Path, vars: &[(&str, String)]) -> String {
+    let xml = |value: &str| value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+    let logs = env::var_os("HOME").map(PathBuf::from).unwrap_or_default().join("Library/Logs/repopatch.log");
+    let mut plist = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+         <plist version=\"1.0\">\n<dict>\n\
+         \t<key>Label</key>\n\t<string>{}</string>\n\
+         \t<key>ProgramArguments</key>\n\t<array>\n\t\t<string>{}</string>\n\t</array>\n\
+         \t<key>WorkingDirectory</key>\n\t<string>{}</string>\n\
+         \t<key>RunAtLoad</key>\n\t<true/>\n\
+         \t<key>KeepAlive</key>\n\t<dict>\n\t\t<key>SuccessfulExit</key>\n\t\t<false/>\n\t</dict>\n\
+         \t<key>StandardOutPath</key>\n\t<string>{3}</string>\n\
+         \t<key>StandardErrorPath</key>\n\t<string>{3}</string>\n",
+        LAUNCHD_LABEL,
+        xml(&exe.to_string_lossy()),
+        xml(&dir.to_string_lossy()),
+        xml(&logs.to_string_lossy()),
+    );
+    if !vars.is_empty() {
+        plist.push_str("\t<key>EnvironmentVariables</key>\n\t<dict>\n");
+        for (name, value) in vars {
+            plist.push_str(&format!("\t\t<key>{}</key>\n\t\t<string>{}</string>\n", name, xml(value)));
+        }
+        plist.push_str("\t</dict>\n");
+    }
+    plist.push_str("</dict>\n</plist>\n");
+    plist
+}
@@ -0,0 +1,155 @@
+// UI preferences kept on the server so they follow a user across browsers:
+// GET /api/settings returns the caller's settings and PUT /api/settings
+// replaces them. Settings are keyed by the signed-in user (bearer token or
+// OIDC session); without authentication everyone shares one entry.
+//
+// They are saved as JSON in SETTINGS_FILE (default repopatch-settings.json
+// in the working directory); an empty SETTINGS_FILE keeps them in memory.
+// With DATA_DIR they are rows of the metadata database instead, imported
+// from SETTINGS_FILE the first time.
+
+use crate::auth::Session;
+use crate::validation::ValidJson;
+use repopatch::metadata::{object_entries, write_json_atomically, Metadata};
+use actix_web::{get, put, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_SETTINGS_FILE: &str = "repopatch-settings.json";
+/// Record kind in the metadata database, keyed by user.
+const KIND: &str = "settings";
+pub const SHARED_USER: &str = "default";
+const THEMES: &[&str] = &["light", "dark", "system"];
+const MAX_STRIP_LEVEL: usize = 10;
+const MAX_FUZZ: usize = 10;
+/// Largest serialized `ui` object accepted per user.
+const MAX_UI_BYTES: usize = 64 * 1024;
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Settings {
+    /// `light`, `dark` or `system`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub theme: Option<String>,
+    /// Default `-p` level for patches pasted without one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strip_level: Option<usize>,
+    /// Default fuzz factor for hunk matching.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fuzz: Option<usize>,
+    /// Directory the UI opened last.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_root: Option<String>,
+    /// Free-form preferences of the frontend.
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub ui: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), (String, &'static str)> {
+        if let Some(theme) = self.theme.as_deref().filter(|t| !THEMES.contains(t)) {
+            return Err((format!("Unknown theme '{}': expected one of {}", theme, THEMES.join(", ")), "theme"));
+        }
+        if self.strip_level.is_some_and(|p| p > MAX_STRIP_LEVEL) {
+            return Err((format!("stripLevel must be at most {}", MAX_STRIP_LEVEL), "stripLevel"));
+        }
+        if self.fuzz.is_some_and(|f| f > MAX_FUZZ) {
+            return Err((format!("fuzz must be at most {}", MAX_FUZZ), "fuzz"));
+        }
+        if serde_json::to_vec(&self.ui).map(|v| v.len()).unwrap_or(0) > MAX_UI_BYTES {
+            return Err((format!("ui must be at most {} bytes of JSON", MAX_UI_BYTES), "ui"));
+        }
+        Ok(())
+    }
+}
+
+pub struct SettingsStore {
+    path: Option<PathBuf>,
+    metadata: Option<Arc<Metadata>>,
+    users: Mutex<BTreeMap<String, Settings>>,
+}
+
+impl SettingsStore {
+    /// Opens the store named by SETTINGS_FILE, or the settings in `metadata`
+    /// when given. A missing file starts empty; an unreadable or invalid one
+    /// is an error.
+    pub fn from_env(metadata: Option<Arc<Metadata>>) -> Result<Self, String> {
+        let path = match env::var("SETTINGS_FILE") {
+            Ok(p) if p.is_empty() => None,
+            Ok(p) => Some(PathBuf::from(p)),
+            Err(_) => Some(PathBuf::from(DEFAULT_SETTINGS_FILE)),
+        };
+        if let Some(metadata) = metadata {
+            if let Some(path) = &path {
+                let imported = metadata.import_file(KIND, path, object_entries)?;
+                if imported > 0 {
+                    log::info!("Imported the settings of {} users from {} into {}", imported, path.display(), metadata.path().display());
+                }
+            }
+            let users = metadata.load_as(KIND)?.into_iter().collect();
+            return Ok(SettingsStore { path: None, metadata: Some(metadata), users: Mutex::new(users) });
+        }
+        let users = match &path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(content) => serde_json::from_str(&content).map_err(|e| format!("Invalid {}: {}", path.display(), e))?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+                Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+            },
+            None => BTreeMap::new(),
+        };
+        Ok(SettingsStore { path, metadata: None, users: Mutex::new(users) })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, Settings>> {
+        self.users.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn get(&self, user: &str) -> Settings {
+        self.lock().get(user).cloned().unwrap_or_default()
+    }
+
+    /// Replaces the settings of `user` and saves the store.
+    pub fn put(&self, user: &str, settings: Settings) -> Result<(), String> {
+        let mut users = self.lock();
+        if let Some(metadata) = &self.metadata {
+            metadata.put(KIND, user, &settings)?;
+        }
+        users.insert(user.to_string(), settings);
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        write_json_atomically(path, &*users)
+    }
+}
+
+/// The user settings and chats are kept for.
+pub fn user_of(session: &Option<web::ReqData<Session>>) -> String {
+    session.as_ref().map(|s| s.user.clone()).unwrap_or_else(|| SHARED_USER.to_string())
+}
+
+#[get("/api/settings")]
+pub async fn get_settings(session: Option<web::ReqData<Session>>, store: web::Data<SettingsStore>) -> HttpResponse {
+    let user = user_of(&session);
+    HttpResponse::Ok().json(json!({ "success": true, "user": user, "settings": store.get(&user) }))
+}
+
+#[put("/api/settings")]
+pub async fn put_settings(body: ValidJson<Settings>, session: Option<web::ReqData<Session>>, store: web::Data<SettingsStore>) -> HttpResponse {
+    if let Err((error, field)) = body.validate() {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": error, "field": field }));
+    }
+    let user = user_of(&session);
+    let settings = body.into_inner();
+    match store.put(&user, settings.clone()) {
+        Ok(()) => HttpResponse::Ok().json(json!({ "success": true, "user": user, "settings": settings })),
+        Err(e) => {
+            log::error!("{}", e);
+            HttpResponse::InternalServerError().json(json!({ "success": false, "error": e }))
+        }
+    }
+}
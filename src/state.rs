@@ -0,0 +1,226 @@
+// Moving a setup to another machine, or backing it up: GET /api/export_state
+// returns a .tar.gz of the caller's settings, chats (with the links to the
+// changes they produced), workspace profiles and the reports kept for
+// /api/export_patch, and POST /api/import_state adds such an archive to this
+// server. Workspace files are not included, though each report holds the
+// before and after of the files it changed. Profiles and reports only travel
+// for workspaces the caller can open, on both ends.
+
+use crate::auth::{Session, Stores};
+use crate::chats::{Chat, ChatStore, Link};
+use crate::history::{History, StoredReport};
+use crate::profiles::{Profile, ProfileStore};
+use crate::settings::{user_of, Settings, SettingsStore};
+use actix_web::http::header;
+use actix_web::{get, web, HttpResponse};
+use chrono::Utc;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+
+pub const FORMAT: &str = "repopatch-state";
+pub const VERSION: u32 = 1;
+/// Largest archive accepted, unpacked or not.
+pub const MAX_STATE_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    format: String,
+    version: u32,
+    exported: String,
+    /// Who exported it; imports belong to whoever imports them.
+    user: String,
+    server: String,
+    /// How many of each thing it holds.
+    #[serde(default)]
+    contents: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct ChatsFile {
+    chats: Vec<Chat>,
+    links: Vec<Link>,
+}
+
+fn pack(files: &[(&str, Vec<u8>)]) -> std::io::Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    let modified = Utc::now().timestamp().max(0) as u64;
+    for (name, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(modified);
+        header.set_cksum();
+        builder.append_data(&mut header, name, content.as_slice())?;
+    }
+    builder.into_inner()?.finish()
+}
+
+fn unpack(data: &[u8]) -> Result<HashMap<String, Vec<u8>>, String> {
+    let mut archive = tar::Archive::new(GzDecoder::new(data).take(MAX_STATE_BYTES as u64 + 1));
+    let mut files = HashMap::new();
+    let mut total = 0;
+    for entry in archive.entries().map_err(|e| format!("Not a state archive: {}", e))? {
+        let mut entry = entry.map_err(|e| format!("Not a state archive: {}", e))?;
+        let name = entry.path().map_err(|e| e.to_string())?.to_string_lossy().trim_start_matches("./").to_string();
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content).map_err(|e| format!("Failed to read {} from the archive: {}", name, e))?;
+        total += content.len();
+        if total > MAX_STATE_BYTES {
+            return Err(format!("The archive unpacks to more than {} bytes", MAX_STATE_BYTES));
+        }
+        files.insert(name, content);
+    }
+    Ok(files)
+}
+
+fn parse<T: DeserializeOwned + Default>(files: &HashMap<String, Vec<u8>>, name: &str) -> Result<T, String> {
+    match files.get(name) {
+        Some(content) => serde_json::from_slice(content).map_err(|e| format!("Invalid {}: {}", name, e)),
+        None => Ok(T::default()),
+    }
+}
+
+fn to_json(value: &impl Serialize) -> Vec<u8> {
+    serde_json::to_vec_pretty(value).unwrap_or_default()
+}
+
+#[get("/api/export_state")]
+pub async fn export_state(
+    stores: Stores,
+    session: Option<web::ReqData<Session>>,
+    settings: web::Data<SettingsStore>,
+    chats: web::Data<ChatStore>,
+    profiles: web::Data<ProfileStore>,
+    history: web::Data<History>,
+) -> HttpResponse {
+    let user = user_of(&session);
+    let now = Utc::now();
+    let (chat_list, links) = chats.export(&user);
+    let workspaces = profiles.export(|root| stores.open_root(root).is_ok());
+    let reports: Vec<StoredReport> = history.reports().iter().filter(|r| stores.open_root(&r.root).is_ok()).map(StoredReport::from).collect();
+    let manifest = Manifest {
+        format: FORMAT.to_string(),
+        version: VERSION,
+        exported: now.to_rfc3339(),
+        user: user.clone(),
+        server: env!("CARGO_PKG_VERSION").to_string(),
+        contents: json!({ "chats": chat_list.len(), "links": links.len(), "workspaces": workspaces.len(), "reports": reports.len() }),
+    };
+    let files = [
+        ("manifest.json", to_json(&manifest)),
+        ("settings.json", to_json(&settings.get(&user))),
+        ("chats.json", to_json(&ChatsFile { chats: chat_list, links })),
+        ("profiles.json", to_json(&workspaces)),
+        ("reports.json", to_json(&reports)),
+    ];
+    match web::block(move || pack(&files)).await {
+        Ok(Ok(archive)) => HttpResponse::Ok()
+            .content_type("application/gzip")
+            .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"repopatch-state-{}.tar.gz\"", now.format("%Y%m%d-%H%M%S"))))
+            .body(archive),
+        Ok(Err(e)) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Failed to write the archive: {}", e) })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Export task failed: {}", e) })),
+    }
+}
+
+/// POST /api/import_state, registered with a body limit of
+/// [`MAX_STATE_BYTES`]. The body is an archive from /api/export_state.
+#[allow(clippy::too_many_arguments)]
+pub async fn import_state(
+    body: web::Bytes,
+    stores: Stores,
+    session: Option<web::ReqData<Session>>,
+    settings: web::Data<SettingsStore>,
+    chats: web::Data<ChatStore>,
+    profiles: web::Data<ProfileStore>,
+    history: web::Data<History>,
+) -> HttpResponse {
+    let files = match web::block(move || unpack(&body)).await {
+        Ok(Ok(files)) => files,
+        Ok(Err(e)) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+        Err(e) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Import task failed: {}", e) })),
+    };
+    let manifest: Manifest = match files.get("manifest.json").map(|m| serde_json::from_slice(m)) {
+        Some(Ok(manifest)) => manifest,
+        Some(Err(e)) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid manifest.json: {}", e) })),
+        None => return HttpResponse::BadRequest().json(json!({ "success": false, "error": "Not a state archive: it has no manifest.json" })),
+    };
+    if manifest.format != FORMAT {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Not a state archive: its format is '{}'", manifest.format) }));
+    }
+    if manifest.version > VERSION {
+        return HttpResponse::UnprocessableEntity().json(json!({
+            "success": false,
+            "error": format!("The archive has version {} from repopatch {}; this server reads up to version {}", manifest.version, manifest.server, VERSION)
+        }));
+    }
+    let parsed = (|| -> Result<_, String> {
+        let imported_settings: Option<Settings> = parse(&files, "settings.json")?;
+        let chats_file: ChatsFile = parse(&files, "chats.json")?;
+        let workspaces: BTreeMap<String, BTreeMap<String, Profile>> = parse(&files, "profiles.json")?;
+        let reports: Vec<StoredReport> = parse(&files, "reports.json")?;
+        Ok((imported_settings, chats_file, workspaces, reports))
+    })();
+    let (imported_settings, chats_file, workspaces, reports) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return HttpResponse::UnprocessableEntity().json(json!({ "success": false, "error": e })),
+    };
+
+    let user = user_of(&session);
+    let mut skipped = Vec::new();
+    let mut settings_imported = false;
+    if let Some(imported) = imported_settings {
+        match imported.validate() {
+            Ok(()) => match settings.put(&user, imported) {
+                Ok(()) => settings_imported = true,
+                Err(e) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": e })),
+            },
+            Err((error, field)) => skipped.push(json!({ "kind": "settings", "field": field, "reason": error })),
+        }
+    }
+    let (chat_count, link_count) = match chats.import(&user, chats_file.chats, chats_file.links) {
+        Ok(counts) => counts,
+        Err(e) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": e })),
+    };
+    let mut profile_count = 0;
+    for (root, list) in workspaces {
+        let store = match stores.open_root(&root) {
+            Ok(store) => store,
+            Err(e) => {
+                skipped.push(json!({ "kind": "profiles", "root": root, "reason": e }));
+                continue;
+            }
+        };
+        let count = list.len();
+        match profiles.import(&store.display_path(""), list) {
+            Ok(()) => profile_count += count,
+            Err(e) => skipped.push(json!({ "kind": "profiles", "root": root, "reason": e })),
+        }
+    }
+    let mut report_count = 0;
+    for stored in reports {
+        let id = stored.id.clone();
+        if let Err(e) = stores.open_root(&stored.root) {
+            skipped.push(json!({ "kind": "reports", "reportId": id, "reason": e }));
+            continue;
+        }
+        match stored.into_report().map(|report| history.restore(report)) {
+            Ok(true) => report_count += 1,
+            Ok(false) => skipped.push(json!({ "kind": "reports", "reportId": id, "reason": "Already kept, or older than every kept report" })),
+            Err(e) => skipped.push(json!({ "kind": "reports", "reportId": id, "reason": e })),
+        }
+    }
+    log::info!("{} imported state exported by {} on {}", user, manifest.user, manifest.exported);
+    HttpResponse::Ok().json(json!({
+        "success": true,
+        "exported": manifest.exported,
+        "imported": { "settings": settings_imported, "chats": chat_count, "links": link_count, "profiles": profile_count, "reports": report_count },
+        "skipped": skipped
+    }))
+}
@@ -0,0 +1,247 @@
+//! Per-language file, line and byte counts for a tree, in the spirit of
+//! GitHub's linguist: languages are detected from a vim or Emacs modeline,
+//! then well-known file names, then the extension, then a `#!` interpreter
+//! line.
+
+use crate::store::FileStore;
+use crate::tree::walk_files;
+use ignore::gitignore::Gitignore;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+
+/// Totals for one language.
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct LanguageStats {
+    pub files: u64,
+    pub lines: u64,
+    pub bytes: u64,
+}
+
+const FILE_NAMES: &[(&str, &str)] = &[
+    ("Makefile", "Makefile"),
+    ("GNUmakefile", "Makefile"),
+    ("Dockerfile", "Dockerfile"),
+    ("CMakeLists.txt", "CMake"),
+    ("Cargo.lock", "TOML"),
+    ("Gemfile", "Ruby"),
+    ("Rakefile", "Ruby"),
+    ("Jenkinsfile", "Groovy"),
+];
+
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("rs", "Rust"),
+    ("py", "Python"),
+    ("pyi", "Python"),
+    ("js", "JavaScript"),
+    ("mjs", "JavaScript"),
+    ("cjs", "JavaScript"),
+    ("jsx", "JavaScript"),
+    ("ts", "TypeScript"),
+    ("tsx", "TypeScript"),
+    ("go", "Go"),
+    ("java", "Java"),
+    ("kt", "Kotlin"),
+    ("kts", "Kotlin"),
+    ("scala", "Scala"),
+    ("groovy", "Groovy"),
+    ("c", "C"),
+    ("h", "C"),
+    ("cc", "C++"),
+    ("cpp", "C++"),
+    ("cxx", "C++"),
+    ("hpp", "C++"),
+    ("hh", "C++"),
+    ("cs", "C#"),
+    ("m", "Objective-C"),
+    ("swift", "Swift"),
+    ("rb", "Ruby"),
+    ("php", "PHP"),
+    ("pl", "Perl"),
+    ("pm", "Perl"),
+    ("lua", "Lua"),
+    ("sh", "Shell"),
+    ("bash", "Shell"),
+    ("zsh", "Shell"),
+    ("ps1", "PowerShell"),
+    ("sql", "SQL"),
+    ("html", "HTML"),
+    ("htm", "HTML"),
+    ("css", "CSS"),
+    ("scss", "SCSS"),
+    ("vue", "Vue"),
+    ("svelte", "Svelte"),
+    ("json", "JSON"),
+    ("toml", "TOML"),
+    ("yaml", "YAML"),
+    ("yml", "YAML"),
+    ("xml", "XML"),
+    ("md", "Markdown"),
+    ("markdown", "Markdown"),
+    ("rst", "reStructuredText"),
+    ("txt", "Text"),
+    ("diff", "Diff"),
+    ("patch", "Diff"),
+    ("proto", "Protocol Buffers"),
+    ("graphql", "GraphQL"),
+    ("dart", "Dart"),
+    ("ex", "Elixir"),
+    ("exs", "Elixir"),
+    ("erl", "Erlang"),
+    ("hs", "Haskell"),
+    ("ml", "OCaml"),
+    ("clj", "Clojure"),
+    ("zig", "Zig"),
+    ("nix", "Nix"),
+    ("tf", "HCL"),
+    ("r", "R"),
+    ("jl", "Julia"),
+];
+
+const INTERPRETERS: &[(&str, &str)] = &[
+    ("python", "Python"),
+    ("node", "JavaScript"),
+    ("deno", "TypeScript"),
+    ("ruby", "Ruby"),
+    ("perl", "Perl"),
+    ("php", "PHP"),
+    ("bash", "Shell"),
+    ("sh", "Shell"),
+    ("zsh", "Shell"),
+    ("lua", "Lua"),
+];
+
+// Modeline names that differ from a file extension
+const MODES: &[(&str, &str)] = &[
+    ("python", "Python"),
+    ("rust", "Rust"),
+    ("javascript", "JavaScript"),
+    ("typescript", "TypeScript"),
+    ("ruby", "Ruby"),
+    ("perl", "Perl"),
+    ("shell-script", "Shell"),
+    ("cpp", "C++"),
+    ("c++", "C++"),
+    ("objc", "Objective-C"),
+    ("make", "Makefile"),
+    ("makefile", "Makefile"),
+    ("dockerfile", "Dockerfile"),
+    ("cmake", "CMake"),
+    ("markdown", "Markdown"),
+    ("haskell", "Haskell"),
+    ("elixir", "Elixir"),
+    ("erlang", "Erlang"),
+    ("clojure", "Clojure"),
+    ("go", "Go"),
+    ("java", "Java"),
+    ("kotlin", "Kotlin"),
+];
+
+// Lines at either end of a file that vim looks at for a modeline
+const MODELINE_LINES: usize = 5;
+
+static VIM_MODELINE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?:^|\s)(?:vi|vim|ex):(?:.*?[\s:])?(?:ft|filetype|syntax)=([\w+#-]+)").expect("vim modeline pattern must compile")
+});
+static EMACS_MODELINE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"-\*-(.*?)-\*-").expect("emacs modeline pattern must compile"));
+
+/// Detects the language of the file at `path` (a `/`-separated store path)
+/// from a modeline in `content`, its name or, failing that, a shebang at the
+/// start of `content`. Pass empty content to go by the name alone.
+pub fn detect_language(path: &str, content: &[u8]) -> Option<&'static str> {
+    if let Some(language) = modeline_language(content) {
+        return Some(language);
+    }
+    let name = path.rsplit('/').next().unwrap_or(path);
+    if let Some((_, language)) = FILE_NAMES.iter().find(|(n, _)| *n == name) {
+        return Some(language);
+    }
+    if let Some((_, extension)) = name.rsplit_once('.').filter(|(stem, _)| !stem.is_empty()) {
+        let extension = extension.to_lowercase();
+        if let Some((_, language)) = EXTENSIONS.iter().find(|(e, _)| *e == extension) {
+            return Some(language);
+        }
+    }
+    shebang_language(content)
+}
+
+// Helper function to map a language named by a modeline, either a mode or an extension
+fn mode_language(mode: &str) -> Option<&'static str> {
+    let mode = mode.to_lowercase();
+    MODES
+        .iter()
+        .chain(EXTENSIONS)
+        .chain(INTERPRETERS)
+        .find(|(m, _)| *m == mode)
+        .map(|(_, language)| *language)
+}
+
+// Helper function to find `vim: set ft=python:` in the first or last lines, or
+// `-*- mode: python -*-` in the first line (the second after a shebang)
+fn modeline_language(content: &[u8]) -> Option<&'static str> {
+    let text = String::from_utf8_lossy(content);
+    let lines: Vec<&str> = text.lines().collect();
+    let tail = lines.len().saturating_sub(MODELINE_LINES).max(MODELINE_LINES.min(lines.len()));
+    let vim = lines.iter().take(MODELINE_LINES).chain(&lines[tail..]).find_map(|line| VIM_MODELINE.captures(line));
+    if let Some(language) = vim.and_then(|c| mode_language(&c[1])) {
+        return Some(language);
+    }
+    let emacs_line = lines.iter().take(if text.starts_with("#!") { 2 } else { 1 }).find_map(|line| EMACS_MODELINE.captures(line))?;
+    let settings = emacs_line[1].trim();
+    let mode = match settings.split(';').find_map(|s| s.split_once(':').filter(|(k, _)| k.trim().eq_ignore_ascii_case("mode"))) {
+        Some((_, mode)) => mode.trim(),
+        None if !settings.contains(':') => settings,
+        None => return None,
+    };
+    mode_language(mode)
+}
+
+// Helper function to map `#!/usr/bin/env python3` style lines to a language
+fn shebang_language(content: &[u8]) -> Option<&'static str> {
+    let first_line = content.strip_prefix(b"#!")?.split(|b| *b == b'\n').next()?;
+    let line = String::from_utf8_lossy(first_line);
+    let mut words = line.split_whitespace();
+    let mut program = words.next()?.rsplit('/').next()?;
+    if program == "env" {
+        program = words.find(|w| !w.starts_with('-'))?;
+    }
+    let program = program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    INTERPRETERS.iter().find(|(p, _)| *p == program).map(|(_, language)| *language)
+}
+
+/// Counts lines the way editors show them: a trailing newline does not
+/// start another line.
+pub fn count_lines(content: &[u8]) -> u64 {
+    let newlines = content.iter().filter(|b| **b == b'\n').count() as u64;
+    if content.is_empty() || content.ends_with(b"\n") {
+        newlines
+    } else {
+        newlines + 1
+    }
+}
+
+/// Aggregates statistics for every non-ignored text file below `dir`. Files
+/// whose language can't be detected are counted as "Other"; binary files
+/// are skipped.
+pub fn language_stats(store: &dyn FileStore, dir: &str, ig: &Gitignore) -> Result<BTreeMap<String, LanguageStats>, String> {
+    let mut stats: BTreeMap<String, LanguageStats> = BTreeMap::new();
+    walk_files(store, dir, ig, &mut |path| {
+        let content = match store.read(path) {
+            Ok(c) => c,
+            Err(e) => {
+                log::debug!("Skipping {} in stats: {}", path, e);
+                return;
+            }
+        };
+        if content.contains(&0) {
+            return;
+        }
+        let language = detect_language(path, &content).unwrap_or("Other");
+        let entry = stats.entry(language.to_string()).or_default();
+        entry.files += 1;
+        entry.lines += count_lines(&content);
+        entry.bytes += content.len() as u64;
+    })?;
+    Ok(stats)
+}
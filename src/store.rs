@@ -0,0 +1,941 @@
+//! Storage backends the patch engine reads from and writes to.
+//!
+//! Paths handed to a [`FileStore`] are relative to the store root and use `/`
+//! as separator, exactly as they appear in a patch after path stripping.
+
+use crate::tree::VCS_DIRS;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// An entry returned by [`FileStore::list_dir`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+    /// True for symbolic links; `is_dir` tells what they point to.
+    pub is_symlink: bool,
+}
+
+/// A tree of files the server and the patch engine operate on. The empty
+/// path denotes the store root.
+pub trait FileStore: Send + Sync {
+    /// Reads the whole file at `path`.
+    fn read(&self, path: &str) -> io::Result<Vec<u8>>;
+
+    /// Reads at most the first `max` bytes of the file at `path`. The default
+    /// reads the whole file; backends that can stop early should override it.
+    fn read_head(&self, path: &str, max: usize) -> io::Result<Vec<u8>> {
+        let mut data = self.read(path)?;
+        data.truncate(max);
+        Ok(data)
+    }
+
+    /// Writes `data` to `path`, creating parent directories as needed.
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()>;
+
+    /// Removes the file at `path`.
+    fn remove(&self, path: &str) -> io::Result<()>;
+
+    /// Removes the empty directory at `path`. The default does nothing, for
+    /// stores whose directories only exist through the files in them.
+    fn remove_dir(&self, _path: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Returns true if a file exists at `path`.
+    fn exists(&self, path: &str) -> bool;
+
+    /// Lists the entries of the directory at `path`.
+    fn list_dir(&self, path: &str) -> io::Result<Vec<DirEntry>>;
+
+    /// Returns the path shown to clients for `path`, e.g. an absolute
+    /// filesystem path for local stores.
+    fn display_path(&self, path: &str) -> String {
+        path.to_string()
+    }
+
+    /// Checks that `path` stays inside the store. The default only rejects
+    /// absolute paths and `..` components.
+    fn validate(&self, path: &str) -> Result<(), String> {
+        check_relative(path)
+    }
+
+    /// Checks that `path` may be written or removed. The default allows
+    /// everything [`FileStore::validate`] does.
+    fn check_writable(&self, _path: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Returns the size of the file at `path` in bytes. The default reads the
+    /// whole file; backends with cheap metadata should override it.
+    fn file_size(&self, path: &str) -> Option<u64> {
+        self.read(path).ok().map(|data| data.len() as u64)
+    }
+
+    /// Returns when the file at `path` was last modified, if the backend
+    /// tracks it.
+    fn modified(&self, _path: &str) -> Option<SystemTime> {
+        None
+    }
+
+    /// Returns the bytes available for writing `path`, if the backend can
+    /// tell. Writers compare it against the size they are about to write.
+    fn available_space(&self, _path: &str) -> Option<u64> {
+        None
+    }
+
+    /// Identifies what `path` points to, following symbolic links: the
+    /// device and inode numbers on Unix. Walkers compare them to notice a
+    /// folder that contains itself through a link or a bind mount. `None`
+    /// when the backend can't tell.
+    fn file_id(&self, _path: &str) -> Option<(u64, u64)> {
+        None
+    }
+
+    /// Returns where the file at `path` lives on the local filesystem, so
+    /// it can be sent without reading it into memory. `None` for files only
+    /// [`FileStore::read`] can reach.
+    fn local_path(&self, _path: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// Joins a directory path and an entry name inside a store.
+pub fn join_path(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir.trim_end_matches('/'), name)
+    }
+}
+
+/// The folder holding a store path, `""` for the root.
+pub fn dir_of(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+/// Resolves the `.` and `..` components of a store path; `None` if it
+/// leaves the root.
+pub fn normalize_path(path: &str) -> Option<String> {
+    let mut parts = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            c => parts.push(c),
+        }
+    }
+    Some(parts.join("/"))
+}
+
+/// Rejects paths that could leave the directory they are relative to:
+/// absolute paths, `..` and other non-normal components, and forms that are
+/// harmless here but escape elsewhere (NUL bytes, `\\` separators, drive
+/// prefixes like `C:`, and percent-encoded separators or `..`). This is the
+/// one lexical check every store and endpoint goes through.
+pub fn check_relative(path: &str) -> Result<(), String> {
+    let escapes = || Err(format!("Path {} escapes the target directory", path));
+    if path.contains('\0') || path.contains('\\') {
+        return escapes();
+    }
+    let relative = Path::new(path);
+    let is_plain = relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if relative.is_absolute() || !is_plain {
+        return escapes();
+    }
+    for (i, component) in path.split('/').enumerate() {
+        let bytes = component.as_bytes();
+        let drive = i == 0 && bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':';
+        if drive || (cfg!(windows) && component.contains(':')) {
+            return escapes();
+        }
+        let lower = component.to_ascii_lowercase();
+        let decoded = lower.replace("%2e", ".");
+        if lower.contains("%2f") || lower.contains("%5c") || (decoded == ".." && lower != "..") {
+            return escapes();
+        }
+    }
+    Ok(())
+}
+
+/// Resolves a filesystem path sent by a client to its canonical form. Every
+/// local provider and configured root list goes through it.
+pub fn canonical_client_path(path: &str) -> Result<PathBuf, String> {
+    if path.contains('\0') {
+        return Err(format!("Invalid path '{}': contains a NUL byte", path.escape_debug()));
+    }
+    PathBuf::from(path).canonicalize().map_err(|e| format!("Invalid path '{}': {}", path, e))
+}
+
+/// A store backed by a directory on the local filesystem.
+#[derive(Debug, Clone)]
+pub struct FsStore {
+    root: PathBuf,
+}
+
+impl FsStore {
+    /// Creates a store rooted at `root`, which should already be canonicalized
+    /// so that symlink containment checks are meaningful.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        FsStore { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Resolves `path` to a location under the root, rejecting paths that
+    /// escape it lexically or through symlinks. Paths that don't exist yet
+    /// are checked through their deepest existing ancestor, so creating a
+    /// file below a symlinked folder can't write outside the root either.
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, String> {
+        check_relative(path)?;
+        let full_path = self.root.join(path);
+        for ancestor in full_path.ancestors() {
+            match ancestor.canonicalize() {
+                Ok(canonical) if canonical.starts_with(&self.root) => break,
+                Ok(_) => return Err(format!("Path {} resolves outside the target directory", path)),
+                // A dangling symlink would be followed when the file is created
+                Err(_) if ancestor.symlink_metadata().is_ok() => {
+                    return Err(format!("Path {} goes through a symlink that doesn't resolve", path));
+                }
+                Err(_) => {}
+            }
+        }
+        Ok(full_path)
+    }
+
+    fn resolve_io(&self, path: &str) -> io::Result<PathBuf> {
+        self.resolve(path).map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e))
+    }
+}
+
+impl FileStore for FsStore {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.resolve_io(path)?)
+    }
+
+    fn read_head(&self, path: &str, max: usize) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        fs::File::open(self.resolve_io(path)?)?.take(max as u64).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        let full_path = self.resolve_io(path)?;
+        if let Some(parent) = full_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(full_path, data)
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        fs::remove_file(self.resolve_io(path)?)
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        fs::remove_dir(self.resolve_io(path)?)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.resolve(path).map(|p| p.is_file()).unwrap_or(false)
+    }
+
+    fn list_dir(&self, path: &str) -> io::Result<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(self.resolve_io(path)?)? {
+            let entry = entry?;
+            entries.push(DirEntry {
+                name: entry.file_name().to_string_lossy().to_string(),
+                is_dir: entry.path().is_dir(),
+                is_symlink: entry.file_type()?.is_symlink(),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn display_path(&self, path: &str) -> String {
+        if path.is_empty() {
+            self.root.to_string_lossy().to_string()
+        } else {
+            self.root.join(path).to_string_lossy().to_string()
+        }
+    }
+
+    fn validate(&self, path: &str) -> Result<(), String> {
+        self.resolve(path).map(|_| ())
+    }
+
+    fn file_size(&self, path: &str) -> Option<u64> {
+        let full_path = self.resolve(path).ok()?;
+        fs::metadata(full_path).ok().map(|m| m.len())
+    }
+
+    fn modified(&self, path: &str) -> Option<SystemTime> {
+        let full_path = self.resolve(path).ok()?;
+        fs::metadata(full_path).and_then(|m| m.modified()).ok()
+    }
+
+    #[cfg(unix)]
+    fn file_id(&self, path: &str) -> Option<(u64, u64)> {
+        use std::os::unix::fs::MetadataExt;
+        let full_path = self.resolve(path).ok()?;
+        fs::metadata(full_path).ok().map(|m| (m.dev(), m.ino()))
+    }
+
+    fn available_space(&self, path: &str) -> Option<u64> {
+        // The file and its parent directories may not exist yet
+        let full_path = self.resolve(path).ok()?;
+        let existing = full_path.ancestors().find(|p| p.exists())?;
+        free_space(existing)
+    }
+
+    fn local_path(&self, path: &str) -> Option<PathBuf> {
+        self.resolve(path).ok().filter(|p| p.is_file())
+    }
+}
+
+#[cfg(unix)]
+fn free_space(path: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    // SAFETY: statvfs is a C struct of integers, for which all zeroes is valid
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: c_path is NUL-terminated and stat is a valid out pointer
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// A store holding files in memory, for tests and for previewing patches
+/// without touching disk.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    files: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a store pre-populated with `(path, content)` pairs.
+    pub fn from_files<P, C>(files: impl IntoIterator<Item = (P, C)>) -> Self
+    where
+        P: Into<String>,
+        C: Into<Vec<u8>>,
+    {
+        let files = files.into_iter().map(|(p, c)| (p.into(), c.into())).collect();
+        MemoryStore { files: Mutex::new(files) }
+    }
+
+    /// Returns the content of `path` as text, if present and valid UTF-8.
+    pub fn get(&self, path: &str) -> Option<String> {
+        self.lock().get(path).and_then(|data| String::from_utf8(data.clone()).ok())
+    }
+
+    /// Returns a snapshot of every file in the store.
+    pub fn files(&self) -> BTreeMap<String, Vec<u8>> {
+        self.lock().clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, Vec<u8>>> {
+        self.files.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl FileStore for MemoryStore {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.lock()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path)))
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        check_relative(path).map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e))?;
+        self.lock().insert(path.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        self.lock()
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path)))
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.lock().contains_key(path)
+    }
+
+    fn list_dir(&self, path: &str) -> io::Result<Vec<DirEntry>> {
+        let prefix = if path.is_empty() { String::new() } else { format!("{}/", path.trim_end_matches('/')) };
+        let mut entries: BTreeMap<String, bool> = BTreeMap::new();
+        for key in self.lock().keys() {
+            if let Some(rest) = key.strip_prefix(&prefix) {
+                match rest.split_once('/') {
+                    Some((dir, _)) => entries.insert(dir.to_string(), true),
+                    None => entries.insert(rest.to_string(), false),
+                };
+            }
+        }
+        if entries.is_empty() && !path.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path)));
+        }
+        Ok(entries.into_iter().map(|(name, is_dir)| DirEntry { name, is_dir, is_symlink: false }).collect())
+    }
+}
+
+/// Maps the paths clients send (directory roots and individual files) onto
+/// stores. The local filesystem and an in-memory tree are the backends
+/// today; remote backends plug in by implementing this trait.
+pub trait StoreProvider: Send + Sync {
+    /// Opens the directory `root` as a store.
+    fn open_root(&self, root: &str) -> Result<Arc<dyn FileStore>, String>;
+
+    /// Splits a client-supplied file path into the store holding it and the
+    /// file's path inside that store.
+    fn open_file(&self, path: &str) -> Result<(Arc<dyn FileStore>, String), String>;
+}
+
+/// Serves absolute paths on the local filesystem.
+#[derive(Debug, Default, Clone)]
+pub struct LocalStoreProvider;
+
+impl StoreProvider for LocalStoreProvider {
+    fn open_root(&self, root: &str) -> Result<Arc<dyn FileStore>, String> {
+        let resolved = canonical_client_path(root)?;
+        if !resolved.is_dir() {
+            return Err("Provided path is not a directory".to_string());
+        }
+        Ok(Arc::new(FsStore::new(resolved)))
+    }
+
+    fn open_file(&self, path: &str) -> Result<(Arc<dyn FileStore>, String), String> {
+        let resolved = canonical_client_path(path)?;
+        if !resolved.is_file() {
+            return Err("Path is not a file".to_string());
+        }
+        let parent = resolved.parent().map(Path::to_path_buf).unwrap_or_else(|| PathBuf::from("/"));
+        let name = resolved.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        Ok((Arc::new(FsStore::new(parent)), name))
+    }
+}
+
+/// Serves one [`MemoryStore`] as if it were the directory `root`, so the UI
+/// and the API behave as they do on disk. Used for demos and hermetic tests.
+pub struct MemoryStoreProvider {
+    root: String,
+    store: Arc<MemoryStore>,
+}
+
+impl MemoryStoreProvider {
+    pub fn new(root: &str, store: Arc<MemoryStore>) -> Self {
+        let root = match root.trim_end_matches('/') {
+            "" => "/".to_string(),
+            trimmed => trimmed.to_string(),
+        };
+        MemoryStoreProvider { root, store }
+    }
+
+    // Helper function to turn a client path into a path inside the memory store
+    fn relative(&self, path: &str) -> Result<String, String> {
+        let path = path.trim_end_matches('/');
+        let rest = if path == self.root || (path.is_empty() && self.root == "/") {
+            ""
+        } else {
+            let prefix = if self.root == "/" { "/".to_string() } else { format!("{}/", self.root) };
+            path.strip_prefix(&prefix).ok_or_else(|| format!("Path {} is outside the in-memory repository {}", path, self.root))?
+        };
+        check_relative(rest)?;
+        Ok(rest.to_string())
+    }
+
+    fn open(&self, dir: &str) -> MemoryRoot {
+        let display_root = if dir.is_empty() { self.root.clone() } else { join_path(&self.root, dir) };
+        MemoryRoot { store: self.store.clone(), dir: dir.to_string(), display_root }
+    }
+}
+
+impl StoreProvider for MemoryStoreProvider {
+    fn open_root(&self, root: &str) -> Result<Arc<dyn FileStore>, String> {
+        let dir = self.relative(root)?;
+        if !dir.is_empty() && self.store.list_dir(&dir).is_err() {
+            return Err("Provided path is not a directory".to_string());
+        }
+        Ok(Arc::new(self.open(&dir)))
+    }
+
+    fn open_file(&self, path: &str) -> Result<(Arc<dyn FileStore>, String), String> {
+        let file = self.relative(path)?;
+        if !self.store.exists(&file) {
+            return Err(format!("Invalid file path '{}': not found", path));
+        }
+        let (dir, name) = file.rsplit_once('/').unwrap_or(("", &file));
+        Ok((Arc::new(self.open(dir)), name.to_string()))
+    }
+}
+
+// A directory inside a shared MemoryStore, opened by MemoryStoreProvider
+struct MemoryRoot {
+    store: Arc<MemoryStore>,
+    dir: String,
+    display_root: String,
+}
+
+impl MemoryRoot {
+    fn full(&self, path: &str) -> String {
+        if path.is_empty() {
+            self.dir.clone()
+        } else {
+            join_path(&self.dir, path)
+        }
+    }
+}
+
+impl FileStore for MemoryRoot {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.store.read(&self.full(path))
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        check_relative(path).map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e))?;
+        self.store.write(&self.full(path), data)
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        self.store.remove(&self.full(path))
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.store.exists(&self.full(path))
+    }
+
+    fn list_dir(&self, path: &str) -> io::Result<Vec<DirEntry>> {
+        self.store.list_dir(&self.full(path))
+    }
+
+    fn display_path(&self, path: &str) -> String {
+        if path.is_empty() {
+            self.display_root.clone()
+        } else {
+            join_path(&self.display_root, path)
+        }
+    }
+}
+
+/// Restricts another provider to a set of allowed roots, e.g. the
+/// directories one user may see on a shared instance.
+pub struct ScopedStoreProvider {
+    inner: Arc<dyn StoreProvider>,
+    roots: Vec<PathBuf>,
+}
+
+impl ScopedStoreProvider {
+    /// `roots` are compared against [`FileStore::display_path`] of the opened
+    /// stores, so for local stores they should be canonicalized.
+    pub fn new(inner: Arc<dyn StoreProvider>, roots: Vec<PathBuf>) -> Self {
+        ScopedStoreProvider { inner, roots }
+    }
+
+    fn check(&self, path: &str) -> Result<(), String> {
+        if self.roots.iter().any(|root| Path::new(path).starts_with(root)) {
+            Ok(())
+        } else {
+            Err(format!("Access to {} is not allowed", path))
+        }
+    }
+}
+
+impl StoreProvider for ScopedStoreProvider {
+    fn open_root(&self, root: &str) -> Result<Arc<dyn FileStore>, String> {
+        let store = self.inner.open_root(root)?;
+        self.check(&store.display_path(""))?;
+        Ok(store)
+    }
+
+    fn open_file(&self, path: &str) -> Result<(Arc<dyn FileStore>, String), String> {
+        let (store, name) = self.inner.open_file(path)?;
+        self.check(&store.display_path(&name))?;
+        Ok((store, name))
+    }
+}
+
+/// Resolves `@alias` and `@alias/sub/path` against named workspace roots
+/// before handing paths to another provider, so clients can send paths
+/// that don't depend on where the repositories live on the server.
+pub struct AliasStoreProvider {
+    inner: Arc<dyn StoreProvider>,
+    aliases: BTreeMap<String, String>,
+}
+
+impl AliasStoreProvider {
+    /// Alias names may use letters, digits, `-`, `_` and `.`.
+    pub fn new(inner: Arc<dyn StoreProvider>, aliases: BTreeMap<String, String>) -> Result<Self, String> {
+        if let Some(name) = aliases.keys().find(|n| n.is_empty() || !n.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))) {
+            return Err(format!("Invalid workspace alias '{}': use letters, digits, '-', '_' and '.'", name));
+        }
+        Ok(AliasStoreProvider { inner, aliases })
+    }
+
+    /// The registered aliases and their roots.
+    pub fn aliases(&self) -> &BTreeMap<String, String> {
+        &self.aliases
+    }
+
+    /// Expands a leading `@alias`; other paths are returned unchanged.
+    pub fn resolve(&self, path: &str) -> Result<String, String> {
+        let Some(aliased) = path.strip_prefix('@') else {
+            return Ok(path.to_string());
+        };
+        let (name, rest) = aliased.split_once('/').unwrap_or((aliased, ""));
+        let root = self.aliases.get(name).ok_or_else(|| format!("Unknown workspace alias @{}", name))?;
+        let rest = rest.trim_end_matches('/');
+        if rest.is_empty() {
+            return Ok(root.clone());
+        }
+        check_relative(rest)?;
+        Ok(join_path(root, rest))
+    }
+}
+
+impl StoreProvider for AliasStoreProvider {
+    fn open_root(&self, root: &str) -> Result<Arc<dyn FileStore>, String> {
+        self.inner.open_root(&self.resolve(root)?)
+    }
+
+    fn open_file(&self, path: &str) -> Result<(Arc<dyn FileStore>, String), String> {
+        self.inner.open_file(&self.resolve(path)?)
+    }
+}
+
+/// What clients may do with hidden files, i.e. paths with a component
+/// starting with `.`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HiddenFiles {
+    /// Include hidden entries in directory listings.
+    pub list: bool,
+    /// Allow reading hidden files.
+    pub read: bool,
+    /// Allow patches to write or remove hidden files.
+    pub write: bool,
+    /// Allow writes inside version control folders such as `.git`, even
+    /// when `write` is set.
+    pub vcs_writes: bool,
+}
+
+impl Default for HiddenFiles {
+    fn default() -> Self {
+        HiddenFiles { list: true, read: true, write: true, vcs_writes: false }
+    }
+}
+
+fn is_hidden(path: &str) -> bool {
+    path.split('/').any(|c| c.starts_with('.') && c != "." && c != "..")
+}
+
+fn in_vcs_dir(path: &str) -> bool {
+    Path::new(path).components().any(|c| VCS_DIRS.iter().any(|d| c.as_os_str() == *d))
+}
+
+/// Globs for files that can never be written or removed through a store,
+/// whatever a patch says, plus the rules for hidden files. A pattern
+/// without a `/` matches at any depth.
+pub struct ProtectedPaths {
+    patterns: Vec<String>,
+    globs: GlobSet,
+    hidden: HiddenFiles,
+}
+
+impl ProtectedPaths {
+    pub const DEFAULT: &'static [&'static str] = &[".git/**", ".env", "*.pem"];
+
+    pub fn new(patterns: &[String]) -> Result<Self, String> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            let anchored = if pattern.contains('/') { pattern.trim_start_matches('/').to_string() } else { format!("**/{}", pattern) };
+            let glob = GlobBuilder::new(&anchored)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| format!("Invalid protected path {}: {}", pattern, e))?;
+            builder.add(glob);
+        }
+        let globs = builder.build().map_err(|e| e.to_string())?;
+        Ok(ProtectedPaths { patterns: patterns.to_vec(), globs, hidden: HiddenFiles::default() })
+    }
+
+    pub fn with_hidden(mut self, hidden: HiddenFiles) -> Self {
+        self.hidden = hidden;
+        self
+    }
+
+    /// Returns the pattern protecting `path`, if any.
+    pub fn matching(&self, path: &str) -> Option<&str> {
+        self.globs.matches(path).first().map(|&i| self.patterns[i].as_str())
+    }
+}
+
+/// Wraps a store so that protected paths can be read but not written or
+/// removed, and hidden files are listed, read and written only as allowed.
+pub struct ProtectedStore {
+    inner: Arc<dyn FileStore>,
+    protected: Arc<ProtectedPaths>,
+}
+
+impl ProtectedStore {
+    pub fn new(inner: Arc<dyn FileStore>, protected: Arc<ProtectedPaths>) -> Self {
+        ProtectedStore { inner, protected }
+    }
+}
+
+impl FileStore for ProtectedStore {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        if !self.protected.hidden.read && is_hidden(path) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("Hidden file {} is not readable", path)));
+        }
+        self.inner.read(path)
+    }
+
+    fn read_head(&self, path: &str, max: usize) -> io::Result<Vec<u8>> {
+        if !self.protected.hidden.read && is_hidden(path) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("Hidden file {} is not readable", path)));
+        }
+        self.inner.read_head(path, max)
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.check_writable(path).map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e))?;
+        self.inner.write(path, data)
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        self.check_writable(path).map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e))?;
+        self.inner.remove(path)
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        self.check_writable(path).map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e))?;
+        self.inner.remove_dir(path)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn list_dir(&self, path: &str) -> io::Result<Vec<DirEntry>> {
+        let mut entries = self.inner.list_dir(path)?;
+        if !self.protected.hidden.list {
+            entries.retain(|e| !is_hidden(&e.name));
+        }
+        Ok(entries)
+    }
+
+    fn display_path(&self, path: &str) -> String {
+        self.inner.display_path(path)
+    }
+
+    fn validate(&self, path: &str) -> Result<(), String> {
+        self.inner.validate(path)
+    }
+
+    fn check_writable(&self, path: &str) -> Result<(), String> {
+        if let Some(pattern) = self.protected.matching(path) {
+            return Err(format!("Path {} is protected by the pattern {} and cannot be changed", path, pattern));
+        }
+        let hidden = self.protected.hidden;
+        // The full path catches stores rooted inside a .git folder
+        if !hidden.vcs_writes && in_vcs_dir(&self.inner.display_path(path)) {
+            return Err(format!("Path {} is inside a version control folder and cannot be changed", path));
+        }
+        if !hidden.write && is_hidden(path) {
+            return Err(format!("Hidden file {} cannot be changed", path));
+        }
+        self.inner.check_writable(path)
+    }
+
+    fn file_size(&self, path: &str) -> Option<u64> {
+        self.inner.file_size(path)
+    }
+
+    fn modified(&self, path: &str) -> Option<SystemTime> {
+        self.inner.modified(path)
+    }
+
+    fn available_space(&self, path: &str) -> Option<u64> {
+        self.inner.available_space(path)
+    }
+
+    fn file_id(&self, path: &str) -> Option<(u64, u64)> {
+        self.inner.file_id(path)
+    }
+
+    fn local_path(&self, path: &str) -> Option<PathBuf> {
+        if !self.protected.hidden.read && is_hidden(path) {
+            return None;
+        }
+        self.inner.local_path(path)
+    }
+}
+
+/// Opens every store of another provider as a [`ProtectedStore`].
+pub struct ProtectedStoreProvider {
+    inner: Arc<dyn StoreProvider>,
+    protected: Arc<ProtectedPaths>,
+}
+
+impl ProtectedStoreProvider {
+    pub fn new(inner: Arc<dyn StoreProvider>, protected: ProtectedPaths) -> Self {
+        ProtectedStoreProvider { inner, protected: Arc::new(protected) }
+    }
+}
+
+impl StoreProvider for ProtectedStoreProvider {
+    fn open_root(&self, root: &str) -> Result<Arc<dyn FileStore>, String> {
+        let store = self.inner.open_root(root)?;
+        Ok(Arc::new(ProtectedStore::new(store, self.protected.clone())))
+    }
+
+    fn open_file(&self, path: &str) -> Result<(Arc<dyn FileStore>, String), String> {
+        let (store, name) = self.inner.open_file(path)?;
+        // The store is rooted at the file's folder, so check the whole path
+        if !self.protected.hidden.read && is_hidden(&store.display_path(&name)) {
+            return Err(format!("Hidden file {} is not readable", path));
+        }
+        Ok((Arc::new(ProtectedStore::new(store, self.protected.clone())), name))
+    }
+}
+
+/// A copy-on-write view of another store: reads fall through to the base
+/// store, writes and removals are kept in memory. Used for dry runs.
+pub struct OverlayStore<'a> {
+    base: &'a dyn FileStore,
+    // None marks a removed file
+    changes: Mutex<BTreeMap<String, Option<Vec<u8>>>>,
+}
+
+impl<'a> OverlayStore<'a> {
+    pub fn new(base: &'a dyn FileStore) -> Self {
+        OverlayStore { base, changes: Mutex::new(BTreeMap::new()) }
+    }
+
+    /// Starts the overlay with `files` in place of (or in addition to) the
+    /// base store's content.
+    pub fn with_files(base: &'a dyn FileStore, files: impl IntoIterator<Item = (String, Vec<u8>)>) -> Self {
+        let changes = files.into_iter().map(|(path, data)| (path, Some(data))).collect();
+        OverlayStore { base, changes: Mutex::new(changes) }
+    }
+
+    /// Returns the files written (`Some`) or removed (`None`) so far.
+    pub fn changes(&self) -> BTreeMap<String, Option<Vec<u8>>> {
+        self.lock().clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, Option<Vec<u8>>>> {
+        self.changes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl FileStore for OverlayStore<'_> {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        match self.lock().get(path) {
+            Some(Some(data)) => Ok(data.clone()),
+            Some(None) => Err(io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path))),
+            None => self.base.read(path),
+        }
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.base.validate(path).map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e))?;
+        self.base.check_writable(path).map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e))?;
+        self.lock().insert(path.to_string(), Some(data.to_vec()));
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        self.base.check_writable(path).map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e))?;
+        if !self.exists(path) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path)));
+        }
+        self.lock().insert(path.to_string(), None);
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        match self.lock().get(path) {
+            Some(change) => change.is_some(),
+            None => self.base.exists(path),
+        }
+    }
+
+    fn list_dir(&self, path: &str) -> io::Result<Vec<DirEntry>> {
+        let mut entries: BTreeMap<String, bool> = match self.base.list_dir(path) {
+            Ok(entries) => entries.into_iter().map(|e| (e.name, e.is_dir)).collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        let prefix = if path.is_empty() { String::new() } else { format!("{}/", path.trim_end_matches('/')) };
+        for (key, change) in self.lock().iter() {
+            let Some(rest) = key.strip_prefix(&prefix) else { continue };
+            match (rest.split_once('/'), change) {
+                (Some((dir, _)), Some(_)) => {
+                    entries.insert(dir.to_string(), true);
+                }
+                (None, Some(_)) => {
+                    entries.insert(rest.to_string(), false);
+                }
+                (None, None) => {
+                    entries.remove(rest);
+                }
+                (Some(_), None) => {}
+            }
+        }
+        Ok(entries.into_iter().map(|(name, is_dir)| DirEntry { name, is_dir, is_symlink: false }).collect())
+    }
+
+    fn display_path(&self, path: &str) -> String {
+        self.base.display_path(path)
+    }
+
+    fn validate(&self, path: &str) -> Result<(), String> {
+        self.base.validate(path)
+    }
+
+    fn check_writable(&self, path: &str) -> Result<(), String> {
+        self.base.check_writable(path)
+    }
+
+    fn file_size(&self, path: &str) -> Option<u64> {
+        match self.lock().get(path) {
+            Some(change) => change.as_ref().map(|data| data.len() as u64),
+            None => self.base.file_size(path),
+        }
+    }
+
+    fn available_space(&self, path: &str) -> Option<u64> {
+        self.base.available_space(path)
+    }
+
+    fn file_id(&self, path: &str) -> Option<(u64, u64)> {
+        self.base.file_id(path)
+    }
+}
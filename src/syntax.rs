@@ -0,0 +1,370 @@
+//! Tree-sitter parsing for the languages refactorings understand: Rust,
+//! TypeScript/JavaScript and Python.
+
+use std::ops::Range;
+use tree_sitter::{Node, Parser, Tree};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    Rust,
+    TypeScript,
+    Tsx,
+    Python,
+}
+
+impl Language {
+    /// Picks the grammar for a file from its extension. JavaScript is parsed
+    /// with the TypeScript grammars, which accept it.
+    pub fn from_path(path: &str) -> Option<Self> {
+        let extension = path.rsplit_once('.')?.1.to_lowercase();
+        match extension.as_str() {
+            "rs" => Some(Language::Rust),
+            "ts" | "mts" | "cts" | "js" | "mjs" | "cjs" => Some(Language::TypeScript),
+            "tsx" | "jsx" => Some(Language::Tsx),
+            "py" | "pyi" => Some(Language::Python),
+            _ => None,
+        }
+    }
+
+    /// True when symbols can be shared between files of the two languages.
+    pub fn same_family(self, other: Language) -> bool {
+        let family = |l: Language| match l {
+            Language::Tsx => Language::TypeScript,
+            l => l,
+        };
+        family(self) == family(other)
+    }
+
+    fn grammar(self) -> tree_sitter::Language {
+        match self {
+            Language::Rust => tree_sitter_rust::LANGUAGE.into(),
+            Language::TypeScript => tree_sitter_typescript::LANGUAGE_TYPESCRIPT.into(),
+            Language::Tsx => tree_sitter_typescript::LANGUAGE_TSX.into(),
+            Language::Python => tree_sitter_python::LANGUAGE.into(),
+        }
+    }
+
+    // Leaf node kinds that name something (as opposed to strings or comments)
+    fn identifier_kinds(self) -> &'static [&'static str] {
+        match self {
+            Language::Rust => &["identifier", "type_identifier", "field_identifier", "shorthand_field_identifier"],
+            Language::TypeScript | Language::Tsx => &[
+                "identifier",
+                "type_identifier",
+                "property_identifier",
+                "shorthand_property_identifier",
+                "shorthand_property_identifier_pattern",
+            ],
+            Language::Python => &["identifier"],
+        }
+    }
+
+    // Node kinds that open a scope for local bindings
+    fn function_kinds(self) -> &'static [&'static str] {
+        match self {
+            Language::Rust => &["function_item", "closure_expression"],
+            Language::TypeScript | Language::Tsx => {
+                &["function_declaration", "function_expression", "arrow_function", "method_definition", "generator_function_declaration"]
+            }
+            Language::Python => &["function_definition", "lambda"],
+        }
+    }
+}
+
+/// Parses `source`, failing only if the grammar can't be loaded. Syntax
+/// errors still produce a tree with error nodes.
+pub fn parse(language: Language, source: &str) -> Result<Tree, String> {
+    let mut parser = Parser::new();
+    parser.set_language(&language.grammar()).map_err(|e| format!("Failed to load {:?} grammar: {}", language, e))?;
+    parser.parse(source, None).ok_or_else(|| "Parsing was cancelled".to_string())
+}
+
+/// Returns the byte ranges of every identifier spelled `name`, in order.
+pub fn identifiers(language: Language, tree: &Tree, source: &str, name: &str) -> Vec<Range<usize>> {
+    let kinds = language.identifier_kinds();
+    let mut ranges = Vec::new();
+    visit(tree.root_node(), &mut |node| {
+        if node.child_count() == 0 && kinds.contains(&node.kind()) && &source[node.byte_range()] == name {
+            ranges.push(node.byte_range());
+        }
+    });
+    ranges
+}
+
+/// Returns the byte range of the innermost function or closure enclosing
+/// the first `name` identifier on `line` (1-based).
+pub fn enclosing_function(language: Language, tree: &Tree, source: &str, line: usize, name: &str) -> Option<Range<usize>> {
+    let occurrence = identifiers(language, tree, source, name)
+        .into_iter()
+        .find(|range| source[..range.start].matches('\n').count() + 1 == line)?;
+    let mut node = tree.root_node().descendant_for_byte_range(occurrence.start, occurrence.end)?;
+    while let Some(parent) = node.parent() {
+        if language.function_kinds().contains(&parent.kind()) {
+            return Some(parent.byte_range());
+        }
+        node = parent;
+    }
+    None
+}
+
+/// A module reference made by an import statement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Import {
+    /// Byte range of the module: the specifier inside the quotes for
+    /// TypeScript, the dotted (possibly relative) module path for Python.
+    pub module: Range<usize>,
+    /// Names after Python's `from module import`, which may be submodules.
+    pub names: Vec<Range<usize>>,
+}
+
+/// Returns the imports of a TypeScript/JavaScript file (`import`, `export
+/// ... from`, `require()` and `import()` with a string literal) or a Python
+/// file, in order. Rust has no path-based imports and yields none.
+pub fn imports(language: Language, tree: &Tree, source: &str) -> Vec<Import> {
+    let mut imports = Vec::new();
+    match language {
+        Language::TypeScript | Language::Tsx => visit(tree.root_node(), &mut |node| {
+            let string = match node.kind() {
+                "import_statement" | "export_statement" => node.child_by_field_name("source"),
+                "call_expression" => node
+                    .child_by_field_name("function")
+                    .filter(|f| f.kind() == "import" || (f.kind() == "identifier" && &source[f.byte_range()] == "require"))
+                    .and_then(|_| node.child_by_field_name("arguments"))
+                    .and_then(|args| args.named_child(0)),
+                _ => None,
+            };
+            if let Some(range) = string.filter(|s| s.kind() == "string").map(|s| s.byte_range()).filter(|r| r.len() >= 2) {
+                imports.push(Import { module: range.start + 1..range.end - 1, names: Vec::new() });
+            }
+        }),
+        Language::Python => visit(tree.root_node(), &mut |node| {
+            let mut cursor = node.walk();
+            match node.kind() {
+                "import_statement" => {
+                    for name in node.children_by_field_name("name", &mut cursor).filter_map(unaliased) {
+                        imports.push(Import { module: name.byte_range(), names: Vec::new() });
+                    }
+                }
+                "import_from_statement" => {
+                    if let Some(module) = node.child_by_field_name("module_name") {
+                        let names = node.children_by_field_name("name", &mut cursor).filter_map(unaliased).map(|n| n.byte_range()).collect();
+                        imports.push(Import { module: module.byte_range(), names });
+                    }
+                }
+                _ => {}
+            }
+        }),
+        Language::Rust => {}
+    }
+    imports
+}
+
+/// The module references of a Rust file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RustModules {
+    /// Names declared with `mod name;`, whose source is another file.
+    pub mods: Vec<String>,
+    /// Every path a `use` brings in, split at `::`, with `use a::{b, c}`
+    /// expanded to `a::b` and `a::c` and globs and `self` to the module.
+    pub uses: Vec<Vec<String>>,
+}
+
+/// Returns the `mod` declarations and `use` paths of a Rust file.
+pub fn rust_modules(tree: &Tree, source: &str) -> RustModules {
+    let mut modules = RustModules::default();
+    visit(tree.root_node(), &mut |node| match node.kind() {
+        "mod_item" if node.child_by_field_name("body").is_none() => {
+            if let Some(name) = node.child_by_field_name("name") {
+                modules.mods.push(source[name.byte_range()].to_string());
+            }
+        }
+        "use_declaration" => {
+            if let Some(argument) = node.child_by_field_name("argument") {
+                flatten_use(argument, source, &[], &mut modules.uses);
+            }
+        }
+        _ => {}
+    });
+    modules
+}
+
+// Expands one use tree below `prefix` into full paths
+fn flatten_use(node: Node<'_>, source: &str, prefix: &[String], out: &mut Vec<Vec<String>>) {
+    let with = |segments: Vec<String>| {
+        let mut path = prefix.to_vec();
+        path.extend(segments.into_iter().filter(|s| s != "self" || prefix.is_empty()));
+        path
+    };
+    match node.kind() {
+        "use_as_clause" | "use_wildcard" => {
+            let path = node.child_by_field_name("path").or_else(|| node.named_child(0));
+            if let Some(path) = path {
+                out.push(with(path_segments(path, source)));
+            }
+        }
+        "use_list" => {
+            let mut cursor = node.walk();
+            for child in node.named_children(&mut cursor) {
+                flatten_use(child, source, prefix, out);
+            }
+        }
+        "scoped_use_list" => {
+            let base = with(node.child_by_field_name("path").map(|p| path_segments(p, source)).unwrap_or_default());
+            if let Some(list) = node.child_by_field_name("list") {
+                flatten_use(list, source, &base, out);
+            }
+        }
+        _ => out.push(with(path_segments(node, source))),
+    }
+}
+
+// `a::b::c` as its segments
+fn path_segments(node: Node<'_>, source: &str) -> Vec<String> {
+    if node.kind() != "scoped_identifier" {
+        return vec![source[node.byte_range()].to_string()];
+    }
+    let mut segments = node.child_by_field_name("path").map(|p| path_segments(p, source)).unwrap_or_default();
+    if let Some(name) = node.child_by_field_name("name") {
+        segments.push(source[name.byte_range()].to_string());
+    }
+    segments
+}
+
+/// A named item a file defines: a function, type, class, method or similar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Definition {
+    pub name: String,
+    /// `function`, `method`, `struct`, `enum`, `trait`, `class`, ...
+    pub kind: &'static str,
+    /// The type, trait or class a method belongs to.
+    pub container: Option<String>,
+    /// Byte range including attached doc comments, attributes, decorators
+    /// and an `export` keyword.
+    pub range: Range<usize>,
+}
+
+// Maps a definition node kind to the kind reported for it
+fn definition_kind(language: Language, node: Node<'_>) -> Option<&'static str> {
+    let kind = match (language, node.kind()) {
+        (Language::Rust, "function_item" | "function_signature_item") => "function",
+        (Language::Rust, "struct_item") => "struct",
+        (Language::Rust, "enum_item") => "enum",
+        (Language::Rust, "union_item") => "union",
+        (Language::Rust, "trait_item") => "trait",
+        (Language::Rust, "type_item") => "type",
+        (Language::Rust, "const_item") => "const",
+        (Language::Rust, "static_item") => "static",
+        (Language::Rust, "macro_definition") => "macro",
+        (Language::Rust, "mod_item") => "module",
+        (Language::TypeScript | Language::Tsx, "function_declaration" | "generator_function_declaration") => "function",
+        (Language::TypeScript | Language::Tsx, "class_declaration" | "abstract_class_declaration") => "class",
+        (Language::TypeScript | Language::Tsx, "interface_declaration") => "interface",
+        (Language::TypeScript | Language::Tsx, "type_alias_declaration") => "type",
+        (Language::TypeScript | Language::Tsx, "enum_declaration") => "enum",
+        (Language::TypeScript | Language::Tsx, "method_definition" | "abstract_method_signature") => "method",
+        (Language::TypeScript | Language::Tsx, "variable_declarator") => {
+            let value = node.child_by_field_name("value")?;
+            if !matches!(value.kind(), "arrow_function" | "function_expression" | "function") {
+                return None;
+            }
+            "function"
+        }
+        (Language::Python, "function_definition") => "function",
+        (Language::Python, "class_definition") => "class",
+        _ => return None,
+    };
+    Some(kind)
+}
+
+// The name of the impl, trait or class that directly holds `node`
+fn container_name<'a>(node: Node<'a>, source: &'a str) -> Option<&'a str> {
+    let mut parent = node.parent();
+    while let Some(p) = parent {
+        let named = match p.kind() {
+            "impl_item" => p.child_by_field_name("type"),
+            "trait_item" | "class_declaration" | "abstract_class_declaration" | "class_definition" => p.child_by_field_name("name"),
+            "function_item" | "function_definition" | "function_declaration" | "arrow_function" | "method_definition" => return None,
+            _ => None,
+        };
+        if let Some(name) = named {
+            // `impl<T> Stack<T>` belongs to `Stack`
+            let name = if name.kind() == "generic_type" { name.child_by_field_name("type").unwrap_or(name) } else { name };
+            return Some(&source[name.byte_range()]);
+        }
+        parent = p.parent();
+    }
+    None
+}
+
+// Widens a definition over its `export`, `const x =` and decorator wrappers
+// and the comments and attributes directly above it
+fn attached_range(language: Language, node: Node<'_>, source: &str) -> Range<usize> {
+    let mut outer = node;
+    while let Some(parent) = outer.parent() {
+        let wraps = match parent.kind() {
+            "lexical_declaration" | "variable_declaration" => parent.named_child_count() == 1,
+            "export_statement" | "decorated_definition" => true,
+            _ => false,
+        };
+        if !wraps {
+            break;
+        }
+        outer = parent;
+    }
+    let attachments: &[&str] = match language {
+        Language::Rust => &["line_comment", "block_comment", "attribute_item"],
+        Language::TypeScript | Language::Tsx => &["comment", "decorator"],
+        Language::Python => &["comment"],
+    };
+    let mut start = outer.start_byte();
+    let mut previous = outer.prev_sibling();
+    while let Some(sibling) = previous.filter(|s| attachments.contains(&s.kind())) {
+        if source[sibling.end_byte()..start].matches('\n').count() > 1 {
+            break;
+        }
+        start = sibling.start_byte();
+        previous = sibling.prev_sibling();
+    }
+    start..outer.end_byte()
+}
+
+/// Returns the definitions of a file in source order, nested ones included.
+pub fn definitions(language: Language, tree: &Tree, source: &str) -> Vec<Definition> {
+    let mut definitions = Vec::new();
+    visit(tree.root_node(), &mut |node| {
+        let Some(mut kind) = definition_kind(language, node) else {
+            return;
+        };
+        let Some(name) = node.child_by_field_name("name") else {
+            return;
+        };
+        let container = container_name(node, source).map(str::to_string);
+        if kind == "function" && container.is_some() && node.kind() != "variable_declarator" {
+            kind = "method";
+        }
+        definitions.push(Definition {
+            name: source[name.byte_range()].to_string(),
+            kind,
+            container,
+            range: attached_range(language, node, source),
+        });
+    });
+    definitions
+}
+
+// The imported name of `import a.b as c`
+fn unaliased(node: Node<'_>) -> Option<Node<'_>> {
+    if node.kind() == "aliased_import" {
+        node.child_by_field_name("name")
+    } else {
+        Some(node)
+    }
+}
+
+fn visit<'a>(node: Node<'a>, f: &mut dyn FnMut(Node<'a>)) {
+    f(node);
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        visit(child, f);
+    }
+}
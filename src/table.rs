@@ -0,0 +1,837 @@
+//! Samples of CSV, TSV and Parquet files: their columns with a type for
+//! each, and their first rows, so a data file can be described in a prompt
+//! without its megabytes of rows.
+
+use chrono::{DateTime, NaiveDate, SecondsFormat};
+use flate2::read::GzDecoder;
+use serde::Serialize;
+use serde_json::Value;
+use std::io::Read;
+
+pub const DEFAULT_ROWS: usize = 100;
+pub const MAX_ROWS: usize = 1000;
+/// Files bigger than this aren't read.
+pub const MAX_TABLE_BYTES: u64 = 256 * 1024 * 1024;
+/// Longer cells are cut to this many characters.
+pub const MAX_CELL_CHARS: usize = 500;
+/// Parquet pages bigger than this once decompressed aren't read.
+const MAX_PAGE_BYTES: usize = 256 * 1024 * 1024;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Column {
+    pub name: String,
+    /// `string`, `integer`, `number`, `boolean`, `date`, `timestamp`,
+    /// `decimal`, `binary` or, for repeated Parquet columns, `list`.
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TablePreview {
+    /// `csv`, `tsv` or `parquet`.
+    pub format: &'static str,
+    pub columns: Vec<Column>,
+    /// The first rows, one value per column and `null` for empty cells.
+    pub rows: Vec<Vec<Value>>,
+    /// The rows in the whole file, not counting a header.
+    pub total_rows: u64,
+    /// Why the rows of a Parquet file couldn't be read when its columns
+    /// could, such as a compression this reader doesn't know.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rows_unavailable: Option<String>,
+}
+
+/// The table format of a file by its extension.
+pub fn table_format(path: &str) -> Option<&'static str> {
+    let extension = path.rsplit_once('.')?.1.to_ascii_lowercase();
+    match extension.as_str() {
+        "csv" => Some("csv"),
+        "tsv" | "tab" => Some("tsv"),
+        "parquet" | "pq" => Some("parquet"),
+        _ => None,
+    }
+}
+
+/// The columns and first `rows` rows of the table in `data`.
+pub fn preview(path: &str, data: &[u8], rows: usize) -> Result<TablePreview, String> {
+    match table_format(path) {
+        Some("parquet") => parquet(data, rows),
+        Some(format) => Ok(delimited(format, data, rows)),
+        None => Err(format!("{} isn't a CSV, TSV or Parquet file", path)),
+    }
+}
+
+fn cut(text: &str) -> Value {
+    match text.char_indices().nth(MAX_CELL_CHARS) {
+        Some((at, _)) => Value::from(format!("{}…", &text[..at])),
+        None => Value::from(text),
+    }
+}
+
+// CSV and TSV
+
+// Records of a delimited file; quotes are only special in CSV
+struct Records<'a> {
+    bytes: &'a [u8],
+    at: usize,
+    delimiter: u8,
+    quotes: bool,
+}
+
+impl Records<'_> {
+    // The next record that isn't a blank line, with its cells when `keep`
+    // and empty ones otherwise
+    fn next_record(&mut self, keep: bool) -> Option<Vec<String>> {
+        while self.at < self.bytes.len() {
+            let start = self.at;
+            let (mut cells, mut cell) = (Vec::new(), Vec::new());
+            let (mut quoted, mut fresh) = (false, true);
+            while let Some(&byte) = self.bytes.get(self.at) {
+                self.at += 1;
+                if quoted {
+                    if byte != b'"' {
+                        if keep {
+                            cell.push(byte);
+                        }
+                    } else if self.bytes.get(self.at) == Some(&b'"') {
+                        self.at += 1;
+                        cell.push(b'"');
+                    } else {
+                        quoted = false;
+                    }
+                    continue;
+                }
+                match byte {
+                    b'"' if self.quotes && fresh => quoted = true,
+                    b'\n' => break,
+                    b'\r' if self.bytes.get(self.at) == Some(&b'\n') => {}
+                    _ if byte == self.delimiter => {
+                        cells.push(std::mem::take(&mut cell));
+                        fresh = true;
+                        continue;
+                    }
+                    _ if keep => cell.push(byte),
+                    _ => {}
+                }
+                fresh = false;
+            }
+            if self.bytes[start..self.at].iter().all(|b| matches!(b, b'\r' | b'\n')) {
+                continue;
+            }
+            cells.push(cell);
+            return Some(cells.into_iter().map(|c| String::from_utf8_lossy(&c).into_owned()).collect());
+        }
+        None
+    }
+}
+
+// Numbers with leading zeros, like ZIP codes and IDs, stay strings
+fn leading_zero(cell: &str) -> bool {
+    let digits = cell.strip_prefix(['-', '+']).unwrap_or(cell).as_bytes();
+    digits.len() > 1 && digits[0] == b'0' && digits[1].is_ascii_digit()
+}
+
+fn is_number(cell: &str) -> bool {
+    cell.bytes().all(|b| b.is_ascii_digit() || matches!(b, b'.' | b'-' | b'+' | b'e' | b'E')) && cell.parse::<f64>().is_ok_and(f64::is_finite)
+}
+
+// The type every non-empty cell of a column fits
+fn cell_type<'a>(cells: impl Iterator<Item = &'a str> + Clone) -> &'static str {
+    let mut cells = cells.filter(|c| !c.is_empty()).peekable();
+    if cells.peek().is_none() {
+        return "string";
+    }
+    if cells.clone().all(|c| c.parse::<i64>().is_ok() && !leading_zero(c)) {
+        "integer"
+    } else if cells.clone().all(|c| is_number(c) && !leading_zero(c)) {
+        "number"
+    } else if cells.all(|c| c.eq_ignore_ascii_case("true") || c.eq_ignore_ascii_case("false")) {
+        "boolean"
+    } else {
+        "string"
+    }
+}
+
+fn cell_value(cell: &str, kind: &str) -> Value {
+    if cell.is_empty() {
+        return Value::Null;
+    }
+    match kind {
+        "integer" => cell.parse::<i64>().map(Value::from).unwrap_or_else(|_| cut(cell)),
+        "number" => cell.parse::<f64>().map(Value::from).unwrap_or_else(|_| cut(cell)),
+        "boolean" => Value::from(cell.eq_ignore_ascii_case("true")),
+        _ => cut(cell),
+    }
+}
+
+// The first record is the header; cells past its columns are dropped and
+// short records padded
+fn delimited(format: &'static str, data: &[u8], rows: usize) -> TablePreview {
+    let bytes = data.strip_prefix(b"\xef\xbb\xbf").unwrap_or(data);
+    let tsv = format == "tsv";
+    let mut records = Records { bytes, at: 0, delimiter: if tsv { b'\t' } else { b',' }, quotes: !tsv };
+    let header = records.next_record(true).unwrap_or_default();
+    let mut sample: Vec<Vec<String>> = Vec::new();
+    while sample.len() < rows {
+        match records.next_record(true) {
+            Some(record) => sample.push(record),
+            None => break,
+        }
+    }
+    let mut total_rows = sample.len() as u64;
+    while records.next_record(false).is_some() {
+        total_rows += 1;
+    }
+
+    let columns: Vec<Column> = header
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let name = if name.trim().is_empty() { format!("column {}", i + 1) } else { name.trim().to_string() };
+            Column { name, kind: cell_type(sample.iter().map(move |r| r.get(i).map_or("", String::as_str))) }
+        })
+        .collect();
+    let rows = sample
+        .iter()
+        .map(|record| columns.iter().enumerate().map(|(i, column)| cell_value(record.get(i).map_or("", String::as_str), column.kind)).collect())
+        .collect();
+    TablePreview { format, columns, rows, total_rows, rows_unavailable: None }
+}
+
+// Parquet: the schema and row count come from the footer's Thrift
+// metadata, and rows are read from the first pages of each column chunk.
+
+struct Bytes<'a> {
+    data: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Bytes<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Bytes { data, at: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self.data.get(self.at..self.at.checked_add(len).ok_or("Length overflow")?).ok_or("Unexpected end of data")?;
+        self.at += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn le(&mut self, len: usize) -> Result<u64, String> {
+        Ok(self.take(len)?.iter().rev().fold(0, |value, &b| (value << 8) | b as u64))
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("Varint too long".to_string())
+    }
+
+    fn zigzag(&mut self) -> Result<i64, String> {
+        let value = self.varint()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.at
+    }
+}
+
+// A value in Thrift's compact protocol
+#[derive(Debug)]
+enum Thrift<'a> {
+    Int(i64),
+    Bool(bool),
+    Double,
+    Binary(&'a [u8]),
+    List(Vec<Thrift<'a>>),
+    Struct(Fields<'a>),
+    Map,
+}
+
+#[derive(Debug, Default)]
+struct Fields<'a>(Vec<(i16, Thrift<'a>)>);
+
+impl<'a> Fields<'a> {
+    fn get(&self, id: i16) -> Option<&Thrift<'a>> {
+        self.0.iter().find(|(i, _)| *i == id).map(|(_, v)| v)
+    }
+
+    fn int(&self, id: i16) -> Option<i64> {
+        match self.get(id)? {
+            Thrift::Int(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    fn bool(&self, id: i16) -> Option<bool> {
+        match self.get(id)? {
+            Thrift::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    fn binary(&self, id: i16) -> Option<&'a [u8]> {
+        match self.get(id)? {
+            Thrift::Binary(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn list(&self, id: i16) -> &[Thrift<'a>] {
+        match self.get(id) {
+            Some(Thrift::List(values)) => values,
+            _ => &[],
+        }
+    }
+
+    fn child(&self, id: i16) -> Option<&Fields<'a>> {
+        self.get(id)?.fields()
+    }
+}
+
+impl<'a> Thrift<'a> {
+    fn fields(&self) -> Option<&Fields<'a>> {
+        match self {
+            Thrift::Struct(fields) => Some(fields),
+            _ => None,
+        }
+    }
+
+    fn read(bytes: &mut Bytes<'a>, kind: u8, depth: usize) -> Result<Self, String> {
+        if depth > 32 {
+            return Err("Metadata nested too deeply".to_string());
+        }
+        Ok(match kind {
+            1 => Thrift::Bool(true),
+            2 => Thrift::Bool(false),
+            3 => Thrift::Int(bytes.byte()? as i8 as i64),
+            4..=6 => Thrift::Int(bytes.zigzag()?),
+            7 => {
+                bytes.take(8)?;
+                Thrift::Double
+            }
+            8 => {
+                let len = bytes.varint()? as usize;
+                Thrift::Binary(bytes.take(len)?)
+            }
+            9 | 10 => {
+                let header = bytes.byte()?;
+                let size = match header >> 4 {
+                    15 => bytes.varint()? as usize,
+                    size => size as usize,
+                };
+                let element = header & 0x0f;
+                // Every element takes at least a byte
+                if size > bytes.remaining() {
+                    return Err("List longer than its data".to_string());
+                }
+                let mut values = Vec::with_capacity(size);
+                for _ in 0..size {
+                    values.push(match element {
+                        1 | 2 => Thrift::Bool(bytes.byte()? == 1),
+                        _ => Thrift::read(bytes, element, depth + 1)?,
+                    });
+                }
+                Thrift::List(values)
+            }
+            11 => {
+                let size = bytes.varint()? as usize;
+                if size > 0 {
+                    let types = bytes.byte()?;
+                    for _ in 0..size {
+                        Thrift::read(bytes, types >> 4, depth + 1)?;
+                        Thrift::read(bytes, types & 0x0f, depth + 1)?;
+                    }
+                }
+                Thrift::Map
+            }
+            12 => Thrift::Struct(Thrift::structure(bytes, depth + 1)?),
+            other => return Err(format!("Unknown Thrift type {}", other)),
+        })
+    }
+
+    fn structure(bytes: &mut Bytes<'a>, depth: usize) -> Result<Fields<'a>, String> {
+        let (mut fields, mut last) = (Vec::new(), 0i16);
+        loop {
+            let header = bytes.byte()?;
+            if header == 0 {
+                return Ok(Fields(fields));
+            }
+            let id = match header >> 4 {
+                0 => bytes.zigzag()? as i16,
+                delta => last.wrapping_add(delta as i16),
+            };
+            last = id;
+            fields.push((id, Thrift::read(bytes, header & 0x0f, depth)?));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Logical {
+    None,
+    String,
+    Date,
+    /// Units in a second.
+    Timestamp(i64),
+    Decimal(u32),
+}
+
+// A leaf column of the schema
+struct Leaf {
+    name: String,
+    physical: i64,
+    length: usize,
+    logical: Logical,
+    max_definition: u32,
+    repeated: bool,
+}
+
+impl Leaf {
+    fn kind(&self) -> &'static str {
+        if self.repeated {
+            return "list";
+        }
+        match (self.physical, self.logical) {
+            (_, Logical::Date) => "date",
+            (3, _) | (_, Logical::Timestamp(_)) => "timestamp",
+            (_, Logical::Decimal(_)) => "decimal",
+            (0, _) => "boolean",
+            (1 | 2, _) => "integer",
+            (4 | 5, _) => "number",
+            (_, Logical::String) => "string",
+            _ => "binary",
+        }
+    }
+
+    fn integer(&self, value: i64) -> Value {
+        match self.logical {
+            Logical::Date => NaiveDate::from_num_days_from_ce_opt((value + 719_163) as i32).map_or(Value::Null, |d| Value::from(d.to_string())),
+            Logical::Timestamp(units) => timestamp(value.div_euclid(units), (value.rem_euclid(units) * (1_000_000_000 / units)) as u32),
+            Logical::Decimal(scale) => Value::from(decimal(value as i128, scale)),
+            _ => Value::from(value),
+        }
+    }
+
+    fn bytes(&self, value: &[u8]) -> Value {
+        match self.logical {
+            Logical::Decimal(scale) if !value.is_empty() && value.len() <= 16 => {
+                let unsigned = value.iter().fold(0u128, |v, &b| (v << 8) | b as u128);
+                let shift = 128 - 8 * value.len() as u32;
+                Value::from(decimal(((unsigned << shift) as i128) >> shift, scale))
+            }
+            _ => match std::str::from_utf8(value) {
+                Ok(text) => cut(text),
+                Err(_) if value.len() > 32 => Value::from(format!("0x{}… ({} bytes)", hex(&value[..32]), value.len())),
+                Err(_) => Value::from(format!("0x{}", hex(value))),
+            },
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn timestamp(seconds: i64, nanos: u32) -> Value {
+    DateTime::from_timestamp(seconds, nanos).map_or(Value::Null, |t| Value::from(t.to_rfc3339_opts(SecondsFormat::AutoSi, true)))
+}
+
+fn decimal(value: i128, scale: u32) -> String {
+    let digits = format!("{:0>width$}", value.unsigned_abs(), width = scale as usize + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - scale as usize);
+    let sign = if value < 0 { "-" } else { "" };
+    if fraction.is_empty() { format!("{}{}", sign, whole) } else { format!("{}{}.{}", sign, whole, fraction) }
+}
+
+fn logical(element: &Fields) -> Logical {
+    let scale = element.int(7).unwrap_or(0).clamp(0, 38) as u32;
+    if let Some((id, inner)) = element.child(10).and_then(|l| l.0.first()) {
+        let inner = inner.fields();
+        match id {
+            1 | 4 | 12 => return Logical::String,
+            5 => return Logical::Decimal(inner.and_then(|d| d.int(1)).map_or(scale, |s| s.clamp(0, 38) as u32)),
+            6 => return Logical::Date,
+            8 => {
+                let unit = inner.and_then(|t| t.child(2)).and_then(|u| u.0.first()).map(|(id, _)| *id);
+                return Logical::Timestamp(match unit {
+                    Some(1) => 1_000,
+                    Some(3) => 1_000_000_000,
+                    _ => 1_000_000,
+                });
+            }
+            _ => {}
+        }
+    }
+    match element.int(6) {
+        Some(0 | 4 | 19) => Logical::String,
+        Some(5) => Logical::Decimal(scale),
+        Some(6) => Logical::Date,
+        Some(9) => Logical::Timestamp(1_000),
+        Some(10) => Logical::Timestamp(1_000_000),
+        _ => Logical::None,
+    }
+}
+
+// The leaf columns of the schema below element `at`, named by their path
+fn walk(schema: &[Thrift], at: &mut usize, prefix: &str, levels: (u32, bool), depth: usize, leaves: &mut Vec<Leaf>) -> Result<(), String> {
+    let element = schema.get(*at).and_then(Thrift::fields).ok_or("The schema is shorter than it says")?;
+    if depth > 64 {
+        return Err("The schema is nested too deeply".to_string());
+    }
+    *at += 1;
+    let name = String::from_utf8_lossy(element.binary(4).unwrap_or_default());
+    let name = if prefix.is_empty() { name.into_owned() } else { format!("{}.{}", prefix, name) };
+    let repetition = element.int(3).unwrap_or(0);
+    let levels = (levels.0 + (repetition != 0) as u32, levels.1 || repetition == 2);
+    match element.int(5).filter(|&n| n > 0) {
+        Some(children) => {
+            for _ in 0..children {
+                walk(schema, at, &name, levels, depth + 1, leaves)?;
+            }
+        }
+        None => leaves.push(Leaf {
+            name,
+            physical: element.int(1).unwrap_or(6),
+            length: element.int(2).unwrap_or(0).max(0) as usize,
+            logical: logical(element),
+            max_definition: levels.0,
+            repeated: levels.1,
+        }),
+    }
+    Ok(())
+}
+
+fn parquet(data: &[u8], rows: usize) -> Result<TablePreview, String> {
+    if data.ends_with(b"PARE") {
+        return Err("Encrypted Parquet files can't be read".to_string());
+    }
+    if data.len() < 12 || !data.starts_with(b"PAR1") || !data.ends_with(b"PAR1") {
+        return Err("Not a Parquet file".to_string());
+    }
+    let end = data.len() - 8;
+    let len = u32::from_le_bytes([data[end], data[end + 1], data[end + 2], data[end + 3]]) as usize;
+    let footer = data.get(end.checked_sub(len).ok_or("The Parquet footer is corrupt")?..end).ok_or("The Parquet footer is corrupt")?;
+    let metadata = Thrift::structure(&mut Bytes::new(footer), 0).map_err(|e| format!("The Parquet footer is corrupt: {}", e))?;
+    if metadata.get(8).is_some() {
+        return Err("Encrypted Parquet files can't be read".to_string());
+    }
+    let schema = metadata.list(2);
+    let root = schema.first().and_then(Thrift::fields).ok_or("The Parquet file has no schema")?;
+    let mut leaves = Vec::new();
+    let mut at = 1;
+    for _ in 0..root.int(5).unwrap_or(0) {
+        walk(schema, &mut at, "", (0, false), 0, &mut leaves)?;
+    }
+    let columns = leaves.iter().map(|l| Column { name: l.name.clone(), kind: l.kind() }).collect();
+    let total_rows = metadata.int(3).unwrap_or(0).max(0) as u64;
+    let (rows, rows_unavailable) = match sample(data, &leaves, metadata.list(4), rows) {
+        Ok(rows) => (rows, None),
+        Err(e) => (Vec::new(), Some(e)),
+    };
+    Ok(TablePreview { format: "parquet", columns, rows, total_rows, rows_unavailable })
+}
+
+// The first `rows` rows across the row groups, leaving repeated columns null
+fn sample(data: &[u8], leaves: &[Leaf], row_groups: &[Thrift], rows: usize) -> Result<Vec<Vec<Value>>, String> {
+    let mut columns: Vec<Vec<Value>> = leaves.iter().map(|_| Vec::new()).collect();
+    let mut collected = 0;
+    for group in row_groups.iter().filter_map(Thrift::fields) {
+        let wanted = (rows - collected).min(group.int(3).unwrap_or(0).max(0) as usize);
+        if wanted == 0 {
+            if collected == rows {
+                break;
+            }
+            continue;
+        }
+        let chunks = group.list(1);
+        for (i, leaf) in leaves.iter().enumerate() {
+            if leaf.repeated {
+                columns[i].resize(collected + wanted, Value::Null);
+                continue;
+            }
+            let chunk = chunks.get(i).and_then(Thrift::fields).ok_or("A row group is missing a column")?;
+            if chunk.binary(1).is_some() {
+                return Err("Columns stored in other files can't be read".to_string());
+            }
+            let meta = chunk.child(3).ok_or("A column chunk has no metadata")?;
+            let mut values = read_chunk(data, meta, leaf, wanted).map_err(|e| format!("Column {}: {}", leaf.name, e))?;
+            values.resize(wanted, Value::Null);
+            columns[i].extend(values);
+        }
+        collected += wanted;
+    }
+    Ok((0..collected).map(|row| columns.iter().map(|c| c[row].clone()).collect()).collect())
+}
+
+fn codec_name(codec: i64) -> String {
+    match codec {
+        3 => "LZO".to_string(),
+        4 => "Brotli".to_string(),
+        5 => "LZ4".to_string(),
+        other => format!("codec {}", other),
+    }
+}
+
+fn decompress(codec: i64, data: &[u8], size: usize) -> Result<Vec<u8>, String> {
+    if size > MAX_PAGE_BYTES {
+        return Err(format!("A page is {} MiB, over the {} MiB read", size / 1024 / 1024, MAX_PAGE_BYTES / 1024 / 1024));
+    }
+    match codec {
+        0 => Ok(data.to_vec()),
+        1 => snappy(data),
+        2 => {
+            let mut page = Vec::with_capacity(size);
+            GzDecoder::new(data).take(MAX_PAGE_BYTES as u64).read_to_end(&mut page).map_err(|e| format!("A gzipped page is corrupt: {}", e))?;
+            Ok(page)
+        }
+        6 => zstd::bulk::decompress(data, size).map_err(|e| format!("A Zstandard page is corrupt: {}", e)),
+        7 => lz4(data, size),
+        other => Err(format!("{}-compressed pages can't be read", codec_name(other))),
+    }
+}
+
+fn snappy(data: &[u8]) -> Result<Vec<u8>, String> {
+    let corrupt = || "A Snappy page is corrupt".to_string();
+    let mut bytes = Bytes::new(data);
+    let len = bytes.varint()? as usize;
+    if len > MAX_PAGE_BYTES {
+        return Err(corrupt());
+    }
+    let mut out = Vec::with_capacity(len);
+    while bytes.remaining() > 0 {
+        let tag = bytes.byte()?;
+        let (length, offset) = match tag & 3 {
+            0 => {
+                let length = match (tag >> 2) as usize {
+                    short @ 0..=59 => short,
+                    long => bytes.le(long - 59)? as usize,
+                };
+                out.extend_from_slice(bytes.take(length + 1)?);
+                continue;
+            }
+            1 => (((tag >> 2) & 7) as usize + 4, ((tag as usize >> 5) << 8) | bytes.byte()? as usize),
+            2 => ((tag >> 2) as usize + 1, bytes.le(2)? as usize),
+            _ => ((tag >> 2) as usize + 1, bytes.le(4)? as usize),
+        };
+        copy_match(&mut out, offset, length).ok_or_else(corrupt)?;
+        if out.len() > len {
+            return Err(corrupt());
+        }
+    }
+    if out.len() != len {
+        return Err(corrupt());
+    }
+    Ok(out)
+}
+
+fn lz4(data: &[u8], size: usize) -> Result<Vec<u8>, String> {
+    let corrupt = || "An LZ4 page is corrupt".to_string();
+    let mut bytes = Bytes::new(data);
+    let mut out = Vec::with_capacity(size);
+    while bytes.remaining() > 0 {
+        let token = bytes.byte()?;
+        let literals = lz4_length(&mut bytes, (token >> 4) as usize)?;
+        out.extend_from_slice(bytes.take(literals)?);
+        if bytes.remaining() == 0 {
+            break;
+        }
+        let offset = bytes.le(2)? as usize;
+        let matched = lz4_length(&mut bytes, (token & 0x0f) as usize)? + 4;
+        copy_match(&mut out, offset, matched).ok_or_else(corrupt)?;
+        if out.len() > size.max(MAX_PAGE_BYTES) {
+            return Err(corrupt());
+        }
+    }
+    Ok(out)
+}
+
+// Lengths of 15 go on in the following bytes
+fn lz4_length(bytes: &mut Bytes, mut length: usize) -> Result<usize, String> {
+    if length == 15 {
+        loop {
+            let byte = bytes.byte()?;
+            length += byte as usize;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Ok(length)
+}
+
+fn copy_match(out: &mut Vec<u8>, offset: usize, length: usize) -> Option<()> {
+    if offset == 0 || offset > out.len() {
+        return None;
+    }
+    for _ in 0..length {
+        out.push(out[out.len() - offset]);
+    }
+    Some(())
+}
+
+// `count` values of the RLE and bit-packed hybrid encoding
+fn hybrid(data: &[u8], width: u32, count: usize) -> Result<Vec<u32>, String> {
+    if width > 32 {
+        return Err(format!("Bit width {} is over 32", width));
+    }
+    let corrupt = || "A run of levels or indices is corrupt".to_string();
+    let mut bytes = Bytes::new(data);
+    // Runs can repeat a value any number of times, so only `count` bounds them
+    let mut values = Vec::with_capacity(count.min(data.len().saturating_mul(8)));
+    while values.len() < count {
+        let header = bytes.varint()?;
+        if header & 1 == 1 {
+            let groups = usize::try_from(header >> 1).map_err(|_| corrupt())?;
+            let packed_len = groups.checked_mul(width as usize).ok_or_else(corrupt)?;
+            let packed = bytes.take(packed_len.min(bytes.remaining()))?;
+            let packed_values = groups.checked_mul(8).ok_or_else(corrupt)?;
+            for i in 0..packed_values.min(count - values.len()) {
+                let mut value = 0u32;
+                for bit in 0..width as usize {
+                    let at = i * width as usize + bit;
+                    if packed.get(at / 8).is_some_and(|b| b >> (at % 8) & 1 == 1) {
+                        value |= 1 << bit;
+                    }
+                }
+                values.push(value);
+            }
+        } else {
+            let run = usize::try_from(header >> 1).unwrap_or(usize::MAX).min(count - values.len());
+            let value = bytes.le(width.div_ceil(8) as usize)? as u32;
+            values.extend(std::iter::repeat_n(value, run));
+        }
+    }
+    Ok(values)
+}
+
+// `count` plainly encoded values
+fn plain(data: &[u8], leaf: &Leaf, count: usize) -> Result<Vec<Value>, String> {
+    let mut bytes = Bytes::new(data);
+    // Every value takes at least a bit, whatever the header claims
+    let count = count.min(data.len().saturating_mul(8));
+    let mut values = Vec::with_capacity(count);
+    for i in 0..count {
+        values.push(match leaf.physical {
+            0 => Value::from(data.get(i / 8).ok_or("Unexpected end of data")? >> (i % 8) & 1 == 1),
+            1 => leaf.integer(bytes.le(4)? as u32 as i32 as i64),
+            2 => leaf.integer(bytes.le(8)? as i64),
+            3 => {
+                let nanos = bytes.le(8)? as i64;
+                let day = bytes.le(4)? as i64 - 2_440_588;
+                timestamp(day * 86_400 + nanos.div_euclid(1_000_000_000), nanos.rem_euclid(1_000_000_000) as u32)
+            }
+            4 => serde_json::Number::from_f64(f32::from_bits(bytes.le(4)? as u32) as f64).map_or(Value::Null, Value::Number),
+            5 => serde_json::Number::from_f64(f64::from_bits(bytes.le(8)?)).map_or(Value::Null, Value::Number),
+            6 => {
+                let len = bytes.le(4)? as usize;
+                leaf.bytes(bytes.take(len)?)
+            }
+            _ => leaf.bytes(bytes.take(leaf.length)?),
+        });
+    }
+    Ok(values)
+}
+
+fn encoding_name(encoding: i64) -> String {
+    match encoding {
+        5 => "DELTA_BINARY_PACKED".to_string(),
+        6 => "DELTA_LENGTH_BYTE_ARRAY".to_string(),
+        7 => "DELTA_BYTE_ARRAY".to_string(),
+        9 => "BYTE_STREAM_SPLIT".to_string(),
+        other => format!("encoding {}", other),
+    }
+}
+
+// The values of a data page, with nulls where definition levels say so
+fn page_values(values: &[u8], encoding: i64, definitions: Option<Vec<u32>>, count: usize, leaf: &Leaf, dictionary: Option<&[Value]>) -> Result<Vec<Value>, String> {
+    let present = definitions.as_ref().map_or(count, |d| d.iter().filter(|&&l| l == leaf.max_definition).count());
+    let decoded = match encoding {
+        0 => plain(values, leaf, present)?,
+        2 | 8 => {
+            let dictionary = dictionary.ok_or("A dictionary-encoded page has no dictionary")?;
+            let (&width, indices) = values.split_first().ok_or("Unexpected end of data")?;
+            hybrid(indices, width as u32, present)?
+                .into_iter()
+                .map(|i| dictionary.get(i as usize).cloned().ok_or_else(|| "A dictionary index is out of range".to_string()))
+                .collect::<Result<_, _>>()?
+        }
+        other => return Err(format!("{} pages can't be read", encoding_name(other))),
+    };
+    let Some(definitions) = definitions else {
+        return Ok(decoded);
+    };
+    let mut decoded = decoded.into_iter();
+    Ok(definitions.iter().map(|&l| if l == leaf.max_definition { decoded.next().unwrap_or(Value::Null) } else { Value::Null }).collect())
+}
+
+// Values of a data page to decode: its header's count, which is not
+// trusted, up to the rows still wanted
+fn page_count(page: &Fields, wanted: usize) -> usize {
+    usize::try_from(page.int(1).unwrap_or(0).max(0)).unwrap_or(usize::MAX).min(wanted)
+}
+
+// The first `rows` values of a column chunk
+fn read_chunk(data: &[u8], meta: &Fields, leaf: &Leaf, rows: usize) -> Result<Vec<Value>, String> {
+    let codec = meta.int(4).unwrap_or(0);
+    let data_page = meta.int(9).ok_or("No data page offset")?;
+    let start = meta.int(11).filter(|&o| o > 0 && o < data_page).unwrap_or(data_page).max(0) as usize;
+    let end = start.saturating_add(meta.int(7).unwrap_or(0).max(0) as usize).min(data.len());
+    let width = 32 - leaf.max_definition.leading_zeros();
+    let (mut at, mut dictionary, mut values) = (start, None, Vec::new());
+    while values.len() < rows && at < end {
+        let mut bytes = Bytes::new(&data[at..end]);
+        let header = Thrift::structure(&mut bytes, 0)?;
+        let size = header.int(3).unwrap_or(0).max(0) as usize;
+        let uncompressed = header.int(2).unwrap_or(0).max(0) as usize;
+        let body = bytes.take(size)?;
+        at += bytes.at;
+        match header.int(1) {
+            Some(2) => {
+                let count = header.child(7).and_then(|h| h.int(1)).unwrap_or(0).max(0) as usize;
+                dictionary = Some(plain(&decompress(codec, body, uncompressed)?, leaf, count)?);
+            }
+            Some(0) => {
+                let page = header.child(5).ok_or("A data page has no header")?;
+                let count = page_count(page, rows - values.len());
+                let page_data = decompress(codec, body, uncompressed)?;
+                let (definitions, rest) = if leaf.max_definition > 0 {
+                    let mut levels = Bytes::new(&page_data);
+                    let len = levels.le(4)? as usize;
+                    (Some(hybrid(levels.take(len)?, width, count)?), &page_data[4 + len..])
+                } else {
+                    (None, &page_data[..])
+                };
+                values.extend(page_values(rest, page.int(2).unwrap_or(0), definitions, count, leaf, dictionary.as_deref())?);
+            }
+            Some(3) => {
+                let page = header.child(8).ok_or("A data page has no header")?;
+                let count = page_count(page, rows - values.len());
+                let levels_len = page.int(6).unwrap_or(0).max(0) as usize;
+                let definitions_len = page.int(5).unwrap_or(0).max(0) as usize;
+                let mut levels = Bytes::new(body);
+                levels.take(levels_len)?;
+                let definitions = levels.take(definitions_len)?;
+                let rest = &body[levels.at..];
+                let rest = if page.bool(7).unwrap_or(true) { decompress(codec, rest, uncompressed.saturating_sub(levels.at))? } else { rest.to_vec() };
+                let definitions = (leaf.max_definition > 0).then(|| hybrid(definitions, width, count)).transpose()?;
+                values.extend(page_values(&rest, page.int(4).unwrap_or(0), definitions, count, leaf, dictionary.as_deref())?);
+            }
+            _ => {}
+        }
+    }
+    values.truncate(rows);
+    Ok(values)
+}
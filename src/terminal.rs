@@ -0,0 +1,381 @@
+// Embedded terminal: GET /api/terminal?path=<root> upgrades to a WebSocket
+// attached to a shell running in a PTY in that workspace, for quick git or
+// test runs next to the patches. It is off unless TERMINAL is set, and needs
+// a signed-in user (USERS_FILE or OIDC) since it gives whoever uses it a
+// shell as the server's user, outside the workspace roots too.
+//
+//   TERMINAL            `admin` (admins only) or `users` (every user)
+//   TERMINAL_SHELL      shell to run (default $SHELL, else /bin/sh)
+//   TERMINAL_IDLE_SECS  closes terminals without input or output for this
+//                       long (default 900)
+//
+// Binary frames carry raw terminal input and output. Clients may also send
+// JSON text frames:
+//   {"type": "input",  "data": "ls\n"}
+//   {"type": "resize", "cols": 120, "rows": 40}
+// When the shell exits (or the terminal idles out) the server sends
+// {"type": "exit", "code": 0} or {"type": "exit", "code": null, "reason": "idle"}
+// and closes the socket. Closing the socket hangs up the shell.
+//
+// CORS doesn't cover WebSockets, so upgrades from a browser must come from
+// this server's own origin or one in ALLOWED_ORIGINS; otherwise any page
+// could open a shell with the session cookie of whoever visits it.
+
+use crate::auth::{Session, Stores};
+use crate::csrf::origin_allowed;
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{self, Codec, Frame, Message};
+use actix_web::http::header;
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::{get, rt, web, HttpRequest, HttpResponse};
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::json;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+const MAX_FRAME_BYTES: usize = 64 * 1024;
+const DEFAULT_IDLE_SECS: u64 = 900;
+const DEFAULT_COLS: u16 = 80;
+const DEFAULT_ROWS: u16 = 24;
+
+pub struct TerminalConfig {
+    admins_only: bool,
+    shell: String,
+    idle: Duration,
+    origins: Vec<String>,
+}
+
+impl TerminalConfig {
+    /// The terminal settings, or `None` when TERMINAL leaves it off. Pages
+    /// of `origins` may open terminals besides the server's own.
+    pub fn from_env(origins: &[String]) -> Result<Option<Self>, String> {
+        let admins_only = match env::var("TERMINAL").unwrap_or_default().as_str() {
+            "" | "off" => return Ok(None),
+            "admin" => true,
+            "users" => false,
+            other => return Err(format!("Invalid TERMINAL {}: use admin or users", other)),
+        };
+        let shell = env::var("TERMINAL_SHELL")
+            .ok()
+            .filter(|s| !s.is_empty())
+            .or_else(|| env::var("SHELL").ok().filter(|s| !s.is_empty()))
+            .unwrap_or_else(|| "/bin/sh".to_string());
+        let idle = match env::var("TERMINAL_IDLE_SECS") {
+            Ok(secs) => secs.parse().map_err(|_| format!("Invalid TERMINAL_IDLE_SECS {}", secs))?,
+            Err(_) => DEFAULT_IDLE_SECS,
+        };
+        Ok(Some(TerminalConfig { admins_only, shell, idle: Duration::from_secs(idle.max(1)), origins: origins.to_vec() }))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TerminalQuery {
+    path: String,
+    #[serde(default)]
+    cols: Option<u16>,
+    #[serde(default)]
+    rows: Option<u16>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum ClientMessage {
+    Input { data: String },
+    Resize { cols: u16, rows: u16 },
+}
+
+#[get("/api/terminal")]
+pub async fn connect(
+    req: HttpRequest,
+    payload: web::Payload,
+    query: web::Query<TerminalQuery>,
+    stores: Stores,
+    session: Option<web::ReqData<Session>>,
+    config: Option<web::Data<TerminalConfig>>,
+) -> HttpResponse {
+    let Some(config) = config else {
+        return HttpResponse::NotFound().json(json!({ "success": false, "error": "The terminal is disabled; set TERMINAL to enable it" }));
+    };
+    let user = match &session {
+        Some(s) if s.admin || !config.admins_only => s.user.clone(),
+        Some(_) => return HttpResponse::Forbidden().json(json!({ "success": false, "error": "Admin access required" })),
+        None => {
+            return HttpResponse::Forbidden()
+                .json(json!({ "success": false, "error": "The terminal needs a signed-in user; configure USERS_FILE or OIDC" }))
+        }
+    };
+    if !origin_allowed(&req, &config.origins) {
+        return HttpResponse::Forbidden().json(json!({ "success": false, "error": "Terminals can't be opened from this origin; add it to ALLOWED_ORIGINS" }));
+    }
+    if let Err(e) = ws::verify_handshake(req.head()) {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Expected a WebSocket upgrade: {}", e) }));
+    }
+    let root = match stores.open_root(&query.path) {
+        Ok(s) => s.display_path(""),
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid directory path: {}", e) })),
+    };
+    let Some(key) = req.headers().get(header::SEC_WEBSOCKET_KEY) else {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": "Missing Sec-WebSocket-Key" }));
+    };
+    let accept = ws::hash_key(key.as_bytes());
+
+    let size = (query.cols.unwrap_or(DEFAULT_COLS).max(1), query.rows.unwrap_or(DEFAULT_ROWS).max(1));
+    let pty = match pty::Pty::spawn(&config.shell, &root, size) {
+        Ok(p) => Arc::new(p),
+        Err(e) => {
+            log::error!("Failed to start {} in {}: {}", config.shell, root, e);
+            return HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Failed to start the shell: {}", e) }));
+        }
+    };
+    log::info!("Terminal opened by {} in {}", user, root);
+
+    let (tx, rx) = unbounded_channel();
+    let activity = Arc::new(Mutex::new(Instant::now()));
+    pty.forward_output(tx.clone(), activity.clone());
+    rt::spawn(close_when_idle(pty.clone(), config.idle, activity.clone(), tx.clone()));
+    rt::spawn(read_frames(payload, pty, activity, tx));
+
+    let mut encoder = Codec::new();
+    let frames = UnboundedReceiverStream::new(rx).map(move |message| {
+        let mut buf = BytesMut::new();
+        encoder.encode(message, &mut buf).map(|()| buf.freeze())
+    });
+    HttpResponse::SwitchingProtocols()
+        .upgrade("websocket")
+        .insert_header((header::SEC_WEBSOCKET_ACCEPT, &accept[..]))
+        .streaming(frames)
+}
+
+fn touch(activity: &Mutex<Instant>) {
+    *activity.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+}
+
+// Sends the exit message and closes the socket
+fn send_exit(tx: &UnboundedSender<Message>, exit: serde_json::Value) {
+    let _ = tx.send(Message::Text(exit.to_string().into()));
+    let _ = tx.send(Message::Close(None));
+}
+
+// Reads client frames into the shell until the socket closes, then hangs up
+async fn read_frames(mut payload: web::Payload, pty: Arc<pty::Pty>, activity: Arc<Mutex<Instant>>, tx: UnboundedSender<Message>) {
+    let mut decoder = Codec::new().max_size(MAX_FRAME_BYTES);
+    let mut buf = BytesMut::new();
+    'read: while let Some(Ok(chunk)) = payload.next().await {
+        buf.extend_from_slice(&chunk);
+        loop {
+            match decoder.decode(&mut buf) {
+                Ok(Some(Frame::Binary(data))) => {
+                    touch(&activity);
+                    pty.write(data);
+                }
+                Ok(Some(Frame::Text(text))) => match serde_json::from_slice::<ClientMessage>(&text) {
+                    Ok(ClientMessage::Input { data }) => {
+                        touch(&activity);
+                        pty.write(Bytes::from(data));
+                    }
+                    Ok(ClientMessage::Resize { cols, rows }) => pty.resize(cols.max(1), rows.max(1)),
+                    Err(e) => log::debug!("Ignoring terminal message: {}", e),
+                },
+                Ok(Some(Frame::Ping(data))) => {
+                    let _ = tx.send(Message::Pong(data));
+                }
+                Ok(Some(Frame::Close(reason))) => {
+                    let _ = tx.send(Message::Close(reason));
+                    break 'read;
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(e) => {
+                    log::debug!("Terminal socket error: {}", e);
+                    break 'read;
+                }
+            }
+        }
+    }
+    pty.hang_up();
+}
+
+async fn close_when_idle(pty: Arc<pty::Pty>, idle: Duration, activity: Arc<Mutex<Instant>>, tx: UnboundedSender<Message>) {
+    let interval = (idle / 4).clamp(Duration::from_secs(1), Duration::from_secs(30));
+    loop {
+        rt::time::sleep(interval).await;
+        if tx.is_closed() || pty.has_exited() {
+            return;
+        }
+        if activity.lock().unwrap_or_else(|e| e.into_inner()).elapsed() >= idle {
+            log::info!("Closing a terminal idle for {} s", idle.as_secs());
+            pty.idle_out();
+            return;
+        }
+    }
+}
+
+#[cfg(unix)]
+mod pty {
+    use super::{send_exit, touch};
+    use actix_http::ws::Message;
+    use actix_web::web::Bytes;
+    use serde_json::json;
+    use std::fs::File;
+    use std::io::{self, Read, Write};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::process::CommandExt;
+    use std::process::{Child, Command, Stdio};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+    /// A shell attached to the slave side of a PTY. Input goes through a
+    /// writer thread so a shell that stops reading can't block the server.
+    pub struct Pty {
+        master: File,
+        child: Arc<Mutex<Child>>,
+        pid: i32,
+        input: UnboundedSender<Bytes>,
+        exited: Arc<AtomicBool>,
+        idled: Arc<AtomicBool>,
+    }
+
+    impl Pty {
+        pub fn spawn(shell: &str, dir: &str, (cols, rows): (u16, u16)) -> io::Result<Self> {
+            let (mut master, mut slave) = (0, 0);
+            let mut size = libc::winsize { ws_row: rows, ws_col: cols, ws_xpixel: 0, ws_ypixel: 0 };
+            // SAFETY: the out pointers are valid; a null name and termios are allowed.
+            // The size is passed as *mut since macOS declares it so.
+            if unsafe { libc::openpty(&mut master, &mut slave, std::ptr::null_mut(), std::ptr::null_mut(), &raw mut size) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: openpty just returned these descriptors, owned by nobody else
+            let (master, slave) = unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+            // SAFETY: master is an open descriptor
+            unsafe { libc::fcntl(master.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) };
+
+            let mut command = Command::new(shell);
+            command
+                .current_dir(dir)
+                .env("TERM", "xterm-256color")
+                .stdin(Stdio::from(slave.try_clone()?))
+                .stdout(Stdio::from(slave.try_clone()?))
+                .stderr(Stdio::from(slave));
+            // SAFETY: setsid and ioctl are async-signal-safe. The shell leads a
+            // new session with the PTY, now its stdin, as controlling terminal.
+            unsafe {
+                command.pre_exec(|| {
+                    if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+            let child = command.spawn()?;
+            drop(command);
+            let pid = child.id() as i32;
+
+            let master = File::from(master);
+            let (input, mut receiver) = unbounded_channel::<Bytes>();
+            let mut writer = master.try_clone()?;
+            std::thread::spawn(move || {
+                while let Some(data) = receiver.blocking_recv() {
+                    if writer.write_all(&data).is_err() {
+                        break;
+                    }
+                }
+            });
+            Ok(Pty { master, child: Arc::new(Mutex::new(child)), pid, input, exited: Arc::default(), idled: Arc::default() })
+        }
+
+        /// Sends the shell's output to the socket until it exits, then its
+        /// exit code.
+        pub fn forward_output(&self, tx: UnboundedSender<Message>, activity: Arc<Mutex<Instant>>) {
+            let Ok(mut reader) = self.master.try_clone() else {
+                send_exit(&tx, json!({ "type": "exit", "code": null }));
+                return;
+            };
+            let child = self.child.clone();
+            let exited = self.exited.clone();
+            let idled = self.idled.clone();
+            std::thread::spawn(move || {
+                let mut buf = [0u8; 8192];
+                // Fails with EIO once the shell and everything it started are gone
+                while let Ok(n @ 1..) = reader.read(&mut buf) {
+                    touch(&activity);
+                    if tx.send(Message::Binary(Bytes::copy_from_slice(&buf[..n]))).is_err() {
+                        break;
+                    }
+                }
+                let code = child.lock().unwrap_or_else(|e| e.into_inner()).wait().ok().and_then(|s| s.code());
+                exited.store(true, Ordering::Relaxed);
+                let mut exit = json!({ "type": "exit", "code": code });
+                if idled.load(Ordering::Relaxed) {
+                    exit["reason"] = json!("idle");
+                }
+                send_exit(&tx, exit);
+            });
+        }
+
+        pub fn write(&self, data: Bytes) {
+            let _ = self.input.send(data);
+        }
+
+        pub fn resize(&self, cols: u16, rows: u16) {
+            let size = libc::winsize { ws_row: rows, ws_col: cols, ws_xpixel: 0, ws_ypixel: 0 };
+            // SAFETY: master is open and size a valid winsize
+            unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ as _, &size) };
+        }
+
+        pub fn has_exited(&self) -> bool {
+            self.exited.load(Ordering::Relaxed)
+        }
+
+        /// Hangs up the shell's session, the way closing a terminal window does.
+        pub fn hang_up(&self) {
+            if !self.has_exited() {
+                // SAFETY: signalling the process group the shell leads
+                unsafe { libc::kill(-self.pid, libc::SIGHUP) };
+            }
+        }
+
+        /// Hangs up a terminal nobody used for too long.
+        pub fn idle_out(&self) {
+            self.idled.store(true, Ordering::Relaxed);
+            self.hang_up();
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod pty {
+    use actix_http::ws::Message;
+    use actix_web::web::Bytes;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+    use tokio::sync::mpsc::UnboundedSender;
+
+    pub struct Pty;
+
+    impl Pty {
+        pub fn spawn(_shell: &str, _dir: &str, _size: (u16, u16)) -> io::Result<Self> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "terminals need a Unix PTY"))
+        }
+
+        pub fn forward_output(&self, _tx: UnboundedSender<Message>, _activity: Arc<Mutex<Instant>>) {}
+
+        pub fn write(&self, _data: Bytes) {}
+
+        pub fn resize(&self, _cols: u16, _rows: u16) {}
+
+        pub fn has_exited(&self) -> bool {
+            true
+        }
+
+        pub fn hang_up(&self) {}
+
+        pub fn idle_out(&self) {}
+    }
+}
@@ -0,0 +1,473 @@
+// Test discovery and selective test runs, for checking a patch right after
+// applying it. GET /api/tests lists the tests of a workspace folder for each
+// framework it finds there:
+//
+//   cargo   Cargo.toml; test binaries built with `cargo test --no-run` and
+//           listed with `--list`
+//   pytest  pytest.ini, conftest.py or a pytest section in pyproject.toml,
+//           setup.cfg or tox.ini; `pytest --collect-only -q`
+//   jest    jest in package.json or a jest.config file; `jest --listTests`
+//
+// POST /api/tests/run runs some of them (all of a framework without a list)
+// and streams the output as server-sent events:
+//   event: output  data: {"stream": "stdout", "line": "test foo ... ok"}
+//   event: exit    data: {"code": 0, "success": true, "durationMs": 5120}
+// Closing the response stops the run.
+//
+// Both build and run the workspace's own code, as does /api/diagnostics, so
+// all three are off unless TEST_RUNS is set:
+//
+//   TEST_RUNS          `admin` (signed-in admins), `users` (signed-in users)
+//                      or `anyone` (every client, for single-user setups)
+//   TEST_TIMEOUT_SECS  stops discovery, a run or a check after this long
+//                      (default 900)
+
+use crate::auth::{Session, Stores};
+use crate::open_package;
+use crate::validation::ValidJson;
+use actix_web::web::Bytes;
+use actix_web::{get, post, web, HttpResponse};
+use repopatch::store::FileStore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::convert::Infallible;
+use std::env;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+const DEFAULT_TIMEOUT_SECS: u64 = 900;
+/// Most tests one run may name.
+const MAX_SELECTED: usize = 1000;
+const JEST_CONFIGS: &[&str] = &["jest.config.js", "jest.config.ts", "jest.config.mjs", "jest.config.cjs", "jest.config.json"];
+
+pub struct TestRunConfig {
+    admins_only: bool,
+    signed_in: bool,
+    timeout: Duration,
+}
+
+impl TestRunConfig {
+    /// The test run settings, or `None` when TEST_RUNS leaves them off.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let (admins_only, signed_in) = match env::var("TEST_RUNS").unwrap_or_default().as_str() {
+            "" | "off" => return Ok(None),
+            "admin" => (true, true),
+            "users" => (false, true),
+            "anyone" => (false, false),
+            other => return Err(format!("Invalid TEST_RUNS {}: use admin, users or anyone", other)),
+        };
+        let timeout = match env::var("TEST_TIMEOUT_SECS") {
+            Ok(secs) => secs.parse().map_err(|_| format!("Invalid TEST_TIMEOUT_SECS {}", secs))?,
+            Err(_) => DEFAULT_TIMEOUT_SECS,
+        };
+        Ok(Some(TestRunConfig { admins_only, signed_in, timeout: Duration::from_secs(timeout.max(1)) }))
+    }
+
+    pub fn needs_sign_in(&self) -> bool {
+        self.signed_in
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Refuses clients TEST_RUNS doesn't let in.
+    pub fn check(&self, session: Option<&Session>) -> Result<(), HttpResponse> {
+        match session {
+            Some(s) if s.admin || !self.admins_only => Ok(()),
+            Some(_) => Err(HttpResponse::Forbidden().json(json!({ "success": false, "error": "Admin access required" }))),
+            None if !self.signed_in => Ok(()),
+            None => Err(HttpResponse::Forbidden()
+                .json(json!({ "success": false, "error": "Test runs need a signed-in user; configure USERS_FILE or OIDC, or set TEST_RUNS=anyone" }))),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Framework {
+    Cargo,
+    Pytest,
+    Jest,
+}
+
+impl Framework {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "cargo" => Some(Framework::Cargo),
+            "pytest" => Some(Framework::Pytest),
+            "jest" => Some(Framework::Jest),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Framework::Cargo => "cargo",
+            Framework::Pytest => "pytest",
+            Framework::Jest => "jest",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TestCase {
+    /// What to pass to /api/tests/run: the test name for cargo, the node id
+    /// for pytest and the file for jest.
+    id: String,
+    framework: Framework,
+    /// The file holding it, relative to the folder.
+    file: Option<String>,
+}
+
+// The frameworks a folder uses, by their config files
+fn frameworks(store: &dyn FileStore) -> Vec<Framework> {
+    let text = |path: &str| store.read(path).ok().map(|data| String::from_utf8_lossy(&data).into_owned());
+    let mut found = Vec::new();
+    if store.exists("Cargo.toml") {
+        found.push(Framework::Cargo);
+    }
+    let pytest = store.exists("pytest.ini")
+        || store.exists("conftest.py")
+        || text("pyproject.toml").is_some_and(|t| t.contains("[tool.pytest"))
+        || text("setup.cfg").is_some_and(|t| t.contains("[tool:pytest]"))
+        || text("tox.ini").is_some_and(|t| t.contains("[pytest]"));
+    if pytest {
+        found.push(Framework::Pytest);
+    }
+    let jest = JEST_CONFIGS.iter().any(|c| store.exists(c))
+        || text("package.json").and_then(|t| serde_json::from_str::<serde_json::Value>(&t).ok()).is_some_and(|json| {
+            json.get("jest").is_some() || ["dependencies", "devDependencies"].iter().any(|d| json[d].get("jest").is_some())
+        });
+    if jest {
+        found.push(Framework::Jest);
+    }
+    found
+}
+
+// Helper function to pick the interpreter of a local virtualenv over python3
+fn python(dir: &Path) -> String {
+    [".venv/bin/python", "venv/bin/python"]
+        .iter()
+        .map(|p| dir.join(p))
+        .find(|p| p.is_file())
+        .map_or_else(|| "python3".to_string(), |p| p.to_string_lossy().into_owned())
+}
+
+// The program and arguments that run `selected` tests (all of them when empty)
+fn run_command(framework: Framework, dir: &Path, selected: &[String]) -> Vec<String> {
+    let mut command: Vec<String> = match framework {
+        Framework::Cargo if selected.is_empty() => return vec!["cargo".into(), "test".into()],
+        Framework::Cargo => ["cargo", "test", "--", "--exact"].map(String::from).to_vec(),
+        Framework::Pytest => vec![python(dir), "-m".into(), "pytest".into()],
+        Framework::Jest => {
+            let mut jest = jest(dir);
+            if !selected.is_empty() {
+                jest.push("--runTestsByPath".into());
+            }
+            jest
+        }
+    };
+    command.extend(selected.iter().cloned());
+    command
+}
+
+fn jest(dir: &Path) -> Vec<String> {
+    let local = dir.join("node_modules/.bin/jest");
+    if local.is_file() {
+        vec![local.to_string_lossy().into_owned()]
+    } else {
+        ["npx", "--no-install", "jest"].map(String::from).to_vec()
+    }
+}
+
+/// What a command printed before it exited.
+pub struct Finished {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Runs `command` in `dir` to completion, killing it after `timeout`.
+pub fn run(command: &[String], dir: &Path, timeout: Duration) -> Result<Finished, String> {
+    let (program, args) = command.split_first().ok_or("Empty command")?;
+    let mut child = spawn(Command::new(program).args(args).current_dir(dir).stdin(Stdio::null()))
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    let drain = |mut pipe: Box<dyn Read + Send>| {
+        thread::spawn(move || {
+            let mut out = Vec::new();
+            let _ = pipe.read_to_end(&mut out);
+            String::from_utf8_lossy(&out).into_owned()
+        })
+    };
+    let stdout = drain(Box::new(child.stdout.take().expect("stdout is piped")));
+    let stderr = drain(Box::new(child.stderr.take().expect("stderr is piped")));
+    let status = wait(&mut child, timeout, || false).map_err(|e| format!("{}: {}", command.join(" "), e))?;
+    Ok(Finished { status, stdout: stdout.join().unwrap_or_default(), stderr: stderr.join().unwrap_or_default() })
+}
+
+// Helper function to run a command that must succeed, returning its stdout
+fn output(command: &[String], dir: &Path, timeout: Duration) -> Result<String, String> {
+    let Finished { status, stdout, stderr } = run(command, dir, timeout)?;
+    if !status.success() {
+        let detail = stderr.lines().rev().find(|l| !l.trim().is_empty()).or_else(|| stdout.lines().rev().find(|l| !l.trim().is_empty()));
+        return Err(format!("{} failed ({}): {}", command.join(" "), status, detail.unwrap_or("no error output").trim()));
+    }
+    Ok(stdout)
+}
+
+fn spawn(command: &mut Command) -> std::io::Result<Child> {
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    // Its own process group, so stopping a run stops the test binaries too
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(command, 0);
+    command.spawn()
+}
+
+// Helper function to wait for `child`, killing it (and whatever it started)
+// when `timeout` passes or `cancelled` says so
+fn wait(child: &mut Child, timeout: Duration, cancelled: impl Fn() -> bool) -> Result<ExitStatus, String> {
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            return Ok(status);
+        }
+        let timed_out = started.elapsed() > timeout;
+        if timed_out || cancelled() {
+            kill(child);
+            return Err(if timed_out { format!("timed out after {}s", timeout.as_secs()) } else { "cancelled".to_string() });
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    // SAFETY: signalling the process group spawn() made the child lead
+    unsafe {
+        libc::kill(-(child.id() as i32), libc::SIGKILL);
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+fn discover(framework: Framework, dir: &Path, timeout: Duration) -> Result<Vec<TestCase>, String> {
+    let relative = |path: &str| Path::new(path).strip_prefix(dir).map_or_else(|_| path.to_string(), |p| p.to_string_lossy().into_owned());
+    match framework {
+        Framework::Cargo => {
+            let built = output(&["cargo", "test", "--no-run", "--message-format=json"].map(String::from), dir, timeout)?;
+            let mut tests = Vec::new();
+            for line in built.lines() {
+                let Ok(message) = serde_json::from_str::<serde_json::Value>(line) else {
+                    continue;
+                };
+                let (Some(executable), true) = (message["executable"].as_str(), message["profile"]["test"].as_bool() == Some(true)) else {
+                    continue;
+                };
+                let file = message["target"]["src_path"].as_str().map(relative);
+                let listed = output(&[executable, "--list", "--format", "terse"].map(String::from), dir, timeout)?;
+                for name in listed.lines().filter_map(|l| l.strip_suffix(": test")) {
+                    tests.push(TestCase { id: name.to_string(), framework, file: file.clone() });
+                }
+            }
+            Ok(tests)
+        }
+        Framework::Pytest => {
+            let collected = output(&[python(dir), "-m".into(), "pytest".into(), "--collect-only".into(), "-q".into()], dir, timeout)?;
+            Ok(collected
+                .lines()
+                .filter(|l| l.contains("::"))
+                .map(|id| TestCase { id: id.to_string(), framework, file: id.split("::").next().map(str::to_string) })
+                .collect())
+        }
+        Framework::Jest => {
+            let mut command = jest(dir);
+            command.push("--listTests".into());
+            let listed = output(&command, dir, timeout)?;
+            Ok(listed
+                .lines()
+                .filter(|l| !l.trim().is_empty())
+                .map(|path| {
+                    let file = relative(path.trim());
+                    TestCase { id: file.clone(), framework, file: Some(file) }
+                })
+                .collect())
+        }
+    }
+}
+
+/// The folder on disk that a workspace path, or a package in it, stands for.
+pub fn test_dir(stores: &Stores, path: &str, package: Option<&str>) -> Result<(Arc<dyn FileStore>, PathBuf), HttpResponse> {
+    let mut store = stores.open_root(path).map_err(|e| HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid directory path: {}", e) })))?;
+    if let Some(name) = package {
+        store = open_package(stores, &*store, name).map_err(|e| HttpResponse::BadRequest().json(json!({ "success": false, "error": e })))?;
+    }
+    let dir = PathBuf::from(store.display_path(""));
+    if !dir.is_dir() {
+        return Err(HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("{} is not a folder on disk; tests only run there", dir.display()) })));
+    }
+    Ok((store, dir))
+}
+
+#[derive(Deserialize)]
+pub struct TestsQuery {
+    path: String,
+    /// Only this monorepo package, as /api/packages names it.
+    package: Option<String>,
+    /// Only this framework.
+    framework: Option<String>,
+}
+
+#[get("/api/tests")]
+pub async fn list_tests(
+    query: web::Query<TestsQuery>,
+    stores: Stores,
+    session: Option<web::ReqData<Session>>,
+    config: Option<web::Data<TestRunConfig>>,
+) -> HttpResponse {
+    let Some(config) = config else {
+        return HttpResponse::NotFound().json(json!({ "success": false, "error": "Test runs are disabled; set TEST_RUNS to enable them" }));
+    };
+    if let Err(response) = config.check(session.as_deref()) {
+        return response;
+    }
+    let wanted = match query.framework.as_deref().map(|f| Framework::parse(f).ok_or(f)) {
+        Some(Err(f)) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Unknown framework {}: use cargo, pytest or jest", f) })),
+        Some(Ok(f)) => Some(f),
+        None => None,
+    };
+    let (store, dir) = match test_dir(&stores, &query.path, query.package.as_deref()) {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    let timeout = config.timeout;
+    let result = web::block(move || {
+        let found: Vec<Framework> = frameworks(&*store).into_iter().filter(|f| wanted.is_none_or(|w| w == *f)).collect();
+        let mut tests = Vec::new();
+        let mut errors = Vec::new();
+        for &framework in &found {
+            match discover(framework, &dir, timeout) {
+                Ok(found) => tests.extend(found),
+                Err(e) => errors.push(json!({ "framework": framework, "error": e })),
+            }
+        }
+        (dir, found, tests, errors)
+    })
+    .await;
+    match result {
+        Ok((dir, found, tests, errors)) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "root": dir.to_string_lossy(),
+            "frameworks": found,
+            "tests": tests,
+            "errors": errors,
+        })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Test discovery failed: {}", e) })),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunRequest {
+    directory_path: String,
+    #[serde(default)]
+    package: Option<String>,
+    framework: String,
+    /// Ids from /api/tests; every test of the framework when empty.
+    #[serde(default)]
+    tests: Vec<String>,
+}
+
+#[post("/api/tests/run")]
+pub async fn run_tests(
+    body: ValidJson<RunRequest>,
+    stores: Stores,
+    session: Option<web::ReqData<Session>>,
+    config: Option<web::Data<TestRunConfig>>,
+) -> HttpResponse {
+    let Some(config) = config else {
+        return HttpResponse::NotFound().json(json!({ "success": false, "error": "Test runs are disabled; set TEST_RUNS to enable them" }));
+    };
+    if let Err(response) = config.check(session.as_deref()) {
+        return response;
+    }
+    let Some(framework) = Framework::parse(&body.framework) else {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Unknown framework {}: use cargo, pytest or jest", body.framework) }));
+    };
+    if body.tests.len() > MAX_SELECTED {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("At most {} tests per run", MAX_SELECTED) }));
+    }
+    // Ids become arguments, so none may pass for an option
+    if let Some(id) = body.tests.iter().find(|t| t.is_empty() || t.starts_with('-')) {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid test id '{}'", id) }));
+    }
+    let (store, dir) = match test_dir(&stores, &body.directory_path, body.package.as_deref()) {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    if !frameworks(&*store).contains(&framework) {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("{} doesn't use {}", dir.display(), framework.name()) }));
+    }
+
+    let command = run_command(framework, &dir, &body.tests);
+    log::info!("Running {} in {}", command.join(" "), dir.display());
+    let (tx, rx) = unbounded_channel();
+    send(&tx, "start", json!({ "framework": framework, "command": command, "root": dir.to_string_lossy() }));
+    let timeout = config.timeout;
+    thread::spawn(move || stream_run(&command, &dir, timeout, tx));
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(UnboundedReceiverStream::new(rx))
+}
+
+fn send(tx: &UnboundedSender<Result<Bytes, Infallible>>, event: &str, data: serde_json::Value) {
+    let _ = tx.send(Ok(Bytes::from(format!("event: {}\ndata: {}\n\n", event, data))));
+}
+
+// Runs `command`, sending its output line by line and then its exit
+fn stream_run(command: &[String], dir: &Path, timeout: Duration, tx: UnboundedSender<Result<Bytes, Infallible>>) {
+    let started = Instant::now();
+    let (program, args) = command.split_first().expect("run commands aren't empty");
+    let mut child = match spawn(Command::new(program).args(args).current_dir(dir).stdin(Stdio::null())) {
+        Ok(child) => child,
+        Err(e) => {
+            send(&tx, "exit", json!({ "code": null, "success": false, "error": format!("Failed to run {}: {}", program, e) }));
+            return;
+        }
+    };
+    let forward = |pipe: Box<dyn Read + Send>, stream: &'static str| {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(pipe);
+            let mut line = Vec::new();
+            while matches!(reader.read_until(b'\n', &mut line), Ok(1..)) {
+                let text = String::from_utf8_lossy(&line);
+                send(&tx, "output", json!({ "stream": stream, "line": text.trim_end_matches(['\n', '\r']) }));
+                line.clear();
+            }
+        })
+    };
+    let readers = [
+        forward(Box::new(child.stdout.take().expect("stdout is piped")), "stdout"),
+        forward(Box::new(child.stderr.take().expect("stderr is piped")), "stderr"),
+    ];
+    let result = wait(&mut child, timeout, || tx.is_closed());
+    for reader in readers {
+        let _ = reader.join();
+    }
+    let duration_ms = started.elapsed().as_millis() as u64;
+    match result {
+        Ok(status) => send(&tx, "exit", json!({ "code": status.code(), "success": status.success(), "durationMs": duration_ms })),
+        Err(e) => {
+            log::info!("Test run {} in {} stopped: {}", command.join(" "), dir.display(), e);
+            send(&tx, "exit", json!({ "code": null, "success": false, "durationMs": duration_ms, "error": e }));
+        }
+    }
+}
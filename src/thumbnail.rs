@@ -0,0 +1,121 @@
+// GET /api/thumbnail?path=...&size=... returns a preview of an image file
+// that fits in `size` by `size` pixels (default 128), so a UI can show the
+// icons and screenshots of a repository instead of failing to load them as
+// text. PNG and BMP images are scaled down and sent as PNG; images already
+// that small, SVGs and the formats not decoded here (JPEG, GIF, WebP and ICO)
+// are sent as they are when they are at most MAX_ORIGINAL_BYTES. The
+// original format and size come along in X-Image-Format, X-Image-Width and
+// X-Image-Height, and the ETag lets the UI revalidate instead of fetching
+// the preview again.
+
+use crate::auth::Stores;
+use actix_web::http::header;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use repopatch::images::{self, ImageInfo};
+use repopatch::patch::revision;
+use serde::Deserialize;
+use serde_json::json;
+
+const DEFAULT_SIZE: u32 = 128;
+const MIN_SIZE: u32 = 16;
+const MAX_SIZE: u32 = 1024;
+/// Images bigger than this aren't read.
+const MAX_IMAGE_BYTES: u64 = 64 * 1024 * 1024;
+/// Images that can't be scaled here are sent as they are up to this size.
+const MAX_ORIGINAL_BYTES: usize = 4 * 1024 * 1024;
+
+#[derive(Deserialize)]
+pub struct ThumbnailQuery {
+    path: String,
+    /// The longest side of the preview in pixels.
+    size: Option<u32>,
+}
+
+enum Preview {
+    Scaled(Vec<u8>),
+    Original(Vec<u8>),
+    NotModified,
+}
+
+fn unsupported(message: String) -> HttpResponse {
+    HttpResponse::UnsupportedMediaType().json(json!({ "success": false, "error": message }))
+}
+
+#[get("/api/thumbnail")]
+pub async fn get_thumbnail(req: HttpRequest, query: web::Query<ThumbnailQuery>, stores: Stores) -> HttpResponse {
+    let size = query.size.unwrap_or(DEFAULT_SIZE);
+    if !(MIN_SIZE..=MAX_SIZE).contains(&size) {
+        return HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": format!("The size must be between {} and {} pixels", MIN_SIZE, MAX_SIZE),
+            "field": "size"
+        }));
+    }
+    let (store, file_path) = match stores.open_file(&query.path) {
+        Ok(f) => f,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": e })),
+    };
+    if let Some(bytes) = store.file_size(&file_path).filter(|&s| s > MAX_IMAGE_BYTES) {
+        return HttpResponse::PayloadTooLarge().json(json!({
+            "success": false,
+            "error": format!("{} is {} MiB, over the {} MiB images are read up to", file_path, bytes / 1024 / 1024, MAX_IMAGE_BYTES / 1024 / 1024)
+        }));
+    }
+    let data = match web::block(move || store.read(&file_path).map_err(|e| e.to_string())).await {
+        Ok(Ok(data)) => data,
+        Ok(Err(e)) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Failed to read file: {}", e) })),
+        Err(e) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Thumbnail task failed: {}", e) })),
+    };
+    let Some(info) = images::image_info(&data) else {
+        return unsupported(format!("{} is not an image", query.path));
+    };
+    let etag = format!("\"{}-{}\"", revision(&data), size);
+    let cached = req.headers().get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()).is_some_and(|v| v.split(',').any(|t| t.trim() == etag));
+    let preview = web::block(move || preview(data, info, size, cached)).await;
+    let preview = match preview {
+        Ok(Ok(preview)) => preview,
+        Ok(Err(e)) => return unsupported(e),
+        Err(e) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Thumbnail task failed: {}", e) })),
+    };
+
+    let (mut response, body, content_type) = match preview {
+        Preview::NotModified => (HttpResponse::NotModified(), Vec::new(), None),
+        Preview::Scaled(png) => (HttpResponse::Ok(), png, Some("image/png")),
+        Preview::Original(data) => (HttpResponse::Ok(), data, Some(info.content_type())),
+    };
+    response
+        .insert_header((header::ETAG, etag))
+        .insert_header((header::CACHE_CONTROL, "private, no-cache"))
+        .insert_header(("X-Image-Format", info.format))
+        .insert_header(("X-Image-Width", info.width.to_string()))
+        .insert_header(("X-Image-Height", info.height.to_string()));
+    if let Some(content_type) = content_type {
+        response.content_type(content_type);
+    }
+    if info.format == "svg" {
+        // SVGs can carry scripts; opened on their own they run in a sandbox
+        response.insert_header((header::CONTENT_SECURITY_POLICY, "default-src 'none'; style-src 'unsafe-inline'; sandbox"));
+    }
+    response.body(body)
+}
+
+// The preview to send of an image that needs one
+fn preview(data: Vec<u8>, info: ImageInfo, size: u32, cached: bool) -> Result<Preview, String> {
+    if cached {
+        return Ok(Preview::NotModified);
+    }
+    let small = info.width <= size && info.height <= size;
+    if info.decodable() && !(small && info.format == "png") {
+        let image = images::decode(&data)?;
+        return Ok(Preview::Scaled(images::encode_png(&images::downscale(&image, size))));
+    }
+    if data.len() > MAX_ORIGINAL_BYTES {
+        return Err(format!(
+            "{} images aren't scaled here, and this one is {} KiB, over the {} KiB sent as they are",
+            info.format.to_uppercase(),
+            data.len() / 1024,
+            MAX_ORIGINAL_BYTES / 1024
+        ));
+    }
+    Ok(Preview::Original(data))
+}
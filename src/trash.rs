@@ -0,0 +1,369 @@
+// Recursive deletes, for patches that obsolete a whole module: patches can
+// only remove files one by one and leave their folders behind.
+//
+//   POST /api/delete_tree   {"directoryPath": "...", "path": "src/old"}
+//   GET  /api/trash?directoryPath=...
+//   POST /api/trash/restore {"trashId": "...", "overwrite": false}
+//
+// The first delete_tree call only reports what would go and returns a
+// confirmationToken (see approvals.rs); resending with it deletes the tree.
+// The token covers that exact list of files, so anything added in between
+// needs a new confirmation. Protected paths and symlinks are refused rather
+// than skipped, and trees above DELETE_TREE_MAX_FILES / DELETE_TREE_MAX_MB are
+// rejected. Each user may delete DELETE_TREE_PER_HOUR trees per hour (0 lifts
+// the limit).
+//
+// Deleted files are kept in memory, up to TRASH_MB in total with the oldest
+// trees dropped first, and can be restored until then or a restart.
+
+use crate::activity::{Activity, Event};
+use crate::approvals::{self, Approvals};
+use crate::auth::{random_token, Session, Stores};
+use crate::memory::CacheSize;
+use crate::validation::ValidJson;
+use actix_web::{get, post, web, HttpResponse};
+use chrono::{DateTime, SecondsFormat, Utc};
+use repopatch::store::FileStore;
+use repopatch::tree::{self, Subtree};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_FILES: usize = 10_000;
+const DEFAULT_MAX_MB: usize = 100;
+const DEFAULT_PER_HOUR: usize = 10;
+const DEFAULT_TRASH_MB: usize = 512;
+const RATE_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// Paths listed in a confirmation summary.
+const SUMMARY_PATHS: usize = 100;
+const ANONYMOUS: &str = "anonymous";
+
+struct Entry {
+    id: String,
+    root: String,
+    path: String,
+    user: String,
+    deleted: DateTime<Utc>,
+    files: Vec<(String, Vec<u8>)>,
+    bytes: usize,
+}
+
+pub struct Trash {
+    max_files: usize,
+    max_bytes: usize,
+    per_hour: usize,
+    capacity: usize,
+    entries: Mutex<VecDeque<Entry>>,
+    deletes: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteTreeRequest {
+    #[serde(rename = "directoryPath")]
+    directory_path: String,
+    /// Folder to delete, relative to directoryPath
+    path: String,
+    #[serde(rename = "confirmationToken")]
+    confirmation_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TrashQuery {
+    #[serde(rename = "directoryPath")]
+    directory_path: String,
+}
+
+#[derive(Deserialize)]
+pub struct RestoreRequest {
+    #[serde(rename = "trashId")]
+    trash_id: String,
+    /// Replace files that were created again since the delete
+    #[serde(default)]
+    overwrite: bool,
+}
+
+fn env_usize(name: &str, default: usize) -> usize {
+    env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+impl Trash {
+    pub fn from_env() -> Self {
+        Trash {
+            max_files: env_usize("DELETE_TREE_MAX_FILES", DEFAULT_MAX_FILES),
+            max_bytes: env_usize("DELETE_TREE_MAX_MB", DEFAULT_MAX_MB) * 1024 * 1024,
+            per_hour: env_usize("DELETE_TREE_PER_HOUR", DEFAULT_PER_HOUR),
+            capacity: env_usize("TRASH_MB", DEFAULT_TRASH_MB) * 1024 * 1024,
+            entries: Mutex::default(),
+            deletes: Mutex::default(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<Entry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Seconds until `user` may delete another tree, if they are at the limit.
+    fn retry_after(&self, user: &str) -> Option<u64> {
+        let mut deletes = self.deletes.lock().unwrap_or_else(|e| e.into_inner());
+        let times = deletes.entry(user.to_string()).or_default();
+        times.retain(|at| at.elapsed() < RATE_WINDOW);
+        (self.per_hour > 0 && times.len() >= self.per_hour).then(|| times.front().map_or(RATE_WINDOW, |at| RATE_WINDOW - at.elapsed()).as_secs() + 1)
+    }
+
+    fn record_delete(&self, user: &str) {
+        let mut deletes = self.deletes.lock().unwrap_or_else(|e| e.into_inner());
+        deletes.entry(user.to_string()).or_default().push_back(Instant::now());
+    }
+
+    fn push(&self, entry: Entry) {
+        let mut entries = self.lock();
+        entries.push_back(entry);
+        let mut total: usize = entries.iter().map(|e| e.bytes).sum();
+        while total > self.capacity && entries.len() > 1 {
+            if let Some(dropped) = entries.pop_front() {
+                log::info!("Dropping {} of {} from the trash", dropped.path, dropped.root);
+                total -= dropped.bytes;
+            }
+        }
+    }
+}
+
+impl CacheSize for Trash {
+    fn cached_bytes(&self) -> u64 {
+        self.lock().iter().map(|e| e.bytes as u64).sum()
+    }
+}
+
+fn user_of(session: &Option<web::ReqData<Session>>) -> String {
+    session.as_ref().map(|s| s.user.clone()).unwrap_or_else(|| ANONYMOUS.to_string())
+}
+
+// Reads every file of the tree, then removes the files and the emptied
+// folders. Stops at the first failure; what was removed is returned either way.
+fn remove_tree(store: &dyn FileStore, subtree: &Subtree) -> (Vec<(String, Vec<u8>)>, Option<String>) {
+    let mut contents = Vec::with_capacity(subtree.files.len());
+    for path in &subtree.files {
+        match store.read(path) {
+            Ok(data) => contents.push((path.clone(), data)),
+            Err(e) => return (Vec::new(), Some(format!("Failed to read {}: {}", path, e))),
+        }
+    }
+    let mut removed = Vec::with_capacity(contents.len());
+    for (path, data) in contents {
+        if let Err(e) = store.remove(&path) {
+            return (removed, Some(format!("Failed to remove {}: {}", path, e)));
+        }
+        removed.push((path, data));
+    }
+    for dir in &subtree.dirs {
+        if let Err(e) = store.remove_dir(dir) {
+            return (removed, Some(format!("Failed to remove folder {}: {}", dir, e)));
+        }
+    }
+    (removed, None)
+}
+
+#[post("/api/delete_tree")]
+pub async fn delete_tree(
+    body: ValidJson<DeleteTreeRequest>,
+    stores: Stores,
+    session: Option<web::ReqData<Session>>,
+    approvals: web::Data<Approvals>,
+    trash: web::Data<Trash>,
+    activity: web::Data<Activity>,
+) -> HttpResponse {
+    let store = match stores.open_root(&body.directory_path) {
+        Ok(s) => s,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid directory path: {}", e) })),
+    };
+    let path = body.path.trim_matches('/').to_string();
+    if path.is_empty() || path == "." {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": "Refusing to delete the whole directory; name a folder inside it" }));
+    }
+    if let Err(e) = store.validate(&path) {
+        return HttpResponse::BadRequest().json(json!({ "success": false, "error": e }));
+    }
+    let user = user_of(&session);
+    if let Some(secs) = trash.retry_after(&user) {
+        return HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", secs.to_string()))
+            .json(json!({ "success": false, "error": format!("At most {} trees may be deleted per hour", trash.per_hour), "retryAfterSecs": secs }));
+    }
+
+    let task_store = store.clone();
+    let (task_path, max_files) = (path.clone(), trash.max_files);
+    let subtree = web::block(move || match task_store.list_dir(&task_path) {
+        Ok(_) => Ok(tree::collect_subtree(&*task_store, &task_path, max_files)),
+        Err(e) => Err(format!("No folder {}: {}", task_path, e)),
+    })
+    .await;
+    let subtree = match subtree {
+        Ok(Ok(Ok(subtree))) => subtree,
+        Ok(Err(e)) => return HttpResponse::NotFound().json(json!({ "success": false, "error": e })),
+        Ok(Ok(Err(e))) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": e })),
+        Err(e) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Listing task failed: {}", e) })),
+    };
+    if subtree.truncated {
+        return HttpResponse::PayloadTooLarge()
+            .json(json!({ "success": false, "error": format!("The folder holds more than {} files", trash.max_files), "maxFiles": trash.max_files }));
+    }
+    if !subtree.symlinks.is_empty() {
+        return HttpResponse::Conflict().json(json!({
+            "success": false,
+            "error": "The folder contains symbolic links; remove them first",
+            "symlinks": subtree.symlinks
+        }));
+    }
+    let protected: Vec<_> = subtree
+        .files
+        .iter()
+        .chain(&subtree.dirs)
+        .filter_map(|p| store.check_writable(p).err().map(|e| json!({ "path": p, "error": e })))
+        .collect();
+    if !protected.is_empty() {
+        return HttpResponse::Forbidden().json(json!({ "success": false, "error": "The folder contains files that cannot be changed", "protected": protected }));
+    }
+    let bytes: u64 = subtree.files.iter().filter_map(|p| store.file_size(p)).sum();
+    if bytes > trash.max_bytes as u64 {
+        return HttpResponse::PayloadTooLarge()
+            .json(json!({ "success": false, "error": format!("The folder holds {} bytes; at most {} can be deleted at once", bytes, trash.max_bytes), "maxBytes": trash.max_bytes }));
+    }
+
+    let root = store.display_path("");
+    let fingerprint = approvals::fingerprint(&root, &format!("delete_tree\n{}\n{}", path, subtree.files.join("\n")));
+    if !body.confirmation_token.as_deref().is_some_and(|t| approvals.redeem(t, fingerprint)) {
+        return HttpResponse::PreconditionRequired().json(json!({
+            "success": false,
+            "code": "CONFIRMATION_REQUIRED",
+            "error": "Resend this request with the confirmationToken to delete the folder.",
+            "confirmationToken": approvals.issue_token(fingerprint),
+            "expiresInSecs": approvals.ttl().as_secs(),
+            "summary": {
+                "path": path,
+                "files": subtree.files.len(),
+                "folders": subtree.dirs.len(),
+                "bytes": bytes,
+                "paths": subtree.files.iter().take(SUMMARY_PATHS).collect::<Vec<_>>()
+            }
+        }));
+    }
+
+    trash.record_delete(&user);
+    let task_store = store.clone();
+    let (removed, error) = match web::block(move || remove_tree(&*task_store, &subtree)).await {
+        Ok(result) => result,
+        Err(e) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Delete task failed: {}", e) })),
+    };
+    let id = random_token()[..16].to_string();
+    let bytes = removed.iter().map(|(_, data)| data.len()).sum();
+    let files = removed.len();
+    log::info!("{} deleted {} ({} files, {} bytes) from {}", user, path, files, bytes, root);
+    let event = Event::new("write", "delete_tree", format!("Deleted {} ({} files)", path, files)).files(removed.iter().map(|(p, _)| p.clone()).collect());
+    activity.record(&root, session.as_deref(), event.failed(error.is_some()));
+    if files > 0 {
+        trash.push(Entry { id: id.clone(), root, path, user, deleted: Utc::now(), files: removed, bytes });
+    }
+    let trash_id = (files > 0).then_some(id);
+    match error {
+        None => HttpResponse::Ok().json(json!({ "success": true, "trashId": trash_id, "deletedFiles": files, "bytes": bytes })),
+        Some(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": e, "trashId": trash_id, "deletedFiles": files })),
+    }
+}
+
+#[get("/api/trash")]
+pub async fn list_trash(query: web::Query<TrashQuery>, stores: Stores, trash: web::Data<Trash>) -> HttpResponse {
+    let root = match stores.open_root(&query.directory_path) {
+        Ok(s) => s.display_path(""),
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid directory path: {}", e) })),
+    };
+    let entries: Vec<_> = trash
+        .lock()
+        .iter()
+        .rev()
+        .filter(|e| e.root == root)
+        .map(|e| {
+            json!({
+                "trashId": e.id,
+                "path": e.path,
+                "user": e.user,
+                "deleted": e.deleted.to_rfc3339_opts(SecondsFormat::Secs, true),
+                "files": e.files.len(),
+                "bytes": e.bytes
+            })
+        })
+        .collect();
+    HttpResponse::Ok().json(json!({ "success": true, "entries": entries }))
+}
+
+#[post("/api/trash/restore")]
+pub async fn restore(
+    body: ValidJson<RestoreRequest>,
+    stores: Stores,
+    session: Option<web::ReqData<Session>>,
+    trash: web::Data<Trash>,
+    activity: web::Data<Activity>,
+) -> HttpResponse {
+    let entry = {
+        let mut entries = trash.lock();
+        entries.iter().position(|e| e.id == body.trash_id).and_then(|i| entries.remove(i))
+    };
+    let Some(entry) = entry else {
+        return HttpResponse::NotFound().json(json!({ "success": false, "error": format!("No trash entry {}; it may have been dropped", body.trash_id) }));
+    };
+    let store = match stores.open_root(&entry.root) {
+        Ok(s) => s,
+        Err(e) => {
+            let root = entry.root.clone();
+            trash.push(entry);
+            return HttpResponse::Forbidden().json(json!({ "success": false, "error": format!("Cannot open {}: {}", root, e) }));
+        }
+    };
+    let overwrite = body.overwrite;
+    let (root, path) = (entry.root.clone(), entry.path.clone());
+    let task_store = store.clone();
+    let result = web::block(move || {
+        let mut restored = Vec::new();
+        let mut conflicts = Vec::new();
+        let mut errors = Vec::new();
+        let mut kept = Vec::new();
+        for (path, data) in entry.files {
+            if !overwrite && task_store.exists(&path) {
+                conflicts.push(path.clone());
+                kept.push((path, data));
+                continue;
+            }
+            match task_store.write(&path, &data) {
+                Ok(()) => restored.push(path),
+                Err(e) => {
+                    errors.push(json!({ "path": path, "error": e.to_string() }));
+                    kept.push((path, data));
+                }
+            }
+        }
+        let entry = Entry { bytes: kept.iter().map(|(_, d)| d.len()).sum(), files: kept, ..entry };
+        (restored, conflicts, errors, entry)
+    })
+    .await;
+    let (restored, conflicts, errors, entry) = match result {
+        Ok(result) => result,
+        Err(e) => return HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("Restore task failed: {}", e) })),
+    };
+    let failed = !conflicts.is_empty() || !errors.is_empty();
+    let event = Event::new("write", "restore", format!("Restored {} ({} files)", path, restored.len())).files(restored.clone());
+    activity.record(&root, session.as_deref(), event.failed(failed));
+    // Whatever could not be restored stays in the trash for another attempt
+    let remaining = (!entry.files.is_empty()).then(|| entry.id.clone());
+    if remaining.is_some() {
+        trash.push(entry);
+    }
+    HttpResponse::Ok().json(json!({
+        "success": !failed,
+        "restored": restored,
+        "conflicts": conflicts,
+        "errors": errors,
+        "trashId": remaining
+    }))
+}
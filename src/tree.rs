@@ -0,0 +1,775 @@
+//! Directory tree listing over a [`FileStore`], honouring `.gitignore` files.
+
+use crate::archive;
+use crate::images::{self, ImageInfo};
+use crate::longlines;
+use crate::stats::detect_language;
+use crate::store::{join_path, FileStore};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::SystemTime;
+
+#[derive(Serialize, Debug)]
+pub struct TreeNode {
+    #[serde(rename = "type")]
+    pub node_type: String,
+    pub path: String,
+    pub children: Option<HashMap<String, TreeNode>>,
+    /// File size, or for folders the total size of the files below it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Number of files below a folder, at any depth.
+    #[serde(rename = "fileCount", skip_serializing_if = "Option::is_none")]
+    pub file_count: Option<u64>,
+    /// A file's language, see [`file_language`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<&'static str>,
+    /// True for files with minified or generated lines, see
+    /// [`longlines::file_has_long_lines`]; None otherwise.
+    #[serde(rename = "longLines", skip_serializing_if = "Option::is_none")]
+    pub long_lines: Option<bool>,
+    /// An image file's format and dimensions, see [`images::image_info`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<ImageInfo>,
+}
+
+/// Files up to this size whose name says nothing about their language are
+/// read for a shebang or modeline when listing a tree.
+pub const LANGUAGE_SNIFF_BYTES: u64 = 64 * 1024;
+
+/// Detects a listed file's language from its name or, for small files
+/// without a telling name, its content.
+pub fn file_language(store: &dyn FileStore, path: &str, size: Option<u64>) -> Option<&'static str> {
+    detect_language(path, b"").or_else(|| {
+        if size.is_some_and(|s| s <= LANGUAGE_SNIFF_BYTES) {
+            store.read(path).ok().and_then(|content| detect_language(path, &content))
+        } else {
+            None
+        }
+    })
+}
+
+/// The format and dimensions of a listed image file, from its first
+/// [`images::HEADER_BYTES`].
+pub fn file_image(store: &dyn FileStore, path: &str) -> Option<ImageInfo> {
+    if !images::is_image(path) {
+        return None;
+    }
+    store.read_head(path, images::HEADER_BYTES).ok().and_then(|data| images::image_info(&data))
+}
+
+/// Returns the number of files and their total size in a tree.
+pub fn tree_totals(tree: &HashMap<String, TreeNode>) -> (u64, u64) {
+    tree.values().fold((0, 0), |(count, size), node| match node.children {
+        Some(_) => (count + node.file_count.unwrap_or(0), size + node.size.unwrap_or(0)),
+        None => (count + 1, size + node.size.unwrap_or(0)),
+    })
+}
+
+/// Fields of a [`TreeNode`] that a client can pick with `fields=`.
+pub const NODE_FIELDS: &[&str] = &["type", "path", "children", "size", "fileCount", "language", "longLines", "image"];
+
+/// Parses a comma-separated `fields=` list of `allowed` names.
+pub fn parse_fields(list: &str, allowed: &[&str]) -> Result<Vec<String>, String> {
+    let mut fields: Vec<String> = Vec::new();
+    for field in list.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+        if !allowed.contains(&field) {
+            return Err(format!("Unknown field {}; expected some of {}", field, allowed.join(", ")));
+        }
+        if !fields.iter().any(|f| f == field) {
+            fields.push(field.to_string());
+        }
+    }
+    if fields.is_empty() {
+        return Err("fields is empty".to_string());
+    }
+    Ok(fields)
+}
+
+/// A tree with only the node `fields`. Without `children` it is flattened
+/// to its files in tree order, and with `path` alone to a list of their
+/// paths.
+pub fn project_tree(tree: &HashMap<String, TreeNode>, fields: &[String]) -> serde_json::Value {
+    if fields.iter().any(|f| f == "children") {
+        return serde_json::Value::Object(project_children(tree, fields));
+    }
+    let mut files = Vec::new();
+    flatten(tree, &mut files);
+    let paths_only = fields.len() == 1 && fields[0] == "path";
+    files
+        .into_iter()
+        .map(|node| match paths_only {
+            true => serde_json::Value::String(node.path.clone()),
+            false => serde_json::Value::Object(project_node(node, fields)),
+        })
+        .collect()
+}
+
+fn project_children(tree: &HashMap<String, TreeNode>, fields: &[String]) -> serde_json::Map<String, serde_json::Value> {
+    tree.iter().map(|(name, node)| (name.clone(), serde_json::Value::Object(project_node(node, fields)))).collect()
+}
+
+fn project_node(node: &TreeNode, fields: &[String]) -> serde_json::Map<String, serde_json::Value> {
+    let mut object = serde_json::Map::new();
+    for field in fields {
+        let value = match field.as_str() {
+            "type" => serde_json::json!(node.node_type),
+            "path" => serde_json::json!(node.path),
+            "children" => node.children.as_ref().map_or(serde_json::Value::Null, |c| serde_json::Value::Object(project_children(c, fields))),
+            "size" => serde_json::json!(node.size),
+            "fileCount" => serde_json::json!(node.file_count),
+            "language" => serde_json::json!(node.language),
+            "longLines" => serde_json::json!(node.long_lines),
+            "image" => serde_json::json!(node.image),
+            _ => continue,
+        };
+        // Like the full node, which leaves out unset sizes, counts, languages and flags
+        if !value.is_null() || field == "children" {
+            object.insert(field.clone(), value);
+        }
+    }
+    object
+}
+
+fn flatten<'a>(tree: &'a HashMap<String, TreeNode>, files: &mut Vec<&'a TreeNode>) {
+    let mut names: Vec<&String> = tree.keys().collect();
+    names.sort_by(|a, b| natural_compare(a, b));
+    for name in names {
+        let node = &tree[name];
+        match &node.children {
+            Some(children) => flatten(children, files),
+            None => files.push(node),
+        }
+    }
+}
+
+/// Files and bytes of one language in a tree.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LanguageFiles {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// What a tree says about its workspace without reading any file again.
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+pub struct TreeSummary {
+    /// By [`TreeNode::language`]; files without one are left out.
+    pub languages: BTreeMap<String, LanguageFiles>,
+    /// Paths of the `.gitignore` files in the tree, in tree order.
+    #[serde(rename = "ignoreFiles")]
+    pub ignore_files: Vec<String>,
+}
+
+/// Sums up the languages and ignore files of a tree.
+pub fn summarize_tree(tree: &HashMap<String, TreeNode>) -> TreeSummary {
+    fn visit(tree: &HashMap<String, TreeNode>, summary: &mut TreeSummary) {
+        let mut names: Vec<&String> = tree.keys().collect();
+        names.sort_by(|a, b| natural_compare(a, b));
+        for name in names {
+            let node = &tree[name];
+            match &node.children {
+                Some(children) => visit(children, summary),
+                None => {
+                    if name == ".gitignore" {
+                        summary.ignore_files.push(node.path.clone());
+                    }
+                    if let Some(language) = node.language {
+                        let entry = summary.languages.entry(language.to_string()).or_default();
+                        entry.files += 1;
+                        entry.bytes += node.size.unwrap_or(0);
+                    }
+                }
+            }
+        }
+    }
+    let mut summary = TreeSummary::default();
+    visit(tree, &mut summary);
+    summary
+}
+
+/// Combines the trees of several roots (display path and tree) into one,
+/// with a folder per root named after its last path component. Roots with
+/// the same name are told apart as `name-2`, `name-3`, ...; the names are
+/// returned in the order of `roots`.
+pub fn merge_roots(roots: Vec<(String, HashMap<String, TreeNode>)>) -> (HashMap<String, TreeNode>, Vec<String>) {
+    let mut merged = HashMap::new();
+    let mut names = Vec::new();
+    for (root, children) in roots {
+        let base = root.trim_end_matches(['/', '\\']).rsplit(['/', '\\']).next().filter(|n| !n.is_empty()).unwrap_or("root").to_string();
+        let name = (1..).map(|n| if n == 1 { base.clone() } else { format!("{}-{}", base, n) }).find(|n| !merged.contains_key(n)).unwrap_or(base);
+        let (file_count, size) = tree_totals(&children);
+        merged.insert(
+            name.clone(),
+            TreeNode {
+                node_type: "folder".to_string(),
+                path: root,
+                children: Some(children),
+                size: Some(size),
+                file_count: Some(file_count),
+                language: None,
+                long_lines: None,
+                image: None,
+            },
+        );
+        names.push(name);
+    }
+    (merged, names)
+}
+
+/// Compiles a list of globs, `None` when the list is empty.
+pub fn glob_set(patterns: &[String]) -> Result<Option<GlobSet>, String> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).map_err(|e| format!("Invalid glob {}: {}", pattern, e))?);
+    }
+    builder.build().map(Some).map_err(|e| format!("Invalid globs: {}", e))
+}
+
+/// Include and exclude globs over store paths, such as a saved profile of
+/// the folders someone works on.
+#[derive(Debug, Clone)]
+pub struct PathFilter {
+    /// `None` allows every path.
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, String> {
+        Ok(PathFilter { include: glob_set(include)?, exclude: glob_set(exclude)? })
+    }
+
+    /// Whether a file is in.
+    pub fn allows(&self, path: &str) -> bool {
+        self.include.as_ref().is_none_or(|globs| globs.is_match(path)) && !self.excludes(path)
+    }
+
+    /// Whether a path (a folder too) is excluded outright.
+    pub fn excludes(&self, path: &str) -> bool {
+        self.exclude.as_ref().is_some_and(|globs| globs.is_match(path))
+    }
+}
+
+/// Restricts which files [`build_filtered_tree`] includes. Folders left
+/// without any matching file are dropped like empty ones.
+#[derive(Debug, Clone)]
+pub struct TreeFilter {
+    /// Lowercase extensions without the dot; `None` allows every file.
+    pub extensions: Option<Vec<String>>,
+    /// Largest file size in bytes to include.
+    pub max_size: Option<u64>,
+    /// Whether to include files and folders whose name starts with a dot.
+    pub include_hidden: bool,
+    /// Globs files must pass; excluded folders are not walked.
+    pub paths: Option<PathFilter>,
+    /// Whether archives are listed as `archive` nodes with their entries
+    /// as children, see [`archive_children`].
+    pub archives: bool,
+}
+
+impl Default for TreeFilter {
+    fn default() -> Self {
+        TreeFilter { extensions: None, max_size: None, include_hidden: true, paths: None, archives: false }
+    }
+}
+
+impl TreeFilter {
+    /// Parses a comma-separated extension list such as `rs,.toml`.
+    pub fn parse_extensions(list: &str) -> Vec<String> {
+        list.split(',')
+            .map(|e| e.trim().trim_start_matches('.').to_lowercase())
+            .filter(|e| !e.is_empty())
+            .collect()
+    }
+
+    fn allows_name(&self, name: &str) -> bool {
+        self.include_hidden || !name.starts_with('.')
+    }
+
+    fn allows_file(&self, path: &str, size: Option<u64>) -> bool {
+        if self.paths.as_ref().is_some_and(|p| !p.allows(path)) {
+            return false;
+        }
+        let name = path.rsplit('/').next().unwrap_or(path);
+        if let Some(extensions) = &self.extensions {
+            let extension = name.rsplit_once('.').map(|(_, e)| e.to_lowercase());
+            if !extension.is_some_and(|e| extensions.contains(&e)) {
+                return false;
+            }
+        }
+        match self.max_size {
+            Some(max) => size.is_some_and(|size| size <= max),
+            None => true,
+        }
+    }
+}
+
+// Orders names with their runs of digits compared as numbers, so `file9`
+// comes before `file10`, as alphanumeric-sort did. That crate's comparison
+// isn't a total order for names such as git's hex object folders, which the
+// standard sort may panic on, so runs with the same number go by their
+// leading zeros here (`1` before `01`).
+fn natural_compare(a: &str, b: &str) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+    let digits = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (mut x, mut y) = (a, b);
+    loop {
+        match (x.chars().next(), y.chars().next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(c), Some(d)) if c.is_ascii_digit() && d.is_ascii_digit() => {
+                let (run_x, rest_x) = x.split_at(digits(x));
+                let (run_y, rest_y) = y.split_at(digits(y));
+                let (number_x, number_y) = (run_x.trim_start_matches('0'), run_y.trim_start_matches('0'));
+                let order = number_x.len().cmp(&number_y.len()).then_with(|| number_x.cmp(number_y)).then_with(|| run_x.len().cmp(&run_y.len()));
+                if order != Ordering::Equal {
+                    return order;
+                }
+                (x, y) = (rest_x, rest_y);
+            }
+            (Some(c), Some(d)) => {
+                if c != d {
+                    return c.cmp(&d);
+                }
+                (x, y) = (&x[c.len_utf8()..], &y[d.len_utf8()..]);
+            }
+        }
+    }
+}
+
+/// Loads the `.gitignore` in directory `dir` of the store, if there is one.
+pub fn load_gitignore(store: &dyn FileStore, dir: &str) -> Option<Gitignore> {
+    let ig_path = join_path(dir, ".gitignore");
+    let content = store.read(&ig_path).ok()?;
+    let mut builder = GitignoreBuilder::new(dir);
+    for line in String::from_utf8_lossy(&content).lines() {
+        if let Err(e) = builder.add_line(None, line) {
+            log::debug!("Ignoring invalid pattern in {}: {}", ig_path, e);
+        }
+    }
+    match builder.build() {
+        Ok(ig) => Some(ig),
+        Err(e) => {
+            log::warn!("Failed to build gitignore from {}: {}", ig_path, e);
+            None
+        }
+    }
+}
+
+/// Builds the tree below `dir`. A directory's own `.gitignore` replaces the
+/// inherited rules; empty directories are left out.
+pub fn build_tree(store: &dyn FileStore, dir: &str, ig: &Gitignore) -> Result<HashMap<String, TreeNode>, String> {
+    build_filtered_tree(store, dir, ig, &TreeFilter::default())
+}
+
+/// Bounds on a directory scan, so that listing `/` or a tree of
+/// `node_modules` folders returns a partial tree instead of running on.
+/// 0 turns a limit off.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TreeLimits {
+    /// Levels of folders below the scanned one that are walked.
+    pub max_depth: usize,
+    /// Entries looked at in the whole scan.
+    pub max_entries: usize,
+    /// Entries listed per folder, in listing order.
+    pub max_dir_entries: usize,
+}
+
+impl Default for TreeLimits {
+    fn default() -> Self {
+        TreeLimits { max_depth: 64, max_entries: 500_000, max_dir_entries: 50_000 }
+    }
+}
+
+/// A folder left out of a tree, or only partly listed, and why.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TreeWarning {
+    /// Display path of the folder.
+    pub path: String,
+    pub reason: String,
+}
+
+/// What a directory scan may do and what it left out.
+#[derive(Debug, Default)]
+pub struct TreeScan {
+    pub limits: TreeLimits,
+    /// Folders that couldn't be listed or were cut short, the first
+    /// [`MAX_TREE_WARNINGS`] of them.
+    pub warnings: Vec<TreeWarning>,
+    /// Set when a limit left entries out.
+    pub truncated: bool,
+    entries: usize,
+    /// [`FileStore::file_id`] and display path of the folders being walked.
+    ancestors: Vec<((u64, u64), String)>,
+}
+
+pub const MAX_TREE_WARNINGS: usize = 100;
+
+impl TreeScan {
+    pub fn new(limits: TreeLimits) -> Self {
+        TreeScan { limits, ..TreeScan::default() }
+    }
+
+    fn warn(&mut self, path: String, reason: String) {
+        if self.warnings.len() < MAX_TREE_WARNINGS {
+            self.warnings.push(TreeWarning { path, reason });
+        }
+    }
+
+    fn truncate(&mut self, path: String, reason: String) {
+        self.truncated = true;
+        self.warn(path, reason);
+    }
+}
+
+/// Like [`build_tree`], keeping only files accepted by `filter`. Hidden
+/// folders are skipped without being walked.
+pub fn build_filtered_tree(
+    store: &dyn FileStore,
+    dir: &str,
+    ig: &Gitignore,
+    filter: &TreeFilter,
+) -> Result<HashMap<String, TreeNode>, String> {
+    build_reported_tree(store, dir, ig, filter, &mut TreeScan::default())
+}
+
+/// Like [`build_filtered_tree`], within the limits of `scan`. Folders below
+/// `dir` that couldn't be listed, such as those without read permission, and
+/// those the limits cut short are added to its warnings; only `dir` itself
+/// failing is an error.
+pub fn build_reported_tree(
+    store: &dyn FileStore,
+    dir: &str,
+    ig: &Gitignore,
+    filter: &TreeFilter,
+    scan: &mut TreeScan,
+) -> Result<HashMap<String, TreeNode>, String> {
+    let root = store.file_id(dir).map(|id| (id, store.display_path(dir)));
+    scan.ancestors.extend(root);
+    let tree = build_level(store, dir, ig, filter, 0, scan);
+    scan.ancestors.clear();
+    tree
+}
+
+fn build_level(
+    store: &dyn FileStore,
+    dir: &str,
+    ig: &Gitignore,
+    filter: &TreeFilter,
+    depth: usize,
+    scan: &mut TreeScan,
+) -> Result<HashMap<String, TreeNode>, String> {
+    let mut tree = HashMap::new();
+    let entries = store.list_dir(dir).map_err(|e| format!("Failed to read directory: {}", e))?;
+    let mut dirents = Vec::new();
+
+    for entry in entries {
+        let entry_path = join_path(dir, &entry.name);
+        if !filter.allows_name(&entry.name) || ig.matched(&entry_path, entry.is_dir).is_ignore() {
+            continue;
+        }
+        if entry.is_dir && filter.paths.as_ref().is_some_and(|p| p.excludes(&entry_path)) {
+            continue;
+        }
+        dirents.push(entry);
+    }
+
+    dirents.sort_by(|a, b| {
+        if a.is_dir && !b.is_dir {
+            std::cmp::Ordering::Less
+        } else if !a.is_dir && b.is_dir {
+            std::cmp::Ordering::Greater
+        } else {
+            natural_compare(&a.name, &b.name)
+        }
+    });
+    let max_dir_entries = scan.limits.max_dir_entries;
+    if max_dir_entries > 0 && dirents.len() > max_dir_entries {
+        scan.truncate(store.display_path(dir), format!("Listed the first {} of {} entries", max_dir_entries, dirents.len()));
+        dirents.truncate(max_dir_entries);
+    }
+
+    for dirent in dirents {
+        let entry_path = join_path(dir, &dirent.name);
+        let entry_path_str = store.display_path(&entry_path);
+        if scan.limits.max_entries > 0 && scan.entries >= scan.limits.max_entries {
+            // Only the first entry over the limit is reported
+            if scan.entries == scan.limits.max_entries {
+                scan.truncate(entry_path_str, format!("Stopped here after {} entries", scan.limits.max_entries));
+                scan.entries += 1;
+            }
+            break;
+        }
+        scan.entries += 1;
+        if dirent.is_dir {
+            if scan.limits.max_depth > 0 && depth >= scan.limits.max_depth {
+                scan.truncate(entry_path_str, format!("Below the depth limit of {}", scan.limits.max_depth));
+                continue;
+            }
+            // A link or bind mount back to a folder above would recurse forever
+            let id = store.file_id(&entry_path);
+            if let Some((_, ancestor)) = id.and_then(|id| scan.ancestors.iter().find(|(a, _)| *a == id)) {
+                let reason = format!("Loops back to {}", ancestor);
+                log::warn!("Skipping directory {}: {}", entry_path_str, reason);
+                scan.warn(entry_path_str, reason);
+                continue;
+            }
+            let sub_ig = load_gitignore(store, &entry_path).unwrap_or_else(|| ig.clone());
+            scan.ancestors.extend(id.map(|id| (id, entry_path_str.clone())));
+            let children = build_level(store, &entry_path, &sub_ig, filter, depth + 1, scan);
+            if id.is_some() {
+                scan.ancestors.pop();
+            }
+            match children {
+                Ok(children) => {
+                    if !children.is_empty() {
+                        let (file_count, size) = tree_totals(&children);
+                        tree.insert(
+                            dirent.name,
+                            TreeNode {
+                                node_type: "folder".to_string(),
+                                path: entry_path_str,
+                                children: Some(children),
+                                size: Some(size),
+                                file_count: Some(file_count),
+                                language: None,
+                                long_lines: None,
+                                image: None,
+                            },
+                        );
+                    }
+                }
+                Err(e) => {
+                    log::warn!("Skipping directory {}: {}", entry_path_str, e);
+                    let reason = e.strip_prefix("Failed to read directory: ").unwrap_or(&e).to_string();
+                    scan.warn(entry_path_str, reason);
+                }
+            }
+        } else {
+            let size = store.file_size(&entry_path);
+            if filter.archives && archive::archive_kind(&entry_path).is_some() {
+                if let Some(children) = archive_children(store, &entry_path, size, filter, scan) {
+                    let (file_count, _) = tree_totals(&children);
+                    let node = TreeNode {
+                        node_type: "archive".to_string(),
+                        path: entry_path_str,
+                        children: Some(children),
+                        size,
+                        file_count: Some(file_count),
+                        language: None,
+                        long_lines: None,
+                        image: None,
+                    };
+                    tree.insert(dirent.name, node);
+                    continue;
+                }
+            }
+            if !filter.allows_file(&entry_path, size) {
+                continue;
+            }
+            tree.insert(
+                dirent.name,
+                TreeNode {
+                    node_type: "file".to_string(),
+                    path: entry_path_str,
+                    children: None,
+                    size,
+                    file_count: None,
+                    language: file_language(store, &entry_path, size),
+                    long_lines: longlines::file_has_long_lines(store, &entry_path, size).then_some(true),
+                    image: file_image(store, &entry_path),
+                },
+            );
+        }
+    }
+    Ok(tree)
+}
+
+/// Entries of an archive listed at most, in archive order.
+pub const MAX_ARCHIVE_ENTRIES: usize = 10_000;
+
+/// The files of the archive at `path` as a tree of virtual nodes, with
+/// paths of the form `archive!entry` that an [`archive::ArchiveStore`]
+/// reads. None for archives that can't be opened and those without an entry
+/// `filter` accepts; the reason for the former goes to `scan`'s warnings.
+pub fn archive_children(store: &dyn FileStore, path: &str, size: Option<u64>, filter: &TreeFilter, scan: &mut TreeScan) -> Option<HashMap<String, TreeNode>> {
+    let display = store.display_path(path);
+    let kind = archive::archive_kind(path)?;
+    if size.is_none_or(|s| s > archive::MAX_ARCHIVE_BYTES) {
+        scan.warn(display, format!("Archives are listed up to {} MiB", archive::MAX_ARCHIVE_BYTES / 1024 / 1024));
+        return None;
+    }
+    let entries = store.read(path).map_err(|e| e.to_string()).and_then(|data| archive::entries(kind, &data));
+    let mut entries = match entries {
+        Ok(entries) => entries,
+        Err(e) => {
+            scan.warn(display, e);
+            return None;
+        }
+    };
+    entries.retain(|e| !e.is_dir);
+    if entries.len() > MAX_ARCHIVE_ENTRIES {
+        scan.truncate(display.clone(), format!("Listed the first {} of {} entries", MAX_ARCHIVE_ENTRIES, entries.len()));
+        entries.truncate(MAX_ARCHIVE_ENTRIES);
+    }
+    let mut tree = HashMap::new();
+    for entry in entries {
+        let parts: Vec<&str> = entry.path.split('/').collect();
+        let entry_path = format!("{}!{}", path, entry.path);
+        if parts.iter().all(|p| filter.allows_name(p)) && filter.allows_file(&entry_path, Some(entry.size)) {
+            add_entry(&mut tree, &display, &parts, 0, entry.size);
+        }
+    }
+    fill_totals(&mut tree);
+    (!tree.is_empty()).then_some(tree)
+}
+
+fn add_entry(tree: &mut HashMap<String, TreeNode>, archive: &str, parts: &[&str], depth: usize, size: u64) {
+    let path = format!("{}!{}", archive, parts[..=depth].join("/"));
+    let node = |node_type: &str, children, size, language| TreeNode {
+        node_type: node_type.to_string(),
+        path: path.clone(),
+        children,
+        size,
+        file_count: None,
+        language,
+        long_lines: None,
+        image: None,
+    };
+    if depth + 1 == parts.len() {
+        tree.insert(parts[depth].to_string(), node("file", None, Some(size), detect_language(parts[depth], b"")));
+        return;
+    }
+    let folder = tree.entry(parts[depth].to_string()).or_insert_with(|| node("folder", Some(HashMap::new()), None, None));
+    if let Some(children) = folder.children.as_mut() {
+        add_entry(children, archive, parts, depth + 1, size);
+    }
+}
+
+fn fill_totals(tree: &mut HashMap<String, TreeNode>) {
+    for node in tree.values_mut() {
+        if let Some(children) = node.children.as_mut() {
+            fill_totals(children);
+            let (file_count, size) = tree_totals(children);
+            (node.file_count, node.size) = (Some(file_count), Some(size));
+        }
+    }
+}
+
+/// Version control metadata folders, which [`walk_files`] never enters.
+pub const VCS_DIRS: &[&str] = &[".git", ".hg", ".svn", ".jj"];
+
+/// Calls `visit` with the store path of every file below `dir` that is not
+/// ignored or inside a [`VCS_DIRS`] folder.
+pub fn walk_files(store: &dyn FileStore, dir: &str, ig: &Gitignore, visit: &mut dyn FnMut(&str)) -> Result<(), String> {
+    let mut ancestors: Vec<(u64, u64)> = store.file_id(dir).into_iter().collect();
+    walk_level(store, dir, ig, visit, &mut ancestors)
+}
+
+fn walk_level(store: &dyn FileStore, dir: &str, ig: &Gitignore, visit: &mut dyn FnMut(&str), ancestors: &mut Vec<(u64, u64)>) -> Result<(), String> {
+    let mut entries = store.list_dir(dir).map_err(|e| format!("Failed to read directory: {}", e))?;
+    entries.sort_by(|a, b| natural_compare(&a.name, &b.name));
+    for entry in entries {
+        let entry_path = join_path(dir, &entry.name);
+        if ig.matched(&entry_path, entry.is_dir).is_ignore() || (entry.is_dir && VCS_DIRS.contains(&entry.name.as_str())) {
+            continue;
+        }
+        if entry.is_dir {
+            let id = store.file_id(&entry_path);
+            if id.is_some_and(|id| ancestors.contains(&id)) {
+                log::warn!("Skipping directory {}: it loops back to a folder above it", store.display_path(&entry_path));
+                continue;
+            }
+            let sub_ig = load_gitignore(store, &entry_path).unwrap_or_else(|| ig.clone());
+            ancestors.extend(id);
+            if let Err(e) = walk_level(store, &entry_path, &sub_ig, visit, ancestors) {
+                log::warn!("Skipping directory {}: {}", store.display_path(&entry_path), e);
+            }
+            if id.is_some() {
+                ancestors.pop();
+            }
+        } else {
+            visit(&entry_path);
+        }
+    }
+    Ok(())
+}
+
+/// Everything below a directory, as [`collect_subtree`] finds it.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Subtree {
+    pub files: Vec<String>,
+    /// Folders deepest first, ending with the directory itself, so removing
+    /// them in order only ever removes empty ones.
+    pub dirs: Vec<String>,
+    /// Symbolic links, which are neither followed nor counted as files.
+    pub symlinks: Vec<String>,
+    /// Set when the walk stopped after `max_files` files.
+    pub truncated: bool,
+}
+
+/// Lists every file and folder below `dir`, ignored and version control
+/// ones included, stopping once more than `max_files` files are found. A
+/// folder mounted inside itself is an error.
+pub fn collect_subtree(store: &dyn FileStore, dir: &str, max_files: usize) -> Result<Subtree, String> {
+    fn visit(store: &dyn FileStore, dir: &str, max_files: usize, tree: &mut Subtree, ancestors: &mut Vec<(u64, u64)>) -> Result<(), String> {
+        let id = store.file_id(dir);
+        if id.is_some_and(|id| ancestors.contains(&id)) {
+            return Err(format!("Folder {} loops back to a folder above it", dir));
+        }
+        ancestors.extend(id);
+        let mut entries = store.list_dir(dir).map_err(|e| format!("Failed to read directory {}: {}", dir, e))?;
+        entries.sort_by(|a, b| natural_compare(&a.name, &b.name));
+        for entry in entries {
+            if tree.truncated {
+                return Ok(());
+            }
+            let entry_path = join_path(dir, &entry.name);
+            if entry.is_symlink {
+                tree.symlinks.push(entry_path);
+            } else if entry.is_dir {
+                visit(store, &entry_path, max_files, tree, ancestors)?;
+            } else if tree.files.len() == max_files {
+                tree.truncated = true;
+            } else {
+                tree.files.push(entry_path);
+            }
+        }
+        tree.dirs.push(dir.to_string());
+        if id.is_some() {
+            ancestors.pop();
+        }
+        Ok(())
+    }
+    let mut tree = Subtree::default();
+    visit(store, dir, max_files, &mut tree, &mut Vec::new())?;
+    Ok(tree)
+}
+
+/// Returns up to `limit` files below `dir` modified at or after `since`,
+/// most recently modified first. Files without a modification time are
+/// left out.
+pub fn recent_files(
+    store: &dyn FileStore,
+    dir: &str,
+    ig: &Gitignore,
+    since: Option<SystemTime>,
+    limit: usize,
+) -> Result<Vec<(String, SystemTime)>, String> {
+    let mut files = Vec::new();
+    walk_files(store, dir, ig, &mut |path| {
+        if let Some(modified) = store.modified(path).filter(|m| since.is_none_or(|since| *m >= since)) {
+            files.push((path.to_string(), modified));
+        }
+    })?;
+    files.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    files.truncate(limit);
+    Ok(files)
+}
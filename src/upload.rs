@@ -0,0 +1,164 @@
+// Chunked patch uploads, for patches bigger than the request body limit of a
+// proxy in front of the server (whole-file rewrites of large files add up).
+// The client starts an upload, appends the patch text in chunks and finishes
+// it, then sends the uploadId to /api/apply_patch instead of patchContent.
+//
+//   POST /api/patch_upload/start  {"totalBytes": 12345678}
+//   POST /api/patch_upload/append {"uploadId": "...", "offset": 0, "chunk": "--- a/..."}
+//   POST /api/patch_upload/finish {"uploadId": "..."}
+//
+// `offset` is the number of bytes received so far, so a retried chunk is
+// rejected instead of being appended twice. Uploads are kept in memory and
+// dropped after UPLOAD_TTL without activity or once the patch is applied.
+// Their text counts against MEMORY_LIMIT_MB, see memory.rs.
+
+use crate::auth::random_token;
+use crate::memory::{MemoryBudget, Pool, Reservation};
+use crate::validation::ValidJson;
+use actix_web::{post, web, HttpResponse};
+use repopatch::patch::PatchSet;
+use repopatch::policy;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_MAX_UPLOAD_BYTES: usize = 256 * 1024 * 1024;
+const UPLOAD_TTL: Duration = Duration::from_secs(60 * 60);
+const MAX_UPLOADS: usize = 64;
+
+struct Upload {
+    data: Arc<String>,
+    total_bytes: Option<usize>,
+    finished: bool,
+    touched: Instant,
+    memory: Reservation,
+}
+
+pub struct Uploads {
+    max_bytes: usize,
+    budget: Arc<MemoryBudget>,
+    entries: Mutex<HashMap<String, Upload>>,
+}
+
+#[derive(Deserialize)]
+pub struct StartRequest {
+    /// Size of the whole patch in bytes, checked when the upload finishes.
+    #[serde(rename = "totalBytes")]
+    total_bytes: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct AppendRequest {
+    #[serde(rename = "uploadId")]
+    upload_id: String,
+    offset: usize,
+    chunk: String,
+}
+
+#[derive(Deserialize)]
+pub struct FinishRequest {
+    #[serde(rename = "uploadId")]
+    upload_id: String,
+}
+
+impl Uploads {
+    pub fn new(max_bytes: usize, budget: Arc<MemoryBudget>) -> Self {
+        Uploads { max_bytes, budget, entries: Mutex::default() }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Upload>> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.retain(|_, u| u.touched.elapsed() < UPLOAD_TTL);
+        entries
+    }
+
+    /// The patch text of a finished upload.
+    pub fn get(&self, id: &str) -> Result<Arc<String>, String> {
+        match self.lock().get_mut(id) {
+            Some(upload) if upload.finished => {
+                upload.touched = Instant::now();
+                Ok(upload.data.clone())
+            }
+            Some(_) => Err(format!("Upload {} is not finished", id)),
+            None => Err(format!("No upload {}; it may have expired", id)),
+        }
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.lock().remove(id);
+    }
+}
+
+#[post("/api/patch_upload/start")]
+pub async fn start(body: ValidJson<StartRequest>, uploads: web::Data<Uploads>) -> HttpResponse {
+    if body.total_bytes.is_some_and(|total| total > uploads.max_bytes) {
+        return HttpResponse::PayloadTooLarge()
+            .json(json!({ "success": false, "error": format!("Patches are limited to {} bytes", uploads.max_bytes), "maxBytes": uploads.max_bytes }));
+    }
+    let mut entries = uploads.lock();
+    if entries.len() >= MAX_UPLOADS {
+        return HttpResponse::TooManyRequests().json(json!({ "success": false, "error": "Too many uploads in progress; finish or wait for one to expire" }));
+    }
+    let memory = match uploads.budget.reserve(Pool::Patches, 0) {
+        Ok(memory) => memory,
+        Err(overloaded) => return overloaded.response(),
+    };
+    let id = random_token();
+    let upload = Upload { data: Arc::default(), total_bytes: body.total_bytes, finished: false, touched: Instant::now(), memory };
+    entries.insert(id.clone(), upload);
+    HttpResponse::Ok().json(json!({ "success": true, "uploadId": id, "maxBytes": uploads.max_bytes }))
+}
+
+#[post("/api/patch_upload/append")]
+pub async fn append(body: ValidJson<AppendRequest>, uploads: web::Data<Uploads>) -> HttpResponse {
+    let max_bytes = uploads.max_bytes;
+    let mut entries = uploads.lock();
+    let Some(upload) = entries.get_mut(&body.upload_id) else {
+        return HttpResponse::NotFound().json(json!({ "success": false, "error": format!("No upload {}; it may have expired", body.upload_id) }));
+    };
+    if upload.finished {
+        return HttpResponse::Conflict().json(json!({ "success": false, "error": "The upload is already finished" }));
+    }
+    let received = upload.data.len();
+    if body.offset != received {
+        return HttpResponse::Conflict().json(json!({
+            "success": false,
+            "code": "OFFSET_MISMATCH",
+            "error": format!("Expected the chunk at offset {}", received),
+            "received": received
+        }));
+    }
+    if received + body.chunk.len() > max_bytes {
+        return HttpResponse::PayloadTooLarge().json(json!({ "success": false, "error": format!("Patches are limited to {} bytes", max_bytes), "maxBytes": max_bytes }));
+    }
+    if let Err(overloaded) = upload.memory.grow(body.chunk.len() as u64) {
+        return overloaded.response();
+    }
+    Arc::make_mut(&mut upload.data).push_str(&body.chunk);
+    upload.touched = Instant::now();
+    HttpResponse::Ok().json(json!({ "success": true, "received": upload.data.len() }))
+}
+
+#[post("/api/patch_upload/finish")]
+pub async fn finish(body: ValidJson<FinishRequest>, uploads: web::Data<Uploads>) -> HttpResponse {
+    let mut entries = uploads.lock();
+    let Some(upload) = entries.get_mut(&body.upload_id) else {
+        return HttpResponse::NotFound().json(json!({ "success": false, "error": format!("No upload {}; it may have expired", body.upload_id) }));
+    };
+    let received = upload.data.len();
+    if let Some(total) = upload.total_bytes.filter(|total| *total != received) {
+        return HttpResponse::Conflict().json(json!({
+            "success": false,
+            "code": "INCOMPLETE_UPLOAD",
+            "error": format!("Received {} of {} bytes", received, total),
+            "received": received
+        }));
+    }
+    upload.finished = true;
+    upload.touched = Instant::now();
+    let summary = policy::summarize(&PatchSet::parse(&upload.data, 1));
+    log::info!("Finished upload {} ({} bytes, {} files)", body.upload_id, received, summary.files);
+    HttpResponse::Ok().json(json!({ "success": true, "uploadId": body.upload_id, "bytes": received, "summary": summary }))
+}
@@ -0,0 +1,417 @@
+// Usage accounting: bytes read and written through the stores and patches
+// applied, per identity (the signed-in user, or `anonymous` without sign-in),
+// so one runaway agent can't take a shared instance's disk and I/O for
+// itself. Counts are kept in memory, for the current window of
+// USAGE_WINDOW_SECS (default a day) and since the server started.
+//
+// Quotas cap the window's counts. QUOTA_READ_MB, QUOTA_WRITE_MB and
+// QUOTA_PATCHES apply to every identity; `[[quotas]]` entries in
+// repopatch.toml override them for one user:
+//
+//   [[quotas]]
+//   user = "ci-agent"
+//   write_mb = 500
+//   patches = 200
+//
+// An identity past its read quota gets 429 from every endpoint reading
+// files; past its write or patch quota, writes and patches are refused until
+// the window ends. GET /api/usage reports the caller's usage; admins may add
+// all=true for everyone's.
+
+use crate::auth::Session;
+use actix_web::{get, web, HttpResponse};
+use chrono::{DateTime, SecondsFormat, Utc};
+use repopatch::store::{DirEntry, FileStore, StoreProvider};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+const DEFAULT_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Identity of requests without sign-in.
+pub const ANONYMOUS: &str = "anonymous";
+
+/// A `[[quotas]]` entry of the config file.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaConfig {
+    pub user: String,
+    pub read_mb: Option<u64>,
+    pub write_mb: Option<u64>,
+    pub patches: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    #[serde(rename = "bytesRead")]
+    pub bytes_read: Option<u64>,
+    #[serde(rename = "bytesWritten")]
+    pub bytes_written: Option<u64>,
+    #[serde(rename = "patchesApplied")]
+    pub patches_applied: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counters {
+    #[serde(rename = "bytesRead")]
+    pub bytes_read: u64,
+    #[serde(rename = "bytesWritten")]
+    pub bytes_written: u64,
+    #[serde(rename = "patchesApplied")]
+    pub patches_applied: u64,
+}
+
+struct Account {
+    started: DateTime<Utc>,
+    window: Counters,
+    total: Counters,
+}
+
+/// A quota an identity has used up.
+pub struct Exceeded {
+    pub message: String,
+    pub retry_after: u64,
+}
+
+impl Exceeded {
+    pub fn response(&self) -> HttpResponse {
+        HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", self.retry_after.to_string()))
+            .json(json!({ "success": false, "code": "QUOTA_EXCEEDED", "error": self.message, "retryAfterSecs": self.retry_after }))
+    }
+}
+
+pub struct Usage {
+    window: Duration,
+    defaults: Limits,
+    users: HashMap<String, Limits>,
+    accounts: Mutex<HashMap<String, Account>>,
+}
+
+fn mb(value: Option<u64>) -> Option<u64> {
+    value.map(|mb| mb.saturating_mul(1024 * 1024))
+}
+
+fn env_number(name: &str) -> Result<Option<u64>, String> {
+    match env::var(name) {
+        Ok(v) if !v.is_empty() => v.parse().map(Some).map_err(|_| format!("{} must be a number, not {}", name, v)),
+        _ => Ok(None),
+    }
+}
+
+impl Usage {
+    pub fn from_env(quotas: &[QuotaConfig]) -> Result<Self, String> {
+        let window = env_number("USAGE_WINDOW_SECS")?.filter(|&s| s > 0).map_or(DEFAULT_WINDOW, Duration::from_secs);
+        let defaults = Limits {
+            bytes_read: mb(env_number("QUOTA_READ_MB")?),
+            bytes_written: mb(env_number("QUOTA_WRITE_MB")?),
+            patches_applied: env_number("QUOTA_PATCHES")?,
+        };
+        let mut users = HashMap::new();
+        for quota in quotas {
+            let limits = Limits {
+                bytes_read: mb(quota.read_mb).or(defaults.bytes_read),
+                bytes_written: mb(quota.write_mb).or(defaults.bytes_written),
+                patches_applied: quota.patches.or(defaults.patches_applied),
+            };
+            if users.insert(quota.user.clone(), limits).is_some() {
+                return Err(format!("Quotas for {} are configured twice", quota.user));
+            }
+        }
+        Ok(Usage { window, defaults, users, accounts: Mutex::default() })
+    }
+
+    pub fn has_quotas(&self) -> bool {
+        self.defaults != Limits::default() || !self.users.is_empty()
+    }
+
+    pub fn limits(&self, user: &str) -> Limits {
+        self.users.get(user).copied().unwrap_or(self.defaults)
+    }
+
+    // The account of `user`, starting a new window when the last one ended
+    fn with_account<T>(&self, user: &str, f: impl FnOnce(&mut Account) -> T) -> T {
+        let mut accounts = self.accounts.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Utc::now();
+        let account = accounts.entry(user.to_string()).or_insert_with(|| Account { started: now, window: Counters::default(), total: Counters::default() });
+        if (now - account.started).to_std().unwrap_or_default() >= self.window {
+            account.started = now;
+            account.window = Counters::default();
+        }
+        f(account)
+    }
+
+    fn exceeded(&self, account: &Account, message: String) -> Exceeded {
+        let ends = account.started + chrono::Duration::from_std(self.window).unwrap_or_default();
+        let retry_after = (ends - Utc::now()).num_seconds().max(1) as u64;
+        Exceeded { message: format!("{}; it resets in {} seconds", message, retry_after), retry_after }
+    }
+
+    /// Whether `user` may read more.
+    pub fn check_read(&self, user: &str) -> Result<(), Exceeded> {
+        let Some(limit) = self.limits(user).bytes_read else {
+            return Ok(());
+        };
+        self.with_account(user, |account| {
+            if account.window.bytes_read < limit {
+                return Ok(());
+            }
+            Err(self.exceeded(account, format!("{} has used the read quota of {} bytes", user, limit)))
+        })
+    }
+
+    /// Whether `user` may write `bytes` more; with 0, whether any is left.
+    pub fn check_write(&self, user: &str, bytes: u64) -> Result<(), Exceeded> {
+        let Some(limit) = self.limits(user).bytes_written else {
+            return Ok(());
+        };
+        self.with_account(user, |account| {
+            if account.window.bytes_written < limit && account.window.bytes_written.saturating_add(bytes) <= limit {
+                return Ok(());
+            }
+            Err(self.exceeded(account, format!("{} has used the write quota of {} bytes", user, limit)))
+        })
+    }
+
+    /// Whether `user` may apply another patch.
+    pub fn check_patch(&self, user: &str) -> Result<(), Exceeded> {
+        let Some(limit) = self.limits(user).patches_applied else {
+            return Ok(());
+        };
+        self.with_account(user, |account| {
+            if account.window.patches_applied < limit {
+                return Ok(());
+            }
+            Err(self.exceeded(account, format!("{} has applied the quota of {} patches", user, limit)))
+        })
+    }
+
+    pub fn add(&self, user: &str, counted: Counters) {
+        self.with_account(user, |account| {
+            for counters in [&mut account.window, &mut account.total] {
+                counters.bytes_read += counted.bytes_read;
+                counters.bytes_written += counted.bytes_written;
+                counters.patches_applied += counted.patches_applied;
+            }
+        });
+    }
+
+    fn report(&self, user: &str) -> serde_json::Value {
+        let limits = self.limits(user);
+        self.with_account(user, |account| {
+            let ends = account.started + chrono::Duration::from_std(self.window).unwrap_or_default();
+            json!({
+                "user": user,
+                "windowStart": account.started.to_rfc3339_opts(SecondsFormat::Secs, true),
+                "windowEnd": ends.to_rfc3339_opts(SecondsFormat::Secs, true),
+                "window": account.window,
+                "total": account.total,
+                "limits": limits,
+            })
+        })
+    }
+}
+
+/// The identity usage is counted under.
+pub fn identity(session: Option<&Session>) -> String {
+    session.map_or_else(|| ANONYMOUS.to_string(), |s| s.user.clone())
+}
+
+/// A store counting what `user` reads and writes through it, and refusing
+/// writes past their quota.
+pub struct MeteredStore {
+    inner: Arc<dyn FileStore>,
+    usage: Arc<Usage>,
+    user: String,
+}
+
+fn quota_error(exceeded: Exceeded) -> io::Error {
+    io::Error::new(io::ErrorKind::QuotaExceeded, exceeded.message)
+}
+
+impl FileStore for MeteredStore {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.usage.check_read(&self.user).map_err(quota_error)?;
+        let data = self.inner.read(path)?;
+        self.usage.add(&self.user, Counters { bytes_read: data.len() as u64, ..Counters::default() });
+        Ok(data)
+    }
+
+    fn read_head(&self, path: &str, max: usize) -> io::Result<Vec<u8>> {
+        self.usage.check_read(&self.user).map_err(quota_error)?;
+        let data = self.inner.read_head(path, max)?;
+        self.usage.add(&self.user, Counters { bytes_read: data.len() as u64, ..Counters::default() });
+        Ok(data)
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.usage.check_write(&self.user, data.len() as u64).map_err(quota_error)?;
+        self.inner.write(path, data)?;
+        self.usage.add(&self.user, Counters { bytes_written: data.len() as u64, ..Counters::default() });
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        self.inner.remove(path)
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        self.inner.remove_dir(path)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn list_dir(&self, path: &str) -> io::Result<Vec<DirEntry>> {
+        self.inner.list_dir(path)
+    }
+
+    fn display_path(&self, path: &str) -> String {
+        self.inner.display_path(path)
+    }
+
+    fn validate(&self, path: &str) -> Result<(), String> {
+        self.inner.validate(path)
+    }
+
+    fn check_writable(&self, path: &str) -> Result<(), String> {
+        self.inner.check_writable(path)
+    }
+
+    fn file_size(&self, path: &str) -> Option<u64> {
+        self.inner.file_size(path)
+    }
+
+    fn modified(&self, path: &str) -> Option<SystemTime> {
+        self.inner.modified(path)
+    }
+
+    fn available_space(&self, path: &str) -> Option<u64> {
+        self.inner.available_space(path)
+    }
+
+    fn file_id(&self, path: &str) -> Option<(u64, u64)> {
+        self.inner.file_id(path)
+    }
+
+    // Counted as read in full; past the read quota the file is left to
+    // read(), which refuses it
+    fn local_path(&self, path: &str) -> Option<PathBuf> {
+        self.usage.check_read(&self.user).ok()?;
+        let local = self.inner.local_path(path)?;
+        let size = self.inner.file_size(path).unwrap_or(0);
+        self.usage.add(&self.user, Counters { bytes_read: size, ..Counters::default() });
+        Some(local)
+    }
+}
+
+/// Opens every store of another provider as a [`MeteredStore`] of `user`.
+pub struct MeteredStoreProvider {
+    inner: Arc<dyn StoreProvider>,
+    usage: Arc<Usage>,
+    user: String,
+}
+
+impl MeteredStoreProvider {
+    pub fn new(inner: Arc<dyn StoreProvider>, usage: Arc<Usage>, user: String) -> Self {
+        MeteredStoreProvider { inner, usage, user }
+    }
+
+    fn meter(&self, store: Arc<dyn FileStore>) -> Arc<dyn FileStore> {
+        Arc::new(MeteredStore { inner: store, usage: self.usage.clone(), user: self.user.clone() })
+    }
+}
+
+impl StoreProvider for MeteredStoreProvider {
+    fn open_root(&self, root: &str) -> Result<Arc<dyn FileStore>, String> {
+        Ok(self.meter(self.inner.open_root(root)?))
+    }
+
+    fn open_file(&self, path: &str) -> Result<(Arc<dyn FileStore>, String), String> {
+        let (store, name) = self.inner.open_file(path)?;
+        Ok((self.meter(store), name))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UsageQuery {
+    #[serde(default)]
+    all: bool,
+}
+
+#[get("/api/usage")]
+pub async fn get_usage(query: web::Query<UsageQuery>, session: Option<web::ReqData<Session>>, usage: web::Data<Usage>) -> HttpResponse {
+    if !query.all {
+        let mut report = usage.report(&identity(session.as_deref()));
+        report["success"] = json!(true);
+        return HttpResponse::Ok().json(report);
+    }
+    if !session.as_ref().is_some_and(|s| s.admin) {
+        return HttpResponse::Forbidden().json(json!({ "success": false, "error": "Admin access required" }));
+    }
+    let mut users: Vec<String> = usage.accounts.lock().unwrap_or_else(|e| e.into_inner()).keys().cloned().collect();
+    users.sort();
+    let reports: Vec<_> = users.iter().map(|user| usage.report(user)).collect();
+    HttpResponse::Ok().json(json!({ "success": true, "users": reports }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::{App, HttpMessage};
+    use repopatch::store::MemoryStore;
+
+    fn usage(defaults: Limits, users: &[(&str, Limits)]) -> Arc<Usage> {
+        let users = users.iter().map(|(user, limits)| (user.to_string(), *limits)).collect();
+        Arc::new(Usage { window: DEFAULT_WINDOW, defaults, users, accounts: Mutex::default() })
+    }
+
+    #[test]
+    fn writes_and_patches_stop_at_the_quota() {
+        let usage = usage(Limits { bytes_written: Some(10), ..Limits::default() }, &[("ci", Limits { patches_applied: Some(1), ..Limits::default() })]);
+        let store = MeteredStore { inner: Arc::new(MemoryStore::new()), usage: usage.clone(), user: "alice".to_string() };
+
+        store.write("a.txt", b"123456").unwrap();
+        assert_eq!(store.write("b.txt", b"123456").unwrap_err().kind(), io::ErrorKind::QuotaExceeded);
+        assert!(!store.exists("b.txt"));
+        store.write("c.txt", b"1234").unwrap();
+        assert!(usage.check_write("alice", 0).is_err());
+        assert_eq!(store.read("a.txt").unwrap(), b"123456");
+
+        // Quotas of one user leave the others alone
+        assert!(usage.check_write("bob", 10).is_ok());
+        assert!(usage.check_patch("ci").is_ok());
+        usage.add("ci", Counters { patches_applied: 1, ..Counters::default() });
+        let exceeded = usage.check_patch("ci").err().unwrap();
+        assert!(exceeded.retry_after > 0 && exceeded.retry_after <= DEFAULT_WINDOW.as_secs());
+        assert!(usage.check_patch("alice").is_ok());
+    }
+
+    #[actix_web::test]
+    async fn only_admins_see_everyones_usage() {
+        let usage = usage(Limits::default(), &[]);
+        usage.add("alice", Counters { bytes_read: 5, ..Counters::default() });
+        let app = init_service(App::new().app_data(web::Data::from(usage)).service(get_usage)).await;
+
+        let request = |admin: bool| {
+            let request = TestRequest::get().uri("/api/usage?all=true").to_request();
+            request.extensions_mut().insert(Session::for_test(if admin { "root" } else { "alice" }, admin, false));
+            request
+        };
+        assert_eq!(call_service(&app, request(false)).await.status(), 403);
+        let report: serde_json::Value = call_and_read_body_json(&app, request(true)).await;
+        assert_eq!(report["users"][0]["user"], "alice");
+        assert_eq!(report["users"][0]["window"]["bytesRead"], 5);
+
+        let own = TestRequest::get().uri("/api/usage").to_request();
+        let report: serde_json::Value = call_and_read_body_json(&app, own).await;
+        assert_eq!(report["user"], ANONYMOUS);
+    }
+}
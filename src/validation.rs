@@ -0,0 +1,276 @@
+// Turns actix's terse extractor failures into JSON errors naming the
+// offending field and showing an example of a valid request.
+
+use actix_web::dev::Payload;
+use actix_web::error::{InternalError, JsonPayloadError, QueryPayloadError};
+use actix_web::{web, Error, FromRequest, HttpRequest, HttpResponse};
+use futures::future::LocalBoxFuture;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::ops::Deref;
+
+/// Example request for each endpoint taking a JSON body or query string.
+fn example_for(path: &str) -> Option<Value> {
+    let example = match path.trim_end_matches('/') {
+        "/api/directory" => json!({
+            "query": "?path=/home/me/project&extensions=rs,toml&maxSize=200000&includeHidden=false",
+            "roots": "?roots=/home/me/project,/home/me/other instead of path lists both in one tree",
+            "profile": "&profile=backend applies a saved /api/profiles selection",
+            "fields": "&fields=path lists only file paths; &fields=path,children,size keeps the nesting",
+            "archives": "&archives=true lists the entries of ZIP and tar archives, read as /api/file?path=.../fixtures.zip!data/a.json"
+        }),
+        "/api/workspace_info" => json!({ "query": "?path=/home/me/project" }),
+        "/api/stats" => json!({ "query": "?path=/home/me/project/src" }),
+        "/api/duplicates" => json!({ "query": "?path=/home/me/project" }),
+        "/api/recent_files" => json!({ "query": "?path=/home/me/project&since=24h&limit=50" }),
+        "/api/preview" => json!({ "query": "?path=/home/me/project/src/main.rs&line=42&context=3" }),
+        "/api/deps" => json!({ "query": "?path=/home/me/project&dir=src&file=src/main.rs" }),
+        "/api/related" => json!({ "query": "?path=/home/me/project/src/main.rs&root=/home/me/project&limit=20" }),
+        "/api/semantic_search" => json!({ "query": "?path=/home/me/project&q=where%20are%20patches%20applied&limit=10" }),
+        "/api/extract" => json!({ "query": "?path=/home/me/project/src/tree.rs&symbol=build_tree&context=0" }),
+        "/api/export_patch" => json!({ "query": "?reportId=3f2a9c1d0b7e4a65&subject=Fix%20parser&author=Me%20%3Cme@example.com%3E" }),
+        "/api/file" => json!({ "query": "?path=/home/me/project/src/main.rs&numbered=false&blame=false&longLines=elide&lineWidth=200&notebook=raw&fields=content,revision" }),
+        "/api/table_preview" => json!({ "query": "?path=/home/me/project/data/sales.csv&rows=100" }),
+        "/api/files" => json!({
+            "paths": ["/home/me/project/src/main.rs", "/home/me/project/Cargo.toml"],
+            "numbered": false,
+            "profile": "optional: refuse files outside this saved profile",
+            "fields": ["content"]
+        }),
+        "/api/pack" => json!({
+            "directoryPath": "/home/me/project",
+            "include": ["src/**", "Cargo.toml"],
+            "exclude": ["src/generated"],
+            "maxTokens": 100000,
+            "longLines": "optional: wrap or elide",
+            "lineWidth": 200
+        }),
+        "/api/check_writable" => json!({ "directoryPath": "/home/me/project", "recheck": false }),
+        "/api/checkpoint" => json!({ "directoryPath": "/home/me/project" }),
+        "/api/apply_patch" => json!({
+            "directoryPath": "/home/me/project",
+            "patchContent": "--- a/src/main.rs\n+++ b/src/main.rs\n@@ -1 +1 @@\n-old\n+new\n",
+            "contextLines": 20,
+            "uploadId": "optional: a finished /api/patch_upload, instead of patchContent",
+            "ifRevision": { "src/main.rs": "optional: the revision /api/file returned" },
+            "virtualFiles": { "optional/path.rs": "content to patch instead of the file on disk\n" },
+            "chatId": "optional: the /api/chats conversation the patch came from",
+            "messageId": "optional: the message in that chat",
+            "engine": "optional: dmp, strict, git, dmp-text or search-replace",
+            "patchFormat": "optional: auto to pick the engine from the patch"
+        }),
+        "/api/apply_patches" => json!({
+            "patches": {
+                "frontend": { "patchContent": "--- a/src/api.ts\n+++ b/src/api.ts\n@@ -1 +1 @@\n-old\n+new\n" },
+                "backend": { "patchContent": "--- a/src/main.rs\n+++ b/src/main.rs\n@@ -1 +1 @@\n-old\n+new\n", "engine": "optional", "contextLines": 20, "ifRevision": {} }
+            },
+            "dryRun": false
+        }),
+        "/api/replace" => json!({
+            "directoryPath": "/home/me/project",
+            "pattern": "\\bold_name\\(",
+            "replacement": "new_name(",
+            "include": ["src/**/*.rs"],
+            "planId": "optional: from the dry run, to apply"
+        }),
+        "/api/refactor/rename_symbol" => json!({
+            "directoryPath": "/home/me/project",
+            "file": "src/lib.rs",
+            "symbol": "old_name",
+            "newName": "new_name",
+            "scope": "project | file | function",
+            "line": 42
+        }),
+        "/api/move" => json!({
+            "directoryPath": "/home/me/project",
+            "from": "src/util/math.ts",
+            "to": "src/lib/math.ts",
+            "updateReferences": true
+        }),
+        "/api/impact" => json!({
+            "directoryPath": "/home/me/project",
+            "patchContent": "--- a/src/patch.rs\n+++ b/src/patch.rs\n@@ -1 +1 @@\n-old\n+new\n",
+            "maxDepth": 3
+        }),
+        "/api/diagnostics" => json!({ "query": "?path=/home/me/project&package=web&checker=tsc" }),
+        "/api/lsp/definition" | "/api/lsp/hover" | "/api/lsp/references" => json!({
+            "query": "?path=/home/me/project/src/main.rs&line=42&column=17",
+            "root": "&root=/home/me/project picks the language server's workspace",
+            "includeDeclaration": "&includeDeclaration=false leaves the declaration out of references"
+        }),
+        "/api/tests" => json!({ "query": "?path=/home/me/project&package=web&framework=jest" }),
+        "/api/tests/run" => json!({
+            "directoryPath": "/home/me/project",
+            "framework": "cargo",
+            "tests": ["tree::tests::builds_nested_tree"],
+            "package": "optional: run in this monorepo package"
+        }),
+        "/api/plan_context" => json!({
+            "directoryPath": "/home/me/project",
+            "budget": 32000,
+            "files": ["src/patch.rs"],
+            "question": "optional: why do fuzzy hunks match the wrong place?",
+            "includeContent": false
+        }),
+        "/api/scaffold" => json!({
+            "directoryPath": "/home/me/project",
+            "template": "rust-module",
+            "variables": { "name": "rate_limit", "parent": "src/lib.rs" }
+        }),
+        "/api/diff_view" => json!({
+            "directoryPath": "/home/me/project",
+            "patchContent": "--- a/src/main.rs\n+++ b/src/main.rs\n@@ -1 +1 @@\n-old\n+new\n",
+            "reportId": "optional: instead of directoryPath and patchContent",
+            "context": 3
+        }),
+        "/api/import_patch" => json!({
+            "url": "https://github.com/owner/repo/pull/42",
+            "directoryPath": "optional: /home/me/project, to dry-run the patch there"
+        }),
+        "/api/pr/create" => json!({
+            "reportId": "3f2a9c1d0b7e4a65",
+            "title": "optional: defaults to the changed files",
+            "body": "optional: defaults to the diffstat",
+            "branch": "optional: defaults to repopatch/<reportId>",
+            "base": "optional: defaults to the checked out branch",
+            "draft": false
+        }),
+        "/api/reviews" => json!({
+            "directoryPath": "/home/me/project",
+            "patchContent": "--- a/src/main.rs\n+++ b/src/main.rs\n@@ -1 +1 @@\n-old\n+new\n",
+            "patchId": "optional: instead of patchContent",
+            "file": "src/main.rs",
+            "hunk": 0,
+            "line": 1,
+            "body": "Should this keep the old name for now?",
+            "query": "GET ?patchId=3f2a9c1d0b7e4a65 lists the comments"
+        }),
+        "/api/reviews/resolve" => json!({ "patchId": "3f2a9c1d0b7e4a65", "commentId": 1, "resolved": true }),
+        "/api/patch_upload/start" => json!({ "totalBytes": 12345678 }),
+        "/api/patch_upload/append" => json!({ "uploadId": "from /api/patch_upload/start", "offset": 0, "chunk": "--- a/src/main.rs\n..." }),
+        "/api/patch_upload/finish" => json!({ "uploadId": "from /api/patch_upload/start" }),
+        "/api/chats" => json!({
+            "chatId": "optional: adds to an existing chat",
+            "title": "Fix the tree walker",
+            "messages": [
+                { "id": "optional", "role": "user", "content": "Why does the walker follow symlink loops?" },
+                { "role": "assistant", "content": "Here is a patch..." }
+            ],
+            "query": "GET lists your chats; GET ?chatId=... returns one with its patches"
+        }),
+        "/api/chats/provenance" => json!({ "query": "?reportId=3f2a9c1d0b7e4a65 or ?path=/home/me/project/src/tree.rs" }),
+        "/api/profiles" => json!({
+            "directoryPath": "/home/me/project",
+            "name": "backend only",
+            "include": ["server/**", "Cargo.toml"],
+            "exclude": ["server/fixtures/**"],
+            "query": "GET ?path=/home/me/project lists the profiles; DELETE ?path=...&name=... removes one"
+        }),
+        "/api/activity" => json!({ "query": "?path=/home/me/project&kind=patch,vcs&limit=50&before=120" }),
+        "/api/usage" => json!({ "query": "?all=true" }),
+        "/api/messages" => json!({ "query": "?lang=de" }),
+        "/api/settings" => json!({ "theme": "dark", "stripLevel": 1, "fuzz": 2, "lastRoot": "/home/me/project", "ui": { "fontSize": 14 } }),
+        "/api/delete_tree" => json!({
+            "directoryPath": "/home/me/project",
+            "path": "src/old_module",
+            "confirmationToken": "optional: from the CONFIRMATION_REQUIRED response, to delete"
+        }),
+        "/api/thumbnail" => json!({ "query": "?path=/home/me/project/docs/logo.png&size=128" }),
+        "/api/trash" => json!({ "query": "?directoryPath=/home/me/project" }),
+        "/api/trash/restore" => json!({ "trashId": "3f2a9c1d0b7e4a65", "overwrite": false }),
+        "/api/format" => json!({ "directoryPath": "/home/me/project", "paths": ["src/main.rs"], "write": false }),
+        "/api/instances" => json!({ "name": "laptop", "url": "http://10.0.0.2:3000", "token": "optional" }),
+        _ => return None,
+    };
+    Some(example)
+}
+
+// Helper function to pull the field name out of serde's "missing field `x`" style messages
+fn field_from_message(message: &str) -> Option<String> {
+    let start = message.find('`')? + 1;
+    let end = start + message[start..].find('`')?;
+    let mentions_field = message.contains("missing field") || message.contains("unknown field") || message.contains("duplicate field");
+    mentions_field.then(|| message[start..end].to_string())
+}
+
+fn bad_request(req: &HttpRequest, error: String, field: Option<String>) -> HttpResponse {
+    log::debug!("Rejected request to {}: {}", req.path(), error);
+    HttpResponse::BadRequest().json(json!({
+        "success": false,
+        "error": error,
+        "field": field,
+        "example": example_for(req.path())
+    }))
+}
+
+pub fn json_config(limit: usize) -> web::JsonConfig {
+    web::JsonConfig::default().limit(limit).error_handler(|err, req| {
+        let (error, field) = match &err {
+            JsonPayloadError::Deserialize(e) if e.is_data() => {
+                let message = e.to_string();
+                let field = field_from_message(&message);
+                (format!("Invalid request body: {}", message), field)
+            }
+            JsonPayloadError::Deserialize(e) => (format!("Malformed JSON: {}", e), None),
+            JsonPayloadError::ContentType => ("Content-Type must be application/json".to_string(), None),
+            other => (format!("Invalid request body: {}", other), None),
+        };
+        let response = bad_request(req, error, field);
+        InternalError::from_response(err, response).into()
+    })
+}
+
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default().error_handler(|err, req| {
+        let message = match &err {
+            QueryPayloadError::Deserialize(e) => e.to_string(),
+            other => other.to_string(),
+        };
+        let field = field_from_message(&message);
+        let response = bad_request(req, format!("Invalid query string: {}", message), field);
+        InternalError::from_response(err, response).into()
+    })
+}
+
+/// A JSON body like [`web::Json`], but type errors report the path of the
+/// offending field (e.g. `paths[2]`) instead of a line and column.
+pub struct ValidJson<T>(pub T);
+
+impl<T> ValidJson<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for ValidJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for ValidJson<T> {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        // Syntax and Content-Type errors are reported by json_config()
+        let value = web::Json::<Value>::from_request(&req, payload);
+        Box::pin(async move {
+            let value = value.await?.into_inner();
+            serde_path_to_error::deserialize(value).map(ValidJson).map_err(|e| {
+                let message = e.inner().to_string();
+                let path = e.path().to_string();
+                let field = if path == "." { field_from_message(&message) } else { Some(path) };
+                let error = match &field {
+                    Some(field) => format!("Invalid request body: {} (field {})", message, field),
+                    None => format!("Invalid request body: {}", message),
+                };
+                InternalError::from_response(e, bad_request(&req, error, field)).into()
+            })
+        })
+    }
+}
+
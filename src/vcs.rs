@@ -0,0 +1,717 @@
+// Version control behind one interface, so status, diffs, checkpoints and blame
+// work in git and Mercurial repositories and in plain folders alike.
+//
+// A workspace uses the innermost git (`.git`) or Mercurial (`.hg`)
+// repository holding it, through the `git` or `hg` binary. Outside a
+// repository, when the binary is missing, or for stores that aren't folders
+// on disk, it falls back to snapshots the server takes itself.
+//
+// Status lists the changes since the last commit, or since the last
+// checkpoint in a plain folder. Diffs compare with the last checkpoint, or
+// the last commit without one. A checkpoint records the workspace as it is
+// now: in git a commit under refs/repopatch/checkpoints that leaves HEAD,
+// the index and the working tree alone; elsewhere a snapshot of the files
+// kept in memory, at most CHECKPOINT_MAX_MB (default 64) per workspace.
+
+use crate::activity::{Activity, Event};
+use crate::auth::{Session, Stores};
+use crate::forge;
+use crate::memory::CacheSize;
+use crate::validation::ValidJson;
+use actix_web::{get, post, web, HttpResponse};
+use ignore::gitignore::Gitignore;
+use repopatch::diff::{git_diff, FileChange};
+use repopatch::patch::revision;
+use repopatch::related::{parse_co_changes, CoChanges};
+use repopatch::store::FileStore;
+use repopatch::tree::{load_gitignore, walk_files};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+/// Commits searched for files changed together with a file.
+const HISTORY_COMMITS: &str = "500";
+const DEFAULT_CHECKPOINT_MB: usize = 64;
+/// Workspaces with a snapshot checkpoint; the oldest is dropped beyond this.
+const MAX_SNAPSHOTS: usize = 50;
+const CHECKPOINT_REFS: &str = "refs/repopatch/checkpoints";
+
+/// A changed file, with its path relative to the workspace.
+#[derive(Serialize, Clone, Debug)]
+pub struct Change {
+    pub path: String,
+    /// `added`, `modified` or `deleted`.
+    pub status: &'static str,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Checkpoint {
+    pub id: String,
+    #[serde(rename = "createdAt")]
+    pub created_at: String,
+}
+
+/// A git-style diff and what it compares with.
+#[derive(Serialize, Debug)]
+pub struct Diff {
+    /// `checkpoint` or `commit`.
+    pub base: &'static str,
+    pub diff: String,
+}
+
+/// Who last changed each line of a file, as arrays indexed by line.
+#[derive(Serialize, Debug, Default)]
+pub struct Blame {
+    /// Abbreviated commit, null for lines not committed yet.
+    pub commit: Vec<Option<String>>,
+    pub author: Vec<Option<String>>,
+    /// When the commit was authored, RFC 3339.
+    pub date: Vec<Option<String>>,
+}
+
+impl Blame {
+    fn push(&mut self, commit: Option<String>, author: Option<String>, date: Option<String>) {
+        self.commit.push(commit);
+        self.author.push(author);
+        self.date.push(date);
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Status {
+    /// `git`, `hg` or `none`.
+    pub kind: &'static str,
+    /// The repository root, or the workspace itself for `none`.
+    pub root: String,
+    pub branch: Option<String>,
+    pub commit: Option<String>,
+    pub checkpoint: Option<Checkpoint>,
+    /// Unknown in a plain folder without a checkpoint.
+    pub dirty: Option<bool>,
+    pub changes: Vec<Change>,
+}
+
+pub trait Vcs: Send + Sync {
+    /// The workspace's changes since the last commit, or since the last
+    /// checkpoint without version control.
+    fn status(&self) -> Result<Status, String>;
+
+    /// A git-style diff of `path` (the whole workspace when empty) from the
+    /// last checkpoint, or the last commit without one.
+    fn diff(&self, path: &str) -> Result<Diff, String>;
+
+    /// Records the workspace as it is now for later diffs.
+    fn checkpoint(&self) -> Result<Checkpoint, String>;
+
+    /// How often files changed together with `path` in recent history, None
+    /// without history.
+    fn co_changes(&self, _path: &str) -> Option<CoChanges> {
+        None
+    }
+
+    /// Who last changed each line of `path` as it is on disk, None without
+    /// history.
+    fn blame(&self, _path: &str) -> Option<Result<Blame, String>> {
+        None
+    }
+}
+
+/// The version control of the store's workspace.
+pub fn open(store: Arc<dyn FileStore>, checkpoints: Arc<Checkpoints>) -> Box<dyn Vcs> {
+    let plain = Plain { root: store.display_path(""), store, checkpoints };
+    let dir = PathBuf::from(&plain.root);
+    if !dir.is_absolute() || !dir.is_dir() {
+        return Box::new(plain);
+    }
+    match repository_root(&dir).map(|root| root.join(".git").exists()) {
+        Some(true) => match Git::open(&dir) {
+            Ok(git) => return Box::new(git),
+            Err(e) => log::debug!("Using snapshots for {}: {}", dir.display(), e),
+        },
+        Some(false) => match hg(&dir, &["root"]) {
+            Ok(root) => return Box::new(Hg { dir, root, plain }),
+            Err(e) => log::debug!("Using snapshots for {}: {}", dir.display(), e),
+        },
+        None => {}
+    }
+    Box::new(plain)
+}
+
+/// The innermost folder from `dir` up holding a git or Mercurial
+/// repository.
+pub fn repository_root(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors().find(|d| d.join(".git").exists() || d.join(".hg").is_dir()).map(Path::to_path_buf)
+}
+
+// The display path of `path` below the workspace at `prefix` (relative to
+// the repository root, "" or ending in `/`), None outside it
+fn in_workspace<'a>(prefix: &str, path: &'a str) -> Option<&'a str> {
+    path.strip_prefix(prefix).filter(|p| !p.is_empty())
+}
+
+struct Git {
+    dir: PathBuf,
+    root: String,
+    /// The workspace relative to the repository root, ending in `/`.
+    prefix: String,
+}
+
+impl Git {
+    fn open(dir: &Path) -> Result<Self, String> {
+        let top = forge::git(dir, &["rev-parse", "--show-toplevel"], &[], None)?;
+        let prefix = forge::git(dir, &["rev-parse", "--show-prefix"], &[], None)?;
+        Ok(Git { dir: dir.to_path_buf(), root: top, prefix })
+    }
+
+    fn git(&self, args: &[&str], envs: &[(&str, &Path)]) -> Result<String, String> {
+        forge::git(&self.dir, args, envs, None)
+    }
+
+    fn checkpoint_ref(&self) -> String {
+        format!("{}/{}", CHECKPOINT_REFS, revision(self.prefix.as_bytes()))
+    }
+
+    // The last checkpoint's commit and time
+    fn last_checkpoint(&self) -> Option<Checkpoint> {
+        let log = self.git(&["log", "-1", "--format=%h%n%cI", &self.checkpoint_ref(), "--"], &[]).ok()?;
+        let (id, created_at) = log.split_once('\n')?;
+        Some(Checkpoint { id: id.to_string(), created_at: created_at.to_string() })
+    }
+
+    // The repository with the workspace as it is now, untracked files
+    // included, as a tree. Written through a copy of the index so the
+    // repository's own stays as it is, and the copy's stat data saves
+    // hashing unchanged files again.
+    fn working_tree(&self) -> Result<String, String> {
+        let index = self.dir.join(self.git(&["rev-parse", "--git-path", "index"], &[])?);
+        let temp = self.dir.join(self.git(&["rev-parse", "--git-path", "repopatch-checkpoint-index"], &[])?);
+        let copied = match fs::copy(&index, &temp) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => fs::remove_file(&temp).or(Ok(())),
+            Err(e) => Err(e),
+        };
+        copied.map_err(|e| format!("Failed to copy the index to {}: {}", temp.display(), e))?;
+        let env = [("GIT_INDEX_FILE", temp.as_path())];
+        let tree = self.git(&["add", "-A", "--", "."], &env).and_then(|_| self.git(&["write-tree"], &env));
+        let _ = fs::remove_file(&temp);
+        tree
+    }
+}
+
+impl Vcs for Git {
+    fn status(&self) -> Result<Status, String> {
+        let porcelain = self.git(&["status", "--porcelain=v2", "-z", "--no-renames", "--untracked-files=all", "--", "."], &[])?;
+        let mut changes = Vec::new();
+        for entry in porcelain.split('\0').filter(|e| !e.is_empty()) {
+            let (xy, path) = match entry.split_at(1) {
+                ("?", rest) => ("A.", rest.trim_start()),
+                ("1", _) => match entry.splitn(9, ' ').collect::<Vec<_>>().as_slice() {
+                    [_, xy, .., path] => (*xy, *path),
+                    _ => continue,
+                },
+                ("u", _) => match entry.splitn(11, ' ').collect::<Vec<_>>().as_slice() {
+                    [_, _, .., path] => ("M.", *path),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            let Some(path) = in_workspace(&self.prefix, path) else {
+                continue;
+            };
+            let status = match xy.as_bytes() {
+                [_, b'D'] | [b'D', b'.'] => "deleted",
+                [b'A', _] => "added",
+                _ => "modified",
+            };
+            changes.push(Change { path: path.to_string(), status });
+        }
+        Ok(Status {
+            kind: "git",
+            root: self.root.clone(),
+            // A detached HEAD has no branch and a new repository no commit
+            branch: self.git(&["symbolic-ref", "--short", "-q", "HEAD"], &[]).ok(),
+            commit: self.git(&["rev-parse", "--short", "HEAD"], &[]).ok(),
+            checkpoint: self.last_checkpoint(),
+            dirty: Some(!changes.is_empty()),
+            changes,
+        })
+    }
+
+    fn diff(&self, path: &str) -> Result<Diff, String> {
+        let checkpoint = self.checkpoint_ref();
+        let has = |r: &str| self.git(&["rev-parse", "--verify", "-q", &format!("{}^{{commit}}", r)], &[]).is_ok();
+        let (base, tree) = if has(&checkpoint) {
+            ("checkpoint", format!("{}^{{tree}}", checkpoint))
+        } else if has("HEAD") {
+            ("commit", "HEAD^{tree}".to_string())
+        } else {
+            // An empty tree stands in for a repository without commits
+            ("commit", self.git(&["hash-object", "-t", "tree", "/dev/null"], &[])?)
+        };
+        let working = self.working_tree()?;
+        let path = if path.is_empty() { "." } else { path };
+        let diff = self.git(&["diff", "--no-color", "--relative", &tree, &working, "--", path], &[])?;
+        Ok(Diff { base, diff: with_newline(diff) })
+    }
+
+    fn checkpoint(&self) -> Result<Checkpoint, String> {
+        let tree = self.working_tree()?;
+        let head = self.git(&["rev-parse", "--verify", "-q", "HEAD"], &[]).ok();
+        let mut args = vec!["-c", "user.name=RepoPatch", "-c", "user.email=repopatch@localhost", "commit-tree", &tree, "-m", "RepoPatch checkpoint"];
+        if let Some(head) = &head {
+            args.extend(["-p", head.as_str()]);
+        }
+        let commit = self.git(&args, &[])?;
+        self.git(&["update-ref", &self.checkpoint_ref(), &commit], &[])?;
+        self.last_checkpoint().ok_or_else(|| format!("Checkpoint {} was not recorded", commit))
+    }
+
+    fn co_changes(&self, path: &str) -> Option<CoChanges> {
+        let log = self.git(&["log", "-n", HISTORY_COMMITS, "--format=%x1e", "--name-only", "--full-diff", "--relative", "--no-renames", "--", path], &[]);
+        log.ok().map(|log| parse_co_changes(&log, path))
+    }
+
+    fn blame(&self, path: &str) -> Option<Result<Blame, String>> {
+        Some(self.git(&["blame", "--porcelain", "--", path], &[]).map(|out| parse_git_blame(&out)))
+    }
+}
+
+// The author of a commit blamed for some lines
+#[derive(Default, Clone, Copy)]
+struct Authored<'a> {
+    author: Option<&'a str>,
+    time: Option<i64>,
+    tz: Option<&'a str>,
+}
+
+// Reads `git blame --porcelain`: a header per line, with the commit's details
+// after the first line it blames
+fn parse_git_blame(out: &str) -> Blame {
+    let mut commits: HashMap<&str, Authored> = HashMap::new();
+    let mut blame = Blame::default();
+    let mut current = "";
+    for line in out.lines() {
+        if line.starts_with('\t') {
+            // Lines not committed yet carry an all-zero id
+            if current.bytes().all(|b| b == b'0') {
+                blame.push(None, None, None);
+                continue;
+            }
+            let authored = commits.get(current).copied().unwrap_or_default();
+            let date = authored.time.zip(authored.tz).and_then(|(time, tz)| format_date(time, tz));
+            blame.push(Some(current[..12].to_string()), authored.author.map(str::to_string), date);
+        } else if let Some((key, value)) = line.split_once(' ') {
+            if key.len() == 40 && key.bytes().all(|b| b.is_ascii_hexdigit()) {
+                current = key;
+                commits.entry(key).or_default();
+            } else if let Some(authored) = commits.get_mut(current) {
+                match key {
+                    "author" => authored.author = Some(value),
+                    "author-time" => authored.time = value.parse().ok(),
+                    "author-tz" => authored.tz = Some(value),
+                    _ => {}
+                }
+            }
+        }
+    }
+    blame
+}
+
+// Unix time and a `+0200` offset as RFC 3339
+fn format_date(time: i64, tz: &str) -> Option<String> {
+    let sign = if tz.starts_with('-') { -1 } else { 1 };
+    let digits = tz.trim_start_matches(['+', '-']);
+    let (hours, minutes) = (digits.get(..2)?.parse::<i32>().ok()?, digits.get(2..4)?.parse::<i32>().ok()?);
+    let offset = chrono::FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))?;
+    Some(chrono::DateTime::from_timestamp(time, 0)?.with_timezone(&offset).to_rfc3339())
+}
+
+// Gives back the newline trimmed from a command's diff
+fn with_newline(mut diff: String) -> String {
+    if !diff.is_empty() {
+        diff.push('\n');
+    }
+    diff
+}
+
+struct Hg {
+    dir: PathBuf,
+    root: String,
+    /// Checkpoints are snapshots, as in plain folders.
+    plain: Plain,
+}
+
+impl Hg {
+    // The workspace relative to the repository root, "" or ending in `/`
+    fn prefix(&self) -> String {
+        match self.dir.strip_prefix(&self.root) {
+            Ok(rel) if !rel.as_os_str().is_empty() => format!("{}/", rel.to_string_lossy()),
+            _ => String::new(),
+        }
+    }
+}
+
+// Runs hg in `dir` without the user's aliases and output settings,
+// returning its trimmed stdout
+fn hg(dir: &Path, args: &[&str]) -> Result<String, String> {
+    let output = Command::new("hg")
+        .current_dir(dir)
+        .env("HGPLAIN", "1")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("Failed to run hg: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("hg {} failed: {}", args.first().unwrap_or(&""), String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+impl Vcs for Hg {
+    fn status(&self) -> Result<Status, String> {
+        // Without patterns hg lists paths relative to the repository root
+        let listed = hg(&self.dir, &["status"])?;
+        let prefix = self.prefix();
+        let changes: Vec<Change> = listed
+            .lines()
+            .filter_map(|line| {
+                let (code, path) = line.split_once(' ')?;
+                let status = match code {
+                    "A" | "?" => "added",
+                    "R" | "!" => "deleted",
+                    _ => "modified",
+                };
+                Some(Change { path: in_workspace(&prefix, path)?.to_string(), status })
+            })
+            .collect();
+        Ok(Status {
+            kind: "hg",
+            root: self.root.clone(),
+            branch: hg(&self.dir, &["branch"]).ok(),
+            // `hg id` marks uncommitted changes with a `+`
+            commit: hg(&self.dir, &["id", "-i"]).ok().map(|id| id.trim_end_matches('+').to_string()),
+            checkpoint: self.plain.checkpoints.get(&self.plain.root),
+            dirty: Some(!changes.is_empty()),
+            changes,
+        })
+    }
+
+    fn diff(&self, path: &str) -> Result<Diff, String> {
+        if self.plain.checkpoints.get(&self.plain.root).is_some() {
+            return self.plain.diff(path);
+        }
+        let prefix = self.prefix();
+        let path = if path.is_empty() { "." } else { path };
+        let mut args = vec!["diff", "--git"];
+        if !prefix.is_empty() {
+            args.extend(["--root", prefix.trim_end_matches('/')]);
+        }
+        args.extend(["--", path]);
+        Ok(Diff { base: "commit", diff: with_newline(hg(&self.dir, &args)?) })
+    }
+
+    fn checkpoint(&self) -> Result<Checkpoint, String> {
+        self.plain.checkpoint()
+    }
+
+    fn co_changes(&self, path: &str) -> Option<CoChanges> {
+        let limit = HISTORY_COMMITS;
+        let log = hg(&self.dir, &["log", "-l", limit, "--template", "\\x1e\\n{join(files, '\\n')}\\n", "--", path]).ok()?;
+        // Files are listed relative to the repository root; keep the
+        // workspace's, relative to it, like git's --relative
+        let prefix = self.prefix();
+        let mut relative = String::new();
+        for line in log.lines() {
+            if line.contains('\x1e') {
+                relative.push_str("\x1e\n");
+            } else if let Some(file) = in_workspace(&prefix, line.trim()) {
+                relative.push_str(file);
+                relative.push('\n');
+            }
+        }
+        Some(parse_co_changes(&relative, path))
+    }
+
+    fn blame(&self, path: &str) -> Option<Result<Blame, String>> {
+        // wdir() annotates the file on disk, uncommitted lines included
+        let annotated = match hg(&self.dir, &["annotate", "-r", "wdir()", "-u", "-c", "-d", "-T", "json", "--", path]) {
+            Ok(out) => out,
+            Err(e) => return Some(Err(e)),
+        };
+        let files: serde_json::Value = match serde_json::from_str(&annotated) {
+            Ok(files) => files,
+            Err(e) => return Some(Err(format!("Unreadable hg annotate output: {}", e))),
+        };
+        let mut blame = Blame::default();
+        for line in files[0]["lines"].as_array().into_iter().flatten() {
+            let node = line["node"].as_str().filter(|n| !n.bytes().all(|b| b == b'f'));
+            let date = node.and(line["date"].as_array()).and_then(|d| {
+                // hg gives the offset in seconds west of UTC
+                let (time, west) = (d.first()?.as_f64()? as i64, d.get(1)?.as_i64()?);
+                let offset = chrono::FixedOffset::west_opt(west as i32)?;
+                Some(chrono::DateTime::from_timestamp(time, 0)?.with_timezone(&offset).to_rfc3339())
+            });
+            let author = node.and(line["user"].as_str()).map(str::to_string);
+            blame.push(node.map(|n| n[..n.len().min(12)].to_string()), author, date);
+        }
+        Some(Ok(blame))
+    }
+}
+
+/// A workspace without version control, or one whose checkpoints are
+/// snapshots.
+struct Plain {
+    store: Arc<dyn FileStore>,
+    root: String,
+    checkpoints: Arc<Checkpoints>,
+}
+
+impl Plain {
+    // The files changed since the checkpoint, None without one
+    fn changes(&self) -> Option<Result<Vec<FileChange>, String>> {
+        let snapshot = self.checkpoints.snapshot(&self.root)?;
+        Some(snapshot.changes(&*self.store))
+    }
+}
+
+impl Vcs for Plain {
+    fn status(&self) -> Result<Status, String> {
+        let changes = self.changes().transpose()?;
+        Ok(Status {
+            kind: "none",
+            root: self.root.clone(),
+            branch: None,
+            commit: None,
+            checkpoint: self.checkpoints.get(&self.root),
+            dirty: changes.as_ref().map(|c| !c.is_empty()),
+            changes: changes.unwrap_or_default().iter().map(change_status).collect(),
+        })
+    }
+
+    fn diff(&self, path: &str) -> Result<Diff, String> {
+        let changes = self.changes().ok_or("No checkpoint to compare with; take one with POST /api/checkpoint")??;
+        let dir = format!("{}/", path.trim_end_matches('/'));
+        let selected: Vec<FileChange> = changes.into_iter().filter(|c| path.is_empty() || c.path == path || c.path.starts_with(&dir)).collect();
+        Ok(Diff { base: "checkpoint", diff: git_diff(&selected) })
+    }
+
+    fn checkpoint(&self) -> Result<Checkpoint, String> {
+        self.checkpoints.take(&*self.store)
+    }
+}
+
+fn change_status(change: &FileChange) -> Change {
+    let status = match (&change.old, &change.new) {
+        (None, _) => "added",
+        (_, None) => "deleted",
+        _ => "modified",
+    };
+    Change { path: change.path.clone(), status }
+}
+
+struct SnapshotFile {
+    data: Vec<u8>,
+    modified: Option<SystemTime>,
+}
+
+struct Snapshot {
+    checkpoint: Checkpoint,
+    files: BTreeMap<String, SnapshotFile>,
+    taken: Instant,
+}
+
+impl Snapshot {
+    // The store's files that differ from the snapshot. Files with the size
+    // and modification time they were taken with aren't read again.
+    fn changes(&self, store: &dyn FileStore) -> Result<Vec<FileChange>, String> {
+        let ig = load_gitignore(store, "").unwrap_or_else(Gitignore::empty);
+        let mut current = Vec::new();
+        walk_files(store, "", &ig, &mut |path| current.push(path.to_string()))?;
+        let listed: HashSet<&str> = current.iter().map(String::as_str).collect();
+        let text = |data: &[u8]| String::from_utf8_lossy(data).into_owned();
+        let mut changes = Vec::new();
+        for path in &current {
+            let old = self.files.get(path);
+            if let Some(old) = old {
+                let unchanged = old.modified.is_some() && store.modified(path) == old.modified && store.file_size(path) == Some(old.data.len() as u64);
+                if unchanged {
+                    continue;
+                }
+            }
+            let Ok(new) = store.read(path) else {
+                continue;
+            };
+            if old.is_some_and(|old| old.data == new) {
+                continue;
+            }
+            changes.push(FileChange { path: path.clone(), old: old.map(|o| text(&o.data)), new: Some(text(&new)) });
+        }
+        for (path, old) in &self.files {
+            if !listed.contains(path.as_str()) {
+                changes.push(FileChange { path: path.clone(), old: Some(text(&old.data)), new: None });
+            }
+        }
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(changes)
+    }
+}
+
+/// Snapshot checkpoints of workspaces outside version control, kept in
+/// memory.
+pub struct Checkpoints {
+    snapshots: Mutex<HashMap<String, Arc<Snapshot>>>,
+    max_bytes: usize,
+}
+
+impl Checkpoints {
+    /// Checkpoints capped at CHECKPOINT_MAX_MB per workspace.
+    pub fn from_env() -> Self {
+        let max_mb = env::var("CHECKPOINT_MAX_MB").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_CHECKPOINT_MB);
+        Checkpoints { snapshots: Mutex::new(HashMap::new()), max_bytes: max_mb * 1024 * 1024 }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Arc<Snapshot>>> {
+        self.snapshots.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn snapshot(&self, root: &str) -> Option<Arc<Snapshot>> {
+        self.lock().get(root).cloned()
+    }
+
+    fn get(&self, root: &str) -> Option<Checkpoint> {
+        self.snapshot(root).map(|s| s.checkpoint.clone())
+    }
+
+    // Snapshots every file the ignore rules keep
+    fn take(&self, store: &dyn FileStore) -> Result<Checkpoint, String> {
+        let ig = load_gitignore(store, "").unwrap_or_else(Gitignore::empty);
+        let mut paths = Vec::new();
+        walk_files(store, "", &ig, &mut |path| paths.push(path.to_string()))?;
+        let mut files = BTreeMap::new();
+        let mut total = 0;
+        let mut state = String::new();
+        for path in paths {
+            let modified = store.modified(&path);
+            let Ok(data) = store.read(&path) else {
+                continue;
+            };
+            total += data.len();
+            if total > self.max_bytes {
+                return Err(format!(
+                    "The workspace holds more than {} MB, the most a checkpoint outside a repository keeps (CHECKPOINT_MAX_MB)",
+                    self.max_bytes / (1024 * 1024)
+                ));
+            }
+            state.push_str(&format!("{}\0{}\n", path, revision(&data)));
+            files.insert(path, SnapshotFile { data, modified });
+        }
+        let checkpoint = Checkpoint { id: revision(state.as_bytes()), created_at: chrono::Utc::now().to_rfc3339() };
+        let root = store.display_path("");
+        let mut snapshots = self.lock();
+        snapshots.insert(root, Arc::new(Snapshot { checkpoint: checkpoint.clone(), files, taken: Instant::now() }));
+        if snapshots.len() > MAX_SNAPSHOTS {
+            if let Some(oldest) = snapshots.iter().min_by_key(|(_, s)| s.taken).map(|(r, _)| r.clone()) {
+                snapshots.remove(&oldest);
+            }
+        }
+        Ok(checkpoint)
+    }
+}
+
+impl CacheSize for Checkpoints {
+    fn cached_bytes(&self) -> u64 {
+        self.lock().values().flat_map(|s| s.files.values()).map(|f| f.data.len() as u64).sum()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct VcsQuery {
+    path: Option<String>,
+    /// A file or folder in the workspace to diff; the whole workspace when
+    /// missing.
+    file: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CheckpointRequest {
+    #[serde(rename = "directoryPath")]
+    directory_path: String,
+}
+
+fn workspace(stores: &Stores, path: Option<&str>) -> Result<Arc<dyn FileStore>, HttpResponse> {
+    let requested_path = path.map(str::to_string).unwrap_or_else(|| env::current_dir().unwrap().to_string_lossy().to_string());
+    stores
+        .open_root(&requested_path)
+        .map_err(|e| HttpResponse::BadRequest().json(json!({ "success": false, "error": format!("Invalid directory path: {}", e) })))
+}
+
+fn respond(result: Result<Result<serde_json::Value, String>, actix_web::error::BlockingError>, task: &str) -> HttpResponse {
+    match result {
+        Ok(Ok(body)) => HttpResponse::Ok().json(body),
+        Ok(Err(e)) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": e })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "success": false, "error": format!("{} task failed: {}", task, e) })),
+    }
+}
+
+#[get("/api/vcs_status")]
+pub async fn get_status(query: web::Query<VcsQuery>, stores: Stores, checkpoints: web::Data<Checkpoints>) -> HttpResponse {
+    let store = match workspace(&stores, query.path.as_deref()) {
+        Ok(s) => s,
+        Err(response) => return response,
+    };
+    let root = store.display_path("");
+    let checkpoints = checkpoints.into_inner();
+    let result = web::block(move || open(store, checkpoints).status().map(|status| json!({ "success": true, "root": root, "vcs": status }))).await;
+    respond(result, "Status")
+}
+
+#[get("/api/vcs_diff")]
+pub async fn get_diff(query: web::Query<VcsQuery>, stores: Stores, checkpoints: web::Data<Checkpoints>) -> HttpResponse {
+    let store = match workspace(&stores, query.path.as_deref()) {
+        Ok(s) => s,
+        Err(response) => return response,
+    };
+    let file = query.file.clone().unwrap_or_default();
+    if !file.is_empty() {
+        if let Err(e) = store.validate(&file) {
+            return HttpResponse::BadRequest().json(json!({ "success": false, "error": e }));
+        }
+    }
+    let root = store.display_path("");
+    let checkpoints = checkpoints.into_inner();
+    let result = web::block(move || {
+        let diff = open(store, checkpoints).diff(&file)?;
+        Ok(json!({ "success": true, "root": root, "base": diff.base, "diff": diff.diff }))
+    })
+    .await;
+    respond(result, "Diff")
+}
+
+#[post("/api/checkpoint")]
+pub async fn take_checkpoint(
+    body: ValidJson<CheckpointRequest>,
+    stores: Stores,
+    session: Option<web::ReqData<Session>>,
+    checkpoints: web::Data<Checkpoints>,
+    activity: web::Data<Activity>,
+) -> HttpResponse {
+    let store = match workspace(&stores, Some(&body.directory_path)) {
+        Ok(s) => s,
+        Err(response) => return response,
+    };
+    let root = store.display_path("");
+    let checkpoints = checkpoints.into_inner();
+    let task_root = root.clone();
+    let result = web::block(move || open(store, checkpoints).checkpoint().map(|checkpoint| json!({ "success": true, "root": task_root, "checkpoint": checkpoint }))).await;
+    if let Ok(Ok(body)) = &result {
+        let id = body["checkpoint"]["id"].as_str().unwrap_or_default();
+        activity.record(&root, session.as_deref(), Event::new("vcs", "checkpoint", format!("Took checkpoint {}", id)));
+    }
+    respond(result, "Checkpoint")
+}
@@ -0,0 +1,234 @@
+// Watchdog: remembers which files each UI client has read and watches them
+// on disk. When one changes, clients subscribed to GET /api/events get a
+// `fileChanged` event so they can flag stale prompt context, and the next
+// /api/files response marks the file `changedSinceRead`. Roots listed through
+// /api/directory are watched recursively and produce `treeChanged` events.
+//
+// Clients identify themselves with the X-Repopatch-Client header (or the
+// `client` query parameter for EventSource, which can't set headers).
+
+use crate::instance::RootLocks;
+use actix_web::web::Bytes;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+pub const CLIENT_HEADER: &str = "X-Repopatch-Client";
+const CLIENT_IDLE_TTL: Duration = Duration::from_secs(60 * 60);
+const TREE_EVENT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct ClientState {
+    /// Content hash of every file at the time this client last read it.
+    reads: HashMap<PathBuf, u64>,
+    /// Files reported as changed since they were read.
+    stale: HashSet<PathBuf>,
+    /// Listed roots and when a treeChanged event was last sent for each.
+    roots: HashMap<PathBuf, Option<Instant>>,
+    events: Option<UnboundedSender<Result<Bytes, Infallible>>>,
+    last_seen: Option<Instant>,
+}
+
+impl ClientState {
+    fn send(&mut self, event: &str, data: serde_json::Value) {
+        let frame = Bytes::from(format!("event: {}\ndata: {}\n\n", event, data));
+        if let Some(sender) = &self.events {
+            if sender.send(Ok(frame)).is_err() {
+                self.events = None;
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct WatchState {
+    clients: HashMap<String, ClientState>,
+    watched: HashMap<PathBuf, RecursiveMode>,
+}
+
+pub struct Watchdog {
+    state: Mutex<WatchState>,
+    watcher: Mutex<Option<RecommendedWatcher>>,
+    /// Repositories locked by another instance are not watched.
+    locks: Option<Arc<RootLocks>>,
+}
+
+// Helper function to hash file content for change detection
+fn content_hash(content: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Watchdog {
+    pub fn new(locks: Option<Arc<RootLocks>>) -> Arc<Self> {
+        Arc::new_cyclic(|weak: &Weak<Watchdog>| {
+            let weak = weak.clone();
+            let watcher = notify::recommended_watcher(move |res: notify::Result<Event>| match (res, weak.upgrade()) {
+                (Ok(event), Some(watchdog)) => watchdog.on_event(event),
+                (Err(e), _) => log::warn!("File watcher error: {}", e),
+                _ => {}
+            });
+            let watcher = match watcher {
+                Ok(w) => Some(w),
+                Err(e) => {
+                    log::warn!("File watching unavailable: {}", e);
+                    None
+                }
+            };
+            Watchdog { state: Mutex::new(WatchState::default()), watcher: Mutex::new(watcher), locks }
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, WatchState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Records that `client` read `path` with `content`; returns whether the
+    /// content differs from what the client read last time.
+    pub fn record_read(&self, client: &str, path: &Path, content: &[u8]) -> bool {
+        let hash = content_hash(content);
+        let mut state = self.lock();
+        let entry = state.clients.entry(client.to_string()).or_default();
+        entry.last_seen = Some(Instant::now());
+        entry.stale.remove(path);
+        let changed = entry.reads.insert(path.to_path_buf(), hash).is_some_and(|previous| previous != hash);
+        if let Some(parent) = path.parent() {
+            self.watch(&mut state, parent, RecursiveMode::NonRecursive);
+        }
+        changed
+    }
+
+    /// Records that `client` listed the tree below `root`.
+    pub fn record_root(&self, client: &str, root: &Path) {
+        let mut state = self.lock();
+        let entry = state.clients.entry(client.to_string()).or_default();
+        entry.last_seen = Some(Instant::now());
+        entry.roots.entry(root.to_path_buf()).or_insert(None);
+        self.watch(&mut state, root, RecursiveMode::Recursive);
+        self.prune(&mut state);
+    }
+
+    fn watch(&self, state: &mut WatchState, path: &Path, mode: RecursiveMode) {
+        let covered = state.watched.iter().any(|(watched, watched_mode)| {
+            watched == path || (*watched_mode == RecursiveMode::Recursive && path.starts_with(watched))
+        });
+        if covered {
+            return;
+        }
+        if let Err(e) = self.locks.as_ref().map_or(Ok(()), |locks| locks.acquire(path)) {
+            log::debug!("Not watching {}: {}", path.display(), e);
+            return;
+        }
+        let mut watcher = self.watcher.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(watcher) = watcher.as_mut() {
+            match watcher.watch(path, mode) {
+                Ok(()) => {
+                    log::debug!("Watching {} ({:?})", path.display(), mode);
+                    state.watched.insert(path.to_path_buf(), mode);
+                }
+                Err(e) => log::warn!("Failed to watch {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    // Forgets idle clients and stops watching paths nobody needs any more
+    fn prune(&self, state: &mut WatchState) {
+        state
+            .clients
+            .retain(|_, c| c.events.is_some() || c.last_seen.is_some_and(|t| t.elapsed() < CLIENT_IDLE_TTL));
+        let needed: HashSet<PathBuf> = state
+            .clients
+            .values()
+            .flat_map(|c| c.reads.keys().filter_map(|p| p.parent().map(Path::to_path_buf)).chain(c.roots.keys().cloned()))
+            .collect();
+        let unused: Vec<PathBuf> = state.watched.keys().filter(|p| !needed.contains(*p)).cloned().collect();
+        let mut watcher = self.watcher.lock().unwrap_or_else(|e| e.into_inner());
+        for path in unused {
+            if let Some(watcher) = watcher.as_mut() {
+                let _ = watcher.unwatch(&path);
+            }
+            state.watched.remove(&path);
+        }
+    }
+
+    fn on_event(&self, event: Event) {
+        let structural = matches!(event.kind, EventKind::Create(_) | EventKind::Remove(_) | EventKind::Modify(notify::event::ModifyKind::Name(_)));
+        if matches!(event.kind, EventKind::Access(_)) {
+            return;
+        }
+        let mut state = self.lock();
+        for client in state.clients.values_mut() {
+            for path in &event.paths {
+                if client.reads.contains_key(path) && client.stale.insert(path.clone()) {
+                    client.send("fileChanged", json!({ "path": path.to_string_lossy() }));
+                }
+            }
+            if structural {
+                let roots: Vec<PathBuf> = client
+                    .roots
+                    .iter()
+                    .filter(|(root, last)| {
+                        last.is_none_or(|t| t.elapsed() >= TREE_EVENT_INTERVAL) && event.paths.iter().any(|p| p.starts_with(root))
+                    })
+                    .map(|(root, _)| root.clone())
+                    .collect();
+                for root in roots {
+                    client.roots.insert(root.clone(), Some(Instant::now()));
+                    client.send("treeChanged", json!({ "root": root.to_string_lossy() }));
+                }
+            }
+        }
+    }
+
+    /// Returns `(clients, subscribed clients, watched paths)`.
+    pub fn stats(&self) -> (usize, usize, Vec<String>) {
+        let state = self.lock();
+        let subscribed = state.clients.values().filter(|c| c.events.is_some()).count();
+        let watched = state.watched.keys().map(|p| p.to_string_lossy().to_string()).collect();
+        (state.clients.len(), subscribed, watched)
+    }
+}
+
+/// Returns the client id a request was made with, if any.
+pub fn client_id(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(CLIENT_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(str::to_string)
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    client: String,
+}
+
+#[get("/api/events")]
+pub async fn events(query: web::Query<EventsQuery>, watchdog: web::Data<Watchdog>) -> HttpResponse {
+    let (tx, rx) = unbounded_channel();
+    let _ = tx.send(Ok(Bytes::from_static(b": connected\n\n")));
+    {
+        let mut state = watchdog.lock();
+        let client = state.clients.entry(query.client.clone()).or_default();
+        client.last_seen = Some(Instant::now());
+        client.events = Some(tx);
+    }
+    log::debug!("Client {} subscribed to file events", query.client);
+    let stream = UnboundedReceiverStream::new(rx);
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream)
+}
@@ -0,0 +1,208 @@
+// Writability of workspace roots, kept by the server instead of tested on
+// every request.
+//
+// The first /api/check_writable for a root checks it: the folder must exist
+// and be writable by the server's user (which also catches read-only file
+// systems), the store's own rules must allow writes (root locks), and a probe
+// file is written and removed in a `.repopatch` folder at the root, which
+// ignores itself through its own .gitignore. The result is kept and checked
+// again every WRITABLE_REFRESH_SECS (default 300) for roots asked about in
+// the last hour, and on the next request when a watcher sees the root's
+// permissions or ownership change. /api/check_writable and /api/workspaces
+// report the kept status and when it was taken.
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use repopatch::store::FileStore;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+const DEFAULT_REFRESH_SECS: u64 = 300;
+const ROOT_IDLE_TTL: Duration = Duration::from_secs(60 * 60);
+const PROBE_DIR: &str = ".repopatch";
+
+/// A root's writability as last checked.
+#[derive(Serialize, Clone, Debug)]
+pub struct Writability {
+    pub writable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(rename = "checkedAt")]
+    pub checked_at: String,
+}
+
+struct RootState {
+    store: Arc<dyn FileStore>,
+    status: Writability,
+    /// Unset once the watcher saw the root change.
+    checked: Option<Instant>,
+    last_asked: Instant,
+}
+
+pub struct WritabilityMonitor {
+    roots: Mutex<HashMap<String, RootState>>,
+    watcher: Mutex<Option<RecommendedWatcher>>,
+    refresh: Duration,
+}
+
+impl WritabilityMonitor {
+    /// The monitor refreshing every WRITABLE_REFRESH_SECS, with its refresh
+    /// thread running.
+    pub fn from_env() -> Arc<Self> {
+        let secs = env::var("WRITABLE_REFRESH_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_REFRESH_SECS).max(1);
+        let monitor = Arc::new_cyclic(|weak: &Weak<WritabilityMonitor>| {
+            let weak = weak.clone();
+            let watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+                if let (Ok(event), Some(monitor)) = (res, weak.upgrade()) {
+                    monitor.on_event(event);
+                }
+            });
+            let watcher = watcher.map_err(|e| log::warn!("Writability watching unavailable: {}", e)).ok();
+            WritabilityMonitor { roots: Mutex::new(HashMap::new()), watcher: Mutex::new(watcher), refresh: Duration::from_secs(secs) }
+        });
+        let weak = Arc::downgrade(&monitor);
+        let refresh = monitor.refresh;
+        std::thread::spawn(move || loop {
+            std::thread::sleep(refresh);
+            match weak.upgrade() {
+                Some(monitor) => monitor.refresh_all(),
+                None => return,
+            }
+        });
+        monitor
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, RootState>> {
+        self.roots.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The status of the store's root, checked now when it was never
+    /// checked, is out of date or `recheck` asks for it.
+    pub fn status(&self, store: Arc<dyn FileStore>, recheck: bool) -> Writability {
+        let root = store.display_path("");
+        if let Some(state) = self.lock().get_mut(&root) {
+            state.last_asked = Instant::now();
+            if !recheck && state.checked.is_some_and(|t| t.elapsed() < self.refresh) {
+                return state.status.clone();
+            }
+        }
+        let status = check(&*store);
+        let watch = {
+            let mut roots = self.lock();
+            let new = !roots.contains_key(&root);
+            roots.insert(root.clone(), RootState { store, status: status.clone(), checked: Some(Instant::now()), last_asked: Instant::now() });
+            new
+        };
+        if watch && Path::new(&root).is_dir() {
+            if let Some(watcher) = self.watcher.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+                if let Err(e) = watcher.watch(Path::new(&root), RecursiveMode::NonRecursive) {
+                    log::debug!("Not watching {} for permission changes: {}", root, e);
+                }
+            }
+        }
+        status
+    }
+
+    // Checks the roots asked about lately again and forgets the others
+    fn refresh_all(&self) {
+        let (due, idle): (Vec<_>, Vec<_>) = {
+            let roots = self.lock();
+            let (active, idle): (Vec<_>, Vec<_>) = roots.iter().partition(|(_, s)| s.last_asked.elapsed() < ROOT_IDLE_TTL);
+            (active.into_iter().map(|(r, s)| (r.clone(), s.store.clone())).collect(), idle.into_iter().map(|(r, _)| r.clone()).collect())
+        };
+        if !idle.is_empty() {
+            let mut roots = self.lock();
+            let mut watcher = self.watcher.lock().unwrap_or_else(|e| e.into_inner());
+            for root in idle {
+                roots.remove(&root);
+                if let Some(watcher) = watcher.as_mut() {
+                    let _ = watcher.unwatch(Path::new(&root));
+                }
+            }
+        }
+        for (root, store) in due {
+            let status = check(&*store);
+            if let Some(state) = self.lock().get_mut(&root) {
+                if status.writable != state.status.writable {
+                    log::info!("{} is {} writable", root, if status.writable { "now" } else { "no longer" });
+                }
+                state.status = status;
+                state.checked = Some(Instant::now());
+            }
+        }
+    }
+
+    fn on_event(&self, event: Event) {
+        let relevant = matches!(event.kind, EventKind::Remove(_) | EventKind::Modify(notify::event::ModifyKind::Metadata(_)));
+        if !relevant {
+            return;
+        }
+        let mut roots = self.lock();
+        for path in &event.paths {
+            if let Some(state) = roots.get_mut(&*path.to_string_lossy()) {
+                state.checked = None;
+            }
+        }
+    }
+}
+
+// Checks a root: its metadata, the store's rules and a probe write
+fn check(store: &dyn FileStore) -> Writability {
+    let result = check_root(store);
+    if let Err(e) = &result {
+        log::info!("{} is not writable: {}", store.display_path(""), e);
+    }
+    Writability { writable: result.is_ok(), error: result.err(), checked_at: chrono::Utc::now().to_rfc3339() }
+}
+
+fn check_root(store: &dyn FileStore) -> Result<(), String> {
+    store.check_writable("")?;
+    let root = PathBuf::from(store.display_path(""));
+    // Stores that aren't folders on disk, like the in-memory one, go by
+    // their own rules alone
+    if !root.is_absolute() {
+        return Ok(());
+    }
+    let meta = fs::metadata(&root).map_err(|e| format!("Cannot access {}: {}", root.display(), e))?;
+    if !meta.is_dir() {
+        return Err(format!("{} is not a folder", root.display()));
+    }
+    may_write(&root)?;
+    probe(&root.join(PROBE_DIR)).map_err(|e| format!("Test write in {} failed: {}", root.join(PROBE_DIR).display(), e))
+}
+
+#[cfg(unix)]
+fn may_write(dir: &Path) -> Result<(), String> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes()).map_err(|e| e.to_string())?;
+    // SAFETY: `path` is a NUL-terminated string that outlives the call
+    match unsafe { libc::access(path.as_ptr(), libc::W_OK) } {
+        0 => Ok(()),
+        _ => Err(format!("The server cannot write to {}: {}", dir.display(), std::io::Error::last_os_error())),
+    }
+}
+
+#[cfg(not(unix))]
+fn may_write(dir: &Path) -> Result<(), String> {
+    match fs::metadata(dir) {
+        Ok(meta) if meta.permissions().readonly() => Err(format!("{} is read-only", dir.display())),
+        _ => Ok(()),
+    }
+}
+
+// Writes and removes a file with a fixed name in the probe folder, so a
+// failed removal leaves one ignored file rather than one per check
+fn probe(dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let ignore = dir.join(".gitignore");
+    if !ignore.exists() {
+        fs::write(&ignore, "*\n")?;
+    }
+    let file = dir.join("write-probe");
+    fs::write(&file, std::process::id().to_string())?;
+    fs::remove_file(&file)
+}
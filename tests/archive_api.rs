@@ -0,0 +1,181 @@
+// Browsing ZIP and tar archives as read-only folders.
+
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Compression;
+use ignore::gitignore::Gitignore;
+use repopatch::archive::{archive_kind, entries, read_entry, split_archive_path, ArchiveEntry, ArchiveStore, ArchiveStoreProvider, MAX_ARCHIVE_BYTES};
+use repopatch::store::{FileStore, FsStore, MemoryStore, MemoryStoreProvider, StoreProvider};
+use repopatch::tree::{build_filtered_tree, TreeFilter};
+use std::io::{ErrorKind, Write};
+use std::sync::Arc;
+
+// A ZIP archive of deflated entries; names ending in `/` are folders
+fn zip(entries: &[(&str, &str)]) -> Vec<u8> {
+    let (mut archive, mut directory) = (Vec::new(), Vec::new());
+    for (name, content) in entries {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let offset = archive.len() as u32;
+        let sizes = [(compressed.len() as u32).to_le_bytes(), (content.len() as u32).to_le_bytes()].concat();
+        archive.extend(b"PK\x03\x04\x14\x00\x00\x00\x08\x00\x00\x00\x00\x00\x00\x00\x00\x00");
+        archive.extend(&sizes);
+        archive.extend((name.len() as u16).to_le_bytes());
+        archive.extend([0, 0]);
+        archive.extend(name.as_bytes());
+        archive.extend(&compressed);
+        directory.extend(b"PK\x01\x02\x14\x00\x14\x00\x00\x00\x08\x00\x00\x00\x00\x00\x00\x00\x00\x00");
+        directory.extend(&sizes);
+        directory.extend((name.len() as u16).to_le_bytes());
+        directory.extend([0; 12]);
+        directory.extend(offset.to_le_bytes());
+        directory.extend(name.as_bytes());
+    }
+    let (start, size) = (archive.len() as u32, directory.len() as u32);
+    archive.extend(directory);
+    archive.extend(b"PK\x05\x06\x00\x00\x00\x00");
+    archive.extend([(entries.len() as u16).to_le_bytes(), (entries.len() as u16).to_le_bytes()].concat());
+    archive.extend(size.to_le_bytes());
+    archive.extend(start.to_le_bytes());
+    archive.extend([0, 0]);
+    archive
+}
+
+fn tar_gz(files: &[(&str, &str)]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for (name, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, content.as_bytes()).unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap()
+}
+
+fn site_zip() -> Vec<u8> {
+    zip(&[("pages/", ""), ("pages/index.html", "<h1>Hi</h1>\n"), ("data/users.json", "[1, 2]\n"), ("../escape.txt", "no")])
+}
+
+#[test]
+fn entry_paths_name_the_archive_then_the_entry() {
+    assert_eq!(split_archive_path("fixtures/site.zip!pages/index.html"), Some(("fixtures/site.zip", "pages/index.html")));
+    assert_eq!(split_archive_path("/repo/a!b/logs.TGZ!/2024/"), Some(("/repo/a!b/logs.TGZ", "2024")), "only a ! after an archive name splits");
+    assert_eq!(split_archive_path("fixtures/site.zip"), None);
+    assert_eq!(split_archive_path("notes/wow!.md"), None);
+    assert_eq!((archive_kind("lib/app.jar"), archive_kind("a.tar"), archive_kind("a.txt")), (Some("zip"), Some("tar"), None));
+}
+
+#[test]
+fn zip_and_tar_entries_are_listed_and_read() {
+    let site = site_zip();
+    let listed = entries("zip", &site).unwrap();
+    let paths: Vec<(&str, bool)> = listed.iter().map(|e| (e.path.as_str(), e.is_dir)).collect();
+    assert_eq!(paths, [("pages", true), ("pages/index.html", false), ("data/users.json", false)], "names escaping the archive are dropped");
+    assert_eq!(listed[1].size, 12);
+    assert_eq!(read_entry("zip", &site, "data/users.json").unwrap().as_deref(), Some(&b"[1, 2]\n"[..]));
+    assert_eq!(read_entry("zip", &site, "pages").unwrap(), None, "folders aren't files");
+    assert!(entries("zip", b"PK\x03\x04 truncated").is_err());
+
+    let logs = tar_gz(&[("./2024/01.log", "started\n"), ("2024/02.log", "stopped\n")]);
+    assert_eq!(
+        entries("tar.gz", &logs).unwrap(),
+        [
+            ArchiveEntry { path: "2024/01.log".to_string(), size: 8, is_dir: false },
+            ArchiveEntry { path: "2024/02.log".to_string(), size: 8, is_dir: false }
+        ]
+    );
+    assert_eq!(read_entry("tar.gz", &logs, "2024/02.log").unwrap().as_deref(), Some(&b"stopped\n"[..]));
+    assert_eq!(read_entry("tar.gz", &logs, "2024/03.log").unwrap(), None);
+}
+
+#[test]
+fn gzipped_tars_stop_at_the_archive_limit_once_inflated() {
+    let big = " ".repeat(MAX_ARCHIVE_BYTES as usize);
+    let logs = tar_gz(&[("2024/01.log", "started\n"), ("2024/big.log", &big)]);
+    assert!((logs.len() as u64) < MAX_ARCHIVE_BYTES / 100);
+    let error = entries("tar.gz", &logs).unwrap_err();
+    assert!(error.contains("too large once inflated"), "{}", error);
+    assert!(read_entry("tar.gz", &logs, "2024/missing.log").is_err());
+    // An entry found before the limit is still read
+    assert_eq!(read_entry("tar.gz", &logs, "2024/01.log").unwrap().as_deref(), Some(&b"started\n"[..]));
+}
+
+#[test]
+fn archive_entries_read_through_the_store_but_cannot_be_written() {
+    let memory = MemoryStore::new();
+    memory.write("fixtures/site.zip", &site_zip()).unwrap();
+    memory.write("README.md", b"# Site\n").unwrap();
+    let store = ArchiveStore::new(Arc::new(memory));
+
+    assert_eq!(store.read("fixtures/site.zip!pages/index.html").unwrap(), b"<h1>Hi</h1>\n");
+    assert_eq!(store.read("fixtures/site.zip!pages/missing.html").unwrap_err().kind(), ErrorKind::NotFound);
+    assert!(store.exists("fixtures/site.zip!data/users.json") && !store.exists("fixtures/site.zip!data"));
+    assert_eq!(store.file_size("fixtures/site.zip!data/users.json"), Some(7));
+    assert_eq!(store.read("README.md").unwrap(), b"# Site\n");
+
+    let top: Vec<(String, bool)> = store.list_dir("fixtures/site.zip!").unwrap().into_iter().map(|e| (e.name, e.is_dir)).collect();
+    assert_eq!(top, [("data".to_string(), true), ("pages".to_string(), true)]);
+    assert_eq!(store.list_dir("fixtures/site.zip!pages").unwrap()[0].name, "index.html");
+
+    assert_eq!(store.write("fixtures/site.zip!pages/index.html", b"changed").unwrap_err().kind(), ErrorKind::PermissionDenied);
+    assert!(store.remove("fixtures/site.zip!data/users.json").is_err());
+    assert!(store.check_writable("fixtures/site.zip!pages/new.html").is_err());
+    assert!(store.check_writable("pages/new.html").is_ok());
+}
+
+#[test]
+fn client_paths_open_archive_entries() {
+    let memory = MemoryStore::new();
+    memory.write("fixtures/site.zip", &site_zip()).unwrap();
+    let provider = ArchiveStoreProvider::new(Arc::new(MemoryStoreProvider::new("/repo", Arc::new(memory))));
+    let (store, name) = provider.open_file("/repo/fixtures/site.zip!data/users.json").unwrap();
+    assert_eq!(store.read(&name).unwrap(), b"[1, 2]\n");
+    assert!(provider.open_file("/repo/fixtures/other.zip!data/users.json").is_err());
+}
+
+#[test]
+fn trees_list_archives_as_folders_when_asked() {
+    let store = MemoryStore::new();
+    store.write("fixtures/site.zip", &site_zip()).unwrap();
+    store.write("fixtures/logs.tgz", &tar_gz(&[("2024/01.log", "started\n")])).unwrap();
+    store.write("fixtures/broken.zip", b"not a zip").unwrap();
+
+    let plain = build_filtered_tree(&store, "", &Gitignore::empty(), &TreeFilter::default()).unwrap();
+    assert_eq!(plain["fixtures"].children.as_ref().unwrap()["site.zip"].node_type, "file");
+
+    let filter = TreeFilter { archives: true, ..TreeFilter::default() };
+    let tree = build_filtered_tree(&store, "", &Gitignore::empty(), &filter).unwrap();
+    let fixtures = tree["fixtures"].children.as_ref().unwrap();
+    let site = &fixtures["site.zip"];
+    assert_eq!((site.node_type.as_str(), site.file_count), ("archive", Some(2)));
+    let pages = &site.children.as_ref().unwrap()["pages"];
+    assert_eq!((pages.node_type.as_str(), pages.size), ("folder", Some(12)));
+    let index = &pages.children.as_ref().unwrap()["index.html"];
+    assert_eq!((index.path.as_str(), index.size, index.language), ("fixtures/site.zip!pages/index.html", Some(12), Some("HTML")));
+    assert_eq!(fixtures["logs.tgz"].children.as_ref().unwrap()["2024"].file_count, Some(1));
+    assert_eq!(fixtures["broken.zip"].node_type, "file", "unreadable archives stay files");
+
+    let json_only = TreeFilter { archives: true, extensions: Some(vec!["json".to_string()]), ..TreeFilter::default() };
+    let tree = build_filtered_tree(&store, "", &Gitignore::empty(), &json_only).unwrap();
+    let site = &tree["fixtures"].children.as_ref().unwrap()["site.zip"];
+    assert_eq!(site.children.as_ref().unwrap().keys().collect::<Vec<_>>(), ["data"], "filters apply to entries");
+}
+
+#[test]
+fn only_files_on_disk_have_a_local_path() {
+    let dir = std::env::temp_dir().join(format!("repopatch-local-path-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let root = std::fs::canonicalize(&dir).unwrap();
+    let disk = FsStore::new(&root);
+    disk.write("fixtures/site.zip", &site_zip()).unwrap();
+    let store = ArchiveStore::new(Arc::new(disk));
+
+    assert_eq!(store.local_path("fixtures/site.zip"), Some(root.join("fixtures/site.zip")));
+    assert_eq!(store.local_path("fixtures/site.zip!pages/index.html"), None);
+    assert_eq!(store.local_path("fixtures"), None);
+    assert_eq!(store.local_path("../outside.txt"), None);
+    assert_eq!(MemoryStore::from_files([("a.txt", "a\n")]).local_path("a.txt"), None);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
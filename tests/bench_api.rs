@@ -0,0 +1,42 @@
+// Synthetic repositories and timings of the benchmark suite.
+
+use repopatch::bench::{self, BenchOptions};
+use repopatch::engine;
+use repopatch::patch::ApplyOptions;
+use repopatch::store::MemoryStore;
+
+#[test]
+fn synthetic_paths_nest_in_folders() {
+    assert_eq!(bench::synthetic_path(7), "mod0/file7.rs");
+    assert_eq!(bench::synthetic_path(345), "pkg3/mod4/file345.rs");
+}
+
+#[test]
+fn synthetic_patch_applies_with_the_default_engines() {
+    for name in &BenchOptions::default().engines {
+        let store = MemoryStore::new();
+        bench::populate(&store, 25, 6).unwrap();
+        let engine = engine::by_name(name).unwrap();
+        let outcome = engine.apply(&engine.parse(&bench::synthetic_patch(25, 6), 1), &store, &ApplyOptions::default());
+        assert!(outcome.is_success(), "{}: {:?}", name, outcome.details);
+        assert!(store.get(&bench::synthetic_path(0)).unwrap().contains("fn f0_3() -> usize { 3 + 1 }"));
+    }
+}
+
+#[test]
+fn every_case_is_timed_on_disk() {
+    let options = BenchOptions { sizes: vec![5, 30], lines: 4, iterations: 2, on_disk: true, ..BenchOptions::default() };
+    let timings = bench::run(&options).unwrap();
+    let cases: Vec<(&str, usize)> = timings.iter().map(|t| (t.case.as_str(), t.files)).collect();
+    assert_eq!(cases, [
+        ("tree_walk", 5), ("batch_read", 5), ("patch_apply:dmp", 5), ("patch_apply:strict", 5),
+        ("tree_walk", 30), ("batch_read", 30), ("patch_apply:dmp", 30), ("patch_apply:strict", 30),
+    ]);
+    assert!(timings.iter().all(|t| t.iterations == 2 && t.min_us <= t.median_us));
+}
+
+#[test]
+fn unknown_engines_are_refused() {
+    let options = BenchOptions { sizes: vec![3], iterations: 1, engines: vec!["patch".to_string()], ..BenchOptions::default() };
+    assert_eq!(bench::run(&options).unwrap_err(), "Unknown patch engine 'patch'");
+}
@@ -0,0 +1,122 @@
+// Planning and packing prompt context under a token budget, mostly over the
+// in-memory store.
+
+use ignore::gitignore::Gitignore;
+use repopatch::context::{estimate_tokens, plan_context, OVERHEAD_TOKENS};
+use repopatch::longlines::LongLines;
+use repopatch::pack::{fingerprint, pack, selection};
+use repopatch::related::CoChanges;
+use repopatch::store::{FileStore, FsStore, MemoryStore};
+use repopatch::tree::PathFilter;
+
+fn no_history(_: &str) -> Option<CoChanges> {
+    None
+}
+
+fn targets(paths: &[&str]) -> Vec<String> {
+    paths.iter().map(|p| p.to_string()).collect()
+}
+
+#[test]
+fn targets_come_first_and_related_files_fill_the_budget() {
+    let store = MemoryStore::from_files([
+        ("src/app.ts", "import { api } from './api';\nexport const app = api;\n"),
+        ("src/api.ts", "export const api = 1;\n"),
+        ("src/main.ts", "import './app';\n"),
+        ("docs/other.md", "unrelated\n"),
+    ]);
+    let plan = plan_context(&store, &Gitignore::empty(), &targets(&["src/main.ts", "src/app.ts"]), None, &no_history, 1000, true).unwrap();
+
+    let paths: Vec<&str> = plan.selections.iter().map(|s| s.path.as_str()).collect();
+    assert_eq!(paths, ["src/main.ts", "src/app.ts", "src/api.ts"]);
+    assert_eq!(plan.selections[0].score, None);
+    assert_eq!(plan.selections[0].reasons, ["target"]);
+    assert_eq!(plan.selections[2].reasons, ["sameDirectory", "imports"]);
+    assert_eq!(plan.selections[2].content.as_deref(), Some("export const api = 1;\n"));
+    assert_eq!(plan.used, plan.selections.iter().map(|s| s.tokens).sum::<usize>());
+    assert_eq!(plan.selections[1].tokens, estimate_tokens("import { api } from './api';\nexport const app = api;\n") + OVERHEAD_TOKENS);
+
+    let tight = plan_context(&store, &Gitignore::empty(), &targets(&["src/app.ts"]), None, &no_history, 30, false).unwrap();
+    assert_eq!(tight.selections.len(), 1);
+    assert!(tight.used <= 30);
+    assert_eq!(tight.skipped.iter().map(|s| s.path.as_str()).collect::<Vec<_>>(), ["src/api.ts", "src/main.ts"]);
+}
+
+#[test]
+fn oversized_files_are_cut_down_to_the_definitions_closest_to_the_question() {
+    let filler = "    let padding = 0;\n".repeat(60);
+    let source = format!(
+        "fn parse_hunk_header(line: &str) -> usize {{\n    line.len()\n}}\n\nfn render_tree(depth: usize) {{\n{}}}\n\nfn match_hunk(hunk: &str) -> bool {{\n    hunk.is_empty()\n}}\n",
+        filler
+    );
+    let store = MemoryStore::from_files([("src/patch.rs", source.as_str()), ("notes.txt", "nothing in common")]);
+    let plan = plan_context(&store, &Gitignore::empty(), &[], Some("how is a hunk header parsed"), &no_history, 60, true).unwrap();
+
+    assert_eq!(plan.selections.len(), 1);
+    let selection = &plan.selections[0];
+    assert_eq!(selection.path, "src/patch.rs");
+    assert_eq!(selection.kind, "symbols");
+    assert_eq!(selection.reasons, ["question"]);
+    let names: Vec<&str> = selection.symbols.iter().map(|s| s.name.as_str()).collect();
+    assert_eq!(names, ["parse_hunk_header", "match_hunk"]);
+    assert_eq!((selection.symbols[0].start_line, selection.symbols[0].end_line), (1, 3));
+    assert!(selection.symbols[0].content.as_deref().unwrap().starts_with("fn parse_hunk_header"));
+    assert!(plan.used <= 60);
+}
+
+#[test]
+fn missing_targets_are_errors() {
+    let store = MemoryStore::from_files([("a.rs", "")]);
+    assert!(plan_context(&store, &Gitignore::empty(), &targets(&["b.rs"]), None, &no_history, 100, false).is_err());
+}
+
+#[test]
+fn packs_filtered_files_within_the_budget() {
+    let store = MemoryStore::from_files([
+        (".gitignore", "dist/\n"),
+        ("dist/app.js", "built"),
+        ("src/a.rs", "fn a() {}\n"),
+        ("src/big.rs", &"x".repeat(400)),
+        ("src/logo.png", "\u{0}PNG"),
+        ("src/vendor/v.rs", "fn v() {}\n"),
+        ("docs/notes.md", "```sh\nls\n```\n"),
+    ]);
+    let ig = repopatch::tree::load_gitignore(&store, "").unwrap_or_else(Gitignore::empty);
+    let filter = PathFilter::new(&["src/**".to_string(), "docs/**".to_string()], &["src/vendor".to_string()]).unwrap();
+    let packed = pack(&store, &ig, &filter, "/repo", Some(60), LongLines::Keep).unwrap();
+
+    assert_eq!(packed.files, ["docs/notes.md", "src/a.rs"]);
+    assert!(packed.text.starts_with("# /repo\n"));
+    assert!(packed.text.contains("\n## src/a.rs\n\n```rs\nfn a() {}\n```\n"));
+    assert!(packed.text.contains("````md\n```sh"), "the fence outgrows backticks in the file");
+    assert!(packed.tokens <= 60);
+    assert_eq!(packed.tokens, estimate_tokens(&packed.text));
+    let omitted: Vec<(&str, &str)> = packed.omitted.iter().map(|o| (o.path.as_str(), o.reason)).collect();
+    assert_eq!(omitted, [("src/big.rs", "budget"), ("src/logo.png", "binary")]);
+
+    let everything = pack(&store, &ig, &PathFilter::new(&[], &[]).unwrap(), "/repo", None, LongLines::Keep).unwrap();
+    assert_eq!(everything.files, [".gitignore", "docs/notes.md", "src/a.rs", "src/big.rs", "src/vendor/v.rs"]);
+}
+
+#[test]
+fn fingerprints_change_with_the_selected_files() {
+    let dir = std::env::temp_dir().join(format!("repopatch-pack-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("src")).unwrap();
+    std::fs::write(dir.join("src/a.rs"), "fn a() {}\n").unwrap();
+    let store = FsStore::new(dir.canonicalize().unwrap());
+    let everything = PathFilter::new(&[], &[]).unwrap();
+    let paths = selection(&store, &Gitignore::empty(), &everything).unwrap();
+    let before = fingerprint(&store, &paths).unwrap();
+    assert_eq!(fingerprint(&store, &paths), Some(before.clone()));
+
+    store.write("src/a.rs", b"fn a() { todo!() }\n").unwrap();
+    assert_ne!(fingerprint(&store, &paths).unwrap(), before);
+    store.write("src/b.rs", b"").unwrap();
+    let grown = selection(&store, &Gitignore::empty(), &everything).unwrap();
+    assert_eq!(grown, ["src/a.rs", "src/b.rs"]);
+    assert_ne!(fingerprint(&store, &grown), fingerprint(&store, &paths));
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let memory = MemoryStore::from_files([("a.rs", "")]);
+    assert_eq!(fingerprint(&memory, &["a.rs".to_string()]), None, "no modification times, no reuse");
+}
@@ -0,0 +1,135 @@
+// File-level dependency graphs, related-file suggestions and patch impact
+// over the in-memory store.
+
+use ignore::gitignore::Gitignore;
+use repopatch::deps::dependency_graph;
+use repopatch::impact::{impact, is_test_file};
+use repopatch::packages::find_packages;
+use repopatch::related::{parse_co_changes, related_files};
+use repopatch::store::MemoryStore;
+
+fn set(items: &[&str]) -> std::collections::BTreeSet<String> {
+    items.iter().map(|s| s.to_string()).collect()
+}
+
+#[test]
+fn rust_mods_and_uses_resolve_to_files() {
+    let store = MemoryStore::from_files([
+        ("src/lib.rs", "pub mod tree;\nmod store;\nuse serde::Serialize;\npub use crate::tree::Node;\n"),
+        ("src/tree.rs", "mod walk;\nuse super::store::{self, FileStore};\nuse std::fs;\n"),
+        ("src/tree/walk.rs", "use crate::{store::join, Config};\n"),
+        ("src/store/mod.rs", "pub fn join() {}\n"),
+    ]);
+    let graph = dependency_graph(&store, &Gitignore::empty(), "").unwrap();
+
+    assert_eq!(graph.files["src/lib.rs"].imports, set(&["src/store/mod.rs", "src/tree.rs"]));
+    assert_eq!(graph.files["src/lib.rs"].external, set(&["serde"]));
+    assert_eq!(graph.files["src/tree.rs"].imports, set(&["src/store/mod.rs", "src/tree/walk.rs"]));
+    assert_eq!(graph.files["src/tree.rs"].external, set(&["std"]));
+    assert_eq!(graph.files["src/tree/walk.rs"].imports, set(&["src/lib.rs", "src/store/mod.rs"]));
+    assert_eq!(graph.dependents("src/store/mod.rs"), ["src/lib.rs", "src/tree.rs", "src/tree/walk.rs"]);
+}
+
+#[test]
+fn typescript_python_and_c_imports_resolve_to_files() {
+    let store = MemoryStore::from_files([
+        ("web/app.tsx", "import React from 'react';\nimport { a } from './util';\nimport b from './lib/b.js';\nconst c = require('@scope/pkg/deep');\n"),
+        ("web/util/index.ts", "export const a = 1;\n"),
+        ("web/lib/b.ts", "import fs from 'node:fs';\nexport default 2;\n"),
+        ("pkg/__init__.py", ""),
+        ("pkg/core.py", "from . import helpers\nfrom .models import User\nimport os.path\nimport pkg.models\n"),
+        ("pkg/helpers.py", ""),
+        ("pkg/models.py", ""),
+        ("native/main.c", "#include \"util.h\"\n#include <stdio.h>\n"),
+        ("native/util.h", ""),
+        ("README.md", "import './web/app'\n"),
+    ]);
+    let graph = dependency_graph(&store, &Gitignore::empty(), "").unwrap();
+
+    assert_eq!(graph.files["web/app.tsx"].imports, set(&["web/lib/b.ts", "web/util/index.ts"]));
+    assert_eq!(graph.files["web/app.tsx"].external, set(&["@scope/pkg", "react"]));
+    assert!(graph.files["web/lib/b.ts"].external.is_empty(), "node: builtins are left out");
+    assert_eq!(graph.files["pkg/core.py"].imports, set(&["pkg/__init__.py", "pkg/helpers.py", "pkg/models.py"]));
+    assert_eq!(graph.files["pkg/core.py"].external, set(&["os"]));
+    assert_eq!(graph.files["native/main.c"].imports, set(&["native/util.h"]));
+    assert_eq!(graph.files["native/main.c"].external, set(&["stdio.h"]));
+    assert!(!graph.files.contains_key("README.md"));
+
+    let web = dependency_graph(&store, &Gitignore::empty(), "web").unwrap();
+    assert_eq!(web.files.keys().collect::<Vec<_>>(), ["web/app.tsx", "web/lib/b.ts", "web/util/index.ts"]);
+    assert_eq!(web.edge_count(), 2);
+}
+
+#[test]
+fn related_files_rank_imports_history_and_neighbours() {
+    let store = MemoryStore::from_files([
+        ("src/app.ts", "import { api } from './api';\n"),
+        ("src/api.ts", "export const api = 1;\n"),
+        ("src/main.ts", "import './app';\n"),
+        ("src/style.css", ""),
+        ("docs/app.md", ""),
+        ("tests/app.test.ts", ""),
+    ]);
+    let log = "\x1e\nsrc/app.ts\ntests/app.test.ts\n\x1e\nsrc/app.ts\ntests/app.test.ts\ndocs/app.md\nsrc/gone.ts\n\x1e\nsrc/app.ts\n";
+    let history = parse_co_changes(log, "src/app.ts");
+    assert_eq!(history.commits, 3);
+    assert_eq!(history.counts["tests/app.test.ts"], 2);
+
+    let related = related_files(&store, &Gitignore::empty(), "src/app.ts", Some(&history), 10).unwrap();
+    let ranked: Vec<(&str, f64)> = related.iter().map(|r| (r.path.as_str(), r.score)).collect();
+    assert_eq!(ranked, [("src/api.ts", 1.3), ("src/main.ts", 1.1), ("tests/app.test.ts", 0.667), ("docs/app.md", 0.333), ("src/style.css", 0.3)]);
+    assert_eq!(related[0].reasons, ["imports", "sameDirectory"]);
+    assert_eq!(related_files(&store, &Gitignore::empty(), "src/app.ts", None, 2).unwrap().len(), 2);
+}
+
+#[test]
+fn impact_follows_importers_to_packages_and_tests() {
+    let store = MemoryStore::from_files([
+        ("package.json", r#"{"workspaces": ["packages/*"]}"#),
+        ("packages/core/package.json", r#"{"name": "@acme/core"}"#),
+        ("packages/core/src/util.ts", "export const a = 1;\n"),
+        ("packages/core/src/api.ts", "import { a } from './util';\n"),
+        ("packages/core/src/util.test.ts", "import { a } from './other';\n"),
+        ("packages/core/src/other.ts", ""),
+        ("packages/web/package.json", r#"{"name": "@acme/web"}"#),
+        ("packages/web/src/app.ts", "import { api } from '../../core/src/api';\n"),
+        ("packages/web/src/main.ts", "import './app';\n"),
+        ("packages/web/tests/app.spec.ts", "import '../src/app';\n"),
+        ("packages/web/tests/setup.ts", ""),
+    ]);
+    let graph = dependency_graph(&store, &Gitignore::empty(), "").unwrap();
+    let packages = find_packages(&store).unwrap();
+    let changed = vec!["packages/core/src/util.ts".to_string()];
+
+    let all = impact(&graph, &changed, &packages, 0);
+    let dependents: Vec<(&str, usize, &str)> = all.dependents.iter().map(|d| (d.path.as_str(), d.depth, d.via.as_str())).collect();
+    assert_eq!(
+        dependents,
+        [
+            ("packages/core/src/api.ts", 1, "packages/core/src/util.ts"),
+            ("packages/web/src/app.ts", 2, "packages/core/src/api.ts"),
+            ("packages/web/src/main.ts", 3, "packages/web/src/app.ts"),
+            ("packages/web/tests/app.spec.ts", 3, "packages/web/src/app.ts"),
+        ]
+    );
+    assert!(!all.truncated);
+    let commands: Vec<&str> = all.packages.iter().map(|p| p.test_command.as_str()).collect();
+    assert_eq!(commands, ["npm test --workspace @acme/core", "npm test --workspace @acme/web"]);
+    let tests: Vec<(&str, &str)> = all.tests.iter().map(|t| (t.path.as_str(), t.reason)).collect();
+    assert_eq!(
+        tests,
+        [
+            ("packages/core/src/util.test.ts", "namesake"),
+            ("packages/web/tests/app.spec.ts", "dependent"),
+            ("packages/web/tests/setup.ts", "package"),
+        ]
+    );
+
+    let near = impact(&graph, &changed, &packages, 1);
+    assert_eq!(near.dependents.len(), 1);
+    assert!(near.truncated);
+    assert_eq!(near.packages.len(), 1);
+
+    assert!(is_test_file("src/__tests__/a.ts") && is_test_file("pkg/test_core.py") && is_test_file("cmd/main_test.go"));
+    assert!(!is_test_file("src/testing.rs") && !is_test_file("src/test_.py"));
+}
@@ -0,0 +1,60 @@
+// Parsing compiler and linter output into diagnostics.
+
+use repopatch::diagnostics::{parse, CheckerConfig, Checkers};
+
+#[test]
+fn cargo_messages_keep_primary_spans_codes_and_notes() {
+    let output = r#"{"reason":"compiler-artifact","target":{"name":"dep"}}
+{"reason":"compiler-message","message":{"message":"mismatched types","level":"error","code":{"code":"E0308"},"spans":[{"file_name":"src/other.rs","line_start":2,"column_start":1,"is_primary":false},{"file_name":"src/lib.rs","line_start":7,"column_start":23,"is_primary":true}],"children":[{"message":"expected `i32`","level":"note"}]}}
+{"reason":"compiler-message","message":{"message":"mismatched types","level":"error","code":{"code":"E0308"},"spans":[{"file_name":"src/lib.rs","line_start":7,"column_start":23,"is_primary":true}],"children":[]}}
+{"reason":"compiler-message","message":{"message":"`main` function not found in crate `app`","level":"error","code":{"code":"E0601"},"spans":[],"children":[]}}
+{"reason":"compiler-message","message":{"message":"aborting due to 2 previous errors","level":"error","code":null,"spans":[],"children":[]}}
+{"reason":"compiler-message","message":{"message":"1 warning emitted","level":"warning","code":null,"spans":[],"children":[]}}
+{"reason":"build-finished","success":false}
+"#;
+    let diagnostics = parse("cargo", output).unwrap();
+    let found: Vec<_> = diagnostics.iter().map(|d| (d.file.as_deref(), d.line, d.severity.as_str(), d.code.as_deref())).collect();
+
+    assert_eq!(found, [(Some("src/lib.rs"), Some(7), "error", Some("E0308")), (None, None, "error", Some("E0601"))]);
+    assert_eq!(diagnostics[0].column, Some(23));
+    assert_eq!(diagnostics[0].notes, ["note: expected `i32`"]);
+}
+
+#[test]
+fn tsc_and_gcc_lines_become_diagnostics() {
+    let tsc = "src/app.ts(3,7): error TS2322: Type 'string' is not assignable to type 'number'.\n  The expected type comes from here.\nerror TS5023: Unknown compiler option 'foo'.\nFound 2 errors.\n";
+    let diagnostics = parse("tsc", tsc).unwrap();
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!((diagnostics[0].file.as_deref(), diagnostics[0].line, diagnostics[0].column), (Some("src/app.ts"), Some(3), Some(7)));
+    assert_eq!(diagnostics[0].message, "Type 'string' is not assignable to type 'number'.\nThe expected type comes from here.");
+    assert_eq!((diagnostics[1].file.as_deref(), diagnostics[1].code.as_deref()), (None, Some("TS5023")));
+
+    let gcc = "main.c: In function 'main':\nmain.c:4:5: warning: unused variable 'x' [-Wunused-variable]\nmain.c:9:1: fatal error: foo.h: No such file or directory\nmain.c:2:1: note: declared here\napp.py:12: error: Incompatible return value type  [return-value]\n";
+    let diagnostics = parse("gcc", gcc).unwrap();
+    let found: Vec<_> = diagnostics
+        .iter()
+        .map(|d| (d.file.as_deref().unwrap(), d.line, d.column, d.severity.as_str(), d.code.as_deref()))
+        .collect();
+    assert_eq!(
+        found,
+        [
+            ("main.c", Some(4), Some(5), "warning", Some("-Wunused-variable")),
+            ("main.c", Some(9), Some(1), "error", None),
+            ("app.py", Some(12), None, "error", Some("return-value")),
+        ]
+    );
+    assert_eq!(diagnostics[1].notes, ["note: declared here"]);
+    assert!(parse("eslint", "").is_err());
+}
+
+#[test]
+fn checkers_default_by_folder_and_validate_formats() {
+    let defaults = Checkers::new(Vec::new()).unwrap();
+    let names = |exists: &dyn Fn(&str) -> bool| defaults.for_dir(exists).iter().map(|c| c.name.clone()).collect::<Vec<_>>();
+    assert_eq!(names(&|f| f == "Cargo.toml"), ["cargo"]);
+    assert_eq!(names(&|f| f == "tsconfig.json" || f == "Cargo.toml"), ["cargo", "tsc"]);
+
+    let checker = |format: &str| CheckerConfig { name: "lint".to_string(), command: vec!["ruff".to_string()], format: format.to_string(), when: None };
+    assert_eq!(Checkers::new(vec![checker("gcc")]).unwrap().for_dir(|_| false).len(), 1);
+    assert!(Checkers::new(vec![checker("eslint")]).is_err_and(|e| e.contains("unknown format eslint")));
+}
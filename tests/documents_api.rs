@@ -0,0 +1,195 @@
+// Text of PDF and Word files, with the `documents` feature.
+
+use repopatch::documents::{document_kind, extract_text, ENABLED};
+
+#[test]
+fn documents_have_text_only_with_the_feature() {
+    assert_eq!(ENABLED, cfg!(feature = "documents"));
+    assert_eq!(document_kind("docs/spec.PDF").is_some(), ENABLED);
+    assert_eq!(document_kind("src/main.rs"), None);
+    assert!(extract_text("src/main.rs", b"fn main() {}").is_none());
+}
+
+#[cfg(feature = "documents")]
+mod extraction {
+    use flate2::write::{DeflateEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use ignore::gitignore::Gitignore;
+    use repopatch::documents::extract_text;
+    use repopatch::longlines::LongLines;
+    use repopatch::pack::pack;
+    use repopatch::store::{FileStore, MemoryStore};
+    use repopatch::tree::PathFilter;
+    use std::io::Write;
+
+    fn pdf(content: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let mut pdf = b"%PDF-1.4\n1 0 obj\n<< /Type /Catalog >>\nendobj\n".to_vec();
+        pdf.extend(format!("2 0 obj\n<< /Length {} /Filter /FlateDecode >>\nstream\n", compressed.len()).as_bytes());
+        pdf.extend(compressed);
+        pdf.extend(b"\nendstream\nendobj\n3 0 obj\n<< /Length 30 >>\nstream\nBT (Plain \\(stream\\)) Tj ET\nendstream\nendobj\n%%EOF\n");
+        pdf
+    }
+
+    // A PDF of numbered objects, each a dictionary and maybe a stream
+    fn pdf_objects(objects: &[(u32, &str, Option<&[u8]>)]) -> Vec<u8> {
+        let mut pdf = b"%PDF-1.7\n".to_vec();
+        for (number, dict, stream) in objects {
+            pdf.extend(format!("{} 0 obj\n{}\n", number, dict).as_bytes());
+            if let Some(stream) = stream {
+                pdf.extend(b"stream\n");
+                pdf.extend(*stream);
+                pdf.extend(b"\nendstream\n");
+            }
+            pdf.extend(b"endobj\n");
+        }
+        pdf.extend(b"%%EOF\n");
+        pdf
+    }
+
+    const TO_UNICODE: &[u8] = b"/CIDInit /ProcSet findresource begin 12 dict begin begincmap
+1 begincodespacerange <0000> <FFFF> endcodespacerange
+2 beginbfchar <0003> <0048> <0004> <0069> endbfchar
+2 beginbfrange <0010> <0012> <0061> <0020> <0021> [<00DC> <FB01>] endbfrange
+endcmap CMapName currentdict /CMap defineresource pop end end";
+
+    // A ZIP archive of deflated entries, as Word writes them
+    fn zip(entries: &[(&str, &str)]) -> Vec<u8> {
+        let (mut archive, mut directory) = (Vec::new(), Vec::new());
+        for (name, content) in entries {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(content.as_bytes()).unwrap();
+            let compressed = encoder.finish().unwrap();
+            let offset = archive.len() as u32;
+            let sizes = [(compressed.len() as u32).to_le_bytes(), (content.len() as u32).to_le_bytes()].concat();
+            archive.extend(b"PK\x03\x04\x14\x00\x00\x00\x08\x00\x00\x00\x00\x00\x00\x00\x00\x00");
+            archive.extend(&sizes);
+            archive.extend((name.len() as u16).to_le_bytes());
+            archive.extend([0, 0]);
+            archive.extend(name.as_bytes());
+            archive.extend(&compressed);
+            directory.extend(b"PK\x01\x02\x14\x00\x14\x00\x00\x00\x08\x00\x00\x00\x00\x00\x00\x00\x00\x00");
+            directory.extend(&sizes);
+            directory.extend((name.len() as u16).to_le_bytes());
+            directory.extend([0; 12]);
+            directory.extend(offset.to_le_bytes());
+            directory.extend(name.as_bytes());
+        }
+        let (start, size) = (archive.len() as u32, directory.len() as u32);
+        archive.extend(directory);
+        archive.extend(b"PK\x05\x06\x00\x00\x00\x00");
+        archive.extend([(entries.len() as u16).to_le_bytes(), (entries.len() as u16).to_le_bytes()].concat());
+        archive.extend(size.to_le_bytes());
+        archive.extend(start.to_le_bytes());
+        archive.extend([0, 0]);
+        archive
+    }
+
+    const DOCUMENT_XML: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<w:document xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main"><w:body>
+<w:p><w:pPr><w:pStyle w:val="Heading1"/></w:pPr><w:r><w:t>Sync protocol</w:t></w:r></w:p>
+<w:p><w:r><w:t xml:space="preserve">Clients send </w:t></w:r><w:r><w:rPr><w:b/></w:rPr><w:t>deltas</w:t></w:r><w:r><w:t xml:space="preserve"> &amp; acks.</w:t></w:r></w:p>
+<w:p/>
+<w:p><w:r><w:t>Step</w:t><w:tab/><w:t>Action</w:t></w:r></w:p>
+</w:body></w:document>"#;
+
+    #[test]
+    fn pdf_pages_give_their_shown_strings() {
+        let content = b"BT /F1 12 Tf 72 700 Td (Hello, spec) Tj 0 -14 Td [(Wor) -50 (ld) -300 (again)] TJ ET\nBT <4869> Tj ET";
+        let text = extract_text("docs/spec.pdf", &pdf(content)).unwrap().unwrap();
+        assert_eq!(text, "Hello, spec\nWorld again\nHi\nPlain (stream)\n");
+
+        assert!(extract_text("docs/spec.pdf", b"not a pdf").unwrap().is_err());
+        assert!(extract_text("docs/secret.pdf", b"%PDF-1.7\n<< /Encrypt 5 0 R >>").unwrap().is_err());
+    }
+
+    #[test]
+    fn composite_fonts_are_read_through_their_tounicode_maps() {
+        let content = b"BT /F1 12 Tf <00030004> Tj 0 -14 Td [<0010> -20 <00110012>] TJ 0 -14 Td <00200021> Tj /F2 12 Tf ( plain) Tj ET";
+        let pdf = pdf_objects(&[
+            (1, "<< /Type /Page /Resources << /Font << /F1 4 0 R /F2 6 0 R >> >> /Contents 2 0 R >>", None),
+            (2, "<< /Length 99 >>", Some(content)),
+            (4, "<< /Type /Font /Subtype /Type0 /BaseFont /Noto /Encoding /Identity-H /ToUnicode 5 0 R >>", None),
+            (5, "<< /Length 99 >>", Some(TO_UNICODE)),
+            (6, "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica >>", None),
+        ]);
+        let text = extract_text("docs/spec.pdf", &pdf).unwrap().unwrap();
+        assert_eq!(text, "Hi\nabc\n\u{dc}\u{fb01} plain\n");
+
+        // The same fonts packed into an object stream, as PDF 1.5 writers do
+        let packed = "1 0 4 80 6 200";
+        let dicts = format!(
+            "{:<80}{:<120}{}",
+            "<< /Type /Page /Resources << /Font << /F1 4 0 R /F2 6 0 R >> >> /Contents 2 0 R >>",
+            "<< /Type /Font /Subtype /Type0 /Encoding /Identity-H /ToUnicode 5 0 R >>",
+            "<< /Type /Font /Subtype /Type1 >>"
+        );
+        let object_stream = format!("{} {}", packed, dicts);
+        let first = format!("<< /Type /ObjStm /N 3 /First {} >>", packed.len() + 1);
+        let pdf = pdf_objects(&[(2, "<< /Length 99 >>", Some(content)), (5, "<< /Length 99 >>", Some(TO_UNICODE)), (7, &first, Some(object_stream.as_bytes()))]);
+        assert_eq!(extract_text("docs/spec.pdf", &pdf).unwrap().unwrap(), text);
+    }
+
+    #[test]
+    fn composite_fonts_without_a_map_are_not_extractable() {
+        let pdf = pdf_objects(&[
+            (1, "<< /Type /Page /Resources << /Font << /F1 4 0 R >> >> /Contents 2 0 R >>", None),
+            (2, "<< /Length 99 >>", Some(b"BT /F1 12 Tf <00030004> Tj ET")),
+            (4, "<< /Type /Font /Subtype /Type0 /BaseFont /Noto /Encoding /Identity-H >>", None),
+        ]);
+        let error = extract_text("docs/scan.pdf", &pdf).unwrap().unwrap_err();
+        assert!(error.contains("not extractable"), "{}", error);
+    }
+
+    #[test]
+    fn streams_are_found_in_one_pass() {
+        // Streams outside objects once made every stream search back to the start
+        let mut pdf = b"%PDF-1.4\n".to_vec();
+        for _ in 0..50_000 {
+            pdf.extend(b"stream\nBT (x) Tj ET\nendstream\n");
+        }
+        pdf.extend(b"1 0 obj\n<< /Length 20 >>\nstream\nBT (Found) Tj ET\nendstream\nendobj\n");
+        assert_eq!(extract_text("docs/odd.pdf", &pdf).unwrap().unwrap(), "Found\n");
+    }
+
+    #[test]
+    fn many_small_bombs_share_one_budget() {
+        // Each stream inflates to 8 MiB, well under the cap on one stream,
+        // but object streams and page contents together go past the total
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&vec![b' '; 8 << 20]).unwrap();
+        let bomb = encoder.finish().unwrap();
+        let (packed, content) = ("<< /Type /ObjStm /N 1 /First 4 /Filter /FlateDecode >>", "<< /Filter /FlateDecode >>");
+        let objects: Vec<(u32, &str, Option<&[u8]>)> = (1..=10).map(|n| (n, if n <= 6 { packed } else { content }, Some(&bomb[..]))).collect();
+        let error = extract_text("docs/bombs.pdf", &pdf_objects(&objects)).unwrap().unwrap_err();
+        assert!(error.contains("inflates to more than"), "{}", error);
+
+        // Fewer of them are read
+        let text = extract_text("docs/bombs.pdf", &pdf_objects(&objects[..7])).unwrap().unwrap();
+        assert_eq!(text, "");
+    }
+
+    #[test]
+    fn word_documents_give_their_paragraphs() {
+        let docx = zip(&[("[Content_Types].xml", "<Types/>"), ("word/document.xml", DOCUMENT_XML)]);
+        let text = extract_text("docs/Sync.docx", &docx).unwrap().unwrap();
+        assert_eq!(text, "Sync protocol\nClients send deltas & acks.\n\nStep\tAction\n");
+
+        let no_body = zip(&[("[Content_Types].xml", "<Types/>")]);
+        assert!(extract_text("docs/empty.docx", &no_body).unwrap().is_err());
+        assert!(extract_text("docs/broken.docx", b"PK").unwrap().is_err());
+    }
+
+    #[test]
+    fn documents_are_packed_as_text() {
+        let store = MemoryStore::new();
+        store.write("docs/spec.pdf", &pdf(b"BT (Rate limits apply) Tj ET")).unwrap();
+        store.write("docs/broken.docx", b"PK\x00\x00").unwrap();
+        let packed = pack(&store, &Gitignore::empty(), &PathFilter::new(&[], &[]).unwrap(), "/repo", None, LongLines::Keep).unwrap();
+        assert_eq!(packed.documents, ["docs/spec.pdf"]);
+        assert!(packed.text.contains("## docs/spec.pdf\n\n```text\nRate limits apply\nPlain (stream)\n```"));
+        assert_eq!(packed.omitted.iter().map(|o| (o.path.as_str(), o.reason)).collect::<Vec<_>>(), [("docs/broken.docx", "noText")]);
+    }
+}
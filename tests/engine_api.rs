@@ -0,0 +1,132 @@
+// The dmp, strict and git patch engines, and picking one per workspace.
+
+use repopatch::engine::{self, detect_format, EngineSelection, PatchFormat, DEFAULT_ENGINE};
+use repopatch::patch::{ApplyOptions, ErrorCode};
+use repopatch::store::MemoryStore;
+use std::collections::BTreeMap;
+
+// Eight lines, the second with trailing whitespace the patches don't have
+fn letters() -> MemoryStore {
+    MemoryStore::from_files([("letters.txt", "a\nb  \nc\nd\ne\nf\ng\nh\n")])
+}
+
+const DRIFTED: &str = "--- a/letters.txt\n+++ b/letters.txt\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n";
+const MOVED: &str = "--- a/letters.txt\n+++ b/letters.txt\n@@ -2,3 +2,3 @@\n f\n-g\n+G\n h\n";
+
+#[test]
+fn engines_are_picked_by_name() {
+    for name in engine::ENGINES {
+        assert_eq!(engine::by_name(name).unwrap().name(), name);
+    }
+    assert!(engine::by_name("patch").is_none());
+}
+
+#[test]
+fn strict_matching_needs_exact_context_and_counts() {
+    let strict = engine::by_name("strict").unwrap();
+    let store = letters();
+    let preview = strict.preview(&strict.parse(DRIFTED, 1), &store, &ApplyOptions::default());
+    assert_eq!(preview.outcome.details[0].code, ErrorCode::ContextMismatch);
+    assert!(preview.files.is_empty());
+
+    let outcome = strict.apply(&strict.parse(MOVED, 1), &store, &ApplyOptions::default());
+    assert!(outcome.is_success(), "hunks may still move: {:?}", outcome.details);
+    assert_eq!(store.get("letters.txt").unwrap(), "a\nb  \nc\nd\ne\nf\nG\nh\n");
+
+    let miscounted = "--- a/letters.txt\n+++ b/letters.txt\n@@ -3,4 +3,4 @@\n c\n-d\n+D\n e\n";
+    let options = ApplyOptions { trace: true, ..ApplyOptions::default() };
+    let outcome = strict.apply(&strict.parse(miscounted, 1), &store, &options);
+    assert!(!outcome.is_success());
+    assert!(outcome.trace.iter().any(|step| step.message.contains("counts 4 old lines in its header but has 3")), "{:?}", outcome.trace);
+    assert!(engine::by_name("dmp").unwrap().apply(&strict.parse(miscounted, 1), &store, &ApplyOptions::default()).is_success());
+}
+
+#[test]
+fn dmp_previews_without_writing() {
+    let dmp = engine::by_name("dmp").unwrap();
+    let store = letters();
+    let preview = dmp.preview(&dmp.parse(DRIFTED, 1), &store, &ApplyOptions::default());
+    assert!(preview.outcome.is_success(), "{:?}", preview.outcome.details);
+    assert_eq!(preview.files["letters.txt"].as_deref(), Some(&b"a\nB\nc\nd\ne\nf\ng\nh\n"[..]));
+    assert_eq!(store.get("letters.txt").unwrap(), "a\nb  \nc\nd\ne\nf\ng\nh\n");
+}
+
+#[test]
+fn git_applies_through_any_store() {
+    if std::process::Command::new("git").arg("--version").output().is_err() {
+        eprintln!("git is not installed; skipping");
+        return;
+    }
+    let git = engine::by_name("git").unwrap();
+    let store = letters();
+    let options = ApplyOptions { trace: true, ..ApplyOptions::default() };
+    let outcome = git.apply(&git.parse(MOVED, 1), &store, &options);
+    assert!(outcome.is_success(), "{:?}", outcome.details);
+    assert_eq!(store.get("letters.txt").unwrap(), "a\nb  \nc\nd\ne\nf\nG\nh\n");
+    assert!(outcome.trace.iter().any(|step| step.message.starts_with("git apply: Hunk #1")), "{:?}", outcome.trace);
+
+    let both = "--- a/letters.txt\n+++ b/letters.txt\n@@ -1,3 +1,3 @@\n a\n-x\n+X\n c\n@@ -4,3 +4,3 @@\n d\n-e\n+E\n f\n";
+    let outcome = git.apply(&git.parse(both, 1), &store, &ApplyOptions::default());
+    let context = outcome.details[0].context.as_ref().expect("failed hunks come with context");
+    assert_eq!(context.failed_hunks, [0]);
+    assert_eq!(store.get("letters.txt").unwrap(), "a\nb  \nc\nd\ne\nf\nG\nh\n", "nothing is written on a partial match");
+}
+
+const SEARCH_REPLACE: &str = "Change the second letter:\n\nletters.txt\n```\n<<<<<<< SEARCH\nb\n=======\nB\n>>>>>>> REPLACE\n```\n\n\
+                              `notes/new.md`\n<<<<<<< SEARCH\n=======\n# New\n>>>>>>> REPLACE\n";
+
+#[test]
+fn formats_are_told_apart() {
+    assert_eq!(detect_format(SEARCH_REPLACE), Some(PatchFormat::SearchReplace));
+    assert_eq!(detect_format(DRIFTED), Some(PatchFormat::Unified));
+    assert_eq!(detect_format(&format!("diff --git a/letters.txt b/letters.txt\nindex 1..2 100644\n{}", DRIFTED)), Some(PatchFormat::Git));
+    assert_eq!(detect_format("--- a/x\n+++ b/x\n@@ -1,6 +1,6 @@\n a%0A\n-b\n+B\n %0Ac\n"), Some(PatchFormat::DmpText));
+    assert_eq!(detect_format("--- a/x\n+++ b/x\n@@ -1 +1 @@ fn main\n-url=%0A\n+url=\n"), Some(PatchFormat::Unified), "function context means a unified diff");
+    assert_eq!(detect_format("Just replace b with B"), None);
+    assert_eq!(PatchFormat::DmpText.engine(), Some("dmp-text"));
+    assert_eq!(PatchFormat::Unified.engine(), None);
+}
+
+#[test]
+fn search_replace_blocks_edit_and_create_files() {
+    let engine = engine::by_name("search-replace").unwrap();
+    let store = letters();
+    let patch = engine.parse(SEARCH_REPLACE, 0);
+    let paths: Vec<(&str, bool)> = patch.files.iter().map(|f| (f.path(), f.is_creation())).collect();
+    assert_eq!(paths, [("letters.txt", false), ("notes/new.md", true)]);
+    let outcome = engine.apply(&patch, &store, &ApplyOptions::default());
+    assert!(outcome.is_success(), "{:?}", outcome.details);
+    assert_eq!(store.get("letters.txt").unwrap(), "a\nB\nc\nd\ne\nf\ng\nh\n");
+    assert_eq!(store.get("notes/new.md").unwrap(), "# New\n");
+    assert!(engine.parse("<<<<<<< SEARCH\nb\n=======\nB\n>>>>>>> REPLACE\n", 0).files.is_empty(), "a block needs a path");
+}
+
+#[test]
+fn dmp_patch_text_applies_by_character() {
+    let engine = engine::by_name("dmp-text").unwrap();
+    let store = MemoryStore::from_files([("fox.txt", "The quick brown fox jumps over the lazy dog.\n")]);
+    let patch = "--- a/fox.txt\n+++ b/fox.txt\n@@ -1,18 +1,17 @@\n The quick \n-brown\n+red\n  fox jum\n";
+    let outcome = engine.apply(&engine.parse(patch, 1), &store, &ApplyOptions::default());
+    assert!(outcome.is_success(), "{:?}", outcome.details);
+    assert_eq!(store.get("fox.txt").unwrap(), "The quick red fox jumps over the lazy dog.\n");
+
+    let missing = "--- a/fox.txt\n+++ b/fox.txt\n@@ -1,12 +1,12 @@\n Pack my \n-box\n+bag\n  with\n";
+    let outcome = engine.apply(&engine.parse(missing, 1), &store, &ApplyOptions::default());
+    assert_eq!(outcome.details[0].code, ErrorCode::ContextMismatch);
+}
+
+#[test]
+fn requests_override_workspace_engines() {
+    let none = EngineSelection::new(&BTreeMap::new()).unwrap();
+    assert_eq!(none.select(None, "/repo").unwrap().name(), DEFAULT_ENGINE);
+
+    let defaults = BTreeMap::from([("/srv/legacy".to_string(), "strict".to_string()), ("*".to_string(), "git".to_string())]);
+    let selection = EngineSelection::new(&defaults).unwrap();
+    assert_eq!(selection.select(None, "/srv/legacy").unwrap().name(), "strict");
+    assert_eq!(selection.select(None, "/srv/other").unwrap().name(), "git");
+    assert_eq!(selection.select(Some("dmp"), "/srv/legacy").unwrap().name(), "dmp");
+    assert!(selection.select(Some("fuzzy"), "/srv/legacy").err().unwrap().contains("dmp, strict, git"));
+
+    let unknown = BTreeMap::from([("/srv".to_string(), "patch".to_string())]);
+    assert!(EngineSelection::new(&unknown).is_err());
+}
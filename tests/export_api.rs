@@ -0,0 +1,41 @@
+// git format-patch export of applied changes.
+
+use chrono::{TimeZone, Utc};
+use repopatch::diff::{format_patch, side_by_side, FileChange, PatchHeader, ViewKind};
+
+#[test]
+fn format_patch_writes_mail_headers_diffstat_and_git_diffs() {
+    let header = PatchHeader {
+        author: "Dev <dev@example.com>".to_string(),
+        date: Utc.with_ymd_and_hms(2026, 1, 2, 3, 4, 5).unwrap(),
+        subject: "Update greeting".to_string(),
+        body: String::new(),
+    };
+    let changes = [
+        FileChange { path: "a.txt".to_string(), old: Some("hello\n".to_string()), new: Some("goodbye\n".to_string()) },
+        FileChange { path: "new.txt".to_string(), old: None, new: Some("x\n".to_string()) },
+        FileChange { path: "same.txt".to_string(), old: Some("s\n".to_string()), new: Some("s\n".to_string()) },
+    ];
+    let mail = format_patch(&header, &changes);
+
+    assert!(mail.starts_with("From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001\nFrom: Dev <dev@example.com>\n"));
+    assert!(mail.contains("Date: Fri, 2 Jan 2026 03:04:05 +0000\nSubject: [PATCH] Update greeting\n"));
+    assert!(mail.contains(" a.txt   | 2 +-\n new.txt | 1 +\n 2 files changed, 2 insertions(+), 1 deletion(-)\n"));
+    assert!(mail.contains("diff --git a/new.txt b/new.txt\nnew file mode 100644\n--- /dev/null\n+++ b/new.txt\n@@ -0,0 +1 @@\n+x\n"));
+    assert!(!mail.contains("same.txt"));
+}
+
+#[test]
+fn side_by_side_aligns_lines_and_marks_changed_characters() {
+    let old = "a\nb\nc\nd\nkeep\nlet x = 1;\ngone\n";
+    let new = "a\nb\nc\nd\nkeep\nlet x = 2;\n";
+    let view = side_by_side(old, new, Some(1));
+    assert_eq!(view.old.len(), view.new.len());
+
+    let kinds: Vec<_> = view.old.iter().map(|l| l.as_ref().map(|l| l.kind)).collect();
+    assert_eq!(kinds, [Some(ViewKind::Skip), Some(ViewKind::Equal), Some(ViewKind::Change), Some(ViewKind::Delete)]);
+    assert_eq!(view.old[0].as_ref().unwrap().skipped, Some(4));
+    let changed = view.new[2].as_ref().unwrap();
+    assert_eq!((changed.number, changed.text.as_str(), changed.spans.as_slice()), (6, "let x = 2;", &[(8, 9)][..]));
+    assert!(view.new[3].is_none());
+}
@@ -0,0 +1,18 @@
+// filler line number 1
+// filler line number 2
+// filler line number 3
+// filler line number 4
+// filler line number 5
+// filler line number 6
+// filler line number 7
+// filler line number 8
+// filler line number 9
+// filler line number 10
+fn render(items: &[Item]) -> String {
+    let mut out = String::new();
+    for item in items {
+        out.push_str(&item.name);
+        out.push('\n');
+    }
+    out
+}
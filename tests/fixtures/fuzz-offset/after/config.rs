@@ -0,0 +1,21 @@
+// filler 1
+// filler 2
+// filler 3
+// filler 4
+// filler 5
+// filler 6
+// filler 7
+// filler 8
+// filler 9
+// filler 10
+// filler 11
+// filler 12
+// filler 13
+// filler 14
+// filler 15
+fn config() -> Config {
+    Config {
+        retries: 5,
+        verbose: false,
+    }
+}
@@ -0,0 +1,7 @@
+pub fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+pub fn sub(a: i32, b: i32) -> i32 {
+    a.wrapping_sub(b)
+}
@@ -0,0 +1,5 @@
+mod helpers;
+
+fn main() {
+    helpers::run();
+}
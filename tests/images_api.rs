@@ -0,0 +1,135 @@
+// Image formats and dimensions in trees, and the pixels behind thumbnails.
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use ignore::gitignore::Gitignore;
+use repopatch::images::{decode, downscale, encode_png, image_info, ImageInfo, Rgba};
+use repopatch::store::{FileStore, MemoryStore};
+use repopatch::tree::build_tree;
+use std::io::Write;
+
+fn info(format: &'static str, width: u32, height: u32) -> Option<ImageInfo> {
+    Some(ImageInfo { format, width, height })
+}
+
+// A PNG of filtered `rows`, with chunk checksums left at zero
+fn png(width: u32, height: u32, depth: u8, color: u8, rows: &[u8], extra: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+    let chunk = |png: &mut Vec<u8>, kind: &[u8], body: &[u8]| {
+        png.extend((body.len() as u32).to_be_bytes());
+        png.extend(kind);
+        png.extend(body);
+        png.extend([0; 4]);
+    };
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    let header = [width.to_be_bytes().as_slice(), &height.to_be_bytes(), &[depth, color, 0, 0, 0]].concat();
+    chunk(&mut png, b"IHDR", &header);
+    for (kind, body) in extra {
+        chunk(&mut png, *kind, body);
+    }
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(rows).unwrap();
+    chunk(&mut png, b"IDAT", &encoder.finish().unwrap());
+    chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+// A bottom-up 24-bit BMP with rows padded to four bytes
+fn bmp(width: u32, height: u32, bgr: &[[u8; 3]]) -> Vec<u8> {
+    let stride = (width as usize * 3).div_ceil(4) * 4;
+    let mut data = b"BM".to_vec();
+    data.extend((54 + stride as u32 * height).to_le_bytes());
+    data.extend([0, 0, 0, 0]);
+    data.extend(54u32.to_le_bytes());
+    data.extend(40u32.to_le_bytes());
+    data.extend(width.to_le_bytes());
+    data.extend(height.to_le_bytes());
+    data.extend([1, 0, 24, 0]);
+    data.extend([0; 24]);
+    for row in bgr.chunks(width as usize).rev() {
+        let mut line: Vec<u8> = row.iter().flatten().copied().collect();
+        line.resize(stride, 0);
+        data.extend(line);
+    }
+    data
+}
+
+#[test]
+fn formats_and_dimensions_come_from_the_headers() {
+    let red = Rgba { width: 3, height: 2, pixels: [255, 0, 0, 255].repeat(6) };
+    assert_eq!(image_info(&encode_png(&red)), info("png", 3, 2));
+    assert_eq!(image_info(b"GIF89a\x40\x01\xf0\x00\x80\x00\x00"), info("gif", 320, 240));
+    assert_eq!(image_info(&bmp(2, 1, &[[0, 0, 255], [255, 0, 0]])), info("bmp", 2, 1));
+    let jpeg = b"\xff\xd8\xff\xe0\x00\x10JFIF\x00\x01\x01\x00\x00\x01\x00\x01\x00\x00\xff\xc0\x00\x11\x08\x01\xe0\x02\x80\x03\x01\x22\x00";
+    assert_eq!(image_info(jpeg), info("jpeg", 640, 480));
+    assert_eq!(image_info(br#"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg" width="48px" height="24">"#), info("svg", 48, 24));
+    assert_eq!(image_info(br#"<svg viewBox="0 0 100 50"><rect/></svg>"#), info("svg", 100, 50));
+    assert_eq!(image_info(b"fn main() {}"), None);
+    assert_eq!(image_info(b"\x89PNG\r\n"), None, "a truncated header");
+}
+
+#[test]
+fn pngs_and_bmps_are_decoded_and_scaled_down() {
+    let image = Rgba { width: 4, height: 2, pixels: [[255, 0, 0, 255], [0, 0, 255, 255]].repeat(4).concat() };
+    assert_eq!(decode(&encode_png(&image)).unwrap(), image, "encoding round-trips");
+
+    let small = downscale(&image, 2);
+    assert_eq!((small.width, small.height), (2, 1));
+    assert_eq!(small.pixels, [127, 0, 127, 255].repeat(2), "each pixel averages the ones it covers");
+    assert_eq!(downscale(&image, 16), image, "small images stay as they are");
+
+    // A fully transparent pixel doesn't darken its neighbour
+    let edge = Rgba { width: 2, height: 1, pixels: vec![255, 255, 255, 255, 0, 0, 0, 0] };
+    assert_eq!(downscale(&edge, 1).pixels, [255, 255, 255, 127]);
+
+    let decoded = decode(&bmp(3, 2, &[[0, 0, 255], [0, 255, 0], [255, 0, 0], [0, 0, 0], [255, 255, 255], [0, 0, 0]])).unwrap();
+    assert_eq!((decoded.width, decoded.height), (3, 2));
+    assert_eq!(&decoded.pixels[..12], [255, 0, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255], "the first row is the top one");
+    assert_eq!(&decoded.pixels[16..20], [255, 255, 255, 255]);
+
+    assert!(decode(b"GIF89a\x01\x00\x01\x00").is_err(), "GIFs aren't decoded");
+}
+
+#[test]
+fn paletted_and_filtered_pngs_are_decoded() {
+    // A 2-bit palette row where index 0 is transparent
+    let palette: &[u8] = &[0, 0, 0, 255, 0, 0, 0, 255, 0, 0, 0, 255];
+    let data = png(4, 1, 2, 3, &[0, 0b0001_1011], &[(b"PLTE", palette), (b"tRNS", &[0])]);
+    let decoded = decode(&data).unwrap();
+    assert_eq!((decoded.width, decoded.height), (4, 1));
+    assert_eq!(decoded.pixels, [0, 0, 0, 0, 255, 0, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255]);
+
+    // Sub adds the byte to the left
+    let sub = png(3, 1, 8, 0, &[1, 10, 20, 30], &[]);
+    assert_eq!(decode(&sub).unwrap().pixels, [10, 10, 10, 255, 30, 30, 30, 255, 60, 60, 60, 255]);
+
+    let gray = png(2, 1, 16, 0, &[0, 0xff, 0xff, 0x80, 0x00], &[]);
+    assert_eq!(decode(&gray).unwrap().pixels, [255, 255, 255, 255, 128, 128, 128, 255]);
+}
+
+#[test]
+fn trees_give_the_format_and_size_of_images() {
+    let store = MemoryStore::new();
+    store.write("docs/logo.png", &encode_png(&Rgba { width: 5, height: 7, pixels: vec![0; 140] })).unwrap();
+    store.write("docs/icon.svg", br#"<svg width="16" height="16"></svg>"#).unwrap();
+    store.write("docs/fake.png", b"not really").unwrap();
+    store.write("src/main.rs", b"fn main() {}").unwrap();
+    let tree = build_tree(&store, "", &Gitignore::empty()).unwrap();
+    let docs = tree["docs"].children.as_ref().unwrap();
+    assert_eq!(docs["logo.png"].image, info("png", 5, 7));
+    assert_eq!(docs["icon.svg"].image, info("svg", 16, 16));
+    assert_eq!(docs["fake.png"].image, None);
+    assert_eq!(tree["src"].children.as_ref().unwrap()["main.rs"].image, None);
+    let json = serde_json::to_value(&docs["logo.png"]).unwrap();
+    assert_eq!(json["image"], serde_json::json!({ "format": "png", "width": 5, "height": 7 }));
+}
+
+#[test]
+fn hostile_headers_are_refused_without_panicking() {
+    // A second IHDR would get past the size checked on the first
+    let header = [60000u32.to_be_bytes().as_slice(), &60000u32.to_be_bytes(), &[8, 6, 0, 0, 0]].concat();
+    let data = png(1, 1, 8, 6, &[0, 0, 0, 0, 0], &[(b"IHDR", &header)]);
+    assert_eq!(decode(&data).unwrap_err(), "The PNG file has more than one IHDR chunk");
+
+    assert_eq!(image_info("<svg width=é5\" height=\"4\">".as_bytes()), None);
+    assert_eq!(image_info("<svg width='5' height=\"4\">".as_bytes()), info("svg", 5, 4));
+}
@@ -0,0 +1,78 @@
+// Flagging minified files and wrapping or eliding their long lines.
+
+use ignore::gitignore::Gitignore;
+use repopatch::longlines::{file_has_long_lines, has_long_lines, longest_line, soften, LongLines, LONG_LINE_CHARS};
+use repopatch::pack::pack;
+use repopatch::store::MemoryStore;
+use repopatch::tree::{build_tree, PathFilter};
+
+#[test]
+fn long_lines_are_wrapped_or_elided_and_short_ones_kept() {
+    let long = "é".repeat(LONG_LINE_CHARS + 50);
+    let text = format!("short\n{}\nend", long);
+    assert!(has_long_lines(text.as_bytes()));
+    assert_eq!(longest_line(&text), LONG_LINE_CHARS + 50);
+    assert!(!has_long_lines("a\n".repeat(5000).as_bytes()), "a long file of short lines");
+
+    let (wrapped, count) = soften(&text, LongLines::Wrap { width: 400 });
+    assert_eq!(count, 1);
+    let lines: Vec<usize> = wrapped.lines().map(|l| l.chars().count()).collect();
+    assert_eq!(lines, [5, 400, 400, 250, 3]);
+    assert_eq!(wrapped.replace('\n', ""), text.replace('\n', ""), "wrapping only adds line breaks");
+
+    let (elided, count) = soften(&text, LongLines::Elide { width: 30 });
+    assert_eq!(count, 1);
+    assert_eq!(elided, format!("short\n{} … [1020 more characters]\nend", "é".repeat(30)));
+
+    let (kept, count) = soften(&text, LongLines::Keep);
+    assert_eq!((kept.as_str(), count), (text.as_str(), 1));
+    assert_eq!(soften("a\nb\n", LongLines::Elide { width: 30 }), ("a\nb\n".to_string(), 0));
+}
+
+#[test]
+fn modes_are_parsed_with_a_width() {
+    assert_eq!(LongLines::parse(None, None), Ok(LongLines::Keep));
+    assert_eq!(LongLines::parse(Some("wrap"), None), Ok(LongLines::Wrap { width: 200 }));
+    assert_eq!(LongLines::parse(Some("elide"), Some(80)), Ok(LongLines::Elide { width: 80 }));
+    assert!(LongLines::parse(Some("fold"), None).is_err());
+    assert!(LongLines::parse(Some("wrap"), Some(5)).is_err());
+}
+
+#[test]
+fn minified_files_are_flagged_in_the_tree() {
+    let bundle = format!("var a={};\n", "1+".repeat(LONG_LINE_CHARS));
+    let store = MemoryStore::from_files([
+        ("dist/app.min.js", "x"),
+        ("dist/bundle.js", bundle.as_str()),
+        ("src/app.js", "let a = 1;\n"),
+        ("src/data.rs", bundle.as_str()),
+    ]);
+    assert!(file_has_long_lines(&store, "dist/app.min.js", Some(1)), "named minified");
+    assert!(file_has_long_lines(&store, "dist/bundle.js", Some(bundle.len() as u64)));
+    assert!(!file_has_long_lines(&store, "src/app.js", Some(11)));
+    assert!(!file_has_long_lines(&store, "src/data.rs", Some(bundle.len() as u64)), "only likely minified kinds are read");
+
+    let tree = build_tree(&store, "", &Gitignore::empty()).unwrap();
+    let dist = tree["dist"].children.as_ref().unwrap();
+    assert_eq!(dist["bundle.js"].long_lines, Some(true));
+    assert_eq!(tree["src"].children.as_ref().unwrap()["app.js"].long_lines, None);
+    let json = serde_json::to_value(&dist["bundle.js"]).unwrap();
+    assert_eq!(json["longLines"], true);
+}
+
+#[test]
+fn packs_name_and_shorten_files_with_long_lines() {
+    let bundle = format!("{}\n", "x".repeat(4 * LONG_LINE_CHARS));
+    let store = MemoryStore::from_files([("app.js", bundle.as_str()), ("main.rs", "fn main() {}\n")]);
+    let everything = PathFilter::new(&[], &[]).unwrap();
+
+    let kept = pack(&store, &Gitignore::empty(), &everything, "/repo", None, LongLines::Keep).unwrap();
+    assert_eq!(kept.long_lines, ["app.js"]);
+    assert!(kept.text.contains(&bundle));
+
+    let elided = pack(&store, &Gitignore::empty(), &everything, "/repo", None, LongLines::Elide { width: 100 }).unwrap();
+    assert_eq!(elided.long_lines, ["app.js"]);
+    assert!(elided.text.contains(&format!("{} … [3900 more characters]\n", "x".repeat(100))));
+    assert!(elided.tokens * 10 < kept.tokens);
+    assert_eq!(elided.files, ["app.js", "main.rs"]);
+}
@@ -0,0 +1,58 @@
+// In-memory repository mode: seeding and serving a MemoryStore as a directory.
+
+use repopatch::patch::PatchSet;
+use repopatch::seed;
+use repopatch::store::{AliasStoreProvider, MemoryStore, MemoryStoreProvider, StoreProvider};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[test]
+fn seeds_from_json_and_tar() {
+    let store = seed::from_json(br#"{"src/main.rs": "fn main() {}\n", "README.md": "demo\n"}"#).unwrap();
+    assert_eq!(store.get("src/main.rs").unwrap(), "fn main() {}\n");
+    assert!(seed::from_json(br#"{"../escape": ""}"#).is_err());
+
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(3);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, "./docs/a.txt", &b"abc"[..]).unwrap();
+    let archive = builder.into_inner().unwrap();
+    assert_eq!(seed::from_tar(&archive[..]).unwrap().get("docs/a.txt").unwrap(), "abc");
+}
+
+#[test]
+fn memory_provider_serves_roots_and_files_under_a_virtual_path() {
+    let memory = Arc::new(MemoryStore::from_files([("src/lib.rs", "old\n"), ("README.md", "demo\n")]));
+    let provider = MemoryStoreProvider::new("/demo", memory.clone());
+
+    let root = provider.open_root("/demo").unwrap();
+    assert_eq!(root.display_path("src/lib.rs"), "/demo/src/lib.rs");
+    let patch = "--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -1 +1 @@\n-old\n+new\n";
+    assert!(PatchSet::parse(patch, 1).apply(&*root).is_success());
+    assert_eq!(memory.get("src/lib.rs").unwrap(), "new\n");
+
+    let src = provider.open_root("/demo/src").unwrap();
+    assert_eq!(src.list_dir("").unwrap()[0].name, "lib.rs");
+    let (store, name) = provider.open_file("/demo/src/lib.rs").unwrap();
+    assert_eq!(store.read(&name).unwrap(), b"new\n");
+    assert!(provider.open_root("/elsewhere").is_err());
+    assert!(provider.open_root("/demo/missing").is_err());
+}
+
+#[test]
+fn workspace_aliases_resolve_to_their_roots() {
+    let memory = Arc::new(MemoryStore::from_files([("repo/src/main.rs", "fn main() {}\n")]));
+    let inner = Arc::new(MemoryStoreProvider::new("/demo", memory));
+    let aliases = BTreeMap::from([("myrepo".to_string(), "/demo/repo".to_string())]);
+    let provider = AliasStoreProvider::new(inner.clone(), aliases).unwrap();
+
+    assert_eq!(provider.open_root("@myrepo").unwrap().display_path(""), "/demo/repo");
+    let (store, name) = provider.open_file("@myrepo/src/main.rs").unwrap();
+    assert_eq!(store.display_path(&name), "/demo/repo/src/main.rs");
+    assert_eq!(provider.open_root("/demo/repo/src").unwrap().display_path(""), "/demo/repo/src");
+    assert!(provider.resolve("@other/x").unwrap_err().contains("Unknown workspace alias"));
+    assert!(provider.open_file("@myrepo/../escape").is_err());
+    assert!(AliasStoreProvider::new(inner, BTreeMap::from([("bad name".to_string(), "/demo".to_string())])).is_err());
+}
@@ -0,0 +1,76 @@
+// Localizing server messages through the message catalogs.
+
+use repopatch::messages::{fill, load_dir, parse, Catalogs};
+use std::collections::BTreeMap;
+
+fn catalogs(entries: &[(&str, &str)]) -> Catalogs {
+    let mut catalogs = BTreeMap::new();
+    for (locale, text) in entries {
+        catalogs.insert(locale.to_string(), parse(text).unwrap());
+    }
+    Catalogs::new(catalogs, "en").unwrap()
+}
+
+#[test]
+fn messages_are_matched_by_template_and_their_parts_translated() {
+    let catalogs = Catalogs::builtin();
+
+    let localized = catalogs.localize("Invalid directory path: Invalid path '/srv/x': No such file or directory (os error 2)", "de").unwrap();
+    assert_eq!(localized.code, "INVALID_DIRECTORY_PATH");
+    assert_eq!(localized.text, "Ungültiger Verzeichnispfad: Ungültiger Pfad '/srv/x': Datei oder Verzeichnis nicht gefunden");
+
+    let localized = catalogs.localize("src/lib.rs is not inside /srv/app", "fr").unwrap();
+    assert_eq!((localized.code.as_str(), localized.text.as_str()), ("NOT_INSIDE", "src/lib.rs n'est pas dans /srv/app"));
+
+    // English keeps the text and still names the message
+    let localized = catalogs.localize("Admin access required", "en").unwrap();
+    assert_eq!((localized.code.as_str(), localized.text.as_str()), ("ADMIN_REQUIRED", "Admin access required"));
+    assert_eq!(catalogs.localize("Something nobody catalogued", "de"), None);
+}
+
+#[test]
+fn accept_language_picks_by_quality_and_falls_back_to_the_language() {
+    let catalogs = Catalogs::builtin();
+
+    assert_eq!(catalogs.negotiate(Some("de-AT,de;q=0.9,en;q=0.8")), "de");
+    assert_eq!(catalogs.negotiate(Some("ja, fr;q=0.5, es;q=0.7")), "es");
+    assert_eq!(catalogs.negotiate(Some("es;q=0, fr-CA")), "fr");
+    assert_eq!(catalogs.negotiate(Some("ja, *")), "en");
+    assert_eq!(catalogs.negotiate(None), "en");
+    assert_eq!(catalogs.locales(), ["de", "en", "es", "fr"]);
+}
+
+#[test]
+fn placeholders_can_be_reordered_and_are_checked() {
+    let catalogs = catalogs(&[("en", r#"INCOMPLETE_UPLOAD = "Received {} of {} bytes""#), ("xx", r#"INCOMPLETE_UPLOAD = "{2} bytes, got {1}""#)]);
+    assert_eq!(catalogs.localize("Received 10 of 40 bytes", "xx").unwrap().text, "40 bytes, got 10");
+    assert_eq!(fill("{} and {}, not {1}", &["a".to_string(), "b".to_string()]), "a and b, not a");
+
+    let mut bad = BTreeMap::new();
+    bad.insert("en".to_string(), parse(r#"NO_CHAT = "No chat {}""#).unwrap());
+    bad.insert("xx".to_string(), parse(r#"NO_CHAT = "{2}""#).unwrap());
+    assert!(Catalogs::new(bad.clone(), "en").err().unwrap().contains("placeholder {2}"));
+    bad.insert("xx".to_string(), parse(r#"UNHEARD_OF = "?""#).unwrap());
+    assert!(Catalogs::new(bad, "en").err().unwrap().contains("unknown code UNHEARD_OF"));
+}
+
+#[test]
+fn catalogs_load_from_a_folder_and_fall_back_to_english() {
+    let dir = std::env::temp_dir().join(format!("repopatch-messages-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("NL.toml"), r#"NO_CHAT = "Geen chat {}""#).unwrap();
+    std::fs::write(dir.join("notes.txt"), "not a catalog").unwrap();
+
+    let mut all = load_dir(&dir).unwrap();
+    all.insert("en".to_string(), parse(r#"NO_CHAT = "No chat {}"
+NO_TEMPLATE = "No template named {}""#).unwrap());
+    let catalogs = Catalogs::new(all, "nl").unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(catalogs.default_locale(), "nl");
+    assert_eq!(catalogs.negotiate(Some("ja")), "nl");
+    assert_eq!(catalogs.localize("No chat 7", "nl").unwrap().text, "Geen chat 7");
+    let messages = catalogs.messages("nl");
+    assert_eq!(messages["NO_TEMPLATE"], "No template named {}");
+    assert_eq!(messages["NO_CHAT"], "Geen chat {}");
+}
@@ -0,0 +1,106 @@
+// The SQLite metadata database, with the `sqlite` feature, and the JSON files
+// kept without it.
+
+use repopatch::metadata::{write_json_atomically, Metadata, ENABLED};
+
+#[test]
+fn the_database_needs_the_feature() {
+    assert_eq!(ENABLED, cfg!(feature = "sqlite"));
+    let dir = std::env::temp_dir().join(format!("repopatch-metadata-off-{}", std::process::id()));
+    let opened = Metadata::open(&dir);
+    assert_eq!(opened.is_ok(), ENABLED);
+    if !ENABLED {
+        assert!(opened.err().unwrap().contains("sqlite feature"));
+        assert!(!dir.exists(), "nothing is created for a database that can't open");
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[cfg(feature = "sqlite")]
+mod database {
+    use repopatch::metadata::{object_entries, Metadata, DATABASE_FILE};
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    fn data_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("repopatch-metadata-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn records_are_saved_queried_and_kept_across_opens() {
+        let dir = data_dir("records");
+        let metadata = Metadata::open(&dir.join("nested")).unwrap();
+        assert!(dir.join("nested").join(DATABASE_FILE).is_file());
+        assert_eq!(metadata.schema_version().unwrap(), 2);
+
+        metadata.put("chats", "c1", &json!({ "owner": "ann", "title": "Fix the tree" })).unwrap();
+        metadata.put("chats", "c2", &json!({ "owner": "bob", "title": "It's \"quoted\"" })).unwrap();
+        metadata.put("settings", "ann", &json!({ "theme": "dark" })).unwrap();
+        metadata.put("chats", "c1", &json!({ "owner": "ann", "title": "Fix the tree view" })).unwrap();
+
+        assert_eq!(metadata.get("chats", "c1").unwrap(), Some(json!({ "owner": "ann", "title": "Fix the tree view" })));
+        assert_eq!(metadata.get("chats", "c3").unwrap(), None);
+        let keys: Vec<String> = metadata.load("chats").unwrap().into_iter().map(|r| r.key).collect();
+        assert_eq!(keys, ["c2", "c1"], "oldest write first");
+        let bobs = metadata.find("chats", "owner", "bob").unwrap();
+        assert_eq!((bobs.len(), bobs[0].value["title"].as_str()), (1, Some("It's \"quoted\"")));
+        assert!(metadata.find("chats", "owner", "' OR 1=1 --").unwrap().is_empty());
+
+        assert!(metadata.delete("chats", "c2").unwrap());
+        assert!(!metadata.delete("chats", "c2").unwrap());
+        drop(metadata);
+
+        let reopened = Metadata::open(&dir.join("nested")).unwrap();
+        assert_eq!(reopened.load("chats").unwrap().len(), 1);
+        assert_eq!(reopened.load_as::<serde_json::Value>("settings").unwrap(), [("ann".to_string(), json!({ "theme": "dark" }))]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn pruning_keeps_the_latest_records() {
+        let dir = data_dir("prune");
+        let metadata = Metadata::open(&dir).unwrap();
+        for n in 1..=5 {
+            metadata.put("reports", &format!("r{}", n), &json!({ "n": n })).unwrap();
+        }
+        assert_eq!(metadata.prune("reports", 2).unwrap(), 3);
+        let keys: Vec<String> = metadata.load("reports").unwrap().into_iter().map(|r| r.key).collect();
+        assert_eq!(keys, ["r4", "r5"]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn json_files_are_imported_once() {
+        let dir = data_dir("import");
+        let metadata = Metadata::open(&dir).unwrap();
+        let file = dir.join("repopatch-settings.json");
+        std::fs::write(&file, r#"{"ann": {"theme": "dark"}, "bob": {"fuzz": 2}}"#).unwrap();
+
+        assert_eq!(metadata.import_file("settings", &dir.join("missing.json"), object_entries).unwrap(), 0);
+        assert_eq!(metadata.import_file("settings", &file, object_entries).unwrap(), 2);
+        assert_eq!(metadata.get("settings", "bob").unwrap(), Some(json!({ "fuzz": 2 })));
+
+        std::fs::write(&file, r#"{"cy": {}}"#).unwrap();
+        assert_eq!(metadata.import_file("settings", &file, object_entries).unwrap(), 0, "a kind with records isn't imported again");
+        std::fs::write(&file, "[1, 2]").unwrap();
+        assert!(metadata.import_file("profiles", &file, object_entries).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
+
+#[test]
+fn json_files_are_replaced_whole() {
+    let dir = std::env::temp_dir().join(format!("repopatch-metadata-json-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("settings.json");
+    write_json_atomically(&path, &serde_json::json!({ "alice": { "theme": "dark" } })).unwrap();
+    write_json_atomically(&path, &serde_json::json!({ "bob": {} })).unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "{\n  \"bob\": {}\n}");
+    let names: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+    assert_eq!(names, ["settings.json"], "no temporary file is left behind");
+
+    assert!(write_json_atomically(&dir.join("missing/settings.json"), &serde_json::json!({})).is_err());
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,131 @@
+// Reading and patching Jupyter notebooks through their source view.
+
+use ignore::gitignore::Gitignore;
+use repopatch::longlines::LongLines;
+use repopatch::notebook::{apply_view, language, source_view, view_paths};
+use repopatch::pack::pack;
+use repopatch::patch::PatchSet;
+use repopatch::store::MemoryStore;
+use repopatch::tree::PathFilter;
+use serde_json::Value;
+
+const NOTEBOOK: &str = r##"{
+ "cells": [
+  {
+   "cell_type": "markdown",
+   "id": "intro",
+   "metadata": {},
+   "source": [
+    "# Sales\n",
+    "Totals by month."
+   ]
+  },
+  {
+   "cell_type": "code",
+   "execution_count": 3,
+   "id": "load",
+   "metadata": {
+    "tags": ["setup"]
+   },
+   "outputs": [
+    {
+     "name": "stdout",
+     "output_type": "stream",
+     "text": ["12 rows\n"]
+    }
+   ],
+   "source": [
+    "import pandas as pd\n",
+    "df = pd.read_csv(\"sales.csv\")\n",
+    "print(len(df), \"rows\")"
+   ]
+  }
+ ],
+ "metadata": {
+  "kernelspec": {
+   "display_name": "Python 3",
+   "language": "python",
+   "name": "python3"
+  }
+ },
+ "nbformat": 4,
+ "nbformat_minor": 5
+}
+"##;
+
+const VIEW: &str = "# %% [markdown] id=intro\n# Sales\nTotals by month.\n\n# %% [code] id=load\nimport pandas as pd\ndf = pd.read_csv(\"sales.csv\")\nprint(len(df), \"rows\")\n";
+
+fn cells(notebook: &str) -> Vec<Value> {
+    serde_json::from_str::<Value>(notebook).unwrap()["cells"].as_array().unwrap().clone()
+}
+
+#[test]
+fn the_source_view_lists_cells_without_outputs() {
+    assert_eq!(source_view(NOTEBOOK).unwrap(), VIEW);
+    assert_eq!(language(NOTEBOOK).as_deref(), Some("python"));
+    assert!(source_view("not json").is_err());
+
+    let old = r#"{"cells": [{"cell_type": "code", "metadata": {}, "outputs": [], "source": "x = 1"}], "metadata": {}, "nbformat": 4, "nbformat_minor": 2}"#;
+    assert_eq!(source_view(old).unwrap(), "# %% [code] id=#1\nx = 1\n", "cells without ids go by position");
+}
+
+#[test]
+fn views_are_turned_back_into_the_notebook() {
+    assert_eq!(apply_view(Some(NOTEBOOK), VIEW).unwrap(), NOTEBOOK, "an unchanged view keeps the file as it is");
+
+    let edited = VIEW.replace("print(len(df), \"rows\")", "print(df.head())") + "\n# %% [markdown]\nDone.\n";
+    let notebook = apply_view(Some(NOTEBOOK), &edited).unwrap();
+    let cells = cells(&notebook);
+    assert_eq!(cells.len(), 3);
+    assert_eq!(cells[0], self::cells(NOTEBOOK)[0], "untouched cells stay as they were");
+    assert_eq!(cells[1]["source"], serde_json::json!(["import pandas as pd\n", "df = pd.read_csv(\"sales.csv\")\n", "print(df.head())"]));
+    assert_eq!(cells[1]["outputs"], serde_json::json!([]), "edited cells lose their stale outputs");
+    assert_eq!(cells[1]["execution_count"], Value::Null);
+    assert_eq!(cells[1]["metadata"]["tags"][0], "setup");
+    assert_eq!((cells[2]["cell_type"].as_str(), cells[2]["source"][0].as_str()), (Some("markdown"), Some("Done.")));
+    assert_eq!(cells[2]["id"].as_str().map(str::len), Some(8));
+    assert!(notebook.contains("\n \"nbformat\": 4,\n") && notebook.ends_with("}\n"), "written the way Jupyter does");
+
+    assert!(apply_view(Some(NOTEBOOK), "print(1)\n").is_err(), "a view starts with a marker");
+    assert!(apply_view(Some(NOTEBOOK), "# %% [sql]\nselect 1\n").is_err());
+}
+
+#[test]
+fn patches_against_the_view_are_applied_to_the_notebook() {
+    let store = MemoryStore::from_files([("analysis.ipynb", NOTEBOOK)]);
+    let patch = "--- a/analysis.ipynb\n+++ b/analysis.ipynb\n@@ -5,4 +5,4 @@\n # %% [code] id=load\n import pandas as pd\n-df = pd.read_csv(\"sales.csv\")\n+df = pd.read_csv(\"sales.csv\", parse_dates=[\"month\"])\n print(len(df), \"rows\")\n";
+    let patch_set = PatchSet::parse(patch, 1);
+    assert!(view_paths(&patch_set, &store).contains("analysis.ipynb"));
+
+    let outcome = patch_set.apply(&store);
+    assert!(outcome.is_success(), "{:?}", outcome.details);
+    let notebook = store.get("analysis.ipynb").unwrap();
+    assert_eq!(cells(&notebook)[1]["source"][1], "df = pd.read_csv(\"sales.csv\", parse_dates=[\"month\"])\n");
+    assert_eq!(cells(&notebook)[1]["metadata"]["tags"][0], "setup");
+    assert!(serde_json::from_str::<Value>(&notebook).is_ok());
+
+    // Patches to the JSON itself still apply to the JSON
+    let raw = "--- a/analysis.ipynb\n+++ b/analysis.ipynb\n@@ -1,4 +1,4 @@\n {\n  \"cells\": [\n   {\n-   \"cell_type\": \"markdown\",\n+   \"cell_type\": \"raw\",\n";
+    let raw = PatchSet::parse(raw, 1);
+    assert!(view_paths(&raw, &store).is_empty());
+    assert!(raw.apply(&store).is_success());
+    assert!(store.get("analysis.ipynb").unwrap().contains("\"cell_type\": \"raw\""));
+}
+
+#[test]
+fn new_notebooks_can_be_written_as_a_view() {
+    let store = MemoryStore::from_files([("README.md", "")]);
+    let patch = "--- /dev/null\n+++ b/new.ipynb\n@@ -0,0 +1,2 @@\n+# %% [code]\n+print(\"hi\")\n";
+    assert!(PatchSet::parse(patch, 1).apply(&store).is_success());
+    let notebook: Value = serde_json::from_str(&store.get("new.ipynb").unwrap()).unwrap();
+    assert_eq!(notebook["nbformat"], 4);
+    assert_eq!(notebook["cells"][0]["source"], serde_json::json!(["print(\"hi\")"]));
+}
+
+#[test]
+fn notebooks_are_packed_as_their_view() {
+    let store = MemoryStore::from_files([("analysis.ipynb", NOTEBOOK)]);
+    let packed = pack(&store, &Gitignore::empty(), &PathFilter::new(&[], &[]).unwrap(), "/repo", None, LongLines::Keep).unwrap();
+    assert!(packed.text.contains(&format!("```python\n{}```", VIEW)));
+    assert!(!packed.text.contains("12 rows"), "outputs are left out");
+}
@@ -0,0 +1,242 @@
+// Exercises the public library API against the in-memory store.
+
+use repopatch::patch::{self, ApplyOptions, ErrorCode, PatchSet};
+use repopatch::remap::{PathRewrites, Rewrite, RewriteConfig};
+use repopatch::store::{DirEntry, FileStore, HiddenFiles, MemoryStore, ProtectedPaths, ProtectedStore};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+#[test]
+fn parse_strips_paths_and_classifies_files() {
+    let patch = "--- a/src/old.rs\n+++ b/src/new.rs\n@@ -1 +1 @@\n-a\n+b\n--- /dev/null\n+++ b/added.txt\n@@ -0,0 +1 @@\n+x\n";
+    let set = PatchSet::parse(patch, 1);
+    assert_eq!(set.files.len(), 2);
+    assert!(set.files[0].is_rename());
+    assert_eq!(set.files[0].path(), "src/old.rs");
+    assert!(set.files[1].is_creation());
+    assert_eq!(set.files[1].path(), "added.txt");
+}
+
+#[test]
+fn rename_moves_content_in_memory() {
+    let store = MemoryStore::from_files([("src/old.rs", "fn a() {}\n")]);
+    let patch = "--- a/src/old.rs\n+++ b/src/new.rs\n@@ -1 +1 @@\n-fn a() {}\n+fn b() {}\n";
+    let outcome = PatchSet::parse(patch, 1).apply(&store);
+    assert!(outcome.is_success(), "{:?}", outcome.details);
+    assert_eq!(outcome.applied_files, vec!["src/new.rs"]);
+    assert!(store.get("src/old.rs").is_none());
+    assert_eq!(store.get("src/new.rs").unwrap(), "fn b() {}\n");
+}
+
+#[test]
+fn git_renames_without_hunks_move_the_file() {
+    let store = MemoryStore::from_files([("a.bin", "\0\x01"), ("b.rs", "one\n"), ("c.rs", "two\n")]);
+    let patch = "diff --git a/a.bin b/bin/a.bin\nsimilarity index 100%\nrename from a.bin\nrename to bin/a.bin\n\
+                 --- a/b.rs\n+++ b/b.rs\n@@ -1 +1 @@\n-one\n+uno\n\
+                 diff --git a/c.rs b/d.rs\nrename from c.rs\nrename to d.rs\n--- a/c.rs\n+++ b/d.rs\n@@ -1 +1 @@\n-two\n+dos\n";
+    let set = PatchSet::parse(patch, 1);
+    let paths: Vec<_> = set.files.iter().map(|f| (f.path(), f.new_path.as_deref().unwrap())).collect();
+    assert_eq!(paths, [("a.bin", "bin/a.bin"), ("b.rs", "b.rs"), ("c.rs", "d.rs")]);
+    let outcome = set.apply(&store);
+    assert!(outcome.is_success(), "{:?}", outcome.details);
+    assert_eq!(store.files().get("bin/a.bin").unwrap(), b"\0\x01");
+    assert_eq!(store.get("b.rs").unwrap(), "uno\n");
+    assert_eq!(store.get("d.rs").unwrap(), "dos\n");
+    assert!(store.get("a.bin").is_none() && store.get("c.rs").is_none());
+
+    let outcome = PatchSet::parse("diff --git a/b.rs b/d.rs\nrename from b.rs\nrename to d.rs\n", 1).apply(&store);
+    assert_eq!(outcome.details[0].code, ErrorCode::WriteFailed, "renames never overwrite");
+}
+
+#[test]
+fn failures_are_reported_per_file() {
+    let store = MemoryStore::from_files([("keep.txt", "one\n")]);
+    let patch = "--- a/keep.txt\n+++ b/keep.txt\n@@ -1 +1 @@\n-one\n+two\n--- a/../escape.txt\n+++ b/../escape.txt\n@@ -1 +1 @@\n-a\n+b\n";
+    let outcome = PatchSet::parse(patch, 1).apply(&store);
+    assert_eq!(outcome.applied_files, vec!["keep.txt"]);
+    assert_eq!(outcome.details.len(), 1);
+    assert_eq!(outcome.details[0].code, ErrorCode::PathEscape);
+    assert_eq!(store.get("keep.txt").unwrap(), "two\n");
+}
+
+/// A memory store pretending its volume has `free` bytes left.
+struct NearlyFullStore {
+    inner: MemoryStore,
+    free: u64,
+}
+
+impl FileStore for NearlyFullStore {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.inner.read(path)
+    }
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.inner.write(path, data)
+    }
+    fn remove(&self, path: &str) -> io::Result<()> {
+        self.inner.remove(path)
+    }
+    fn exists(&self, path: &str) -> bool {
+        self.inner.exists(path)
+    }
+    fn list_dir(&self, path: &str) -> io::Result<Vec<DirEntry>> {
+        self.inner.list_dir(path)
+    }
+    fn available_space(&self, _path: &str) -> Option<u64> {
+        Some(self.free)
+    }
+}
+
+#[test]
+fn writes_beyond_free_space_fail_with_disk_full() {
+    let store = NearlyFullStore { inner: MemoryStore::from_files([("small.txt", "a\n")]), free: 64 };
+    let patch = "--- a/small.txt\n+++ b/small.txt\n@@ -1 +1 @@\n-a\n+b\n--- /dev/null\n+++ b/big.txt\n@@ -0,0 +1 @@\n+0123456789\n";
+    let options = ApplyOptions { disk_reserve: 60, ..ApplyOptions::default() };
+    let outcome = PatchSet::parse(patch, 1).apply_with(&store, &options);
+    assert_eq!(outcome.applied_files, vec!["small.txt"]);
+    assert_eq!(outcome.details[0].code, ErrorCode::DiskFull);
+    assert!(store.inner.get("big.txt").is_none());
+}
+
+/// A memory store whose file gets rewritten behind the engine's back after
+/// it has been read `edit_after` times.
+struct EditingStore {
+    inner: MemoryStore,
+    reads: std::sync::atomic::AtomicUsize,
+    edit_after: usize,
+}
+
+impl FileStore for EditingStore {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let data = self.inner.read(path);
+        if self.reads.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1 == self.edit_after {
+            self.inner.write(path, b"edited in the editor\n")?;
+        }
+        data
+    }
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.inner.write(path, data)
+    }
+    fn remove(&self, path: &str) -> io::Result<()> {
+        self.inner.remove(path)
+    }
+    fn exists(&self, path: &str) -> bool {
+        self.inner.exists(path)
+    }
+    fn list_dir(&self, path: &str) -> io::Result<Vec<DirEntry>> {
+        self.inner.list_dir(path)
+    }
+}
+
+#[test]
+fn external_edits_during_apply_are_not_overwritten() {
+    let store = EditingStore { inner: MemoryStore::from_files([("a.txt", "one\n")]), reads: Default::default(), edit_after: 2 };
+    let patch = "--- a/a.txt\n+++ b/a.txt\n@@ -1 +1 @@\n-one\n+two\n";
+    let outcome = PatchSet::parse(patch, 1).apply(&store);
+    assert_eq!(outcome.details[0].code, ErrorCode::ConcurrentModification);
+    assert_eq!(store.inner.get("a.txt").unwrap(), "edited in the editor\n");
+}
+
+#[test]
+fn stale_revisions_are_reported_per_file() {
+    let store = MemoryStore::from_files([("a.txt", "one\n"), ("b.txt", "two\n")]);
+    let read = patch::revision(b"one\n");
+    let mut expected = HashMap::from([("a.txt".to_string(), read.clone()), ("new.txt".to_string(), String::new())]);
+    assert!(patch::check_revisions(&store, &expected).is_empty());
+
+    store.write("a.txt", b"edited\n").unwrap();
+    expected.insert("b.txt".to_string(), read);
+    let stale = patch::check_revisions(&store, &expected);
+    let files: Vec<&str> = stale.iter().map(|f| f.file.as_str()).collect();
+    assert_eq!(files, ["a.txt", "b.txt"]);
+    assert!(stale.iter().all(|f| f.code == ErrorCode::StaleRevision));
+}
+
+#[test]
+fn created_files_follow_editorconfig() {
+    let store = MemoryStore::from_files([
+        (".editorconfig", "root = true\n\n[*]\ninsert_final_newline = true\ntrim_trailing_whitespace = true\n\n[*.py]\nindent_style = space\nindent_size = 4\n\n[Makefile]\nindent_style = tab\n"),
+        ("tools/.editorconfig", "[*.py]\nend_of_line = crlf\n"),
+    ]);
+    let patch = "--- /dev/null\n+++ b/tools/run.py\n@@ -0,0 +1,2 @@\n+def main():  \n+\tpass\n\\ No newline at end of file\n\
+--- /dev/null\n+++ b/Makefile\n@@ -0,0 +1,2 @@\n+all:\n+    cargo build\n";
+
+    let outcome = PatchSet::parse(patch, 1).apply(&store);
+    assert!(outcome.is_success(), "{:?}", outcome.details);
+    assert_eq!(store.get("tools/run.py").unwrap(), "def main():\r\n    pass\r\n");
+    assert_eq!(store.get("Makefile").unwrap(), "all:\n\tcargo build\n");
+}
+
+#[test]
+fn protected_paths_cannot_be_patched() {
+    let base: Arc<dyn FileStore> = Arc::new(MemoryStore::from_files([(".env", "KEY=1\n"), ("certs/site.pem", "x\n"), ("a.txt", "a\n")]));
+    let protected = ProtectedPaths::new(&ProtectedPaths::DEFAULT.iter().map(|p| p.to_string()).collect::<Vec<_>>()).unwrap();
+    let store = ProtectedStore::new(base, Arc::new(protected));
+    let patch = "--- a/.env\n+++ b/.env\n@@ -1 +1 @@\n-KEY=1\n+KEY=2\n\
+--- a/certs/site.pem\n+++ /dev/null\n@@ -1 +0,0 @@\n-x\n\
+--- /dev/null\n+++ b/.git/hooks/pre-commit\n@@ -0,0 +1 @@\n+rm -rf /\n\
+--- a/a.txt\n+++ b/a.txt\n@@ -1 +1 @@\n-a\n+b\n";
+    let outcome = PatchSet::parse(patch, 1).apply(&store);
+
+    assert_eq!(outcome.applied_files, ["a.txt"]);
+    let codes: Vec<_> = outcome.details.iter().map(|d| (d.file.as_str(), d.code)).collect();
+    assert_eq!(codes, [(".env", ErrorCode::ProtectedPath), ("certs/site.pem", ErrorCode::ProtectedPath), (".git/hooks/pre-commit", ErrorCode::ProtectedPath)]);
+    assert_eq!(store.read(".env").unwrap(), b"KEY=1\n");
+    assert!(store.write(".git/config", b"").is_err());
+}
+
+#[test]
+fn hidden_file_policy_limits_listing_reading_and_writing() {
+    let base: Arc<dyn FileStore> = Arc::new(MemoryStore::from_files([(".env", "KEY=1\n"), (".github/ci.yml", "on: push\n"), ("a.txt", "a\n")]));
+    let hidden = HiddenFiles { list: false, read: false, write: false, vcs_writes: false };
+    let store = ProtectedStore::new(base, Arc::new(ProtectedPaths::new(&[]).unwrap().with_hidden(hidden)));
+
+    let names: Vec<String> = store.list_dir("").unwrap().into_iter().map(|e| e.name).collect();
+    assert_eq!(names, ["a.txt"]);
+    assert_eq!(store.read(".env").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    let outcome = PatchSet::parse("--- a/.github/ci.yml\n+++ b/.github/ci.yml\n@@ -1 +1 @@\n-on: push\n+on: pull_request\n", 1).apply(&store);
+    assert_eq!(outcome.details[0].code, ErrorCode::ProtectedPath);
+
+    assert!(ProtectedStore::new(Arc::new(MemoryStore::new()), Arc::new(ProtectedPaths::new(&[]).unwrap())).write(".git/HEAD", b"x").is_err());
+}
+
+#[test]
+fn trace_records_resolution_hunk_matches_and_writes() {
+    let store = MemoryStore::from_files([("a.txt", "one\ntwo\nthree\n")]);
+    let patch = "--- a/a.txt\n+++ b/a.txt\n@@ -5,2 +5,2 @@\n two\n-three\n+3\n--- a/missing.txt\n+++ b/missing.txt\n@@ -1 +1 @@\n-x\n+y\n";
+    let options = ApplyOptions { trace: true, ..ApplyOptions::default() };
+    let outcome = PatchSet::parse(patch, 1).apply_with(&store, &options);
+
+    let steps: Vec<_> = outcome.trace.iter().map(|s| (s.file.as_str(), s.step)).collect();
+    assert_eq!(
+        steps,
+        [("a.txt", "resolve"), ("a.txt", "read"), ("a.txt", "hunk"), ("a.txt", "write"), ("missing.txt", "resolve"), ("missing.txt", "failed")]
+    );
+    assert_eq!(outcome.trace[2].message, "Hunk 1 (line 5) matched exactly at line 2");
+    assert!(PatchSet::parse(patch, 1).apply(&store).trace.is_empty());
+}
+
+#[test]
+fn path_rewrites_retarget_patch_files() {
+    let rule = |pattern: &str, replacement: &str| RewriteConfig { pattern: pattern.to_string(), replacement: replacement.to_string() };
+    let rewrites = PathRewrites::new(&[rule("^myproject/", ""), rule(r"^(handlers)_(\w+)\.py$", "app/$1/$2.py"), rule("^.*$", "")]).unwrap();
+    let store = MemoryStore::from_files([("app/handlers/auth.py", "old\n"), ("README.md", "a\n")]);
+    let patch = "--- a/handlers_auth.py\n+++ b/handlers_auth.py\n@@ -1 +1 @@\n-old\n+new\n--- a/myproject/README.md\n+++ b/myproject/README.md\n@@ -1 +1 @@\n-a\n+b\n";
+    let mut set = PatchSet::parse(patch, 1);
+
+    let rewritten = rewrites.apply(&mut set);
+    assert_eq!(
+        rewritten,
+        [
+            Rewrite { from: "handlers_auth.py".to_string(), to: "app/handlers/auth.py".to_string() },
+            Rewrite { from: "myproject/README.md".to_string(), to: "README.md".to_string() },
+        ]
+    );
+    let outcome = set.apply(&store);
+    assert!(outcome.is_success(), "{:?}", outcome.details);
+    assert_eq!(store.get("app/handlers/auth.py").unwrap(), "new\n");
+    assert_eq!(store.get("README.md").unwrap(), "b\n");
+    // A rule that would leave nothing of the path is skipped
+    assert_eq!(rewrites.rewrite_path("other.txt"), None);
+    assert!(PathRewrites::new(&[rule("(", "")]).err().is_some_and(|e| e.starts_with("Path rewrite (")));
+}
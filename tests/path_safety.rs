@@ -0,0 +1,103 @@
+// Property tests for path handling: relative path checks, patch header
+// stripping and store resolution, over generated paths built from the
+// segments that usually cause trouble. fuzz/ has the open-ended version.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use repopatch::patch::PatchSet;
+use repopatch::store::{check_relative, FileStore, FsStore, MemoryStore};
+use std::fs;
+use std::path::{Component, Path};
+
+const SEGMENTS: &[&str] = &[
+    "a", "src", "inside.txt", "secret.txt", "link", "dangling", "..", ".", "", "...", "~", " ", "%2e%2e", "%2E.", ".%2e", "%2f", "a%5cb", "..\\", "a\\..",
+    "C:", "c:\\windows", "\0", "con", "a.b",
+];
+const CASES: usize = 5000;
+
+fn random_path(rng: &mut StdRng) -> String {
+    let count = rng.random_range(1..=5);
+    let segments: Vec<&str> = (0..count).map(|_| SEGMENTS[rng.random_range(0..SEGMENTS.len())]).collect();
+    let path = segments.join("/");
+    if rng.random_bool(0.1) {
+        format!("/{}", path)
+    } else {
+        path
+    }
+}
+
+// Whether `path` could leave its directory on any platform we care about
+fn could_escape(path: &str) -> bool {
+    let unified = path.replace('\\', "/").to_ascii_lowercase().replace("%2e", ".").replace("%2f", "/").replace("%5c", "/");
+    path.contains('\0')
+        || unified.starts_with('/')
+        || unified.as_bytes().get(1) == Some(&b':')
+        || Path::new(&unified).components().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+#[test]
+fn accepted_relative_paths_never_escape() {
+    let mut rng = StdRng::seed_from_u64(942);
+    for _ in 0..CASES {
+        let path = random_path(&mut rng);
+        if check_relative(&path).is_ok() {
+            assert!(!could_escape(&path), "accepted {:?}", path);
+            assert!(Path::new("/root").join(&path).starts_with("/root"));
+        }
+    }
+    for path in ["..", "a/../b", "/etc/passwd", "..\\x", "C:x", "%2e%2e/x", "a/%2F/b", "a\0b"] {
+        assert!(check_relative(path).is_err(), "{:?}", path);
+    }
+    for path in ["", "a/b.txt", "./a", "...", "a..b", "100%.txt"] {
+        assert!(check_relative(path).is_ok(), "{:?}", path);
+    }
+}
+
+#[test]
+fn patch_paths_stay_inside_the_store() {
+    let mut rng = StdRng::seed_from_u64(9420);
+    let store = MemoryStore::from_files([("inside.txt", "x\n")]);
+    for _ in 0..CASES {
+        let path = random_path(&mut rng);
+        let strip = rng.random_range(0..3);
+        let patch = format!("--- /dev/null\n+++ {}\n@@ -0,0 +1 @@\n+created\n", path);
+        let outcome = PatchSet::parse(&patch, strip).apply(&store);
+        for written in &outcome.applied_files {
+            assert!(!could_escape(written), "{:?} -p{} wrote {:?}", path, strip, written);
+        }
+    }
+    assert!(store.files().keys().all(|p| !could_escape(p)));
+}
+
+#[test]
+fn symlinks_out_of_the_root_are_not_followed() {
+    let base = std::env::temp_dir().join(format!("repopatch-path-safety-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&base);
+    fs::create_dir_all(base.join("root/src")).unwrap();
+    fs::create_dir_all(base.join("outside")).unwrap();
+    fs::write(base.join("root/inside.txt"), "x\n").unwrap();
+    fs::write(base.join("outside/secret.txt"), "secret\n").unwrap();
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(base.join("outside"), base.join("root/link")).unwrap();
+        std::os::unix::fs::symlink(base.join("outside/missing.txt"), base.join("root/dangling")).unwrap();
+    }
+    let store = FsStore::new(base.join("root").canonicalize().unwrap());
+
+    let mut rng = StdRng::seed_from_u64(94200);
+    for _ in 0..CASES / 5 {
+        let path = random_path(&mut rng);
+        assert!(store.read(&path).map(|data| data != b"secret\n").unwrap_or(true), "read the secret through {:?}", path);
+        let patch = format!("--- a/{0}\n+++ b/{0}\n@@ -1 +1 @@\n-secret\n+overwritten\n", path);
+        PatchSet::parse(&patch, 1).apply(&store);
+        let create = format!("--- /dev/null\n+++ b/{}\n@@ -0,0 +1 @@\n+created\n", path);
+        PatchSet::parse(&create, 1).apply(&store);
+    }
+    assert_eq!(fs::read_to_string(base.join("outside/secret.txt")).unwrap(), "secret\n");
+    assert_eq!(fs::read_dir(base.join("outside")).unwrap().count(), 1, "nothing was created outside the root");
+    assert!(store.write("link/new.txt", b"x").is_err());
+    assert!(store.write("dangling", b"x").is_err());
+    assert!(store.write("src/new/deeper.txt", b"x").is_ok());
+    assert!(store.read("link/secret.txt").is_err());
+    let _ = fs::remove_dir_all(&base);
+}
@@ -0,0 +1,119 @@
+// Patch policy rules, monorepo packages and dry runs on an overlay store.
+
+use repopatch::packages::{find_packages, package_of};
+use repopatch::patch::PatchSet;
+use repopatch::policy::{self, Action, Operation, Policy, RuleConfig};
+use repopatch::store::{FileStore, MemoryStore, OverlayStore};
+
+fn rule(id: &str, action: Action, paths: &[&str], operations: &[Operation], min_files: usize) -> RuleConfig {
+    RuleConfig {
+        id: id.to_string(),
+        action,
+        paths: paths.iter().map(|p| p.to_string()).collect(),
+        packages: Vec::new(),
+        operations: operations.to_vec(),
+        min_files,
+        message: None,
+    }
+}
+
+#[test]
+fn policy_reports_tripped_rules() {
+    let policy = Policy::new(vec![
+        rule("no-lockfiles", Action::Deny, &["**/*.lock", "*.lock"], &[], 1),
+        rule("migrations", Action::DryRun, &["migrations/**"], &[], 1),
+        rule("mass-delete", Action::Confirm, &[], &[Operation::Delete], 3),
+    ])
+    .unwrap();
+    let patch = PatchSet::parse(
+        "--- a/Cargo.lock\n+++ b/Cargo.lock\n@@ -1 +1 @@\n-a\n+b\n\
+--- a/old1.txt\n+++ /dev/null\n@@ -1 +0,0 @@\n-x\n\
+--- a/old2.txt\n+++ /dev/null\n@@ -1 +0,0 @@\n-x\n",
+        1,
+    );
+    let violations = policy.evaluate(&patch);
+
+    assert_eq!(violations.len(), 1, "two deletions stay under the mass-delete threshold");
+    assert_eq!((violations[0].rule.as_str(), violations[0].action), ("no-lockfiles", Action::Deny));
+    assert_eq!(violations[0].files, ["Cargo.lock"]);
+}
+
+#[test]
+fn guard_rules_confirm_mass_deletions_and_summarize_them() {
+    let policy = Policy::new(policy::guard_rules(2, 0)).unwrap();
+    let patch = PatchSet::parse(
+        "--- a/keep.txt\n+++ b/keep.txt\n@@ -1 +1,2 @@\n-a\n+b\n+c\n\
+--- a/old1.txt\n+++ /dev/null\n@@ -1,2 +0,0 @@\n-x\n-y\n\
+--- a/old2.txt\n+++ /dev/null\n@@ -1 +0,0 @@\n-x\n",
+        1,
+    );
+    let violations = policy.evaluate(&patch);
+    assert_eq!(violations.len(), 1);
+    assert_eq!((violations[0].rule.as_str(), violations[0].action), ("mass-delete", Action::Confirm));
+    assert_eq!(violations[0].files, ["old1.txt", "old2.txt"]);
+
+    let summary = policy::summarize(&patch);
+    assert_eq!((summary.files, summary.modified, summary.deleted), (3, 1, 2));
+    assert_eq!((summary.lines_added, summary.lines_removed), (2, 4));
+    assert_eq!(summary.changes[2].path, "old2.txt");
+}
+
+#[test]
+fn overlay_store_keeps_dry_run_writes_in_memory() {
+    let base = MemoryStore::from_files([("a.txt", "one\n"), ("b.txt", "gone\n")]);
+    let overlay = OverlayStore::new(&base);
+    let patch = "--- a/a.txt\n+++ b/a.txt\n@@ -1 +1 @@\n-one\n+two\n--- a/b.txt\n+++ /dev/null\n@@ -1 +0,0 @@\n-gone\n";
+
+    assert!(PatchSet::parse(patch, 1).apply(&overlay).is_success());
+    assert_eq!(overlay.read("a.txt").unwrap(), b"two\n");
+    assert!(!overlay.exists("b.txt"));
+    assert_eq!(base.get("a.txt").unwrap(), "one\n");
+    assert!(base.exists("b.txt"));
+    let names: Vec<String> = overlay.list_dir("").unwrap().into_iter().map(|e| e.name).collect();
+    assert_eq!(names, ["a.txt"]);
+}
+
+#[test]
+fn overlay_store_seeded_with_virtual_files_chains_patches() {
+    let base = MemoryStore::from_files([("a.txt", "on disk\n")]);
+    let overlay = OverlayStore::with_files(&base, [("a.txt".to_string(), b"one\n".to_vec())]);
+    let first = "--- a/a.txt\n+++ b/a.txt\n@@ -1 +1 @@\n-one\n+two\n";
+    let second = "--- a/a.txt\n+++ b/a.txt\n@@ -1 +1 @@\n-two\n+three\n";
+
+    assert!(PatchSet::parse(first, 1).apply(&overlay).is_success());
+    assert!(PatchSet::parse(second, 1).apply(&overlay).is_success());
+    assert_eq!(overlay.read("a.txt").unwrap(), b"three\n");
+    assert_eq!(base.get("a.txt").unwrap(), "on disk\n");
+}
+
+#[test]
+fn packages_come_from_workspace_manifests_and_scope_rules() {
+    let store = MemoryStore::from_files([
+        ("Cargo.toml", "[workspace]\nmembers = [\"crates/*\"]\nexclude = [\"crates/old\"]\n"),
+        ("crates/core/Cargo.toml", "[package]\nname = \"app-core\"\n"),
+        ("crates/old/Cargo.toml", "[package]\nname = \"old\"\n"),
+        ("package.json", r#"{"workspaces": {"packages": ["web/*"]}}"#),
+        ("web/ui/package.json", r#"{"name": "@app/ui"}"#),
+        ("web/notes/README.md", "not a package\n"),
+        ("pnpm-workspace.yaml", "packages:\n  - 'tools/**'\n  - '!tools/skip'\n"),
+        ("tools/gen/package.json", "{}"),
+        ("tools/skip/package.json", "{}"),
+        ("go.work", "go 1.22\n\nuse (\n\t./svc\n)\n"),
+        ("svc/go.mod", "module example.com/svc\n"),
+    ]);
+    let packages = find_packages(&store).unwrap();
+    let found: Vec<_> = packages.iter().map(|p| (p.name.as_str(), p.root.as_str(), p.kind)).collect();
+    assert_eq!(
+        found,
+        [("app-core", "crates/core", "cargo"), ("example.com/svc", "svc", "go"), ("gen", "tools/gen", "npm"), ("@app/ui", "web/ui", "npm")]
+    );
+    assert_eq!(package_of(&packages, "web/ui/src/App.tsx").map(|p| p.name.as_str()), Some("@app/ui"));
+    assert_eq!(package_of(&packages, "web/uikit/x.ts"), None);
+
+    let policy = Policy::new(vec![RuleConfig { packages: vec!["app-core".to_string()], ..rule("core-frozen", Action::Deny, &[], &[], 1) }]).unwrap();
+    assert!(policy.uses_packages());
+    let patch = PatchSet::parse("--- a/crates/core/src/lib.rs\n+++ b/crates/core/src/lib.rs\n@@ -1 +1 @@\n-a\n+b\n--- a/svc/main.go\n+++ b/svc/main.go\n@@ -1 +1 @@\n-a\n+b\n", 1);
+    let violations = policy.evaluate_in(&patch, &packages);
+    assert_eq!(violations[0].files, ["crates/core/src/lib.rs"]);
+    assert!(policy.evaluate(&patch).is_empty(), "without packages the rule matches nothing");
+}
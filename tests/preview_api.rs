@@ -0,0 +1,64 @@
+// Context snippets around a line, line-numbered content and single
+// definitions extracted from a file.
+
+use repopatch::extract::extract;
+use repopatch::preview::{number_lines, snippet};
+
+#[test]
+fn snippet_reports_lines_and_byte_offsets() {
+    let content = "one\ntwo\r\nthree\nfour\n";
+    let s = snippet(content, 3, 1);
+
+    assert_eq!(s.total_lines, 4);
+    let numbers: Vec<usize> = s.lines.iter().map(|l| l.number).collect();
+    assert_eq!(numbers, [2, 3, 4]);
+    assert_eq!(s.lines[0].text, "two");
+    assert_eq!(&content[s.lines[1].offset..s.lines[1].offset + 5], "three");
+    assert_eq!(&content[s.start_offset..s.end_offset], "two\r\nthree\nfour\n");
+
+    let clamped = snippet(content, 99, 0);
+    assert_eq!((clamped.line, clamped.lines[0].text.as_str()), (4, "four"));
+    assert!(snippet("", 1, 3).lines.is_empty());
+}
+
+#[test]
+fn numbered_lines_are_padded_to_the_widest_number() {
+    let content: String = (1..=10).map(|i| format!("line {}\n", i)).collect();
+    let numbered = number_lines(&content);
+    assert!(numbered.starts_with(" 1 | line 1\n 2 | line 2\n"), "{}", numbered);
+    assert!(numbered.ends_with("10 | line 10\n"));
+    assert_eq!(number_lines("a\r\nb"), "1 | a\r\n2 | b");
+    assert_eq!(number_lines(""), "");
+}
+
+#[test]
+fn extract_returns_a_definition_with_its_docs() {
+    let source = "use std::fmt;\n\n/// A stack.\n#[derive(Debug)]\npub struct Stack<T>(Vec<T>);\n\nimpl<T> Stack<T> {\n    /// Pushes.\n    pub fn push(&mut self, x: T) {\n        self.0.push(x);\n    }\n}\n\nfn push() {}\n";
+
+    let stack = extract("src/stack.rs", source, "Stack", 0).unwrap();
+    assert_eq!(stack.len(), 1);
+    assert_eq!((stack[0].kind, stack[0].start_line, stack[0].end_line), ("struct", 3, 5));
+    assert_eq!(stack[0].content, "/// A stack.\n#[derive(Debug)]\npub struct Stack<T>(Vec<T>);\n");
+
+    let method = extract("src/stack.rs", source, "Stack::push", 1).unwrap();
+    assert_eq!(method.len(), 1);
+    assert_eq!((method[0].kind, method[0].container.as_deref()), ("method", Some("Stack")));
+    assert_eq!((method[0].start_line, method[0].end_line), (7, 12));
+    assert!(method[0].content.starts_with("impl<T> Stack<T> {\n    /// Pushes.\n"));
+    assert_eq!(extract("src/stack.rs", source, "push", 0).unwrap().len(), 2);
+    assert!(extract("src/stack.rs", source, "pop", 0).unwrap().is_empty());
+    assert!(extract("notes.txt", "push", "push", 0).is_err());
+}
+
+#[test]
+fn extract_understands_typescript_and_python_wrappers() {
+    let ts = "// Renders.\nexport const render = (x: number) => x;\n\nexport class View {\n  draw() {}\n}\n";
+    let render = extract("view.ts", ts, "render", 0).unwrap();
+    assert_eq!(render[0].content, "// Renders.\nexport const render = (x: number) => x;\n");
+    assert_eq!(extract("view.ts", ts, "View.draw", 0).unwrap()[0].content, "  draw() {}\n");
+
+    let py = "class Tree:\n    @property\n    def size(self):\n        return 1\n";
+    let size = extract("tree.py", py, "Tree.size", 0).unwrap();
+    assert_eq!((size[0].kind, size[0].start_line), ("method", 2));
+    assert_eq!(size[0].content, "    @property\n    def size(self):\n        return 1\n");
+}
@@ -0,0 +1,81 @@
+// Syntax-aware renames and file moves over the in-memory store.
+
+use ignore::gitignore::Gitignore;
+use repopatch::moves::move_file;
+use repopatch::patch::PatchSet;
+use repopatch::refactor::{rename_symbol, RenameScope};
+use repopatch::store::MemoryStore;
+
+#[test]
+fn rename_skips_strings_comments_and_other_languages() {
+    let store = MemoryStore::from_files([
+        ("src/lib.rs", "// total is summed here\npub fn total(xs: &[u32]) -> u32 { xs.iter().sum() }\nconst NAME: &str = \"total\";\n"),
+        ("src/main.rs", "fn main() { let totals = 1; println!(\"{}\", repo::total(&[totals])); }\n"),
+        ("scripts/total.py", "def total(xs):\n    return sum(xs)\n"),
+    ]);
+    let plan = rename_symbol(&store, &Gitignore::empty(), "src/lib.rs", "total", "sum_all", RenameScope::Project).unwrap();
+
+    let changed: Vec<(&str, usize)> = plan.files.iter().map(|f| (f.path.as_str(), f.matches)).collect();
+    assert_eq!(changed, [("src/lib.rs", 1), ("src/main.rs", 1)]);
+    assert!(plan.conflicts.is_empty());
+
+    assert!(plan.patch.contains("+pub fn sum_all(xs: &[u32])"));
+    assert!(!plan.patch.contains("+// sum_all"), "comments are left alone");
+    assert!(plan.patch.contains("repo::sum_all(&[totals])"), "longer names are left alone");
+}
+
+#[test]
+fn rename_within_function_scope() {
+    let source = "function a() {\n  const value = 1;\n  return value;\n}\nfunction b() {\n  const value = 2;\n  return value;\n}\n";
+    let store = MemoryStore::from_files([("app.ts", source)]);
+    let plan = rename_symbol(&store, &Gitignore::empty(), "app.ts", "value", "count", RenameScope::Function { line: 6 }).unwrap();
+
+    assert_eq!(plan.files[0].matches, 2);
+    assert!(plan.patch.contains("-  const value = 2;\n-  return value;\n+  const count = 2;\n+  return count;"));
+    assert!(!plan.patch.contains("const count = 1"));
+}
+
+#[test]
+fn move_rewrites_imports_and_applies_as_a_patch() {
+    let store = MemoryStore::from_files([
+        ("src/util/math.ts", "import { log } from '../log';\nexport const add = (a: number, b: number) => a + b;\n"),
+        ("src/log.ts", "export const log = console.log;\n"),
+        ("src/app.ts", "import { add } from './util/math';\nconst m = require(\"./util/math.ts\");\nimport x from 'math';\n"),
+        ("src/pkg/helpers.py", "def helper():\n    pass\n"),
+        ("src/pkg/main.py", "from .helpers import helper\nfrom . import helpers\nimport pkg.helpers\n"),
+        ("native/io.c", "#include \"../native/io.h\"\n#include \"io.h\"\n"),
+        ("native/io.h", "int io(void);\n"),
+    ]);
+    let plan = move_file(&store, &Gitignore::empty(), "src/util/math.ts", "src/lib/math/index.ts", true).unwrap();
+    let changed: Vec<(&str, usize)> = plan.files.iter().map(|f| (f.path.as_str(), f.matches)).collect();
+    assert_eq!(changed, [("src/app.ts", 2), ("src/lib/math/index.ts", 1)]);
+    assert!(plan.patch.contains("+import { add } from './lib/math';"), "{}", plan.patch);
+    assert!(plan.patch.contains("+const m = require(\"./lib/math/index.ts\");"));
+    assert!(plan.patch.contains("+import { log } from '../../log';"));
+
+    let outcome = PatchSet::parse(&plan.patch, 1).apply(&store);
+    assert!(outcome.is_success(), "{:?}", outcome.details);
+    assert!(store.get("src/util/math.ts").is_none());
+    assert!(store.get("src/lib/math/index.ts").unwrap().contains("export const add"));
+
+    let plan = move_file(&store, &Gitignore::empty(), "src/pkg/helpers.py", "src/pkg/tools/helpers.py", true).unwrap();
+    assert!(plan.patch.contains("+from .tools.helpers import helper"), "{}", plan.patch);
+    assert!(plan.patch.contains("+from .tools import helpers"));
+    assert!(plan.patch.contains("+import pkg.tools.helpers"));
+
+    let plan = move_file(&store, &Gitignore::empty(), "native/io.h", "include/io.h", true).unwrap();
+    assert_eq!(plan.files[0].matches, 2);
+    assert!(plan.patch.contains("+#include \"../include/io.h\"\n+#include \"../include/io.h\""), "{}", plan.patch);
+}
+
+#[test]
+fn move_reports_references_it_cannot_rewrite() {
+    let store = MemoryStore::from_files([("pkg/a.py", "x = 1\n"), ("pkg/b.py", "x = 2\n"), ("main.py", "from pkg import a, b\n")]);
+    let plan = move_file(&store, &Gitignore::empty(), "pkg/a.py", "other/a.py", true).unwrap();
+    assert_eq!(plan.unresolved, ["main.py:1: from pkg import a, b"]);
+    assert!(plan.files.is_empty());
+    assert!(move_file(&store, &Gitignore::empty(), "pkg/a.py", "pkg/b.py", true).is_err(), "targets must not exist");
+
+    let plan = move_file(&store, &Gitignore::empty(), "pkg/a.py", "pkg/c.py", false).unwrap();
+    assert_eq!(plan.patch, "diff --git a/pkg/a.py b/pkg/c.py\nrename from pkg/a.py\nrename to pkg/c.py\n");
+}
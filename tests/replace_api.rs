@@ -0,0 +1,30 @@
+// Regex replacement planned as a diff and applied through the patch engine.
+
+use ignore::gitignore::Gitignore;
+use repopatch::replace::{plan, ReplaceSpec};
+use repopatch::store::MemoryStore;
+
+#[test]
+fn replace_plan_applies_through_patch_engine() {
+    let store = MemoryStore::from_files([
+        ("src/a.rs", "fn old_name() {}\nfn main() { old_name(); }\n"),
+        ("src/b.rs", "use crate::old_name;"),
+        ("docs/notes.md", "old_name is documented here\n"),
+    ]);
+    let spec = ReplaceSpec::new(r"\bold_(\w+)", "new_$1", &["src/**/*.rs".to_string()], &[]).unwrap();
+    let dry_run = plan(&store, "", &Gitignore::empty(), &spec).unwrap();
+
+    let changed: Vec<(&str, usize)> = dry_run.files.iter().map(|f| (f.path.as_str(), f.matches)).collect();
+    assert_eq!(changed, [("src/a.rs", 2), ("src/b.rs", 1)]);
+    assert_eq!(store.get("src/a.rs").unwrap(), "fn old_name() {}\nfn main() { old_name(); }\n", "dry run writes nothing");
+
+    let outcome = dry_run.patch_set().apply(&store);
+    assert!(outcome.is_success(), "{:?}", outcome.details);
+    assert_eq!(store.get("src/a.rs").unwrap(), "fn new_name() {}\nfn main() { new_name(); }\n");
+    assert_eq!(store.get("src/b.rs").unwrap(), "use crate::new_name;");
+    assert_eq!(store.get("docs/notes.md").unwrap(), "old_name is documented here\n");
+
+    let again = plan(&store, "", &Gitignore::empty(), &spec).unwrap();
+    assert!(again.files.is_empty());
+    assert_ne!(again.id(), dry_run.id());
+}
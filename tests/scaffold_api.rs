@@ -0,0 +1,67 @@
+// File templates instantiated over the in-memory store.
+
+use repopatch::patch::PatchSet;
+use repopatch::scaffold::{apply_filter, plan, render, TemplateConfig, Templates};
+use repopatch::store::{FileStore, MemoryStore};
+use std::collections::BTreeMap;
+
+fn values(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+    pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+#[test]
+fn filters_change_the_case_of_any_spelling() {
+    for input in ["rate limit", "rate_limit", "rate-limit", "RateLimit", "rateLimit"] {
+        assert_eq!(apply_filter(input, "snake").unwrap(), "rate_limit", "{:?}", input);
+        assert_eq!(apply_filter(input, "kebab").unwrap(), "rate-limit");
+        assert_eq!(apply_filter(input, "pascal").unwrap(), "RateLimit");
+        assert_eq!(apply_filter(input, "camel").unwrap(), "rateLimit");
+        assert_eq!(apply_filter(input, "upper").unwrap(), "RATE_LIMIT");
+    }
+    let vars = values(&[("name", "UserCard")]);
+    assert_eq!(render("{{name}}/{{ name|kebab }}.css", &vars).unwrap(), "UserCard/user-card.css");
+    assert!(render("{{other}}", &vars).is_err());
+    assert!(render("{{name|title}}", &vars).is_err());
+}
+
+#[test]
+fn rust_module_creates_the_folder_and_declares_it() {
+    let store = MemoryStore::from_files([("src/lib.rs", "pub mod store;")]);
+    let templates = Templates::new(Vec::new());
+    let made = plan(&store, templates.get("rust-module").unwrap(), &values(&[("name", "RateLimit")])).unwrap();
+
+    let paths: Vec<(&str, bool)> = made.files.iter().map(|f| (f.path.as_str(), f.append)).collect();
+    assert_eq!(paths, [("src/rate_limit/mod.rs", false), ("src/lib.rs", true)]);
+    let outcome = PatchSet::parse(&made.patch, 1).apply(&store);
+    assert!(outcome.is_success(), "{:?}", outcome.details);
+    assert_eq!(store.read("src/lib.rs").unwrap(), b"pub mod store;\npub mod rate_limit;\n");
+    assert_eq!(store.read("src/rate_limit/mod.rs").unwrap(), b"//! RateLimit.\n");
+
+    let err = plan(&store, templates.get("rust-module").unwrap(), &values(&[("name", "rate_limit")])).unwrap_err();
+    assert!(err.contains("src/rate_limit/mod.rs already exists"), "{}", err);
+    assert!(plan(&store, templates.get("rust-module").unwrap(), &BTreeMap::new()).is_err(), "name is required");
+    assert!(plan(&store, templates.get("rust-module").unwrap(), &values(&[("name", "x"), ("extra", "y")])).is_err());
+}
+
+#[test]
+fn configured_templates_replace_builtins_and_stay_inside_the_root() {
+    let configured: Vec<TemplateConfig> = toml::from_str::<BTreeMap<String, Vec<TemplateConfig>>>(
+        r#"
+        [[templates]]
+        name = "react-component"
+        variables = { name = "", dir = "ui" }
+        files = [{ path = "{{dir}}/{{name|pascal}}.jsx", content = "export default function {{name|pascal}}() {}\n" }]
+        "#,
+    )
+    .unwrap()
+    .remove("templates")
+    .unwrap();
+    let templates = Templates::new(configured);
+    assert_eq!(templates.list().iter().filter(|t| t.name == "react-component").count(), 1);
+
+    let store = MemoryStore::from_files([("README.md", "x\n")]);
+    let made = plan(&store, templates.get("react-component").unwrap(), &values(&[("name", "user card")])).unwrap();
+    assert!(made.patch.contains("+++ b/ui/UserCard.jsx\n"), "{}", made.patch);
+    assert!(made.patch.contains("+export default function UserCard() {}"));
+    assert!(plan(&store, templates.get("react-component").unwrap(), &values(&[("name", "a"), ("dir", "../outside")])).is_err());
+}
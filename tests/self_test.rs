@@ -0,0 +1,36 @@
+// Runs the embedded golden fixtures (tests/fixtures) through the binary's
+// `self-test` subcommand, so every fixture is also a regression test.
+
+use std::process::Command;
+
+fn self_test(filter: Option<&str>) -> (bool, String) {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_repopatch"));
+    cmd.arg("self-test");
+    if let Some(filter) = filter {
+        cmd.arg(filter);
+    }
+    let output = cmd.output().expect("failed to run repopatch self-test");
+    (output.status.success(), String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[test]
+fn all_fixtures_pass() {
+    let (success, stdout) = self_test(None);
+    assert!(success, "self-test failed:\n{}", stdout);
+    assert!(!stdout.contains("FAIL"), "{}", stdout);
+}
+
+#[test]
+fn fixtures_cover_core_scenarios() {
+    let (_, stdout) = self_test(None);
+    for case in ["create", "delete", "rename", "crlf", "unicode", "fuzz", "conflict"] {
+        assert!(stdout.contains(&format!("ok   {}", case)), "fixture {} missing or failing:\n{}", case, stdout);
+    }
+}
+
+#[test]
+fn filter_with_no_matches_fails() {
+    let (success, stdout) = self_test(Some("no-such-fixture"));
+    assert!(!success);
+    assert!(stdout.contains("0 passed, 0 failed"), "{}", stdout);
+}
@@ -0,0 +1,52 @@
+// The semantic search index over the in-memory store, with the local model.
+
+use ignore::gitignore::Gitignore;
+use repopatch::semantic::{chunk_file, cosine, hash_embedding, VectorIndex, CHUNK_LINES, CHUNK_OVERLAP, INDEX_PATH};
+use repopatch::store::{FileStore, MemoryStore};
+
+fn build(store: &MemoryStore, model: &str) -> (VectorIndex, usize) {
+    let mut index = VectorIndex::load(store, model);
+    let pending = index.refresh(store, &Gitignore::empty()).unwrap();
+    for chunk in &pending {
+        index.insert(chunk, hash_embedding(&chunk.text));
+    }
+    index.save(store).unwrap();
+    (index, pending.len())
+}
+
+#[test]
+fn chunks_overlap_and_skip_blank_windows() {
+    let source: String = (1..=100).map(|i| format!("line {}\n", i)).collect();
+    let chunks = chunk_file("a.txt", "r1", &source);
+    let ranges: Vec<(usize, usize)> = chunks.iter().map(|c| (c.start_line, c.end_line)).collect();
+    let step = CHUNK_LINES - CHUNK_OVERLAP;
+    assert_eq!(ranges[..2], [(1, CHUNK_LINES), (1 + step, CHUNK_LINES + step)]);
+    assert_eq!(ranges.last().unwrap().1, 100);
+    assert!(chunk_file("b.txt", "r1", "\n\n\n").is_empty());
+}
+
+#[test]
+fn index_finds_matching_code_and_only_reembeds_changes() {
+    let store = MemoryStore::from_files([
+        ("src/patch.rs", "fn apply_hunk(file: &str, hunk: &Hunk) -> Result<String, PatchError> { fuzzy_match(hunk) }\n"),
+        ("src/tree.rs", "fn build_tree(dir: &str) -> HashMap<String, TreeNode> { list_dir(dir) }\n"),
+        ("logo.png", "\u{0}PNG"),
+    ]);
+    let (index, embedded) = build(&store, "local");
+    assert_eq!((embedded, index.file_count()), (2, 2));
+    assert!(store.read(INDEX_PATH).is_ok());
+
+    let hits = index.search(&hash_embedding("where is a hunk applied to a patch"), 2);
+    assert_eq!(hits[0].entry.path, "src/patch.rs");
+    assert!(hits[0].score > hits[1].score);
+    assert!(cosine(&hash_embedding("buildTree"), &hash_embedding("build_tree")) > 0.99, "identifiers split into words");
+
+    store.write("src/tree.rs", b"fn walk() {}\n").unwrap();
+    store.remove("src/patch.rs").unwrap();
+    let (index, embedded) = build(&store, "local");
+    assert_eq!((embedded, index.file_count()), (1, 1), "the index file itself is not indexed");
+    let (_, embedded) = build(&store, "local");
+    assert_eq!(embedded, 0);
+    let (_, embedded) = build(&store, "other-model");
+    assert_eq!(embedded, 1, "a new model starts over");
+}
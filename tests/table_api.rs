@@ -0,0 +1,276 @@
+// Previews of CSV, TSV and Parquet files.
+
+use repopatch::table::{preview, table_format, Column, MAX_CELL_CHARS};
+use serde_json::{json, Value};
+
+fn columns(preview: &repopatch::table::TablePreview) -> Vec<(&str, &str)> {
+    preview.columns.iter().map(|Column { name, kind }| (name.as_str(), *kind)).collect()
+}
+
+#[test]
+fn csv_files_give_typed_columns_and_their_first_rows() {
+    let csv = "\u{feff}id,name,zip,score,active,\r\n1,\"Lee, Ann\",02134,9.5,true,x\r\n2,\"Said \"\"hi\"\"\nthen left\",10001,,FALSE\r\n\r\n3,Bo,94105,7,false,\n";
+    let table = preview("data/people.csv", csv.as_bytes(), 2).unwrap();
+    assert_eq!(table.format, "csv");
+    assert_eq!(
+        columns(&table),
+        [("id", "integer"), ("name", "string"), ("zip", "string"), ("score", "number"), ("active", "boolean"), ("column 6", "string")]
+    );
+    assert_eq!(table.rows[0], [json!(1), json!("Lee, Ann"), json!("02134"), json!(9.5), json!(true), json!("x")]);
+    assert_eq!(table.rows[1], [json!(2), json!("Said \"hi\"\nthen left"), json!("10001"), Value::Null, json!(false), Value::Null], "short records are padded");
+    assert_eq!(table.total_rows, 3, "blank lines aren't rows");
+    assert_eq!(table.rows_unavailable, None);
+
+    let none = preview("data/people.csv", csv.as_bytes(), 0).unwrap();
+    assert!(none.rows.is_empty());
+    assert_eq!((none.columns.len(), none.total_rows), (6, 3));
+    assert_eq!(columns(&none)[0], ("id", "string"), "no rows, no types");
+}
+
+#[test]
+fn tsv_files_split_on_tabs_without_quotes() {
+    let tsv = "path\tlines\textra\n\"src/a.rs\"\t120\n";
+    let table = preview("stats.TSV", tsv.as_bytes(), 10).unwrap();
+    assert_eq!(table.format, "tsv");
+    assert_eq!(table.rows, [vec![json!("\"src/a.rs\""), json!(120), Value::Null]]);
+
+    let long = format!("text\n{}\n", "é".repeat(MAX_CELL_CHARS + 10));
+    let cell = preview("long.csv", long.as_bytes(), 1).unwrap().rows[0][0].as_str().unwrap().to_string();
+    assert_eq!(cell, format!("{}…", "é".repeat(MAX_CELL_CHARS)));
+
+    assert_eq!(table_format("data/events.parquet"), Some("parquet"));
+    assert_eq!(table_format("src/main.rs"), None);
+    assert!(preview("src/main.rs", b"", 1).is_err());
+}
+
+// Thrift compact protocol values, enough to write Parquet metadata
+enum T {
+    I(i64),
+    Bool(bool),
+    B(Vec<u8>),
+    L(u8, Vec<T>),
+    S(Vec<(i16, T)>),
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn kind(value: &T) -> u8 {
+    match value {
+        T::I(_) => 6,
+        T::Bool(true) => 1,
+        T::Bool(false) => 2,
+        T::B(_) => 8,
+        T::L(..) => 9,
+        T::S(_) => 12,
+    }
+}
+
+fn write(out: &mut Vec<u8>, value: &T) {
+    match value {
+        T::I(v) => varint(out, zigzag(*v)),
+        T::Bool(_) => {}
+        T::B(bytes) => {
+            varint(out, bytes.len() as u64);
+            out.extend(bytes);
+        }
+        T::L(element, values) => {
+            out.push(((values.len() as u8) << 4) | element);
+            values.iter().for_each(|v| write(out, v));
+        }
+        T::S(fields) => {
+            let mut last = 0;
+            for (id, value) in fields {
+                if (1..=15).contains(&(id - last)) {
+                    out.push((((id - last) as u8) << 4) | kind(value));
+                } else {
+                    out.push(kind(value));
+                    varint(out, zigzag(*id as i64));
+                }
+                last = *id;
+                write(out, value);
+            }
+            out.push(0);
+        }
+    }
+}
+
+fn thrift(value: T) -> Vec<u8> {
+    let mut out = Vec::new();
+    write(&mut out, &value);
+    out
+}
+
+fn text(value: &str) -> T {
+    T::B(value.as_bytes().to_vec())
+}
+
+// A page: its header, then its body as stored
+fn page(kind: i64, uncompressed: usize, body: &[u8], header: (i16, T)) -> Vec<u8> {
+    let mut page = thrift(T::S(vec![(1, T::I(kind)), (2, T::I(uncompressed as i64)), (3, T::I(body.len() as i64)), header]));
+    page.extend(body);
+    page
+}
+
+// Snappy with everything as literals
+fn snappy(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    varint(&mut out, data.len() as u64);
+    out.push(((data.len() - 1) as u8) << 2);
+    out.extend(data);
+    out
+}
+
+struct Chunk {
+    physical: i64,
+    name: &'static str,
+    codec: i64,
+    pages: Vec<Vec<u8>>,
+    dictionary: bool,
+}
+
+// A Parquet file of one row group with `rows` rows
+fn parquet(schema: Vec<T>, chunks: Vec<Chunk>, rows: i64) -> Vec<u8> {
+    let mut file = b"PAR1".to_vec();
+    let mut columns = Vec::new();
+    for chunk in chunks {
+        let start = file.len() as i64;
+        let data_page = start + if chunk.dictionary { chunk.pages[0].len() as i64 } else { 0 };
+        let size: usize = chunk.pages.iter().map(Vec::len).sum();
+        chunk.pages.iter().for_each(|p| file.extend(p));
+        let mut meta = vec![
+            (1, T::I(chunk.physical)),
+            (2, T::L(5, vec![T::I(0)])),
+            (3, T::L(8, vec![text(chunk.name)])),
+            (4, T::I(chunk.codec)),
+            (5, T::I(rows)),
+            (6, T::I(size as i64)),
+            (7, T::I(size as i64)),
+            (9, T::I(data_page)),
+        ];
+        if chunk.dictionary {
+            meta.push((11, T::I(start)));
+        }
+        columns.push(T::S(vec![(2, T::I(start)), (3, T::S(meta))]));
+    }
+    let footer = thrift(T::S(vec![
+        (1, T::I(2)),
+        (2, T::L(12, schema)),
+        (3, T::I(rows)),
+        (4, T::L(12, vec![T::S(vec![(1, T::L(12, columns)), (2, T::I(0)), (3, T::I(rows))])])),
+    ]));
+    file.extend(&footer);
+    file.extend((footer.len() as u32).to_le_bytes());
+    file.extend(b"PAR1");
+    file
+}
+
+fn element(name: &str, physical: Option<i64>, repetition: i64, converted: Option<i64>) -> T {
+    let mut fields = physical.map(|p| (1, T::I(p))).into_iter().collect::<Vec<_>>();
+    fields.push((3, T::I(repetition)));
+    fields.push((4, text(name)));
+    fields.extend(converted.map(|c| (6, T::I(c))));
+    T::S(fields)
+}
+
+fn sample_file(name_encoding: i64) -> Vec<u8> {
+    let schema = vec![
+        T::S(vec![(4, text("schema")), (5, T::I(4))]),
+        element("id", Some(2), 0, None),
+        element("name", Some(6), 1, Some(0)),
+        element("day", Some(1), 0, Some(6)),
+        T::S(vec![(3, T::I(1)), (4, text("tags")), (5, T::I(1))]),
+        element("item", Some(6), 2, Some(0)),
+    ];
+
+    // Plain INT64s in a v1 page
+    let ids: Vec<u8> = [1i64, 2, 3].iter().flat_map(|v| v.to_le_bytes()).collect();
+    let ids = page(0, ids.len(), &ids, (5, T::S(vec![(1, T::I(3)), (2, T::I(0)), (3, T::I(3)), (4, T::I(3))])));
+
+    // Dictionary-encoded strings with a null, Snappy-compressed
+    let dictionary: Vec<u8> = ["ann", "bob"].iter().flat_map(|s| [(s.len() as u32).to_le_bytes().as_slice(), s.as_bytes()].concat()).collect();
+    let dictionary = page(2, dictionary.len(), &snappy(&dictionary), (7, T::S(vec![(1, T::I(2)), (2, T::I(0))])));
+    // Definition levels 1 0 1, then indices 1 0, each as one bit-packed group
+    let names = [2, 0, 0, 0, 3, 0b101, 1, 3, 0b01];
+    let names = page(0, names.len(), &snappy(&names), (5, T::S(vec![(1, T::I(3)), (2, T::I(name_encoding)), (3, T::I(3)), (4, T::I(3))])));
+
+    // Dates in a Zstandard-compressed v2 page
+    let days: Vec<u8> = [19000i32, 19001, 19002].iter().flat_map(|v| v.to_le_bytes()).collect();
+    let compressed = zstd::bulk::compress(&days, 3).unwrap();
+    let header = T::S(vec![(1, T::I(3)), (2, T::I(0)), (3, T::I(3)), (4, T::I(0)), (5, T::I(0)), (6, T::I(0)), (7, T::Bool(true))]);
+    let days = page(3, days.len(), &compressed, (8, header));
+
+    let chunks = vec![
+        Chunk { physical: 2, name: "id", codec: 0, pages: vec![ids], dictionary: false },
+        Chunk { physical: 6, name: "name", codec: 1, pages: vec![dictionary, names], dictionary: true },
+        Chunk { physical: 1, name: "day", codec: 6, pages: vec![days], dictionary: false },
+        Chunk { physical: 6, name: "tags.item", codec: 0, pages: vec![], dictionary: false },
+    ];
+    parquet(schema, chunks, 3)
+}
+
+#[test]
+fn parquet_files_give_their_schema_and_first_rows() {
+    let table = preview("data/people.parquet", &sample_file(8), 100).unwrap();
+    assert_eq!(table.format, "parquet");
+    assert_eq!(columns(&table), [("id", "integer"), ("name", "string"), ("day", "date"), ("tags.item", "list")]);
+    assert_eq!(table.total_rows, 3);
+    assert_eq!(table.rows_unavailable, None);
+    assert_eq!(
+        table.rows,
+        [
+            vec![json!(1), json!("bob"), json!("2022-01-08"), Value::Null],
+            vec![json!(2), Value::Null, json!("2022-01-09"), Value::Null],
+            vec![json!(3), json!("ann"), json!("2022-01-10"), Value::Null],
+        ]
+    );
+    assert_eq!(preview("data/people.parquet", &sample_file(8), 1).unwrap().rows.len(), 1);
+}
+
+#[test]
+fn unreadable_parquet_rows_still_give_the_schema() {
+    let table = preview("data/people.parquet", &sample_file(7), 10).unwrap();
+    assert_eq!(table.columns.len(), 4);
+    assert!(table.rows.is_empty());
+    assert_eq!(table.rows_unavailable.as_deref(), Some("Column name: DELTA_BYTE_ARRAY pages can't be read"));
+
+    assert!(preview("data/bad.parquet", b"PAR1 not really PAR1", 10).is_err());
+    assert!(preview("data/bad.parquet", b"a,b\n1,2\n", 10).is_err());
+}
+
+// One optional INT64 column whose data page claims 2^40 values
+fn hostile_file(levels: &[u8]) -> Vec<u8> {
+    let schema = vec![T::S(vec![(4, text("schema")), (5, T::I(1))]), element("n", Some(2), 1, None)];
+    let mut body = (levels.len() as u32).to_le_bytes().to_vec();
+    body.extend(levels);
+    body.extend([5i64, 6].iter().flat_map(|v| v.to_le_bytes()));
+    let header = T::S(vec![(1, T::I(1 << 40)), (2, T::I(0)), (3, T::I(3)), (4, T::I(3))]);
+    let values = page(0, body.len(), &body, (5, header));
+    parquet(schema, vec![Chunk { physical: 2, name: "n", codec: 0, pages: vec![values], dictionary: false }], 2)
+}
+
+#[test]
+fn parquet_value_counts_are_not_trusted() {
+    // A run of 2^40 definition levels of 1 decodes only the rows asked for
+    let mut run = Vec::new();
+    varint(&mut run, 1 << 41);
+    run.push(1);
+    let table = preview("data/hostile.parquet", &hostile_file(&run), 2).unwrap();
+    assert_eq!(table.rows, [vec![json!(5)], vec![json!(6)]]);
+
+    // Bit-packed groups whose size overflows
+    let mut packed = Vec::new();
+    varint(&mut packed, u64::MAX);
+    let table = preview("data/hostile.parquet", &hostile_file(&packed), 2).unwrap();
+    assert!(table.rows.is_empty());
+    assert!(table.rows_unavailable.is_some());
+}
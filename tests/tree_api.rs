@@ -0,0 +1,362 @@
+// Directory trees, language statistics, duplicate and recent file reports.
+
+use ignore::gitignore::Gitignore;
+use repopatch::duplicates::find_duplicates;
+use repopatch::stats::{detect_language, language_stats, LanguageStats};
+use repopatch::store::{DirEntry, FileStore, FsStore, MemoryStore};
+use repopatch::tree::{build_filtered_tree, build_reported_tree, build_tree, collect_subtree, load_gitignore, merge_roots, parse_fields, project_tree, recent_files, summarize_tree, tree_totals, PathFilter, TreeFilter, TreeLimits, TreeScan, NODE_FIELDS};
+use std::io;
+use std::time::{Duration, SystemTime};
+
+#[test]
+fn tree_honours_nested_gitignore() {
+    let store = MemoryStore::from_files([
+        (".gitignore", "*.log\n"),
+        ("debug.log", "x"),
+        ("src/main.rs", "fn main() {}"),
+        ("src/.gitignore", "generated/\n"),
+        ("src/generated/out.rs", "x"),
+        ("empty/debug.log", "x"),
+    ]);
+    let ig = load_gitignore(&store, "").unwrap_or_else(Gitignore::empty);
+    let tree = build_tree(&store, "", &ig).unwrap();
+
+    assert!(!tree.contains_key("debug.log"));
+    assert!(!tree.contains_key("empty"), "folders with only ignored files are dropped");
+    let src = tree["src"].children.as_ref().unwrap();
+    assert!(src.contains_key("main.rs"));
+    assert!(!src.contains_key("generated"));
+    assert_eq!(src["main.rs"].path, "src/main.rs");
+}
+
+#[test]
+fn tree_filters_by_extension_size_and_hidden() {
+    let store = MemoryStore::from_files([
+        ("Cargo.toml", "[package]"),
+        ("README.md", "docs"),
+        ("src/lib.RS", "pub fn f() {}"),
+        ("src/big.rs", &"x".repeat(100)),
+        ("docs/guide.md", "docs"),
+        (".cargo/config.toml", "[build]"),
+    ]);
+    let filter = TreeFilter {
+        extensions: Some(TreeFilter::parse_extensions("rs, .toml")),
+        max_size: Some(50),
+        include_hidden: false,
+        paths: None,
+        archives: false,
+    };
+    let tree = build_filtered_tree(&store, "", &Gitignore::empty(), &filter).unwrap();
+
+    assert!(tree.contains_key("Cargo.toml"));
+    assert!(!tree.contains_key("README.md"));
+    assert!(!tree.contains_key("docs"), "folders without matching files are dropped");
+    assert!(!tree.contains_key(".cargo"));
+    let src = tree["src"].children.as_ref().unwrap();
+    assert!(src.contains_key("lib.RS"));
+    assert!(!src.contains_key("big.rs"));
+    assert_eq!((tree["src"].file_count, tree["src"].size), (Some(1), Some(13)));
+}
+
+#[test]
+fn language_stats_by_extension_and_shebang() {
+    let store = MemoryStore::from_files([
+        ("src/main.rs", "fn main() {}\n"),
+        ("src/lib.rs", "pub mod a;\npub mod b;"),
+        ("bin/run", "#!/usr/bin/env python3\nprint('hi')\n"),
+        ("Makefile", "all:\n\tcargo build\n"),
+        ("logo.png", "\u{0}PNG"),
+    ]);
+    let stats = language_stats(&store, "", &Gitignore::empty()).unwrap();
+
+    assert_eq!(stats["Rust"], LanguageStats { files: 2, lines: 3, bytes: 34 });
+    assert_eq!(stats["Python"].files, 1);
+    assert_eq!(stats["Makefile"].lines, 2);
+    assert!(!stats.contains_key("Other"), "binary files are skipped");
+}
+
+#[test]
+fn languages_from_modelines_and_in_the_tree() {
+    assert_eq!(detect_language("notes.txt", b"# vim: set ft=python :\nx = 1\n"), Some("Python"));
+    assert_eq!(detect_language("build", b"a\nb\nc\nd\ne\nf\n\n/* vim:ft=cpp */\n"), Some("C++"));
+    assert_eq!(detect_language("conf", b"#!/bin/sh\n# -*- mode: ruby; coding: utf-8 -*-\n"), Some("Ruby"));
+    assert_eq!(detect_language("x.el", b";; -*- lisp -*-\n"), None, "unknown modes fall through");
+    assert_eq!(detect_language("script.py", b"# -*- coding: utf-8 -*-\n"), Some("Python"));
+
+    let store = MemoryStore::from_files([
+        ("src/app.tsx", "export {};\n"),
+        ("bin/deploy", "#!/usr/bin/env bash\necho hi\n"),
+        ("LICENSE", "MIT\n"),
+    ]);
+    let tree = build_tree(&store, "", &Gitignore::empty()).unwrap();
+    let children = |name: &str| tree[name].children.as_ref().unwrap();
+    assert_eq!(children("src")["app.tsx"].language, Some("TypeScript"));
+    assert_eq!(children("bin")["deploy"].language, Some("Shell"));
+    assert_eq!(tree["LICENSE"].language, None);
+    assert_eq!(tree["src"].language, None);
+}
+
+#[test]
+fn duplicates_group_identical_content() {
+    let store = MemoryStore::from_files([
+        ("vendor/a/util.js", "same content"),
+        ("vendor/b/util.js", "same content"),
+        ("src/util.js", "same content"),
+        ("src/other.js", "diff content"),
+        ("empty1", ""),
+        ("empty2", ""),
+    ]);
+    let groups = find_duplicates(&store, "", &Gitignore::empty()).unwrap();
+
+    assert_eq!(groups.len(), 1, "same-size files with different content and empty files are not duplicates");
+    assert_eq!(groups[0].size, 12);
+    assert_eq!(groups[0].paths, ["src/util.js", "vendor/a/util.js", "vendor/b/util.js"]);
+}
+
+#[test]
+fn recent_files_orders_by_mtime() {
+    let dir = std::env::temp_dir().join(format!("repopatch-recent-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("src")).unwrap();
+    let now = SystemTime::now();
+    for (name, age) in [("old.txt", 3600), ("src/new.rs", 10), ("src/mid.rs", 600)] {
+        let file = std::fs::File::create(dir.join(name)).unwrap();
+        file.set_modified(now - Duration::from_secs(age)).unwrap();
+    }
+    let store = FsStore::new(dir.canonicalize().unwrap());
+
+    let all = recent_files(&store, "", &Gitignore::empty(), None, 10).unwrap();
+    let paths: Vec<&str> = all.iter().map(|(p, _)| p.as_str()).collect();
+    assert_eq!(paths, ["src/new.rs", "src/mid.rs", "old.txt"]);
+
+    let since = recent_files(&store, "", &Gitignore::empty(), Some(now - Duration::from_secs(1200)), 1).unwrap();
+    assert_eq!(since.len(), 1);
+    assert_eq!(since[0].0, "src/new.rs");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn subtrees_list_ignored_files_and_folders_deepest_first() {
+    let store = MemoryStore::from_files([
+        ("old/.gitignore", "*.log\n"),
+        ("old/a.rs", "x"),
+        ("old/debug.log", "x"),
+        ("old/nested/.git/HEAD", "x"),
+        ("old/nested/b.rs", "x"),
+        ("keep.rs", "x"),
+    ]);
+    let subtree = collect_subtree(&store, "old", 10).unwrap();
+    assert_eq!(subtree.files, ["old/.gitignore", "old/a.rs", "old/debug.log", "old/nested/.git/HEAD", "old/nested/b.rs"]);
+    assert_eq!(subtree.dirs, ["old/nested/.git", "old/nested", "old"]);
+    assert!(!subtree.truncated);
+
+    let subtree = collect_subtree(&store, "old", 2).unwrap();
+    assert!(subtree.truncated);
+    assert_eq!(subtree.files.len(), 2);
+}
+
+#[test]
+fn merged_roots_are_namespaced_by_name() {
+    let a = MemoryStore::from_files([("src/main.rs", "fn main() {}"), ("README.md", "docs")]);
+    let b = MemoryStore::from_files([("lib.rs", "")]);
+    let trees = vec![
+        ("/srv/app".to_string(), build_tree(&a, "", &Gitignore::empty()).unwrap()),
+        ("/home/me/app/".to_string(), build_tree(&b, "", &Gitignore::empty()).unwrap()),
+        ("/srv/web".to_string(), build_tree(&b, "", &Gitignore::empty()).unwrap()),
+    ];
+    let (tree, names) = merge_roots(trees);
+
+    assert_eq!(names, ["app", "app-2", "web"]);
+    assert_eq!(tree["app"].path, "/srv/app");
+    assert_eq!(tree["app"].node_type, "folder");
+    assert_eq!((tree["app"].file_count, tree["app"].size), (Some(2), Some(16)));
+    assert!(tree["app"].children.as_ref().unwrap()["src"].children.as_ref().unwrap().contains_key("main.rs"));
+    assert_eq!(tree["app-2"].path, "/home/me/app/");
+    assert_eq!(tree_totals(&tree), (4, 16));
+}
+
+#[test]
+fn path_filters_keep_profile_globs() {
+    let store = MemoryStore::from_files([
+        ("server/api.rs", ""),
+        ("server/fixtures/big.json", ""),
+        ("web/app.ts", ""),
+        ("Cargo.toml", ""),
+    ]);
+    let paths = PathFilter::new(&["server/**".to_string(), "Cargo.toml".to_string()], &["server/fixtures".to_string()]).unwrap();
+    assert!(paths.allows("server/api.rs"));
+    assert!(!paths.allows("web/app.ts"));
+    let filter = TreeFilter { paths: Some(paths), ..TreeFilter::default() };
+    let tree = build_filtered_tree(&store, "", &Gitignore::empty(), &filter).unwrap();
+
+    assert!(tree.contains_key("Cargo.toml"));
+    assert!(!tree.contains_key("web"));
+    let server = tree["server"].children.as_ref().unwrap();
+    assert!(server.contains_key("api.rs"));
+    assert!(!server.contains_key("fixtures"), "excluded folders are not walked");
+    assert!(PathFilter::new(&["[".to_string()], &[]).is_err());
+}
+
+#[test]
+fn field_projection_trims_tree_nodes() {
+    let store = MemoryStore::from_files([("src/main.rs", "fn main() {}"), ("src/lib10.rs", ""), ("src/lib9.rs", ""), ("README.md", "docs")]);
+    let tree = build_tree(&store, "", &Gitignore::empty()).unwrap();
+
+    let paths = project_tree(&tree, &parse_fields("path", NODE_FIELDS).unwrap());
+    assert_eq!(paths, serde_json::json!(["README.md", "src/lib9.rs", "src/lib10.rs", "src/main.rs"]));
+
+    let flat = project_tree(&tree, &parse_fields("path, size,path", NODE_FIELDS).unwrap());
+    assert_eq!(flat[3], serde_json::json!({ "path": "src/main.rs", "size": 12 }));
+
+    let nested = project_tree(&tree, &parse_fields("type,children", NODE_FIELDS).unwrap());
+    assert_eq!(nested["src"]["type"], "folder");
+    assert_eq!(nested["src"]["children"]["main.rs"], serde_json::json!({ "type": "file", "children": null }));
+    assert!(nested["src"].get("path").is_none());
+
+    assert!(parse_fields("path,mtime", NODE_FIELDS).unwrap_err().contains("mtime"));
+    assert!(parse_fields(" , ", NODE_FIELDS).is_err());
+}
+
+/// A memory store that can't list the folders in `denied`.
+struct DeniedStore {
+    inner: MemoryStore,
+    denied: &'static [&'static str],
+}
+
+impl FileStore for DeniedStore {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.inner.read(path)
+    }
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.inner.write(path, data)
+    }
+    fn remove(&self, path: &str) -> io::Result<()> {
+        self.inner.remove(path)
+    }
+    fn exists(&self, path: &str) -> bool {
+        self.inner.exists(path)
+    }
+    fn list_dir(&self, path: &str) -> io::Result<Vec<DirEntry>> {
+        if self.denied.contains(&path) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Permission denied"));
+        }
+        self.inner.list_dir(path)
+    }
+}
+
+#[test]
+fn unreadable_folders_are_reported_as_skipped() {
+    let inner = MemoryStore::from_files([("src/main.rs", ""), ("secrets/key.pem", ""), ("src/private/notes.md", "")]);
+    let store = DeniedStore { inner, denied: &["secrets", "src/private"] };
+    let mut scan = TreeScan::default();
+    let tree = build_reported_tree(&store, "", &Gitignore::empty(), &TreeFilter::default(), &mut scan).unwrap();
+
+    assert!(!tree.contains_key("secrets"));
+    assert!(tree["src"].children.as_ref().unwrap().contains_key("main.rs"));
+    let reported: Vec<(&str, &str)> = scan.warnings.iter().map(|s| (s.path.as_str(), s.reason.as_str())).collect();
+    assert_eq!(reported, [("secrets", "Permission denied"), ("src/private", "Permission denied")]);
+    assert!(!scan.truncated, "nothing was cut short by a limit");
+
+    let root = DeniedStore { inner: MemoryStore::new(), denied: &[""] };
+    assert!(build_reported_tree(&root, "", &Gitignore::empty(), &TreeFilter::default(), &mut TreeScan::default()).is_err());
+}
+
+#[test]
+fn scan_limits_return_a_partial_tree() {
+    let store = MemoryStore::from_files([
+        ("a/b/c/deep.rs", ""),
+        ("a/top.rs", ""),
+        ("many/1.txt", ""),
+        ("many/2.txt", ""),
+        ("many/3.txt", ""),
+        ("z.txt", ""),
+    ]);
+    let build = |limits: TreeLimits| {
+        let mut scan = TreeScan::new(limits);
+        let tree = build_reported_tree(&store, "", &Gitignore::empty(), &TreeFilter::default(), &mut scan).unwrap();
+        (tree, scan)
+    };
+
+    let (tree, scan) = build(TreeLimits { max_depth: 1, ..TreeLimits::default() });
+    let a = tree["a"].children.as_ref().unwrap();
+    assert!(a.contains_key("top.rs") && !a.contains_key("b"));
+    assert!(scan.truncated);
+    assert_eq!(scan.warnings[0].path, "a/b");
+
+    let (tree, scan) = build(TreeLimits { max_dir_entries: 2, ..TreeLimits::default() });
+    assert_eq!(tree["many"].file_count, Some(2));
+    assert!(!tree.contains_key("z.txt"), "folders are listed first");
+    assert_eq!(scan.warnings.iter().map(|w| w.path.as_str()).collect::<Vec<_>>(), ["", "many"]);
+
+    let (tree, scan) = build(TreeLimits { max_entries: 4, ..TreeLimits::default() });
+    assert_eq!(tree_totals(&tree).0, 1, "a, a/b, a/b/c and deep.rs");
+    assert_eq!(scan.warnings.len(), 1);
+    assert_eq!(scan.warnings[0].path, "a/top.rs");
+
+    let (_, scan) = build(TreeLimits { max_depth: 0, max_entries: 0, max_dir_entries: 0 });
+    assert!(!scan.truncated && scan.warnings.is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn walks_stop_at_links_back_to_an_ancestor() {
+    let dir = std::env::temp_dir().join(format!("repopatch-loop-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("a/b")).unwrap();
+    std::fs::write(dir.join("a/b/file.rs"), "").unwrap();
+    std::os::unix::fs::symlink("..", dir.join("a/b/up")).unwrap();
+    std::os::unix::fs::symlink("b", dir.join("a/same")).unwrap();
+    let root = dir.canonicalize().unwrap();
+    let store = FsStore::new(&root);
+
+    let mut scan = TreeScan::default();
+    let tree = build_reported_tree(&store, "", &Gitignore::empty(), &TreeFilter::default(), &mut scan).unwrap();
+    let a = tree["a"].children.as_ref().unwrap();
+    assert!(a["same"].children.as_ref().unwrap().contains_key("file.rs"), "a second link to a folder is no loop");
+    assert!(!a["b"].children.as_ref().unwrap().contains_key("up"));
+    assert!(!scan.truncated);
+    let loops: Vec<&str> = scan.warnings.iter().map(|w| w.reason.as_str()).collect();
+    let a_path = root.join("a").to_string_lossy().into_owned();
+    assert_eq!(loops, [format!("Loops back to {}", a_path), format!("Loops back to {}", a_path)]);
+
+    let mut files = Vec::new();
+    repopatch::tree::walk_files(&store, "", &Gitignore::empty(), &mut |p| files.push(p.to_string())).unwrap();
+    assert_eq!(files, ["a/b/file.rs", "a/same/file.rs"]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn trees_sum_up_languages_and_ignore_files() {
+    let store = MemoryStore::from_files([
+        (".gitignore", "target/\n"),
+        ("src/main.rs", "fn main() {}"),
+        ("src/lib.rs", "pub fn f() {}"),
+        ("web/.gitignore", "dist/\n"),
+        ("web/app.ts", "x"),
+        ("notes", "x"),
+    ]);
+    let ig = load_gitignore(&store, "").unwrap_or_else(Gitignore::empty);
+    let summary = summarize_tree(&build_tree(&store, "", &ig).unwrap());
+
+    assert_eq!(summary.ignore_files, [".gitignore", "web/.gitignore"]);
+    assert_eq!((summary.languages["Rust"].files, summary.languages["Rust"].bytes), (2, 25));
+    assert_eq!(summary.languages["TypeScript"].files, 1);
+    assert!(summary.languages.values().map(|l| l.files).sum::<u64>() < 6, "files without a language are left out");
+}
+
+#[test]
+fn names_keep_the_order_alphanumeric_sort_gave_them() {
+    let names = ["img12.png", "img2.png", "Zebra", "apple", "a", "a1", "a-1", "v1.10", "v1.9.3", "v1.9", "page_010", "page_9"];
+    let store = MemoryStore::from_files(names.map(|n| (n, "")));
+    let tree = build_tree(&store, "", &Gitignore::empty()).unwrap();
+    let paths = project_tree(&tree, &parse_fields("path", NODE_FIELDS).unwrap());
+    assert_eq!(paths, serde_json::json!(["Zebra", "a", "a-1", "a1", "apple", "img2.png", "img12.png", "page_9", "page_010", "v1.9", "v1.9.3", "v1.10"]));
+}
+
+#[test]
+fn names_sort_naturally_in_a_total_order() {
+    // Hex names like these broke the sort of git's object folders
+    let names = ["1c", "01", "1e", "b1", "1", "file10", "file9", "file09", "0a"];
+    let store = MemoryStore::from_files(names.map(|n| (n, "")));
+    let tree = build_tree(&store, "", &Gitignore::empty()).unwrap();
+    let paths = project_tree(&tree, &parse_fields("path", NODE_FIELDS).unwrap());
+    assert_eq!(paths, serde_json::json!(["0a", "1", "1c", "1e", "01", "b1", "file9", "file09", "file10"]));
+}